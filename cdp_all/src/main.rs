@@ -0,0 +1,112 @@
+//! Everything in one process, for trying the system out locally: the API,
+//! keeping its data in memory, a broker sending home to it, and a few dummy
+//! sensors publishing to the broker, all set up in cdp_all.toml. Not for
+//! anything that matters, where each runs on its own.
+
+#[macro_use]
+extern crate libcdp;
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+use config::{Config, ConfigError};
+use libcdp::runtime;
+
+/// Where the API listens when cdp_all.toml doesn't say.
+const DEFAULT_API_BIND: &str = "0.0.0.0:9869";
+
+/// How long each part gets to come up before the next is started. They'd
+/// cope without it, but with fewer scary messages.
+const HEAD_START: Duration = Duration::from_millis(500);
+
+/// A table of the configuration, as if it were a file of its own.
+fn section(cfg: &Config, name: &str) -> Result<Config, ConfigError> {
+  let mut section = Config::default();
+  for (key, value) in cfg.get_table(name)? {
+    section.set(&key, value)?;
+  }
+  return Ok(section);
+}
+
+/// Where the broker finds the API: its first address that isn't a Unix
+/// socket, over loopback if it listens on every one.
+fn api_endpoint(api: &Config) -> String {
+  let binds: Vec<String> = api.get("binds").unwrap_or_default();
+  let bind = binds.iter()
+    .find(|b| !b.starts_with("unix:"))
+    .map(String::as_str)
+    .unwrap_or(DEFAULT_API_BIND);
+  return format!("http://{}/", bind.replacen("0.0.0.0", "127.0.0.1", 1));
+}
+
+/// The API's and the broker's configurations. The API's data always lives
+/// in memory, and the broker sends to it unless told otherwise.
+fn split(cfg: &Config) -> Result<(Config, Config), ConfigError> {
+  let mut api = section(cfg, "api")?;
+  api.set("database.backend", "in_memory")?;
+  let mut broker = section(cfg, "broker")?;
+  broker.set_default("endpoint", api_endpoint(&api))?;
+  return Ok((api, broker));
+}
+
+/// Runs a part on a thread of its own, saying when it stops, and whether
+/// it did so without panicking.
+fn spawn<F>(name: &'static str, done: &Sender<(&'static str, bool)>, f: F)
+where F: FnOnce() + Send + 'static {
+  let done = done.clone();
+  thread::Builder::new()
+    .name(name.to_owned())
+    .spawn(move || {
+      let ok = panic::catch_unwind(AssertUnwindSafe(f)).is_ok();
+      let _ = done.send((name, ok));
+    })
+    .expect("Couldn't start a thread!");
+}
+
+fn main() {
+  runtime::init("cdp_all");
+  let mut args: Vec<String> = std::env::args().skip(1).collect();
+  let given = runtime::config_arg(&mut args)
+    .and_then(|given| match args.first() {
+      Some(arg) => Err(format!("Unknown argument \"{}\".", arg)),
+      None => Ok(given),
+    })
+    .unwrap_or_else(|e| {
+      eprintln!("{}\n\nUsage: cdp_all [-c, --config <path>]", e);
+      std::process::exit(2);
+    });
+  info!("Hey! Loading configuration...");
+  let mut cfg = Config::default();
+  cfg.merge(runtime::config_file("cdp_all", given.as_deref()))
+    .unwrap_or_else(|e| panic!("Configuration tragedy: {}", e));
+  let (api, broker) = split(&cfg)
+    .unwrap_or_else(|e| panic!("Configuration tragedy: {}", e));
+  let (done_tx, done_rx) = mpsc::channel();
+  info!("Starting the API, in memory...");
+  spawn("API", &done_tx, move || {
+    cdp_api::run(api).unwrap_or_else(|e| panic!("API tragedy: {}", e));
+  });
+  thread::sleep(HEAD_START);
+  info!("Starting the broker...");
+  spawn("broker", &done_tx, move || cdp_broker::run(broker));
+  thread::sleep(HEAD_START);
+  info!("Starting the dummies...");
+  spawn("dummies", &done_tx, move || cdp_dummy::run(cfg));
+  drop(done_tx);
+  // dummies may be done for the day, but without either of the others,
+  // there's nothing left to try out.
+  for (name, ok) in done_rx.iter() {
+    if name == "dummies" && ok {
+      info!("The dummies are done. The rest keeps going; ^C to stop.");
+      continue;
+    }
+    if ok {
+      info!("The {} stopped, so everything does. Bye!", name);
+      std::process::exit(0);
+    }
+    warn!("The {} died, so everything does!", name);
+    std::process::exit(1);
+  }
+}
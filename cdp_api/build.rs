@@ -0,0 +1,9 @@
+//! Generates the gRPC service from its protobuf definition.
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  tonic_build::configure()
+    .build_client(false)
+    .extern_path(".cdp", "::libcdp::proto::pb")
+    .compile(&["proto/cdp_api.proto"], &["proto", "../libcdp/proto"])?;
+  return Ok(());
+}
@@ -0,0 +1,789 @@
+//! Alerts, and everything that happened to each. An alert opens when a
+//! sensor needs a human: a reading the anomaly detector flagged, or a device
+//! whose battery ran low. Someone acknowledges it to say they're on it, and
+//! it resolves by itself once the sensor recovers, acknowledged or not.
+//!
+//! Alert rules open alerts on conditions across sensors, evaluated against
+//! the last-value cache and the arming state. See the rule module.
+//!
+//! Alerts nobody acknowledges escalate: each has a severity, which goes by
+//! its kind, and the escalation steps for that severity say which channels
+//! to notify after how long. See the notify module for the channels.
+//!
+//! Mutes keep alerts about some sensors from opening or escalating for a
+//! while, for maintenance. See the mute module.
+//!
+//! Every transition is kept, with when and by whom, so there's an incident
+//! trail. With a log_path, each is also appended to a file as the alert's
+//! JSON, one line each, and read back at startup so the trail survives
+//! restarts. Mutes are logged there too.
+//!
+//! Instances sharing a database tell each other about every line they log,
+//! and log each other's lines too, so they all keep the same book. If two
+//! open an alert about the same thing at once, the one opened first wins
+//! everywhere, and the other is dropped.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, SensorId};
+
+use crate::alerts::rule::AlertRule;
+use crate::arming::Arming;
+use crate::db::News;
+use crate::lastvalue::LastValueCache;
+use crate::notify::ChannelConfig;
+
+mod mute;
+mod rule;
+
+pub(crate) use crate::alerts::mute::Mute;
+
+/// Alert settings, as they lie in the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct AlertConfig {
+  /// Where transitions are logged and read back from. None means alerts
+  /// are forgotten on restart.
+  pub(crate) log_path: Option<PathBuf>,
+  /// Resolved alerts to keep in memory. Older ones are only in the log.
+  #[serde(default = "AlertConfig::default_keep_resolved")]
+  pub(crate) keep_resolved: usize,
+  /// Severity of each kind of alert, by kind name. Kinds left out are
+  /// warnings.
+  #[serde(default)]
+  pub(crate) severity: HashMap<String, AlertSeverity>,
+  /// Where notifications may go, by name.
+  #[serde(default)]
+  pub(crate) channels: HashMap<String, ChannelConfig>,
+  /// Who to notify when alerts go unacknowledged.
+  #[serde(default)]
+  pub(crate) escalation: Vec<EscalationStep>,
+  /// Conditions across sensors to open alerts on.
+  #[serde(default)]
+  pub(crate) rules: Vec<AlertRule>
+}
+
+impl AlertConfig {
+  /// For serde.
+  fn default_keep_resolved() -> usize {
+    return 1000;
+  }
+
+  /// Checks that severities go by known kinds, that escalation steps name
+  /// known channels, and that rule names are unique.
+  pub(crate) fn check(&self) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for rule in &self.rules {
+      if !names.insert(&rule.name) {
+        return Err(format!("Alert rule \"{}\" is there twice.", rule.name));
+      }
+    }
+    for kind in self.severity.keys() {
+      if AlertKind::from_name(kind).is_none() {
+        return Err(format!("Unknown alert kind \"{}\".", kind));
+      }
+    }
+    for step in &self.escalation {
+      for name in &step.channels {
+        if !self.channels.contains_key(name) {
+          return Err(format!("Unknown alert channel \"{}\".", name));
+        }
+      }
+    }
+    return Ok(());
+  }
+}
+
+impl Default for AlertConfig {
+  fn default() -> Self {
+    return Self {
+      log_path: None,
+      keep_resolved: Self::default_keep_resolved(),
+      severity: HashMap::new(),
+      channels: HashMap::new(),
+      escalation: Vec::new(),
+      rules: Vec::new()
+    };
+  }
+}
+
+/// What an alert is about.
+#[derive(
+  Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash
+)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AlertKind {
+  /// A reading strayed too far from the sensor's usual.
+  Anomaly,
+  /// A device's battery is below the low_battery_threshold.
+  LowBattery,
+  /// An alert rule holds.
+  Rule
+}
+
+impl AlertKind {
+  /// Every kind there is.
+  const ALL: [AlertKind; 3] = [
+    AlertKind::Anomaly, AlertKind::LowBattery, AlertKind::Rule
+  ];
+
+  /// What it's called in the config.
+  pub(crate) fn name(self) -> &'static str {
+    return match self {
+      AlertKind::Anomaly => "anomaly",
+      AlertKind::LowBattery => "low_battery",
+      AlertKind::Rule => "rule",
+    };
+  }
+
+  /// The kind going by a name.
+  fn from_name(name: &str) -> Option<Self> {
+    return Self::ALL.iter().copied().find(|k| k.name() == name);
+  }
+}
+
+/// How much an alert matters.
+#[derive(
+  Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash
+)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AlertSeverity {
+  /// Worth knowing.
+  Info,
+  /// Worth looking into.
+  Warning,
+  /// Worth getting up for.
+  Critical
+}
+
+impl Default for AlertSeverity {
+  fn default() -> Self {
+    return Self::Warning;
+  }
+}
+
+impl Display for AlertSeverity {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return write!(f, "{}", match self {
+      AlertSeverity::Info => "info",
+      AlertSeverity::Warning => "warning",
+      AlertSeverity::Critical => "critical",
+    });
+  }
+}
+
+/// Who to notify about alerts of a severity, once they've gone
+/// unacknowledged for so long.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct EscalationStep {
+  /// Which alerts it's for.
+  pub(crate) severity: AlertSeverity,
+  /// How long after opening, in minutes.
+  pub(crate) after_minutes: u64,
+  /// Names of the channels to notify.
+  pub(crate) channels: Vec<String>
+}
+
+/// Where an alert is at.
+#[derive(
+  Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash
+)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AlertState {
+  /// Nobody has said they're on it.
+  Open,
+  /// Somebody is on it.
+  Acknowledged,
+  /// The sensor recovered.
+  Resolved
+}
+
+/// Something that happened to an alert.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct AlertTransition {
+  /// What it went to.
+  pub(crate) state: AlertState,
+  /// When.
+  pub(crate) when: DateTime<Local>,
+  /// Who did it. None when it happened by itself.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) by: Option<String>,
+  /// Anything they had to say.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) note: Option<String>
+}
+
+/// A notification sent about an alert.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Escalation {
+  /// Which escalation step it was, counting from 0.
+  pub(crate) step: usize,
+  /// When it was sent.
+  pub(crate) when: DateTime<Local>,
+  /// Names of the channels notified.
+  pub(crate) channels: Vec<String>
+}
+
+/// A sensor, or device, needing a human.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Alert {
+  /// Unique ID, for acknowledging.
+  pub(crate) id: Uuid,
+  /// What it's about.
+  pub(crate) kind: AlertKind,
+  /// Type of the sensor it's about. None for device and rule alerts.
+  pub(crate) sensor_type: Option<SensorType>,
+  /// ID of the sensor, or device, it's about. 0 for rule alerts.
+  pub(crate) sensor_id: SensorId,
+  /// Name of the rule that holds, for rule alerts.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) rule: Option<String>,
+  /// UID of the broker at the sensor's site, as of the reading that opened
+  /// it.
+  #[serde(default)]
+  pub(crate) broker_id: Option<BrokerId>,
+  /// How much it matters.
+  #[serde(default)]
+  pub(crate) severity: AlertSeverity,
+  /// What set it off, in words.
+  pub(crate) summary: String,
+  /// Where it's at.
+  pub(crate) state: AlertState,
+  /// Everything that happened to it, oldest first.
+  pub(crate) history: Vec<AlertTransition>,
+  /// Notifications sent about it, oldest first.
+  #[serde(default)]
+  pub(crate) escalations: Vec<Escalation>
+}
+
+/// What an alert is about, so a sensor, or rule, only has one going at a
+/// time.
+type AlertSubject = (AlertKind, Option<SensorType>, SensorId, Option<String>);
+
+impl Alert {
+  /// What it's about.
+  fn subject(&self) -> AlertSubject {
+    return (self.kind, self.sensor_type, self.sensor_id, self.rule.clone());
+  }
+
+  /// When it opened.
+  pub(crate) fn opened_when(&self) -> Option<DateTime<Local>> {
+    return self.history.first().map(|t| t.when);
+  }
+
+  /// Moves it along, noting it down.
+  fn transition(
+    &mut self, state: AlertState, when: DateTime<Local>, by: Option<String>,
+    note: Option<String>
+  ) {
+    self.state = state;
+    self.history.push(AlertTransition {
+      state: state,
+      when: when,
+      by: by,
+      note: note
+    });
+  }
+}
+
+/// Why an acknowledgement didn't go through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AckError {
+  /// No alert has that ID, or it's too old to be kept around.
+  NoSuchAlert,
+  /// Somebody already is on it.
+  AlreadyAcknowledged,
+  /// It's over.
+  AlreadyResolved
+}
+
+impl std::error::Error for AckError {}
+
+impl Display for AckError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return write!(f, "{}", match self {
+      AckError::NoSuchAlert => "No such alert.",
+      AckError::AlreadyAcknowledged => "Alert already acknowledged.",
+      AckError::AlreadyResolved => "Alert already resolved.",
+    });
+  }
+}
+
+/// A line of the log, which is also what other instances are told.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum LogLine {
+  /// An alert, as it was then.
+  Alert(Alert),
+  /// A mute, as it was then.
+  Mute(Mute)
+}
+
+/// The alerts themselves, behind the lock.
+#[derive(Debug, Default)]
+struct Book {
+  /// Every alert kept, oldest first.
+  alerts: Vec<Alert>,
+  /// IDs of the unresolved ones, by what they're about.
+  active: HashMap<AlertSubject, Uuid>,
+  /// Mutes not over yet, as of the last look.
+  mutes: Vec<Mute>,
+  /// Where transitions are logged. None means nowhere.
+  log: Option<File>,
+  /// Where to tell other instances about them. None means nobody's told.
+  news: Option<Sender<News>>,
+  /// Resolved alerts to keep.
+  keep_resolved: usize
+}
+
+impl Book {
+  /// Finds an alert.
+  fn get_mut(&mut self, id: Uuid) -> Option<&mut Alert> {
+    return self.alerts.iter_mut().rev().find(|a| a.id == id);
+  }
+
+  /// Logs an alert as it is now, if there's a log.
+  fn log(&mut self, id: Uuid) {
+    if let Some(alert) = self.alerts.iter().rev().find(|a| a.id == id) {
+      let line = LogLine::Alert(alert.clone());
+      self.write_log(&line, id);
+    }
+  }
+
+  /// Appends a line to the log, if there's a log, and tells other instances
+  /// about it, if anyone's listening.
+  fn write_log(&mut self, line: &LogLine, id: Uuid) {
+    self.append(line, id);
+    if let Some(tx) = &self.news {
+      let _ = tx.send(News::Alert(line.clone()));
+    }
+  }
+
+  /// Appends a line to the log, if there's a log.
+  fn append(&mut self, line: &LogLine, id: Uuid) {
+    let res = match (&mut self.log, serde_json::to_string(line)) {
+      (Some(f), Ok(line)) => writeln!(f, "{}", line),
+      (Some(_), Err(e)) => Err(e.into()),
+      (None, _) => Ok(()),
+    };
+    if let Err(e) = res {
+      warn!("Failed to log {}: {}", id, e);
+    }
+  }
+
+  /// Takes in a mute, or a newer take on one.
+  fn put_mute(&mut self, mute: Mute) {
+    match self.mutes.iter_mut().find(|m| m.id == mute.id) {
+      Some(old) => *old = mute,
+      None => self.mutes.push(mute),
+    }
+  }
+
+  /// Drops the oldest resolved alerts past the limit.
+  fn trim(&mut self) {
+    let resolved = self.alerts.iter()
+      .filter(|a| a.state == AlertState::Resolved)
+      .count();
+    let mut excess = resolved.saturating_sub(self.keep_resolved);
+    self.alerts.retain(|a| {
+      if excess > 0 && a.state == AlertState::Resolved {
+        excess -= 1;
+        return false;
+      }
+      return true;
+    });
+  }
+
+  /// Takes in an alert as logged. Later lines about the same alert replace
+  /// earlier ones. Of two unresolved alerts about the same thing, the one
+  /// opened first stays, and the other is dropped.
+  fn restore(&mut self, alert: Alert) {
+    let subject = alert.subject();
+    if alert.state == AlertState::Resolved {
+      if self.active.get(&subject) == Some(&alert.id) {
+        self.active.remove(&subject);
+      }
+    } else {
+      let rival = self.active.get(&subject).copied()
+        .filter(|id| *id != alert.id)
+        .and_then(|id| self.alerts.iter().find(|a| a.id == id))
+        .map(|a| (a.opened_when(), a.id));
+      if let Some(rival) = rival {
+        if rival <= (alert.opened_when(), alert.id) {
+          return;
+        }
+        self.alerts.retain(|a| a.id != rival.1);
+      }
+      self.active.insert(subject, alert.id);
+    }
+    match self.get_mut(alert.id) {
+      Some(old) => *old = alert,
+      None => self.alerts.push(alert),
+    }
+  }
+}
+
+/// Every alert, open or not. Cheap to clone, all clones share the same
+/// alerts.
+#[derive(Clone, Debug)]
+pub(crate) struct AlertBook {
+  /// Alert settings.
+  cfg: Arc<AlertConfig>,
+  /// Battery percentage below which devices get an alert.
+  low_battery_threshold: u8,
+  /// How the system is armed, for rules.
+  arming: Arming,
+  /// The alerts.
+  book: Arc<Mutex<Book>>
+}
+
+impl AlertBook {
+  /// Sets up the book, reading back whatever was logged.
+  pub(crate) fn open(
+    cfg: &AlertConfig, low_battery_threshold: u8, arming: Arming
+  ) -> io::Result<Self> {
+    let mut book = Book {
+      keep_resolved: cfg.keep_resolved,
+      ..Book::default()
+    };
+    if let Some(path) = &cfg.log_path {
+      if path.exists() {
+        Self::read_log(&mut book, path)?;
+      }
+      book.log = Some(
+        OpenOptions::new().create(true).append(true).open(path)?
+      );
+    }
+    book.trim();
+    return Ok(Self {
+      cfg: Arc::new(cfg.clone()),
+      low_battery_threshold: low_battery_threshold,
+      arming: arming,
+      book: Arc::new(Mutex::new(book))
+    });
+  }
+
+  /// Reads a log back into a book.
+  fn read_log(book: &mut Book, path: &Path) -> io::Result<()> {
+    let mut bad_lines = 0;
+    for line in BufReader::new(File::open(path)?).lines() {
+      match serde_json::from_str::<LogLine>(&line?) {
+        Ok(LogLine::Alert(alert)) => book.restore(alert),
+        Ok(LogLine::Mute(mute)) => book.put_mute(mute),
+        Err(_) => bad_lines += 1,
+      }
+    }
+    if bad_lines > 0 {
+      warn!("Skipped {} bad lines in the alert log.", bad_lines);
+    }
+    return Ok(());
+  }
+
+  /// Tells other instances about every line logged from now on.
+  pub(crate) fn share(&self, news: Sender<News>) {
+    self.lock().news = Some(news);
+  }
+
+  /// Takes in a line another instance logged, and logs it here too, without
+  /// telling anyone.
+  pub(crate) fn apply(&self, line: LogLine) {
+    let mut book = self.lock();
+    match line {
+      LogLine::Alert(alert) => {
+        book.append(&LogLine::Alert(alert.clone()), alert.id);
+        book.restore(alert);
+        book.trim();
+      },
+      LogLine::Mute(mute) => {
+        book.append(&LogLine::Mute(mute.clone()), mute.id);
+        book.put_mute(mute);
+        book.mutes.retain(|m| !m.is_over(Local::now()));
+      },
+    }
+  }
+
+  /// Used to acquire a lock on the alerts.
+  fn lock(&self) -> std::sync::MutexGuard<'_, Book> {
+    return self.book.lock().unwrap_or_else(|e| e.into_inner());
+  }
+
+  /// A rule, by name.
+  fn rule(&self, name: &str) -> Option<&AlertRule> {
+    return self.cfg.rules.iter().find(|r| r.name == name);
+  }
+
+  /// Whether any of some mutes covers an alert at a time. Rule alerts are
+  /// muted along with any sensor the rule reads, by mutes not scoped to a
+  /// site.
+  fn muted(&self, mutes: &[Mute], alert: &Alert, when: DateTime<Local>)
+  -> bool {
+    let sensors = match &alert.rule {
+      Some(name) => self.rule(name)
+        .map(|r| r.sensors())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(st, id)| (Some(st), id))
+        .collect(),
+      None => vec![(alert.sensor_type, alert.sensor_id)],
+    };
+    return mutes.iter().any(|m| {
+      m.active_at(when) && sensors.iter().any(|(st, id)| {
+        m.covers(*st, *id, alert.broker_id)
+      })
+    });
+  }
+
+  /// Opens an alert, unless one about the same thing is going already, or
+  /// it's muted.
+  fn raise(
+    &self, subject: AlertSubject, broker_id: Option<BrokerId>, summary: String,
+    when: DateTime<Local>
+  ) {
+    let mut book = self.lock();
+    if book.active.contains_key(&subject) {
+      return;
+    }
+    let (kind, sensor_type, sensor_id, rule) = subject.clone();
+    let severity = rule.as_deref()
+      .and_then(|name| self.rule(name)?.severity)
+      .or_else(|| self.cfg.severity.get(kind.name()).copied());
+    let mut alert = Alert {
+      id: Uuid::new_v4(),
+      kind: kind,
+      sensor_type: sensor_type,
+      sensor_id: sensor_id,
+      rule: rule,
+      broker_id: broker_id,
+      severity: severity.unwrap_or_default(),
+      summary: summary,
+      state: AlertState::Open,
+      history: Vec::new(),
+      escalations: Vec::new()
+    };
+    if self.muted(&book.mutes, &alert, when) {
+      return;
+    }
+    alert.transition(AlertState::Open, when, None, None);
+    let id = alert.id;
+    book.active.insert(subject, id);
+    book.alerts.push(alert);
+    book.log(id);
+  }
+
+  /// Resolves the alert about something, if one is going.
+  fn recover(&self, subject: AlertSubject, when: DateTime<Local>) {
+    let mut book = self.lock();
+    let id = match book.active.remove(&subject) {
+      Some(id) => id,
+      None => return,
+    };
+    if let Some(alert) = book.get_mut(id) {
+      alert.transition(AlertState::Resolved, when, None, None);
+    }
+    book.log(id);
+    book.trim();
+  }
+
+  /// Opens or resolves alerts as a message about to be stored calls for.
+  /// Its anomaly_score must be set by then, if it's getting one.
+  pub(crate) fn observe(&self, msg: &BrokerMessage, when: DateTime<Local>) {
+    match &msg.payload {
+      BrokerMessagePayload::SensorData(sd) => {
+        let stype = sd.sensor_type();
+        let subject = (AlertKind::Anomaly, Some(stype), sd.sensor_id(), None);
+        match msg.anomaly_score {
+          Some(z) => self.raise(subject, Some(msg.broker_id), format!(
+            "{} sensor {} read {}, a z-score of {:.1}.",
+            stype, sd.sensor_id(), sd.reading().human_value(), z
+          ), when),
+          None => self.recover(subject, when),
+        }
+      },
+      BrokerMessagePayload::DeviceHealth(dh) => {
+        let subject =
+          (AlertKind::LowBattery, None, SensorId(dh.sensor_id), None);
+        match dh.battery_percent() {
+          Some(b) if b < self.low_battery_threshold => {
+            self.raise(subject, Some(msg.broker_id), format!(
+              "Device {} is down to {}% battery.", dh.sensor_id, b
+            ), when);
+          },
+          _ => self.recover(subject, when),
+        }
+      },
+      BrokerMessagePayload::Heartbeat(_) => (),
+      BrokerMessagePayload::DecodeFailure { .. } => (),
+    }
+  }
+
+  /// Opens or resolves the alerts of every rule reading any of the touched
+  /// sensors, as the last-value cache has them now.
+  pub(crate) fn evaluate_rules(
+    &self, lvc: &LastValueCache, touched: &HashSet<(SensorType, SensorId)>,
+    when: DateTime<Local>
+  ) {
+    let rules = self.cfg.rules.iter().filter(|r| r.depends_on(touched));
+    self.evaluate(lvc, rules, when);
+  }
+
+  /// Opens or resolves the alerts of every rule, like after the system is
+  /// armed or disarmed.
+  pub(crate) fn evaluate_all_rules(
+    &self, lvc: &LastValueCache, when: DateTime<Local>
+  ) {
+    self.evaluate(lvc, self.cfg.rules.iter(), when);
+  }
+
+  /// Opens or resolves the alerts of some rules.
+  fn evaluate<'a, I: Iterator<Item = &'a AlertRule>>(
+    &self, lvc: &LastValueCache, rules: I, when: DateTime<Local>
+  ) {
+    let armed = self.arming.state();
+    for rule in rules {
+      let subject =
+        (AlertKind::Rule, None, SensorId(0), Some(rule.name.clone()));
+      match rule.holds(lvc, armed, when) {
+        Some(true) => self.raise(subject, None, rule.summary(), when),
+        Some(false) => self.recover(subject, when),
+        None => (),
+      }
+    }
+  }
+
+  /// Notes that somebody is on an alert.
+  pub(crate) fn acknowledge(
+    &self, id: Uuid, by: String, note: Option<String>
+  ) -> Result<Alert, AckError> {
+    let mut book = self.lock();
+    let alert = book.get_mut(id).ok_or(AckError::NoSuchAlert)?;
+    match alert.state {
+      AlertState::Open => (),
+      AlertState::Acknowledged => return Err(AckError::AlreadyAcknowledged),
+      AlertState::Resolved => return Err(AckError::AlreadyResolved),
+    }
+    alert.transition(AlertState::Acknowledged, Local::now(), Some(by), note);
+    let acked = alert.clone();
+    book.log(id);
+    return Ok(acked);
+  }
+
+  /// Whether any escalation steps are configured.
+  pub(crate) fn escalates(&self) -> bool {
+    return !self.cfg.escalation.is_empty();
+  }
+
+  /// The escalation steps an open alert is due, and not yet through.
+  fn due_steps(&self, alert: &Alert, now: DateTime<Local>) -> Vec<usize> {
+    let opened = match alert.opened_when() {
+      Some(w) if alert.state == AlertState::Open => w,
+      _ => return Vec::new(),
+    };
+    return self.cfg.escalation.iter()
+      .enumerate()
+      .filter(|(i, step)| {
+        let wait = chrono::Duration::minutes(step.after_minutes as i64);
+        return step.severity == alert.severity
+          && now >= opened + wait
+          && !alert.escalations.iter().any(|e| e.step == *i);
+      })
+      .map(|(i, _)| i)
+      .collect();
+  }
+
+  /// Notes down every escalation step that's come due, and returns the
+  /// alerts escalated, each with the channels to notify, by name. Muted
+  /// alerts wait until the mute is over.
+  pub(crate) fn escalate(&self, now: DateTime<Local>)
+  -> Vec<(Alert, Vec<(String, ChannelConfig)>)> {
+    let mut guard = self.lock();
+    let book = &mut *guard;
+    let mut escalated = Vec::new();
+    for alert in book.alerts.iter_mut() {
+      if self.muted(&book.mutes, alert, now) {
+        continue;
+      }
+      let mut names: Vec<String> = Vec::new();
+      for step in self.due_steps(alert, now) {
+        let channels = self.cfg.escalation[step].channels.clone();
+        names.extend(channels.iter().cloned());
+        alert.escalations.push(Escalation {
+          step: step,
+          when: now,
+          channels: channels
+        });
+      }
+      if names.is_empty() {
+        continue;
+      }
+      names.sort();
+      names.dedup();
+      let channels = names.into_iter()
+        .filter_map(|n| {
+          let ch = self.cfg.channels.get(&n)?.clone();
+          return Some((n, ch));
+        })
+        .collect();
+      escalated.push((alert.clone(), channels));
+    }
+    for (alert, _) in &escalated {
+      book.log(alert.id);
+    }
+    return escalated;
+  }
+
+  /// Mutes some sensors for a while.
+  pub(crate) fn mute(&self, mute: Mute) -> Mute {
+    let mut book = self.lock();
+    book.mutes.retain(|m| !m.is_over(Local::now()));
+    book.put_mute(mute.clone());
+    book.write_log(&LogLine::Mute(mute.clone()), mute.id);
+    return mute;
+  }
+
+  /// Mutes in effect or yet to be, soonest first.
+  pub(crate) fn mutes(&self) -> Vec<Mute> {
+    let mut book = self.lock();
+    book.mutes.retain(|m| !m.is_over(Local::now()));
+    let mut mutes = book.mutes.clone();
+    mutes.sort_by_key(|m| m.from);
+    return mutes;
+  }
+
+  /// Ends a mute early, or calls it off if it's yet to start.
+  pub(crate) fn unmute(&self, id: Uuid) -> Option<Mute> {
+    let now = Local::now();
+    let mut book = self.lock();
+    let mute = book.mutes.iter_mut().find(|m| m.id == id && !m.is_over(now))?;
+    mute.from = mute.from.min(now);
+    mute.until = now;
+    let ended = mute.clone();
+    book.write_log(&LogLine::Mute(ended.clone()), id);
+    book.mutes.retain(|m| m.id != id);
+    return Some(ended);
+  }
+
+  /// Alerts kept, newest first, optionally only those in a state.
+  pub(crate) fn list(&self, state: Option<AlertState>) -> Vec<Alert> {
+    return self.lock().alerts.iter()
+      .rev()
+      .filter(|a| state.map(|s| a.state == s).unwrap_or(true))
+      .cloned()
+      .collect();
+  }
+
+  /// An alert, if it's kept.
+  pub(crate) fn get(&self, id: Uuid) -> Option<Alert> {
+    return self.lock().alerts.iter().rev().find(|a| a.id == id).cloned();
+  }
+}
@@ -0,0 +1,58 @@
+//! Maintenance windows. A mute covers some sensors for a while, and alerts
+//! about them don't open, or escalate, in the meantime. Readings are still
+//! stored and flagged as ever, and alerts already open still resolve.
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, SensorId};
+
+/// Some sensors, muted for a while. Whatever scope is left out covers
+/// everything: a mute with none at all mutes every alert.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Mute {
+  /// Unique ID, for ending it early.
+  pub(crate) id: Uuid,
+  /// Only sensors of this type. Device alerts have no type, so they're
+  /// never covered by a mute with one.
+  pub(crate) sensor_type: Option<SensorType>,
+  /// Only the sensor, or device, with this ID.
+  pub(crate) sensor_id: Option<SensorId>,
+  /// Only sensors at this site, by its broker's UID.
+  pub(crate) broker_id: Option<BrokerId>,
+  /// When it starts.
+  pub(crate) from: DateTime<Local>,
+  /// When it's over.
+  pub(crate) until: DateTime<Local>,
+  /// Who asked for it.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) by: Option<String>,
+  /// What for.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) reason: Option<String>
+}
+
+impl Mute {
+  /// Whether it's in effect at a time.
+  pub(crate) fn active_at(&self, when: DateTime<Local>) -> bool {
+    return self.from <= when && when < self.until;
+  }
+
+  /// Whether it's over by a time.
+  pub(crate) fn is_over(&self, when: DateTime<Local>) -> bool {
+    return self.until <= when;
+  }
+
+  /// Whether it covers a sensor, in effect or not.
+  pub(crate) fn covers(
+    &self, sensor_type: Option<SensorType>, sensor_id: SensorId,
+    broker_id: Option<BrokerId>
+  ) -> bool {
+    let type_ok = self.sensor_type.is_none() || self.sensor_type == sensor_type;
+    let id_ok = self.sensor_id.map(|id| id == sensor_id).unwrap_or(true);
+    let site_ok = self.broker_id.is_none() || self.broker_id == broker_id;
+    return type_ok && id_ok && site_ok;
+  }
+}
@@ -0,0 +1,153 @@
+//! Alert rules: conditions over the latest readings of any number of
+//! sensors, written in the expr language, like
+//!
+//! ```text
+//! temperature:1 > 30 and (humidity:3 < 20 or humidity:4 < 20)
+//! ```
+//!
+//! An alert opens when the condition starts holding and resolves when it
+//! stops. A rule that can't be told, because a sensor it reads has never
+//! been heard from, or not lately, leaves its alert be. Rules may only apply
+//! in some arming states, and don't hold in the others.
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+
+use libcdp::comm::broker_api::BrokerMessagePayload;
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::SensorId;
+
+use crate::alerts::AlertSeverity;
+use crate::arming::ArmingState;
+use crate::expr::Expr;
+use crate::lastvalue::LastValueCache;
+
+/// How old, in seconds, a reading may be for rules to go by it, unless a
+/// rule says otherwise.
+const DEFAULT_MAX_AGE_SECS: u64 = 600;
+
+/// An alert rule, as written in the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct AlertRuleFile {
+  /// Unique name.
+  name: String,
+  /// The condition, see the expr module.
+  when: String,
+  /// What to say when it holds. None means the condition itself.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  summary: Option<String>,
+  /// How much it matters. None means whatever rule alerts are.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  severity: Option<AlertSeverity>,
+  /// Arming states it applies in. Empty means all of them.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  armed: Vec<ArmingState>,
+  /// How old, in seconds, a reading may be for it to go by. None means 600.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  max_age_secs: Option<u64>
+}
+
+/// An alert rule, parsed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "AlertRuleFile", into = "AlertRuleFile")]
+pub(crate) struct AlertRule {
+  /// Unique name, which its alerts go by.
+  pub(crate) name: String,
+  /// The condition, as written in config.
+  pub(crate) source: String,
+  /// The parsed condition.
+  expr: Expr,
+  /// What to say when it holds.
+  summary: Option<String>,
+  /// How much it matters, if it says.
+  pub(crate) severity: Option<AlertSeverity>,
+  /// Arming states it applies in. Empty means all of them.
+  armed: Vec<ArmingState>,
+  /// How old, in seconds, a reading may be for it to go by, if it says.
+  max_age_secs: Option<u64>
+}
+
+impl TryFrom<AlertRuleFile> for AlertRule {
+  type Error = String;
+  fn try_from(file: AlertRuleFile) -> Result<Self, Self::Error> {
+    let expr = Expr::from_str(&file.when)
+      .map_err(|e| format!("Alert rule \"{}\": {}", file.name, e))?;
+    return Ok(Self {
+      name: file.name,
+      source: file.when,
+      expr: expr,
+      summary: file.summary,
+      severity: file.severity,
+      armed: file.armed,
+      max_age_secs: file.max_age_secs
+    });
+  }
+}
+
+impl From<AlertRule> for AlertRuleFile {
+  fn from(rule: AlertRule) -> Self {
+    return Self {
+      name: rule.name,
+      when: rule.source,
+      summary: rule.summary,
+      severity: rule.severity,
+      armed: rule.armed,
+      max_age_secs: rule.max_age_secs
+    };
+  }
+}
+
+impl AlertRule {
+  /// Every sensor it reads.
+  pub(crate) fn sensors(&self) -> Vec<(SensorType, SensorId)> {
+    return self.expr.sensors();
+  }
+
+  /// Whether any of the given sensors is read by it.
+  pub(crate) fn depends_on(&self, touched: &HashSet<(SensorType, SensorId)>)
+  -> bool {
+    return self.sensors().iter().any(|k| touched.contains(k));
+  }
+
+  /// How old a reading may be for it to go by.
+  fn max_age(&self) -> Duration {
+    let secs = self.max_age_secs.unwrap_or(DEFAULT_MAX_AGE_SECS);
+    return Duration::seconds(secs.min(i64::MAX as u64) as i64);
+  }
+
+  /// Whether it holds as of the latest readings, armed as the system is.
+  /// Readings older than its max age as of now count as never heard from.
+  /// None if it can't be told.
+  pub(crate) fn holds(
+    &self, lvc: &LastValueCache, armed: ArmingState, now: DateTime<Local>
+  ) -> Option<bool> {
+    if !self.armed.is_empty() && !self.armed.contains(&armed) {
+      return Some(false);
+    }
+    let oldest = now - self.max_age();
+    return self.expr.holds(&|stype, sensor_id| {
+      let msg = lvc.get(stype, sensor_id)?;
+      if msg.received_when.unwrap_or(msg.constructed_when) < oldest {
+        return None;
+      }
+      return match msg.payload {
+        BrokerMessagePayload::SensorData(sd) => {
+          Some(sd.reading().human_value())
+        },
+        _ => None,
+      };
+    });
+  }
+
+  /// What its alerts say.
+  pub(crate) fn summary(&self) -> String {
+    return match &self.summary {
+      Some(s) => s.clone(),
+      None => format!("Rule {} holds: {}", self.name, self.source),
+    };
+  }
+}
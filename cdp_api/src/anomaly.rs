@@ -0,0 +1,113 @@
+//! Online anomaly detection. Keeps an exponentially-weighted mean and
+//! variance per sensor and flags readings that stray too far from them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::SensorId;
+
+/// Detection parameters for a sensor type.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) struct AnomalyParams {
+  /// Smoothing factor, in (0, 1]. Higher adapts faster.
+  #[serde(default = "AnomalyParams::default_alpha")]
+  pub(crate) alpha: f64,
+  /// Readings with a z-score above this are flagged.
+  #[serde(default = "AnomalyParams::default_threshold")]
+  pub(crate) threshold: f64,
+  /// Readings to observe before flagging anything.
+  #[serde(default = "AnomalyParams::default_warmup")]
+  pub(crate) warmup: usize
+}
+
+impl AnomalyParams {
+  /// For serde.
+  fn default_alpha() -> f64 {
+    return 0.1;
+  }
+
+  /// For serde.
+  fn default_threshold() -> f64 {
+    return 4.0;
+  }
+
+  /// For serde.
+  fn default_warmup() -> usize {
+    return 20;
+  }
+}
+
+/// Running statistics of a single sensor.
+#[derive(Clone, Copy, Debug, Default)]
+struct EwmaState {
+  /// Smoothed mean.
+  mean: f64,
+  /// Smoothed variance.
+  var: f64,
+  /// Readings seen so far.
+  count: usize
+}
+
+impl EwmaState {
+  /// Updates the state with a new value. Returns the z-score of the value
+  /// against the state *before* the update, once warmed up.
+  fn observe(&mut self, x: f64, params: &AnomalyParams) -> Option<f64> {
+    self.count += 1;
+    if self.count == 1 {
+      self.mean = x;
+      return None;
+    }
+    let diff = x - self.mean;
+    let z = if self.var > 0.0 {
+      Some(diff.abs() / self.var.sqrt())
+    } else {
+      None
+    };
+    self.mean += params.alpha * diff;
+    self.var = (1.0 - params.alpha) * (self.var + params.alpha * diff * diff);
+    return if self.count > params.warmup { z } else { None };
+  }
+}
+
+/// Per-sensor anomaly detector. Cheap to clone, all clones share the same
+/// state.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AnomalyDetector {
+  /// Parameters per sensor type. Types not in here aren't checked.
+  params: HashMap<SensorType, AnomalyParams>,
+  /// State per sensor.
+  state: Arc<Mutex<HashMap<(SensorType, SensorId), EwmaState>>>
+}
+
+impl AnomalyDetector {
+  /// Creates a detector with the given per-type parameters.
+  pub(crate) fn new(params: HashMap<SensorType, AnomalyParams>) -> Self {
+    return Self {
+      params: params,
+      state: Arc::new(Mutex::new(HashMap::new()))
+    };
+  }
+
+  /// Feeds a message about to be ingested to the detector, setting its
+  /// anomaly_score if it's an outlier. Returns the score, if flagged.
+  pub(crate) fn check(&self, msg: &mut BrokerMessage) -> Option<f64> {
+    if let BrokerMessagePayload::SensorData(sd) = &msg.payload {
+      let params = self.params.get(&sd.sensor_type())?;
+      let key = (sd.sensor_type(), sd.sensor_id());
+      let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+      let z = state
+        .entry(key)
+        .or_default()
+        .observe(sd.reading().human_value(), params)?;
+      if z > params.threshold {
+        msg.anomaly_score = Some(z);
+        return Some(z);
+      }
+    }
+    return None;
+  }
+}
@@ -1,43 +1,397 @@
 //! Abstracts away inner API state and config.
 
+mod cors;
+mod error;
 mod handlers;
+mod views;
 
-use actix_web::{App, HttpServer, web};
+use std::path::PathBuf;
+use std::sync::Arc;
 
+use actix_web::{App, HttpServer, guard, web};
+use actix_web::dev::Service;
+use actix_web::http::header::ORIGIN;
+use futures::future::{Either, ready};
+#[cfg(feature = "systemd")]
+use libcdp::systemd::{self, ActivatedListener};
+
+use crate::alerts::AlertBook;
+use crate::anomaly::AnomalyDetector;
+pub(crate) use crate::api::cors::CorsConfig;
+use crate::api::error::ApiError;
+use crate::arming::Arming;
 use crate::config::ApiConfig;
-use crate::db::ApiDatabase;
+use crate::db::{ApiDatabase, AsyncApiDatabase};
+use crate::feed::MessageFeed;
+use crate::grpc::GrpcIngest;
+use crate::health::ProcessInfo;
+use crate::ingest::Intake;
+use crate::lastvalue::LastValueCache;
+use crate::presence::Presence;
+use crate::ratelimit::RateLimits;
+use crate::stats::IngestStats;
+use crate::supervisor::Jobs;
+#[cfg(unix)]
+use crate::uds;
+use crate::wal::WriteAheadLog;
 
 /// Contains the whole state of the API.
 #[derive(Clone)]
-pub(crate) struct Api<D: ApiDatabase> {
+pub(crate) struct Api<D: AsyncApiDatabase> {
   /// Configuration loaded from files.
   pub(crate) config: ApiConfig,
   /// Database configuration.
-  pub(crate) db_config: D::DbConfig,
+  pub(crate) db_config: <D::Sync as ApiDatabase>::DbConfig,
   /// API database connection.
-  pub(crate) db: D
+  pub(crate) db: D,
+  /// Latest reading of every sensor.
+  pub(crate) last_values: LastValueCache,
+  /// Outlier detector for incoming readings.
+  pub(crate) anomalies: AnomalyDetector,
+  /// Alerts, open or not.
+  pub(crate) alerts: AlertBook,
+  /// How the system is armed.
+  pub(crate) arming: Arming,
+  /// Who's home.
+  pub(crate) presence: Presence,
+  /// Ingestion counters and rates.
+  pub(crate) ingest_stats: IngestStats,
+  /// Request rate limiters.
+  pub(crate) rate_limits: RateLimits,
+  /// Facts about the running process.
+  pub(crate) process: ProcessInfo,
+  /// Log of incoming bundles.
+  pub(crate) wal: WriteAheadLog,
+  /// Freshly stored messages, for live watchers.
+  pub(crate) feed: MessageFeed,
+  /// Background jobs, and how they're doing.
+  pub(crate) jobs: Jobs
 }
 
-impl<D: ApiDatabase + 'static> Api<D> {
+impl<D: AsyncApiDatabase> Api<D> {
+  /// What every transport hands its bundles to.
+  pub(crate) fn intake(&self) -> Intake<D> {
+    return Intake {
+      db: self.db.clone(),
+      lvc: self.last_values.clone(),
+      anm: self.anomalies.clone(),
+      alr: self.alerts.clone(),
+      ist: self.ingest_stats.clone(),
+      rls: self.rate_limits.clone(),
+      wal: self.wal.clone(),
+      feed: self.feed.clone(),
+      derived: self.config.derived.clone()
+    };
+  }
+
   /// Say something generic when people hit up /.
   pub(crate) async fn run_server(&self) -> std::io::Result<()> {
+    if let Some(addr) = self.config.grpc_bind {
+      GrpcIngest::spawn(self.clone(), addr);
+    }
     // init server
     let dbc = self.db.clone();
+    let cfg = self.config.clone();
+    let lvc = self.last_values.clone();
+    let anm = self.anomalies.clone();
+    let alr = self.alerts.clone();
+    let arm = self.arming.clone();
+    let prs = self.presence.clone();
+    let ist = self.ingest_stats.clone();
+    let rls = self.rate_limits.clone();
+    let cors = self.config.cors.clone().map(Arc::new);
+    let pinfo = self.process.clone();
+    let wal = self.wal.clone();
+    let feed = self.feed.clone();
+    let jobs = self.jobs.clone();
+    let intake = self.intake();
     let mut srv = HttpServer::new(move || {
+      let ip_limits = rls.clone();
+      let cors = cors.clone();
       App::new()
+        .wrap_fn(move |req, srv| {
+          // per-IP limit, before anything else gets to run
+          let ip = req.peer_addr().map(|a| a.ip());
+          if ip_limits.check_ip(ip) {
+            return Either::Left(srv.call(req));
+          }
+          let resp = ApiError::rate_limited().response();
+          return Either::Right(ready(Ok(req.into_response(resp))));
+        })
+        .wrap_fn(move |req, srv| {
+          // CORS, if enabled and the origin is one we like
+          let origin = req.headers()
+            .get(ORIGIN)
+            .and_then(|v| v.to_str().ok())
+            .map(|o| o.to_owned());
+          let allowed = match (&cors, origin) {
+            (Some(c), Some(o)) if c.allows_origin(&o) => Some((c.clone(), o)),
+            _ => None,
+          };
+          if let Some((c, o)) = &allowed {
+            if CorsConfig::is_preflight(req.method(), req.headers()) {
+              let resp = c.preflight(o, req.headers());
+              return Either::Right(ready(Ok(req.into_response(resp))));
+            }
+          }
+          let fut = srv.call(req);
+          return Either::Left(async move {
+            let mut res = fut.await?;
+            if let Some((c, o)) = allowed {
+              c.decorate(res.headers_mut(), &o);
+            }
+            return Ok(res);
+          });
+        })
         .data(dbc.clone())
+        .data(cfg.clone())
+        .data(lvc.clone())
+        .data(anm.clone())
+        .data(alr.clone())
+        .data(arm.clone())
+        .data(prs.clone())
+        .data(ist.clone())
+        .data(rls.clone())
+        .data(pinfo.clone())
+        .data(wal.clone())
+        .data(feed.clone())
+        .data(jobs.clone())
+        .data(intake.clone())
+        // extractor failures get the same envelope as everything else
+        .app_data(web::JsonConfig::default().error_handler(error::json_error))
+        .app_data(web::QueryConfig::default().error_handler(error::query_error))
+        .app_data(web::PathConfig::default().error_handler(error::path_error))
+        .default_service(web::route().to(|| {
+          ApiError::not_found("not_found", "Nothing here.").response()
+        }))
         .route("/", web::get().to(handlers::index::<D>))
+        .route("/healthz", web::get().to(handlers::healthz))
+        .route("/readyz", web::get().to(handlers::readyz::<D>))
         .route("/heartbeat", web::post().to(handlers::heartbeat::<D>))
-        .route("/bundle", web::post().to(handlers::bundle::<D>))
+        .route("/enroll", web::post().to(handlers::enroll::<D>))
+        .route("/brokers", web::get().to(handlers::brokers::<D>))
+        .route("/brokers/{uid}", web::get().to(handlers::broker::<D>))
+        .route("/fleet/versions", web::get().to(handlers::fleet_versions::<D>))
+        .service(
+          web::resource("/bundle")
+            .app_data(web::PayloadConfig::new(cfg.max_bundle_bytes))
+            .route(
+              web::post()
+                .guard(guard::fn_guard(handlers::is_ndjson))
+                .to(handlers::bundle_ndjson::<D>)
+            )
+            .route(web::post().to(handlers::bundle::<D>))
+        )
+        .route(
+          "/ingest/webhook/{source}",
+          web::post().to(handlers::webhook::<D>)
+        )
         .route("/messages/sensor", web::get().to(handlers::all_sensor::<D>))
+        .route(
+          "/messages/sensor/{sensor_type}",
+          web::get().to(handlers::sensor_range::<D>)
+        )
+        .route(
+          "/messages/{id}/raw",
+          web::get().to(handlers::message_raw::<D>)
+        )
+        .route("/current", web::get().to(handlers::current))
+        .route("/changes", web::get().to(handlers::changes))
+        .route("/anomalies", web::get().to(handlers::anomalies::<D>))
+        .route("/alerts", web::get().to(handlers::alerts))
+        .route("/alerts.ics", web::get().to(handlers::alerts_ics))
+        .route("/alerts/mute", web::post().to(handlers::mute_alerts::<D>))
+        .route("/alerts/mutes", web::get().to(handlers::mutes))
+        .route(
+          "/alerts/mutes/{id}",
+          web::delete().to(handlers::unmute::<D>)
+        )
+        .route("/alerts/{id}", web::get().to(handlers::alert))
+        .route(
+          "/alerts/{id}/ack",
+          web::post().to(handlers::ack_alert::<D>)
+        )
+        .route("/arming", web::get().to(handlers::arming))
+        .route("/arming", web::put().to(handlers::set_arming::<D>))
+        .route("/presence", web::get().to(handlers::presence))
+        .route(
+          "/presence",
+          web::post().to(handlers::report_presence::<D>)
+        )
+        .route("/audit", web::get().to(handlers::audit_log::<D>))
+        .route("/stats/ingest", web::get().to(handlers::ingest_stats))
+        .route("/stats/latency", web::get().to(handlers::latency_stats))
+        .route("/stats/jobs", web::get().to(handlers::job_stats))
+        .route(
+          "/stats/sensor/{sensor_type}/{sensor_id}/percentiles",
+          web::get().to(handlers::percentiles::<D>)
+        )
+        .route(
+          "/stats/sensor/{sensor_type}/{sensor_id}/histogram",
+          web::get().to(handlers::histogram::<D>)
+        )
+        .route("/metrics", web::get().to(handlers::metrics))
+        .route("/derived", web::get().to(handlers::derived_sensors::<D>))
+        .route(
+          "/derived/{name}",
+          web::get().to(handlers::derived_readings::<D>)
+        )
+        .route("/calibrations", web::get().to(handlers::calibrations::<D>))
+        .route(
+          "/calibrations/{sensor_type}/{sensor_id}",
+          web::put().to(handlers::set_calibration::<D>)
+        )
+        .route(
+          "/calibrations/{sensor_type}/{sensor_id}",
+          web::delete().to(handlers::remove_calibration::<D>)
+        )
+        .route("/sensors", web::get().to(handlers::sensors::<D>))
+        .route(
+          "/sensors/{sensor_type}/{sensor_id}",
+          web::put().to(handlers::set_sensor_info::<D>)
+        )
+        .route(
+          "/sensors/{sensor_type}/{sensor_id}",
+          web::delete().to(handlers::remove_sensor_info::<D>)
+        )
+        .route("/rooms", web::get().to(handlers::rooms::<D>))
+        .route(
+          "/rooms/{room}/current",
+          web::get().to(handlers::room_current::<D>)
+        )
+        .route(
+          "/forecast/{sensor_type}/{sensor_id}",
+          web::get().to(handlers::forecast::<D>)
+        )
+        .route("/reports", web::get().to(handlers::reports::<D>))
+        .route("/reports/{id}", web::get().to(handlers::report::<D>))
+        .route("/floorplan", web::get().to(handlers::floorplan::<D>))
+        .route("/floorplan", web::put().to(handlers::set_floorplan::<D>))
+        .route(
+          "/devices/low_battery",
+          web::get().to(handlers::low_battery::<D>)
+        )
+        .route(
+          "/decode-failures",
+          web::get().to(handlers::decode_failure_reports::<D>)
+        )
+        .route(
+          "/devices/decode_failures",
+          web::get().to(handlers::decode_failures::<D>)
+        )
+        .route(
+          "/admin/wal/replay",
+          web::post().to(handlers::replay_wal::<D>)
+        )
+        .route(
+          "/admin/brokers/{uid}/rotate_key",
+          web::post().to(handlers::rotate_broker_key::<D>)
+        )
+        .route(
+          "/admin/brokers/{uid}/approve",
+          web::post().to(handlers::approve_broker::<D>)
+        )
+        .route(
+          "/admin/brokers/{uid}/config",
+          web::put().to(handlers::set_broker_config::<D>)
+        )
+        .route(
+          "/admin/brokers/{uid}/update",
+          web::post().to(handlers::schedule_update::<D>)
+        )
+        .route("/admin/backup", web::get().to(handlers::backup::<D>))
+        .service(
+          web::resource("/admin/restore")
+            .app_data(web::PayloadConfig::new(cfg.max_restore_bytes))
+            .route(web::post().to(handlers::restore::<D>))
+        )
     });
+    // tune it, if asked to
+    let sc = &self.config.server;
+    if let Some(n) = sc.workers {
+      srv = srv.workers(n);
+    }
+    if let Some(secs) = sc.keep_alive_secs {
+      // None turns it off.
+      srv = srv.keep_alive(Some(secs).filter(|s| *s > 0));
+    }
+    if let Some(ms) = sc.client_timeout_msec {
+      srv = srv.client_timeout(ms);
+    }
+    if let Some(n) = sc.max_connections {
+      srv = srv.max_connections(n);
+    }
+    // sockets systemd opened for us take the place of cfg'd addrs
+    #[cfg(feature = "systemd")]
+    let binds: &[String] = {
+      let activated = systemd::listen_fds();
+      let binds: &[String] = if activated.is_empty() {
+        &self.config.binds
+      } else {
+        &[]
+      };
+      for lfd in activated {
+        srv = match lfd.into_listener() {
+          ActivatedListener::Tcp(l) => {
+            info!("Listening on {} from systemd...", l.local_addr()?);
+            srv.listen(l)?
+          },
+          ActivatedListener::Unix(l) => {
+            info!("Listening on a Unix socket from systemd...");
+            srv.listen_uds(l)?
+          },
+        };
+      }
+      binds
+    };
+    #[cfg(not(feature = "systemd"))]
+    let binds: &[String] = &self.config.binds;
     // bind to cfg'd addrs
-    for addr in self.config.binds.iter() {
-      println!("Binding to {}...", &addr);
+    let mut sockets: Vec<PathBuf> = Vec::new();
+    for addr in binds.iter() {
+      info!("Binding to {}...", &addr);
+      #[cfg(unix)]
+      if let Some(path) = addr.strip_prefix(uds::PREFIX) {
+        let path = PathBuf::from(path);
+        uds::clear_stale(&path)?;
+        srv = srv.bind_uds(&path)?;
+        if let Some(mode) = self.config.unix_socket_mode {
+          uds::set_mode(&path, mode)?;
+        }
+        sockets.push(path);
+        continue;
+      }
       srv = srv.bind(addr)?;
     }
     // showtime!
-    println!("API is up!");
-    return srv.run().await;
+    info!("API is up!");
+    let server = srv.run();
+    #[cfg(feature = "systemd")]
+    notify_systemd();
+    let res = server.await;
+    #[cfg(feature = "systemd")]
+    systemd::stopping();
+    // leave no stale sockets behind, if we can help it
+    for path in sockets {
+      if let Err(e) = std::fs::remove_file(&path) {
+        warn!("Couldn't remove {}: {}", path.display(), e);
+      }
+    }
+    return res;
+  }
+}
+
+/// Tells systemd we're up, with the database connected and every listener
+/// bound, and keeps its watchdog fed for as long as the server is.
+#[cfg(feature = "systemd")]
+fn notify_systemd() {
+  systemd::ready();
+  if let Some(every) = systemd::watchdog_interval() {
+    actix_web::rt::spawn(async move {
+      loop {
+        actix_web::rt::time::delay_for(every).await;
+        systemd::watchdog_ping();
+      }
+    });
   }
 }
@@ -0,0 +1,94 @@
+//! Minimal CORS handling, so dashboards hosted elsewhere can call the read
+//! endpoints from a browser.
+
+use actix_web::HttpResponse;
+use actix_web::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use actix_web::http::header::{ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, VARY};
+use serde::{Deserialize, Serialize};
+
+use crate::api::error::ApiError;
+
+/// CORS configuration, as it lies in the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CorsConfig {
+  /// Origins allowed to make requests, like "https://dash.example.com".
+  /// "*" allows any origin.
+  pub(crate) allowed_origins: Vec<String>,
+  /// Methods allowed in cross-origin requests.
+  #[serde(default = "CorsConfig::default_methods")]
+  pub(crate) allowed_methods: Vec<String>,
+  /// Request headers allowed in cross-origin requests.
+  #[serde(default = "CorsConfig::default_headers")]
+  pub(crate) allowed_headers: Vec<String>,
+  /// How long browsers may cache preflight results, in seconds.
+  #[serde(default = "CorsConfig::default_max_age")]
+  pub(crate) max_age_secs: usize
+}
+
+impl CorsConfig {
+  /// For serde. Read-only by default.
+  fn default_methods() -> Vec<String> {
+    return vec!["GET".to_owned()];
+  }
+
+  /// For serde.
+  fn default_headers() -> Vec<String> {
+    return vec!["Content-Type".to_owned()];
+  }
+
+  /// For serde.
+  fn default_max_age() -> usize {
+    return 3600;
+  }
+
+  /// Whether requests from this origin are allowed.
+  pub(crate) fn allows_origin(&self, origin: &str) -> bool {
+    return self.allowed_origins.iter().any(|o| o == "*" || o == origin);
+  }
+
+  /// Whether the method is allowed in cross-origin requests.
+  fn allows_method(&self, method: &str) -> bool {
+    return self.allowed_methods
+      .iter()
+      .any(|m| m.eq_ignore_ascii_case(method));
+  }
+
+  /// Whether this is a preflight request we should answer ourselves.
+  pub(crate) fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+    return method == Method::OPTIONS
+      && headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+  }
+
+  /// Answers a preflight request from an allowed origin.
+  pub(crate) fn preflight(&self, origin: &str, headers: &HeaderMap)
+  -> HttpResponse {
+    let requested = headers
+      .get(ACCESS_CONTROL_REQUEST_METHOD)
+      .and_then(|v| v.to_str().ok())
+      .unwrap_or("");
+    if !self.allows_method(requested) {
+      return ApiError::new(
+        StatusCode::FORBIDDEN, "cors_forbidden", "Method not allowed by CORS."
+      ).response();
+    }
+    let mut resp = HttpResponse::NoContent().finish();
+    self.decorate(resp.headers_mut(), origin);
+    let hdrs = resp.headers_mut();
+    if let Ok(v) = HeaderValue::from_str(&self.allowed_methods.join(", ")) {
+      hdrs.insert(ACCESS_CONTROL_ALLOW_METHODS, v);
+    }
+    if let Ok(v) = HeaderValue::from_str(&self.allowed_headers.join(", ")) {
+      hdrs.insert(ACCESS_CONTROL_ALLOW_HEADERS, v);
+    }
+    hdrs.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(self.max_age_secs));
+    return resp;
+  }
+
+  /// Adds the allow-origin headers to a response to an allowed origin.
+  pub(crate) fn decorate(&self, headers: &mut HeaderMap, origin: &str) {
+    if let Ok(v) = HeaderValue::from_str(origin) {
+      headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, v);
+      headers.insert(VARY, HeaderValue::from_static("Origin"));
+    }
+  }
+}
@@ -0,0 +1,121 @@
+//! The one shape every error response takes: a JSON envelope with a code to
+//! match on, a message for humans and, for bundles, which message was the
+//! culprit. 4xx means the client should fix something, 5xx means we should.
+
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::error::{InternalError, JsonPayloadError, PathError, QueryPayloadError};
+use actix_web::http::StatusCode;
+use serde::Serialize;
+
+/// An error, as handed to clients.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ApiError {
+  /// What went wrong, in snake_case, stable enough to match on.
+  pub(crate) code: &'static str,
+  /// What went wrong, in words.
+  pub(crate) message: String,
+  /// Position, within the bundle, of the message at fault, if any.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub(crate) index: Option<usize>,
+  /// The HTTP status to answer with.
+  #[serde(skip)]
+  status: StatusCode
+}
+
+impl ApiError {
+  /// An error with any status.
+  pub(crate) fn new<S: Into<String>>(
+    status: StatusCode, code: &'static str, message: S
+  ) -> Self {
+    return Self {
+      code: code,
+      message: message.into(),
+      index: None,
+      status: status
+    };
+  }
+
+  /// The request makes no sense.
+  pub(crate) fn bad_request<S: Into<String>>(code: &'static str, message: S)
+  -> Self {
+    return Self::new(StatusCode::BAD_REQUEST, code, message);
+  }
+
+  /// The request makes sense, but what's in it doesn't check out.
+  pub(crate) fn unprocessable<S: Into<String>>(
+    code: &'static str, message: S
+  ) -> Self {
+    return Self::new(StatusCode::UNPROCESSABLE_ENTITY, code, message);
+  }
+
+  /// There's nothing there.
+  pub(crate) fn not_found<S: Into<String>>(code: &'static str, message: S)
+  -> Self {
+    return Self::new(StatusCode::NOT_FOUND, code, message);
+  }
+
+  /// Our fault. The details go to the log, not to the client.
+  pub(crate) fn internal(code: &'static str) -> Self {
+    return Self::new(
+      StatusCode::INTERNAL_SERVER_ERROR, code, "Something broke on our end."
+    );
+  }
+
+  /// Admins only, and the request didn't say it was one.
+  pub(crate) fn unauthorized() -> Self {
+    return Self::new(StatusCode::UNAUTHORIZED, "unauthorized", "Admins only.");
+  }
+
+  /// Too many requests.
+  pub(crate) fn rate_limited() -> Self {
+    return Self::new(
+      StatusCode::TOO_MANY_REQUESTS, "rate_limited", "Slow down."
+    );
+  }
+
+  /// Points at the message at fault.
+  pub(crate) fn at(mut self, index: usize) -> Self {
+    self.index = Some(index);
+    return self;
+  }
+
+  /// The response to answer with.
+  pub(crate) fn response(&self) -> HttpResponse {
+    return HttpResponse::build(self.status).json(self);
+  }
+}
+
+impl From<ApiError> for HttpResponse {
+  fn from(e: ApiError) -> Self {
+    return e.response();
+  }
+}
+
+/// For JsonConfig, so bad JSON bodies get the envelope too.
+pub(crate) fn json_error(err: JsonPayloadError, _: &HttpRequest)
+-> actix_web::Error {
+  let e = match &err {
+    JsonPayloadError::Deserialize(de) if de.is_data() => {
+      ApiError::unprocessable("invalid_body", de.to_string())
+    },
+    JsonPayloadError::Overflow => ApiError::new(
+      StatusCode::PAYLOAD_TOO_LARGE, "body_too_large", err.to_string()
+    ),
+    _ => ApiError::bad_request("bad_body", err.to_string()),
+  };
+  return InternalError::from_response(err, e.response()).into();
+}
+
+/// For QueryConfig, so bad query strings get the envelope too.
+pub(crate) fn query_error(err: QueryPayloadError, _: &HttpRequest)
+-> actix_web::Error {
+  let e = ApiError::bad_request("bad_query", err.to_string());
+  return InternalError::from_response(err, e.response()).into();
+}
+
+/// For PathConfig, so bad paths get the envelope too.
+pub(crate) fn path_error(err: PathError, _: &HttpRequest)
+-> actix_web::Error {
+  let e = ApiError::not_found("not_found", err.to_string());
+  return InternalError::from_response(err, e.response()).into();
+}
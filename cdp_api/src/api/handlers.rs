@@ -1,44 +1,2140 @@
 //! Implement request handlers for the API.
 
-use actix_web::{web, HttpResponse};
-use chrono::Local;
-use libcdp::comm::broker_api::{BrokerMessage, BrokerMessageBundle, BrokerMessagePayloadType};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::str::FromStr;
 
-use crate::db::ApiDatabase;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use actix_web::dev::{Payload, RequestHead};
+use actix_web::error::{BlockingError, InternalError};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE};
+use chrono::{DateTime, Duration, Local, TimeZone};
+use futures::StreamExt;
+use futures::stream;
+use futures::future::{Ready, ready};
+use libcdp::comm::broker_api::{KEY_HEADER, NDJSON_CONTENT_TYPE, BrokerMessage, BrokerMessageBundle, BundleAck, BrokerMessagePayload, BrokerMessagePayloadType, HeartbeatMessage, RemoteConfig};
+use libcdp::comm::enrollment::{EnrollRequest, EnrollState};
+use libcdp::comm::sealing;
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, MessageId, SensorId};
+use libcdp::proto::{self, ProtoError};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::alerts::{AckError, AlertBook, AlertState, Mute};
+use crate::anomaly::AnomalyDetector;
+use crate::api::error::ApiError;
+use crate::backup;
+use crate::api::views::{self, BrokerMessageView, ChangesView, DecodeFailureView, DerivedSensorView, ForecastView, NamedReadingView, ProblemDeviceView, RawPayloadView, RoomCurrentView, RoomView};
+use crate::arming::{Arming, ArmingError, ArmingState};
+use crate::audit::{self, AuditEntry};
+use crate::brokers::{self, BrokerRecord, FleetVersions, HeartbeatError};
+use crate::calibration::Calibration;
+use crate::config::ApiConfig;
+use crate::db::{
+  ApiDatabase, AsyncApiDatabase, DbError, MessageFilter, MessageOrder, News,
+  Pages
+};
+use crate::enrollment::{self, EnrollError};
+use crate::distribution::{Histogram, Summary};
+use crate::floorplan::Floorplan;
+use crate::forecast::{ForecastMethod, StepSeries};
+use crate::health::{self, Liveness, ProcessInfo};
+use crate::ics::{Calendar, Event};
+use crate::ingest::{self, IngestError, Ingested, Intake, StoreError};
+use crate::lastvalue::LastValueCache;
+use crate::migrate::MigrationError;
+use crate::presence::{Presence, PresenceState};
+use crate::sensors::{RegisteredSensor, SensorInfo};
+use crate::ratelimit::RateLimits;
+use crate::reports::ReportPeriod;
+use crate::stats::IngestStats;
+use crate::supervisor::Jobs;
+use crate::updates::{self, UpdateRequest};
+use crate::wal::WriteAheadLog;
 
 /// Handles request to /. Nothing special.
-pub(crate) async fn index<D: ApiDatabase>(_: web::Data<D>)
+pub(crate) async fn index<D: AsyncApiDatabase>(_: web::Data<D>)
 -> HttpResponse {
   return HttpResponse::Ok().body("API up!");
 }
 
-/// We'll do a lil' checkin' later.
-pub(crate) async fn heartbeat<D: ApiDatabase>(_: web::Data<D>)
+/// Liveness probe. If we can answer, we're alive.
+pub(crate) async fn healthz(pinfo: web::Data<ProcessInfo>) -> HttpResponse {
+  return HttpResponse::Ok().json(Liveness::from(pinfo.get_ref()));
+}
+
+/// Readiness probe. Checks everything we depend on.
+pub(crate) async fn readyz<D: AsyncApiDatabase>(
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>,
+  jobs: web::Data<Jobs>
+) -> HttpResponse {
+  let rd = health::check_readiness(db.get_ref(), cfg.get_ref(), &jobs).await;
+  if rd.is_ready() {
+    return HttpResponse::Ok().json(rd);
+  } else {
+    return HttpResponse::ServiceUnavailable().json(rd);
+  }
+}
+
+/// Takes note of a broker's heartbeat, and whatever health figures came with
+/// it. We'll do a lil' checkin' of the key later.
+pub(crate) async fn heartbeat<D: AsyncApiDatabase>(
+  hb: web::Json<HeartbeatMessage>,
+  db: web::Data<D>
+) -> HttpResponse {
+  let hb = hb.into_inner();
+  return match db.blocking(move |db| brokers::heartbeat(db, &hb)).await {
+    Ok(reply) => HttpResponse::Ok().json(reply),
+    Err(HeartbeatError::WrongKey) => ApiError::unauthorized().response(),
+    Err(HeartbeatError::Pending) => ApiError::new(
+      StatusCode::FORBIDDEN, "pending", "Enrollment not approved yet."
+    ).response(),
+    Err(HeartbeatError::Incompatible) => ApiError::new(
+      StatusCode::UPGRADE_REQUIRED, "incompatible", "Incompatible protocol."
+    ).response(),
+    Err(HeartbeatError::Db(e)) => db_error(e),
+  };
+}
+
+/// Lists every broker we've heard from, with their latest health figures.
+pub(crate) async fn brokers<D: AsyncApiDatabase>(db: web::Data<D>)
 -> HttpResponse {
-  return HttpResponse::Ok().body("OK");
+  return match db.brokers().await {
+    Ok(b) => HttpResponse::Ok().json(
+      b.into_iter().map(BrokerRecord::redacted).collect::<Vec<_>>()
+    ),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Tallies up what the fleet runs, and who doesn't get along with us.
+pub(crate) async fn fleet_versions<D: AsyncApiDatabase>(db: web::Data<D>)
+-> HttpResponse {
+  return match db.brokers().await {
+    Ok(b) => HttpResponse::Ok().json(FleetVersions::from_records(&b)),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Shows the latest health figures of a single broker.
+pub(crate) async fn broker<D: AsyncApiDatabase>(
+  path: web::Path<String>,
+  db: web::Data<D>
+) -> HttpResponse {
+  let uid = match path.parse::<BrokerId>() {
+    Ok(u) => u,
+    Err(_) => return no_such_broker(),
+  };
+  return match db.broker(uid).await {
+    Ok(Some(b)) => HttpResponse::Ok().json(b.redacted()),
+    Ok(None) => no_such_broker(),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Query parameters for POST /admin/brokers/{uid}/rotate_key.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RotateKeyQuery {
+  /// How long the old key keeps working, in minutes. None means a day.
+  grace_minutes: Option<u32>
 }
 
-/// Pushes the message bundle to the database.
-pub(crate) async fn bundle<D: ApiDatabase>(
-  msgs: web::Json<BrokerMessageBundle>, db: web::Data<D>
+/// Gives a broker a new key, which it picks up with its next heartbeat.
+/// Admin only. Answers with the new key, in case it's needed by hand.
+pub(crate) async fn rotate_broker_key<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  path: web::Path<String>,
+  query: web::Query<RotateKeyQuery>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
 ) -> HttpResponse {
-  for mut msg in msgs.into_inner() {
-    msg.received_when = Some(Local::now());
-    match db.insert_message(msg) {
-      Ok(_) => continue,
-      Err(_) => return HttpResponse::InternalServerError().body("god damnit"),
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let uid = match path.parse::<BrokerId>() {
+    Ok(u) => u,
+    Err(_) => return no_such_broker(),
+  };
+  let grace = Duration::minutes(query.grace_minutes.unwrap_or(1440).into());
+  let rotated = db.blocking(move |db| brokers::rotate_key(db, uid, grace));
+  return match rotated.await {
+    Ok(Some(keys)) => {
+      let res = serde_json::json!({
+        "uid": uid,
+        "key": keys.current,
+        "previous_until": keys.previous_until
+      });
+      audit::record(db.get_ref(), AuditEntry::new(
+        actor(&req, &cfg), "broker.rotate_key"
+      )
+        .target(uid.to_string())
+        .after(&serde_json::json!({ "previous_until": keys.previous_until })))
+        .await;
+      HttpResponse::Ok().json(res)
+    },
+    Ok(None) => no_such_broker(),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Takes a broker's request to enroll, or to know whether it was. Answers
+/// 202 while it waits for an admin, and 200 with its key once approved.
+pub(crate) async fn enroll<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  body: web::Json<EnrollRequest>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  let (ecfg, er) = (cfg.enrollment.clone(), body.clone());
+  let res = db.blocking(move |db| enrollment::enroll(db, &ecfg, &er)).await;
+  let (new, reply) = match res {
+    Ok(r) => r,
+    Err(EnrollError::BadRequest(e)) => {
+      return ApiError::new(
+        StatusCode::UNAUTHORIZED, "bad_signature", e.to_string()
+      ).response();
+    },
+    Err(EnrollError::BadToken) => {
+      return ApiError::new(
+        StatusCode::UNAUTHORIZED, "bad_token", "Unknown or used-up token."
+      ).response();
+    },
+    Err(EnrollError::Taken) => {
+      return ApiError::new(
+        StatusCode::CONFLICT, "uid_taken", "That UID is taken."
+      ).response();
+    },
+    Err(EnrollError::Db(e)) => return db_error(e),
+  };
+  if new {
+    audit::record(db.get_ref(), AuditEntry::new(
+      actor(&req, &cfg), "broker.enroll"
+    )
+      .target(body.uid.to_string())
+      .after(&serde_json::json!({
+        "public_key": body.public_key,
+        "state": reply.state
+      }))).await;
+  }
+  return match reply.state {
+    EnrollState::Pending => HttpResponse::Accepted().json(reply),
+    EnrollState::Approved => HttpResponse::Ok().json(reply),
+  };
+}
+
+/// Approves a broker's enrollment, which gets its key the next time it
+/// asks. Admin only.
+pub(crate) async fn approve_broker<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  path: web::Path<String>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let uid = match path.parse::<BrokerId>() {
+    Ok(u) => u,
+    Err(_) => return no_such_broker(),
+  };
+  return match db.blocking(move |db| enrollment::approve(db, uid)).await {
+    Ok(Some(rec)) => {
+      audit::record(db.get_ref(), AuditEntry::new(
+        actor(&req, &cfg), "broker.approve"
+      ).target(uid.to_string())).await;
+      HttpResponse::Ok().json(rec.redacted())
+    },
+    Ok(None) => ApiError::not_found(
+      "no_such_enrollment", "That broker never enrolled."
+    ).response(),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Sets the settings a broker should run with, which go out with replies
+/// to its heartbeats until it reports running with them. Fields left out
+/// go as per the broker's own config; {} undoes everything. Admin only.
+/// Answers with the settings and their hash, which the broker's record
+/// shows as config_hash once it's caught up.
+pub(crate) async fn set_broker_config<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  path: web::Path<String>,
+  body: web::Json<RemoteConfig>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let uid = match path.parse::<BrokerId>() {
+    Ok(u) => u,
+    Err(_) => return no_such_broker(),
+  };
+  let want = match brokers::check_config(body.into_inner()) {
+    Ok(w) => w,
+    Err(e) => return ApiError::unprocessable("bad_config", e).response(),
+  };
+  let wanted = want.clone();
+  let res = db.blocking(move |db| {
+    return brokers::set_desired_config(db, uid, wanted);
+  }).await;
+  return match res {
+    Ok(Some(before)) => {
+      audit::record(db.get_ref(), AuditEntry::new(
+        actor(&req, &cfg), "broker.config"
+      )
+        .target(uid.to_string())
+        .before(&before.desired_config)
+        .after(&want)).await;
+      HttpResponse::Ok().json(serde_json::json!({
+        "uid": uid,
+        "config": want,
+        "hash": want.hash()
+      }))
+    },
+    Ok(None) => no_such_broker(),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Schedules a broker update, replacing whatever was scheduled. The order
+/// goes out with the broker's next heartbeat reply. Admin only.
+pub(crate) async fn schedule_update<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  path: web::Path<String>,
+  body: web::Json<UpdateRequest>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let uid = match path.parse::<BrokerId>() {
+    Ok(u) => u,
+    Err(_) => return no_such_broker(),
+  };
+  let order = match body.order() {
+    Ok(o) => o,
+    Err(e) => return ApiError::unprocessable("bad_update", e).response(),
+  };
+  return match db.blocking(move |db| updates::schedule(db, uid, order)).await {
+    Ok(Some((before, job))) => {
+      audit::record(db.get_ref(), AuditEntry::new(
+        actor(&req, &cfg), "broker.update"
+      )
+        .target(uid.to_string())
+        .before(&before.map(|b| b.order))
+        .after(&job.order)).await;
+      HttpResponse::Ok().json(job)
+    },
+    Ok(None) => no_such_broker(),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Logs a database error, and tells the client it was ours.
+fn db_error<E: Display>(e: E) -> HttpResponse {
+  warn!("Database error: {}", e);
+  return ApiError::internal("database_error").response();
+}
+
+/// Streams the messages a query matched out as a JSON array, a page at a
+/// time, each turned into what view makes of it, if anything.
+fn json_pages<D, T, F>(pages: Pages<D>, view: F) -> HttpResponse
+where
+  D: AsyncApiDatabase,
+  T: Serialize,
+  F: Fn(BrokerMessage) -> Option<T> + 'static
+{
+  let mut first = true;
+  let items = pages.stream().map(move |page| {
+    let mut chunk = Vec::new();
+    for v in page.into_iter().filter_map(&view) {
+      if !first {
+        chunk.push(b',');
+      }
+      first = false;
+      serde_json::to_writer(&mut chunk, &v)?;
+    }
+    return Ok(web::Bytes::from(chunk));
+  });
+  let chunks = stream::once(ready(Ok(web::Bytes::from_static(b"["))))
+    .chain(items)
+    .chain(stream::once(ready(Ok(web::Bytes::from_static(b"]")))))
+    .map(|c: Result<web::Bytes, serde_json::Error>| {
+      return c.map_err(actix_web::Error::from);
+    });
+  return HttpResponse::Ok()
+    .content_type("application/json")
+    .streaming(Box::pin(chunks));
+}
+
+/// For paths naming a sensor type we don't know.
+fn no_such_sensor_type() -> HttpResponse {
+  return ApiError::not_found("no_such_sensor_type", "No such sensor type.")
+    .response();
+}
+
+/// For paths naming a broker we don't know.
+fn no_such_broker() -> HttpResponse {
+  return ApiError::not_found("no_such_broker", "No such broker.").response();
+}
+
+/// For paths naming a message we don't know.
+fn no_such_message() -> HttpResponse {
+  return ApiError::not_found("no_such_message", "No such message.")
+    .response();
+}
+
+/// For paths naming an alert we don't know.
+fn no_such_alert() -> HttpResponse {
+  return ApiError::not_found("no_such_alert", "No such alert.").response();
+}
+
+/// For paths naming a mute we don't know, or one that's over.
+fn no_such_mute() -> HttpResponse {
+  return ApiError::not_found("no_such_mute", "No such mute.").response();
+}
+
+/// Whether the request carries the admin token. Always false when no token
+/// is configured, which keeps the admin endpoints off.
+fn is_admin(req: &HttpRequest, cfg: &ApiConfig) -> bool {
+  return match &cfg.admin_token {
+    Some(t) => bears(req, t),
+    None => false,
+  };
+}
+
+/// Whether the request carries the presence token phones report with.
+/// Always false when none is configured.
+fn is_phone(req: &HttpRequest, cfg: &ApiConfig) -> bool {
+  return match cfg.presence.token.as_deref() {
+    Some(t) if !t.is_empty() => bears(req, t),
+    _ => false,
+  };
+}
+
+/// Who's making a request, for the audit log, as far as we can vouch for:
+/// the admin or a phone if they carry either token, else where they're
+/// calling from. Whoever they say they are goes in claimed_by instead.
+fn actor(req: &HttpRequest, cfg: &ApiConfig) -> String {
+  if is_admin(req, cfg) {
+    return "admin".to_owned();
+  }
+  if is_phone(req, cfg) {
+    return "phone".to_owned();
+  }
+  return match req.peer_addr() {
+    Some(addr) => addr.ip().to_string(),
+    None => "unknown".to_owned(),
+  };
+}
+
+/// Whether the request carries a bearer token.
+fn bears(req: &HttpRequest, token: &str) -> bool {
+  let given = req.headers()
+    .get(AUTHORIZATION)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.strip_prefix("Bearer "))
+    .unwrap_or("");
+  // compare everything, so timing doesn't give the token away
+  return given.len() == token.len()
+    && given.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b))
+      == 0;
+}
+
+/// Opens a sealed bundle with the key of the broker that sealed it.
+/// Returns the broker, and the content type and body within. None if it
+/// isn't sealed.
+fn unseal(req: &HttpRequest, cfg: &ApiConfig, ctype: &str, body: &[u8])
+-> Result<Option<(BrokerId, String, Vec<u8>)>, ApiError> {
+  if !ctype.starts_with(sealing::CONTENT_TYPE) {
+    return Ok(None);
+  }
+  let broker = req.headers()
+    .get(sealing::BROKER_HEADER)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.trim().parse().ok())
+    .ok_or_else(|| ApiError::bad_request(
+      "no_broker", "Sealed bundles need the sealing broker's UID."
+    ))?;
+  let key = cfg.seal_keys.get(&broker).ok_or_else(|| ApiError::new(
+    StatusCode::UNAUTHORIZED, "no_seal_key", "No key for that broker."
+  ))?;
+  let (inner, body) = key.open(&broker, body).map_err(|e| {
+    return ApiError::new(StatusCode::UNAUTHORIZED, "bad_seal", e.to_string());
+  })?;
+  return Ok(Some((broker, inner, body)));
+}
+
+/// Checks that messages from brokers with a key came sealed, and that
+/// sealed ones are all from the broker that sealed them.
+fn check_sealing(
+  cfg: &ApiConfig, sealer: Option<BrokerId>, batch: &[BrokerMessage],
+  offset: usize
+) -> Result<(), ApiError> {
+  for (i, msg) in batch.iter().enumerate() {
+    let ok = match sealer {
+      Some(b) => msg.broker_id == b,
+      None => !cfg.seal_keys.contains_key(&msg.broker_id),
+    };
+    if !ok {
+      let why = match sealer {
+        Some(_) => "Sealed by another broker.",
+        None => "Messages from this broker must come sealed.",
+      };
+      return Err(ApiError::new(StatusCode::UNAUTHORIZED, "unsealed", why)
+        .at(offset + i));
+    }
+  }
+  return Ok(());
+}
+
+/// Reads a message from a line of NDJSON.
+fn ndjson_message(line: &[u8], index: usize)
+-> Result<BrokerMessage, ApiError> {
+  return serde_json::from_slice(line).map_err(|e| match e.is_data() {
+    true => ApiError::unprocessable("invalid_message", e.to_string()),
+    false => ApiError::bad_request("bad_json", e.to_string()),
+  }.at(index));
+}
+
+/// Decodes a bundle as JSON, NDJSON or protobuf, going by the content type.
+/// JSON is the default. Unreadable bodies are a 400; readable ones with a
+/// message that doesn't check out are a 422, pointing at the message.
+fn decode_bundle(ctype: &str, body: &[u8])
+-> Result<BrokerMessageBundle, ApiError> {
+  if ctype.starts_with(NDJSON_CONTENT_TYPE) {
+    return body.split(|b| *b == b'\n')
+      .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+      .enumerate()
+      .map(|(i, line)| ndjson_message(line, i))
+      .collect();
+  }
+  if ctype.starts_with(proto::CONTENT_TYPE) {
+    return proto::decode_bundle(body).map_err(|e| match e {
+      ProtoError::Decode(_) => {
+        ApiError::bad_request("bad_protobuf", e.to_string())
+      },
+      ProtoError::BadMessage(i, _) => {
+        ApiError::unprocessable("invalid_message", e.to_string()).at(i)
+      },
+      _ => ApiError::unprocessable("invalid_message", e.to_string()),
+    });
+  }
+  // one message at a time, so we can tell which one is wrong.
+  let items: Vec<serde_json::Value> = serde_json::from_slice(body)
+    .map_err(|e| match e.is_data() {
+      true => ApiError::unprocessable("not_a_bundle", e.to_string()),
+      false => ApiError::bad_request("bad_json", e.to_string()),
+    })?;
+  return items.into_iter()
+    .enumerate()
+    .map(|(i, item)| serde_json::from_value(item).map_err(|e| {
+      ApiError::unprocessable("invalid_message", e.to_string()).at(i)
+    }))
+    .collect();
+}
+
+/// Shared state, as web::Data would extract it.
+fn app_data<T: 'static>(req: &HttpRequest)
+-> Result<web::Data<T>, actix_web::Error> {
+  return req.app_data::<web::Data<T>>().cloned().ok_or_else(|| {
+    let name = std::any::type_name::<T>();
+    warn!("No {} in the app data. Did someone forget a .data()?", name);
+    let e = ApiError::internal("misconfigured");
+    return InternalError::from_response(name, e.response()).into();
+  });
+}
+
+/// What storing a bundle takes, from the app data, so handlers don't need a
+/// parameter for each.
+pub(crate) struct BundleSink<D: AsyncApiDatabase> {
+  intake: web::Data<Intake<D>>,
+  cfg: web::Data<ApiConfig>,
+  /// The broker key the request came with, if any.
+  key: Option<String>
+}
+
+impl<D: AsyncApiDatabase> FromRequest for BundleSink<D> {
+  type Error = actix_web::Error;
+  type Future = Ready<Result<Self, Self::Error>>;
+  type Config = ();
+
+  fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+    let sink = || -> Result<Self, Self::Error> {
+      return Ok(Self {
+        intake: app_data(req)?,
+        cfg: app_data(req)?,
+        key: req.headers()
+          .get(KEY_HEADER)
+          .and_then(|v| v.to_str().ok())
+          .map(|k| k.to_owned())
+      });
     };
-  } 
-  return HttpResponse::Ok().body("OK")
+    return ready(sink());
+  }
 }
 
-/// Returns all messages.
-pub(crate) async fn all_sensor<D: ApiDatabase>(db: web::Data<D>)
+impl<D: AsyncApiDatabase> BundleSink<D> {
+  /// Stores a batch through the intake, noting what became of it in an
+  /// acknowledgement. The offset is where the batch starts within the
+  /// request. Every batch counts against its brokers' rate limits.
+  async fn store(
+    &self, batch: BrokerMessageBundle, offset: usize, ack: &mut BundleAck
+  ) -> Result<(), ApiError> {
+    return match self.intake.store(batch, self.key.clone()).await {
+      Ok(ingested) => {
+        acknowledge(ack, &ingested);
+        Ok(())
+      },
+      Err(StoreError::BadTime(i)) => Err(ApiError::unprocessable(
+        "bad_time", "Times must be between 1677-09-22 and 2262-04-11."
+      ).at(offset + i)),
+      Err(StoreError::RateLimited) => Err(ApiError::rate_limited()),
+      Err(StoreError::WrongKey(uid)) => Err(ApiError::new(
+        StatusCode::UNAUTHORIZED, "wrong_key",
+        format!("Wrong key for broker {}.", uid)
+      )),
+      Err(StoreError::Wal(e)) => {
+        warn!("Failed to log a bundle: {}", e);
+        Err(ApiError::internal("wal_error"))
+      },
+      Err(StoreError::Ingest(IngestError::Insert(e))) => {
+        warn!("Bundle insert failed: {}", e);
+        Err(ApiError::internal("insert_failed").at(offset + e.index))
+      },
+      Err(e) => {
+        warn!("Database error: {}", e);
+        Err(ApiError::internal("database_error"))
+      },
+    };
+  }
+}
+
+/// An acknowledgement of nothing yet.
+fn new_ack() -> BundleAck {
+  return BundleAck {
+    accepted: 0,
+    rejected: 0,
+    reasons: BTreeMap::new(),
+    seq_range: None,
+    server_time: Local::now()
+  };
+}
+
+/// Counts an ingested batch in an acknowledgement. Duplicates count as
+/// rejected, for being duplicates.
+fn acknowledge(ack: &mut BundleAck, ingested: &Ingested) {
+  ack.accepted += ingested.stored.len();
+  if ingested.duplicates > 0 {
+    ack.rejected += ingested.duplicates;
+    *ack.reasons.entry(BundleAck::DUPLICATE.to_owned()).or_insert(0)
+      += ingested.duplicates;
+  }
+  for seq in ingested.stored.iter().filter_map(|msg| msg.seq) {
+    ack.seq_range = Some(match ack.seq_range {
+      Some((lo, hi)) => (lo.min(seq), hi.max(seq)),
+      None => (seq, seq),
+    });
+  }
+  ack.server_time = Local::now();
+}
+
+/// Logs the message bundle, then pushes it to the database. Replies with
+/// what became of it.
+pub(crate) async fn bundle<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  body: web::Bytes,
+  sink: BundleSink<D>
+) -> HttpResponse {
+  let ctype = req.headers()
+    .get(CONTENT_TYPE)
+    .and_then(|v| v.to_str().ok())
+    .unwrap_or("");
+  let opened = match unseal(&req, &sink.cfg, ctype, &body) {
+    Ok(o) => o,
+    Err(e) => return e.response(),
+  };
+  let (sealer, ctype, body): (_, &str, &[u8]) = match &opened {
+    Some((broker, inner, plain)) => (Some(*broker), inner, plain),
+    None => (None, ctype, &body),
+  };
+  let batch = match decode_bundle(ctype, body) {
+    Ok(b) => b,
+    Err(e) => return e.response(),
+  };
+  if let Err(e) = check_sealing(&sink.cfg, sealer, &batch, 0) {
+    return e.response();
+  }
+  let mut ack = new_ack();
+  return match sink.store(batch, 0, &mut ack).await {
+    Ok(()) => HttpResponse::Ok().json(ack),
+    Err(e) => e.response(),
+  };
+}
+
+/// Takes in readings from a device that can only call webhooks, picking
+/// them out of its JSON as its source's config says. Replies with what
+/// became of them, like for bundles.
+pub(crate) async fn webhook<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  path: web::Path<String>,
+  body: web::Json<serde_json::Value>,
+  sink: BundleSink<D>
+) -> HttpResponse {
+  let source = match sink.cfg.webhooks.get(path.as_str()) {
+    Some(s) => s,
+    None => return ApiError::not_found(
+      "no_such_source", "No such webhook source."
+    ).response(),
+  };
+  let device = source.token.as_deref().map(|t| bears(&req, t));
+  if !device.unwrap_or(false) && !is_admin(&req, &sink.cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let batch = match source.messages(&body) {
+    Ok(b) => b,
+    Err(e) => {
+      return ApiError::unprocessable(e.code(), e.to_string()).response();
+    },
+  };
+  let mut ack = new_ack();
+  return match sink.store(batch, 0, &mut ack).await {
+    Ok(()) => HttpResponse::Ok().json(ack),
+    Err(e) => e.response(),
+  };
+}
+
+/// How many messages of an NDJSON bundle are stored at a time.
+const NDJSON_BATCH: usize = 100;
+
+/// Whether a request's body is NDJSON, for routing it to bundle_ndjson.
+pub(crate) fn is_ndjson(head: &RequestHead) -> bool {
+  return head.headers()
+    .get(CONTENT_TYPE)
+    .and_then(|v| v.to_str().ok())
+    .map(|ct| ct.starts_with(NDJSON_CONTENT_TYPE))
+    .unwrap_or(false);
+}
+
+/// An NDJSON bundle, as it comes in.
+struct NdjsonBundle<D: AsyncApiDatabase> {
+  sink: BundleSink<D>,
+  /// Messages read but not stored yet.
+  batch: BrokerMessageBundle,
+  /// Messages stored so far.
+  stored: usize,
+  /// What became of them.
+  ack: BundleAck
+}
+
+impl<D: AsyncApiDatabase> NdjsonBundle<D> {
+  /// Takes a line, storing the batch if that fills it. Blank lines are
+  /// fine.
+  async fn line(&mut self, line: &[u8]) -> Result<(), ApiError> {
+    if line.iter().all(u8::is_ascii_whitespace) {
+      return Ok(());
+    }
+    let index = self.stored + self.batch.len();
+    let max = self.sink.cfg.max_ndjson_messages;
+    if index >= max {
+      return Err(ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE, "too_many_messages",
+        format!("NDJSON bundles may have up to {} messages.", max)
+      ));
+    }
+    let msg = ndjson_message(line, index)?;
+    check_sealing(&self.sink.cfg, None, std::slice::from_ref(&msg), index)?;
+    self.batch.push(msg);
+    if self.batch.len() >= NDJSON_BATCH {
+      return self.flush().await;
+    }
+    return Ok(());
+  }
+
+  /// Stores whatever was read since the last time.
+  async fn flush(&mut self) -> Result<(), ApiError> {
+    if self.batch.is_empty() {
+      return Ok(());
+    }
+    let batch = std::mem::take(&mut self.batch);
+    let len = batch.len();
+    self.sink.store(batch, self.stored, &mut self.ack).await?;
+    self.stored += len;
+    return Ok(());
+  }
+
+  /// Notes, in an error, how much got stored before it.
+  fn failed(&self, mut e: ApiError) -> HttpResponse {
+    if self.stored > 0 {
+      e.message = format!(
+        "{} The {} message(s) before it were stored.", e.message, self.stored
+      );
+    }
+    return e.response();
+  }
+}
+
+/// Like bundle, for NDJSON bodies: one message per line, stored a batch at a
+/// time as the lines come in, rather than once the whole body is there. So
+/// each line is held to the bundle size limit, and the whole body to limits
+/// of its own. Whatever came before a bad line stays stored.
+pub(crate) async fn bundle_ndjson<D: AsyncApiDatabase>(
+  mut body: web::Payload,
+  sink: BundleSink<D>
+) -> HttpResponse {
+  let max_line = sink.cfg.max_bundle_bytes;
+  let max_body = sink.cfg.max_ndjson_bytes;
+  let mut read = 0;
+  let mut bnd = NdjsonBundle {
+    sink: sink,
+    batch: BrokerMessageBundle::new(),
+    stored: 0,
+    ack: new_ack()
+  };
+  let mut buf: Vec<u8> = Vec::new();
+  while let Some(chunk) = body.next().await {
+    match chunk {
+      Ok(c) => {
+        read += c.len();
+        buf.extend_from_slice(&c);
+      },
+      Err(e) => {
+        return bnd.failed(ApiError::bad_request("bad_body", e.to_string()));
+      },
+    };
+    if read > max_body {
+      return bnd.failed(ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE, "body_too_large",
+        format!("NDJSON bundles may be up to {} bytes.", max_body)
+      ));
+    }
+    while let Some(nl) = buf.iter().position(|b| *b == b'\n') {
+      let line: Vec<u8> = buf.drain(..=nl).collect();
+      if let Err(e) = bnd.line(&line).await {
+        return bnd.failed(e);
+      }
+    }
+    if buf.len() > max_line {
+      return bnd.failed(ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE, "line_too_long",
+        format!("Lines may be up to {} bytes.", max_line)
+      ));
+    }
+  }
+  // the last line needn't end in a newline.
+  let res = match bnd.line(&buf).await {
+    Ok(()) => bnd.flush().await,
+    Err(e) => Err(e),
+  };
+  return match res {
+    Ok(()) => HttpResponse::Ok().json(bnd.ack),
+    Err(e) => bnd.failed(e),
+  };
+}
+
+/// Re-ingests everything in the write-ahead log. Meant for a fresh database,
+/// but harmless on any other: messages already in there are left out.
+pub(crate) async fn replay_wal<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  db: web::Data<D>,
+  lvc: web::Data<LastValueCache>,
+  anm: web::Data<AnomalyDetector>,
+  ist: web::Data<IngestStats>,
+  wal: web::Data<WriteAheadLog>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  if !wal.is_enabled() {
+    return ApiError::not_found(
+      "no_wal", "No write-ahead log configured."
+    ).response();
+  }
+  let (wal, derived) = (wal.clone(), cfg.derived.clone());
+  let res = db.blocking(move |db| wal.replay(|rec| {
+    // old news: no alerts for it
+    let ingested = ingest::ingest(
+      db, &lvc, &anm, None, &ist, &derived, rec.bundle, rec.logged_when
+    );
+    if let Err(e) = &ingested {
+      warn!("Failed to replay a bundle: {}", e);
+    }
+    return ingested.is_ok();
+  })).await;
+  return match res {
+    Ok(report) => {
+      audit::record(db.get_ref(), AuditEntry::new(
+        actor(&req, &cfg), "wal.replay"
+      ).after(&report)).await;
+      HttpResponse::Ok().json(report)
+    },
+    Err(e) => {
+      warn!("Failed to read the write-ahead log: {}", e);
+      ApiError::internal("wal_error").response()
+    },
+  };
+}
+
+/// Returns all messages, with their readings converted.
+pub(crate) async fn all_sensor<D: AsyncApiDatabase>(db: web::Data<D>)
+-> HttpResponse {
+  let all = MessageFilter::of_type(BrokerMessagePayloadType::SensorData);
+  return match db.query(all).await {
+    Ok(pages) => json_pages(pages, |msg| Some(BrokerMessageView::from(msg))),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Query parameters for /messages/sensor/{sensor_type}.
+#[derive(Debug, Deserialize)]
+pub(crate) struct TimeRangeQuery {
+  /// Start of the range, RFC 3339. None means the dawn of time.
+  from: Option<DateTime<Local>>,
+  /// End of the range, RFC 3339. None means now, or no end at all when
+  /// going by sequence number.
+  to: Option<DateTime<Local>>,
+  /// Only messages whose signature the broker checked, or only ones it
+  /// didn't. None means both.
+  verified: Option<bool>,
+  /// Which way messages go, and which time the range goes by. None means
+  /// by construction time, or by sequence number if there's an after.
+  order: Option<MessageOrder>,
+  /// Only messages with a sequence number past this one, for paging through
+  /// messages as they come in. Only goes with order=seq.
+  after: Option<u64>,
+  /// Most messages returned. None means all of them.
+  limit: Option<usize>
+}
+
+/// Returns messages of a sensor type within a time range, with their
+/// readings converted. To page through them as they come in, ask for
+/// order=seq, then for after=the last seq seen, and so on; nothing is
+/// skipped or repeated that way.
+pub(crate) async fn sensor_range<D: AsyncApiDatabase>(
+  path: web::Path<String>,
+  query: web::Query<TimeRangeQuery>,
+  db: web::Data<D>
+) -> HttpResponse {
+  let stype = match SensorType::from_str(&path.into_inner()) {
+    Ok(st) => st,
+    Err(_) => return no_such_sensor_type(),
+  };
+  let from = query.from.unwrap_or_else(|| Local.timestamp(0, 0));
+  let order = match (query.order, query.after) {
+    (None, Some(_)) => MessageOrder::Seq,
+    (Some(o), Some(_)) if o != MessageOrder::Seq => {
+      return ApiError::unprocessable(
+        "bad_order", "after only goes with order=seq."
+      ).response();
+    },
+    (o, _) => o.unwrap_or_default(),
+  };
+  // numbered messages may have been received after now, and still be the
+  // next ones; leaving them out would skip them for good.
+  let to = match (query.to, order) {
+    (Some(t), _) => t,
+    (None, MessageOrder::Seq) => Local.ymd(9999, 12, 31).and_hms(23, 59, 59),
+    (None, _) => Local::now(),
+  };
+  let filter = MessageFilter {
+    after: query.after,
+    verified: query.verified,
+    limit: query.limit,
+    ..MessageFilter::default().sensor(stype).between(from, to).ordered(order)
+  };
+  return match db.query(filter).await {
+    Ok(pages) => json_pages(pages, |msg| Some(BrokerMessageView::from(msg))),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Query parameters for the distribution of a sensor's readings.
+#[derive(Debug, Deserialize)]
+pub(crate) struct DistributionQuery {
+  /// Start of the window, RFC 3339. None means minutes before its end.
+  from: Option<DateTime<Local>>,
+  /// End of the window, RFC 3339. None means now.
+  to: Option<DateTime<Local>>,
+  /// Length of the window, in minutes, if there's no from. None means a
+  /// day.
+  minutes: Option<u32>,
+  /// Width of histogram bins, in human units. None means 1.
+  width: Option<f64>
+}
+
+/// Feeds every reading of a sensor in a query's window, in human units, to
+/// an accumulator, straight from the database, and hands it back.
+async fn each_reading<D: AsyncApiDatabase, T: Send + 'static>(
+  db: &D, tname: &str, sensor_id: SensorId, query: &DistributionQuery,
+  mut acc: T, f: fn(&mut T, f64)
+) -> Result<T, HttpResponse> {
+  let stype = SensorType::from_str(tname)
+    .map_err(|_| no_such_sensor_type())?;
+  let to = query.to.unwrap_or_else(Local::now);
+  let from = query.from.unwrap_or_else(|| {
+    return to - chrono::Duration::minutes(query.minutes.unwrap_or(1440).into());
+  });
+  let filter = MessageFilter::default()
+    .sensor(stype)
+    .sensor_id(sensor_id)
+    .between(from, to);
+  let fed: Result<T, DbError<D>> = db.blocking(move |db| {
+    for msg in db.query(filter)? {
+      if let BrokerMessagePayload::SensorData(sd) = &msg.payload {
+        f(&mut acc, sd.reading().human_value());
+      }
+    }
+    return Ok(acc);
+  }).await;
+  return fed.map_err(db_error);
+}
+
+/// Returns the count, extremes, mean and percentiles of a sensor's
+/// readings over a window.
+pub(crate) async fn percentiles<D: AsyncApiDatabase>(
+  path: web::Path<(String, SensorId)>,
+  query: web::Query<DistributionQuery>,
+  db: web::Data<D>
+) -> HttpResponse {
+  let (tname, sensor_id) = path.into_inner();
+  let fed = each_reading(
+    db.get_ref(), &tname, sensor_id, &query, Summary::new(), Summary::observe
+  ).await;
+  return match fed {
+    Ok(summary) => HttpResponse::Ok().json(summary.view()),
+    Err(resp) => resp,
+  };
+}
+
+/// Returns a histogram of a sensor's readings over a window.
+pub(crate) async fn histogram<D: AsyncApiDatabase>(
+  path: web::Path<(String, SensorId)>,
+  query: web::Query<DistributionQuery>,
+  db: web::Data<D>
+) -> HttpResponse {
+  let (tname, sensor_id) = path.into_inner();
+  let width = query.width.unwrap_or(1.0);
+  if !(width.is_finite() && width > 0.0) {
+    return ApiError::unprocessable(
+      "bad_width", "Bins need a positive width."
+    ).response();
+  }
+  let hist = Histogram::new(width);
+  let fed = each_reading(
+    db.get_ref(), &tname, sensor_id, &query, hist, Histogram::observe
+  ).await;
+  return match fed {
+    Ok(hist) => HttpResponse::Ok().json(hist.view()),
+    Err(resp) => resp,
+  };
+}
+
+/// Query parameters for /forecast/{sensor_type}/{sensor_id}.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ForecastQuery {
+  /// How far ahead, in minutes. None means an hour.
+  horizon: Option<u32>,
+  /// Which model to fit. None means holt.
+  method: Option<ForecastMethod>,
+  /// A value to say when the forecast reaches, like 0 for a freezer.
+  threshold: Option<f64>
+}
+
+/// Predicts a sensor's readings over the next while, from its recent ones.
+pub(crate) async fn forecast<D: AsyncApiDatabase>(
+  path: web::Path<(String, SensorId)>,
+  query: web::Query<ForecastQuery>,
+  db: web::Data<D>,
+  lvc: web::Data<LastValueCache>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  let (tname, sensor_id) = path.into_inner();
+  let stype = match SensorType::from_str(&tname) {
+    Ok(st) => st,
+    Err(_) => return no_such_sensor_type(),
+  };
+  let fcfg = &cfg.forecast;
+  let horizon = query.horizon.unwrap_or(60);
+  if horizon == 0 || horizon > fcfg.max_horizon_minutes {
+    return ApiError::unprocessable("bad_horizon", format!(
+      "The horizon must be between 1 and {} minutes.",
+      fcfg.max_horizon_minutes
+    )).response();
+  }
+  let latest = match lvc.get(stype, sensor_id).map(|m| m.payload) {
+    Some(BrokerMessagePayload::SensorData(sd)) => sd.reading().human_value(),
+    _ => return ApiError::not_found(
+      "no_such_sensor", "That sensor has never been heard from."
+    ).response(),
+  };
+  let now = Local::now();
+  let mut series = StepSeries::new(fcfg, now);
+  let filter = MessageFilter::default()
+    .sensor(stype)
+    .sensor_id(sensor_id)
+    .between(series.start(), now);
+  let pushed: Result<StepSeries, DbError<D>> = db.blocking(move |db| {
+    for msg in db.query(filter)? {
+      if let BrokerMessagePayload::SensorData(sd) = &msg.payload {
+        series.push(msg.constructed_when, sd.reading().human_value());
+      }
+    }
+    return Ok(series);
+  }).await;
+  let series = match pushed {
+    Ok(s) => s,
+    Err(e) => return db_error(e),
+  };
+  let steps = (horizon + fcfg.step_minutes - 1) / fcfg.step_minutes;
+  let method = query.method.unwrap_or_default();
+  let forecast = match series.forecast(fcfg, method, steps as usize) {
+    Ok(f) => f,
+    Err(e) => return ApiError::unprocessable("not_enough_data", e.to_string())
+      .response(),
+  };
+  let reaches_at = query.threshold.and_then(|t| forecast.reaches(latest, t));
+  return HttpResponse::Ok().json(ForecastView {
+    sensor_type: stype,
+    sensor_id: sensor_id,
+    latest: latest,
+    unit: views::unit_of(stype),
+    forecast: forecast,
+    threshold: query.threshold,
+    reaches_at: reaches_at
+  });
+}
+
+/// Returns the bytes a sensor sent for a message, if its broker kept them.
+pub(crate) async fn message_raw<D: AsyncApiDatabase>(
+  path: web::Path<String>,
+  db: web::Data<D>
+) -> HttpResponse {
+  let id = match path.parse::<MessageId>() {
+    Ok(u) => u,
+    Err(_) => return no_such_message(),
+  };
+  let msg = match db.message(id).await {
+    Ok(Some(m)) => m,
+    Ok(None) => return no_such_message(),
+    Err(e) => return db_error(e),
+  };
+  return match RawPayloadView::new(&msg) {
+    Some(view) => HttpResponse::Ok().json(view),
+    None => ApiError::not_found("no_raw_payload", "No raw bytes kept for it.")
+      .response(),
+  };
+}
+
+/// Returns all sensor messages flagged as outliers, with conversions.
+pub(crate) async fn anomalies<D: AsyncApiDatabase>(db: web::Data<D>)
 -> HttpResponse {
-  let msgs: Vec<BrokerMessage> = db
-    .messages_by_type(BrokerMessagePayloadType::SensorData)
-    .unwrap()
+  let mtype = BrokerMessagePayloadType::SensorData;
+  return match db.query(MessageFilter::of_type(mtype)).await {
+    Ok(pages) => json_pages(pages, |msg| {
+      return msg.anomaly_score.map(|_| BrokerMessageView::from(msg));
+    }),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Query parameters for /alerts.
+#[derive(Debug, Deserialize)]
+pub(crate) struct AlertsQuery {
+  /// Only alerts in this state. None means all of them.
+  state: Option<AlertState>
+}
+
+/// Returns the alerts kept, newest first.
+pub(crate) async fn alerts(
+  query: web::Query<AlertsQuery>,
+  alr: web::Data<AlertBook>
+) -> HttpResponse {
+  return HttpResponse::Ok().json(alr.list(query.state));
+}
+
+/// Returns every alert kept, and every maintenance window in effect or yet
+/// to be, as an iCalendar feed.
+pub(crate) async fn alerts_ics(alr: web::Data<AlertBook>) -> HttpResponse {
+  let mut events: Vec<Event> = alr.list(None)
+    .iter()
+    .filter(|a| a.opened_when().is_some())
+    .map(Event::from)
+    .collect();
+  events.extend(alr.mutes().iter().map(Event::from));
+  let cal = Calendar {
+    name: "Casa do Pânico".to_owned(),
+    events: events
+  };
+  return HttpResponse::Ok()
+    .content_type("text/calendar; charset=utf-8")
+    .body(cal.to_ics());
+}
+
+/// Returns an alert, with everything that happened to it.
+pub(crate) async fn alert(
+  path: web::Path<String>,
+  alr: web::Data<AlertBook>
+) -> HttpResponse {
+  let found = Uuid::parse_str(&path.into_inner())
+    .ok()
+    .and_then(|id| alr.get(id));
+  return match found {
+    Some(a) => HttpResponse::Ok().json(a),
+    None => no_such_alert(),
+  };
+}
+
+/// Body of an acknowledgement.
+#[derive(Debug, Deserialize)]
+pub(crate) struct AckRequest {
+  /// Who says they're on it. Nothing checks it, so it only goes in the
+  /// note.
+  by: Option<String>,
+  /// Anything they have to say.
+  note: Option<String>
+}
+
+/// Notes that somebody is on an alert. Admin or phones only.
+pub(crate) async fn ack_alert<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  path: web::Path<String>,
+  ack: web::Json<AckRequest>,
+  alr: web::Data<AlertBook>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  let admin = is_admin(&req, &cfg);
+  if !admin && !is_phone(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let id = match Uuid::parse_str(&path.into_inner()) {
+    Ok(u) => u,
+    Err(_) => return no_such_alert(),
+  };
+  let AckRequest { by, note } = ack.into_inner();
+  let by = by.filter(|b| !b.trim().is_empty());
+  let note = match (&by, note) {
+    (Some(by), Some(note)) => Some(format!("{}: {}", by, note)),
+    (Some(by), None) => Some(by.clone()),
+    (None, note) => note,
+  };
+  let who = actor(&req, &cfg);
+  let before = alr.get(id).map(|a| a.state);
+  return match alr.acknowledge(id, who.clone(), note) {
+    Ok(a) => {
+      audit::record(db.get_ref(), AuditEntry::new(who, "alert.ack")
+        .claimed_by(by.as_deref())
+        .target(id.to_string())
+        .before(&before)
+        .after(&a)).await;
+      HttpResponse::Ok().json(a)
+    },
+    Err(AckError::NoSuchAlert) => no_such_alert(),
+    Err(e) => {
+      let code = match e {
+        AckError::AlreadyAcknowledged => "already_acknowledged",
+        _ => "already_resolved",
+      };
+      ApiError::new(StatusCode::CONFLICT, code, e.to_string()).response()
+    },
+  };
+}
+
+/// Body of a mute.
+#[derive(Debug, Deserialize)]
+pub(crate) struct MuteRequest {
+  /// Only sensors of this type.
+  sensor_type: Option<String>,
+  /// Only the sensor, or device, with this ID.
+  sensor_id: Option<SensorId>,
+  /// Only sensors at this site, by its broker's UID.
+  broker_id: Option<BrokerId>,
+  /// When it starts, RFC 3339. None means now.
+  from: Option<DateTime<Local>>,
+  /// When it's over, RFC 3339.
+  until: Option<DateTime<Local>>,
+  /// How long it lasts, if there's no until.
+  minutes: Option<u32>,
+  /// Who's asking.
+  by: Option<String>,
+  /// What for.
+  reason: Option<String>
+}
+
+/// Keeps alerts about some sensors from opening, or escalating, for a
+/// while. Readings still get stored and flagged. Admin or phones only.
+pub(crate) async fn mute_alerts<D: AsyncApiDatabase>(
+  http: HttpRequest,
+  req: web::Json<MuteRequest>,
+  alr: web::Data<AlertBook>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&http, &cfg) && !is_phone(&http, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let req = req.into_inner();
+  let sensor_type = match req.sensor_type.as_deref().map(SensorType::from_str) {
+    Some(Err(_)) => return no_such_sensor_type(),
+    Some(Ok(st)) => Some(st),
+    None => None,
+  };
+  let from = req.from.unwrap_or_else(Local::now);
+  let until = match (req.until, req.minutes) {
+    (Some(until), _) => until,
+    (None, Some(m)) => from + chrono::Duration::minutes(m.into()),
+    (None, None) => return ApiError::unprocessable(
+      "mute_never_ends", "Say until when, or for how many minutes."
+    ).response(),
+  };
+  if until <= from {
+    return ApiError::unprocessable(
+      "mute_ends_first", "The mute has to end after it starts."
+    ).response();
+  }
+  let mute = alr.mute(Mute {
+    id: Uuid::new_v4(),
+    sensor_type: sensor_type,
+    sensor_id: req.sensor_id,
+    broker_id: req.broker_id,
+    from: from,
+    until: until,
+    by: req.by,
+    reason: req.reason,
+  });
+  let who = actor(&http, &cfg);
+  audit::record(db.get_ref(), AuditEntry::new(who, "alert.mute")
+    .claimed_by(mute.by.as_deref())
+    .target(mute.id.to_string())
+    .after(&mute)).await;
+  return HttpResponse::Created().json(mute);
+}
+
+/// Returns the mutes in effect, or yet to be, soonest first.
+pub(crate) async fn mutes(alr: web::Data<AlertBook>) -> HttpResponse {
+  return HttpResponse::Ok().json(alr.mutes());
+}
+
+/// Ends a mute early. Admin or phones only.
+pub(crate) async fn unmute<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  path: web::Path<String>,
+  alr: web::Data<AlertBook>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) && !is_phone(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let id = match Uuid::parse_str(&path.into_inner()) {
+    Ok(u) => u,
+    Err(_) => return no_such_mute(),
+  };
+  let before = alr.mutes().into_iter().find(|m| m.id == id);
+  return match alr.unmute(id) {
+    Some(m) => {
+      audit::record(db.get_ref(), AuditEntry::new(
+        actor(&req, &cfg), "alert.unmute"
+      ).target(id.to_string()).before(&before).after(&m)).await;
+      HttpResponse::Ok().json(m)
+    },
+    None => no_such_mute(),
+  };
+}
+
+/// Returns how the system is armed, and every change.
+pub(crate) async fn arming(arm: web::Data<Arming>) -> HttpResponse {
+  return HttpResponse::Ok().json(arm.status());
+}
+
+/// Body of an arming change.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ArmingRequest {
+  /// What to go to.
+  state: ArmingState,
+  /// Who's doing it.
+  by: String,
+  /// Anything they have to say.
+  note: Option<String>
+}
+
+/// Arms or disarms the system, and re-evaluates the alert rules.
+pub(crate) async fn set_arming<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  body: web::Json<ArmingRequest>,
+  arm: web::Data<Arming>,
+  alr: web::Data<AlertBook>,
+  lvc: web::Data<LastValueCache>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let ArmingRequest { state, by, note } = body.into_inner();
+  if by.trim().is_empty() {
+    return ApiError::unprocessable("no_one_armed", "Say who's doing it.")
+      .response();
+  }
+  return match arm.set(state, by, note) {
+    Ok(change) => {
+      alr.evaluate_all_rules(&lvc, change.when);
+      audit::record(db.get_ref(), AuditEntry::new(
+        actor(&req, &cfg), "arming.set"
+      ).claimed_by(Some(&change.by)).before(&change.from).after(&change))
+        .await;
+      HttpResponse::Ok().json(change)
+    },
+    Err(e @ ArmingError::AlreadyThere(_)) => {
+      ApiError::new(StatusCode::CONFLICT, "already_there", e.to_string())
+        .response()
+    },
+  };
+}
+
+/// Returns where everybody is, by name.
+pub(crate) async fn presence(prs: web::Data<Presence>) -> HttpResponse {
+  return HttpResponse::Ok().json(prs.snapshot());
+}
+
+/// An OwnTracks region event.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OwnTracksEvent {
+  /// Got into the region.
+  Enter,
+  /// Got out of it.
+  Leave
+}
+
+/// Body of a presence report.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum PresenceReport {
+  /// Like {"person": "ana", "state": "home"}.
+  Plain {
+    /// Who.
+    person: String,
+    /// Where.
+    state: PresenceState
+  },
+  /// Whatever an OwnTracks phone POSTs. Only transitions, like
+  /// {"_type": "transition", "event": "enter", "tid": "an"}, say anything.
+  OwnTracks {
+    /// Which of its messages it is.
+    #[serde(rename = "_type")]
+    kind: String,
+    /// For transitions, into the region or out of it.
+    event: Option<OwnTracksEvent>,
+    /// The phone's tracker ID.
+    tid: Option<String>
+  }
+}
+
+/// Query parameters for POST /presence.
+#[derive(Debug, Deserialize)]
+pub(crate) struct PresenceQuery {
+  /// Who's reporting, for OwnTracks. None means the tracker ID.
+  person: Option<String>
+}
+
+/// Notes where somebody is, which may arm or disarm the system.
+pub(crate) async fn report_presence<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  query: web::Query<PresenceQuery>,
+  body: web::Json<PresenceReport>,
+  prs: web::Data<Presence>,
+  alr: web::Data<AlertBook>,
+  lvc: web::Data<LastValueCache>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  let phone = prs.token().map(|t| bears(&req, t)).unwrap_or(false);
+  if !phone && !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let (person, state) = match body.into_inner() {
+    PresenceReport::Plain { person, state } => (Some(person), state),
+    PresenceReport::OwnTracks { kind, event: Some(ev), tid }
+      if kind == "transition" => {
+      let state = match ev {
+        OwnTracksEvent::Enter => PresenceState::Home,
+        OwnTracksEvent::Leave => PresenceState::Away,
+      };
+      (query.into_inner().person.or(tid), state)
+    },
+    // locations and such, nothing to do with us
+    PresenceReport::OwnTracks { .. } => {
+      return HttpResponse::Ok().json(Vec::<()>::new());
+    },
+  };
+  let person = match person.filter(|p| !p.trim().is_empty()) {
+    Some(p) => p,
+    None => return ApiError::unprocessable(
+      "no_one_present", "Say who it is."
+    ).response(),
+  };
+  let before = prs.snapshot().remove(&person);
+  return match prs.report(person.clone(), state) {
+    Ok(change) => {
+      let after = prs.snapshot().remove(&person);
+      if let Some(c) = &change {
+        alr.evaluate_all_rules(&lvc, c.when);
+      }
+      let res = serde_json::json!({ "arming": change });
+      audit::record(db.get_ref(), AuditEntry::new(
+        actor(&req, &cfg), "presence.report"
+      ).claimed_by(Some(&person)).target(person)
+        .before(&before)
+        .after(&serde_json::json!({ "presence": after, "arming": change })))
+        .await;
+      HttpResponse::Ok().json(res)
+    },
+    Err(e) => {
+      ApiError::unprocessable("stranger", e.to_string()).response()
+    },
+  };
+}
+
+/// Returns the latest reading of every sensor, with conversions.
+pub(crate) async fn current(lvc: web::Data<LastValueCache>) -> HttpResponse {
+  let msgs: Vec<BrokerMessageView> = lvc
+    .snapshot()
+    .into_iter()
+    .map(BrokerMessageView::from)
     .collect();
   return HttpResponse::Ok().json(msgs);
 }
 
+/// Query parameters for /changes.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ChangesQuery {
+  /// Cursor returned last time. None means every sensor.
+  since: Option<u64>
+}
+
+/// Returns the latest reading of every sensor whose reading changed since
+/// the cursor, with conversions, and the cursor to ask from next.
+pub(crate) async fn changes(
+  query: web::Query<ChangesQuery>,
+  lvc: web::Data<LastValueCache>
+) -> HttpResponse {
+  let changes = lvc.changes_since(query.since.unwrap_or(0));
+  return HttpResponse::Ok().json(ChangesView::from(changes));
+}
+
+/// Returns all virtual sensors, with their latest values.
+pub(crate) async fn derived_sensors<D: AsyncApiDatabase>(
+  cfg: web::Data<ApiConfig>,
+  db: web::Data<D>
+) -> HttpResponse {
+  let mut views: Vec<DerivedSensorView> = Vec::new();
+  for ds in cfg.derived.iter() {
+    match db.derived_readings(ds.name.clone()).await {
+      Ok(mut rds) => views.push(DerivedSensorView::new(ds, rds.pop())),
+      Err(e) => return db_error(e),
+    };
+  }
+  return HttpResponse::Ok().json(views);
+}
+
+/// Returns all computed values of a single virtual sensor.
+pub(crate) async fn derived_readings<D: AsyncApiDatabase>(
+  path: web::Path<String>,
+  cfg: web::Data<ApiConfig>,
+  db: web::Data<D>
+) -> HttpResponse {
+  let name = path.into_inner();
+  if !cfg.derived.iter().any(|ds| ds.name == name) {
+    return ApiError::not_found(
+      "no_such_derived_sensor", "No such derived sensor."
+    ).response();
+  }
+  return match db.derived_readings(name).await {
+    Ok(rds) => HttpResponse::Ok().json(rds),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Returns ingestion counters and rates.
+pub(crate) async fn ingest_stats(ist: web::Data<IngestStats>) -> HttpResponse {
+  return HttpResponse::Ok().json(ist.report());
+}
+
+/// Returns how long messages take to get here, hop by hop, per broker.
+pub(crate) async fn latency_stats(ist: web::Data<IngestStats>)
+-> HttpResponse {
+  return HttpResponse::Ok().json(ist.latency_report());
+}
+
+/// Returns how every background job is doing.
+pub(crate) async fn job_stats(jobs: web::Data<Jobs>) -> HttpResponse {
+  return HttpResponse::Ok().json(jobs.report());
+}
+
+/// Returns metrics in the Prometheus text format.
+pub(crate) async fn metrics(
+  ist: web::Data<IngestStats>,
+  rls: web::Data<RateLimits>,
+  jobs: web::Data<Jobs>
+) -> HttpResponse {
+  let mut out = String::new();
+  ist.render_prometheus(&mut out);
+  rls.render_prometheus(&mut out);
+  jobs.render_prometheus(&mut out);
+  return HttpResponse::Ok()
+    .content_type("text/plain; version=0.0.4")
+    .body(out);
+}
+
+/// Returns all sensor calibrations.
+pub(crate) async fn calibrations<D: AsyncApiDatabase>(db: web::Data<D>)
+-> HttpResponse {
+  return match db.calibrations().await {
+    Ok(cals) => HttpResponse::Ok().json(cals),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Sets, or with None removes, the calibration for a single sensor, and
+/// audits it.
+async fn change_calibration<D: AsyncApiDatabase>(
+  req: &HttpRequest, cfg: &ApiConfig, db: &D, tname: &str, sensor_id: SensorId,
+  cal: Option<Calibration>
+) -> HttpResponse {
+  let stype = match SensorType::from_str(tname) {
+    Ok(st) => st,
+    Err(_) => return no_such_sensor_type(),
+  };
+  let before = match db.calibration(stype, sensor_id).await {
+    Ok(c) => c,
+    Err(e) => return db_error(e),
+  };
+  if let Err(e) = db.set_calibration(stype, sensor_id, cal).await {
+    return db_error(e);
+  }
+  let action = match cal {
+    Some(_) => "calibration.set",
+    None => "calibration.remove",
+  };
+  audit::record(db, AuditEntry::new(actor(req, cfg), action)
+    .target(format!("{}:{}", stype, sensor_id))
+    .before(&before)
+    .after(&cal)).await;
+  return HttpResponse::Ok().body("OK");
+}
+
+/// Sets the calibration for a single sensor. Admin only.
+pub(crate) async fn set_calibration<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  path: web::Path<(String, SensorId)>,
+  cal: web::Json<Calibration>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let (tname, sensor_id) = path.into_inner();
+  let cal = Some(cal.into_inner());
+  return change_calibration(&req, &cfg, db.get_ref(), &tname, sensor_id, cal)
+    .await;
+}
+
+/// Removes the calibration for a single sensor. Admin only.
+pub(crate) async fn remove_calibration<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  path: web::Path<(String, SensorId)>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let (tname, sensor_id) = path.into_inner();
+  return change_calibration(&req, &cfg, db.get_ref(), &tname, sensor_id, None)
+    .await;
+}
+
+/// Returns the name and room of every registered sensor.
+pub(crate) async fn sensors<D: AsyncApiDatabase>(db: web::Data<D>)
+-> HttpResponse {
+  return match db.registered_sensors().await {
+    Ok(mut rss) => {
+      rss.sort_by_key(|rs| (rs.sensor_type, rs.sensor_id));
+      HttpResponse::Ok().json(rss)
+    },
+    Err(e) => db_error(e),
+  };
+}
+
+/// Sets, or with None removes, the name and room of a single sensor, and
+/// audits it.
+async fn change_sensor_info<D: AsyncApiDatabase>(
+  req: &HttpRequest, cfg: &ApiConfig, db: &D, tname: &str, sensor_id: SensorId,
+  info: Option<SensorInfo>
+) -> HttpResponse {
+  let stype = match SensorType::from_str(tname) {
+    Ok(st) => st,
+    Err(_) => return no_such_sensor_type(),
+  };
+  let before = match db.sensor_info(stype, sensor_id).await {
+    Ok(i) => i,
+    Err(e) => return db_error(e),
+  };
+  if let Err(e) = db.set_sensor_info(stype, sensor_id, info.clone()).await {
+    return db_error(e);
+  }
+  let action = match info {
+    Some(_) => "sensor.set",
+    None => "sensor.remove",
+  };
+  audit::record(db, AuditEntry::new(actor(req, cfg), action)
+    .target(format!("{}:{}", stype, sensor_id))
+    .before(&before)
+    .after(&info)).await;
+  return HttpResponse::Ok().body("OK");
+}
+
+/// Names a single sensor, and says which room it's in. Admin only.
+pub(crate) async fn set_sensor_info<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  path: web::Path<(String, SensorId)>,
+  info: web::Json<SensorInfo>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let (tname, sensor_id) = path.into_inner();
+  let info = info.into_inner().tidy();
+  if info.is_empty() {
+    return ApiError::unprocessable(
+      "no_sensor_info", "Give it a name, a room, or both."
+    ).response();
+  }
+  let db = db.get_ref();
+  return change_sensor_info(&req, &cfg, db, &tname, sensor_id, Some(info))
+    .await;
+}
+
+/// Forgets the name and room of a single sensor. Admin only.
+pub(crate) async fn remove_sensor_info<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  path: web::Path<(String, SensorId)>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let (tname, sensor_id) = path.into_inner();
+  let db = db.get_ref();
+  return change_sensor_info(&req, &cfg, db, &tname, sensor_id, None)
+    .await;
+}
+
+/// Registered sensors, grouped by room, rooms sorted by name. Sensors with
+/// no room are left out.
+fn rooms_of(sensors: Vec<RegisteredSensor>) -> Vec<RoomView> {
+  let mut rooms: Vec<RoomView> = Vec::new();
+  for rs in sensors {
+    let room = match &rs.info.room {
+      Some(r) => r.clone(),
+      None => continue,
+    };
+    match rooms.iter_mut().find(|rv| rv.room == room) {
+      Some(rv) => rv.sensors.push(rs),
+      None => rooms.push(RoomView { room: room, sensors: vec![rs] }),
+    }
+  }
+  rooms.sort_by(|a, b| a.room.cmp(&b.room));
+  for rv in rooms.iter_mut() {
+    rv.sensors.sort_by_key(|rs| (rs.sensor_type, rs.sensor_id));
+  }
+  return rooms;
+}
+
+/// Returns every room, with its sensors.
+pub(crate) async fn rooms<D: AsyncApiDatabase>(db: web::Data<D>)
+-> HttpResponse {
+  return match db.registered_sensors().await {
+    Ok(rss) => HttpResponse::Ok().json(rooms_of(rss)),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Returns the latest reading of every sensor in a room, with their names
+/// and averages per sensor type.
+pub(crate) async fn room_current<D: AsyncApiDatabase>(
+  path: web::Path<String>,
+  db: web::Data<D>,
+  lvc: web::Data<LastValueCache>
+) -> HttpResponse {
+  let room = path.into_inner();
+  let rss = match db.registered_sensors().await {
+    Ok(rss) => rss,
+    Err(e) => return db_error(e),
+  };
+  let rv = match rooms_of(rss).into_iter().find(|rv| rv.room == room) {
+    Some(rv) => rv,
+    None => return ApiError::not_found("no_such_room", "No such room.")
+      .response(),
+  };
+  let readings = rv.sensors.into_iter()
+    .filter_map(|rs| Some(NamedReadingView {
+      message: BrokerMessageView::from(lvc.get(rs.sensor_type, rs.sensor_id)?),
+      name: rs.info.name
+    }))
+    .collect();
+  return HttpResponse::Ok().json(RoomCurrentView::new(room, readings));
+}
+
+/// Returns the floorplan. An empty one if none was ever uploaded.
+pub(crate) async fn floorplan<D: AsyncApiDatabase>(db: web::Data<D>)
+-> HttpResponse {
+  return match db.floorplan().await {
+    Ok(plan) => HttpResponse::Ok().json(plan.unwrap_or_default()),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Replaces the floorplan, as long as every sensor placed in it is either
+/// registered or has been heard from, and audits it. Admin only.
+pub(crate) async fn set_floorplan<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  plan: web::Json<Floorplan>,
+  db: web::Data<D>,
+  lvc: web::Data<LastValueCache>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let plan = plan.into_inner();
+  let registered = match db.registered_sensors().await {
+    Ok(rss) => rss,
+    Err(e) => return db_error(e),
+  };
+  let known = |stype: SensorType, sensor_id: SensorId| {
+    return lvc.get(stype, sensor_id).is_some() || registered.iter()
+      .any(|rs| rs.sensor_type == stype && rs.sensor_id == sensor_id);
+  };
+  if let Err(e) = plan.validate(known) {
+    return ApiError::unprocessable(e.code(), e.to_string()).response();
+  }
+  let before = match db.floorplan().await {
+    Ok(p) => p,
+    Err(e) => return db_error(e),
+  };
+  if let Err(e) = db.set_floorplan(Some(plan.clone())).await {
+    return db_error(e);
+  }
+  let db = db.get_ref();
+  audit::record(db, AuditEntry::new(actor(&req, &cfg), "floorplan.set")
+    .before(&before)
+    .after(&plan)).await;
+  return HttpResponse::Ok().body("OK");
+}
+
+/// Query parameters for /reports.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ReportsQuery {
+  /// Only reports covering this long.
+  period: Option<ReportPeriod>
+}
+
+/// Returns the summary reports made so far, newest first.
+pub(crate) async fn reports<D: AsyncApiDatabase>(
+  query: web::Query<ReportsQuery>,
+  db: web::Data<D>
+) -> HttpResponse {
+  return match db.reports().await {
+    Ok(reports) => HttpResponse::Ok().json(reports
+      .into_iter()
+      .rev()
+      .filter(|r| query.period.map(|p| r.period == p).unwrap_or(true))
+      .collect::<Vec<_>>()
+    ),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Returns a single summary report.
+pub(crate) async fn report<D: AsyncApiDatabase>(
+  path: web::Path<String>,
+  db: web::Data<D>
+) -> HttpResponse {
+  let no_such_report = || ApiError::not_found(
+    "no_such_report", "No such report."
+  ).response();
+  let id = match Uuid::parse_str(&path.into_inner()) {
+    Ok(u) => u,
+    Err(_) => return no_such_report(),
+  };
+  return match db.reports().await {
+    Ok(reports) => match reports.into_iter().find(|r| r.id == id) {
+      Some(r) => HttpResponse::Ok().json(r),
+      None => no_such_report(),
+    },
+    Err(e) => db_error(e),
+  };
+}
+
+/// Query parameters for /devices/low_battery.
+#[derive(Debug, Deserialize)]
+pub(crate) struct LowBatteryQuery {
+  /// Overrides the configured threshold, in percent.
+  threshold: Option<u8>
+}
+
+/// Returns the latest health message of every device whose battery is below
+/// the threshold.
+pub(crate) async fn low_battery<D: AsyncApiDatabase>(
+  query: web::Query<LowBatteryQuery>,
+  cfg: web::Data<ApiConfig>,
+  db: web::Data<D>
+) -> HttpResponse {
+  let threshold = query.threshold.unwrap_or(cfg.low_battery_threshold);
+  let mtype = BrokerMessagePayloadType::DeviceHealth;
+  let mut health = match db.query(MessageFilter::of_type(mtype)).await {
+    Ok(pages) => pages,
+    Err(e) => return db_error(e),
+  };
+  // keep only the latest message from each device
+  let mut latest: HashMap<SensorId, BrokerMessage> = HashMap::new();
+  while let Some(page) = health.next_page().await {
+    for msg in page {
+      if let BrokerMessagePayload::DeviceHealth(dh) = &msg.payload {
+        let newer = latest
+          .get(&SensorId(dh.sensor_id))
+          .map(|prev| prev.constructed_when < msg.constructed_when)
+          .unwrap_or(true);
+        if newer {
+          latest.insert(SensorId(dh.sensor_id), msg);
+        }
+      }
+    }
+  }
+  let low: Vec<BrokerMessage> = latest
+    .into_iter()
+    .map(|(_, msg)| msg)
+    .filter(|msg| match &msg.payload {
+      BrokerMessagePayload::DeviceHealth(dh) => {
+        dh.battery_percent().map(|b| b < threshold).unwrap_or(false)
+      },
+      _ => false,
+    })
+    .collect();
+  return HttpResponse::Ok().json(low);
+}
+
+/// Query parameters for /decode-failures and /devices/decode_failures.
+#[derive(Debug, Deserialize)]
+pub(crate) struct DecodeFailureQuery {
+  /// Only those from this broker.
+  broker_id: Option<BrokerId>,
+  /// Only those published to this topic.
+  topic: Option<String>,
+  /// Most returned, newest first. None means all of them.
+  limit: Option<usize>
+}
+
+/// Decode failures a query asks for, oldest first.
+fn decode_failure_filter(q: &DecodeFailureQuery) -> MessageFilter {
+  return MessageFilter {
+    broker_id: q.broker_id,
+    topic: q.topic.clone(),
+    ..MessageFilter::of_type(BrokerMessagePayloadType::DecodeFailure)
+      .ordered(MessageOrder::Constructed)
+  };
+}
+
+/// Returns the payloads brokers couldn't decode, newest first.
+pub(crate) async fn decode_failure_reports<D: AsyncApiDatabase>(
+  query: web::Query<DecodeFailureQuery>,
+  db: web::Data<D>
+) -> HttpResponse {
+  let q = query.into_inner();
+  let filter = MessageFilter {
+    limit: q.limit,
+    ..decode_failure_filter(&q).newest_first()
+  };
+  return match db.query(filter).await {
+    Ok(pages) => json_pages(pages, |msg| DecodeFailureView::new(&msg)),
+    Err(e) => db_error(e),
+  };
+}
+
+/// Returns every broker and topic some payload that didn't decode came
+/// from, most recently troubled first.
+pub(crate) async fn decode_failures<D: AsyncApiDatabase>(
+  query: web::Query<DecodeFailureQuery>,
+  db: web::Data<D>
+) -> HttpResponse {
+  let q = query.into_inner();
+  let mut failures = match db.query(decode_failure_filter(&q)).await {
+    Ok(pages) => pages,
+    Err(e) => return db_error(e),
+  };
+  let mut devices: HashMap<(BrokerId, String), ProblemDeviceView> =
+    HashMap::new();
+  while let Some(page) = failures.next_page().await {
+    for msg in page {
+      let (topic, bytes, error) = match msg.payload {
+        BrokerMessagePayload::DecodeFailure { topic, bytes, error } => {
+          (topic, bytes, error)
+        },
+        _ => continue,
+      };
+      let (broker_id, when) = (msg.broker_id, msg.constructed_when);
+      let dev = devices
+        .entry((broker_id, topic.clone()))
+        .or_insert_with(|| ProblemDeviceView {
+          broker_id: broker_id,
+          topic: topic,
+          failures: 0,
+          first_seen: when,
+          last_seen: when,
+          last_error: error.clone(),
+          last_bytes: bytes.clone()
+        });
+      dev.failures += 1;
+      dev.first_seen = dev.first_seen.min(when);
+      if when >= dev.last_seen {
+        dev.last_seen = when;
+        dev.last_error = error;
+        dev.last_bytes = bytes;
+      }
+    }
+  }
+  let mut devices: Vec<ProblemDeviceView> = devices
+    .into_iter()
+    .map(|(_, dev)| dev)
+    .collect();
+  devices.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+  devices.truncate(q.limit.unwrap_or(usize::MAX));
+  return HttpResponse::Ok().json(devices);
+}
+
+/// Names of the configured virtual sensors.
+fn derived_names(cfg: &ApiConfig) -> Vec<String> {
+  return cfg.derived.iter().map(|ds| ds.name.clone()).collect();
+}
+
+/// Downloads a gzipped snapshot of the whole database, whatever the backend.
+/// It's streamed out as it's made, so if that fails midway, the download is
+/// cut short, and won't unzip.
+pub(crate) async fn backup<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let chunks = backup::stream(db.sync().clone(), derived_names(&cfg))
+    .map(|c| c.map(web::Bytes::from).map_err(actix_web::Error::from));
+  return HttpResponse::Ok()
+    .content_type("application/gzip")
+    .header(
+      CONTENT_DISPOSITION, "attachment; filename=\"cdp_backup.json.gz\""
+    )
+    .streaming(chunks);
+}
+
+/// Loads a backup made by the endpoint above into the database. Whatever's
+/// in there already is skipped, so restoring the same backup twice changes
+/// nothing. Backups that unzip into more than max_restore_unzipped_bytes are
+/// turned away.
+pub(crate) async fn restore<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  body: web::Bytes,
+  db: web::Data<D>,
+  lvc: web::Data<LastValueCache>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let dbc = db.sync().clone();
+  let names = derived_names(&cfg);
+  let max = cfg.max_restore_unzipped_bytes;
+  let res = web::block(move || backup::restore(&dbc, &body, &names, max))
+    .await;
+  let report = match res {
+    Ok(r) => r,
+    Err(BlockingError::Error(e @ MigrationError::TooBig(_))) => {
+      return ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE, "backup_too_big", e.to_string()
+      ).response();
+    },
+    Err(e) => {
+      warn!("Restore failed: {}", e);
+      return ApiError::internal("restore_failed").response();
+    },
+  };
+  // the caches have no idea what just happened, ours or anyone else's
+  match db.latest_per_sensor().await {
+    Ok(latest) => {
+      latest.iter().for_each(|msg| lvc.update(msg));
+      if let Err(e) = db.announce(News::Ingested(latest)).await {
+        warn!("Couldn't tell other instances about a restore: {}", e);
+      }
+    },
+    Err(e) => warn!("Failed to refresh the cache after a restore: {}", e),
+  }
+  audit::record(db.get_ref(), AuditEntry::new(
+    actor(&req, &cfg), "backup.restore"
+  ).after(&report)).await;
+  return HttpResponse::Ok().json(report);
+}
+
+/// Query parameters for /audit.
+#[derive(Debug, Deserialize)]
+pub(crate) struct AuditQuery {
+  /// Start of the range, RFC 3339. None means the dawn of time.
+  from: Option<DateTime<Local>>,
+  /// End of the range, RFC 3339. None means now.
+  to: Option<DateTime<Local>>,
+  /// Only entries by this actor.
+  actor: Option<String>,
+  /// Only entries of this action, like "arming.set", or of these, like
+  /// "alert".
+  action: Option<String>
+}
+
+/// Returns the audit log, oldest first.
+pub(crate) async fn audit_log<D: AsyncApiDatabase>(
+  req: HttpRequest,
+  query: web::Query<AuditQuery>,
+  db: web::Data<D>,
+  cfg: web::Data<ApiConfig>
+) -> HttpResponse {
+  if !is_admin(&req, &cfg) {
+    return ApiError::unauthorized().response();
+  }
+  let entries = match db.audit_log().await {
+    Ok(e) => e,
+    Err(e) => return db_error(e),
+  };
+  let q = query.into_inner();
+  let action_matches = |action: &str| match &q.action {
+    Some(a) => action == a || action.starts_with(&format!("{}.", a)),
+    None => true,
+  };
+  let entries: Vec<AuditEntry> = entries.into_iter()
+    .filter(|e| q.from.map(|f| e.when >= f).unwrap_or(true))
+    .filter(|e| q.to.map(|t| e.when <= t).unwrap_or(true))
+    .filter(|e| q.actor.as_ref().map(|a| &e.actor == a).unwrap_or(true))
+    .filter(|e| action_matches(&e.action))
+    .collect();
+  return HttpResponse::Ok().json(entries);
+}
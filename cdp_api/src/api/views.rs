@@ -0,0 +1,280 @@
+//! Serializable views of stored data, as handed out to API clients.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, MessageId, SensorId};
+use libcdp::units::AnyReading;
+
+use crate::derived::{DerivedReading, DerivedSensor};
+use crate::forecast::Forecast;
+use crate::lastvalue::Changes;
+use crate::sensors::RegisteredSensor;
+
+/// What readings of a sensor type are handed out in: °C for temperature,
+/// even though it goes over the wire in kelvins, and %RH for humidity.
+pub(crate) fn unit_of(stype: SensorType) -> &'static str {
+  return match stype {
+    SensorType::Temperature => "°C",
+    SensorType::Humidity => "%RH",
+  };
+}
+
+/// A reading in the unit people read it in, saying which unit that is, so
+/// clients needn't know how each sensor type goes over the wire.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub(crate) struct Quantity {
+  /// The value, in unit.
+  pub(crate) value: f64,
+  /// What it's in, like "°C".
+  pub(crate) unit: &'static str
+}
+
+/// A broker message as stored, plus the converted reading if it carries
+/// sensor data. Clients get both the raw payload and human units this way.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct BrokerMessageView {
+  /// The message itself, flattened so old clients see the same fields.
+  #[serde(flatten)]
+  pub(crate) message: BrokerMessage,
+  /// Typed reading, with conversions. None for non-sensor messages.
+  pub(crate) reading: Option<AnyReading>,
+  /// The reading as value and unit, side by side with the message's own
+  /// fields. Left out for non-sensor messages.
+  #[serde(flatten)]
+  pub(crate) quantity: Option<Quantity>
+}
+
+impl From<BrokerMessage> for BrokerMessageView {
+  fn from(msg: BrokerMessage) -> Self {
+    let (reading, quantity) = match &msg.payload {
+      BrokerMessagePayload::SensorData(sd) => {
+        let quantity = Quantity {
+          value: sd.reading().human_value(),
+          unit: unit_of(sd.sensor_type())
+        };
+        (Some(sd.reading()), Some(quantity))
+      },
+      _ => (None, None),
+    };
+    return Self {
+      message: msg,
+      reading: reading,
+      quantity: quantity
+    };
+  }
+}
+
+/// Sensors whose reading changed since a cursor, with conversions.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ChangesView {
+  /// Where to ask from next time.
+  pub(crate) cursor: u64,
+  /// Latest message of every sensor that changed.
+  pub(crate) changed: Vec<BrokerMessageView>
+}
+
+impl From<Changes> for ChangesView {
+  fn from(ch: Changes) -> Self {
+    return Self {
+      cursor: ch.cursor,
+      changed: ch.changed.into_iter().map(BrokerMessageView::from).collect()
+    };
+  }
+}
+
+/// The bytes a sensor sent for a message, for looking into bad sensors.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct RawPayloadView {
+  /// Which message they decoded to.
+  pub(crate) id: MessageId,
+  /// The bytes, in base64, as kept.
+  pub(crate) base64: String,
+  /// The bytes, in hex, for reading.
+  pub(crate) hex: String,
+  /// How many bytes there were.
+  pub(crate) len: usize
+}
+
+impl RawPayloadView {
+  /// Builds the view, if the message has an ID and its bytes were kept.
+  pub(crate) fn new(msg: &BrokerMessage) -> Option<Self> {
+    let bytes = msg.raw_payload_bytes()?;
+    return Some(Self {
+      id: msg.id?,
+      base64: msg.raw_payload.clone()?,
+      hex: bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+      len: bytes.len()
+    });
+  }
+}
+
+/// A payload some broker couldn't decode, as it reported it.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct DecodeFailureView {
+  /// ID of the report, if the broker gave it one.
+  pub(crate) id: Option<MessageId>,
+  /// Broker it came through.
+  pub(crate) broker_id: BrokerId,
+  /// When the broker got it.
+  pub(crate) when: DateTime<Local>,
+  /// When the report got here.
+  pub(crate) received_when: Option<DateTime<Local>>,
+  /// Topic it was published to.
+  pub(crate) topic: String,
+  /// Why it didn't decode.
+  pub(crate) error: String,
+  /// The bytes, in base64, as reported.
+  pub(crate) base64: String,
+  /// The bytes, in hex, for reading. Empty if the base64 is bad.
+  pub(crate) hex: String
+}
+
+impl DecodeFailureView {
+  /// Builds the view, if the message is a decode failure.
+  pub(crate) fn new(msg: &BrokerMessage) -> Option<Self> {
+    let (topic, bytes, error) = match &msg.payload {
+      BrokerMessagePayload::DecodeFailure { topic, bytes, error } => {
+        (topic, bytes, error)
+      },
+      _ => return None,
+    };
+    return Some(Self {
+      id: msg.id,
+      broker_id: msg.broker_id,
+      when: msg.constructed_when,
+      received_when: msg.received_when,
+      topic: topic.clone(),
+      error: error.clone(),
+      base64: bytes.clone(),
+      hex: base64::decode(bytes)
+        .map(|b| b.iter().map(|b| format!("{:02x}", b)).collect())
+        .unwrap_or_default()
+    });
+  }
+}
+
+/// Payloads that didn't decode, sent by brokers that keep them, from one
+/// broker and topic: a device acting up, or a few.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ProblemDeviceView {
+  /// Broker they came through.
+  pub(crate) broker_id: BrokerId,
+  /// Topic they were published to.
+  pub(crate) topic: String,
+  /// How many didn't decode.
+  pub(crate) failures: usize,
+  /// When the broker got the first of them.
+  pub(crate) first_seen: DateTime<Local>,
+  /// When the broker got the latest of them.
+  pub(crate) last_seen: DateTime<Local>,
+  /// Why the latest didn't decode.
+  pub(crate) last_error: String,
+  /// The latest one's bytes, in base64.
+  pub(crate) last_bytes: String
+}
+
+/// A virtual sensor definition, along with its latest value.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct DerivedSensorView {
+  /// Unique name.
+  pub(crate) name: String,
+  /// The expression, as written in config.
+  pub(crate) expr: String,
+  /// Unit label, if any.
+  pub(crate) unit: Option<String>,
+  /// Latest computed value, if any.
+  pub(crate) latest: Option<DerivedReading>
+}
+
+impl DerivedSensorView {
+  /// Builds the view from a definition and its latest value.
+  pub(crate) fn new(ds: &DerivedSensor, latest: Option<DerivedReading>)
+  -> Self {
+    return Self {
+      name: ds.name.clone(),
+      expr: ds.source.clone(),
+      unit: ds.unit.clone(),
+      latest: latest
+    };
+  }
+}
+
+/// A room, and the sensors in it.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct RoomView {
+  /// The room's name.
+  pub(crate) room: String,
+  /// Its sensors, by type and ID.
+  pub(crate) sensors: Vec<RegisteredSensor>
+}
+
+/// The latest reading of a registered sensor, along with its name.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct NamedReadingView {
+  /// What the sensor is called, if anything.
+  pub(crate) name: Option<String>,
+  /// The message itself, with conversions.
+  #[serde(flatten)]
+  pub(crate) message: BrokerMessageView
+}
+
+/// What a room is like right now.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct RoomCurrentView {
+  /// The room's name.
+  pub(crate) room: String,
+  /// Latest reading of each sensor in it that's ever been heard from.
+  pub(crate) readings: Vec<NamedReadingView>,
+  /// Mean of those, per sensor type, in human units.
+  pub(crate) averages: BTreeMap<SensorType, f64>
+}
+
+impl RoomCurrentView {
+  /// Builds the view from the latest readings of a room's sensors.
+  pub(crate) fn new(room: String, readings: Vec<NamedReadingView>) -> Self {
+    let mut sums: BTreeMap<SensorType, (f64, usize)> = BTreeMap::new();
+    for nr in readings.iter() {
+      let payload = &nr.message.message.payload;
+      if let BrokerMessagePayload::SensorData(sd) = payload {
+        let sum = sums.entry(sd.sensor_type()).or_insert((0.0, 0));
+        sum.0 += sd.reading().human_value();
+        sum.1 += 1;
+      }
+    }
+    return Self {
+      room: room,
+      readings: readings,
+      averages: sums.into_iter()
+        .map(|(st, (total, n))| (st, total / n as f64))
+        .collect()
+    };
+  }
+}
+
+/// Where a sensor's readings are headed.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ForecastView {
+  /// Type of the sensor.
+  pub(crate) sensor_type: SensorType,
+  /// ID of the sensor.
+  pub(crate) sensor_id: SensorId,
+  /// Its latest reading, in human units.
+  pub(crate) latest: f64,
+  /// What those are, like "°C".
+  pub(crate) unit: &'static str,
+  /// The forecast itself.
+  #[serde(flatten)]
+  pub(crate) forecast: Forecast,
+  /// The threshold asked about, if any.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub(crate) threshold: Option<f64>,
+  /// When the best guess reaches the threshold. None if it doesn't within
+  /// the horizon, or none was asked about.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub(crate) reaches_at: Option<DateTime<Local>>
+}
@@ -0,0 +1,268 @@
+//! Whether the system is armed, and how. Alert rules can be made to only
+//! fire in some states, like motion only mattering when nobody's home.
+//!
+//! Every change is kept, with when and by whom. With a log_path, each is
+//! also appended to a file as JSON, one line each, and read back at startup,
+//! so a restart doesn't disarm anything. Changes are announced through the
+//! alert channels listed under notify.
+//!
+//! Instances sharing a database tell each other about every change, and log
+//! each other's too. Changes go in order of when they were made, so if two
+//! come at once, the later one wins everywhere.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::db::News;
+use crate::notify::{self, ChannelConfig, Notice};
+
+/// Arming settings, as they lie in the config file.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ArmingConfig {
+  /// Where changes are logged and read back from. None means the system
+  /// starts disarmed.
+  pub(crate) log_path: Option<PathBuf>,
+  /// Names of the alert channels to announce changes to.
+  #[serde(default)]
+  pub(crate) notify: Vec<String>
+}
+
+impl ArmingConfig {
+  /// Checks that notify names known channels.
+  pub(crate) fn check(&self, channels: &HashMap<String, ChannelConfig>)
+  -> Result<(), String> {
+    for name in &self.notify {
+      if !channels.contains_key(name) {
+        return Err(format!("Unknown alert channel \"{}\".", name));
+      }
+    }
+    return Ok(());
+  }
+}
+
+/// How the system is armed.
+#[derive(
+  Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash
+)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ArmingState {
+  /// Nothing is watched for.
+  Disarmed,
+  /// Somebody's home.
+  ArmedHome,
+  /// Nobody's home.
+  ArmedAway
+}
+
+impl Default for ArmingState {
+  fn default() -> Self {
+    return Self::Disarmed;
+  }
+}
+
+impl Display for ArmingState {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return write!(f, "{}", match self {
+      ArmingState::Disarmed => "disarmed",
+      ArmingState::ArmedHome => "armed_home",
+      ArmingState::ArmedAway => "armed_away",
+    });
+  }
+}
+
+/// A change of state.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ArmingChange {
+  /// What it went to.
+  pub(crate) state: ArmingState,
+  /// What it was.
+  pub(crate) from: ArmingState,
+  /// When.
+  pub(crate) when: DateTime<Local>,
+  /// Who did it.
+  pub(crate) by: String,
+  /// Anything they had to say.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) note: Option<String>
+}
+
+/// Where the system is at, and how it got there.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ArmingStatus {
+  /// How it's armed.
+  pub(crate) state: ArmingState,
+  /// Every change, oldest first.
+  pub(crate) history: Vec<ArmingChange>
+}
+
+/// Why a change didn't go through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ArmingError {
+  /// It's already in that state.
+  AlreadyThere(ArmingState)
+}
+
+impl std::error::Error for ArmingError {}
+
+impl Display for ArmingError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      ArmingError::AlreadyThere(st) => write!(f, "Already {}.", st),
+    };
+  }
+}
+
+/// The changes, behind the lock.
+#[derive(Debug, Default)]
+struct Ledger {
+  /// Every change, oldest first.
+  history: Vec<ArmingChange>,
+  /// Where changes are logged. None means nowhere.
+  log: Option<File>,
+  /// Where to tell other instances about them. None means nobody's told.
+  news: Option<Sender<News>>
+}
+
+impl Ledger {
+  /// How the system is armed.
+  fn state(&self) -> ArmingState {
+    return self.history.last().map(|c| c.state).unwrap_or_default();
+  }
+
+  /// Appends a change to the log, if there's a log.
+  fn append(&mut self, change: &ArmingChange) {
+    let res = match (&mut self.log, serde_json::to_string(change)) {
+      (Some(f), Ok(line)) => writeln!(f, "{}", line),
+      (Some(_), Err(e)) => Err(e.into()),
+      (None, _) => Ok(()),
+    };
+    if let Err(e) = res {
+      warn!("Failed to log an arming change: {}", e);
+    }
+  }
+
+  /// Takes in a change, after every one made before it.
+  fn record(&mut self, change: ArmingChange) {
+    let at = self.history.iter()
+      .rposition(|c| c.when <= change.when)
+      .map(|i| i + 1)
+      .unwrap_or(0);
+    self.history.insert(at, change);
+  }
+}
+
+/// The arming state machine. Cheap to clone, all clones share the same
+/// state.
+#[derive(Clone, Debug)]
+pub(crate) struct Arming {
+  /// Channels to announce changes to, by name.
+  channels: Arc<Vec<(String, ChannelConfig)>>,
+  /// The changes.
+  ledger: Arc<Mutex<Ledger>>
+}
+
+impl Arming {
+  /// Sets it up, reading back whatever was logged. The channels are the
+  /// alert ones, which notify goes by.
+  pub(crate) fn open(
+    cfg: &ArmingConfig, channels: &HashMap<String, ChannelConfig>
+  ) -> io::Result<Self> {
+    let mut ledger = Ledger::default();
+    if let Some(path) = &cfg.log_path {
+      if path.exists() {
+        for line in BufReader::new(File::open(path)?).lines() {
+          match serde_json::from_str::<ArmingChange>(&line?) {
+            Ok(change) => ledger.record(change),
+            Err(_) => warn!("Skipped a bad line in the arming log."),
+          }
+        }
+      }
+      ledger.log = Some(
+        OpenOptions::new().create(true).append(true).open(path)?
+      );
+    }
+    let channels = cfg.notify.iter()
+      .filter_map(|n| Some((n.clone(), channels.get(n)?.clone())))
+      .collect();
+    return Ok(Self {
+      channels: Arc::new(channels),
+      ledger: Arc::new(Mutex::new(ledger))
+    });
+  }
+
+  /// Tells other instances about every change made from now on.
+  pub(crate) fn share(&self, news: Sender<News>) {
+    self.lock().news = Some(news);
+  }
+
+  /// Takes in a change another instance made, and logs it here too, without
+  /// telling anyone.
+  pub(crate) fn apply(&self, change: ArmingChange) {
+    let mut ledger = self.lock();
+    ledger.append(&change);
+    info!("System {} by {}, elsewhere.", change.state, change.by);
+    ledger.record(change);
+  }
+
+  /// Used to acquire a lock on the changes.
+  fn lock(&self) -> std::sync::MutexGuard<'_, Ledger> {
+    return self.ledger.lock().unwrap_or_else(|e| e.into_inner());
+  }
+
+  /// How the system is armed.
+  pub(crate) fn state(&self) -> ArmingState {
+    return self.lock().state();
+  }
+
+  /// Where the system is at, and how it got there.
+  pub(crate) fn status(&self) -> ArmingStatus {
+    let ledger = self.lock();
+    return ArmingStatus {
+      state: ledger.state(),
+      history: ledger.history.clone()
+    };
+  }
+
+  /// Arms or disarms the system, logging and announcing it.
+  pub(crate) fn set(
+    &self, state: ArmingState, by: String, note: Option<String>
+  ) -> Result<ArmingChange, ArmingError> {
+    let mut ledger = self.lock();
+    let from = ledger.state();
+    if from == state {
+      return Err(ArmingError::AlreadyThere(state));
+    }
+    let change = ArmingChange {
+      state: state,
+      from: from,
+      when: Local::now(),
+      by: by,
+      note: note
+    };
+    ledger.append(&change);
+    if let Some(tx) = &ledger.news {
+      let _ = tx.send(News::Arming(change.clone()));
+    }
+    ledger.record(change.clone());
+    info!("System {} by {}, was {}.", state, change.by, from);
+    let headline = format!("System {} by {}", state, change.by);
+    notify::spawn_send(self.channels.to_vec(), Notice {
+      body: match &change.note {
+        Some(n) => format!("{}, was {}.\n\n{}\n", headline, from, n),
+        None => format!("{}, was {}.\n", headline, from),
+      },
+      headline: headline,
+      payload: json!({ "arming": &change })
+    });
+    return Ok(change);
+  }
+}
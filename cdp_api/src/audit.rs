@@ -0,0 +1,92 @@
+//! Who changed what, and when. Every request that changes something, other
+//! than ingestion, gets an entry with what was there before and after, so
+//! a household sharing the system can tell who disarmed it at 3 AM.
+//!
+//! Entries are kept in the database, append-only: nothing ever edits or
+//! removes them, and they go along with backups and migrations.
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::db::AsyncApiDatabase;
+
+/// Something somebody changed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct AuditEntry {
+  /// Unique ID.
+  pub(crate) id: Uuid,
+  /// When.
+  pub(crate) when: DateTime<Local>,
+  /// Who, as far as we can vouch for: how they got in, else where from.
+  pub(crate) actor: String,
+  /// Whoever they said they were, if they said. Nothing checks it.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) claimed_by: Option<String>,
+  /// What they did, like "calibration.set".
+  pub(crate) action: String,
+  /// What they did it to, if anything in particular.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) target: Option<String>,
+  /// How it was before. None if it wasn't there.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) before: Option<Value>,
+  /// How it was after. None if it's gone.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) after: Option<Value>
+}
+
+impl AuditEntry {
+  /// An entry for something done now.
+  pub(crate) fn new<S: Into<String>>(actor: String, action: S) -> Self {
+    return Self {
+      id: Uuid::new_v4(),
+      when: Local::now(),
+      actor: actor,
+      claimed_by: None,
+      action: action.into(),
+      target: None,
+      before: None,
+      after: None
+    };
+  }
+
+  /// Says who they said they were, if they said anything.
+  pub(crate) fn claimed_by(mut self, by: Option<&str>) -> Self {
+    self.claimed_by = by
+      .map(str::trim)
+      .filter(|b| !b.is_empty())
+      .map(str::to_owned);
+    return self;
+  }
+
+  /// Says what it was done to.
+  pub(crate) fn target<S: Into<String>>(mut self, target: S) -> Self {
+    self.target = Some(target.into());
+    return self;
+  }
+
+  /// Says how it was before.
+  pub(crate) fn before<T: Serialize>(mut self, before: &T) -> Self {
+    self.before = serde_json::to_value(before).ok().filter(|v| !v.is_null());
+    return self;
+  }
+
+  /// Says how it was after.
+  pub(crate) fn after<T: Serialize>(mut self, after: &T) -> Self {
+    self.after = serde_json::to_value(after).ok().filter(|v| !v.is_null());
+    return self;
+  }
+}
+
+/// Stores an entry. What it's about already happened, so failing to is
+/// only logged.
+pub(crate) async fn record<D: AsyncApiDatabase>(
+  db: &D, entry: AuditEntry
+) {
+  let what = format!("{} by {}", entry.action, entry.actor);
+  if let Err(e) = db.insert_audit(entry).await {
+    warn!("Failed to audit {}: {}", what, e);
+  }
+}
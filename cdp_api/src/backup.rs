@@ -0,0 +1,183 @@
+//! Backups that don't care about the backend: everything gets written out,
+//! a piece at a time, as a gzipped in-memory database snapshot, which can be
+//! restored into any backend, or loaded as an in-memory snapshot file after
+//! unzipping.
+
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::thread;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use futures::SinkExt;
+use futures::channel::mpsc::{self, Receiver, Sender};
+use futures::executor::block_on;
+use serde::Serialize;
+
+use libcdp::comm::broker_api::BrokerMessagePayloadType;
+
+use crate::db::{ApiDatabase, MessageFilter};
+use crate::db::inmem::InMemoryApiDatabase;
+use crate::migrate::{self, MigrationError, MigrationReport};
+
+/// Bytes handed out at a time when streaming a backup.
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// Chunks a backup may get ahead of whoever's downloading it.
+const CHUNKS_AHEAD: usize = 4;
+
+/// For turning anything that goes wrong while writing into an error.
+fn write_err<E: Display>(e: E) -> MigrationError {
+  return MigrationError::Write(e.to_string());
+}
+
+/// Writes some raw JSON, like a field name, then a value as JSON.
+fn put<W: Write, T: Serialize + ?Sized>(out: &mut W, raw: &str, value: &T)
+-> Result<(), MigrationError> {
+  out.write_all(raw.as_bytes()).map_err(write_err)?;
+  return serde_json::to_writer(out, value).map_err(write_err);
+}
+
+/// Writes a gzipped snapshot of everything in the database to out, one
+/// thing at a time, so nothing has to be held whole. Derived readings are
+/// only included for the virtual sensors named.
+pub(crate) fn backup<D: ApiDatabase, W: Write>(
+  db: &D, derived_names: &[String], out: W
+) -> Result<(), MigrationError> {
+  let read_err = |e: D::DbError| MigrationError::Read(e.to_string());
+  let mut gz = GzEncoder::new(out, Compression::default());
+  // same fields as an in-memory database snapshot, so it loads as one
+  put(&mut gz, "{\"topics\":", &db.topics().map_err(read_err)?)?;
+  let mut sep = ",\"messages\":[";
+  let mut last_seq = 0;
+  for mtype in BrokerMessagePayloadType::all_types() {
+    for msg in db.query(MessageFilter::of_type(mtype)).map_err(read_err)? {
+      last_seq = last_seq.max(msg.seq.unwrap_or(0));
+      put(&mut gz, sep, &msg)?;
+      sep = ",";
+    }
+  }
+  if sep != "," {
+    gz.write_all(sep.as_bytes()).map_err(write_err)?;
+  }
+  put(&mut gz, "],\"calibrations\":", &db.calibrations().map_err(read_err)?)?;
+  put(&mut gz, ",\"sensors\":", &db.registered_sensors().map_err(read_err)?)?;
+  put(&mut gz, ",\"floorplan\":", &db.floorplan().map_err(read_err)?)?;
+  let mut sep = ",\"derived\":[";
+  for name in derived_names {
+    for reading in db.derived_readings(name).map_err(read_err)? {
+      put(&mut gz, sep, &reading)?;
+      sep = ",";
+    }
+  }
+  if sep != "," {
+    gz.write_all(sep.as_bytes()).map_err(write_err)?;
+  }
+  put(&mut gz, "],\"brokers\":", &db.brokers().map_err(read_err)?)?;
+  put(&mut gz, ",\"audit\":", &db.audit_log().map_err(read_err)?)?;
+  put(&mut gz, ",\"reports\":", &db.reports().map_err(read_err)?)?;
+  put(&mut gz, ",\"last_seq\":", &last_seq)?;
+  gz.write_all(b"}").map_err(write_err)?;
+  return gz.finish().and_then(|mut out| out.flush()).map_err(write_err);
+}
+
+/// Hands whatever's written to it over a channel, in chunks.
+struct ChunkSender {
+  /// What's been written since the last chunk went.
+  buf: Vec<u8>,
+  /// Where chunks go.
+  tx: Sender<io::Result<Vec<u8>>>
+}
+
+impl ChunkSender {
+  /// Sends what's buffered, waiting for room in the channel.
+  fn send(&mut self) -> io::Result<()> {
+    let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_LEN));
+    return block_on(self.tx.send(Ok(chunk))).map_err(|_| io::Error::new(
+      io::ErrorKind::BrokenPipe, "nobody's downloading the backup anymore"
+    ));
+  }
+}
+
+impl Write for ChunkSender {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.buf.extend_from_slice(buf);
+    if self.buf.len() >= CHUNK_LEN {
+      self.send()?;
+    }
+    return Ok(buf.len());
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    if self.buf.is_empty() {
+      return Ok(());
+    }
+    return self.send();
+  }
+}
+
+/// Makes a backup on a thread of its own, handing it out in chunks as it
+/// goes. If it fails midway, the last thing out is the error.
+pub(crate) fn stream<D: ApiDatabase + 'static>(
+  db: D, derived_names: Vec<String>
+) -> Receiver<io::Result<Vec<u8>>> {
+  let (mut tx, rx) = mpsc::channel(CHUNKS_AHEAD);
+  let out = ChunkSender {
+    buf: Vec::with_capacity(CHUNK_LEN),
+    tx: tx.clone()
+  };
+  thread::Builder::new()
+    .name("backup".to_owned())
+    .spawn(move || {
+      if let Err(e) = backup(&db, &derived_names, out) {
+        warn!("Backup failed: {}", e);
+        let _ = block_on(tx.send(Err(io::Error::other(e.to_string()))));
+      }
+    })
+    .expect("Couldn't start a thread!");
+  return rx;
+}
+
+/// Reads up to a limit, then fails, rather than stopping quietly.
+struct Capped<R> {
+  /// What's read from.
+  inner: R,
+  /// Bytes still allowed.
+  left: u64,
+  /// Whether it went over.
+  over: bool
+}
+
+impl<R: Read> Read for Capped<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.inner.read(buf)?;
+    if n as u64 > self.left {
+      self.over = true;
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData, "too big once unzipped"
+      ));
+    }
+    self.left -= n as u64;
+    return Ok(n);
+  }
+}
+
+/// Copies everything in a gzipped snapshot into the database, skipping
+/// whatever's in there already, so restoring the same backup twice does no
+/// harm. Gives up if it unzips into more than max_unzipped bytes.
+pub(crate) fn restore<D: ApiDatabase>(
+  db: &D, gzipped: &[u8], derived_names: &[String], max_unzipped: u64
+) -> Result<MigrationReport, MigrationError> {
+  let mut unzipped = Capped {
+    inner: GzDecoder::new(gzipped),
+    left: max_unzipped,
+    over: false
+  };
+  let read = InMemoryApiDatabase::read_snapshot(&mut unzipped);
+  if unzipped.over {
+    return Err(MigrationError::TooBig(max_unzipped));
+  }
+  let copy = read.map_err(|e| MigrationError::Read(e.to_string()))?;
+  return migrate::merge(&copy, db, derived_names);
+}
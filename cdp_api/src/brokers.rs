@@ -0,0 +1,546 @@
+//! What we know about the brokers out there, as told by their heartbeats.
+//!
+//! Also their keys, once an admin rotates one: the new key is handed to the
+//! broker in the reply to its next heartbeat, and the old one keeps working
+//! for a grace period, until the broker is heard using the new one. Brokers
+//! whose keys we don't know are taken at their word, as they always were,
+//! but the key they call in with is noted, so it's the old one when theirs
+//! is first rotated. Bundles must come with a broker's key too, once we
+//! know it.
+//! Brokers that enrolled themselves get their first key on approval, and
+//! can't call in until then.
+//!
+//! And the settings each broker should run with, if an admin set any: they
+//! go out with heartbeat replies until the broker reports their hash back.
+//! Update orders go out the same way; see updates. Neither goes to brokers
+//! that say they can't take them.
+//!
+//! Brokers also say what they run and can do. Those speaking a protocol we
+//! can't follow, or that can't follow ours, are flagged and turned away.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error as StdError;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use libcdp::comm::broker_api::{self, BrokerMessage, BrokerStatus, HeartbeatMessage, HeartbeatReply, RemoteConfig, SoftwareInfo};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::BrokerId;
+
+use crate::db::ApiDatabase;
+use crate::enrollment::Enrollment;
+use crate::updates::UpdateJob;
+
+/// The latest news from a single broker.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct BrokerRecord {
+  /// The unique id of the broker.
+  pub(crate) uid: BrokerId,
+  /// Heartbeat format version the broker spoke last time. 0 if it only
+  /// enrolled so far.
+  pub(crate) version: u32,
+  /// When we first heard from it.
+  pub(crate) first_seen: DateTime<Local>,
+  /// When we last heard from it.
+  pub(crate) last_heartbeat: DateTime<Local>,
+  /// How it was doing, if it told us.
+  pub(crate) status: Option<BrokerStatus>,
+  /// Its keys, once one was rotated. Never served.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) keys: Option<BrokerKeys>,
+  /// The key it last called in with, while it had none of ours. Never
+  /// served.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) last_key: Option<String>,
+  /// How it enrolled, if it did.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) enrollment: Option<Enrollment>,
+  /// Settings it should run with, if an admin set any.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) desired_config: Option<RemoteConfig>,
+  /// Hash of the settings it said it runs with, last time.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) config_hash: Option<String>,
+  /// Its latest update, if it was ever ordered one.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) update: Option<UpdateJob>,
+  /// What it runs, and can do, if it said.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) software: Option<SoftwareInfo>,
+  /// Whether its protocol and ours don't get along.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub(crate) incompatible: bool
+}
+
+/// A broker's keys.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct BrokerKeys {
+  /// The key it should be using.
+  pub(crate) current: String,
+  /// The key it had before. None means there's none, so only the current
+  /// one works.
+  pub(crate) previous: Option<String>,
+  /// Until when the previous key works. None means it doesn't anymore.
+  pub(crate) previous_until: Option<DateTime<Local>>
+}
+
+/// Which of its keys a broker called in with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum KeyCheck {
+  /// The one it should be using.
+  Current,
+  /// The old one, during the grace period.
+  Previous,
+  /// Neither.
+  Wrong
+}
+
+impl BrokerKeys {
+  /// Which key this is, as of a time.
+  pub(crate) fn check(&self, key: Option<&str>, now: DateTime<Local>)
+  -> KeyCheck {
+    let key = key.unwrap_or("");
+    if same(key, &self.current) {
+      return KeyCheck::Current;
+    }
+    let in_grace = self.previous_until.map(|t| now < t).unwrap_or(false);
+    let was = self.previous.as_deref().map(|p| same(key, p)).unwrap_or(false);
+    if in_grace && was {
+      return KeyCheck::Previous;
+    }
+    return KeyCheck::Wrong;
+  }
+}
+
+/// Compares keys, taking the same time wherever they differ.
+fn same(a: &str, b: &str) -> bool {
+  return a.len() == b.len()
+    && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0;
+}
+
+/// A fresh random key.
+pub(crate) fn new_key() -> String {
+  return format!(
+    "{}{}", Uuid::new_v4().to_simple(), Uuid::new_v4().to_simple()
+  );
+}
+
+/// Why a heartbeat was turned away.
+#[derive(Debug)]
+pub(crate) enum HeartbeatError<E: StdError> {
+  /// It came with neither of the broker's keys.
+  WrongKey,
+  /// The broker enrolled, but wasn't approved yet.
+  Pending,
+  /// The broker's protocol and ours don't get along.
+  Incompatible,
+  /// Couldn't read or write the broker's record.
+  Db(E)
+}
+
+impl<E: StdError> StdError for HeartbeatError<E> {}
+
+impl<E: StdError> Display for HeartbeatError<E> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      HeartbeatError::WrongKey => write!(f, "Wrong key."),
+      HeartbeatError::Pending => write!(f, "Enrollment not approved yet."),
+      HeartbeatError::Incompatible => write!(f, "Incompatible protocol."),
+      HeartbeatError::Db(e) => write!(f, "Database error: {}", e),
+    };
+  }
+}
+
+impl BrokerRecord {
+  /// Builds the record for a fresh heartbeat, on top of the previous one.
+  pub(crate) fn from_heartbeat(
+    hb: &HeartbeatMessage, prev: Option<BrokerRecord>
+  ) -> Self {
+    let now = Local::now();
+    let (first_seen, keys, last_key, enrollment, desired_config, update)
+      = match prev {
+        Some(p) => (
+          p.first_seen, p.keys, p.last_key, p.enrollment, p.desired_config,
+          p.update
+        ),
+        None => (now, None, None, None, None, None),
+      };
+    return Self {
+      uid: hb.uid,
+      version: hb.version,
+      first_seen: first_seen,
+      last_heartbeat: now,
+      status: hb.status.clone(),
+      keys: keys,
+      last_key: last_key,
+      enrollment: enrollment,
+      desired_config: desired_config,
+      config_hash: hb.config_hash.clone(),
+      update: update,
+      software: hb.software.clone(),
+      incompatible: hb.software.as_ref()
+        .map(|sw| !sw.compatible())
+        .unwrap_or(false)
+    };
+  }
+
+  /// The record, minus what's not to be served.
+  pub(crate) fn redacted(mut self) -> Self {
+    self.keys = None;
+    self.last_key = None;
+    if let Some(e) = self.enrollment.as_mut() {
+      e.token.clear();
+    }
+    return self;
+  }
+}
+
+/// Takes a heartbeat in, checking its key if we know the broker's. Once the
+/// broker is heard using a new key, the old one stops working; until then,
+/// the reply carries the new key.
+pub(crate) fn heartbeat<D: ApiDatabase>(db: &D, hb: &HeartbeatMessage)
+-> Result<HeartbeatReply, HeartbeatError<D::DbError>> {
+  let prev = db.broker(hb.uid).map_err(HeartbeatError::Db)?;
+  let mut rec = BrokerRecord::from_heartbeat(hb, prev);
+  if rec.enrollment.as_ref().map(|e| e.is_pending()).unwrap_or(false) {
+    return Err(HeartbeatError::Pending);
+  }
+  if rec.incompatible {
+    // kept anyway, so it shows up as such.
+    db.update_broker(rec).map_err(HeartbeatError::Db)?;
+    return Err(HeartbeatError::Incompatible);
+  }
+  let mut reply = HeartbeatReply::default();
+  reply.api = Some(api_software());
+  if let Some(keys) = rec.keys.as_mut() {
+    match keys.check(hb.key.as_deref(), rec.last_heartbeat) {
+      KeyCheck::Current => {
+        keys.previous = None;
+        keys.previous_until = None;
+      },
+      KeyCheck::Previous => reply.new_key = Some(keys.current.clone()),
+      KeyCheck::Wrong => return Err(HeartbeatError::WrongKey),
+    };
+    rec.last_key = None;
+  } else {
+    rec.last_key = hb.key.clone();
+  }
+  // brokers that don't say what they can do are assumed able.
+  let can = |feature: &str| {
+    return rec.software.as_ref().map(|sw| sw.has(feature)).unwrap_or(true);
+  };
+  let takes_config = can(broker_api::features::REMOTE_CONFIG);
+  let takes_updates = can(broker_api::features::UPDATES);
+  if let Some(want) = rec.desired_config.as_ref().filter(|_| takes_config) {
+    if rec.config_hash.as_deref() != Some(want.hash().as_str()) {
+      reply.config = Some(want.clone());
+    }
+  }
+  if let Some(job) = rec.update.as_mut().filter(|_| takes_updates) {
+    reply.update = job.heard(hb.update.as_ref(), rec.last_heartbeat);
+  }
+  db.update_broker(rec).map_err(HeartbeatError::Db)?;
+  return Ok(reply);
+}
+
+/// What we are, and can do.
+pub(crate) fn api_software() -> SoftwareInfo {
+  use broker_api::features::*;
+  return SoftwareInfo::new(env!("CARGO_PKG_VERSION"), &[
+    REMOTE_CONFIG, UPDATES, KEY_ROTATION, ENROLLMENT, SEALING, SIGNING,
+    PROTOBUF, NDJSON, DECODE_FAILURES
+  ]);
+}
+
+/// Who runs what, across the fleet.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct FleetVersions {
+  /// What we run.
+  pub(crate) api: SoftwareInfo,
+  /// How many brokers run each version.
+  pub(crate) versions: BTreeMap<String, usize>,
+  /// How many brokers speak each protocol.
+  pub(crate) protocols: BTreeMap<u32, usize>,
+  /// How many brokers can do each thing.
+  pub(crate) features: BTreeMap<String, usize>,
+  /// Brokers that don't get along with us.
+  pub(crate) incompatible: Vec<BrokerId>,
+  /// Brokers that never said what they run.
+  pub(crate) unknown: Vec<BrokerId>
+}
+
+impl FleetVersions {
+  /// Tallies up some brokers.
+  pub(crate) fn from_records(recs: &[BrokerRecord]) -> Self {
+    let mut fleet = Self {
+      api: api_software(),
+      versions: BTreeMap::new(),
+      protocols: BTreeMap::new(),
+      features: BTreeMap::new(),
+      incompatible: Vec::new(),
+      unknown: Vec::new()
+    };
+    for rec in recs {
+      if rec.incompatible {
+        fleet.incompatible.push(rec.uid);
+      }
+      let sw = match &rec.software {
+        Some(sw) => sw,
+        None => {
+          fleet.unknown.push(rec.uid);
+          continue;
+        },
+      };
+      *fleet.versions.entry(sw.version.clone()).or_insert(0) += 1;
+      *fleet.protocols.entry(sw.protocol).or_insert(0) += 1;
+      for f in &sw.features {
+        *fleet.features.entry(f.clone()).or_insert(0) += 1;
+      }
+    }
+    return fleet;
+  }
+}
+
+/// Checks settings for a broker make sense, and puts topics in order.
+pub(crate) fn check_config(mut cfg: RemoteConfig)
+-> Result<RemoteConfig, String> {
+  if let Some(topics) = cfg.topics.as_mut() {
+    for name in topics.iter() {
+      SensorType::from_str(name)
+        .map_err(|_| format!("Bad sensor type \"{}\".", name))?;
+    }
+    topics.sort();
+    topics.dedup();
+  }
+  if cfg.bundle_size == Some(0) {
+    return Err("bundle_size must be at least 1.".to_owned());
+  }
+  if cfg.heartbeat_interval_secs == Some(0) {
+    return Err("heartbeat_interval_secs must be at least 1.".to_owned());
+  }
+  return Ok(cfg);
+}
+
+/// Sets the settings a broker should run with, which it gets with its next
+/// heartbeat. Returns the record as it was, or None if we never heard from
+/// the broker.
+pub(crate) fn set_desired_config<D: ApiDatabase>(
+  db: &D, uid: BrokerId, cfg: RemoteConfig
+) -> Result<Option<BrokerRecord>, D::DbError> {
+  let before = match db.broker(uid)? {
+    Some(r) => r,
+    None => return Ok(None),
+  };
+  let mut rec = before.clone();
+  rec.desired_config = Some(cfg);
+  db.update_broker(rec)?;
+  return Ok(Some(before));
+}
+
+/// Gives a broker a new key, keeping the one it has working for a grace
+/// period. Returns its keys as they are now. Brokers we haven't heard from
+/// can't have keys. A broker that never called in with a key has none to
+/// keep working, and so can't call in again until it's given the new one
+/// by hand.
+pub(crate) fn rotate_key<D: ApiDatabase>(
+  db: &D, uid: BrokerId, grace: Duration
+) -> Result<Option<BrokerKeys>, D::DbError> {
+  let mut rec = match db.broker(uid)? {
+    Some(r) => r,
+    None => return Ok(None),
+  };
+  let until = Local::now() + grace;
+  let keys = match rec.keys.take() {
+    // rotating again before the broker switched: it's still on the old one
+    Some(k) if k.previous_until.is_some() => BrokerKeys {
+      current: new_key(),
+      previous: k.previous,
+      previous_until: Some(until)
+    },
+    Some(k) => BrokerKeys {
+      current: new_key(),
+      previous: Some(k.current),
+      previous_until: Some(until)
+    },
+    // what it called in with so far, if anything, is the old one
+    None => {
+      let previous = rec.last_key.take();
+      let previous_until = previous.as_ref().map(|_| until);
+      BrokerKeys {
+        current: new_key(),
+        previous: previous,
+        previous_until: previous_until
+      }
+    },
+  };
+  rec.keys = Some(keys.clone());
+  db.update_broker(rec)?;
+  return Ok(Some(keys));
+}
+
+/// Checks the key a bundle came with, against the keys of every broker it
+/// has messages from, as of a time. Returns the first broker it's wrong
+/// for, if any. Brokers whose keys we don't know take any key.
+pub(crate) fn wrong_key_for<D: ApiDatabase>(
+  db: &D, batch: &[BrokerMessage], key: Option<&str>, now: DateTime<Local>
+) -> Result<Option<BrokerId>, D::DbError> {
+  let uids: BTreeSet<BrokerId> = batch.iter().map(|m| m.broker_id).collect();
+  for uid in uids {
+    let keys = db.broker(uid)?.and_then(|r| r.keys);
+    if let Some(keys) = keys {
+      if keys.check(key, now) == KeyCheck::Wrong {
+        return Ok(Some(uid));
+      }
+    }
+  }
+  return Ok(None);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::db::inmem::InMemoryApiDatabase;
+
+  /// Keys whose current one is "new", with some previous one.
+  fn keys(previous: Option<&str>, until: Option<DateTime<Local>>)
+  -> BrokerKeys {
+    return BrokerKeys {
+      current: "new".to_owned(),
+      previous: previous.map(str::to_owned),
+      previous_until: until
+    };
+  }
+
+  /// A heartbeat from a broker, with some key.
+  fn hb(uid: BrokerId, key: Option<&str>) -> HeartbeatMessage {
+    return HeartbeatMessage {
+      version: HeartbeatMessage::VERSION,
+      uid: uid,
+      key: key.map(str::to_owned),
+      status: None,
+      config_hash: None,
+      update: None,
+      software: None
+    };
+  }
+
+  #[test]
+  fn current_key_works_in_and_out_of_grace() {
+    let now = Local::now();
+    let rotated = keys(Some("old"), Some(now + Duration::hours(1)));
+    assert_eq!(rotated.check(Some("new"), now), KeyCheck::Current);
+    assert_eq!(keys(None, None).check(Some("new"), now), KeyCheck::Current);
+  }
+
+  #[test]
+  fn previous_key_works_only_in_grace() {
+    let now = Local::now();
+    let in_grace = keys(Some("old"), Some(now + Duration::hours(1)));
+    let expired = keys(Some("old"), Some(now - Duration::hours(1)));
+    let switched = keys(Some("old"), None);
+    assert_eq!(in_grace.check(Some("old"), now), KeyCheck::Previous);
+    assert_eq!(expired.check(Some("old"), now), KeyCheck::Wrong);
+    assert_eq!(switched.check(Some("old"), now), KeyCheck::Wrong);
+  }
+
+  #[test]
+  fn no_previous_key_means_none_works_in_grace() {
+    let now = Local::now();
+    let in_grace = keys(None, Some(now + Duration::hours(1)));
+    assert_eq!(in_grace.check(Some("old"), now), KeyCheck::Wrong);
+    assert_eq!(in_grace.check(Some(""), now), KeyCheck::Wrong);
+    assert_eq!(in_grace.check(None, now), KeyCheck::Wrong);
+  }
+
+  #[test]
+  fn other_keys_never_work() {
+    let now = Local::now();
+    let in_grace = keys(Some("old"), Some(now + Duration::hours(1)));
+    assert_eq!(in_grace.check(Some("neww"), now), KeyCheck::Wrong);
+    assert_eq!(in_grace.check(Some("ol"), now), KeyCheck::Wrong);
+    assert_eq!(in_grace.check(None, now), KeyCheck::Wrong);
+  }
+
+  #[test]
+  fn first_rotation_keeps_the_key_it_called_in_with() {
+    let db = InMemoryApiDatabase::default();
+    let uid = BrokerId::new_v4();
+    heartbeat(&db, &hb(uid, Some("typed in"))).unwrap();
+    let rotated = rotate_key(&db, uid, Duration::hours(1)).unwrap().unwrap();
+    assert_eq!(rotated.previous.as_deref(), Some("typed in"));
+    assert!(heartbeat(&db, &hb(uid, Some("guess"))).is_err());
+    assert!(heartbeat(&db, &hb(uid, None)).is_err());
+    let reply = heartbeat(&db, &hb(uid, Some("typed in"))).unwrap();
+    assert_eq!(reply.new_key, Some(rotated.current.clone()));
+    heartbeat(&db, &hb(uid, Some(&rotated.current))).unwrap();
+    assert!(heartbeat(&db, &hb(uid, Some("typed in"))).is_err());
+  }
+
+  #[test]
+  fn first_rotation_without_a_key_hands_the_new_one_to_nobody() {
+    let db = InMemoryApiDatabase::default();
+    let uid = BrokerId::new_v4();
+    heartbeat(&db, &hb(uid, None)).unwrap();
+    let rotated = rotate_key(&db, uid, Duration::hours(1)).unwrap().unwrap();
+    assert_eq!(rotated.previous, None);
+    assert!(heartbeat(&db, &hb(uid, None)).is_err());
+    assert!(heartbeat(&db, &hb(uid, Some("anything"))).is_err());
+  }
+
+  #[test]
+  fn bundles_need_the_key_of_every_broker_with_one() {
+    let db = InMemoryApiDatabase::default();
+    let (keyed, open) = (BrokerId::new_v4(), BrokerId::new_v4());
+    heartbeat(&db, &hb(keyed, Some("k"))).unwrap();
+    let rotated = rotate_key(&db, keyed, Duration::hours(1)).unwrap().unwrap();
+    let msg = |uid| BrokerMessage::construct(
+      uid, broker_api::BrokerMessagePayload::Heartbeat(hb(uid, None))
+    );
+    let batch = vec![msg(open), msg(keyed)];
+    let now = Local::now();
+    assert_eq!(wrong_key_for(&db, &batch[..1], None, now).unwrap(), None);
+    assert_eq!(wrong_key_for(&db, &batch, None, now).unwrap(), Some(keyed));
+    let wrong = wrong_key_for(&db, &batch, Some("x"), now).unwrap();
+    assert_eq!(wrong, Some(keyed));
+    assert_eq!(wrong_key_for(&db, &batch, Some("k"), now).unwrap(), None);
+    let current = Some(rotated.current.as_str());
+    assert_eq!(wrong_key_for(&db, &batch, current, now).unwrap(), None);
+  }
+
+  #[test]
+  fn bundles_take_the_previous_key_only_in_grace() {
+    let db = InMemoryApiDatabase::default();
+    let uid = BrokerId::new_v4();
+    heartbeat(&db, &hb(uid, Some("k"))).unwrap();
+    rotate_key(&db, uid, Duration::hours(1)).unwrap().unwrap();
+    let batch = vec![BrokerMessage::construct(
+      uid, broker_api::BrokerMessagePayload::Heartbeat(hb(uid, None))
+    )];
+    let now = Local::now();
+    assert_eq!(wrong_key_for(&db, &batch, Some("k"), now).unwrap(), None);
+    let later = now + Duration::hours(2);
+    let wrong = wrong_key_for(&db, &batch, Some("k"), later).unwrap();
+    assert_eq!(wrong, Some(uid));
+  }
+
+  #[test]
+  fn switching_to_the_new_key_ends_grace_for_bundles() {
+    let db = InMemoryApiDatabase::default();
+    let uid = BrokerId::new_v4();
+    heartbeat(&db, &hb(uid, Some("k"))).unwrap();
+    let rotated = rotate_key(&db, uid, Duration::hours(1)).unwrap().unwrap();
+    heartbeat(&db, &hb(uid, Some(&rotated.current))).unwrap();
+    let batch = vec![BrokerMessage::construct(
+      uid, broker_api::BrokerMessagePayload::Heartbeat(hb(uid, None))
+    )];
+    let now = Local::now();
+    let wrong = wrong_key_for(&db, &batch, Some("k"), now).unwrap();
+    assert_eq!(wrong, Some(uid));
+    let current = Some(rotated.current.as_str());
+    assert_eq!(wrong_key_for(&db, &batch, current, now).unwrap(), None);
+  }
+}
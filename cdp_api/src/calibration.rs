@@ -0,0 +1,90 @@
+//! Per-sensor calibration. Cheap sensors drift, so we correct their readings
+//! at ingestion time, keeping the raw value around.
+
+use serde::{Deserialize, Serialize};
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+use libcdp::comm::sensor_broker::{AnySensorMessage, HumidityMessage, SensorType, TemperatureMessage};
+use libcdp::ids::SensorId;
+
+use crate::db::ApiDatabase;
+
+/// Linear correction for a single sensor: corrected = raw * scale + offset,
+/// in the units of the raw wire value (K, %RH).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Calibration {
+  /// Multiplier applied to the raw value. Defaults to 1.
+  #[serde(default = "Calibration::default_scale")]
+  pub(crate) scale: f64,
+  /// Offset added after scaling. Defaults to 0.
+  #[serde(default)]
+  pub(crate) offset: f64
+}
+
+impl Default for Calibration {
+  /// The identity calibration.
+  fn default() -> Self {
+    return Self {
+      scale: Self::default_scale(),
+      offset: 0.0
+    };
+  }
+}
+
+impl Calibration {
+  /// For serde. Scale is 1 unless told otherwise.
+  fn default_scale() -> f64 {
+    return 1.0;
+  }
+
+  /// Corrects a single raw value, rounding and clamping it to [0, max].
+  fn correct(&self, raw: f64, max: f64) -> f64 {
+    let val = (raw * self.scale + self.offset).round();
+    if val.is_nan() { return raw; }
+    return val.max(0.0).min(max);
+  }
+
+  /// Returns a corrected copy of a sensor message.
+  pub(crate) fn apply(&self, msg: &AnySensorMessage) -> AnySensorMessage {
+    return match msg {
+      AnySensorMessage::Temperature(tm) => {
+        AnySensorMessage::Temperature(TemperatureMessage {
+          sensor_id: tm.sensor_id,
+          kelvin: self.correct(tm.kelvin as f64, u16::MAX as f64) as u16
+        })
+      },
+      AnySensorMessage::Humidity(hm) => {
+        AnySensorMessage::Humidity(HumidityMessage {
+          sensor_id: hm.sensor_id,
+          humidity: self.correct(hm.humidity as f64, u8::MAX as f64) as u8
+        })
+      },
+    };
+  }
+}
+
+/// A calibration, along with the sensor it applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SensorCalibration {
+  /// Type of the calibrated sensor.
+  pub(crate) sensor_type: SensorType,
+  /// ID of the calibrated sensor.
+  pub(crate) sensor_id: SensorId,
+  /// The calibration itself.
+  #[serde(flatten)]
+  pub(crate) calibration: Calibration
+}
+
+/// Applies the stored calibration, if any, to a message about to be
+/// ingested. The original sensor data is kept in raw_sensor_data.
+pub(crate) fn calibrate<D: ApiDatabase>(db: &D, msg: &mut BrokerMessage)
+-> Result<(), D::DbError> {
+  if let BrokerMessagePayload::SensorData(sd) = &msg.payload {
+    if let Some(cal) = db.calibration(sd.sensor_type(), sd.sensor_id())? {
+      let corrected = cal.apply(sd);
+      msg.raw_sensor_data = Some(sd.clone());
+      msg.payload = BrokerMessagePayload::SensorData(corrected);
+    }
+  }
+  return Ok(());
+}
@@ -1,17 +1,184 @@
 //! Implements configuration for the API.
 
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::error::Error;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
 use config::{Config, ConfigError};
 use serde::{Serialize, Deserialize};
 
+use libcdp::comm::sealing::SealingKey;
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::BrokerId;
+use libcdp::runtime;
+
+use crate::alerts::AlertConfig;
+use crate::anomaly::AnomalyParams;
+use crate::arming::ArmingConfig;
+use crate::api::CorsConfig;
+use crate::db::ApiDatabaseType;
+use crate::derived::DerivedSensor;
+use crate::enrollment::EnrollmentConfig;
+use crate::expr::Expr;
+use crate::forecast::ForecastConfig;
+use crate::presence::PresenceConfig;
+use crate::ratelimit::RateLimitConfig;
+use crate::reports::ReportConfig;
+use crate::wal::WalConfig;
+use crate::webhook::WebhookSource;
+
+/// Default battery percentage below which devices are reported as low.
+const DEFAULT_LOW_BATTERY_THRESHOLD: u8 = 20;
+
+/// Default maximum size of a bundle request body, in bytes.
+const DEFAULT_MAX_BUNDLE_BYTES: usize = 256 * 1024;
+
+/// Default maximum size of a whole NDJSON bundle, in bytes.
+const DEFAULT_MAX_NDJSON_BYTES: usize = 64 * 1024 * 1024;
+
+/// Default maximum number of messages in an NDJSON bundle.
+const DEFAULT_MAX_NDJSON_MESSAGES: usize = 100_000;
+
+/// Default maximum size of a backup being restored, in bytes.
+const DEFAULT_MAX_RESTORE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Default maximum size of a backup being restored once unzipped, in bytes.
+const DEFAULT_MAX_RESTORE_UNZIPPED_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Default interval between database snapshots, in seconds.
+const DEFAULT_SNAPSHOT_INTERVAL_SECS: u64 = 60;
+
+/// Database settings, as they lie in the config file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct DatabaseConfig {
+  /// Which database to use. None means in_memory.
+  pub(crate) backend: Option<ApiDatabaseType>,
+  /// Where Redis lives, like "redis://127.0.0.1/". Required by the redis
+  /// backend.
+  pub(crate) redis_url: Option<String>,
+  /// Directory the sled backend keeps its files in. Required by the sled
+  /// backend.
+  pub(crate) sled_path: Option<PathBuf>,
+  /// Where the in-memory database keeps its snapshot. Loaded at startup,
+  /// saved periodically and at shutdown. None means nothing is persisted.
+  pub(crate) snapshot_path: Option<PathBuf>,
+  /// Interval between snapshots, in seconds. None means 60.
+  pub(crate) snapshot_interval_secs: Option<u64>
+}
+
+impl DatabaseConfig {
+  /// Which database to use.
+  pub(crate) fn backend(&self) -> ApiDatabaseType {
+    return self.backend.unwrap_or(ApiDatabaseType::InMemory);
+  }
+
+  /// Interval between snapshots.
+  pub(crate) fn snapshot_interval(&self) -> Duration {
+    return Duration::from_secs(
+      self.snapshot_interval_secs.unwrap_or(DEFAULT_SNAPSHOT_INTERVAL_SECS)
+    );
+  }
+}
+
+/// HTTP server tuning, as it lies in the config file. Whatever is left out
+/// is up to actix.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct ServerConfig {
+  /// Worker threads. None means one per physical CPU.
+  pub(crate) workers: Option<usize>,
+  /// How long an idle connection is kept open, in seconds. 0 turns
+  /// keep-alive off. None means 5.
+  pub(crate) keep_alive_secs: Option<usize>,
+  /// How long a client has to send its request headers, in milliseconds. 0
+  /// means forever. None means 5000.
+  pub(crate) client_timeout_msec: Option<u64>,
+  /// Most connections each worker handles at once. None means 25600.
+  pub(crate) max_connections: Option<usize>
+}
+
 /// Encodes the information in an API config file.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct ApiConfigFile {
-  /// List of address:port items to bind to.
-  /// Note that IPv4 and IPv6 are to be specified separately.
-  binds: Vec<String>
+  /// List of address:port items to bind to, or "unix:<path>" for Unix
+  /// domain sockets. Note that IPv4 and IPv6 are to be specified separately.
+  binds: Vec<String>,
+  /// Permissions for Unix domain sockets, in octal, like "660". None means
+  /// whatever the umask leaves.
+  unix_socket_mode: Option<String>,
+  /// Battery percentage below which devices are reported as low.
+  /// None means the default, 20%.
+  low_battery_threshold: Option<u8>,
+  /// Virtual sensors, by name.
+  #[serde(default)]
+  derived: HashMap<String, DerivedSensorFile>,
+  /// Anomaly detection parameters, by sensor type name.
+  #[serde(default)]
+  anomaly: HashMap<String, AnomalyParams>,
+  /// Alert settings.
+  #[serde(default)]
+  alerts: AlertConfig,
+  /// Arming settings.
+  #[serde(default)]
+  arming: ArmingConfig,
+  /// Presence settings.
+  #[serde(default)]
+  presence: PresenceConfig,
+  /// Forecast settings.
+  #[serde(default)]
+  forecast: ForecastConfig,
+  /// Summary report settings.
+  #[serde(default)]
+  reports: ReportConfig,
+  /// Devices calling in through webhooks, by name.
+  #[serde(default)]
+  webhooks: HashMap<String, WebhookSource>,
+  /// Keys brokers seal their bundles with, in base64, by broker UID.
+  #[serde(default)]
+  seal_keys: HashMap<String, String>,
+  /// Brokers enrolling themselves. Off by default.
+  #[serde(default)]
+  enrollment: EnrollmentConfig,
+  /// Rate limits. Nothing is limited by default.
+  #[serde(default)]
+  rate_limit: RateLimitConfig,
+  /// Maximum size of a bundle request body, in bytes. None means 256 KiB.
+  max_bundle_bytes: Option<usize>,
+  /// Maximum size of a whole NDJSON bundle, in bytes. None means 64 MiB.
+  max_ndjson_bytes: Option<usize>,
+  /// Maximum number of messages in an NDJSON bundle. None means 100000.
+  max_ndjson_messages: Option<usize>,
+  /// Maximum size of a backup being restored, in bytes. None means 256 MiB.
+  max_restore_bytes: Option<usize>,
+  /// Maximum size of a backup being restored once unzipped, in bytes. None
+  /// means 2 GiB.
+  max_restore_unzipped_bytes: Option<u64>,
+  /// Cross-origin request settings. None means CORS is off.
+  cors: Option<CorsConfig>,
+  /// Database settings.
+  #[serde(default)]
+  database: DatabaseConfig,
+  /// Write-ahead log settings. None means no log.
+  wal: Option<WalConfig>,
+  /// Bearer token for the admin endpoints. None means they're off.
+  admin_token: Option<String>,
+  /// Address:port for the gRPC service. None means it's off.
+  grpc_bind: Option<String>,
+  /// HTTP server tuning.
+  #[serde(default)]
+  server: ServerConfig
+}
+
+/// A virtual sensor, as written in the config file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DerivedSensorFile {
+  /// Expression over real sensors, see the expr module.
+  expr: String,
+  /// Unit label for display.
+  unit: Option<String>
 }
 
 impl Default for ApiConfigFile {
@@ -21,7 +188,31 @@ impl Default for ApiConfigFile {
       binds: vec![
         "0.0.0.0:9869".to_owned(),
         "[::]:9869".to_owned()
-      ]
+      ],
+      unix_socket_mode: None,
+      low_battery_threshold: Some(DEFAULT_LOW_BATTERY_THRESHOLD),
+      derived: HashMap::new(),
+      anomaly: HashMap::new(),
+      alerts: AlertConfig::default(),
+      arming: ArmingConfig::default(),
+      presence: PresenceConfig::default(),
+      forecast: ForecastConfig::default(),
+      reports: ReportConfig::default(),
+      webhooks: HashMap::new(),
+      seal_keys: HashMap::new(),
+      enrollment: EnrollmentConfig::default(),
+      rate_limit: RateLimitConfig::default(),
+      max_bundle_bytes: Some(DEFAULT_MAX_BUNDLE_BYTES),
+      max_ndjson_bytes: Some(DEFAULT_MAX_NDJSON_BYTES),
+      max_ndjson_messages: Some(DEFAULT_MAX_NDJSON_MESSAGES),
+      max_restore_bytes: Some(DEFAULT_MAX_RESTORE_BYTES),
+      max_restore_unzipped_bytes: Some(DEFAULT_MAX_RESTORE_UNZIPPED_BYTES),
+      cors: None,
+      database: DatabaseConfig::default(),
+      wal: None,
+      admin_token: None,
+      grpc_bind: None,
+      server: ServerConfig::default()
     }
   }
 }
@@ -29,18 +220,67 @@ impl Default for ApiConfigFile {
 /// The decoded, properly-parsed version of the ApiConfigFile struct.
 #[derive(Debug, Clone)]
 pub(crate) struct ApiConfig {
-  /// List of address:port items to bind to.
-  /// Note that IPv4 and IPv6 are to be specified separately.
-  pub(crate) binds: Vec<String>
+  /// List of address:port items to bind to, or "unix:<path>" for Unix
+  /// domain sockets. Note that IPv4 and IPv6 are to be specified separately.
+  pub(crate) binds: Vec<String>,
+  /// Permissions for Unix domain sockets. None means whatever the umask
+  /// leaves.
+  pub(crate) unix_socket_mode: Option<u32>,
+  /// Battery percentage below which devices are reported as low.
+  pub(crate) low_battery_threshold: u8,
+  /// Virtual sensors, sorted by name.
+  pub(crate) derived: Vec<DerivedSensor>,
+  /// Anomaly detection parameters. Types not in here aren't checked.
+  pub(crate) anomaly: HashMap<SensorType, AnomalyParams>,
+  /// Alert settings.
+  pub(crate) alerts: AlertConfig,
+  /// Arming settings.
+  pub(crate) arming: ArmingConfig,
+  /// Presence settings.
+  pub(crate) presence: PresenceConfig,
+  /// Forecast settings.
+  pub(crate) forecast: ForecastConfig,
+  /// Summary report settings.
+  pub(crate) reports: ReportConfig,
+  /// Devices calling in through webhooks, by name.
+  pub(crate) webhooks: HashMap<String, WebhookSource>,
+  /// Keys brokers seal their bundles with. Brokers in here must seal.
+  pub(crate) seal_keys: HashMap<BrokerId, SealingKey>,
+  /// Brokers enrolling themselves.
+  pub(crate) enrollment: EnrollmentConfig,
+  /// Rate limits.
+  pub(crate) rate_limit: RateLimitConfig,
+  /// Maximum size of a bundle request body, in bytes.
+  pub(crate) max_bundle_bytes: usize,
+  /// Maximum size of a whole NDJSON bundle, in bytes. Each line is held to
+  /// max_bundle_bytes.
+  pub(crate) max_ndjson_bytes: usize,
+  /// Maximum number of messages in an NDJSON bundle.
+  pub(crate) max_ndjson_messages: usize,
+  /// Maximum size of a backup being restored, in bytes.
+  pub(crate) max_restore_bytes: usize,
+  /// Maximum size of a backup being restored once unzipped, in bytes, so a
+  /// small one can't unzip into something that eats all the memory.
+  pub(crate) max_restore_unzipped_bytes: u64,
+  /// Cross-origin request settings. None means CORS is off.
+  pub(crate) cors: Option<CorsConfig>,
+  /// Database settings.
+  pub(crate) database: DatabaseConfig,
+  /// Write-ahead log settings. None means no log.
+  pub(crate) wal: Option<WalConfig>,
+  /// Bearer token for the admin endpoints. None means they're off.
+  pub(crate) admin_token: Option<String>,
+  /// Address for the gRPC service. None means it's off.
+  pub(crate) grpc_bind: Option<SocketAddr>,
+  /// HTTP server tuning.
+  pub(crate) server: ServerConfig
 }
 
 #[derive(Debug)]
 pub(crate) enum ApiConfigParseError {
   /// Parse error from the config crate.
   ConfigError(ConfigError),
-  /// Parse error from our conversion. Dead code allowed because this doesn't
-  /// have the abilit to fail yet, but it might, in the future.
-  #[allow(dead_code)]
+  /// Parse error from our conversion.
   ParseError(Box<dyn Error + Send + Sync>)
 }
 
@@ -54,10 +294,115 @@ impl TryFrom<ApiConfigFile> for ApiConfig {
   /// Generic error type for when the conversion fails.
   type Error = ApiConfigParseError;
 
-  /// Fallible parsing. No errors now... but who knows?
+  /// Fallible parsing. Fails on bad derived sensor expressions and unknown
+  /// sensor types.
   fn try_from(pre: ApiConfigFile) -> Result<Self, Self::Error> {
+    let mut derived: Vec<DerivedSensor> = Vec::new();
+    for (name, dsf) in pre.derived {
+      derived.push(DerivedSensor {
+        expr: Expr::from_str(&dsf.expr)
+          .map_err(|e| Self::Error::ParseError(
+            format!("Derived sensor \"{}\": {}", name, e).into()
+          ))?,
+        name: name,
+        source: dsf.expr,
+        unit: dsf.unit
+      });
+    }
+    derived.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut anomaly: HashMap<SensorType, AnomalyParams> = HashMap::new();
+    for (tname, params) in pre.anomaly {
+      let stype = SensorType::from_str(&tname)
+        .map_err(|_| Self::Error::ParseError(
+          format!("Bad sensor type \"{}\" in anomaly config.", tname).into()
+        ))?;
+      anomaly.insert(stype, params);
+    }
+    if pre.database.backend() == ApiDatabaseType::Redis
+      && pre.database.redis_url.is_none() {
+      return Err(Self::Error::ParseError(
+        "The redis backend needs a redis_url.".into()
+      ));
+    }
+    if pre.database.backend() == ApiDatabaseType::Sled
+      && pre.database.sled_path.is_none() {
+      return Err(Self::Error::ParseError(
+        "The sled backend needs a sled_path.".into()
+      ));
+    }
+    if pre.server.workers == Some(0) || pre.server.max_connections == Some(0) {
+      return Err(Self::Error::ParseError(
+        "The server needs at least one worker and connection.".into()
+      ));
+    }
+    let unix_socket_mode = match &pre.unix_socket_mode {
+      Some(mode) => Some(u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|m| *m <= 0o7777)
+        .ok_or_else(|| Self::Error::ParseError(
+          format!("Bad unix_socket_mode \"{}\".", mode).into()
+        ))?),
+      None => None,
+    };
+    pre.alerts.check().map_err(|e| Self::Error::ParseError(e.into()))?;
+    pre.arming.check(&pre.alerts.channels)
+      .map_err(|e| Self::Error::ParseError(e.into()))?;
+    pre.forecast.check().map_err(|e| Self::Error::ParseError(e.into()))?;
+    pre.reports.check(&pre.alerts.channels)
+      .map_err(|e| Self::Error::ParseError(e.into()))?;
+    for (name, src) in &pre.webhooks {
+      src.check(name).map_err(|e| Self::Error::ParseError(e.into()))?;
+    }
+    let mut seal_keys: HashMap<BrokerId, SealingKey> = HashMap::new();
+    for (uid, key) in &pre.seal_keys {
+      let bad = || Self::Error::ParseError(
+        format!("Bad seal key for broker \"{}\".", uid).into()
+      );
+      seal_keys.insert(
+        uid.parse().map_err(|_| bad())?,
+        SealingKey::from_base64(key).map_err(|_| bad())?
+      );
+    }
+    pre.enrollment.check().map_err(|e| Self::Error::ParseError(e.into()))?;
+    let grpc_bind = match &pre.grpc_bind {
+      Some(addr) => Some(SocketAddr::from_str(addr)
+        .map_err(|_| Self::Error::ParseError(
+          format!("Bad grpc_bind address \"{}\".", addr).into()
+        ))?),
+      None => None,
+    };
     return Ok(Self {
-      binds: pre.binds
+      anomaly: anomaly,
+      alerts: pre.alerts,
+      arming: pre.arming,
+      presence: pre.presence,
+      forecast: pre.forecast,
+      reports: pre.reports,
+      webhooks: pre.webhooks,
+      seal_keys: seal_keys,
+      enrollment: pre.enrollment,
+      rate_limit: pre.rate_limit,
+      max_bundle_bytes: pre.max_bundle_bytes
+        .unwrap_or(DEFAULT_MAX_BUNDLE_BYTES),
+      max_ndjson_bytes: pre.max_ndjson_bytes
+        .unwrap_or(DEFAULT_MAX_NDJSON_BYTES),
+      max_ndjson_messages: pre.max_ndjson_messages
+        .unwrap_or(DEFAULT_MAX_NDJSON_MESSAGES),
+      max_restore_bytes: pre.max_restore_bytes
+        .unwrap_or(DEFAULT_MAX_RESTORE_BYTES),
+      max_restore_unzipped_bytes: pre.max_restore_unzipped_bytes
+        .unwrap_or(DEFAULT_MAX_RESTORE_UNZIPPED_BYTES),
+      cors: pre.cors,
+      database: pre.database,
+      wal: pre.wal,
+      admin_token: pre.admin_token,
+      grpc_bind: grpc_bind,
+      server: pre.server,
+      derived: derived,
+      binds: pre.binds,
+      unix_socket_mode: unix_socket_mode,
+      low_battery_threshold: pre.low_battery_threshold
+        .unwrap_or(DEFAULT_LOW_BATTERY_THRESHOLD)
     });
   }
 }
@@ -70,10 +415,17 @@ impl Default for ApiConfig {
   }
 }
 
-/// Load the default configuration files for the API.
-pub(crate) fn load_defaults() -> Result<ApiConfig, ApiConfigParseError> {
+/// Load the default configuration file for the API, cdp_api.toml, unless
+/// another one is given.
+pub(crate) fn load_defaults(given: Option<&Path>)
+-> Result<ApiConfig, ApiConfigParseError> {
   let mut cfg = Config::default();
-  cfg.merge(config::File::with_name("cdp_api"))?;
+  cfg.merge(runtime::config_file("cdp_api", given))?;
+  return load_from(cfg);
+}
+
+/// Load the configuration for the API from wherever it was merged from.
+pub(crate) fn load_from(cfg: Config) -> Result<ApiConfig, ApiConfigParseError> {
   let api_cfg: ApiConfigFile = cfg.try_into()?;
   return Ok(api_cfg.try_into()?);
 }
@@ -1,27 +1,59 @@
 //! Abstracts away interaction with the database.
 
+pub(crate) mod blocking;
+pub(crate) mod chunked;
 pub(crate) mod inmem;
+pub(crate) mod redisdb;
+pub(crate) mod sleddb;
 
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::error::Error as StdError;
 use std::fmt::Display;
 use std::str::FromStr;
 
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use futures::stream::{self, Stream};
 use serde::{Serialize, Deserialize};
 
-use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayloadType};
-use libcdp::comm::sensor_broker::{AnySensorMessage, SensorType};
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload, BrokerMessagePayloadType};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, MessageId, SensorId};
+
+use crate::alerts::LogLine;
+use crate::arming::ArmingChange;
+use crate::audit::AuditEntry;
+use crate::brokers::BrokerRecord;
+use crate::calibration::{Calibration, SensorCalibration};
+use crate::derived::DerivedReading;
+use crate::floorplan::Floorplan;
+use crate::reports::Report;
+use crate::sensors::{RegisteredSensor, SensorInfo};
+use crate::supervisor::Jobs;
+
+/// Something an API instance tells the others sharing its database, so they
+/// can keep up.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum News {
+  /// Messages it stored.
+  Ingested(Vec<BrokerMessage>),
+  /// An alert or a mute, as it logged it.
+  Alert(LogLine),
+  /// A change of arming state.
+  Arming(ArmingChange)
+}
 
 /// Trait implemented by all types used to implement database abstractions.
 pub(crate) trait ApiDatabase: Sized + Send + Sync + Clone {
   /// The type used when returning broker messages.
-  type BrokerMessageIter: Iterator<Item=BrokerMessage>;
-  /// The type used when returning sensor messages.
-  type SensorMessageIter: Iterator<Item=AnySensorMessage>;
+  type BrokerMessageIter: Iterator<Item=BrokerMessage> + Send + 'static;
   /// Error returned by the database lib, or by us.
   type DbError: StdError + Send + Sync;
   /// Configuration used by the database.
-  type DbConfig: Send + Sync;
+  type DbConfig: Send + Sync + Clone;
 
   /// Returns the name of the type of database in use.
   fn db_type(&self) -> ApiDatabaseType;
@@ -30,31 +62,597 @@ pub(crate) trait ApiDatabase: Sized + Send + Sync + Clone {
   /// Set up the database with the tables and stuff if need be. No harm in
   /// calling it needlessly, but try to be aware -- it might be costly.
   fn setup(&self);
+  /// Cheap check that the database is there and answering. The default just
+  /// asks for the topics.
+  fn ping(&self) -> Result<(), Self::DbError> {
+    return self.topics().map(|_| ());
+  }
   /// Return topics we care about.
   fn topics(&self) -> Result<HashSet<SensorType>, Self::DbError>;
   /// Update the list of topics we care about.
   fn update_topics<T>(&self, new_topics: T) -> Result<(), Self::DbError>
   where T: IntoIterator<Item=SensorType>;
-  /// Get all broker messages of a certain type.
-  fn messages_by_type(&self, mtype: BrokerMessagePayloadType)
+  /// Get the stored messages a filter matches, as it says. Backends should
+  /// look at as few messages as they can, and leave ordering and limiting
+  /// to MessageFilter::finish.
+  fn query(&self, filter: MessageFilter)
   -> Result<Self::BrokerMessageIter, Self::DbError>;
-  /// Get all sensor messages of a certain sensor type.
-  fn sensor_messages_by_type(&self, stype: SensorType)
-  -> Result<Self::SensorMessageIter, Self::DbError>;
+  /// Get a single sensor data or device health message by ID. The default
+  /// scans everything; backends with an index by ID should override it.
+  fn message(&self, id: MessageId)
+  -> Result<Option<BrokerMessage>, Self::DbError> {
+    let mtypes = [
+      BrokerMessagePayloadType::SensorData,
+      BrokerMessagePayloadType::DeviceHealth
+    ];
+    for mtype in mtypes.iter().cloned() {
+      let found = self.query(MessageFilter::of_type(mtype))?
+        .find(|m| m.id == Some(id));
+      if found.is_some() {
+        return Ok(found);
+      }
+    }
+    return Ok(None);
+  }
+  /// Which of some message IDs are already stored. The default scans
+  /// everything; backends with an index by ID should override it.
+  fn known_ids(&self, ids: &[MessageId])
+  -> Result<HashSet<MessageId>, Self::DbError> {
+    let mut known = HashSet::new();
+    if ids.is_empty() {
+      return Ok(known);
+    }
+    let wanted: HashSet<MessageId> = ids.iter().cloned().collect();
+    for mtype in BrokerMessagePayloadType::all_types() {
+      for msg in self.query(MessageFilter::of_type(mtype))? {
+        if let Some(id) = msg.id.filter(|id| wanted.contains(id)) {
+          known.insert(id);
+        }
+      }
+    }
+    return Ok(known);
+  }
+  /// Take some message IDs for messages about to be stored, returning those
+  /// taken already, by messages stored before or being stored right now,
+  /// here or by another instance. Each ID is taken at most once, so of two
+  /// copies of a message coming in at once only one gets to be stored. It
+  /// happens right away, even through a handle given out by with_txn. IDs
+  /// whose messages don't end up stored should be given back with
+  /// release_ids.
+  fn claim_ids(&self, ids: &[MessageId])
+  -> Result<HashSet<MessageId>, Self::DbError>;
+  /// Give back IDs taken with claim_ids, for messages that weren't stored
+  /// after all.
+  fn release_ids(&self, ids: &[MessageId]) -> Result<(), Self::DbError>;
+  /// Hand out a run of sequence numbers, returning the first of them.
+  /// Numbers start at 1, only go up, and are never handed out twice, not
+  /// even across restarts, nor to instances sharing the database. Storing
+  /// the messages is up to the caller, so numbers only say in which order
+  /// they were stored when one instance stores them all.
+  fn next_seq(&self, count: u64) -> Result<u64, Self::DbError>;
   /// Insert a message into the database.
   fn insert_message(&self, msg: BrokerMessage) -> Result<(), Self::DbError>;
+  /// Insert a bunch of messages in one go. All or nothing: if any message
+  /// fails, none of them are stored, and the error says which one it was.
+  fn insert_messages(&self, msgs: Vec<BrokerMessage>)
+  -> Result<(), BatchInsertError<Self::DbError>>;
+  /// Run f with a handle on the database whose writes are all stored at
+  /// once when f returns Ok, or not at all if it returns an error. Reads
+  /// made through it see what was stored before, not what f wrote, and
+  /// sequence numbers are handed out right away either way. Calling it
+  /// again from within f just joins the outer transaction. The default just
+  /// runs f, for backends whose writes can't fail halfway, like the
+  /// in-memory one.
+  fn with_txn<T, E, F>(&self, f: F) -> Result<T, E>
+  where F: FnOnce(&Self) -> Result<T, E>, E: From<Self::DbError> {
+    return f(self);
+  }
+  /// Get the latest sensor data message of each (sensor type, sensor ID).
+  /// The default scans everything; backends with indexes should override it.
+  fn latest_per_sensor(&self) -> Result<Vec<BrokerMessage>, Self::DbError> {
+    let mut latest: HashMap<(SensorType, SensorId), BrokerMessage>
+      = HashMap::new();
+    let sensor_data = MessageFilter::of_type(
+      BrokerMessagePayloadType::SensorData
+    );
+    for msg in self.query(sensor_data)? {
+      if let BrokerMessagePayload::SensorData(sd) = &msg.payload {
+        let key = (sd.sensor_type(), sd.sensor_id());
+        let newer = latest
+          .get(&key)
+          .map(|prev| prev.constructed_when <= msg.constructed_when)
+          .unwrap_or(true);
+        if newer {
+          latest.insert(key, msg);
+        }
+      }
+    }
+    return Ok(latest.into_iter().map(|(_, msg)| msg).collect());
+  }
+  /// Return all stored sensor calibrations.
+  fn calibrations(&self) -> Result<Vec<SensorCalibration>, Self::DbError>;
+  /// Return the calibration for a single sensor, if there is one.
+  fn calibration(&self, stype: SensorType, sensor_id: SensorId)
+  -> Result<Option<Calibration>, Self::DbError>;
+  /// Set the calibration for a single sensor. None removes it.
+  fn set_calibration(
+    &self, stype: SensorType, sensor_id: SensorId, cal: Option<Calibration>
+  ) -> Result<(), Self::DbError>;
+  /// Return the names and rooms of every registered sensor.
+  fn registered_sensors(&self)
+  -> Result<Vec<RegisteredSensor>, Self::DbError>;
+  /// Return the name and room of a single sensor, if it's registered.
+  fn sensor_info(&self, stype: SensorType, sensor_id: SensorId)
+  -> Result<Option<SensorInfo>, Self::DbError>;
+  /// Set the name and room of a single sensor. None unregisters it.
+  fn set_sensor_info(
+    &self, stype: SensorType, sensor_id: SensorId, info: Option<SensorInfo>
+  ) -> Result<(), Self::DbError>;
+  /// Return the floorplan, if one was ever uploaded.
+  fn floorplan(&self) -> Result<Option<Floorplan>, Self::DbError>;
+  /// Replace the floorplan. None removes it.
+  fn set_floorplan(&self, plan: Option<Floorplan>)
+  -> Result<(), Self::DbError>;
+  /// Insert a computed value of a virtual sensor.
+  fn insert_derived(&self, reading: DerivedReading)
+  -> Result<(), Self::DbError>;
+  /// Get all computed values of a virtual sensor, oldest first.
+  fn derived_readings(&self, name: &str)
+  -> Result<Vec<DerivedReading>, Self::DbError>;
+  /// Return what we know about every broker, sorted by uid.
+  fn brokers(&self) -> Result<Vec<BrokerRecord>, Self::DbError>;
+  /// Return what we know about a single broker, if anything.
+  fn broker(&self, uid: BrokerId)
+  -> Result<Option<BrokerRecord>, Self::DbError>;
+  /// Store the latest news from a broker, replacing the previous record.
+  fn update_broker(&self, rec: BrokerRecord) -> Result<(), Self::DbError>;
+  /// Append an entry to the audit log. Entries are never changed or removed.
+  fn insert_audit(&self, entry: AuditEntry) -> Result<(), Self::DbError>;
+  /// Get every entry of the audit log, oldest first.
+  fn audit_log(&self) -> Result<Vec<AuditEntry>, Self::DbError>;
+  /// Store a summary report.
+  fn insert_report(&self, report: Report) -> Result<(), Self::DbError>;
+  /// Get every summary report, oldest first.
+  fn reports(&self) -> Result<Vec<Report>, Self::DbError>;
+  /// Tell every other API instance sharing this database some news, like
+  /// freshly stored messages or alert changes, so they can keep their caches
+  /// and books fresh. The default does nothing, for backends that can't be
+  /// shared.
+  fn announce(&self, _news: &News) -> Result<(), Self::DbError> {
+    return Ok(());
+  }
+  /// Start calling back with news announced by other API instances, in the
+  /// background, as one of jobs, for as long as the process lives. After
+  /// (re)connecting, it also calls back with the latest message of every
+  /// sensor, to catch up on anything missed. The default never calls back.
+  fn listen<F>(&self, _jobs: &Jobs, _on_news: F) -> Result<(), Self::DbError>
+  where F: Fn(News) + Send + 'static {
+    return Ok(());
+  }
+}
+
+/// Nanoseconds since the epoch, for backends that key or score by time.
+/// Those only fit in 64 bits from about 1677 to 2262; times beyond that are
+/// taken as the nearest that fits, so ranges reaching past them still work.
+/// Messages that far out are turned away before they're stored; see
+/// storable_time.
+pub(crate) fn time_nanos(when: &DateTime<Local>) -> i64 {
+  return wide_nanos(when).clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+}
+
+/// Nanoseconds since the epoch, however many.
+fn wide_nanos(when: &DateTime<Local>) -> i128 {
+  return when.timestamp() as i128 * 1_000_000_000
+    + when.timestamp_subsec_nanos() as i128;
+}
+
+/// Whether a message's times all fit in time_nanos, as they must to be
+/// stored.
+pub(crate) fn storable_time(msg: &BrokerMessage) -> bool {
+  let fits = |when: &DateTime<Local>| {
+    return i64::try_from(wide_nanos(when)).is_ok();
+  };
+  return fits(&msg.constructed_when)
+    && msg.decoded_when.as_ref().map(fits).unwrap_or(true)
+    && msg.sent_when.as_ref().map(fits).unwrap_or(true);
+}
+
+/// Errors of the synchronous database under an asynchronous one.
+pub(crate) type DbError<D> =
+  <<D as AsyncApiDatabase>::Sync as ApiDatabase>::DbError;
+
+/// The database as request handlers see it: the queries they make, as
+/// futures, so that a slow backend doesn't hold up the workers answering
+/// everyone else. Each method defaults to running the synchronous query
+/// through blocking, which is all it takes for backends that never wait on
+/// anything, like the in-memory one; those that do go through
+/// blocking::Blocking instead. Backends that can really wait asynchronously
+/// override the methods outright.
+#[async_trait]
+pub(crate) trait AsyncApiDatabase: Send + Sync + Clone + 'static {
+  /// The synchronous database underneath.
+  type Sync: ApiDatabase + 'static;
+
+  /// The synchronous database underneath, for setting up and for whatever
+  /// runs on threads of its own.
+  fn sync(&self) -> &Self::Sync;
+  /// Runs f with the synchronous database, somewhere it's fine for it to
+  /// block, for everything not covered by the methods below.
+  async fn blocking<T, F>(&self, f: F) -> T
+  where T: Send + 'static, F: FnOnce(&Self::Sync) -> T + Send + 'static;
+
+  /// See ApiDatabase::ping.
+  async fn ping(&self) -> Result<(), DbError<Self>> {
+    return self.blocking(|db| db.ping()).await;
+  }
+  /// See ApiDatabase::query. They're fetched a page at a time, as they're
+  /// asked for, so they're never all held at once.
+  async fn query(&self, filter: MessageFilter)
+  -> Result<Pages<Self>, DbError<Self>> {
+    let left = self.blocking(move |db| db.query(filter)).await?;
+    return Ok(Pages { db: self.clone(), left: Some(left) });
+  }
+  /// See ApiDatabase::message.
+  async fn message(&self, id: MessageId)
+  -> Result<Option<BrokerMessage>, DbError<Self>> {
+    return self.blocking(move |db| db.message(id)).await;
+  }
+  /// See ApiDatabase::latest_per_sensor.
+  async fn latest_per_sensor(&self)
+  -> Result<Vec<BrokerMessage>, DbError<Self>> {
+    return self.blocking(|db| db.latest_per_sensor()).await;
+  }
+  /// See ApiDatabase::calibrations.
+  async fn calibrations(&self)
+  -> Result<Vec<SensorCalibration>, DbError<Self>> {
+    return self.blocking(|db| db.calibrations()).await;
+  }
+  /// See ApiDatabase::calibration.
+  async fn calibration(&self, stype: SensorType, sensor_id: SensorId)
+  -> Result<Option<Calibration>, DbError<Self>> {
+    return self.blocking(move |db| db.calibration(stype, sensor_id)).await;
+  }
+  /// See ApiDatabase::set_calibration.
+  async fn set_calibration(
+    &self, stype: SensorType, sensor_id: SensorId, cal: Option<Calibration>
+  ) -> Result<(), DbError<Self>> {
+    return self.blocking(move |db| {
+      return db.set_calibration(stype, sensor_id, cal);
+    }).await;
+  }
+  /// See ApiDatabase::registered_sensors.
+  async fn registered_sensors(&self)
+  -> Result<Vec<RegisteredSensor>, DbError<Self>> {
+    return self.blocking(|db| db.registered_sensors()).await;
+  }
+  /// See ApiDatabase::sensor_info.
+  async fn sensor_info(&self, stype: SensorType, sensor_id: SensorId)
+  -> Result<Option<SensorInfo>, DbError<Self>> {
+    return self.blocking(move |db| db.sensor_info(stype, sensor_id)).await;
+  }
+  /// See ApiDatabase::set_sensor_info.
+  async fn set_sensor_info(
+    &self, stype: SensorType, sensor_id: SensorId, info: Option<SensorInfo>
+  ) -> Result<(), DbError<Self>> {
+    return self.blocking(move |db| {
+      return db.set_sensor_info(stype, sensor_id, info);
+    }).await;
+  }
+  /// See ApiDatabase::floorplan.
+  async fn floorplan(&self) -> Result<Option<Floorplan>, DbError<Self>> {
+    return self.blocking(|db| db.floorplan()).await;
+  }
+  /// See ApiDatabase::set_floorplan.
+  async fn set_floorplan(&self, plan: Option<Floorplan>)
+  -> Result<(), DbError<Self>> {
+    return self.blocking(move |db| db.set_floorplan(plan)).await;
+  }
+  /// See ApiDatabase::derived_readings.
+  async fn derived_readings(&self, name: String)
+  -> Result<Vec<DerivedReading>, DbError<Self>> {
+    return self.blocking(move |db| db.derived_readings(&name)).await;
+  }
+  /// See ApiDatabase::brokers.
+  async fn brokers(&self) -> Result<Vec<BrokerRecord>, DbError<Self>> {
+    return self.blocking(|db| db.brokers()).await;
+  }
+  /// See ApiDatabase::broker.
+  async fn broker(&self, uid: BrokerId)
+  -> Result<Option<BrokerRecord>, DbError<Self>> {
+    return self.blocking(move |db| db.broker(uid)).await;
+  }
+  /// See ApiDatabase::insert_audit.
+  async fn insert_audit(&self, entry: AuditEntry)
+  -> Result<(), DbError<Self>> {
+    return self.blocking(move |db| db.insert_audit(entry)).await;
+  }
+  /// See ApiDatabase::audit_log.
+  async fn audit_log(&self) -> Result<Vec<AuditEntry>, DbError<Self>> {
+    return self.blocking(|db| db.audit_log()).await;
+  }
+  /// See ApiDatabase::reports.
+  async fn reports(&self) -> Result<Vec<Report>, DbError<Self>> {
+    return self.blocking(|db| db.reports()).await;
+  }
+  /// See ApiDatabase::announce.
+  async fn announce(&self, news: News) -> Result<(), DbError<Self>> {
+    return self.blocking(move |db| db.announce(&news)).await;
+  }
+}
+
+/// Messages AsyncApiDatabase::query fetches at a time.
+const PAGE_LEN: usize = 1000;
+
+/// Messages a query matched, for asynchronous callers: each page is fetched
+/// through blocking when it's asked for.
+pub(crate) struct Pages<D: AsyncApiDatabase> {
+  /// Where they come from.
+  db: D,
+  /// What's left of them. None once they ran out.
+  left: Option<<D::Sync as ApiDatabase>::BrokerMessageIter>
+}
+
+impl<D: AsyncApiDatabase> Pages<D> {
+  /// The next page of messages, or None once there's no more.
+  pub(crate) async fn next_page(&mut self) -> Option<Vec<BrokerMessage>> {
+    let mut left = self.left.take()?;
+    let (page, left) = self.db.blocking(move |_| {
+      let page: Vec<BrokerMessage> = left.by_ref().take(PAGE_LEN).collect();
+      return (page, left);
+    }).await;
+    if page.len() == PAGE_LEN {
+      self.left = Some(left);
+    }
+    return match page.is_empty() {
+      true => None,
+      false => Some(page),
+    };
+  }
+
+  /// The pages, one after the other.
+  pub(crate) fn stream(self) -> impl Stream<Item=Vec<BrokerMessage>> {
+    return stream::unfold(self, |mut pages| async move {
+      let page = pages.next_page().await?;
+      return Some((page, pages));
+    });
+  }
+}
+
+/// Which way messages go, oldest first. Ties are broken by sequence number,
+/// so the order is the same every time.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MessageOrder {
+  /// By when brokers made them. Late messages land in the past.
+  Constructed,
+  /// By when we got them.
+  Received,
+  /// By sequence number, which is how they were stored. The only order new
+  /// messages always land at the end of, so the one to page by. With
+  /// several instances sharing a database, a message can land before the
+  /// end, when an instance stores a batch after another stored one
+  /// numbered after it.
+  Seq
+}
+
+impl Default for MessageOrder {
+  fn default() -> Self {
+    return MessageOrder::Constructed;
+  }
+}
+
+impl MessageOrder {
+  /// The time a message goes by, for time ranges. Sequence numbers go with
+  /// when messages were received. None for messages stored before we kept
+  /// that.
+  pub(crate) fn when(&self, msg: &BrokerMessage) -> Option<DateTime<Local>> {
+    return match self {
+      MessageOrder::Constructed => Some(msg.constructed_when),
+      MessageOrder::Received | MessageOrder::Seq => msg.received_when,
+    };
+  }
+
+  /// Compares two messages.
+  pub(crate) fn cmp(&self, a: &BrokerMessage, b: &BrokerMessage) -> Ordering {
+    let by_seq = a.seq.cmp(&b.seq).then_with(|| a.id.cmp(&b.id));
+    return match self {
+      MessageOrder::Seq => by_seq,
+      _ => self.when(a).cmp(&self.when(b)).then(by_seq),
+    };
+  }
+
+  /// Sorts messages, oldest first.
+  pub(crate) fn sort(&self, msgs: &mut [BrokerMessage]) {
+    msgs.sort_by(|a, b| self.cmp(a, b));
+  }
+}
+
+/// Which stored messages a query wants, and how. Whatever's left as None
+/// matches everything. Built up from MessageFilter::default() or of_type,
+/// with whatever's optional filled in as is:
+///
+/// ```ignore
+/// MessageFilter {
+///   limit: query.limit,
+///   ..MessageFilter::default()
+///     .sensor(SensorType::Temperature)
+///     .between(from, to)
+///     .ordered(MessageOrder::Seq)
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct MessageFilter {
+  /// Payload type. Implied to be sensor data by a sensor type or ID.
+  pub(crate) mtype: Option<BrokerMessagePayloadType>,
+  /// Sensor type, for sensor data.
+  pub(crate) sensor_type: Option<SensorType>,
+  /// Sensor ID, for sensor data.
+  pub(crate) sensor_id: Option<SensorId>,
+  /// Broker that sent them.
+  pub(crate) broker_id: Option<BrokerId>,
+  /// Topic, for decode failures, which implies them.
+  pub(crate) topic: Option<String>,
+  /// Start of the time range, inclusive.
+  pub(crate) from: Option<DateTime<Local>>,
+  /// End of the time range, inclusive.
+  pub(crate) to: Option<DateTime<Local>>,
+  /// Only those with a sequence number past this one, for paging.
+  pub(crate) after: Option<u64>,
+  /// Only those whose signature the broker checked, or only the others.
+  pub(crate) verified: Option<bool>,
+  /// Which way they go, and which time the range goes by. None for no
+  /// particular order, going by construction time, which lets backends
+  /// stream them instead of holding them all to sort.
+  pub(crate) order: Option<MessageOrder>,
+  /// Whether they go the other way, newest first. Only with an order.
+  pub(crate) newest_first: bool,
+  /// How many to get at most, the first ones in order.
+  pub(crate) limit: Option<usize>
+}
+
+impl MessageFilter {
+  /// Messages of a payload type.
+  pub(crate) fn of_type(mtype: BrokerMessagePayloadType) -> Self {
+    return Self { mtype: Some(mtype), ..Self::default() };
+  }
+
+  /// Only sensor data of a sensor type.
+  pub(crate) fn sensor(self, stype: SensorType) -> Self {
+    return Self { sensor_type: Some(stype), ..self };
+  }
+
+  /// Only sensor data of sensors with an ID.
+  pub(crate) fn sensor_id(self, sensor_id: SensorId) -> Self {
+    return Self { sensor_id: Some(sensor_id), ..self };
+  }
+
+  /// Only messages within a time range, inclusive.
+  pub(crate) fn between(self, from: DateTime<Local>, to: DateTime<Local>)
+  -> Self {
+    return Self { from: Some(from), to: Some(to), ..self };
+  }
+
+  /// In an order, with the time range going by it.
+  pub(crate) fn ordered(self, order: MessageOrder) -> Self {
+    return Self { order: Some(order), ..self };
+  }
+
+  /// Newest first, in whatever order it's in.
+  pub(crate) fn newest_first(self) -> Self {
+    return Self { newest_first: true, ..self };
+  }
+
+  /// The payload type wanted, said or implied, if any.
+  pub(crate) fn payload_type(&self) -> Option<BrokerMessagePayloadType> {
+    if self.sensor_type.is_some() || self.sensor_id.is_some() {
+      return Some(BrokerMessagePayloadType::SensorData);
+    }
+    if self.topic.is_some() {
+      return Some(BrokerMessagePayloadType::DecodeFailure);
+    }
+    return self.mtype;
+  }
+
+  /// Whether the time range goes by construction time, which backends with
+  /// keys by it can look up as a range.
+  pub(crate) fn by_construction(&self) -> bool {
+    return self.order.unwrap_or_default() == MessageOrder::Constructed;
+  }
+
+  /// Whether a message matches, ordering and limit aside.
+  pub(crate) fn matches(&self, msg: &BrokerMessage) -> bool {
+    let payload_type = self.payload_type();
+    if payload_type.map(|t| msg.payload_type() != t).unwrap_or(false) {
+      return false;
+    }
+    if self.broker_id.map(|b| msg.broker_id != b).unwrap_or(false) {
+      return false;
+    }
+    if self.after.map(|a| msg.seq <= Some(a)).unwrap_or(false) {
+      return false;
+    }
+    if self.verified.map(|v| msg.verified != v).unwrap_or(false) {
+      return false;
+    }
+    let in_payload = match &msg.payload {
+      BrokerMessagePayload::SensorData(sd) => {
+        self.sensor_type.map(|t| sd.sensor_type() == t).unwrap_or(true)
+          && self.sensor_id.map(|i| sd.sensor_id() == i).unwrap_or(true)
+      },
+      BrokerMessagePayload::DecodeFailure { topic, .. } => {
+        self.topic.as_ref().map(|t| topic == t).unwrap_or(true)
+      },
+      _ => true,
+    };
+    if !in_payload {
+      return false;
+    }
+    if self.from.is_none() && self.to.is_none() {
+      return true;
+    }
+    return match self.order.unwrap_or_default().when(msg) {
+      Some(when) => {
+        self.from.map(|f| when >= f).unwrap_or(true)
+          && self.to.map(|t| when <= t).unwrap_or(true)
+      },
+      None => false,
+    };
+  }
+
+  /// Orders and limits the messages that matched, as backends find them.
+  /// Holds them all if there's an order to put them in.
+  pub(crate) fn finish<I>(&self, matched: I)
+  -> Box<dyn Iterator<Item=BrokerMessage> + Send>
+  where I: Iterator<Item=BrokerMessage> + Send + 'static {
+    let limit = self.limit.unwrap_or(usize::MAX);
+    return match self.order {
+      Some(order) => {
+        let mut msgs: Vec<BrokerMessage> = matched.collect();
+        order.sort(&mut msgs);
+        if self.newest_first {
+          msgs.reverse();
+        }
+        msgs.truncate(limit);
+        Box::new(msgs.into_iter())
+      },
+      None => Box::new(matched.take(limit)),
+    };
+  }
+}
+
+/// Error from a batch insert. Nothing from the batch was stored.
+#[derive(Debug)]
+pub(crate) struct BatchInsertError<E: StdError> {
+  /// Position, within the batch, of the message that failed.
+  pub(crate) index: usize,
+  /// What went wrong with it.
+  pub(crate) error: E
+}
+
+impl<E: StdError> StdError for BatchInsertError<E> {}
+
+impl<E: StdError> Display for BatchInsertError<E> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return write!(f, "Message #{} of the batch failed: {}", self.index,
+      self.error);
+  }
 }
 
 /// Types of available API databases.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub(crate) enum ApiDatabaseType {
-  InMemory
+  InMemory,
+  Redis,
+  Sled
 }
 
 impl ApiDatabaseType {
   pub(crate) fn all_types() -> Vec<Self> {
     return vec![
-      ApiDatabaseType::InMemory
+      ApiDatabaseType::InMemory,
+      ApiDatabaseType::Redis,
+      ApiDatabaseType::Sled
     ];
   }
 }
@@ -63,6 +661,8 @@ impl Display for ApiDatabaseType {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(f, "{}", match self {
       ApiDatabaseType::InMemory => "in_memory",
+      ApiDatabaseType::Redis => "redis",
+      ApiDatabaseType::Sled => "sled",
     })
   }
 }
@@ -0,0 +1,42 @@
+//! The way sync backends, like Redis and sled, serve asynchronous callers:
+//! every query runs on actix's thread pool for blocking work, so the workers
+//! answering requests never wait on the network or the disk themselves.
+
+use actix_web::web;
+use async_trait::async_trait;
+
+use crate::db::{ApiDatabase, AsyncApiDatabase};
+
+/// A synchronous database, made asynchronous.
+#[derive(Clone, Debug)]
+pub(crate) struct Blocking<D: ApiDatabase> {
+  /// The database.
+  db: D
+}
+
+impl<D: ApiDatabase> Blocking<D> {
+  /// Wraps a database.
+  pub(crate) fn new(db: D) -> Self {
+    return Self { db: db };
+  }
+}
+
+#[async_trait]
+impl<D: ApiDatabase + 'static> AsyncApiDatabase for Blocking<D> {
+  type Sync = D;
+
+  fn sync(&self) -> &D {
+    return &self.db;
+  }
+
+  /// Runs on the thread pool. A query that panics there takes the caller
+  /// down with it, as it would have had it run in place.
+  async fn blocking<T, F>(&self, f: F) -> T
+  where T: Send + 'static, F: FnOnce(&D) -> T + Send + 'static {
+    let db = self.db.clone();
+    return match web::block(move || Ok::<T, ()>(f(&db))).await {
+      Ok(t) => t,
+      Err(_) => panic!("A database query panicked on the thread pool."),
+    };
+  }
+}
@@ -0,0 +1,216 @@
+//! An append-only log kept in shared chunks. Readers take a snapshot by
+//! cloning a handful of Arcs instead of copying every item.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// How many items go in a chunk before a new one is started.
+pub(crate) const CHUNK_LEN: usize = 1024;
+
+/// The log. Only the last chunk is ever written to; if a snapshot still
+/// holds it, it gets copied first, which costs at most CHUNK_LEN clones.
+#[derive(Clone, Debug)]
+pub(crate) struct ChunkedLog<T> {
+  chunks: Vec<Arc<Vec<T>>>
+}
+
+impl<T: Clone> ChunkedLog<T> {
+  /// An empty log.
+  pub(crate) fn new() -> Self {
+    return Self { chunks: Vec::new() };
+  }
+
+  /// Appends an item.
+  pub(crate) fn push(&mut self, item: T) {
+    match self.chunks.last_mut() {
+      Some(c) if c.len() < CHUNK_LEN => Arc::make_mut(c).push(item),
+      _ => {
+        let mut c = Vec::with_capacity(CHUNK_LEN);
+        c.push(item);
+        self.chunks.push(Arc::new(c));
+      },
+    };
+  }
+
+  /// Iterates over the items, oldest first.
+  pub(crate) fn iter(&self) -> impl Iterator<Item=&T> {
+    return self.chunks.iter().flat_map(|c| c.iter());
+  }
+
+  /// Takes a snapshot, and lazily maps and filters it. Only the items the
+  /// closure keeps are ever cloned, and the log is free to grow meanwhile.
+  pub(crate) fn snapshot_filter_map<U, F>(&self, f: F) -> SnapshotIter<T, F>
+  where F: FnMut(&T) -> Option<U> {
+    return SnapshotIter {
+      chunks: self.chunks.clone(),
+      chunk: 0,
+      idx: 0,
+      f: f
+    };
+  }
+}
+
+impl<T: Clone> Extend<T> for ChunkedLog<T> {
+  fn extend<I: IntoIterator<Item=T>>(&mut self, items: I) {
+    for item in items {
+      self.push(item);
+    }
+  }
+}
+
+impl<T: Clone> Default for ChunkedLog<T> {
+  fn default() -> Self {
+    return Self::new();
+  }
+}
+
+impl<T: Serialize + Clone> Serialize for ChunkedLog<T> {
+  /// Serializes as a flat sequence, same as a Vec would.
+  fn serialize<S: Serializer>(&self, serializer: S)
+  -> Result<S::Ok, S::Error> {
+    return serializer.collect_seq(self.iter());
+  }
+}
+
+impl<'de, T: Deserialize<'de> + Clone> Deserialize<'de> for ChunkedLog<T> {
+  /// Deserializes from a flat sequence, same as a Vec would.
+  fn deserialize<D: Deserializer<'de>>(deserializer: D)
+  -> Result<Self, D::Error> {
+    let items: Vec<T> = Vec::deserialize(deserializer)?;
+    let mut log = Self::new();
+    log.extend(items);
+    return Ok(log);
+  }
+}
+
+/// Iterator over a snapshot of a ChunkedLog.
+pub(crate) struct SnapshotIter<T, F> {
+  /// The chunks, as they were when the snapshot was taken.
+  chunks: Vec<Arc<Vec<T>>>,
+  /// Current chunk.
+  chunk: usize,
+  /// Position within the current chunk.
+  idx: usize,
+  /// Picks and converts items.
+  f: F
+}
+
+impl<T, U, F> Iterator for SnapshotIter<T, F>
+where F: FnMut(&T) -> Option<U> {
+  type Item = U;
+
+  fn next(&mut self) -> Option<U> {
+    while let Some(c) = self.chunks.get(self.chunk) {
+      // writers copy any chunk we still hold before touching it, so this
+      // never changes under our feet.
+      while let Some(item) = c.get(self.idx) {
+        self.idx += 1;
+        if let Some(out) = (self.f)(item) {
+          return Some(out);
+        }
+      }
+      self.chunk += 1;
+      self.idx = 0;
+    }
+    return None;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use criterion::{black_box, Criterion};
+
+  use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+  use libcdp::comm::sensor_broker::{AnySensorMessage, HumidityMessage, SensorType, TemperatureMessage};
+  use libcdp::ids::BrokerId;
+
+  use super::*;
+
+  /// Messages in the log being timed.
+  const MESSAGES: usize = 200_000;
+
+  /// Temperature and humidity readings taking turns, over a few sensors.
+  fn readings(n: usize) -> Vec<BrokerMessage> {
+    let broker_id = BrokerId::new_v4();
+    return (0..n).map(|i| {
+      let sensor_id = (i % 8) as u8;
+      let sd = match i % 2 {
+        0 => AnySensorMessage::Temperature(TemperatureMessage {
+          sensor_id: sensor_id,
+          kelvin: 294
+        }),
+        _ => AnySensorMessage::Humidity(HumidityMessage {
+          sensor_id: sensor_id,
+          humidity: 21
+        }),
+      };
+      return BrokerMessage::construct(
+        broker_id, BrokerMessagePayload::SensorData(sd)
+      );
+    }).collect();
+  }
+
+  /// Keeps temperature readings.
+  fn temperature(msg: &BrokerMessage) -> Option<BrokerMessage> {
+    return match &msg.payload {
+      BrokerMessagePayload::SensorData(sd)
+        if sd.sensor_type() == SensorType::Temperature => Some(msg.clone()),
+      _ => None,
+    };
+  }
+
+  #[test]
+  fn snapshots_keep_what_they_saw() {
+    let mut log = ChunkedLog::new();
+    log.extend(0..CHUNK_LEN + 1);
+    let snap = log.snapshot_filter_map(|i| Some(*i));
+    log.extend(0..CHUNK_LEN);
+    assert_eq!(snap.count(), CHUNK_LEN + 1);
+    assert_eq!(log.iter().count(), 2 * CHUNK_LEN + 1);
+  }
+
+  #[test]
+  fn round_trips_as_a_flat_sequence() {
+    let mut log = ChunkedLog::new();
+    log.extend(0..CHUNK_LEN * 2 + 3);
+    let json = serde_json::to_string(&log).unwrap();
+    let flat: Vec<usize> = serde_json::from_str(&json).unwrap();
+    assert_eq!(flat, (0..CHUNK_LEN * 2 + 3).collect::<Vec<usize>>());
+    let back: ChunkedLog<usize> = serde_json::from_str(&json).unwrap();
+    assert!(back.iter().eq(log.iter()));
+  }
+
+  /// How long picking temperature readings out takes, from a snapshot,
+  /// against cloning every message first, as the Vec the log replaced had
+  /// to be. Run it with
+  /// `cargo test --release -p cdp_api query_speed -- --ignored --nocapture`.
+  #[test]
+  #[ignore]
+  fn query_speed() {
+    let msgs = readings(MESSAGES);
+    let mut log = ChunkedLog::new();
+    log.extend(msgs.iter().cloned());
+    let mut c = Criterion::default().sample_size(50);
+    let mut group = c.benchmark_group("inmem");
+    for &(name, limit) in [("all", usize::MAX), ("first 10", 10)].iter() {
+      group.bench_function(format!("{}, from a snapshot", name), |b| {
+        b.iter(|| {
+          return log.snapshot_filter_map(temperature)
+            .take(black_box(limit))
+            .count();
+        })
+      });
+      group.bench_function(format!("{}, cloning all", name), |b| {
+        b.iter(|| {
+          return msgs.clone()
+            .iter()
+            .filter_map(temperature)
+            .take(black_box(limit))
+            .count();
+        })
+      });
+    }
+    group.finish();
+  }
+}
@@ -4,21 +4,57 @@
 use std::collections::HashSet;
 use std::error::Error as StdError;
 use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
-use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload, BrokerMessagePayloadType};
-use libcdp::comm::sensor_broker::{AnySensorMessage, SensorType};
+use libcdp::comm::broker_api::BrokerMessage;
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, MessageId, SensorId};
 use std::sync::{Arc, Mutex, PoisonError};
 
-use crate::db::ApiDatabase;
+use crate::audit::AuditEntry;
+use crate::brokers::BrokerRecord;
+use crate::calibration::{Calibration, SensorCalibration};
+use crate::db::{ApiDatabase, AsyncApiDatabase, BatchInsertError, MessageFilter};
+use crate::db::chunked::ChunkedLog;
+use crate::derived::DerivedReading;
+use crate::floorplan::Floorplan;
+use crate::reports::Report;
+use crate::sensors::{RegisteredSensor, SensorInfo};
+use crate::supervisor::{Jobs, Restart};
 
 /// The underlying data for the simple in-memory database.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct UnderlyingData {
   topics: HashSet<SensorType>,
-  messages: Vec<BrokerMessage>
+  messages: ChunkedLog<BrokerMessage>,
+  #[serde(default)]
+  calibrations: Vec<SensorCalibration>,
+  #[serde(default)]
+  sensors: Vec<RegisteredSensor>,
+  #[serde(default)]
+  floorplan: Option<Floorplan>,
+  #[serde(default)]
+  derived: Vec<DerivedReading>,
+  #[serde(default)]
+  brokers: Vec<BrokerRecord>,
+  #[serde(default)]
+  audit: Vec<AuditEntry>,
+  #[serde(default)]
+  reports: Vec<Report>,
+  #[serde(default)]
+  last_seq: u64,
+  /// IDs of every stored message, and those taken for messages being
+  /// stored, rebuilt on load rather than saved.
+  #[serde(skip)]
+  ids: HashSet<MessageId>
 }
 
 impl UnderlyingData {
@@ -26,9 +62,26 @@ impl UnderlyingData {
   pub(crate) fn new<T>(iter: T) -> Self where T: Iterator<Item=SensorType> {
     return Self {
       topics: HashSet::from_iter(iter),
-      messages: Vec::new()
+      messages: ChunkedLog::new(),
+      calibrations: Vec::new(),
+      sensors: Vec::new(),
+      floorplan: None,
+      derived: Vec::new(),
+      brokers: Vec::new(),
+      audit: Vec::new(),
+      reports: Vec::new(),
+      last_seq: 0,
+      ids: HashSet::new()
     }
   }
+
+  /// Stores a message, remembering its ID.
+  fn push(&mut self, msg: BrokerMessage) {
+    if let Some(id) = msg.id {
+      self.ids.insert(id);
+    }
+    self.messages.push(msg);
+  }
 }
 
 impl Default for UnderlyingData {
@@ -47,7 +100,8 @@ pub(crate) struct InMemoryApiDatabase {
 }
 
 impl From<UnderlyingData> for InMemoryApiDatabase {
-  fn from(backing: UnderlyingData) -> Self {
+  fn from(mut backing: UnderlyingData) -> Self {
+    backing.ids = backing.messages.iter().filter_map(|m| m.id).collect();
     return Self {
       backing: Arc::new(Mutex::new(backing))
     }
@@ -64,7 +118,11 @@ impl Default for InMemoryApiDatabase {
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) enum InMemoryDatabaseError {
   /// A mutex lock died. String is type name.
-  PoisonError(String)
+  PoisonError(String),
+  /// Could not read or write a snapshot file.
+  SnapshotIo(String),
+  /// A snapshot file had bad contents.
+  SnapshotFormat(String)
 }
 
 impl StdError for InMemoryDatabaseError {}
@@ -74,7 +132,13 @@ impl Display for InMemoryDatabaseError {
     match self {
       InMemoryDatabaseError::PoisonError(tn) => {
         return write!(f, "A mutex on a {} was poisoned!", tn);
-      }
+      },
+      InMemoryDatabaseError::SnapshotIo(e) => {
+        return write!(f, "Snapshot I/O error: {}", e);
+      },
+      InMemoryDatabaseError::SnapshotFormat(e) => {
+        return write!(f, "Bad snapshot: {}", e);
+      },
     }
   }
 }
@@ -85,10 +149,73 @@ impl<T> From<PoisonError<T>> for InMemoryDatabaseError {
   }
 }
 
+impl InMemoryApiDatabase {
+  /// Loads a database from a JSON snapshot file.
+  pub(crate) fn load_snapshot(path: &Path)
+  -> Result<Self, InMemoryDatabaseError> {
+    let file = File::open(path)
+      .map_err(|e| InMemoryDatabaseError::SnapshotIo(e.to_string()))?;
+    return Self::read_snapshot(BufReader::new(file));
+  }
+
+  /// Loads a database from JSON snapshot data.
+  pub(crate) fn read_snapshot<R: Read>(reader: R)
+  -> Result<Self, InMemoryDatabaseError> {
+    let data: UnderlyingData = serde_json::from_reader(reader)
+      .map_err(|e| InMemoryDatabaseError::SnapshotFormat(e.to_string()))?;
+    return Ok(Self::from(data));
+  }
+
+  /// Writes the whole database as JSON snapshot data.
+  pub(crate) fn write_snapshot<W: Write>(&self, writer: W)
+  -> Result<(), InMemoryDatabaseError> {
+    let data = self.backing.lock()?.clone();
+    return serde_json::to_writer(writer, &data)
+      .map_err(|e| InMemoryDatabaseError::SnapshotFormat(e.to_string()));
+  }
+
+  /// Saves the whole database as a JSON snapshot file. Writes to a temporary
+  /// file first and syncs it before renaming it over the old one, then syncs
+  /// the directory, so neither a crash nor a power cut mid-write will eat the
+  /// previous snapshot.
+  pub(crate) fn save_snapshot(&self, path: &Path)
+  -> Result<(), InMemoryDatabaseError> {
+    let tmp = path.with_extension("tmp");
+    let io_err = |e: std::io::Error| {
+      InMemoryDatabaseError::SnapshotIo(e.to_string())
+    };
+    let mut writer = BufWriter::new(File::create(&tmp).map_err(io_err)?);
+    self.write_snapshot(&mut writer)?;
+    let file = writer.into_inner().map_err(|e| io_err(e.into_error()))?;
+    file.sync_all().map_err(io_err)?;
+    fs::rename(&tmp, path).map_err(io_err)?;
+    let dir = match path.parent() {
+      Some(d) if !d.as_os_str().is_empty() => d,
+      _ => Path::new("."),
+    };
+    File::open(dir).and_then(|d| d.sync_all()).map_err(io_err)?;
+    return Ok(());
+  }
+
+  /// Spawns a job that saves a snapshot every so often. Runs forever.
+  pub(crate) fn spawn_snapshotter(
+    &self, jobs: &Jobs, path: PathBuf, every: Duration
+  ) {
+    let db = self.clone();
+    jobs.spawn("snapshotter", Restart::Always, move || {
+      loop {
+        thread::sleep(every);
+        if let Err(e) = db.save_snapshot(&path) {
+          warn!("Failed to save snapshot: {}", e);
+        }
+      }
+    });
+  }
+}
+
 impl ApiDatabase for InMemoryApiDatabase {
   type DbError = InMemoryDatabaseError;
-  type BrokerMessageIter = Box<dyn Iterator<Item=BrokerMessage>>;
-  type SensorMessageIter = Box<dyn Iterator<Item=AnySensorMessage>>;
+  type BrokerMessageIter = Box<dyn Iterator<Item=BrokerMessage> + Send>;
   type DbConfig = ();
 
   fn db_type(&self) -> super::ApiDatabaseType {
@@ -118,36 +245,211 @@ impl ApiDatabase for InMemoryApiDatabase {
     return Ok(());
   }
 
-  fn messages_by_type(&self, mtype: BrokerMessagePayloadType)
+  fn query(&self, filter: MessageFilter)
   -> Result<Self::BrokerMessageIter, Self::DbError> {
     let d = self.backing.lock()?;
-    return Ok(Box::new(d.messages
-      .clone()
-      .into_iter()
-      .filter_map(move |m|
-        if m.payload_type() == mtype { Some(m.clone()) } else { None }
-      )
-    ));
+    let wanted = filter.clone();
+    let matched = d.messages.snapshot_filter_map(move |m|
+      if wanted.matches(m) { Some(m.clone()) } else { None }
+    );
+    return Ok(filter.finish(matched));
   }
 
-  fn sensor_messages_by_type(&self, stype: SensorType)
-  -> Result<Self::SensorMessageIter, Self::DbError> {
-    let d = self.backing.lock()?;
-    return Ok(Box::new(d.messages
-      .clone()
-      .into_iter()
-      .filter_map(move |msg| match msg.payload {
-        BrokerMessagePayload::SensorData(sd) => {
-          if sd.sensor_type() == stype { Some(sd) } else { None }
-        }
-        _ => None,
-      })
-    ));
+  fn next_seq(&self, count: u64) -> Result<u64, Self::DbError> {
+    let mut d = self.backing.lock()?;
+    let first = d.last_seq + 1;
+    d.last_seq += count;
+    return Ok(first);
   }
 
   fn insert_message(&self, msg: BrokerMessage) -> Result<(), Self::DbError> {
     let mut d = self.backing.lock()?;
-    d.messages.push(msg);
+    d.push(msg);
+    return Ok(());
+  }
+
+  /// Nothing can fail halfway in here, so holding the lock is transaction
+  /// enough.
+  fn insert_messages(&self, msgs: Vec<BrokerMessage>)
+  -> Result<(), BatchInsertError<Self::DbError>> {
+    let mut d = self.backing.lock()
+      .map_err(|e| BatchInsertError { index: 0, error: e.into() })?;
+    for msg in msgs {
+      d.push(msg);
+    }
+    return Ok(());
+  }
+
+  fn known_ids(&self, ids: &[MessageId])
+  -> Result<HashSet<MessageId>, Self::DbError> {
+    let d = self.backing.lock()?;
+    return Ok(ids.iter().filter(|id| d.ids.contains(id)).cloned().collect());
+  }
+
+  /// Taken IDs go straight in with those of the stored messages, under the
+  /// same lock.
+  fn claim_ids(&self, ids: &[MessageId])
+  -> Result<HashSet<MessageId>, Self::DbError> {
+    let mut d = self.backing.lock()?;
+    return Ok(ids.iter().filter(|id| !d.ids.insert(**id)).cloned().collect());
+  }
+
+  fn release_ids(&self, ids: &[MessageId]) -> Result<(), Self::DbError> {
+    let mut d = self.backing.lock()?;
+    for id in ids {
+      d.ids.remove(id);
+    }
+    return Ok(());
+  }
+
+  fn calibrations(&self) -> Result<Vec<SensorCalibration>, Self::DbError> {
+    let d = self.backing.lock()?;
+    return Ok(d.calibrations.clone());
+  }
+
+  fn calibration(&self, stype: SensorType, sensor_id: SensorId)
+  -> Result<Option<Calibration>, Self::DbError> {
+    let d = self.backing.lock()?;
+    return Ok(d.calibrations
+      .iter()
+      .find(|sc| sc.sensor_type == stype && sc.sensor_id == sensor_id)
+      .map(|sc| sc.calibration)
+    );
+  }
+
+  fn set_calibration(
+    &self, stype: SensorType, sensor_id: SensorId, cal: Option<Calibration>
+  ) -> Result<(), Self::DbError> {
+    let mut d = self.backing.lock()?;
+    d.calibrations
+      .retain(|sc| !(sc.sensor_type == stype && sc.sensor_id == sensor_id));
+    if let Some(c) = cal {
+      d.calibrations.push(SensorCalibration {
+        sensor_type: stype,
+        sensor_id: sensor_id,
+        calibration: c
+      });
+    }
+    return Ok(());
+  }
+
+  fn registered_sensors(&self)
+  -> Result<Vec<RegisteredSensor>, Self::DbError> {
+    let d = self.backing.lock()?;
+    return Ok(d.sensors.clone());
+  }
+
+  fn sensor_info(&self, stype: SensorType, sensor_id: SensorId)
+  -> Result<Option<SensorInfo>, Self::DbError> {
+    let d = self.backing.lock()?;
+    return Ok(d.sensors
+      .iter()
+      .find(|rs| rs.sensor_type == stype && rs.sensor_id == sensor_id)
+      .map(|rs| rs.info.clone())
+    );
+  }
+
+  fn set_sensor_info(
+    &self, stype: SensorType, sensor_id: SensorId, info: Option<SensorInfo>
+  ) -> Result<(), Self::DbError> {
+    let mut d = self.backing.lock()?;
+    d.sensors
+      .retain(|rs| !(rs.sensor_type == stype && rs.sensor_id == sensor_id));
+    if let Some(i) = info {
+      d.sensors.push(RegisteredSensor {
+        sensor_type: stype,
+        sensor_id: sensor_id,
+        info: i
+      });
+    }
+    return Ok(());
+  }
+
+  fn floorplan(&self) -> Result<Option<Floorplan>, Self::DbError> {
+    let d = self.backing.lock()?;
+    return Ok(d.floorplan.clone());
+  }
+
+  fn set_floorplan(&self, plan: Option<Floorplan>)
+  -> Result<(), Self::DbError> {
+    let mut d = self.backing.lock()?;
+    d.floorplan = plan;
     return Ok(());
   }
+
+  fn insert_derived(&self, reading: DerivedReading)
+  -> Result<(), Self::DbError> {
+    let mut d = self.backing.lock()?;
+    d.derived.push(reading);
+    return Ok(());
+  }
+
+  fn derived_readings(&self, name: &str)
+  -> Result<Vec<DerivedReading>, Self::DbError> {
+    let d = self.backing.lock()?;
+    return Ok(d.derived
+      .iter()
+      .filter(|r| r.name == name)
+      .cloned()
+      .collect()
+    );
+  }
+
+  fn brokers(&self) -> Result<Vec<BrokerRecord>, Self::DbError> {
+    let d = self.backing.lock()?;
+    let mut brokers = d.brokers.clone();
+    brokers.sort_by_key(|b| b.uid);
+    return Ok(brokers);
+  }
+
+  fn broker(&self, uid: BrokerId)
+  -> Result<Option<BrokerRecord>, Self::DbError> {
+    let d = self.backing.lock()?;
+    return Ok(d.brokers.iter().find(|b| b.uid == uid).cloned());
+  }
+
+  fn update_broker(&self, rec: BrokerRecord) -> Result<(), Self::DbError> {
+    let mut d = self.backing.lock()?;
+    d.brokers.retain(|b| b.uid != rec.uid);
+    d.brokers.push(rec);
+    return Ok(());
+  }
+
+  fn insert_audit(&self, entry: AuditEntry) -> Result<(), Self::DbError> {
+    let mut d = self.backing.lock()?;
+    d.audit.push(entry);
+    return Ok(());
+  }
+
+  fn audit_log(&self) -> Result<Vec<AuditEntry>, Self::DbError> {
+    let d = self.backing.lock()?;
+    return Ok(d.audit.clone());
+  }
+
+  fn insert_report(&self, report: Report) -> Result<(), Self::DbError> {
+    let mut d = self.backing.lock()?;
+    d.reports.push(report);
+    return Ok(());
+  }
+
+  fn reports(&self) -> Result<Vec<Report>, Self::DbError> {
+    let d = self.backing.lock()?;
+    return Ok(d.reports.clone());
+  }
+}
+
+/// Queries only ever wait on a lock held just as long as another query
+/// takes, so they run in place, wherever they're awaited.
+#[async_trait]
+impl AsyncApiDatabase for InMemoryApiDatabase {
+  type Sync = Self;
+
+  fn sync(&self) -> &Self {
+    return self;
+  }
+
+  async fn blocking<T, F>(&self, f: F) -> T
+  where T: Send + 'static, F: FnOnce(&Self) -> T + Send + 'static {
+    return f(self);
+  }
 }
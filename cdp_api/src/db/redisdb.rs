@@ -0,0 +1,911 @@
+//! Implements a Redis-backed database, so several API replicas can share
+//! storage without a full SQL server.
+//!
+//! Everything lives under the "cdp:" prefix, stored as JSON:
+//! - `cdp:topics`: set of sensor type names.
+//! - `cdp:sensor:{type}`: sorted set of sensor data messages, per sensor
+//!   type, scored by construction time.
+//! - `cdp:messages:{type}`: sorted set of every other message, per payload
+//!   type, scored the same way.
+//! - `cdp:sensors`: set of every "{type}:{id}" we've heard from.
+//! - `cdp:ids`: set of the ID of every stored message, or one being stored.
+//! - `cdp:latest:{type}:{id}`: sorted set holding only the newest message of
+//!   a sensor, scored by construction time.
+//! - `cdp:calibrations`: hash of "{type}:{id}" to calibration.
+//! - `cdp:sensor_info`: hash of "{type}:{id}" to the sensor's name and room.
+//! - `cdp:floorplan`: the floorplan.
+//! - `cdp:derived:{name}`: stream of computed values of a virtual sensor.
+//! - `cdp:brokers`: hash of broker uid to broker record.
+//! - `cdp:audit`: stream of audit log entries.
+//! - `cdp:reports`: stream of summary reports.
+//! - `cdp:seq`: the last sequence number handed out.
+//!
+//! API instances sharing the database tell each other about stored messages,
+//! alerts, mutes and arming changes through the `cdp:ingested` pub/sub
+//! channel.
+//!
+//! Within with_txn, commands that write are queued, and sent at the end as a
+//! single MULTI/EXEC. Nothing is sent if it's called off.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error as StdError;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use r2d2::{Pool, PooledConnection};
+use redis::{Client, Commands, Pipeline};
+use redis::streams::StreamRangeReply;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload, BrokerMessagePayloadType};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, MessageId, SensorId};
+
+use crate::audit::AuditEntry;
+use crate::brokers::BrokerRecord;
+use crate::calibration::{Calibration, SensorCalibration};
+use crate::db::{
+  self, ApiDatabase, ApiDatabaseType, BatchInsertError, MessageFilter, News
+};
+use crate::derived::DerivedReading;
+use crate::floorplan::Floorplan;
+use crate::reports::Report;
+use crate::sensors::{RegisteredSensor, SensorInfo};
+use crate::supervisor::{Jobs, Restart};
+
+/// Prefix of every key we touch.
+const KEY_PREFIX: &str = "cdp";
+
+/// Stream entries fetched per round trip when iterating.
+const PAGE_LEN: usize = 1000;
+
+/// Stream entry field holding the JSON.
+const JSON_FIELD: &str = "json";
+
+/// How long to wait before resubscribing after losing the connection.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Key of the sorted set holding sensor data of a sensor type.
+fn sensor_key(stype: SensorType) -> String {
+  return format!("{}:sensor:{}", KEY_PREFIX, stype);
+}
+
+/// Key of the sorted set holding messages of a non-sensor payload type.
+fn messages_key(mtype: BrokerMessagePayloadType) -> String {
+  return format!("{}:messages:{}", KEY_PREFIX, mtype);
+}
+
+/// Keys of the sorted sets holding messages of a payload type.
+fn message_keys(mtype: BrokerMessagePayloadType) -> Vec<String> {
+  return match mtype {
+    BrokerMessagePayloadType::SensorData => {
+      SensorType::all_types().into_iter().map(sensor_key).collect()
+    },
+    other => vec![messages_key(other)],
+  };
+}
+
+/// Score of a message in the sorted sets: when it was constructed, in
+/// nanoseconds. Doubles can't tell every nanosecond apart, but they keep
+/// the order, so ranges by them come out a little wide at worst, and are
+/// narrowed down after.
+fn score(when: &DateTime<Local>) -> f64 {
+  return db::time_nanos(when) as f64;
+}
+
+/// Key of the sorted set holding the newest message of a sensor.
+fn latest_key(sensor: &str) -> String {
+  return format!("{}:latest:{}", KEY_PREFIX, sensor);
+}
+
+/// Key of the stream holding values of a virtual sensor.
+fn derived_key(name: &str) -> String {
+  return format!("{}:derived:{}", KEY_PREFIX, name);
+}
+
+/// Key of some other, single-item thing.
+fn key(what: &str) -> String {
+  return format!("{}:{}", KEY_PREFIX, what);
+}
+
+/// How a sensor is named within hashes and sets.
+fn sensor_field(stype: SensorType, sensor_id: SensorId) -> String {
+  return format!("{}:{}", stype, sensor_id);
+}
+
+/// Undoes sensor_field.
+fn parse_sensor_field(field: &str) -> Option<(SensorType, SensorId)> {
+  let mut parts = field.splitn(2, ':');
+  let stype = SensorType::from_str(parts.next()?).ok()?;
+  let sensor_id = parts.next()?.parse().ok()?;
+  return Some((stype, sensor_id));
+}
+
+/// The stream entry ID right after the given one, so we can page through
+/// streams without relying on exclusive ranges.
+fn next_stream_id(id: &str) -> String {
+  let mut parts = id.splitn(2, '-');
+  let ms = parts.next().unwrap_or("0");
+  let seq: u64 = parts.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+  return format!("{}-{}", ms, seq + 1);
+}
+
+/// An error that the Redis database can return.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum RedisDatabaseError {
+  /// Redis said no, or the connection died.
+  Redis(String),
+  /// Couldn't get a connection from the pool.
+  Pool(String),
+  /// Something in there isn't what we stored.
+  Format(String)
+}
+
+impl StdError for RedisDatabaseError {}
+
+impl Display for RedisDatabaseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      RedisDatabaseError::Redis(e) => {
+        return write!(f, "Redis error: {}", e);
+      },
+      RedisDatabaseError::Pool(e) => {
+        return write!(f, "Redis pool error: {}", e);
+      },
+      RedisDatabaseError::Format(e) => {
+        return write!(f, "Bad data in Redis: {}", e);
+      },
+    }
+  }
+}
+
+impl From<redis::RedisError> for RedisDatabaseError {
+  fn from(e: redis::RedisError) -> Self {
+    return RedisDatabaseError::Redis(e.to_string());
+  }
+}
+
+impl From<r2d2::Error> for RedisDatabaseError {
+  fn from(e: r2d2::Error) -> Self {
+    return RedisDatabaseError::Pool(e.to_string());
+  }
+}
+
+impl From<serde_json::Error> for RedisDatabaseError {
+  fn from(e: serde_json::Error) -> Self {
+    return RedisDatabaseError::Format(e.to_string());
+  }
+}
+
+/// Iterates over one or more streams, a page at a time, decoding entries as
+/// it goes. Entries that don't decode are skipped; if Redis goes away
+/// midway, iteration just ends early.
+pub(crate) struct StreamIter<T> {
+  /// Where to get connections.
+  pool: Pool<Client>,
+  /// Streams left to read, current one first.
+  keys: VecDeque<String>,
+  /// First entry ID of the next page.
+  start: String,
+  /// Decoded entries of the current page.
+  buf: VecDeque<T>,
+  /// Turns an entry's JSON into an item, if it should be yielded.
+  decode: fn(&str) -> Option<T>
+}
+
+impl<T> StreamIter<T> {
+  /// Iterates over the given streams, in order.
+  fn new(pool: Pool<Client>, keys: Vec<String>, decode: fn(&str) -> Option<T>)
+  -> Self {
+    return Self {
+      pool: pool,
+      keys: keys.into(),
+      start: "-".to_owned(),
+      buf: VecDeque::new(),
+      decode: decode
+    };
+  }
+
+  /// Fetches the next page of the current stream. False if it was empty.
+  fn fetch(&mut self) -> Result<bool, RedisDatabaseError> {
+    let key = match self.keys.front() {
+      Some(k) => k,
+      None => return Ok(false),
+    };
+    let mut con = self.pool.get()?;
+    let page: StreamRangeReply = con
+      .xrange_count(key, &self.start, "+", PAGE_LEN)?;
+    if let Some(last) = page.ids.last() {
+      self.start = next_stream_id(&last.id);
+    }
+    for entry in page.ids.iter() {
+      let json: Option<String> = entry.get(JSON_FIELD);
+      if let Some(item) = json.as_deref().and_then(self.decode) {
+        self.buf.push_back(item);
+      }
+    }
+    return Ok(!page.ids.is_empty());
+  }
+}
+
+impl<T> Iterator for StreamIter<T> {
+  type Item = T;
+
+  fn next(&mut self) -> Option<T> {
+    loop {
+      if let Some(item) = self.buf.pop_front() {
+        return Some(item);
+      }
+      if self.keys.is_empty() {
+        return None;
+      }
+      match self.fetch() {
+        Ok(true) => {},
+        Ok(false) => {
+          // this stream is done, on to the next one
+          self.keys.pop_front();
+          self.start = "-".to_owned();
+        },
+        Err(e) => {
+          warn!("Stopped reading from Redis early: {}", e);
+          self.keys.clear();
+        },
+      };
+    }
+  }
+}
+
+/// Iterates over messages in one or more sorted sets, within a range of
+/// scores, a page at a time. Otherwise, same as StreamIter.
+pub(crate) struct ScoreIter {
+  /// Where to get connections.
+  pool: Pool<Client>,
+  /// Sorted sets left to read, current one first.
+  keys: VecDeque<String>,
+  /// Lowest score wanted, as Redis takes it.
+  min: String,
+  /// Highest score wanted, as Redis takes it.
+  max: String,
+  /// How many of the current set's matches were read already.
+  offset: usize,
+  /// Decoded messages of the current page.
+  buf: VecDeque<BrokerMessage>
+}
+
+impl ScoreIter {
+  /// Iterates over the given sorted sets, in order, within a range of
+  /// construction times, if any.
+  fn new(
+    pool: Pool<Client>, keys: Vec<String>, from: Option<&DateTime<Local>>,
+    to: Option<&DateTime<Local>>
+  ) -> Self {
+    return Self {
+      pool: pool,
+      keys: keys.into(),
+      min: from.map(|f| score(f).to_string()).unwrap_or("-inf".to_owned()),
+      max: to.map(|t| score(t).to_string()).unwrap_or("+inf".to_owned()),
+      offset: 0,
+      buf: VecDeque::new()
+    };
+  }
+
+  /// Fetches the next page of the current set. False if it was empty.
+  fn fetch(&mut self) -> Result<bool, RedisDatabaseError> {
+    let key = match self.keys.front() {
+      Some(k) => k,
+      None => return Ok(false),
+    };
+    let mut con = self.pool.get()?;
+    let page: Vec<String> = con.zrangebyscore_limit(
+      key, &self.min, &self.max, self.offset as isize, PAGE_LEN as isize
+    )?;
+    self.offset += page.len();
+    for json in page.iter() {
+      if let Ok(msg) = serde_json::from_str(json) {
+        self.buf.push_back(msg);
+      }
+    }
+    return Ok(!page.is_empty());
+  }
+}
+
+impl Iterator for ScoreIter {
+  type Item = BrokerMessage;
+
+  fn next(&mut self) -> Option<BrokerMessage> {
+    loop {
+      if let Some(msg) = self.buf.pop_front() {
+        return Some(msg);
+      }
+      if self.keys.is_empty() {
+        return None;
+      }
+      match self.fetch() {
+        Ok(true) => {},
+        Ok(false) => {
+          // this set is done, on to the next one
+          self.keys.pop_front();
+          self.offset = 0;
+        },
+        Err(e) => {
+          warn!("Stopped reading from Redis early: {}", e);
+          self.keys.clear();
+        },
+      };
+    }
+  }
+}
+
+/// What goes through the pub/sub channel.
+#[derive(Debug, Serialize, Deserialize)]
+struct Notice {
+  /// Instance that had the news.
+  origin: Uuid,
+  /// The news.
+  news: News
+}
+
+/// Commands queued within a transaction, as a MULTI/EXEC pipeline.
+struct Queued(Pipeline);
+
+impl std::fmt::Debug for Queued {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return write!(f, "Queued({} commands)", self.0.cmd_iter().count());
+  }
+}
+
+/// A database living in Redis. Cheap to clone, all clones share the same
+/// connection pool.
+#[derive(Debug, Clone)]
+pub(crate) struct RedisApiDatabase {
+  pool: Pool<Client>,
+  /// For connections the pool can't give, like pub/sub ones.
+  client: Client,
+  /// Tells our own announcements apart from others'.
+  origin: Uuid,
+  /// Commands queued, when this is a handle given out by with_txn.
+  txn: Option<Arc<Mutex<Queued>>>
+}
+
+impl RedisApiDatabase {
+  /// Connects to Redis at the given URL, like "redis://127.0.0.1/".
+  pub(crate) fn connect(url: &str) -> Result<Self, RedisDatabaseError> {
+    let client = Client::open(url)?;
+    let pool = Pool::builder().build(client.clone())?;
+    return Ok(Self {
+      pool: pool,
+      client: client,
+      origin: Uuid::new_v4(),
+      txn: None
+    });
+  }
+
+  /// Subscribes to announcements and calls back with the news until the
+  /// connection dies.
+  fn listen_once<F>(&self, on_news: &F) -> Result<(), RedisDatabaseError>
+  where F: Fn(News) {
+    let mut con = self.client.get_connection()?;
+    let mut sub = con.as_pubsub();
+    sub.subscribe(key("ingested"))?;
+    // whatever happened while we weren't listening
+    on_news(News::Ingested(self.latest_per_sensor()?));
+    loop {
+      let payload: String = sub.get_message()?.get_payload()?;
+      match serde_json::from_str::<Notice>(&payload) {
+        Ok(notice) if notice.origin != self.origin => on_news(notice.news),
+        Ok(_) => {},
+        Err(e) => warn!("Bad announcement from another instance: {}", e),
+      };
+    }
+  }
+
+  /// Gets a connection from the pool.
+  fn con(&self) -> Result<PooledConnection<Client>, RedisDatabaseError> {
+    return Ok(self.pool.get()?);
+  }
+
+  /// Sends commands whose replies don't matter, right away or once the
+  /// transaction commits.
+  fn write(&self, pipe: &Pipeline) -> Result<(), RedisDatabaseError> {
+    match &self.txn {
+      Some(txn) => {
+        let mut txn = txn.lock().unwrap_or_else(|e| e.into_inner());
+        for cmd in pipe.cmd_iter() {
+          txn.0.add_command(cmd.clone()).ignore();
+        }
+      },
+      None => {
+        let _: () = pipe.query(&mut *self.con()?)?;
+      },
+    };
+    return Ok(());
+  }
+
+  /// Reads a whole hash, decoding its values.
+  fn hash_values<T>(&self, hkey: &str)
+  -> Result<Vec<(String, T)>, RedisDatabaseError>
+  where T: for<'de> Deserialize<'de> {
+    let raw: HashMap<String, String> = self.con()?.hgetall(hkey)?;
+    let mut out = Vec::with_capacity(raw.len());
+    for (field, json) in raw {
+      out.push((field, serde_json::from_str(&json)?));
+    }
+    return Ok(out);
+  }
+}
+
+impl ApiDatabase for RedisApiDatabase {
+  type DbError = RedisDatabaseError;
+  type BrokerMessageIter = Box<dyn Iterator<Item=BrokerMessage> + Send>;
+  /// The Redis URL.
+  type DbConfig = String;
+
+  fn db_type(&self) -> ApiDatabaseType {
+    return ApiDatabaseType::Redis;
+  }
+
+  fn init(&self, cfg: Self::DbConfig) -> Result<Self, Self::DbError> {
+    return Self::connect(&cfg);
+  }
+
+  /// Seeds the topics with every sensor type, unless someone already set
+  /// them up.
+  fn setup(&self) {
+    let seeded = self.con().and_then(|mut con| {
+      let exists: bool = con.exists(key("topics"))?;
+      if !exists {
+        let names: Vec<String> = SensorType::all_types()
+          .into_iter()
+          .map(|st| st.to_string())
+          .collect();
+        let _: () = con.sadd(key("topics"), names)?;
+      }
+      return Ok(());
+    });
+    if let Err(e) = seeded {
+      warn!("Failed to set up Redis: {}", e);
+    }
+  }
+
+  fn ping(&self) -> Result<(), Self::DbError> {
+    let _: String = redis::cmd("PING").query(&mut *self.con()?)?;
+    return Ok(());
+  }
+
+  fn topics(&self) -> Result<HashSet<SensorType>, Self::DbError> {
+    let names: Vec<String> = self.con()?.smembers(key("topics"))?;
+    return Ok(names
+      .iter()
+      .filter_map(|n| SensorType::from_str(n).ok())
+      .collect()
+    );
+  }
+
+  fn update_topics<T>(&self, new_topics: T) -> Result<(), Self::DbError>
+  where T: IntoIterator<Item=SensorType> {
+    let names: Vec<String> = new_topics
+      .into_iter()
+      .map(|st| st.to_string())
+      .collect();
+    let mut pipe = redis::pipe();
+    pipe.atomic().del(key("topics")).ignore();
+    if !names.is_empty() {
+      pipe.sadd(key("topics"), names).ignore();
+    }
+    return self.write(&pipe);
+  }
+
+  /// Goes over the sorted sets of the payload type, or sensor type,
+  /// wanted, or every set. They're scored by construction time, so ranges
+  /// by it only read what's within them.
+  fn query(&self, filter: MessageFilter)
+  -> Result<Self::BrokerMessageIter, Self::DbError> {
+    let keys = match (filter.payload_type(), filter.sensor_type) {
+      (_, Some(stype)) => vec![sensor_key(stype)],
+      (Some(mtype), None) => message_keys(mtype),
+      (None, None) => BrokerMessagePayloadType::all_types()
+        .into_iter()
+        .flat_map(message_keys)
+        .collect(),
+    };
+    let (from, to) = match filter.by_construction() {
+      true => (filter.from.as_ref(), filter.to.as_ref()),
+      false => (None, None),
+    };
+    let wanted = filter.clone();
+    let matched = ScoreIter::new(self.pool.clone(), keys, from, to)
+      .filter(move |m| wanted.matches(m));
+    return Ok(filter.finish(matched));
+  }
+
+  /// A plain INCRBY, so instances sharing the database never hand out the
+  /// same number. Numbers only say in which order batches were stored when
+  /// they come from the same instance, though.
+  fn next_seq(&self, count: u64) -> Result<u64, Self::DbError> {
+    let mut con = self.con()?;
+    let last: u64 = con.incr(key("seq"), count)?;
+    return Ok(last - count + 1);
+  }
+
+  fn insert_message(&self, msg: BrokerMessage) -> Result<(), Self::DbError> {
+    return self.insert_messages(vec![msg]).map_err(|e| e.error);
+  }
+
+  /// Runs as a single MULTI/EXEC. Redis fails the whole transaction at
+  /// once, so errors from it are blamed on the first message.
+  fn insert_messages(&self, msgs: Vec<BrokerMessage>)
+  -> Result<(), BatchInsertError<Self::DbError>> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for (i, msg) in msgs.iter().enumerate() {
+      let json = serde_json::to_string(msg)
+        .map_err(|e| BatchInsertError { index: i, error: e.into() })?;
+      if let Some(id) = msg.id {
+        pipe.sadd(key("ids"), id.to_string()).ignore();
+      }
+      let score = score(&msg.constructed_when);
+      match &msg.payload {
+        BrokerMessagePayload::SensorData(sd) => {
+          let sensor = sensor_field(sd.sensor_type(), sd.sensor_id());
+          pipe
+            .zadd(sensor_key(sd.sensor_type()), &json, score)
+            .ignore()
+            .sadd(key("sensors"), &sensor)
+            .ignore()
+            .zadd(latest_key(&sensor), &json, score)
+            .ignore()
+            .zremrangebyrank(latest_key(&sensor), 0, -2)
+            .ignore();
+        },
+        _ => {
+          pipe
+            .zadd(messages_key(msg.payload_type()), &json, score)
+            .ignore();
+        },
+      };
+    }
+    return self.write(&pipe)
+      .map_err(|e| BatchInsertError { index: 0, error: e });
+  }
+
+  /// Queues the writes made through the handle, and sends them all as a
+  /// single MULTI/EXEC.
+  fn with_txn<T, E, F>(&self, f: F) -> Result<T, E>
+  where F: FnOnce(&Self) -> Result<T, E>, E: From<Self::DbError> {
+    if self.txn.is_some() {
+      return f(self);
+    }
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    let queued = Arc::new(Mutex::new(Queued(pipe)));
+    let handle = Self { txn: Some(queued.clone()), ..self.clone() };
+    let out = f(&handle)?;
+    let queued = queued.lock().unwrap_or_else(|e| e.into_inner());
+    let _: () = queued.0.query(&mut *self.con()?).map_err(Self::DbError::from)?;
+    return Ok(out);
+  }
+
+  /// One round trip, however many IDs.
+  fn known_ids(&self, ids: &[MessageId])
+  -> Result<HashSet<MessageId>, Self::DbError> {
+    if ids.is_empty() {
+      return Ok(HashSet::new());
+    }
+    let mut pipe = redis::pipe();
+    for id in ids {
+      pipe.sismember(key("ids"), id.to_string());
+    }
+    let mut con = self.con()?;
+    let found: Vec<bool> = pipe.query(&mut *con)?;
+    return Ok(ids
+      .iter()
+      .zip(found)
+      .filter(|(_, known)| *known)
+      .map(|(id, _)| *id)
+      .collect()
+    );
+  }
+
+  /// A SADD of each ID, in a single MULTI/EXEC. Those that didn't add
+  /// anything were taken.
+  fn claim_ids(&self, ids: &[MessageId])
+  -> Result<HashSet<MessageId>, Self::DbError> {
+    if ids.is_empty() {
+      return Ok(HashSet::new());
+    }
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for id in ids {
+      pipe.sadd(key("ids"), id.to_string());
+    }
+    let mut con = self.con()?;
+    let added: Vec<u64> = pipe.query(&mut *con)?;
+    return Ok(ids
+      .iter()
+      .zip(added)
+      .filter(|(_, added)| *added == 0)
+      .map(|(id, _)| *id)
+      .collect()
+    );
+  }
+
+  fn release_ids(&self, ids: &[MessageId]) -> Result<(), Self::DbError> {
+    if ids.is_empty() {
+      return Ok(());
+    }
+    let names: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+    let _: () = self.con()?.srem(key("ids"), names)?;
+    return Ok(());
+  }
+
+  /// Reads straight from the per-sensor sorted sets, no scanning.
+  fn latest_per_sensor(&self) -> Result<Vec<BrokerMessage>, Self::DbError> {
+    let mut con = self.con()?;
+    let sensors: Vec<String> = con.smembers(key("sensors"))?;
+    let mut latest = Vec::with_capacity(sensors.len());
+    for sensor in sensors.iter() {
+      let newest: Vec<String> = con.zrange(latest_key(sensor), -1, -1)?;
+      for json in newest.iter() {
+        latest.push(serde_json::from_str(json)?);
+      }
+    }
+    return Ok(latest);
+  }
+
+  fn calibrations(&self) -> Result<Vec<SensorCalibration>, Self::DbError> {
+    let cals: Vec<(String, Calibration)>
+      = self.hash_values(&key("calibrations"))?;
+    return Ok(cals
+      .into_iter()
+      .filter_map(|(field, cal)| {
+        let (stype, sensor_id) = parse_sensor_field(&field)?;
+        return Some(SensorCalibration {
+          sensor_type: stype,
+          sensor_id: sensor_id,
+          calibration: cal
+        });
+      })
+      .collect()
+    );
+  }
+
+  fn calibration(&self, stype: SensorType, sensor_id: SensorId)
+  -> Result<Option<Calibration>, Self::DbError> {
+    let json: Option<String> = self.con()?
+      .hget(key("calibrations"), sensor_field(stype, sensor_id))?;
+    return match json {
+      Some(j) => Ok(Some(serde_json::from_str(&j)?)),
+      None => Ok(None),
+    };
+  }
+
+  fn set_calibration(
+    &self, stype: SensorType, sensor_id: SensorId, cal: Option<Calibration>
+  ) -> Result<(), Self::DbError> {
+    let field = sensor_field(stype, sensor_id);
+    let mut pipe = redis::pipe();
+    match cal {
+      Some(c) => {
+        let json = serde_json::to_string(&c)?;
+        pipe.hset(key("calibrations"), field, json).ignore();
+      },
+      None => {
+        pipe.hdel(key("calibrations"), field).ignore();
+      },
+    };
+    return self.write(&pipe);
+  }
+
+  fn registered_sensors(&self)
+  -> Result<Vec<RegisteredSensor>, Self::DbError> {
+    let infos: Vec<(String, SensorInfo)>
+      = self.hash_values(&key("sensor_info"))?;
+    return Ok(infos
+      .into_iter()
+      .filter_map(|(field, info)| {
+        let (stype, sensor_id) = parse_sensor_field(&field)?;
+        return Some(RegisteredSensor {
+          sensor_type: stype,
+          sensor_id: sensor_id,
+          info: info
+        });
+      })
+      .collect()
+    );
+  }
+
+  fn sensor_info(&self, stype: SensorType, sensor_id: SensorId)
+  -> Result<Option<SensorInfo>, Self::DbError> {
+    let json: Option<String> = self.con()?
+      .hget(key("sensor_info"), sensor_field(stype, sensor_id))?;
+    return match json {
+      Some(j) => Ok(Some(serde_json::from_str(&j)?)),
+      None => Ok(None),
+    };
+  }
+
+  fn set_sensor_info(
+    &self, stype: SensorType, sensor_id: SensorId, info: Option<SensorInfo>
+  ) -> Result<(), Self::DbError> {
+    let field = sensor_field(stype, sensor_id);
+    let mut pipe = redis::pipe();
+    match info {
+      Some(i) => {
+        let json = serde_json::to_string(&i)?;
+        pipe.hset(key("sensor_info"), field, json).ignore();
+      },
+      None => {
+        pipe.hdel(key("sensor_info"), field).ignore();
+      },
+    };
+    return self.write(&pipe);
+  }
+
+  fn floorplan(&self) -> Result<Option<Floorplan>, Self::DbError> {
+    let json: Option<String> = self.con()?.get(key("floorplan"))?;
+    return match json {
+      Some(j) => Ok(Some(serde_json::from_str(&j)?)),
+      None => Ok(None),
+    };
+  }
+
+  fn set_floorplan(&self, plan: Option<Floorplan>)
+  -> Result<(), Self::DbError> {
+    let mut pipe = redis::pipe();
+    match plan {
+      Some(p) => {
+        pipe.set(key("floorplan"), serde_json::to_string(&p)?).ignore();
+      },
+      None => {
+        pipe.del(key("floorplan")).ignore();
+      },
+    };
+    return self.write(&pipe);
+  }
+
+  fn insert_derived(&self, reading: DerivedReading)
+  -> Result<(), Self::DbError> {
+    let json = serde_json::to_string(&reading)?;
+    let mut pipe = redis::pipe();
+    pipe
+      .xadd(derived_key(&reading.name), "*", &[(JSON_FIELD, &json)])
+      .ignore();
+    return self.write(&pipe);
+  }
+
+  fn derived_readings(&self, name: &str)
+  -> Result<Vec<DerivedReading>, Self::DbError> {
+    return Ok(StreamIter::new(
+      self.pool.clone(),
+      vec![derived_key(name)],
+      |json| serde_json::from_str(json).ok()
+    ).collect());
+  }
+
+  fn brokers(&self) -> Result<Vec<BrokerRecord>, Self::DbError> {
+    let mut brokers: Vec<BrokerRecord> = self
+      .hash_values(&key("brokers"))?
+      .into_iter()
+      .map(|(_, rec)| rec)
+      .collect();
+    brokers.sort_by_key(|b| b.uid);
+    return Ok(brokers);
+  }
+
+  fn broker(&self, uid: BrokerId)
+  -> Result<Option<BrokerRecord>, Self::DbError> {
+    let json: Option<String> = self.con()?
+      .hget(key("brokers"), uid.to_string())?;
+    return match json {
+      Some(j) => Ok(Some(serde_json::from_str(&j)?)),
+      None => Ok(None),
+    };
+  }
+
+  fn update_broker(&self, rec: BrokerRecord) -> Result<(), Self::DbError> {
+    let json = serde_json::to_string(&rec)?;
+    let mut pipe = redis::pipe();
+    pipe.hset(key("brokers"), rec.uid.to_string(), json).ignore();
+    return self.write(&pipe);
+  }
+
+  fn insert_audit(&self, entry: AuditEntry) -> Result<(), Self::DbError> {
+    let json = serde_json::to_string(&entry)?;
+    let mut pipe = redis::pipe();
+    pipe.xadd(key("audit"), "*", &[(JSON_FIELD, &json)]).ignore();
+    return self.write(&pipe);
+  }
+
+  fn audit_log(&self) -> Result<Vec<AuditEntry>, Self::DbError> {
+    return Ok(StreamIter::new(
+      self.pool.clone(),
+      vec![key("audit")],
+      |json| serde_json::from_str(json).ok()
+    ).collect());
+  }
+
+  fn insert_report(&self, report: Report) -> Result<(), Self::DbError> {
+    let json = serde_json::to_string(&report)?;
+    let mut pipe = redis::pipe();
+    pipe.xadd(key("reports"), "*", &[(JSON_FIELD, &json)]).ignore();
+    return self.write(&pipe);
+  }
+
+  fn reports(&self) -> Result<Vec<Report>, Self::DbError> {
+    return Ok(StreamIter::new(
+      self.pool.clone(),
+      vec![key("reports")],
+      |json| serde_json::from_str(json).ok()
+    ).collect());
+  }
+
+  fn announce(&self, news: &News) -> Result<(), Self::DbError> {
+    let notice = Notice {
+      origin: self.origin,
+      news: news.clone()
+    };
+    let json = serde_json::to_string(&notice)?;
+    let _: () = self.con()?.publish(key("ingested"), json)?;
+    return Ok(());
+  }
+
+  fn listen<F>(&self, jobs: &Jobs, on_news: F) -> Result<(), Self::DbError>
+  where F: Fn(News) + Send + 'static {
+    let db = self.clone();
+    jobs.spawn("listener", Restart::Always, move || {
+      loop {
+        if let Err(e) = db.listen_once(&on_news) {
+          warn!("Lost touch with other instances: {}", e);
+        }
+        thread::sleep(RESUBSCRIBE_DELAY);
+      }
+    });
+    return Ok(());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::TimeZone;
+
+  use super::*;
+
+  #[test]
+  fn stream_ids_page_forward() {
+    assert_eq!(next_stream_id("1600000000000-0"), "1600000000000-1");
+    assert_eq!(next_stream_id("1600000000000-41"), "1600000000000-42");
+    assert_eq!(next_stream_id("0"), "0-1");
+  }
+
+  #[test]
+  fn sensor_fields_round_trip() {
+    let field = sensor_field(SensorType::Temperature, SensorId(7));
+    assert_eq!(
+      parse_sensor_field(&field), Some((SensorType::Temperature, SensorId(7)))
+    );
+    assert_eq!(parse_sensor_field("temperature"), None);
+    assert_eq!(parse_sensor_field("nonsense:7"), None);
+  }
+
+  #[test]
+  fn scores_keep_the_order_of_times() {
+    let secs = [-4_000_000_000, -1, 0, 1, 1_600_000_000, 4_000_000_000];
+    let scores: Vec<f64> = secs
+      .iter()
+      .map(|s| score(&Local.timestamp(*s, 0)))
+      .collect();
+    assert!(scores.windows(2).all(|w| w[0] < w[1]));
+  }
+
+  #[test]
+  fn sensor_data_spans_a_set_per_sensor_type() {
+    let keys = message_keys(BrokerMessagePayloadType::SensorData);
+    assert_eq!(keys.len(), SensorType::all_types().len());
+    assert!(keys.contains(&sensor_key(SensorType::Temperature)));
+  }
+}
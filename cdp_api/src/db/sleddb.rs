@@ -0,0 +1,773 @@
+//! Implements a database on top of sled, an embedded key-value store, so the
+//! API can keep its data on disk without any external service.
+//!
+//! Values are JSON. Keys of time series are "{name}/" followed by the
+//! big-endian construction time and a sequence number, so they sort by time
+//! and time ranges are plain key ranges. Trees:
+//! - `topics`: sensor type names, no values.
+//! - `sensor`: sensor data messages, named by sensor type.
+//! - `messages`: every other message, named by payload type.
+//! - `latest`: "{type}:{id}" to the newest message of each sensor.
+//! - `ids`: the ID of every stored message, or one being stored, no
+//!   values.
+//! - `calibrations`: "{type}:{id}" to calibration.
+//! - `sensor_info`: "{type}:{id}" to the sensor's name and room.
+//! - `derived`: computed values, named by virtual sensor.
+//! - `brokers`: broker uid to broker record.
+//! - `audit`: audit log entries, named "audit".
+//! - `reports`: summary reports, named "report", by when they were made.
+//!
+//! The floorplan, being just one value, lives in the default tree under
+//! "floorplan", and so does the last sequence number handed out, under
+//! "seq", as a big-endian u64.
+//!
+//! Within with_txn, writes are kept aside, and made at the end in a single
+//! sled transaction over every tree.
+
+use std::collections::HashSet;
+use std::error::Error as StdError;
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use sled::{Db, IVec, Tree};
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionError, Transactional, TransactionalTree};
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload, BrokerMessagePayloadType};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, MessageId, SensorId};
+
+use crate::audit::AuditEntry;
+use crate::brokers::BrokerRecord;
+use crate::calibration::{Calibration, SensorCalibration};
+use crate::db::{self, ApiDatabase, ApiDatabaseType, BatchInsertError, MessageFilter};
+use crate::derived::DerivedReading;
+use crate::floorplan::Floorplan;
+use crate::reports::Report;
+use crate::sensors::{RegisteredSensor, SensorInfo};
+
+/// Key of the floorplan, in the default tree.
+const FLOORPLAN_KEY: &[u8] = b"floorplan";
+
+/// Key of the last sequence number handed out, in the default tree.
+const SEQ_KEY: &[u8] = b"seq";
+
+/// Turns a timestamp into bytes that sort the same way it does.
+fn time_bytes(when: &DateTime<Local>) -> [u8; 8] {
+  let nanos = db::time_nanos(when) as u64 ^ (1 << 63);
+  return nanos.to_be_bytes();
+}
+
+/// Prefix shared by every key of a time series.
+fn series_prefix(name: &str) -> Vec<u8> {
+  let mut key = name.as_bytes().to_vec();
+  key.push(b'/');
+  return key;
+}
+
+/// Key of an item in a time series.
+fn series_key(name: &str, when: [u8; 8], seq: u64) -> Vec<u8> {
+  let mut key = series_prefix(name);
+  key.extend_from_slice(&when);
+  key.extend_from_slice(&seq.to_be_bytes());
+  return key;
+}
+
+/// Reads a sequence number, as stored. Nothing, or garbage, reads as 0.
+fn seq_value(bytes: Option<&[u8]>) -> u64 {
+  let mut buf = [0; 8];
+  if let Some(b) = bytes.filter(|b| b.len() == 8) {
+    buf.copy_from_slice(b);
+  }
+  return u64::from_be_bytes(buf);
+}
+
+/// How a sensor is named within keys.
+fn sensor_field(stype: SensorType, sensor_id: SensorId) -> String {
+  return format!("{}:{}", stype, sensor_id);
+}
+
+/// Undoes sensor_field.
+fn parse_sensor_field(field: &str) -> Option<(SensorType, SensorId)> {
+  let mut parts = field.splitn(2, ':');
+  let stype = SensorType::from_str(parts.next()?).ok()?;
+  let sensor_id = parts.next()?.parse().ok()?;
+  return Some((stype, sensor_id));
+}
+
+/// Decodes a JSON value, if it's sane.
+fn decode<T>(value: sled::Result<(sled::IVec, sled::IVec)>) -> Option<T>
+where T: for<'de> Deserialize<'de> {
+  return serde_json::from_slice(&value.ok()?.1).ok();
+}
+
+/// An error that the sled database can return.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum SledDatabaseError {
+  /// sled itself had a problem, most likely I/O.
+  Sled(String),
+  /// Something in there isn't what we stored.
+  Format(String)
+}
+
+impl StdError for SledDatabaseError {}
+
+impl Display for SledDatabaseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      SledDatabaseError::Sled(e) => {
+        return write!(f, "sled error: {}", e);
+      },
+      SledDatabaseError::Format(e) => {
+        return write!(f, "Bad data in sled: {}", e);
+      },
+    }
+  }
+}
+
+impl From<sled::Error> for SledDatabaseError {
+  fn from(e: sled::Error) -> Self {
+    return SledDatabaseError::Sled(e.to_string());
+  }
+}
+
+impl From<serde_json::Error> for SledDatabaseError {
+  fn from(e: serde_json::Error) -> Self {
+    return SledDatabaseError::Format(e.to_string());
+  }
+}
+
+/// Stores encoded messages, keeping the latest and ids trees up to date,
+/// within a transaction over the trees involved. Aborts with the index of
+/// the message at fault.
+fn store_messages(
+  (sensor, messages, latest, ids): (
+    &TransactionalTree, &TransactionalTree, &TransactionalTree,
+    &TransactionalTree
+  ),
+  encoded: &[(Vec<u8>, BrokerMessage)]
+) -> ConflictableTransactionResult<(), (usize, SledDatabaseError)> {
+  for (i, (json, msg)) in encoded.iter().enumerate() {
+    if let Some(id) = msg.id {
+      ids.insert(&id.as_bytes()[..], &[][..])?;
+    }
+    let when = time_bytes(&msg.constructed_when);
+    let seq = sensor.generate_id()?;
+    let sd = match &msg.payload {
+      BrokerMessagePayload::SensorData(sd) => sd,
+      _ => {
+        let name = msg.payload_type().to_string();
+        messages.insert(series_key(&name, when, seq), json.as_slice())?;
+        continue;
+      },
+    };
+    let name = sd.sensor_type().to_string();
+    sensor.insert(series_key(&name, when, seq), json.as_slice())?;
+    let field = sensor_field(sd.sensor_type(), sd.sensor_id());
+    let newer = match latest.get(field.as_bytes())? {
+      Some(prev) => {
+        let prev: BrokerMessage = serde_json::from_slice(&prev)
+          .map_err(|e| ConflictableTransactionError::Abort(
+            (i, SledDatabaseError::from(e))
+          ))?;
+        prev.constructed_when <= msg.constructed_when
+      },
+      None => true,
+    };
+    if newer {
+      latest.insert(field.as_bytes(), json.as_slice())?;
+    }
+  }
+  return Ok(());
+}
+
+/// A write kept aside until its transaction commits.
+#[derive(Debug)]
+enum Write {
+  /// Sets a key of the tree so named, or removes it if there's no value.
+  Set(IVec, Vec<u8>, Option<Vec<u8>>),
+  /// Stores encoded messages, as insert_messages does.
+  Messages(Vec<(Vec<u8>, BrokerMessage)>)
+}
+
+/// A database living in a sled directory. Cheap to clone, all clones share
+/// the same trees.
+#[derive(Debug, Clone)]
+pub(crate) struct SledApiDatabase {
+  /// Writes kept aside, when this is a handle given out by with_txn.
+  txn: Option<Arc<Mutex<Vec<Write>>>>,
+  db: Db,
+  topics: Tree,
+  sensor: Tree,
+  messages: Tree,
+  latest: Tree,
+  ids: Tree,
+  calibrations: Tree,
+  sensor_info: Tree,
+  derived: Tree,
+  brokers: Tree,
+  audit: Tree,
+  reports: Tree
+}
+
+impl SledApiDatabase {
+  /// Opens (or creates) the database at the given directory.
+  pub(crate) fn open(path: &Path) -> Result<Self, SledDatabaseError> {
+    let db = sled::open(path)?;
+    return Ok(Self {
+      topics: db.open_tree("topics")?,
+      sensor: db.open_tree("sensor")?,
+      messages: db.open_tree("messages")?,
+      latest: db.open_tree("latest")?,
+      ids: db.open_tree("ids")?,
+      calibrations: db.open_tree("calibrations")?,
+      sensor_info: db.open_tree("sensor_info")?,
+      derived: db.open_tree("derived")?,
+      brokers: db.open_tree("brokers")?,
+      audit: db.open_tree("audit")?,
+      reports: db.open_tree("reports")?,
+      txn: None,
+      db: db
+    });
+  }
+
+  /// Every tree, the default one first.
+  fn trees(&self) -> [&Tree; 12] {
+    return [
+      &self.db, &self.topics, &self.sensor, &self.messages, &self.latest,
+      &self.ids, &self.calibrations, &self.sensor_info, &self.derived,
+      &self.brokers, &self.audit, &self.reports
+    ];
+  }
+
+  /// Sets a key of a tree, or removes it if there's no value, right away or
+  /// once the transaction commits.
+  fn set(&self, tree: &Tree, key: Vec<u8>, value: Option<Vec<u8>>)
+  -> Result<(), SledDatabaseError> {
+    if let Some(txn) = &self.txn {
+      txn.lock().unwrap_or_else(|e| e.into_inner())
+        .push(Write::Set(tree.name(), key, value));
+      return Ok(());
+    }
+    match value {
+      Some(v) => tree.insert(key, v)?,
+      None => tree.remove(key)?,
+    };
+    return Ok(());
+  }
+
+  /// Makes every write kept aside, in a single transaction.
+  fn commit(&self, writes: &[Write]) -> Result<(), SledDatabaseError> {
+    let trees = self.trees();
+    let names: Vec<IVec> = trees.iter().map(|t| t.name()).collect();
+    let res: Result<(), TransactionError<(usize, SledDatabaseError)>>
+      = trees[..].transaction(|views| {
+        for w in writes.iter() {
+          match w {
+            Write::Set(name, key, value) => {
+              let i = names.iter().position(|n| n == name)
+                .expect("A write to a tree we don't have!");
+              match value {
+                Some(v) => views[i].insert(key.as_slice(), v.as_slice())?,
+                None => views[i].remove(key.as_slice())?,
+              };
+            },
+            Write::Messages(encoded) => {
+              let (sensor, messages) = (&views[2], &views[3]);
+              let (latest, ids) = (&views[4], &views[5]);
+              store_messages((sensor, messages, latest, ids), encoded)?;
+            },
+          };
+        }
+        return Ok(());
+      });
+    return match res {
+      Ok(_) => Ok(()),
+      Err(TransactionError::Abort((_, e))) => Err(e),
+      Err(TransactionError::Storage(e)) => Err(e.into()),
+    };
+  }
+
+  /// Fills the ids tree from every stored message, for databases made
+  /// before there was one.
+  fn index_ids(&self) -> Result<usize, SledDatabaseError> {
+    let mut n = 0;
+    for tree in [&self.sensor, &self.messages].iter() {
+      for msg in tree.iter().filter_map(decode::<BrokerMessage>) {
+        if let Some(id) = msg.id {
+          self.ids.insert(id.as_bytes(), &[])?;
+          n += 1;
+        }
+      }
+    }
+    return Ok(n);
+  }
+
+  /// Makes sure everything written so far is on disk.
+  pub(crate) fn flush(&self) -> Result<(), SledDatabaseError> {
+    self.db.flush()?;
+    return Ok(());
+  }
+}
+
+impl ApiDatabase for SledApiDatabase {
+  type DbError = SledDatabaseError;
+  type BrokerMessageIter = Box<dyn Iterator<Item=BrokerMessage> + Send>;
+  /// The directory sled lives in.
+  type DbConfig = std::path::PathBuf;
+
+  fn db_type(&self) -> ApiDatabaseType {
+    return ApiDatabaseType::Sled;
+  }
+
+  fn init(&self, cfg: Self::DbConfig) -> Result<Self, Self::DbError> {
+    return Self::open(&cfg);
+  }
+
+  /// Seeds the topics with every sensor type on a brand-new database, and
+  /// indexes message IDs on one from before they were.
+  fn setup(&self) {
+    let unindexed = self.ids.is_empty()
+      && !(self.sensor.is_empty() && self.messages.is_empty());
+    if unindexed {
+      match self.index_ids() {
+        Ok(n) => info!("Indexed the IDs of {} stored messages.", n),
+        Err(e) => warn!("Failed to index message IDs: {}", e),
+      }
+    }
+    if !self.topics.is_empty() {
+      return;
+    }
+    if let Err(e) = self.update_topics(SensorType::all_types()) {
+      warn!("Failed to set up sled: {}", e);
+    }
+  }
+
+  fn topics(&self) -> Result<HashSet<SensorType>, Self::DbError> {
+    let mut topics = HashSet::new();
+    for k in self.topics.iter().keys() {
+      if let Ok(st) = SensorType::from_str(&String::from_utf8_lossy(&k?)) {
+        topics.insert(st);
+      }
+    }
+    return Ok(topics);
+  }
+
+  fn update_topics<T>(&self, new_topics: T) -> Result<(), Self::DbError>
+  where T: IntoIterator<Item=SensorType> {
+    let names: Vec<String> = new_topics
+      .into_iter()
+      .map(|st| st.to_string())
+      .collect();
+    let old: Vec<sled::IVec> = self.topics
+      .iter()
+      .keys()
+      .collect::<Result<_, _>>()?;
+    return self.with_txn(|db| {
+      for k in old.into_iter() {
+        db.set(&db.topics, k.to_vec(), None)?;
+      }
+      for n in names.into_iter() {
+        db.set(&db.topics, n.into_bytes(), Some(Vec::new()))?;
+      }
+      return Ok(());
+    });
+  }
+
+  /// Sensor data of a sensor type is a plain key range when the time range
+  /// goes by construction time, thanks to the key layout. Anything else is
+  /// a prefix of a tree, or the whole of both.
+  fn query(&self, filter: MessageFilter)
+  -> Result<Self::BrokerMessageIter, Self::DbError> {
+    let candidates: Box<dyn Iterator<Item=_> + Send>
+      = match (filter.payload_type(), filter.sensor_type) {
+        (Some(BrokerMessagePayloadType::SensorData), Some(stype))
+        if filter.by_construction() => {
+          let name = stype.to_string();
+          let from = filter.from.as_ref().map(time_bytes).unwrap_or([0; 8]);
+          let to = filter.to.as_ref().map(time_bytes).unwrap_or([!0; 8]);
+          let start = series_key(&name, from, 0);
+          let end = series_key(&name, to, u64::MAX);
+          Box::new(self.sensor.range(start..=end))
+        },
+        (Some(BrokerMessagePayloadType::SensorData), Some(stype)) => {
+          Box::new(self.sensor.scan_prefix(series_prefix(&stype.to_string())))
+        },
+        (Some(BrokerMessagePayloadType::SensorData), None) => {
+          Box::new(self.sensor.iter())
+        },
+        (Some(other), _) => {
+          Box::new(self.messages.scan_prefix(series_prefix(&other.to_string())))
+        },
+        (None, _) => Box::new(self.sensor.iter().chain(self.messages.iter())),
+      };
+    let wanted = filter.clone();
+    let matched = candidates
+      .filter_map(decode)
+      .filter(move |m| wanted.matches(m));
+    return Ok(filter.finish(matched));
+  }
+
+  fn next_seq(&self, count: u64) -> Result<u64, Self::DbError> {
+    let last = self.db.update_and_fetch(SEQ_KEY, |old| {
+      return Some((seq_value(old) + count).to_be_bytes().to_vec());
+    })?;
+    return Ok(seq_value(last.as_deref()) - count + 1);
+  }
+
+  fn insert_message(&self, msg: BrokerMessage) -> Result<(), Self::DbError> {
+    return self.insert_messages(vec![msg]).map_err(|e| e.error);
+  }
+
+  /// A single sled transaction over every tree involved.
+  fn insert_messages(&self, msgs: Vec<BrokerMessage>)
+  -> Result<(), BatchInsertError<Self::DbError>> {
+    let mut encoded: Vec<(Vec<u8>, BrokerMessage)> = Vec::new();
+    for (i, msg) in msgs.into_iter().enumerate() {
+      let json = serde_json::to_vec(&msg)
+        .map_err(|e| BatchInsertError { index: i, error: e.into() })?;
+      encoded.push((json, msg));
+    }
+    if let Some(txn) = &self.txn {
+      txn.lock().unwrap_or_else(|e| e.into_inner())
+        .push(Write::Messages(encoded));
+      return Ok(());
+    }
+    let trees = (&self.sensor, &self.messages, &self.latest, &self.ids);
+    let res: Result<(), TransactionError<(usize, SledDatabaseError)>>
+      = trees.transaction(|(sensor, messages, latest, ids)| {
+        return store_messages((sensor, messages, latest, ids), &encoded);
+      });
+    return match res {
+      Ok(_) => Ok(()),
+      Err(TransactionError::Abort((i, e))) => {
+        Err(BatchInsertError { index: i, error: e })
+      },
+      Err(TransactionError::Storage(e)) => {
+        Err(BatchInsertError { index: 0, error: e.into() })
+      },
+    };
+  }
+
+  /// Writes made through the handle are kept aside, then made in a single
+  /// transaction over every tree.
+  fn with_txn<T, E, F>(&self, f: F) -> Result<T, E>
+  where F: FnOnce(&Self) -> Result<T, E>, E: From<Self::DbError> {
+    if self.txn.is_some() {
+      return f(self);
+    }
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let handle = Self { txn: Some(writes.clone()), ..self.clone() };
+    let out = f(&handle)?;
+    let writes = writes.lock().unwrap_or_else(|e| e.into_inner());
+    self.commit(&writes)?;
+    return Ok(out);
+  }
+
+  fn known_ids(&self, ids: &[MessageId])
+  -> Result<HashSet<MessageId>, Self::DbError> {
+    let mut known = HashSet::new();
+    for id in ids {
+      if self.ids.contains_key(id.as_bytes())? {
+        known.insert(*id);
+      }
+    }
+    return Ok(known);
+  }
+
+  /// A compare-and-swap on the ids tree for each ID, from nothing to there.
+  fn claim_ids(&self, ids: &[MessageId])
+  -> Result<HashSet<MessageId>, Self::DbError> {
+    let mut taken = HashSet::new();
+    for id in ids {
+      let swapped = self.ids
+        .compare_and_swap(id.as_bytes(), None as Option<&[u8]>, Some(&[][..]))?;
+      if swapped.is_err() {
+        taken.insert(*id);
+      }
+    }
+    return Ok(taken);
+  }
+
+  fn release_ids(&self, ids: &[MessageId]) -> Result<(), Self::DbError> {
+    for id in ids {
+      self.ids.remove(id.as_bytes())?;
+    }
+    return Ok(());
+  }
+
+  /// Reads straight from the latest tree, no scanning.
+  fn latest_per_sensor(&self) -> Result<Vec<BrokerMessage>, Self::DbError> {
+    let mut latest = Vec::new();
+    for v in self.latest.iter().values() {
+      latest.push(serde_json::from_slice(&v?)?);
+    }
+    return Ok(latest);
+  }
+
+  fn calibrations(&self) -> Result<Vec<SensorCalibration>, Self::DbError> {
+    let mut cals = Vec::new();
+    for kv in self.calibrations.iter() {
+      let (k, v) = kv?;
+      let field = String::from_utf8_lossy(&k);
+      if let Some((stype, sensor_id)) = parse_sensor_field(&field) {
+        cals.push(SensorCalibration {
+          sensor_type: stype,
+          sensor_id: sensor_id,
+          calibration: serde_json::from_slice(&v)?
+        });
+      }
+    }
+    return Ok(cals);
+  }
+
+  fn calibration(&self, stype: SensorType, sensor_id: SensorId)
+  -> Result<Option<Calibration>, Self::DbError> {
+    let field = sensor_field(stype, sensor_id);
+    return match self.calibrations.get(field.as_bytes())? {
+      Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+      None => Ok(None),
+    };
+  }
+
+  fn set_calibration(
+    &self, stype: SensorType, sensor_id: SensorId, cal: Option<Calibration>
+  ) -> Result<(), Self::DbError> {
+    let field = sensor_field(stype, sensor_id);
+    return match cal {
+      Some(c) => {
+        let value = serde_json::to_vec(&c)?;
+        self.set(&self.calibrations, field.into_bytes(), Some(value))
+      },
+      None => self.set(&self.calibrations, field.into_bytes(), None),
+    };
+  }
+
+  fn registered_sensors(&self)
+  -> Result<Vec<RegisteredSensor>, Self::DbError> {
+    let mut sensors = Vec::new();
+    for kv in self.sensor_info.iter() {
+      let (k, v) = kv?;
+      let field = String::from_utf8_lossy(&k);
+      if let Some((stype, sensor_id)) = parse_sensor_field(&field) {
+        sensors.push(RegisteredSensor {
+          sensor_type: stype,
+          sensor_id: sensor_id,
+          info: serde_json::from_slice(&v)?
+        });
+      }
+    }
+    return Ok(sensors);
+  }
+
+  fn sensor_info(&self, stype: SensorType, sensor_id: SensorId)
+  -> Result<Option<SensorInfo>, Self::DbError> {
+    let field = sensor_field(stype, sensor_id);
+    return match self.sensor_info.get(field.as_bytes())? {
+      Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+      None => Ok(None),
+    };
+  }
+
+  fn set_sensor_info(
+    &self, stype: SensorType, sensor_id: SensorId, info: Option<SensorInfo>
+  ) -> Result<(), Self::DbError> {
+    let field = sensor_field(stype, sensor_id);
+    return match info {
+      Some(i) => {
+        let value = serde_json::to_vec(&i)?;
+        self.set(&self.sensor_info, field.into_bytes(), Some(value))
+      },
+      None => self.set(&self.sensor_info, field.into_bytes(), None),
+    };
+  }
+
+  fn floorplan(&self) -> Result<Option<Floorplan>, Self::DbError> {
+    return match self.db.get(FLOORPLAN_KEY)? {
+      Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+      None => Ok(None),
+    };
+  }
+
+  fn set_floorplan(&self, plan: Option<Floorplan>)
+  -> Result<(), Self::DbError> {
+    return match plan {
+      Some(p) => {
+        let value = serde_json::to_vec(&p)?;
+        self.set(&self.db, FLOORPLAN_KEY.to_vec(), Some(value))
+      },
+      None => self.set(&self.db, FLOORPLAN_KEY.to_vec(), None),
+    };
+  }
+
+  fn insert_derived(&self, reading: DerivedReading)
+  -> Result<(), Self::DbError> {
+    let key = series_key(
+      &reading.name, time_bytes(&reading.computed_when), self.db.generate_id()?
+    );
+    return self.set(&self.derived, key, Some(serde_json::to_vec(&reading)?));
+  }
+
+  fn derived_readings(&self, name: &str)
+  -> Result<Vec<DerivedReading>, Self::DbError> {
+    return Ok(self.derived
+      .scan_prefix(series_prefix(name))
+      .filter_map(decode)
+      .collect()
+    );
+  }
+
+  fn brokers(&self) -> Result<Vec<BrokerRecord>, Self::DbError> {
+    // keys are uid bytes, so they come out sorted already.
+    let mut brokers = Vec::new();
+    for v in self.brokers.iter().values() {
+      brokers.push(serde_json::from_slice(&v?)?);
+    }
+    return Ok(brokers);
+  }
+
+  fn broker(&self, uid: BrokerId)
+  -> Result<Option<BrokerRecord>, Self::DbError> {
+    return match self.brokers.get(uid.as_bytes())? {
+      Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+      None => Ok(None),
+    };
+  }
+
+  fn update_broker(&self, rec: BrokerRecord) -> Result<(), Self::DbError> {
+    let key = rec.uid.as_bytes().to_vec();
+    return self.set(&self.brokers, key, Some(serde_json::to_vec(&rec)?));
+  }
+
+  fn insert_audit(&self, entry: AuditEntry) -> Result<(), Self::DbError> {
+    let key = series_key(
+      "audit", time_bytes(&entry.when), self.db.generate_id()?
+    );
+    return self.set(&self.audit, key, Some(serde_json::to_vec(&entry)?));
+  }
+
+  fn audit_log(&self) -> Result<Vec<AuditEntry>, Self::DbError> {
+    let mut entries = Vec::new();
+    for v in self.audit.scan_prefix(series_prefix("audit")).values() {
+      entries.push(serde_json::from_slice(&v?)?);
+    }
+    return Ok(entries);
+  }
+
+  fn insert_report(&self, report: Report) -> Result<(), Self::DbError> {
+    let key = series_key(
+      "report", time_bytes(&report.generated_when), self.db.generate_id()?
+    );
+    return self.set(&self.reports, key, Some(serde_json::to_vec(&report)?));
+  }
+
+  fn reports(&self) -> Result<Vec<Report>, Self::DbError> {
+    let mut reports = Vec::new();
+    for v in self.reports.scan_prefix(series_prefix("report")).values() {
+      reports.push(serde_json::from_slice(&v?)?);
+    }
+    return Ok(reports);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+  use std::path::PathBuf;
+
+  use chrono::TimeZone;
+  use uuid::Uuid;
+
+  use libcdp::comm::sensor_broker::{AnySensorMessage, HumidityMessage, TemperatureMessage};
+
+  use super::*;
+
+  /// A database in a directory of its own, removed when dropped.
+  struct Scratch(SledApiDatabase, PathBuf);
+
+  impl Scratch {
+    /// Opens a database in a fresh directory.
+    fn new() -> Self {
+      let dir = std::env::temp_dir()
+        .join(format!("cdp_sled_test_{}", Uuid::new_v4()));
+      return Self(SledApiDatabase::open(&dir).unwrap(), dir);
+    }
+  }
+
+  impl Drop for Scratch {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.1);
+    }
+  }
+
+  /// A reading made at some second since the epoch.
+  fn reading(stype: SensorType, secs: i64) -> BrokerMessage {
+    let sd = match stype {
+      SensorType::Temperature => AnySensorMessage::Temperature(
+        TemperatureMessage { sensor_id: 1, kelvin: 294 }
+      ),
+      SensorType::Humidity => AnySensorMessage::Humidity(
+        HumidityMessage { sensor_id: 1, humidity: 21 }
+      ),
+    };
+    let mut msg = BrokerMessage::construct(
+      BrokerId::new_v4(), BrokerMessagePayload::SensorData(sd)
+    );
+    msg.constructed_when = Local.timestamp(secs, 0);
+    return msg;
+  }
+
+  #[test]
+  fn time_bytes_sort_like_times() {
+    let secs = [-4_000_000_000, -1, 0, 1, 1_600_000_000, 4_000_000_000];
+    let keys: Vec<[u8; 8]> = secs
+      .iter()
+      .map(|s| time_bytes(&Local.timestamp(*s, 0)))
+      .collect();
+    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+  }
+
+  #[test]
+  fn sensor_fields_round_trip() {
+    let field = sensor_field(SensorType::Humidity, SensorId(42));
+    assert_eq!(
+      parse_sensor_field(&field), Some((SensorType::Humidity, SensorId(42)))
+    );
+    assert_eq!(parse_sensor_field("nonsense"), None);
+  }
+
+  #[test]
+  fn time_ranges_read_only_what_is_within() {
+    let db = Scratch::new();
+    let secs = [-100, 5, 10, 15, 20, 1_600_000_000];
+    let mut msgs: Vec<BrokerMessage> = secs
+      .iter()
+      .map(|s| reading(SensorType::Temperature, *s))
+      .collect();
+    msgs.push(reading(SensorType::Humidity, 10));
+    db.0.insert_messages(msgs.clone()).unwrap();
+    let filter = MessageFilter::default()
+      .sensor(SensorType::Temperature)
+      .between(Local.timestamp(5, 0), Local.timestamp(15, 0));
+    let found: Vec<BrokerMessage> = db.0.query(filter).unwrap().collect();
+    let ids: Vec<_> = found.iter().map(|m| m.id).collect();
+    let wanted: Vec<_> = msgs[1..4].iter().map(|m| m.id).collect();
+    assert_eq!(ids, wanted);
+    let all = MessageFilter::default().sensor(SensorType::Temperature);
+    assert_eq!(db.0.query(all).unwrap().count(), secs.len());
+  }
+
+  #[test]
+  fn ids_are_taken_once() {
+    let db = Scratch::new();
+    let (a, b) = (MessageId::new_v4(), MessageId::new_v4());
+    assert!(db.0.claim_ids(&[a]).unwrap().is_empty());
+    assert_eq!(db.0.claim_ids(&[a, b]).unwrap(), [a].iter().cloned().collect());
+    db.0.release_ids(&[a]).unwrap();
+    assert!(db.0.known_ids(&[a, b]).unwrap().contains(&b));
+    assert!(db.0.claim_ids(&[a]).unwrap().is_empty());
+  }
+}
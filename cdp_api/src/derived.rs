@@ -0,0 +1,80 @@
+//! Derived (virtual) sensors: values computed from the latest readings of
+//! real sensors, like dew point or the average of a room.
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use libcdp::comm::broker_api::BrokerMessagePayload;
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::SensorId;
+
+use crate::db::ApiDatabase;
+use crate::expr::Expr;
+use crate::lastvalue::LastValueCache;
+
+/// A virtual sensor definition, as parsed from config.
+#[derive(Clone, Debug)]
+pub(crate) struct DerivedSensor {
+  /// Unique name, used to query it.
+  pub(crate) name: String,
+  /// The expression, as written in config.
+  pub(crate) source: String,
+  /// The parsed expression.
+  pub(crate) expr: Expr,
+  /// Unit label for display, if any.
+  pub(crate) unit: Option<String>
+}
+
+impl DerivedSensor {
+  /// Whether any of the given sensors feeds this one.
+  pub(crate) fn depends_on(&self, touched: &HashSet<(SensorType, SensorId)>)
+  -> bool {
+    return self.expr.sensors().iter().any(|k| touched.contains(k));
+  }
+
+  /// Computes the current value from the latest readings, if all inputs are
+  /// known.
+  pub(crate) fn compute(&self, lvc: &LastValueCache) -> Option<f64> {
+    return self.expr.eval(&|stype, sensor_id| {
+      return match lvc.get(stype, sensor_id)?.payload {
+        BrokerMessagePayload::SensorData(sd) => {
+          Some(sd.reading().human_value())
+        },
+        _ => None,
+      };
+    });
+  }
+}
+
+/// A single computed value of a virtual sensor.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct DerivedReading {
+  /// Name of the virtual sensor.
+  pub(crate) name: String,
+  /// The computed value.
+  pub(crate) value: f64,
+  /// When it was computed.
+  pub(crate) computed_when: DateTime<Local>
+}
+
+/// Recomputes every virtual sensor that depends on the touched sensors and
+/// stores the new values.
+pub(crate) fn recompute<D: ApiDatabase>(
+  db: &D,
+  lvc: &LastValueCache,
+  sensors: &[DerivedSensor],
+  touched: &HashSet<(SensorType, SensorId)>
+) -> Result<(), D::DbError> {
+  for ds in sensors.iter().filter(|ds| ds.depends_on(touched)) {
+    if let Some(value) = ds.compute(lvc) {
+      db.insert_derived(DerivedReading {
+        name: ds.name.clone(),
+        value: value,
+        computed_when: Local::now()
+      })?;
+    }
+  }
+  return Ok(());
+}
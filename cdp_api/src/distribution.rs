@@ -0,0 +1,284 @@
+//! How a sensor's readings spread out over a while: percentiles and
+//! histograms, computed as the readings stream out of the database, so a
+//! year of them never has to fit in memory at once.
+//!
+//! Percentiles are exact up to a thousand readings. Past that, they're
+//! estimated with the P² algorithm (Jain and Chlamtac, 1985), which keeps
+//! five markers per percentile and nudges them as readings go by.
+//! Histograms have fixed-width bins, only keeping the ones with readings.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Percentiles reported, in percent.
+pub(crate) const PERCENTILES: [f64; 3] = [50.0, 95.0, 99.0];
+
+/// Readings kept to work percentiles out exactly, before estimating.
+const EXACT_UP_TO: usize = 1000;
+
+/// The value at a quantile, in [0, 1], of some readings, nearest rank.
+fn nearest_rank(values: &[f64], p: f64) -> f64 {
+  let mut sorted = values.to_vec();
+  sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+  return sorted[(p * (sorted.len() - 1) as f64).round() as usize];
+}
+
+/// A streaming estimate of one percentile.
+#[derive(Clone, Debug)]
+struct P2Quantile {
+  /// The quantile, in [0, 1].
+  p: f64,
+  /// Marker heights.
+  q: [f64; 5],
+  /// Marker positions.
+  n: [f64; 5],
+  /// Desired marker positions.
+  want: [f64; 5],
+  /// How much each desired position moves per reading.
+  step: [f64; 5],
+  /// Readings seen so far.
+  count: usize
+}
+
+impl P2Quantile {
+  /// An estimate of the given quantile, with nothing seen yet.
+  fn new(p: f64) -> Self {
+    return Self {
+      p: p,
+      q: [0.0; 5],
+      n: [1.0, 2.0, 3.0, 4.0, 5.0],
+      want: [1.0, 1.0 + 2.0 * p, 1.0 + 4.0 * p, 3.0 + 2.0 * p, 5.0],
+      step: [0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0],
+      count: 0
+    };
+  }
+
+  /// Takes a reading into account.
+  fn observe(&mut self, x: f64) {
+    if self.count < 5 {
+      self.q[self.count] = x;
+      self.count += 1;
+      if self.count == 5 {
+        self.q.sort_by(|a, b| a.partial_cmp(b).unwrap());
+      }
+      return;
+    }
+    self.count += 1;
+    // find the cell it falls in, stretching the ends if need be
+    let k = if x < self.q[0] {
+      self.q[0] = x;
+      0
+    } else if x >= self.q[4] {
+      self.q[4] = x;
+      3
+    } else {
+      (1..5).find(|i| x < self.q[*i]).unwrap_or(4) - 1
+    };
+    for i in (k + 1)..5 {
+      self.n[i] += 1.0;
+    }
+    for i in 0..5 {
+      self.want[i] += self.step[i];
+    }
+    // nudge the middle markers towards where they should be
+    for i in 1..4 {
+      let d = self.want[i] - self.n[i];
+      let room_up = self.n[i + 1] - self.n[i] > 1.0;
+      let room_down = self.n[i - 1] - self.n[i] < -1.0;
+      if (d >= 1.0 && room_up) || (d <= -1.0 && room_down) {
+        let s = d.signum();
+        let qp = self.parabolic(i, s);
+        self.q[i] = if self.q[i - 1] < qp && qp < self.q[i + 1] {
+          qp
+        } else {
+          self.linear(i, s)
+        };
+        self.n[i] += s;
+      }
+    }
+  }
+
+  /// Piecewise-parabolic prediction of a marker's height, moved by s.
+  fn parabolic(&self, i: usize, s: f64) -> f64 {
+    let (q, n) = (&self.q, &self.n);
+    return q[i] + s / (n[i + 1] - n[i - 1]) * (
+      (n[i] - n[i - 1] + s) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+      + (n[i + 1] - n[i] - s) * (q[i] - q[i - 1]) / (n[i] - n[i - 1])
+    );
+  }
+
+  /// Linear prediction of a marker's height, moved by s.
+  fn linear(&self, i: usize, s: f64) -> f64 {
+    let j = if s > 0.0 { i + 1 } else { i - 1 };
+    return self.q[i] + s * (self.q[j] - self.q[i]) / (self.n[j] - self.n[i]);
+  }
+
+  /// The estimate. None if nothing was seen.
+  fn value(&self) -> Option<f64> {
+    if self.count == 0 {
+      return None;
+    }
+    if self.count < 5 {
+      return Some(nearest_rank(&self.q[..self.count], self.p));
+    }
+    return Some(self.q[2]);
+  }
+}
+
+/// Count, extremes, mean and percentiles of some readings, in one pass.
+#[derive(Clone, Debug)]
+pub(crate) struct Summary {
+  /// Readings seen.
+  count: usize,
+  /// Smallest so far.
+  min: f64,
+  /// Largest so far.
+  max: f64,
+  /// Running sum.
+  sum: f64,
+  /// The readings, while there are few enough to keep.
+  exact: Vec<f64>,
+  /// One estimate per reported percentile.
+  quantiles: Vec<P2Quantile>
+}
+
+/// What some readings were like.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SummaryView {
+  /// How many.
+  pub(crate) count: usize,
+  /// Smallest. None if there were none.
+  pub(crate) min: Option<f64>,
+  /// Largest. None if there were none.
+  pub(crate) max: Option<f64>,
+  /// Mean. None if there were none.
+  pub(crate) mean: Option<f64>,
+  /// Percentiles, like "p95". Left out if there were none.
+  pub(crate) percentiles: BTreeMap<String, f64>
+}
+
+impl Summary {
+  /// A summary of nothing yet.
+  pub(crate) fn new() -> Self {
+    return Self {
+      count: 0,
+      min: f64::INFINITY,
+      max: f64::NEG_INFINITY,
+      sum: 0.0,
+      exact: Vec::new(),
+      quantiles: PERCENTILES
+        .iter()
+        .map(|pct| P2Quantile::new(pct / 100.0))
+        .collect()
+    };
+  }
+
+  /// Takes a reading into account. Ones that aren't numbers are skipped.
+  pub(crate) fn observe(&mut self, x: f64) {
+    if !x.is_finite() {
+      return;
+    }
+    self.count += 1;
+    self.min = self.min.min(x);
+    self.max = self.max.max(x);
+    self.sum += x;
+    if self.count <= EXACT_UP_TO {
+      self.exact.push(x);
+    } else {
+      self.exact = Vec::new();
+    }
+    for q in self.quantiles.iter_mut() {
+      q.observe(x);
+    }
+  }
+
+  /// How it turned out.
+  pub(crate) fn view(&self) -> SummaryView {
+    let any = self.count > 0;
+    return SummaryView {
+      count: self.count,
+      min: Some(self.min).filter(|_| any),
+      max: Some(self.max).filter(|_| any),
+      mean: Some(self.sum / self.count as f64).filter(|_| any),
+      percentiles: PERCENTILES
+        .iter()
+        .zip(self.quantiles.iter())
+        .filter_map(|(pct, q)| {
+          let value = if !any {
+            None
+          } else if self.count <= EXACT_UP_TO {
+            Some(nearest_rank(&self.exact, pct / 100.0))
+          } else {
+            q.value()
+          };
+          return Some((format!("p{}", pct), value?));
+        })
+        .collect()
+    };
+  }
+}
+
+/// Readings counted into fixed-width bins.
+#[derive(Clone, Debug)]
+pub(crate) struct Histogram {
+  /// Width of a bin.
+  width: f64,
+  /// Readings per bin, by how many widths from zero it starts.
+  bins: BTreeMap<i64, usize>
+}
+
+/// A bin of a histogram.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct BinView {
+  /// Where it starts, inclusive.
+  pub(crate) low: f64,
+  /// Where it ends, exclusive.
+  pub(crate) high: f64,
+  /// Readings in it.
+  pub(crate) count: usize
+}
+
+/// How some readings spread out.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct HistogramView {
+  /// Width of each bin.
+  pub(crate) width: f64,
+  /// Readings in all bins.
+  pub(crate) count: usize,
+  /// Bins with readings in them, lowest first.
+  pub(crate) bins: Vec<BinView>
+}
+
+impl Histogram {
+  /// An empty histogram with bins of a width, which must be positive.
+  pub(crate) fn new(width: f64) -> Self {
+    return Self {
+      width: width,
+      bins: BTreeMap::new()
+    };
+  }
+
+  /// Counts a reading. Ones that aren't numbers are skipped.
+  pub(crate) fn observe(&mut self, x: f64) {
+    if x.is_finite() {
+      *self.bins.entry((x / self.width).floor() as i64).or_insert(0) += 1;
+    }
+  }
+
+  /// How it turned out.
+  pub(crate) fn view(&self) -> HistogramView {
+    return HistogramView {
+      width: self.width,
+      count: self.bins.values().sum(),
+      bins: self.bins
+        .iter()
+        .map(|(i, n)| BinView {
+          low: *i as f64 * self.width,
+          high: (*i + 1) as f64 * self.width,
+          count: *n
+        })
+        .collect()
+    };
+  }
+}
@@ -0,0 +1,182 @@
+//! Brokers enrolling themselves, with one-time tokens handed out by whoever
+//! runs the API, instead of having UIDs and keys typed into them. Each token
+//! enrolls a single broker. Enrollments wait for an admin to approve them,
+//! unless auto_approve is on; then the broker gets a key like a rotated one,
+//! and its heartbeats must carry it from then on.
+
+use std::error::Error as StdError;
+use std::fmt::Display;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use libcdp::comm::enrollment::{self, EnrollReply, EnrollRequest, EnrollState};
+use libcdp::ids::BrokerId;
+
+use crate::brokers::{self, BrokerKeys, BrokerRecord};
+use crate::db::ApiDatabase;
+
+/// Enrollment settings, as they lie in the config file.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct EnrollmentConfig {
+  /// One-time tokens brokers may enroll with. Empty means enrollment is
+  /// off.
+  pub(crate) tokens: Vec<String>,
+  /// Whether to approve enrollments without waiting for an admin.
+  pub(crate) auto_approve: bool
+}
+
+impl EnrollmentConfig {
+  /// Checks that no token is empty, since that'd let anybody in.
+  pub(crate) fn check(&self) -> Result<(), String> {
+    if self.tokens.iter().any(|t| t.trim().is_empty()) {
+      return Err("Enrollment tokens can't be empty.".to_owned());
+    }
+    return Ok(());
+  }
+}
+
+/// How a broker enrolled, as kept in its record.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Enrollment {
+  /// Its public key, in base64.
+  pub(crate) public_key: String,
+  /// The token it used up. Never served.
+  #[serde(default, skip_serializing_if = "String::is_empty")]
+  pub(crate) token: String,
+  /// When it asked.
+  pub(crate) requested: DateTime<Local>,
+  /// When it was approved. None means it's waiting.
+  pub(crate) approved: Option<DateTime<Local>>
+}
+
+impl Enrollment {
+  /// Whether it's waiting for an admin.
+  pub(crate) fn is_pending(&self) -> bool {
+    return self.approved.is_none();
+  }
+}
+
+/// Why an enrollment request was turned away.
+#[derive(Debug)]
+pub(crate) enum EnrollError<E: StdError> {
+  /// Badly signed, or stale.
+  BadRequest(enrollment::EnrollError),
+  /// Not a token we gave out, or one already used.
+  BadToken,
+  /// The UID is some other broker's.
+  Taken,
+  /// Couldn't read or write the broker's record.
+  Db(E)
+}
+
+impl<E: StdError> StdError for EnrollError<E> {}
+
+impl<E: StdError> Display for EnrollError<E> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      EnrollError::BadRequest(e) => write!(f, "{}", e),
+      EnrollError::BadToken => write!(f, "Unknown or used-up token."),
+      EnrollError::Taken => write!(f, "That UID is taken."),
+      EnrollError::Db(e) => write!(f, "Database error: {}", e),
+    };
+  }
+}
+
+/// Marks an enrollment approved, and gives the broker its first key.
+fn approve_record(rec: &mut BrokerRecord) {
+  if let Some(e) = rec.enrollment.as_mut() {
+    if e.is_pending() {
+      e.approved = Some(Local::now());
+      rec.keys = Some(BrokerKeys {
+        current: brokers::new_key(),
+        previous: None,
+        previous_until: None
+      });
+    }
+  }
+}
+
+/// Where an enrolled broker stands, as told to it.
+fn reply_for(rec: &BrokerRecord) -> EnrollReply {
+  let approved = rec.enrollment.as_ref()
+    .map(|e| !e.is_pending())
+    .unwrap_or(false);
+  return match (approved, &rec.keys) {
+    (true, Some(keys)) => EnrollReply {
+      state: EnrollState::Approved,
+      key: Some(keys.current.clone())
+    },
+    _ => EnrollReply { state: EnrollState::Pending, key: None },
+  };
+}
+
+/// Takes an enrollment request in. The first one from a broker uses up its
+/// token; after that, the same broker may ask again, with the same token
+/// and key, to know whether it was approved. Returns whether the broker is
+/// new, and what to answer it.
+pub(crate) fn enroll<D: ApiDatabase>(
+  db: &D, cfg: &EnrollmentConfig, req: &EnrollRequest
+) -> Result<(bool, EnrollReply), EnrollError<D::DbError>> {
+  req.verify().map_err(EnrollError::BadRequest)?;
+  if !cfg.tokens.contains(&req.token) {
+    return Err(EnrollError::BadToken);
+  }
+  if let Some(rec) = db.broker(req.uid).map_err(EnrollError::Db)? {
+    return match &rec.enrollment {
+      Some(e) if e.public_key == req.public_key && e.token == req.token => {
+        Ok((false, reply_for(&rec)))
+      },
+      _ => Err(EnrollError::Taken),
+    };
+  }
+  let used = db.brokers()
+    .map_err(EnrollError::Db)?
+    .iter()
+    .filter_map(|r| r.enrollment.as_ref())
+    .any(|e| e.token == req.token);
+  if used {
+    return Err(EnrollError::BadToken);
+  }
+  let now = Local::now();
+  let mut rec = BrokerRecord {
+    uid: req.uid,
+    version: 0,
+    first_seen: now,
+    last_heartbeat: now,
+    status: None,
+    keys: None,
+    last_key: None,
+    enrollment: Some(Enrollment {
+      public_key: req.public_key.clone(),
+      token: req.token.clone(),
+      requested: now,
+      approved: None
+    }),
+    desired_config: None,
+    config_hash: None,
+    update: None,
+    software: None,
+    incompatible: false
+  };
+  if cfg.auto_approve {
+    approve_record(&mut rec);
+  }
+  let reply = reply_for(&rec);
+  db.update_broker(rec).map_err(EnrollError::Db)?;
+  return Ok((true, reply));
+}
+
+/// Approves a broker's enrollment. Approving twice changes nothing. None if
+/// the broker never enrolled.
+pub(crate) fn approve<D: ApiDatabase>(db: &D, uid: BrokerId)
+-> Result<Option<BrokerRecord>, D::DbError> {
+  let mut rec = match db.broker(uid)? {
+    Some(r) if r.enrollment.is_some() => r,
+    _ => return Ok(None),
+  };
+  approve_record(&mut rec);
+  db.update_broker(rec.clone())?;
+  return Ok(Some(rec));
+}
@@ -0,0 +1,582 @@
+//! A tiny expression language over sensor readings, used to define virtual
+//! sensors and alert rules. Things like:
+//!
+//! ```text
+//! dew_point(temperature:1, humidity:3)
+//! avg(temperature:1, temperature:2, temperature:5) - 0.5
+//! temperature:1 > 30 and (humidity:3 < 20 or not humidity:4 >= 20)
+//! ```
+//!
+//! `type:id` refers to the latest reading of that sensor, in the unit people
+//! usually read it in (°C, %RH). Supports numbers, + - * /, parentheses, and
+//! the functions avg, min, max and dew_point(celsius, percent).
+//!
+//! Comparisons (< <= > >= == !=) give 1 when they hold and 0 otherwise, and
+//! and, or and not take anything other than 0 as true. They bind looser
+//! than arithmetic, in that order: comparisons, not, and, or.
+
+use std::error::Error as StdError;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::SensorId;
+
+/// Binary operators.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum BinOp {
+  Add,
+  Sub,
+  Mul,
+  Div
+}
+
+/// Comparison operators.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum CmpOp {
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  Eq,
+  Ne
+}
+
+/// Logical connectives.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum LogicOp {
+  And,
+  Or
+}
+
+/// Built-in functions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Function {
+  /// Arithmetic mean of any number of arguments.
+  Avg,
+  /// Smallest of any number of arguments.
+  Min,
+  /// Largest of any number of arguments.
+  Max,
+  /// Dew point in °C from a temperature in °C and a humidity in %RH.
+  DewPoint
+}
+
+impl FromStr for Function {
+  type Err = ();
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    return match s {
+      "avg" => Ok(Function::Avg),
+      "min" => Ok(Function::Min),
+      "max" => Ok(Function::Max),
+      "dew_point" => Ok(Function::DewPoint),
+      _ => Err(()),
+    };
+  }
+}
+
+impl Function {
+  /// Applies the function. None if the arguments make no sense.
+  fn apply(&self, args: &[f64]) -> Option<f64> {
+    if args.is_empty() { return None; }
+    return match self {
+      Function::Avg => Some(args.iter().sum::<f64>() / args.len() as f64),
+      Function::Min => args.iter().cloned().fold(None, |acc, v| {
+        Some(acc.map_or(v, |a: f64| a.min(v)))
+      }),
+      Function::Max => args.iter().cloned().fold(None, |acc, v| {
+        Some(acc.map_or(v, |a: f64| a.max(v)))
+      }),
+      Function::DewPoint => {
+        if args.len() != 2 || args[1] <= 0.0 { return None; }
+        // Magnus formula, good to about ±0.35 °C in home conditions.
+        let (a, b) = (17.62, 243.12);
+        let (t, rh) = (args[0], args[1]);
+        let gamma = (rh / 100.0).ln() + a * t / (b + t);
+        Some(b * gamma / (a - gamma))
+      },
+    };
+  }
+}
+
+/// A parsed expression.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Expr {
+  /// A literal.
+  Number(f64),
+  /// Latest value of a sensor.
+  Sensor(SensorType, SensorId),
+  /// Unary minus.
+  Neg(Box<Expr>),
+  /// Binary arithmetic.
+  Binary(BinOp, Box<Expr>, Box<Expr>),
+  /// Comparison, 1 or 0.
+  Compare(CmpOp, Box<Expr>, Box<Expr>),
+  /// Logical negation, 1 or 0.
+  Not(Box<Expr>),
+  /// Logical connective, 1 or 0.
+  Logic(LogicOp, Box<Expr>, Box<Expr>),
+  /// Function call.
+  Call(Function, Vec<Expr>)
+}
+
+/// A truth value, as a number.
+fn truth(b: bool) -> f64 {
+  return if b { 1.0 } else { 0.0 };
+}
+
+impl Expr {
+  /// Evaluates the expression, looking sensor values up with the given
+  /// function. None if any sensor is missing or the math goes wrong.
+  pub(crate) fn eval<F>(&self, lookup: &F) -> Option<f64>
+  where F: Fn(SensorType, SensorId) -> Option<f64> {
+    let val = match self {
+      Expr::Number(n) => *n,
+      Expr::Sensor(st, id) => lookup(*st, *id)?,
+      Expr::Neg(e) => -e.eval(lookup)?,
+      Expr::Binary(op, l, r) => {
+        let (lv, rv) = (l.eval(lookup)?, r.eval(lookup)?);
+        match op {
+          BinOp::Add => lv + rv,
+          BinOp::Sub => lv - rv,
+          BinOp::Mul => lv * rv,
+          BinOp::Div => lv / rv,
+        }
+      },
+      Expr::Compare(op, l, r) => {
+        let (lv, rv) = (l.eval(lookup)?, r.eval(lookup)?);
+        truth(match op {
+          CmpOp::Lt => lv < rv,
+          CmpOp::Le => lv <= rv,
+          CmpOp::Gt => lv > rv,
+          CmpOp::Ge => lv >= rv,
+          CmpOp::Eq => lv == rv,
+          CmpOp::Ne => lv != rv,
+        })
+      },
+      Expr::Not(e) => truth(e.eval(lookup)? == 0.0),
+      Expr::Logic(op, l, r) => {
+        let (lv, rv) = (l.eval(lookup)? != 0.0, r.eval(lookup)? != 0.0);
+        truth(match op {
+          LogicOp::And => lv && rv,
+          LogicOp::Or => lv || rv,
+        })
+      },
+      Expr::Call(f, args) => {
+        let mut vals: Vec<f64> = Vec::with_capacity(args.len());
+        for a in args {
+          vals.push(a.eval(lookup)?);
+        }
+        f.apply(&vals)?
+      },
+    };
+    return if val.is_finite() { Some(val) } else { None };
+  }
+
+  /// Evaluates the expression as a condition. None if it can't be told.
+  pub(crate) fn holds<F>(&self, lookup: &F) -> Option<bool>
+  where F: Fn(SensorType, SensorId) -> Option<f64> {
+    return self.eval(lookup).map(|v| v != 0.0);
+  }
+
+  /// Returns every sensor the expression refers to.
+  pub(crate) fn sensors(&self) -> Vec<(SensorType, SensorId)> {
+    let mut out = Vec::new();
+    self.collect_sensors(&mut out);
+    return out;
+  }
+
+  /// Helper for sensors().
+  fn collect_sensors(&self, out: &mut Vec<(SensorType, SensorId)>) {
+    match self {
+      Expr::Number(_) => {},
+      Expr::Sensor(st, id) => {
+        if !out.contains(&(*st, *id)) { out.push((*st, *id)); }
+      },
+      Expr::Neg(e) | Expr::Not(e) => e.collect_sensors(out),
+      Expr::Binary(_, l, r)
+      | Expr::Compare(_, l, r)
+      | Expr::Logic(_, l, r) => {
+        l.collect_sensors(out);
+        r.collect_sensors(out);
+      },
+      Expr::Call(_, args) => args.iter().for_each(|a| a.collect_sensors(out)),
+    }
+  }
+}
+
+/// Errors that can come up when parsing an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ExprParseError {
+  /// Found a character we don't know at this position.
+  BadChar(usize, char),
+  /// Expected something else at this position.
+  Unexpected(usize, String),
+  /// Input ended too soon.
+  UnexpectedEnd,
+  /// Not a sensor type we know.
+  BadSensorType(String),
+  /// Not a function we know.
+  BadFunction(String)
+}
+
+impl StdError for ExprParseError {}
+
+impl Display for ExprParseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      ExprParseError::BadChar(pos, c) => {
+        write!(f, "Unexpected character '{}' at {}.", c, pos)
+      },
+      ExprParseError::Unexpected(pos, what) => {
+        write!(f, "Unexpected {} at {}.", what, pos)
+      },
+      ExprParseError::UnexpectedEnd => {
+        write!(f, "Expression ended unexpectedly.")
+      },
+      ExprParseError::BadSensorType(st) => {
+        write!(f, "Bad sensor type \"{}\".", st)
+      },
+      ExprParseError::BadFunction(fname) => {
+        write!(f, "Unknown function \"{}\".", fname)
+      },
+    };
+  }
+}
+
+/// Lexical tokens.
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+  Number(f64),
+  Ident(String),
+  Colon,
+  Comma,
+  LParen,
+  RParen,
+  Op(char),
+  Cmp(CmpOp)
+}
+
+/// Splits the input into (position, token) pairs.
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, ExprParseError> {
+  let chars: Vec<char> = s.chars().collect();
+  let mut toks = Vec::new();
+  let mut i = 0;
+  while i < chars.len() {
+    let c = chars[i];
+    if c.is_whitespace() {
+      i += 1;
+    } else if c.is_ascii_digit() || c == '.' {
+      let start = i;
+      while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+        i += 1;
+      }
+      let lit: String = chars[start..i].iter().collect();
+      let n = f64::from_str(&lit)
+        .map_err(|_| ExprParseError::Unexpected(start, lit.clone()))?;
+      toks.push((start, Token::Number(n)));
+    } else if c.is_ascii_alphabetic() || c == '_' {
+      let start = i;
+      while i < chars.len()
+        && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+        i += 1;
+      }
+      toks.push((start, Token::Ident(chars[start..i].iter().collect())));
+    } else {
+      let start = i;
+      let tok = match c {
+        ':' => Token::Colon,
+        ',' => Token::Comma,
+        '(' => Token::LParen,
+        ')' => Token::RParen,
+        '+' | '-' | '*' | '/' => Token::Op(c),
+        '<' | '>' | '=' | '!' => {
+          let eq = chars.get(i + 1) == Some(&'=');
+          let op = match (c, eq) {
+            ('<', false) => CmpOp::Lt,
+            ('<', true) => CmpOp::Le,
+            ('>', false) => CmpOp::Gt,
+            ('>', true) => CmpOp::Ge,
+            ('=', true) => CmpOp::Eq,
+            ('!', true) => CmpOp::Ne,
+            _ => return Err(ExprParseError::BadChar(i, c)),
+          };
+          if eq {
+            i += 1;
+          }
+          Token::Cmp(op)
+        },
+        _ => return Err(ExprParseError::BadChar(i, c)),
+      };
+      toks.push((start, tok));
+      i += 1;
+    }
+  }
+  return Ok(toks);
+}
+
+/// Recursive-descent parser over the token list.
+struct Parser {
+  toks: Vec<(usize, Token)>,
+  pos: usize
+}
+
+impl Parser {
+  /// Peeks at the next token.
+  fn peek(&self) -> Option<&Token> {
+    return self.toks.get(self.pos).map(|(_, t)| t);
+  }
+
+  /// Consumes the next token.
+  fn next(&mut self) -> Result<(usize, Token), ExprParseError> {
+    let t = self.toks.get(self.pos).cloned();
+    self.pos += 1;
+    return t.ok_or(ExprParseError::UnexpectedEnd);
+  }
+
+  /// Consumes the next token, which must be the given one.
+  fn expect(&mut self, tok: Token) -> Result<(), ExprParseError> {
+    let (p, t) = self.next()?;
+    if t != tok {
+      return Err(ExprParseError::Unexpected(p, format!("{:?}", t)));
+    }
+    return Ok(());
+  }
+
+  /// Consumes the next token if it's the given keyword.
+  fn keyword(&mut self, kw: &str) -> bool {
+    if let Some(Token::Ident(name)) = self.peek() {
+      if name == kw {
+        self.pos += 1;
+        return true;
+      }
+    }
+    return false;
+  }
+
+  /// expr := conj ('or' conj)*
+  fn expr(&mut self) -> Result<Expr, ExprParseError> {
+    let mut lhs = self.conj()?;
+    while self.keyword("or") {
+      let rhs = self.conj()?;
+      lhs = Expr::Logic(LogicOp::Or, Box::new(lhs), Box::new(rhs));
+    }
+    return Ok(lhs);
+  }
+
+  /// conj := neg ('and' neg)*
+  fn conj(&mut self) -> Result<Expr, ExprParseError> {
+    let mut lhs = self.neg()?;
+    while self.keyword("and") {
+      let rhs = self.neg()?;
+      lhs = Expr::Logic(LogicOp::And, Box::new(lhs), Box::new(rhs));
+    }
+    return Ok(lhs);
+  }
+
+  /// neg := 'not' neg | cmp
+  fn neg(&mut self) -> Result<Expr, ExprParseError> {
+    if self.keyword("not") {
+      return Ok(Expr::Not(Box::new(self.neg()?)));
+    }
+    return self.cmp();
+  }
+
+  /// cmp := sum (('<' | '<=' | '>' | '>=' | '==' | '!=') sum)?
+  fn cmp(&mut self) -> Result<Expr, ExprParseError> {
+    let lhs = self.sum()?;
+    if let Some(Token::Cmp(op)) = self.peek().cloned() {
+      self.pos += 1;
+      let rhs = self.sum()?;
+      return Ok(Expr::Compare(op, Box::new(lhs), Box::new(rhs)));
+    }
+    return Ok(lhs);
+  }
+
+  /// sum := term (('+' | '-') term)*
+  fn sum(&mut self) -> Result<Expr, ExprParseError> {
+    let mut lhs = self.term()?;
+    while let Some(Token::Op(c)) = self.peek().cloned() {
+      let op = match c {
+        '+' => BinOp::Add,
+        '-' => BinOp::Sub,
+        _ => break,
+      };
+      self.pos += 1;
+      let rhs = self.term()?;
+      lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+    return Ok(lhs);
+  }
+
+  /// term := factor (('*' | '/') factor)*
+  fn term(&mut self) -> Result<Expr, ExprParseError> {
+    let mut lhs = self.factor()?;
+    while let Some(Token::Op(c)) = self.peek().cloned() {
+      let op = match c {
+        '*' => BinOp::Mul,
+        '/' => BinOp::Div,
+        _ => break,
+      };
+      self.pos += 1;
+      let rhs = self.factor()?;
+      lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+    return Ok(lhs);
+  }
+
+  /// factor := '-' factor | number | type ':' id | func '(' args ')'
+  ///         | '(' expr ')'
+  fn factor(&mut self) -> Result<Expr, ExprParseError> {
+    let (p, t) = self.next()?;
+    return match t {
+      Token::Op('-') => Ok(Expr::Neg(Box::new(self.factor()?))),
+      Token::Number(n) => Ok(Expr::Number(n)),
+      Token::LParen => {
+        let e = self.expr()?;
+        self.expect(Token::RParen)?;
+        Ok(e)
+      },
+      Token::Ident(name) => match self.peek() {
+        Some(Token::Colon) => {
+          self.pos += 1;
+          let stype = SensorType::from_str(&name)
+            .map_err(|_| ExprParseError::BadSensorType(name.clone()))?;
+          match self.next()? {
+            (_, Token::Number(n))
+            if n.fract() == 0.0 && (0.0..=255.0).contains(&n) => {
+              Ok(Expr::Sensor(stype, SensorId(n as u8)))
+            },
+            (ip, it) => {
+              Err(ExprParseError::Unexpected(ip, format!("{:?}", it)))
+            },
+          }
+        },
+        Some(Token::LParen) => {
+          self.pos += 1;
+          let func = Function::from_str(&name)
+            .map_err(|_| ExprParseError::BadFunction(name.clone()))?;
+          let mut args = Vec::new();
+          if self.peek() != Some(&Token::RParen) {
+            args.push(self.expr()?);
+            while self.peek() == Some(&Token::Comma) {
+              self.pos += 1;
+              args.push(self.expr()?);
+            }
+          }
+          self.expect(Token::RParen)?;
+          Ok(Expr::Call(func, args))
+        },
+        _ => Err(ExprParseError::Unexpected(p, name)),
+      },
+      other => Err(ExprParseError::Unexpected(p, format!("{:?}", other))),
+    };
+  }
+}
+
+impl FromStr for Expr {
+  type Err = ExprParseError;
+  /// Parses a whole expression. Trailing garbage is an error.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parser = Parser {
+      toks: tokenize(s)?,
+      pos: 0
+    };
+    let e = parser.expr()?;
+    if let Some((p, t)) = parser.toks.get(parser.pos) {
+      return Err(ExprParseError::Unexpected(*p, format!("{:?}", t)));
+    }
+    return Ok(e);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Parses, or fails the test.
+  fn parse(s: &str) -> Expr {
+    return Expr::from_str(s).unwrap();
+  }
+
+  /// Evaluates with temperature:1 at 30 and humidity:3 at 50, and nothing
+  /// else heard from.
+  fn eval(s: &str) -> Option<f64> {
+    return parse(s).eval(&|st, id| match (st, id) {
+      (SensorType::Temperature, SensorId(1)) => Some(30.0),
+      (SensorType::Humidity, SensorId(3)) => Some(50.0),
+      _ => None,
+    });
+  }
+
+  #[test]
+  fn arithmetic_binds_tighter_the_usual_way() {
+    assert_eq!(eval("1 + 2 * 3"), Some(7.0));
+    assert_eq!(eval("(1 + 2) * 3"), Some(9.0));
+    assert_eq!(eval("10 - 4 - 3"), Some(3.0));
+    assert_eq!(eval("12 / 3 / 2"), Some(2.0));
+    assert_eq!(eval("-2 * 3 + 1"), Some(-5.0));
+    assert_eq!(eval("temperature:1 - humidity:3 / 2"), Some(5.0));
+  }
+
+  #[test]
+  fn comparisons_bind_looser_than_arithmetic_and_tighter_than_logic() {
+    assert_eq!(eval("temperature:1 > 20 + 5"), Some(1.0));
+    assert_eq!(eval("1 + 1 == 2"), Some(1.0));
+    assert_eq!(eval("1 < 2 and 3 < 2 or 1"), Some(1.0));
+    assert_eq!(eval("1 < 2 and (3 < 2 or 0)"), Some(0.0));
+    assert_eq!(eval("0 or 1 and 0"), Some(0.0));
+    assert_eq!(eval("not 1 > 2 and 1"), Some(1.0));
+    assert_eq!(eval("not (1 < 2 and 1)"), Some(0.0));
+  }
+
+  #[test]
+  fn functions_take_expressions() {
+    assert_eq!(eval("avg(temperature:1, 10) * 2"), Some(40.0));
+    assert_eq!(eval("min(3, 1 + 1, 5)"), Some(2.0));
+    assert_eq!(eval("max(temperature:1, humidity:3)"), Some(50.0));
+    assert!(eval("dew_point(temperature:1, humidity:3)").is_some());
+  }
+
+  #[test]
+  fn division_by_zero_cant_be_told() {
+    assert_eq!(eval("1 / 0"), None);
+    assert_eq!(eval("0 / 0"), None);
+    assert_eq!(eval("temperature:1 / (humidity:3 - 50)"), None);
+    assert_eq!(parse("1 / 0 > 1").holds(&|_, _| None), None);
+  }
+
+  #[test]
+  fn missing_sensors_cant_be_told() {
+    assert_eq!(eval("temperature:2 + 1"), None);
+    assert_eq!(eval("temperature:2 > 1 or 1"), None);
+    let e = parse("temperature:1 > 30 and humidity:3 < 20");
+    assert_eq!(e.sensors(), vec![
+      (SensorType::Temperature, SensorId(1)),
+      (SensorType::Humidity, SensorId(3))
+    ]);
+  }
+
+  #[test]
+  fn parse_errors_say_what_and_where() {
+    let err = |s: &str| Expr::from_str(s).unwrap_err();
+    assert_eq!(err("1 + $"), ExprParseError::BadChar(4, '$'));
+    assert_eq!(err("1 = 2"), ExprParseError::BadChar(2, '='));
+    assert_eq!(err("1 +"), ExprParseError::UnexpectedEnd);
+    assert_eq!(err("(1 + 2"), ExprParseError::UnexpectedEnd);
+    assert_eq!(err(""), ExprParseError::UnexpectedEnd);
+    assert!(matches!(err("1 2"), ExprParseError::Unexpected(2, _)));
+    assert!(matches!(err("1 + )"), ExprParseError::Unexpected(4, _)));
+    assert!(matches!(err("temperature"), ExprParseError::Unexpected(0, _)));
+    assert!(matches!(err("temperature:256"), ExprParseError::Unexpected(..)));
+    assert!(matches!(err("temperature:1.5"), ExprParseError::Unexpected(..)));
+    assert_eq!(
+      err("pressure:1"),
+      ExprParseError::BadSensorType("pressure".to_owned())
+    );
+    assert_eq!(
+      err("median(1, 2)"),
+      ExprParseError::BadFunction("median".to_owned())
+    );
+  }
+}
@@ -0,0 +1,43 @@
+//! Live feed of freshly stored messages, for whoever wants to watch them as
+//! they arrive.
+
+use std::sync::Arc;
+
+use tokio::sync::broadcast;
+
+use libcdp::comm::broker_api::BrokerMessage;
+
+/// How many messages a slow watcher may fall behind before missing some.
+const FEED_CAPACITY: usize = 1024;
+
+/// Broadcasts every message stored by this instance. Cheap to clone, all
+/// clones share the same channel.
+#[derive(Clone, Debug)]
+pub(crate) struct MessageFeed {
+  tx: broadcast::Sender<Arc<BrokerMessage>>
+}
+
+impl Default for MessageFeed {
+  fn default() -> Self {
+    let (tx, _) = broadcast::channel(FEED_CAPACITY);
+    return Self { tx: tx };
+  }
+}
+
+impl MessageFeed {
+  /// Hands freshly stored messages to every watcher. Nobody watching is
+  /// fine.
+  pub(crate) fn publish(&self, msgs: &[BrokerMessage]) {
+    if self.tx.receiver_count() == 0 {
+      return;
+    }
+    for msg in msgs {
+      let _ = self.tx.send(Arc::new(msg.clone()));
+    }
+  }
+
+  /// Starts watching. Only messages published from now on are seen.
+  pub(crate) fn subscribe(&self) -> broadcast::Receiver<Arc<BrokerMessage>> {
+    return self.tx.subscribe();
+  }
+}
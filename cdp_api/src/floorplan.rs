@@ -0,0 +1,149 @@
+//! The floorplan: rooms as polygons, and where each sensor sits, so
+//! frontends can draw readings where they're taken. Coordinates are in
+//! whatever units the frontend likes, as long as they're the same for all.
+//!
+//! There's only ever one floorplan, replaced whole with every upload.
+
+use std::collections::HashSet;
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::SensorId;
+
+/// A point, as [x, y].
+pub(crate) type Point = [f64; 2];
+
+/// A room, as drawn.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct FloorplanRoom {
+  /// Its name, unique within the floorplan. Best kept the same as in the
+  /// sensor registry.
+  pub(crate) name: String,
+  /// Its outline, at least three corners, in order.
+  pub(crate) polygon: Vec<Point>
+}
+
+/// Where a sensor sits.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SensorPlacement {
+  /// Type of the sensor.
+  pub(crate) sensor_type: SensorType,
+  /// ID of the sensor.
+  pub(crate) sensor_id: SensorId,
+  /// Where, as [x, y].
+  pub(crate) position: Point,
+  /// Which room it's drawn in, if any.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) room: Option<String>
+}
+
+/// The whole floorplan.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Floorplan {
+  /// Every room.
+  #[serde(default)]
+  pub(crate) rooms: Vec<FloorplanRoom>,
+  /// Every sensor placed.
+  #[serde(default)]
+  pub(crate) sensors: Vec<SensorPlacement>
+}
+
+/// What's wrong with a floorplan.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum FloorplanError {
+  /// A room has fewer than three corners.
+  TooFewCorners(String),
+  /// Two rooms go by the same name.
+  DuplicateRoom(String),
+  /// A coordinate is NaN or infinite.
+  BadCoordinate,
+  /// A sensor we've never heard of.
+  UnknownSensor(SensorType, SensorId),
+  /// A sensor is placed twice.
+  DuplicateSensor(SensorType, SensorId),
+  /// A sensor is placed in a room that isn't drawn.
+  UnknownRoom(String)
+}
+
+impl std::error::Error for FloorplanError {}
+
+impl Display for FloorplanError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      FloorplanError::TooFewCorners(r) => {
+        write!(f, "Room \"{}\" needs at least three corners.", r)
+      },
+      FloorplanError::DuplicateRoom(r) => {
+        write!(f, "Room \"{}\" is there twice.", r)
+      },
+      FloorplanError::BadCoordinate => {
+        write!(f, "Coordinates must be finite numbers.")
+      },
+      FloorplanError::UnknownSensor(st, id) => {
+        write!(f, "No such sensor {}:{}.", st, id)
+      },
+      FloorplanError::DuplicateSensor(st, id) => {
+        write!(f, "Sensor {}:{} is placed twice.", st, id)
+      },
+      FloorplanError::UnknownRoom(r) => {
+        write!(f, "No room \"{}\" is drawn.", r)
+      },
+    };
+  }
+}
+
+impl FloorplanError {
+  /// Machine-readable code, for API errors.
+  pub(crate) fn code(&self) -> &'static str {
+    return match self {
+      FloorplanError::TooFewCorners(_) => "too_few_corners",
+      FloorplanError::DuplicateRoom(_) => "duplicate_room",
+      FloorplanError::BadCoordinate => "bad_coordinate",
+      FloorplanError::UnknownSensor(..) => "unknown_sensor",
+      FloorplanError::DuplicateSensor(..) => "duplicate_sensor",
+      FloorplanError::UnknownRoom(_) => "unknown_room",
+    };
+  }
+}
+
+impl Floorplan {
+  /// Checks that it makes sense, and that every sensor placed is one we
+  /// know, going by the given function.
+  pub(crate) fn validate<F>(&self, known: F) -> Result<(), FloorplanError>
+  where F: Fn(SensorType, SensorId) -> bool {
+    let finite = |p: &Point| p[0].is_finite() && p[1].is_finite();
+    let mut rooms: HashSet<&str> = HashSet::new();
+    for room in &self.rooms {
+      if room.polygon.len() < 3 {
+        return Err(FloorplanError::TooFewCorners(room.name.clone()));
+      }
+      if !room.polygon.iter().all(finite) {
+        return Err(FloorplanError::BadCoordinate);
+      }
+      if !rooms.insert(&room.name) {
+        return Err(FloorplanError::DuplicateRoom(room.name.clone()));
+      }
+    }
+    let mut placed: HashSet<(SensorType, SensorId)> = HashSet::new();
+    for sp in &self.sensors {
+      let key = (sp.sensor_type, sp.sensor_id);
+      if !finite(&sp.position) {
+        return Err(FloorplanError::BadCoordinate);
+      }
+      if !known(sp.sensor_type, sp.sensor_id) {
+        return Err(FloorplanError::UnknownSensor(key.0, key.1));
+      }
+      if !placed.insert(key) {
+        return Err(FloorplanError::DuplicateSensor(key.0, key.1));
+      }
+      if let Some(r) = &sp.room {
+        if !rooms.contains(r.as_str()) {
+          return Err(FloorplanError::UnknownRoom(r.clone()));
+        }
+      }
+    }
+    return Ok(());
+  }
+}
@@ -0,0 +1,347 @@
+//! Forecasts: where a sensor's readings are headed, so "the freezer will
+//! hit 0 °C in two hours" can be said before it does.
+//!
+//! Recent readings are averaged into evenly spaced steps, and one of two
+//! models is fit over them: a least-squares line, or Holt's linear trend
+//! method (Holt-Winters without the seasons, which a house doesn't have on
+//! a scale of hours). Either gives predicted values with bounds that widen
+//! the further ahead they are.
+
+use std::fmt::Display;
+
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+
+/// Forecast settings, as they lie in the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ForecastConfig {
+  /// How far back to fit over, in minutes.
+  #[serde(default = "ForecastConfig::default_lookback_minutes")]
+  pub(crate) lookback_minutes: u32,
+  /// Readings are averaged into steps this long, in minutes. Forecasts
+  /// come in steps this long too.
+  #[serde(default = "ForecastConfig::default_step_minutes")]
+  pub(crate) step_minutes: u32,
+  /// How far ahead forecasts may go, in minutes.
+  #[serde(default = "ForecastConfig::default_max_horizon_minutes")]
+  pub(crate) max_horizon_minutes: u32,
+  /// Holt's level smoothing factor, in (0, 1]. Higher adapts faster.
+  #[serde(default = "ForecastConfig::default_alpha")]
+  pub(crate) alpha: f64,
+  /// Holt's trend smoothing factor, in (0, 1]. Higher adapts faster.
+  #[serde(default = "ForecastConfig::default_beta")]
+  pub(crate) beta: f64,
+  /// How wide the bounds are, in standard errors. 1.96 is about 95%.
+  #[serde(default = "ForecastConfig::default_z")]
+  pub(crate) z: f64
+}
+
+impl ForecastConfig {
+  /// For serde.
+  fn default_lookback_minutes() -> u32 {
+    return 360;
+  }
+
+  /// For serde.
+  fn default_step_minutes() -> u32 {
+    return 5;
+  }
+
+  /// For serde.
+  fn default_max_horizon_minutes() -> u32 {
+    return 1440;
+  }
+
+  /// For serde.
+  fn default_alpha() -> f64 {
+    return 0.5;
+  }
+
+  /// For serde.
+  fn default_beta() -> f64 {
+    return 0.1;
+  }
+
+  /// For serde.
+  fn default_z() -> f64 {
+    return 1.96;
+  }
+
+  /// Checks that the numbers make sense.
+  pub(crate) fn check(&self) -> Result<(), String> {
+    if self.step_minutes == 0 || self.lookback_minutes < self.step_minutes {
+      return Err(
+        "Forecasts need a step, and to look back at least one.".to_owned()
+      );
+    }
+    let unit = |x: f64| x > 0.0 && x <= 1.0;
+    if !unit(self.alpha) || !unit(self.beta) {
+      return Err("Forecast alpha and beta must be in (0, 1].".to_owned());
+    }
+    if !(self.z >= 0.0) {
+      return Err("Forecast z can't be negative.".to_owned());
+    }
+    return Ok(());
+  }
+
+  /// Length of a step.
+  pub(crate) fn step(&self) -> Duration {
+    return Duration::minutes(self.step_minutes.into());
+  }
+}
+
+impl Default for ForecastConfig {
+  fn default() -> Self {
+    return Self {
+      lookback_minutes: Self::default_lookback_minutes(),
+      step_minutes: Self::default_step_minutes(),
+      max_horizon_minutes: Self::default_max_horizon_minutes(),
+      alpha: Self::default_alpha(),
+      beta: Self::default_beta(),
+      z: Self::default_z()
+    };
+  }
+}
+
+/// Which model to fit.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ForecastMethod {
+  /// A least-squares line through every step.
+  Linear,
+  /// Holt's linear trend method, which weighs recent steps more.
+  Holt
+}
+
+impl Default for ForecastMethod {
+  fn default() -> Self {
+    return ForecastMethod::Holt;
+  }
+}
+
+/// A predicted value.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ForecastPoint {
+  /// When.
+  pub(crate) when: DateTime<Local>,
+  /// The best guess.
+  pub(crate) value: f64,
+  /// Lower bound.
+  pub(crate) low: f64,
+  /// Upper bound.
+  pub(crate) high: f64
+}
+
+/// Where a sensor's readings are headed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Forecast {
+  /// Which model was fit.
+  pub(crate) method: ForecastMethod,
+  /// Steps with readings that it was fit over.
+  pub(crate) samples: usize,
+  /// Predicted values, one per step, soonest first.
+  pub(crate) points: Vec<ForecastPoint>
+}
+
+/// Why there's no forecast.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ForecastError {
+  /// Too few steps had readings to fit anything.
+  NotEnoughData(usize)
+}
+
+impl std::error::Error for ForecastError {}
+
+impl Display for ForecastError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      ForecastError::NotEnoughData(n) => {
+        write!(f, "Need readings in at least {} steps to forecast.", n)
+      },
+    };
+  }
+}
+
+/// Readings, averaged into evenly spaced steps ending now.
+#[derive(Clone, Debug)]
+pub(crate) struct StepSeries {
+  /// When the first step starts.
+  start: DateTime<Local>,
+  /// Length of a step.
+  step: Duration,
+  /// Sum and count of the readings in each step.
+  steps: Vec<(f64, usize)>
+}
+
+impl StepSeries {
+  /// An empty series covering the lookback up to a time.
+  pub(crate) fn new(cfg: &ForecastConfig, until: DateTime<Local>) -> Self {
+    let len = (cfg.lookback_minutes / cfg.step_minutes) as usize;
+    return Self {
+      start: until - cfg.step() * len as i32,
+      step: cfg.step(),
+      steps: vec![(0.0, 0); len]
+    };
+  }
+
+  /// When the series starts.
+  pub(crate) fn start(&self) -> DateTime<Local> {
+    return self.start;
+  }
+
+  /// Adds a reading. Ones out of range are ignored.
+  pub(crate) fn push(&mut self, when: DateTime<Local>, value: f64) {
+    let since = (when - self.start).num_milliseconds();
+    let step = self.step.num_milliseconds();
+    if since < 0 || !value.is_finite() {
+      return;
+    }
+    if let Some(s) = self.steps.get_mut((since / step) as usize) {
+      s.0 += value;
+      s.1 += 1;
+    }
+  }
+
+  /// Average of each step, None where there were no readings.
+  fn means(&self) -> Vec<Option<f64>> {
+    return self.steps
+      .iter()
+      .map(|(sum, n)| if *n > 0 { Some(sum / *n as f64) } else { None })
+      .collect();
+  }
+
+  /// Fits a model, and predicts the given number of steps past the end.
+  pub(crate) fn forecast(
+    &self, cfg: &ForecastConfig, method: ForecastMethod, ahead: usize
+  ) -> Result<Forecast, ForecastError> {
+    let end = self.start + self.step * self.steps.len() as i32;
+    let means = self.means();
+    let samples = means.iter().filter(|m| m.is_some()).count();
+    let predicted = match method {
+      ForecastMethod::Linear => linear(&means, ahead),
+      ForecastMethod::Holt => holt(&means, ahead, cfg.alpha, cfg.beta),
+    }?;
+    let points = predicted
+      .into_iter()
+      .enumerate()
+      .map(|(k, (value, se))| ForecastPoint {
+        when: end + self.step * (k as i32 + 1),
+        value: value,
+        low: value - cfg.z * se,
+        high: value + cfg.z * se
+      })
+      .collect();
+    return Ok(Forecast {
+      method: method,
+      samples: samples,
+      points: points
+    });
+  }
+}
+
+impl Forecast {
+  /// When the best guess first reaches a value, from whichever side it's
+  /// on now, interpolating between steps. None if it doesn't within the
+  /// horizon.
+  pub(crate) fn reaches(&self, from: f64, target: f64)
+  -> Option<DateTime<Local>> {
+    let above = from >= target;
+    let mut prev: Option<&ForecastPoint> = None;
+    for p in &self.points {
+      if (p.value >= target) != above || p.value == target {
+        let (t0, v0) = match prev {
+          Some(q) => (q.when, q.value),
+          None => return Some(p.when),
+        };
+        let frac = if p.value == v0 { 1.0 } else {
+          (target - v0) / (p.value - v0)
+        };
+        let ms = (p.when - t0).num_milliseconds() as f64 * frac;
+        return Some(t0 + Duration::milliseconds(ms as i64));
+      }
+      prev = Some(p);
+    }
+    return None;
+  }
+}
+
+/// Fits a least-squares line over the steps with readings. Returns each
+/// prediction with its standard error.
+fn linear(means: &[Option<f64>], ahead: usize)
+-> Result<Vec<(f64, f64)>, ForecastError> {
+  let xy: Vec<(f64, f64)> = means
+    .iter()
+    .enumerate()
+    .filter_map(|(i, m)| Some((i as f64 + 0.5, (*m)?)))
+    .collect();
+  let n = xy.len() as f64;
+  if xy.len() < 3 {
+    return Err(ForecastError::NotEnoughData(3));
+  }
+  let mx = xy.iter().map(|(x, _)| x).sum::<f64>() / n;
+  let my = xy.iter().map(|(_, y)| y).sum::<f64>() / n;
+  let sxx: f64 = xy.iter().map(|(x, _)| (x - mx).powi(2)).sum();
+  let sxy: f64 = xy.iter().map(|(x, y)| (x - mx) * (y - my)).sum();
+  let slope = sxy / sxx;
+  let icept = my - slope * mx;
+  let sse: f64 = xy
+    .iter()
+    .map(|(x, y)| (y - icept - slope * x).powi(2))
+    .sum();
+  let s = (sse / (n - 2.0)).sqrt();
+  let len = means.len() as f64;
+  return Ok((1..=ahead)
+    .map(|k| {
+      let x = len + k as f64;
+      let se = s * (1.0 + 1.0 / n + (x - mx).powi(2) / sxx).sqrt();
+      return (icept + slope * x, se);
+    })
+    .collect()
+  );
+}
+
+/// Runs Holt's linear trend method over the steps, skipping over the ones
+/// without readings. Returns each prediction with its standard error.
+fn holt(means: &[Option<f64>], ahead: usize, alpha: f64, beta: f64)
+-> Result<Vec<(f64, f64)>, ForecastError> {
+  let mut state: Option<(f64, f64)> = None;
+  let mut seen = 0;
+  let mut sq_errors = 0.0;
+  let mut errors = 0;
+  for m in means {
+    state = match (state, m) {
+      (None, None) => None,
+      (None, Some(y)) => Some((*y, 0.0)),
+      (Some((level, trend)), None) => Some((level + trend, trend)),
+      (Some((level, trend)), Some(y)) => {
+        if seen >= 2 {
+          sq_errors += (y - level - trend).powi(2);
+          errors += 1;
+        }
+        let next = alpha * y + (1.0 - alpha) * (level + trend);
+        let trend = if seen == 1 { next - level } else {
+          beta * (next - level) + (1.0 - beta) * trend
+        };
+        Some((next, trend))
+      },
+    };
+    if m.is_some() {
+      seen += 1;
+    }
+  }
+  let (level, trend) = match state {
+    Some(s) if errors > 0 => s,
+    _ => return Err(ForecastError::NotEnoughData(3)),
+  };
+  let sigma = (sq_errors / errors as f64).sqrt();
+  let mut spread: f64 = 1.0;
+  return Ok((1..=ahead)
+    .map(|k| {
+      let value = level + trend * (k as f64 + 0.5);
+      let se = sigma * spread.sqrt();
+      spread += (alpha * (1.0 + k as f64 * beta)).powi(2);
+      return (value, se);
+    })
+    .collect()
+  );
+}
@@ -0,0 +1,188 @@
+//! gRPC flavour of the ingestion API, for typed clients and live streaming.
+//! Runs on its own thread and runtime, next to the HTTP server, if a
+//! grpc_bind address is configured.
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use chrono::Local;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+
+use libcdp::comm::broker_api::{self, BrokerMessage, BrokerMessagePayload, HeartbeatMessage};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::proto::{pb, ProtoError};
+
+use crate::api::Api;
+use crate::brokers::{self, HeartbeatError};
+use crate::db::AsyncApiDatabase;
+use crate::ingest::{IngestError, Intake, StoreError};
+use crate::supervisor::Restart;
+
+/// Generated from proto/cdp_api.proto.
+pub(crate) mod proto {
+  tonic::include_proto!("cdp_api");
+}
+
+use proto::ingest_server::{Ingest, IngestServer};
+
+/// How many messages a subscriber may have queued before it's dropped.
+const SUBSCRIBER_QUEUE: usize = 256;
+
+/// How many times in a row the server may fail before it stays down.
+const GRPC_RESTARTS: u32 = 5;
+
+/// Messages that don't convert are the client's fault.
+fn bad_message(e: ProtoError) -> Status {
+  return Status::invalid_argument(e.to_string());
+}
+
+/// The gRPC service. Shares all state with the HTTP server.
+pub(crate) struct GrpcIngest<D: AsyncApiDatabase> {
+  api: Api<D>,
+  /// Where pushed bundles go, same as over HTTP.
+  intake: Intake<D>
+}
+
+impl<D: AsyncApiDatabase> GrpcIngest<D> {
+  /// Serves as a job, forever. Failing to start, like when the address is
+  /// taken, is tried again a few times before giving up, which /readyz
+  /// then tells.
+  pub(crate) fn spawn(api: Api<D>, addr: SocketAddr) {
+    info!("Serving gRPC on {}...", addr);
+    let jobs = api.jobs.clone();
+    jobs.spawn("gRPC", Restart::UpTo(GRPC_RESTARTS), move || {
+      let rt = tokio::runtime::Runtime::new()
+        .unwrap_or_else(|e| panic!("gRPC runtime tragedy: {}", e));
+      let svc = IngestServer::new(GrpcIngest {
+        api: api.clone(),
+        intake: api.intake()
+      });
+      let res = rt.block_on(Server::builder().add_service(svc).serve(addr));
+      if let Err(e) = res {
+        panic!("gRPC tragedy: {}", e);
+      }
+    });
+  }
+}
+
+#[tonic::async_trait]
+impl<D: AsyncApiDatabase> Ingest for GrpcIngest<D> {
+  type SubscribeStream = ReceiverStream<Result<pb::BrokerMessage, Status>>;
+
+  async fn push_bundle(&self, req: Request<pb::Bundle>)
+  -> Result<Response<proto::PushReply>, Status> {
+    let api = &self.api;
+    let key = req.metadata()
+      .get(broker_api::KEY_HEADER)
+      .and_then(|v| v.to_str().ok())
+      .map(|k| k.to_owned());
+    let batch = req.into_inner().messages
+      .into_iter()
+      .map(BrokerMessage::try_from)
+      .collect::<Result<Vec<BrokerMessage>, ProtoError>>()
+      .map_err(bad_message)?;
+    // there's no sealing over gRPC, so brokers with a key can't use it
+    let keys = &api.config.seal_keys;
+    if batch.iter().any(|m| keys.contains_key(&m.broker_id)) {
+      return Err(Status::unauthenticated("This broker must seal its bundles."));
+    }
+    return match self.intake.store(batch, key).await {
+      Ok(ingested) => {
+        let seqs = ingested.stored.iter().filter_map(|m| m.seq);
+        Ok(Response::new(proto::PushReply {
+          stored: ingested.stored.len() as u64,
+          first_seq: seqs.clone().min().unwrap_or(0),
+          last_seq: seqs.max().unwrap_or(0),
+          server_time_ms: Local::now().timestamp_millis(),
+          duplicates: ingested.duplicates as u64
+        }))
+      },
+      Err(StoreError::BadTime(i)) => Err(Status::invalid_argument(
+        format!("Message #{} has a time too far off.", i)
+      )),
+      Err(StoreError::RateLimited) => {
+        Err(Status::resource_exhausted("Slow down."))
+      },
+      Err(StoreError::WrongKey(_)) => {
+        Err(Status::unauthenticated("Wrong key."))
+      },
+      Err(StoreError::Wal(e)) => {
+        warn!("Failed to log a bundle: {}", e);
+        Err(Status::internal("god damnit"))
+      },
+      Err(StoreError::Ingest(IngestError::Insert(e))) => {
+        warn!("Bundle insert failed: {}", e);
+        Err(Status::internal(
+          format!("god damnit, message #{} didn't make it", e.index)
+        ))
+      },
+      Err(_) => Err(Status::internal("god damnit")),
+    };
+  }
+
+  async fn heartbeat(&self, req: Request<pb::HeartbeatMessage>)
+  -> Result<Response<proto::HeartbeatReply>, Status> {
+    let hb = HeartbeatMessage::try_from(req.into_inner())
+      .map_err(bad_message)?;
+    let res = self.api.db.blocking(move |db| brokers::heartbeat(db, &hb));
+    return match res.await {
+      Ok(reply) => Ok(Response::new(proto::HeartbeatReply {
+        config: reply.config.as_ref().map(pb::RemoteConfig::from),
+        update: reply.update.as_ref().map(pb::UpdateOrder::from),
+        api: reply.api.as_ref().map(pb::SoftwareInfo::from),
+        new_key: reply.new_key.unwrap_or_default()
+      })),
+      Err(HeartbeatError::WrongKey) => {
+        Err(Status::unauthenticated("Wrong key."))
+      },
+      Err(HeartbeatError::Pending) => {
+        Err(Status::permission_denied("Enrollment not approved yet."))
+      },
+      Err(HeartbeatError::Incompatible) => {
+        Err(Status::failed_precondition("Incompatible protocol."))
+      },
+      Err(HeartbeatError::Db(_)) => Err(Status::internal("god damnit")),
+    };
+  }
+
+  async fn subscribe(&self, req: Request<proto::SubscribeRequest>)
+  -> Result<Response<Self::SubscribeStream>, Status> {
+    let mut wanted: HashSet<SensorType> = HashSet::new();
+    for name in req.into_inner().sensor_types {
+      let stype = SensorType::from_str(&name)
+        .map_err(|_| Status::invalid_argument("No such sensor type."))?;
+      wanted.insert(stype);
+    }
+    let mut feed = self.api.feed.subscribe();
+    let (tx, rx) = mpsc::channel(SUBSCRIBER_QUEUE);
+    tokio::spawn(async move {
+      loop {
+        let msg = match feed.recv().await {
+          Ok(m) => m,
+          Err(broadcast::error::RecvError::Lagged(n)) => {
+            let e = format!("Fell behind, missed {} messages.", n);
+            let _ = tx.send(Err(Status::data_loss(e))).await;
+            return;
+          },
+          Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let pass = match &msg.payload {
+          BrokerMessagePayload::SensorData(sd) => {
+            wanted.is_empty() || wanted.contains(&sd.sensor_type())
+          },
+          _ => wanted.is_empty(),
+        };
+        if pass && tx.send(Ok(msg.as_ref().into())).await.is_err() {
+          // they hung up
+          return;
+        }
+      }
+    });
+    return Ok(Response::new(ReceiverStream::new(rx)));
+  }
+}
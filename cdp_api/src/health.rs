@@ -0,0 +1,167 @@
+//! Liveness and readiness reporting, for orchestrators and uptime monitors.
+
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::config::ApiConfig;
+use crate::db::AsyncApiDatabase;
+use crate::supervisor::Jobs;
+
+/// Facts about the running process.
+#[derive(Clone, Debug)]
+pub(crate) struct ProcessInfo {
+  /// When the API started.
+  pub(crate) started_when: DateTime<Local>
+}
+
+impl Default for ProcessInfo {
+  fn default() -> Self {
+    return Self {
+      started_when: Local::now()
+    };
+  }
+}
+
+/// Body of a liveness response.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Liveness {
+  /// Always "ok". If we can answer, we're alive.
+  pub(crate) status: &'static str,
+  /// Crate version.
+  pub(crate) version: &'static str,
+  /// When the API started.
+  pub(crate) started_when: DateTime<Local>,
+  /// Seconds since startup.
+  pub(crate) uptime_secs: i64
+}
+
+impl From<&ProcessInfo> for Liveness {
+  fn from(pi: &ProcessInfo) -> Self {
+    return Self {
+      status: "ok",
+      version: env!("CARGO_PKG_VERSION"),
+      started_when: pi.started_when,
+      uptime_secs: (Local::now() - pi.started_when).num_seconds()
+    };
+  }
+}
+
+/// Outcome of a single readiness check.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct CheckResult {
+  /// What was checked.
+  pub(crate) name: &'static str,
+  /// Whether it passed. Skipped checks pass.
+  pub(crate) ok: bool,
+  /// Whether the check was skipped for not applying.
+  pub(crate) skipped: bool,
+  /// Human-readable detail, mostly for failures.
+  pub(crate) detail: Option<String>
+}
+
+impl CheckResult {
+  /// A passing check.
+  fn pass(name: &'static str) -> Self {
+    return Self { name: name, ok: true, skipped: false, detail: None };
+  }
+
+  /// A failing check.
+  fn fail(name: &'static str, detail: String) -> Self {
+    return Self { name: name, ok: false, skipped: false, detail: Some(detail) };
+  }
+
+  /// A check that doesn't apply.
+  fn skip(name: &'static str, why: &str) -> Self {
+    return Self {
+      name: name,
+      ok: true,
+      skipped: true,
+      detail: Some(why.to_owned())
+    };
+  }
+}
+
+/// Body of a readiness response.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Readiness {
+  /// "ok" if every check passed, "fail" otherwise.
+  pub(crate) status: &'static str,
+  /// The individual checks.
+  pub(crate) checks: Vec<CheckResult>
+}
+
+impl Readiness {
+  /// Whether every check passed.
+  pub(crate) fn is_ready(&self) -> bool {
+    return self.checks.iter().all(|c| c.ok);
+  }
+}
+
+/// Checks that a file can be created next to the given path.
+fn probe_writable(path: &Path) -> Result<(), std::io::Error> {
+  let probe = path.with_extension("probe");
+  OpenOptions::new().write(true).create(true).open(&probe)?;
+  return fs::remove_file(&probe);
+}
+
+/// Checks that notification channels are configured, and that none of them
+/// is broken in a way we can tell without sending anything.
+fn notification_check(cfg: &ApiConfig) -> CheckResult {
+  let channels = &cfg.alerts.channels;
+  if channels.is_empty() {
+    return if cfg.alerts.escalation.is_empty() {
+      CheckResult::skip("notifications", "No notification channels needed.")
+    } else {
+      CheckResult::fail(
+        "notifications", "Escalation is set up, but no channels are.".into()
+      )
+    };
+  }
+  let mut problems: Vec<String> = channels.iter()
+    .filter_map(|(n, ch)| Some(format!("{}: {}", n, ch.problem()?)))
+    .collect();
+  problems.sort();
+  return if problems.is_empty() {
+    CheckResult::pass("notifications")
+  } else {
+    CheckResult::fail("notifications", format!("{}.", problems.join("; ")))
+  };
+}
+
+/// Runs every readiness check.
+pub(crate) async fn check_readiness<D: AsyncApiDatabase>(
+  db: &D, cfg: &ApiConfig, jobs: &Jobs
+) -> Readiness {
+  let mut checks = Vec::new();
+  checks.push(match db.ping().await {
+    Ok(_) => CheckResult::pass("database"),
+    Err(e) => CheckResult::fail("database", e.to_string()),
+  });
+  checks.push(match &cfg.database.snapshot_path {
+    Some(p) => match probe_writable(p) {
+      Ok(_) => CheckResult::pass("snapshot"),
+      Err(e) => CheckResult::fail(
+        "snapshot", format!("{} is not writable: {}", p.display(), e)
+      ),
+    },
+    None => CheckResult::skip("snapshot", "No snapshot path configured."),
+  });
+  checks.push(notification_check(cfg));
+  let down = jobs.down();
+  checks.push(if down.is_empty() {
+    CheckResult::pass("jobs")
+  } else {
+    CheckResult::fail("jobs", format!("Down for good: {}.", down.join(", ")))
+  });
+  let mut rd = Readiness {
+    status: "ok",
+    checks: checks
+  };
+  if !rd.is_ready() {
+    rd.status = "fail";
+  }
+  return rd;
+}
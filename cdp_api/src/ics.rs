@@ -0,0 +1,182 @@
+//! Just enough iCalendar (RFC 5545) to hand calendars a list of events:
+//! alerts, and maintenance windows, so panics can be lined up with
+//! whatever else was going on that day.
+//!
+//! Times are written in UTC, text is escaped, and long lines are folded,
+//! as calendars expect. Every event needs a UID that stays the same across
+//! fetches, or calendars will see every fetch as brand new events, so
+//! alerts and mutes go by their own IDs.
+
+use chrono::{DateTime, Local, Utc};
+
+use crate::alerts::{Alert, AlertState, Mute};
+
+/// Lines may be at most this long, in bytes, before folding.
+const MAX_LINE_BYTES: usize = 75;
+
+/// Something that happened, or will.
+#[derive(Clone, Debug)]
+pub(crate) struct Event {
+  /// Unique and stable ID, like "{uuid}@host".
+  pub(crate) uid: String,
+  /// When it starts.
+  pub(crate) start: DateTime<Local>,
+  /// When it ends. None means it's a moment, not a stretch.
+  pub(crate) end: Option<DateTime<Local>>,
+  /// What it is, in a line.
+  pub(crate) summary: String,
+  /// What it is, in more lines.
+  pub(crate) description: Option<String>,
+  /// Labels, like "alert" or "critical".
+  pub(crate) categories: Vec<String>
+}
+
+/// A calendar, as in a whole feed.
+#[derive(Clone, Debug)]
+pub(crate) struct Calendar {
+  /// What calendars show it as.
+  pub(crate) name: String,
+  /// Everything in it.
+  pub(crate) events: Vec<Event>
+}
+
+/// A time as iCalendar likes it, in UTC.
+fn timestamp(when: &DateTime<Local>) -> String {
+  return when.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ").to_string();
+}
+
+/// Escapes text for a TEXT value.
+fn escape(text: &str) -> String {
+  let mut out = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '\\' => out.push_str("\\\\"),
+      ';' => out.push_str("\\;"),
+      ',' => out.push_str("\\,"),
+      '\n' => out.push_str("\\n"),
+      '\r' => {},
+      c => out.push(c),
+    }
+  }
+  return out;
+}
+
+/// Writes a content line, folding it so no line is too long, without
+/// splitting characters.
+fn push_line(out: &mut String, line: &str) {
+  let mut len = 0;
+  for c in line.chars() {
+    if len + c.len_utf8() > MAX_LINE_BYTES {
+      out.push_str("\r\n ");
+      len = 1;
+    }
+    out.push(c);
+    len += c.len_utf8();
+  }
+  out.push_str("\r\n");
+}
+
+impl Calendar {
+  /// The whole calendar, as text/calendar.
+  pub(crate) fn to_ics(&self) -> String {
+    let now = timestamp(&Local::now());
+    let mut out = String::new();
+    push_line(&mut out, "BEGIN:VCALENDAR");
+    push_line(&mut out, "VERSION:2.0");
+    push_line(&mut out, "PRODID:-//casa_do_panico//cdp_api//EN");
+    push_line(&mut out, "CALSCALE:GREGORIAN");
+    push_line(&mut out, &format!("X-WR-CALNAME:{}", escape(&self.name)));
+    for ev in &self.events {
+      push_line(&mut out, "BEGIN:VEVENT");
+      push_line(&mut out, &format!("UID:{}", ev.uid));
+      push_line(&mut out, &format!("DTSTAMP:{}", now));
+      push_line(&mut out, &format!("DTSTART:{}", timestamp(&ev.start)));
+      if let Some(end) = &ev.end {
+        push_line(&mut out, &format!("DTEND:{}", timestamp(end)));
+      }
+      push_line(&mut out, &format!("SUMMARY:{}", escape(&ev.summary)));
+      if let Some(desc) = &ev.description {
+        push_line(&mut out, &format!("DESCRIPTION:{}", escape(desc)));
+      }
+      if !ev.categories.is_empty() {
+        let cats: Vec<String> = ev.categories.iter()
+          .map(|c| escape(c))
+          .collect();
+        push_line(&mut out, &format!("CATEGORIES:{}", cats.join(",")));
+      }
+      push_line(&mut out, "END:VEVENT");
+    }
+    push_line(&mut out, "END:VCALENDAR");
+    return out;
+  }
+}
+
+impl From<&Alert> for Event {
+  /// Alerts still going end now.
+  fn from(alert: &Alert) -> Self {
+    let start = alert.opened_when().unwrap_or_else(Local::now);
+    let end = alert.history.iter()
+      .find(|t| t.state == AlertState::Resolved)
+      .map(|t| t.when)
+      .unwrap_or_else(Local::now);
+    let mut desc = format!("Alert {}, {}.", alert.id, match alert.state {
+      AlertState::Open => "still open",
+      AlertState::Acknowledged => "acknowledged",
+      AlertState::Resolved => "resolved",
+    });
+    for t in alert.history.iter() {
+      let by = match &t.by {
+        Some(by) => by,
+        None => continue,
+      };
+      desc.push_str(&format!("\n{} by {}", t.when.format("%F %R"), by));
+      if let Some(note) = &t.note {
+        desc.push_str(&format!(": {}", note));
+      }
+    }
+    return Self {
+      uid: format!("alert-{}@casa_do_panico", alert.id),
+      start: start,
+      end: Some(end.max(start)),
+      summary: format!("[{}] {}", alert.severity, alert.summary),
+      description: Some(desc),
+      categories: vec![
+        "alert".to_owned(),
+        alert.kind.name().to_owned(),
+        alert.severity.to_string()
+      ]
+    };
+  }
+}
+
+impl From<&Mute> for Event {
+  fn from(mute: &Mute) -> Self {
+    let mut scope = Vec::new();
+    if let Some(st) = mute.sensor_type {
+      scope.push(format!("type {}", st));
+    }
+    if let Some(id) = mute.sensor_id {
+      scope.push(format!("ID {}", id));
+    }
+    if let Some(b) = mute.broker_id {
+      scope.push(format!("broker {}", b));
+    }
+    let mut desc = if scope.is_empty() {
+      "Every alert is muted.".to_owned()
+    } else {
+      format!("Alerts muted for {}.", scope.join(", "))
+    };
+    if let Some(by) = &mute.by {
+      desc.push_str(&format!("\nBy {}.", by));
+    }
+    let reason = mute.reason.as_deref().unwrap_or("alerts muted");
+    return Self {
+      uid: format!("mute-{}@casa_do_panico", mute.id),
+      start: mute.from,
+      end: Some(mute.until),
+      summary: format!("Maintenance: {}", reason),
+      description: Some(desc),
+      categories: vec!["maintenance".to_owned()]
+    };
+  }
+}
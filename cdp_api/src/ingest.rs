@@ -0,0 +1,306 @@
+//! The ingestion pipeline: everything that happens to a bundle between
+//! arriving and being stored. Every transport, HTTP or gRPC, hands its
+//! bundles to an Intake, so they all go through the same steps.
+
+use std::collections::HashSet;
+use std::error::Error as StdError;
+use std::fmt::Display;
+use std::io;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local};
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, MessageId, SensorId};
+
+use crate::alerts::AlertBook;
+use crate::anomaly::AnomalyDetector;
+use crate::brokers;
+use crate::calibration;
+use crate::db::{
+  self, ApiDatabase, AsyncApiDatabase, BatchInsertError, DbError, News
+};
+use crate::derived::{self, DerivedSensor};
+use crate::feed::MessageFeed;
+use crate::lastvalue::LastValueCache;
+use crate::ratelimit::RateLimits;
+use crate::stats::IngestStats;
+use crate::wal::WriteAheadLog;
+
+/// Held from handing out sequence numbers until the batch is stored and
+/// cached, so batches are stored and cached in the order of their numbers.
+/// Otherwise, whoever pages by them could see a batch, and skip one
+/// numbered before it that was still being stored. It only holds within
+/// this process, though: instances sharing a Redis database number their
+/// batches from the same counter, but store them each on their own, so
+/// across instances that can still happen. Paging by number is only
+/// gapless with a single instance.
+static SEQUENCING: Mutex<()> = Mutex::new(());
+
+/// IDs a batch took in the database, given back when dropped, unless its
+/// messages were stored.
+struct Claim<'a, D: ApiDatabase> {
+  db: &'a D,
+  ids: Vec<MessageId>
+}
+
+impl<D: ApiDatabase> Claim<'_, D> {
+  /// Keeps the IDs taken for good, now that their messages are stored.
+  fn stored(mut self) {
+    self.ids.clear();
+  }
+}
+
+impl<D: ApiDatabase> Drop for Claim<'_, D> {
+  fn drop(&mut self) {
+    if self.ids.is_empty() {
+      return;
+    }
+    if let Err(e) = self.db.release_ids(&self.ids) {
+      warn!("Couldn't give back the IDs of a batch not stored: {}", e);
+    }
+  }
+}
+
+/// Drops the messages of a batch that are already stored, being stored
+/// right now, or in the batch twice, and takes the IDs of the rest in the
+/// database. Messages without an ID can't be told apart, so they're all
+/// kept.
+fn claim<D: ApiDatabase>(db: &D, batch: Vec<BrokerMessage>)
+-> Result<(Vec<BrokerMessage>, Claim<'_, D>), D::DbError> {
+  let mut seen = HashSet::new();
+  let batch: Vec<BrokerMessage> = batch
+    .into_iter()
+    .filter(|msg| msg.id.map(|id| seen.insert(id)).unwrap_or(true))
+    .collect();
+  let ids: Vec<MessageId> = batch.iter().filter_map(|m| m.id).collect();
+  let taken = db.claim_ids(&ids)?;
+  let claim = Claim {
+    db: db,
+    ids: ids.into_iter().filter(|id| !taken.contains(id)).collect()
+  };
+  let kept = batch
+    .into_iter()
+    .filter(|msg| msg.id.map(|id| !taken.contains(&id)).unwrap_or(true))
+    .collect();
+  return Ok((kept, claim));
+}
+
+/// What became of a batch.
+#[derive(Debug)]
+pub(crate) struct Ingested {
+  /// The messages stored, as they were stored.
+  pub(crate) stored: Vec<BrokerMessage>,
+  /// How many weren't, for being stored already, or on their way to be.
+  pub(crate) duplicates: usize
+}
+
+/// Where the pipeline gave up.
+#[derive(Debug)]
+pub(crate) enum IngestError<E: StdError> {
+  /// Couldn't take the IDs of the batch. Nothing was stored.
+  Duplicates(E),
+  /// Couldn't look up a calibration. Nothing was stored.
+  Calibration(E),
+  /// Couldn't number the messages. Nothing was stored.
+  Sequence(E),
+  /// The batch insert failed. Nothing was stored.
+  Insert(BatchInsertError<E>),
+  /// Couldn't update virtual sensors. Nothing was stored.
+  Derived(E),
+  /// Couldn't commit the batch and what it changed. Nothing was stored.
+  Commit(E)
+}
+
+impl<E: StdError> StdError for IngestError<E> {}
+
+impl<E: StdError> From<E> for IngestError<E> {
+  fn from(e: E) -> Self {
+    return IngestError::Commit(e);
+  }
+}
+
+impl<E: StdError> Display for IngestError<E> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      IngestError::Duplicates(e) => {
+        return write!(f, "Taking message IDs failed: {}", e);
+      },
+      IngestError::Calibration(e) => {
+        return write!(f, "Calibration lookup failed: {}", e);
+      },
+      IngestError::Sequence(e) => {
+        return write!(f, "Sequence numbering failed: {}", e);
+      },
+      IngestError::Insert(e) => {
+        return write!(f, "Insert failed: {}", e);
+      },
+      IngestError::Derived(e) => {
+        return write!(f, "Virtual sensor update failed: {}", e);
+      },
+      IngestError::Commit(e) => {
+        return write!(f, "Commit failed: {}", e);
+      },
+    }
+  }
+}
+
+/// Calibrates, checks, stores and accounts for a batch of messages, then
+/// updates the alerts and virtual sensors that depend on them. Messages are
+/// numbered in the order they're stored. Messages stored before, going by
+/// their IDs, are left out, so a broker resending a bundle it never heard
+/// back about stores nothing twice. The batch and the virtual sensor
+/// readings it leads to are stored in a single transaction, and caches and
+/// alerts only hear of it once that went through. Without an alert book,
+/// as when replaying old bundles, no alerts are raised at all.
+pub(crate) fn ingest<D: ApiDatabase>(
+  db: &D,
+  lvc: &LastValueCache,
+  anm: &AnomalyDetector,
+  alr: Option<&AlertBook>,
+  ist: &IngestStats,
+  derived_sensors: &[DerivedSensor],
+  batch: Vec<BrokerMessage>,
+  received_when: DateTime<Local>
+) -> Result<Ingested, IngestError<D::DbError>> {
+  let total = batch.len();
+  let (mut batch, claim) = claim(db, batch)
+    .map_err(IngestError::Duplicates)?;
+  let duplicates = total - batch.len();
+  if batch.is_empty() {
+    return Ok(Ingested { stored: batch, duplicates: duplicates });
+  }
+  let mut touched: HashSet<(SensorType, SensorId)> = HashSet::new();
+  for msg in batch.iter_mut() {
+    msg.received_when = Some(received_when);
+    calibration::calibrate(db, msg).map_err(IngestError::Calibration)?;
+    anm.check(msg);
+    if let BrokerMessagePayload::SensorData(sd) = &msg.payload {
+      touched.insert((sd.sensor_type(), sd.sensor_id()));
+    }
+  }
+  {
+    let _numbering = SEQUENCING.lock().unwrap_or_else(|e| e.into_inner());
+    let first = db.next_seq(batch.len() as u64)
+      .map_err(IngestError::Sequence)?;
+    for (msg, seq) in batch.iter_mut().zip(first..) {
+      msg.seq = Some(seq);
+    }
+    let staged = lvc.staged(&batch);
+    db.with_txn(|txn| {
+      txn.insert_messages(batch.clone()).map_err(IngestError::Insert)?;
+      return derived::recompute(txn, &staged, derived_sensors, &touched)
+        .map_err(IngestError::Derived);
+    })?;
+    claim.stored();
+    for msg in batch.iter() {
+      lvc.update(msg);
+    }
+  }
+  // stored is stored, other instances being out of the loop isn't fatal
+  if let Err(e) = db.announce(&News::Ingested(batch.clone())) {
+    warn!("Couldn't tell other instances about a batch: {}", e);
+  }
+  for msg in batch.iter() {
+    ist.record(msg);
+  }
+  if let Some(alr) = alr {
+    for msg in batch.iter() {
+      alr.observe(msg, received_when);
+    }
+    alr.evaluate_rules(lvc, &touched, received_when);
+  }
+  return Ok(Ingested { stored: batch, duplicates: duplicates });
+}
+
+/// Everything storing a bundle touches. Cheap to clone.
+#[derive(Clone)]
+pub(crate) struct Intake<D: AsyncApiDatabase> {
+  /// Where bundles go.
+  pub(crate) db: D,
+  /// Latest reading of every sensor.
+  pub(crate) lvc: LastValueCache,
+  /// Outlier detector for incoming readings.
+  pub(crate) anm: AnomalyDetector,
+  /// Alerts, open or not.
+  pub(crate) alr: AlertBook,
+  /// Ingestion counters and rates.
+  pub(crate) ist: IngestStats,
+  /// Request rate limiters.
+  pub(crate) rls: RateLimits,
+  /// Log of incoming bundles.
+  pub(crate) wal: WriteAheadLog,
+  /// Freshly stored messages, for live watchers.
+  pub(crate) feed: MessageFeed,
+  /// Virtual sensors to keep up to date.
+  pub(crate) derived: Vec<DerivedSensor>
+}
+
+/// Why a bundle wasn't stored.
+#[derive(Debug)]
+pub(crate) enum StoreError<E: StdError> {
+  /// This message has a time too far off to store. Nothing was stored.
+  BadTime(usize),
+  /// Its broker went over its rate limit. Nothing was stored.
+  RateLimited,
+  /// It didn't come with the key of this broker, which has one. Nothing
+  /// was stored.
+  WrongKey(BrokerId),
+  /// Couldn't look its brokers' keys up. Nothing was stored.
+  Keys(E),
+  /// Couldn't log it. Nothing was stored.
+  Wal(io::Error),
+  /// The pipeline gave up.
+  Ingest(IngestError<E>)
+}
+
+impl<E: StdError> StdError for StoreError<E> {}
+
+impl<E: StdError> Display for StoreError<E> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      StoreError::BadTime(i) => {
+        write!(f, "Message #{} has a time too far off.", i)
+      },
+      StoreError::RateLimited => write!(f, "Rate limited."),
+      StoreError::WrongKey(uid) => write!(f, "Wrong key for {}.", uid),
+      StoreError::Keys(e) => write!(f, "Couldn't check keys: {}", e),
+      StoreError::Wal(e) => write!(f, "Couldn't log the bundle: {}", e),
+      StoreError::Ingest(e) => write!(f, "{}", e),
+    };
+  }
+}
+
+impl<D: AsyncApiDatabase> Intake<D> {
+  /// Checks a batch's times, the rate limits of every broker in it, and
+  /// the key it came with against theirs, logs it, ingests it, and hands
+  /// what got stored to live watchers.
+  pub(crate) async fn store(
+    &self, batch: Vec<BrokerMessage>, key: Option<String>
+  ) -> Result<Ingested, StoreError<DbError<D>>> {
+    if let Some(i) = batch.iter().position(|m| !db::storable_time(m)) {
+      return Err(StoreError::BadTime(i));
+    }
+    if !self.rls.check_batch(&batch) {
+      return Err(StoreError::RateLimited);
+    }
+    let this = self.clone();
+    // logging waits on the disk, so it goes where waiting is fine too
+    let ingested = self.db.blocking(move |db| {
+      let now = Local::now();
+      let wrong = brokers::wrong_key_for(db, &batch, key.as_deref(), now)
+        .map_err(StoreError::Keys)?;
+      if let Some(uid) = wrong {
+        return Err(StoreError::WrongKey(uid));
+      }
+      let received_when = this.wal.append(&batch).map_err(StoreError::Wal)?;
+      return ingest(
+        db, &this.lvc, &this.anm, Some(&this.alr), &this.ist, &this.derived,
+        batch, received_when
+      ).map_err(StoreError::Ingest);
+    }).await?;
+    self.feed.publish(&ingested.stored);
+    return Ok(ingested);
+  }
+}
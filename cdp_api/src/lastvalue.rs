@@ -0,0 +1,125 @@
+//! In-memory cache of the latest reading of every sensor, so we can tell the
+//! current state of the house without scanning the whole database.
+//!
+//! It also remembers the sequence number of the message that last changed
+//! each sensor's reading, so pollers can ask for just what changed since
+//! they last asked. A new message with the same reading changes nothing.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+use libcdp::comm::sensor_broker::{AnySensorMessage, SensorType};
+use libcdp::ids::SensorId;
+
+use crate::db::ApiDatabase;
+
+/// A sensor's latest message, and when its reading last changed.
+#[derive(Clone, Debug)]
+struct Latest {
+  /// The message.
+  msg: BrokerMessage,
+  /// Sequence number of the message that last changed the reading. 0 if
+  /// it had none.
+  changed: u64
+}
+
+/// What sensors changed since some cursor.
+#[derive(Clone, Debug)]
+pub(crate) struct Changes {
+  /// Where to ask from next time.
+  pub(crate) cursor: u64,
+  /// Latest message of every sensor whose reading changed, sorted by type
+  /// and sensor ID.
+  pub(crate) changed: Vec<BrokerMessage>
+}
+
+/// Whether a message carries the given reading.
+fn same_reading(msg: &BrokerMessage, sd: &AnySensorMessage) -> bool {
+  return match &msg.payload {
+    BrokerMessagePayload::SensorData(prev) => prev == sd,
+    _ => false,
+  };
+}
+
+/// Latest sensor message per (sensor type, sensor ID). Cheap to clone, all
+/// clones share the same data.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LastValueCache {
+  /// The actual cache.
+  inner: Arc<RwLock<HashMap<(SensorType, SensorId), Latest>>>
+}
+
+impl LastValueCache {
+  /// Builds a cache pre-filled with whatever the database says is latest.
+  pub(crate) fn warm<D: ApiDatabase>(db: &D) -> Result<Self, D::DbError> {
+    let cache = Self::default();
+    for msg in db.latest_per_sensor()? {
+      cache.update(&msg);
+    }
+    return Ok(cache);
+  }
+
+  /// Considers a freshly-ingested message. Only sensor data is kept, and
+  /// only if it's newer than what we've got.
+  pub(crate) fn update(&self, msg: &BrokerMessage) {
+    if let BrokerMessagePayload::SensorData(sd) = &msg.payload {
+      let key = (sd.sensor_type(), sd.sensor_id());
+      let mut map = self.inner.write().unwrap_or_else(|e| e.into_inner());
+      let changed = match map.get(&key) {
+        Some(prev) if prev.msg.constructed_when > msg.constructed_when => {
+          return;
+        },
+        Some(prev) if same_reading(&prev.msg, sd) => prev.changed,
+        _ => msg.seq.unwrap_or(0),
+      };
+      map.insert(key, Latest { msg: msg.clone(), changed: changed });
+    }
+  }
+
+  /// A cache of its own, with what this one has, plus some messages not
+  /// stored yet, for working out what they'd change before they are.
+  pub(crate) fn staged(&self, msgs: &[BrokerMessage]) -> Self {
+    let map = self.inner.read().unwrap_or_else(|e| e.into_inner()).clone();
+    let staged = Self { inner: Arc::new(RwLock::new(map)) };
+    for msg in msgs.iter() {
+      staged.update(msg);
+    }
+    return staged;
+  }
+
+  /// Returns a copy of the latest message of a single sensor.
+  pub(crate) fn get(&self, stype: SensorType, sensor_id: SensorId)
+  -> Option<BrokerMessage> {
+    let map = self.inner.read().unwrap_or_else(|e| e.into_inner());
+    return map.get(&(stype, sensor_id)).map(|l| l.msg.clone());
+  }
+
+  /// Returns a copy of every cached message, sorted by type and sensor ID.
+  pub(crate) fn snapshot(&self) -> Vec<BrokerMessage> {
+    let map = self.inner.read().unwrap_or_else(|e| e.into_inner());
+    let mut entries: Vec<(&(SensorType, SensorId), &Latest)>
+      = map.iter().collect();
+    entries.sort_by_key(|(k, _)| *k);
+    return entries.into_iter().map(|(_, l)| l.msg.clone()).collect();
+  }
+
+  /// Returns what changed since a cursor. Asking from the last cursor
+  /// returned misses nothing, as long as messages make it here in the order
+  /// of their sequence numbers. Cursor 0 gets every sensor.
+  pub(crate) fn changes_since(&self, cursor: u64) -> Changes {
+    let map = self.inner.read().unwrap_or_else(|e| e.into_inner());
+    let latest = map.values()
+      .filter_map(|l| l.msg.seq)
+      .max()
+      .unwrap_or(0);
+    let mut entries: Vec<(&(SensorType, SensorId), &Latest)> = map.iter()
+      .filter(|(_, l)| cursor == 0 || l.changed > cursor)
+      .collect();
+    entries.sort_by_key(|(k, _)| *k);
+    return Changes {
+      cursor: latest,
+      changed: entries.into_iter().map(|(_, l)| l.msg.clone()).collect()
+    };
+  }
+}
@@ -0,0 +1,224 @@
+//! Implements the services the API responds to.
+
+#[macro_use]
+extern crate libcdp;
+
+mod config;
+mod db;
+mod alerts;
+mod anomaly;
+mod api;
+mod arming;
+mod audit;
+mod backup;
+mod brokers;
+mod calibration;
+mod derived;
+mod distribution;
+mod enrollment;
+mod expr;
+mod feed;
+mod floorplan;
+mod forecast;
+mod grpc;
+mod health;
+mod ics;
+mod ingest;
+mod lastvalue;
+mod migrate;
+mod notify;
+mod presence;
+mod ratelimit;
+mod reports;
+mod sensors;
+mod stats;
+mod supervisor;
+#[cfg(unix)]
+mod uds;
+mod updates;
+mod wal;
+mod webhook;
+
+use std::sync::mpsc::{self, Receiver};
+
+use crate::alerts::AlertBook;
+use crate::anomaly::AnomalyDetector;
+use crate::api::Api;
+use crate::arming::Arming;
+use crate::config::ApiConfig;
+use crate::db::{ApiDatabase, ApiDatabaseType, AsyncApiDatabase, News};
+use crate::db::blocking::Blocking;
+use crate::db::inmem::InMemoryApiDatabase;
+use crate::db::redisdb::RedisApiDatabase;
+use crate::db::sleddb::SledApiDatabase;
+use crate::feed::MessageFeed;
+use crate::health::ProcessInfo;
+use crate::lastvalue::LastValueCache;
+use crate::presence::Presence;
+use crate::ratelimit::RateLimits;
+use crate::stats::IngestStats;
+use crate::supervisor::{Jobs, Restart};
+use crate::wal::WriteAheadLog;
+
+/// Tells other instances sharing the database about whatever comes out of
+/// the outbox, off the threads that put it there.
+fn spawn_announcer<D: ApiDatabase + 'static>(
+  jobs: &Jobs, db: D, outbox: Receiver<News>
+) {
+  jobs.spawn("announcer", Restart::Always, move || {
+    for news in outbox.iter() {
+      if let Err(e) = db.announce(&news) {
+        warn!("Couldn't tell other instances some news: {}", e);
+      }
+    }
+  });
+}
+
+/// Sets up everything that doesn't care about the database, and serves.
+/// Background jobs go in with jobs, along with any the database started.
+async fn serve<D: AsyncApiDatabase>(
+  cfg: ApiConfig, db_config: <D::Sync as ApiDatabase>::DbConfig, db: D,
+  jobs: Jobs
+) -> std::io::Result<()> {
+  let last_values = LastValueCache::warm(db.sync())
+    .unwrap_or_else(|e| panic!("Could not warm up the cache: {}", e));
+  let anomalies = AnomalyDetector::new(cfg.anomaly.clone());
+  let arming = Arming::open(&cfg.arming, &cfg.alerts.channels)
+    .unwrap_or_else(|e| panic!("Could not open the arming log: {}", e));
+  let alerts = AlertBook::open(
+    &cfg.alerts, cfg.low_battery_threshold, arming.clone()
+  ).unwrap_or_else(|e| panic!("Could not open the alert log: {}", e));
+  // keep up with whatever other instances store or change, and tell them
+  // about our own changes
+  let (lvc, alr, arm) = (last_values.clone(), alerts.clone(), arming.clone());
+  db.sync()
+    .listen(&jobs, move |news| match news {
+      News::Ingested(msgs) => msgs.iter().for_each(|msg| lvc.update(msg)),
+      News::Alert(line) => alr.apply(line),
+      News::Arming(change) => arm.apply(change),
+    })
+    .unwrap_or_else(|e| panic!("Could not listen to other instances: {}", e));
+  let (news, outbox) = mpsc::channel();
+  alerts.share(news.clone());
+  arming.share(news);
+  spawn_announcer(&jobs, db.sync().clone(), outbox);
+  notify::spawn_escalator(&jobs, alerts.clone());
+  reports::spawn_reporter(
+    &jobs, cfg.reports.clone(), &cfg.alerts.channels, db.sync().clone(),
+    alerts.clone()
+  );
+  let presence = Presence::new(&cfg.presence, arming.clone());
+  let rate_limits = RateLimits::from(&cfg.rate_limit);
+  let wal = WriteAheadLog::open(cfg.wal.as_ref())
+    .unwrap_or_else(|e| panic!("Could not open the write-ahead log: {}", e));
+  // init the API struct!
+  let api = Api {
+    config: cfg,
+    db_config: db_config,
+    db: db,
+    last_values: last_values,
+    anomalies: anomalies,
+    alerts: alerts,
+    presence: presence,
+    arming: arming,
+    ingest_stats: IngestStats::default(),
+    rate_limits: rate_limits,
+    process: ProcessInfo::default(),
+    wal: wal,
+    feed: MessageFeed::default(),
+    jobs: jobs,
+  };
+  return api.run_server().await;
+}
+
+/// Serves from the in-memory database, loaded from a snapshot if we've got
+/// one.
+async fn serve_in_memory(cfg: ApiConfig) -> std::io::Result<()> {
+  let snapshot_path = cfg.database.snapshot_path.clone();
+  let db = match &snapshot_path {
+    Some(p) if p.exists() => {
+      info!("Loading snapshot from {}...", p.display());
+      InMemoryApiDatabase::load_snapshot(p)
+        .unwrap_or_else(|e| panic!("Snapshot tragedy: {}", e))
+    },
+    _ => InMemoryApiDatabase::default(),
+  };
+  let jobs = Jobs::default();
+  if let Some(p) = &snapshot_path {
+    db.spawn_snapshotter(&jobs, p.clone(), cfg.database.snapshot_interval());
+  }
+  let res = serve(cfg, (), db.clone(), jobs).await;
+  // one last snapshot on the way out
+  if let Some(p) = &snapshot_path {
+    info!("Saving snapshot to {}...", p.display());
+    if let Err(e) = db.save_snapshot(p) {
+      warn!("Failed to save snapshot: {}", e);
+    }
+  }
+  return res;
+}
+
+/// Serves from whichever database the configuration says.
+async fn serve_configured(cfg: ApiConfig) -> std::io::Result<()> {
+  match cfg.database.backend() {
+    ApiDatabaseType::InMemory => return serve_in_memory(cfg).await,
+    ApiDatabaseType::Redis => {
+      let url = cfg.database.redis_url.clone().unwrap_or_default();
+      info!("Connecting to Redis...");
+      let db = RedisApiDatabase::connect(&url)
+        .unwrap_or_else(|e| panic!("Redis tragedy: {}", e));
+      db.setup();
+      return serve(cfg, url, Blocking::new(db), Jobs::default()).await;
+    },
+    ApiDatabaseType::Sled => {
+      let path = cfg.database.sled_path.clone().unwrap_or_default();
+      info!("Opening sled database at {}...", path.display());
+      let db = SledApiDatabase::open(&path)
+        .unwrap_or_else(|e| panic!("sled tragedy: {}", e));
+      db.setup();
+      let blocking = Blocking::new(db.clone());
+      let res = serve(cfg, path, blocking, Jobs::default()).await;
+      if let Err(e) = db.flush() {
+        warn!("Failed to flush sled: {}", e);
+      }
+      return res;
+    },
+  };
+}
+
+/// Serves with the configuration in source, laid out like cdp_api.toml,
+/// until the server stops. For running the API in the same process as other
+/// things, like cdp_all does.
+pub fn run(source: ::config::Config) -> std::io::Result<()> {
+  let cfg = config::load_from(source)
+    .unwrap_or_else(|e| panic!("Configuration tragedy: {:#?}", e));
+  return actix_web::rt::System::new("cdp_api")
+    .block_on(serve_configured(cfg));
+}
+
+/// API entry point. Read config, connect to database, and setup services.
+#[actix_web::main]
+pub async fn main() -> std::io::Result<()> {
+  libcdp::runtime::init("cdp_api");
+  // first, load up config
+  let mut args: Vec<String> = std::env::args().skip(1).collect();
+  let given = libcdp::runtime::config_arg(&mut args).unwrap_or_else(|e| {
+    eprintln!("{}", e);
+    std::process::exit(2);
+  });
+  let cfg = config::load_defaults(given.as_deref())
+    .unwrap_or_else(|e| panic!("Configuration tragedy: {:#?}", e));
+  // maybe we're only here to move data around
+  if args.first().map(|a| a == "migrate").unwrap_or(false) {
+    match migrate::run(&args[1..], &cfg) {
+      Ok(report) => info!("Done! Copied {}.", report),
+      Err(e) => {
+        warn!("Migration failed: {}", e);
+        std::process::exit(1);
+      },
+    }
+    return Ok(());
+  }
+  // now, on to the database.
+  return serve_configured(cfg).await;
+}
@@ -0,0 +1,341 @@
+//! Copies everything from one database backend to another, so one can start
+//! small and move on later. Run as:
+//!
+//! `cdp_api migrate --from <where> --to <where>`
+//!
+//! Where a database lives is written as:
+//! - `redis://...` or `rediss://...` for Redis;
+//! - `sled:<directory>` for sled;
+//! - anything else is the path of an in-memory database snapshot.
+//!
+//! The destination should be empty: messages are appended, not merged.
+
+use std::collections::HashSet;
+use std::error::Error as StdError;
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use uuid::Uuid;
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayloadType};
+use libcdp::ids::MessageId;
+
+use crate::config::ApiConfig;
+use crate::db::{ApiDatabase, MessageFilter};
+use crate::db::inmem::InMemoryApiDatabase;
+use crate::db::redisdb::RedisApiDatabase;
+use crate::db::sleddb::SledApiDatabase;
+
+/// Messages inserted per batch.
+const BATCH_LEN: usize = 1000;
+
+/// Where a database lives.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum DbLocation {
+  /// An in-memory database snapshot file.
+  Snapshot(PathBuf),
+  /// A Redis URL.
+  Redis(String),
+  /// A sled directory.
+  Sled(PathBuf)
+}
+
+impl From<&str> for DbLocation {
+  fn from(s: &str) -> Self {
+    if s.starts_with("redis://") || s.starts_with("rediss://") {
+      return DbLocation::Redis(s.to_owned());
+    }
+    if let Some(path) = s.strip_prefix("sled:") {
+      return DbLocation::Sled(PathBuf::from(path));
+    }
+    return DbLocation::Snapshot(PathBuf::from(s));
+  }
+}
+
+impl Display for DbLocation {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      DbLocation::Snapshot(p) => return write!(f, "snapshot {}", p.display()),
+      DbLocation::Redis(url) => return write!(f, "Redis at {}", url),
+      DbLocation::Sled(p) => return write!(f, "sled at {}", p.display()),
+    }
+  }
+}
+
+/// Things that can go wrong while migrating.
+#[derive(Debug)]
+pub(crate) enum MigrationError {
+  /// Bad command line. String is usage hints.
+  Usage(String),
+  /// Couldn't open or save one of the databases.
+  Open(String),
+  /// Couldn't read from the source.
+  Read(String),
+  /// Couldn't write to the destination.
+  Write(String),
+  /// A backup got bigger than this many bytes once unzipped.
+  TooBig(u64)
+}
+
+impl StdError for MigrationError {}
+
+impl Display for MigrationError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      MigrationError::Usage(e) => return write!(f, "{}", e),
+      MigrationError::Open(e) => return write!(f, "Couldn't open: {}", e),
+      MigrationError::Read(e) => return write!(f, "Couldn't read: {}", e),
+      MigrationError::Write(e) => return write!(f, "Couldn't write: {}", e),
+      MigrationError::TooBig(n) => {
+        return write!(f, "Over {} bytes once unzipped.", n);
+      },
+    }
+  }
+}
+
+/// How much got copied.
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct MigrationReport {
+  pub(crate) topics: usize,
+  pub(crate) messages: usize,
+  pub(crate) calibrations: usize,
+  pub(crate) registered_sensors: usize,
+  pub(crate) floorplans: usize,
+  pub(crate) derived_readings: usize,
+  pub(crate) brokers: usize,
+  pub(crate) audit_entries: usize,
+  pub(crate) reports: usize,
+  pub(crate) skipped: usize
+}
+
+impl Display for MigrationReport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return write!(
+      f,
+      "{} topics, {} messages, {} calibrations, {} registered sensors, {} \
+      floorplans, {} derived readings, {} brokers, {} audit entries, {} \
+      reports, {} skipped for being there already",
+      self.topics, self.messages, self.calibrations, self.registered_sensors,
+      self.floorplans, self.derived_readings, self.brokers, self.audit_entries,
+      self.reports, self.skipped
+    );
+  }
+}
+
+/// Copies everything in one database to the other. Derived readings are
+/// only copied for the virtual sensors named.
+pub(crate) fn copy<S: ApiDatabase, T: ApiDatabase>(
+  from: &S, to: &T, derived_names: &[String]
+) -> Result<MigrationReport, MigrationError> {
+  return transfer(from, to, derived_names, false);
+}
+
+/// Like copy, but skips whatever the destination has already: messages,
+/// audit entries and reports by ID, and derived readings by when they were
+/// computed.
+pub(crate) fn merge<S: ApiDatabase, T: ApiDatabase>(
+  from: &S, to: &T, derived_names: &[String]
+) -> Result<MigrationReport, MigrationError> {
+  return transfer(from, to, derived_names, true);
+}
+
+/// Inserts a batch of messages, minus those already there if skip_known.
+fn insert_batch<T: ApiDatabase>(
+  to: &T, mut batch: Vec<BrokerMessage>, skip_known: bool,
+  report: &mut MigrationReport
+) -> Result<(), MigrationError> {
+  let write_err = |e: &dyn Display| MigrationError::Write(e.to_string());
+  if skip_known {
+    let ids: Vec<MessageId> = batch.iter().filter_map(|m| m.id).collect();
+    let known = to.known_ids(&ids).map_err(|e| write_err(&e))?;
+    let before = batch.len();
+    batch.retain(|m| m.id.map(|id| !known.contains(&id)).unwrap_or(true));
+    report.skipped += before - batch.len();
+  }
+  report.messages += batch.len();
+  return to.insert_messages(batch).map_err(|e| write_err(&e));
+}
+
+/// Copies everything, skipping whatever's there already if skip_known.
+fn transfer<S: ApiDatabase, T: ApiDatabase>(
+  from: &S, to: &T, derived_names: &[String], skip_known: bool
+) -> Result<MigrationReport, MigrationError> {
+  let read_err = |e: S::DbError| MigrationError::Read(e.to_string());
+  let write_err = |e: T::DbError| MigrationError::Write(e.to_string());
+  let mut report = MigrationReport::default();
+  let topics = from.topics().map_err(read_err)?;
+  report.topics = topics.len();
+  to.update_topics(topics).map_err(write_err)?;
+  let mut last_seq = 0;
+  for mtype in BrokerMessagePayloadType::all_types() {
+    let mut batch: Vec<BrokerMessage> = Vec::with_capacity(BATCH_LEN);
+    let all = MessageFilter::of_type(mtype);
+    for msg in from.query(all).map_err(read_err)? {
+      last_seq = last_seq.max(msg.seq.unwrap_or(0));
+      batch.push(msg);
+      if batch.len() == BATCH_LEN {
+        let full = std::mem::take(&mut batch);
+        insert_batch(to, full, skip_known, &mut report)?;
+      }
+    }
+    insert_batch(to, batch, skip_known, &mut report)?;
+  }
+  // so the copied sequence numbers are never handed out again.
+  let next = to.next_seq(1).map_err(write_err)?;
+  if next < last_seq {
+    to.next_seq(last_seq - next).map_err(write_err)?;
+  }
+  for sc in from.calibrations().map_err(read_err)? {
+    to.set_calibration(sc.sensor_type, sc.sensor_id, Some(sc.calibration))
+      .map_err(write_err)?;
+    report.calibrations += 1;
+  }
+  for rs in from.registered_sensors().map_err(read_err)? {
+    to.set_sensor_info(rs.sensor_type, rs.sensor_id, Some(rs.info))
+      .map_err(write_err)?;
+    report.registered_sensors += 1;
+  }
+  if let Some(plan) = from.floorplan().map_err(read_err)? {
+    to.set_floorplan(Some(plan)).map_err(write_err)?;
+    report.floorplans += 1;
+  }
+  for name in derived_names {
+    let known: HashSet<DateTime<Local>> = if skip_known {
+      to.derived_readings(name).map_err(write_err)?
+        .into_iter()
+        .map(|r| r.computed_when)
+        .collect()
+    } else {
+      HashSet::new()
+    };
+    for reading in from.derived_readings(name).map_err(read_err)? {
+      if known.contains(&reading.computed_when) {
+        report.skipped += 1;
+        continue;
+      }
+      to.insert_derived(reading).map_err(write_err)?;
+      report.derived_readings += 1;
+    }
+  }
+  for rec in from.brokers().map_err(read_err)? {
+    to.update_broker(rec).map_err(write_err)?;
+    report.brokers += 1;
+  }
+  let known: HashSet<Uuid> = if skip_known {
+    to.audit_log().map_err(write_err)?.iter().map(|e| e.id).collect()
+  } else {
+    HashSet::new()
+  };
+  for entry in from.audit_log().map_err(read_err)? {
+    if known.contains(&entry.id) {
+      report.skipped += 1;
+      continue;
+    }
+    to.insert_audit(entry).map_err(write_err)?;
+    report.audit_entries += 1;
+  }
+  let known: HashSet<Uuid> = if skip_known {
+    to.reports().map_err(write_err)?.iter().map(|r| r.id).collect()
+  } else {
+    HashSet::new()
+  };
+  for r in from.reports().map_err(read_err)? {
+    if known.contains(&r.id) {
+      report.skipped += 1;
+      continue;
+    }
+    to.insert_report(r).map_err(write_err)?;
+    report.reports += 1;
+  }
+  return Ok(report);
+}
+
+/// Opens the destination and copies the source into it.
+fn copy_to<S: ApiDatabase>(
+  from: &S, to: &DbLocation, derived_names: &[String]
+) -> Result<MigrationReport, MigrationError> {
+  let open_err = |e: &dyn Display| MigrationError::Open(e.to_string());
+  match to {
+    DbLocation::Snapshot(path) => {
+      // a snapshot is overwritten whole, so don't clobber one by accident
+      if path.exists() {
+        return Err(MigrationError::Open(
+          format!("{} already exists", path.display())
+        ));
+      }
+      let db = InMemoryApiDatabase::default();
+      let report = copy(from, &db, derived_names)?;
+      db.save_snapshot(path).map_err(|e| open_err(&e))?;
+      return Ok(report);
+    },
+    DbLocation::Redis(url) => {
+      let db = RedisApiDatabase::connect(url).map_err(|e| open_err(&e))?;
+      db.setup();
+      return copy(from, &db, derived_names);
+    },
+    DbLocation::Sled(path) => {
+      let db = SledApiDatabase::open(path).map_err(|e| open_err(&e))?;
+      db.setup();
+      let report = copy(from, &db, derived_names)?;
+      db.flush().map_err(|e| open_err(&e))?;
+      return Ok(report);
+    },
+  }
+}
+
+/// Opens both databases and copies one into the other.
+pub(crate) fn migrate(
+  from: &DbLocation, to: &DbLocation, derived_names: &[String]
+) -> Result<MigrationReport, MigrationError> {
+  if from == to {
+    return Err(MigrationError::Usage("Source and destination are the same."
+      .to_owned()));
+  }
+  let open_err = |e: &dyn Display| MigrationError::Open(e.to_string());
+  match from {
+    DbLocation::Snapshot(path) => {
+      let db = InMemoryApiDatabase::load_snapshot(path)
+        .map_err(|e| open_err(&e))?;
+      return copy_to(&db, to, derived_names);
+    },
+    DbLocation::Redis(url) => {
+      let db = RedisApiDatabase::connect(url).map_err(|e| open_err(&e))?;
+      return copy_to(&db, to, derived_names);
+    },
+    DbLocation::Sled(path) => {
+      let db = SledApiDatabase::open(path).map_err(|e| open_err(&e))?;
+      return copy_to(&db, to, derived_names);
+    },
+  }
+}
+
+/// Runs the migrate command, given the arguments after "migrate".
+pub(crate) fn run(args: &[String], cfg: &ApiConfig)
+-> Result<MigrationReport, MigrationError> {
+  let usage = || MigrationError::Usage(
+    "Usage: cdp_api migrate --from <where> --to <where>".to_owned()
+  );
+  let mut from: Option<DbLocation> = None;
+  let mut to: Option<DbLocation> = None;
+  let mut it = args.iter();
+  while let Some(arg) = it.next() {
+    let value = it.next().ok_or_else(usage)?;
+    match arg.as_str() {
+      "--from" => from = Some(DbLocation::from(value.as_str())),
+      "--to" => to = Some(DbLocation::from(value.as_str())),
+      _ => return Err(usage()),
+    }
+  }
+  let (from, to) = match (from, to) {
+    (Some(f), Some(t)) => (f, t),
+    _ => return Err(usage()),
+  };
+  let derived_names: Vec<String> = cfg.derived
+    .iter()
+    .map(|ds| ds.name.clone())
+    .collect();
+  info!("Migrating from {} to {}...", from, to);
+  return migrate(&from, &to, &derived_names);
+}
@@ -0,0 +1,247 @@
+//! Telling humans about alerts nobody acknowledged. Channels are configured
+//! by name under [alerts.channels], and escalation steps say which ones to
+//! notify after how long, per severity. A thread of its own checks every
+//! so often, so a slow webhook never holds up ingestion.
+//!
+//! Other things worth telling, like the system being armed, go out through
+//! the same channels as a Notice.
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::thread;
+use std::time::Duration;
+
+use chrono::Local;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::alerts::{Alert, AlertBook};
+use crate::supervisor::{Jobs, Restart};
+
+/// How often unacknowledged alerts are checked on.
+const ESCALATION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Somewhere to send notifications to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum ChannelConfig {
+  /// POSTs the alert, as JSON, to a URL.
+  Webhook {
+    /// Where to.
+    url: String
+  },
+  /// Has a Telegram bot message a chat.
+  Telegram {
+    /// The bot's token, as BotFather gave it.
+    bot_token: String,
+    /// Which chat.
+    chat_id: String
+  },
+  /// Sends an email through the local sendmail.
+  Email {
+    /// Who to.
+    to: Vec<String>,
+    /// Who from. None means whatever sendmail says.
+    from: Option<String>,
+    /// Where sendmail is.
+    #[serde(default = "ChannelConfig::default_sendmail")]
+    sendmail: PathBuf
+  }
+}
+
+impl ChannelConfig {
+  /// For serde.
+  fn default_sendmail() -> PathBuf {
+    return PathBuf::from("/usr/sbin/sendmail");
+  }
+
+  /// What's wrong with this channel that would keep anything from going
+  /// out through it, if something we can tell without sending is.
+  pub(crate) fn problem(&self) -> Option<String> {
+    return match self {
+      ChannelConfig::Webhook { url } => reqwest::Url::parse(url).err()
+        .map(|e| format!("bad webhook URL {:?}: {}", url, e)),
+      ChannelConfig::Telegram { bot_token, chat_id } => {
+        if bot_token.is_empty() || chat_id.is_empty() {
+          Some("Telegram bot token or chat ID is empty".to_owned())
+        } else {
+          None
+        }
+      },
+      ChannelConfig::Email { to, sendmail, .. } => {
+        if to.is_empty() {
+          Some("no email recipients".to_owned())
+        } else if !sendmail.is_file() {
+          Some(format!("no sendmail at {}", sendmail.display()))
+        } else {
+          None
+        }
+      },
+    };
+  }
+}
+
+/// Why a notification didn't go out.
+#[derive(Debug)]
+pub(crate) enum NotifyError {
+  /// Couldn't make the request, or it was refused.
+  Http(reqwest::Error),
+  /// Couldn't run sendmail.
+  Sendmail(std::io::Error),
+  /// sendmail ran, but failed.
+  SendmailFailed(std::process::ExitStatus)
+}
+
+impl std::error::Error for NotifyError {}
+
+impl Display for NotifyError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      NotifyError::Http(e) => return write!(f, "HTTP error: {}", e),
+      NotifyError::Sendmail(e) => {
+        return write!(f, "Couldn't run sendmail: {}", e);
+      },
+      NotifyError::SendmailFailed(st) => {
+        return write!(f, "sendmail failed: {}", st);
+      },
+    }
+  }
+}
+
+impl From<reqwest::Error> for NotifyError {
+  fn from(e: reqwest::Error) -> Self {
+    return Self::Http(e);
+  }
+}
+
+impl From<std::io::Error> for NotifyError {
+  fn from(e: std::io::Error) -> Self {
+    return Self::Sendmail(e);
+  }
+}
+
+/// Something to tell humans about.
+#[derive(Clone, Debug)]
+pub(crate) struct Notice {
+  /// What it is, in a line.
+  pub(crate) headline: String,
+  /// What it is, in a few lines.
+  pub(crate) body: String,
+  /// What webhooks get, as JSON.
+  pub(crate) payload: Value
+}
+
+impl From<&Alert> for Notice {
+  fn from(alert: &Alert) -> Self {
+    let headline = format!("[{}] {}", alert.severity, alert.summary);
+    let since = alert.opened_when()
+      .map(|w| w.to_rfc3339())
+      .unwrap_or_default();
+    return Self {
+      body: format!(
+        "{}\n\nAlert {}, unacknowledged since {}.\n",
+        headline, alert.id, since
+      ),
+      headline: headline,
+      payload: serde_json::to_value(alert).unwrap_or(Value::Null)
+    };
+  }
+}
+
+/// Pipes an email through sendmail.
+async fn sendmail(
+  path: &Path, to: &[String], from: Option<&str>, notice: &Notice
+) -> Result<(), NotifyError> {
+  let mut mail = format!("To: {}\n", to.join(", "));
+  if let Some(from) = from {
+    mail.push_str(&format!("From: {}\n", from));
+  }
+  mail.push_str(&format!("Subject: {}\n\n{}", notice.headline, notice.body));
+  let mut child = Command::new(path)
+    .arg("-t")
+    .stdin(Stdio::piped())
+    .spawn()?;
+  if let Some(mut stdin) = child.stdin.take() {
+    stdin.write_all(mail.as_bytes()).await?;
+  }
+  let status = child.wait().await?;
+  if !status.success() {
+    return Err(NotifyError::SendmailFailed(status));
+  }
+  return Ok(());
+}
+
+/// Tells a channel about something.
+pub(crate) async fn send(client: &Client, ch: &ChannelConfig, notice: &Notice)
+-> Result<(), NotifyError> {
+  match ch {
+    ChannelConfig::Webhook { url } => {
+      let req = client.post(url).json(&notice.payload);
+      req.send().await?.error_for_status()?;
+    },
+    ChannelConfig::Telegram { bot_token, chat_id } => {
+      let url = format!(
+        "https://api.telegram.org/bot{}/sendMessage", bot_token
+      );
+      let msg = serde_json::json!({ "chat_id": chat_id, "text": notice.body });
+      client.post(&url).json(&msg).send().await?.error_for_status()?;
+    },
+    ChannelConfig::Email { to, from, sendmail: path } => {
+      sendmail(path, to, from.as_deref(), notice).await?;
+    },
+  }
+  return Ok(());
+}
+
+/// Checks on unacknowledged alerts forever, as a job, and notifies whoever
+/// their escalation steps say once they're due.
+pub(crate) fn spawn_escalator(jobs: &Jobs, alerts: AlertBook) {
+  if !alerts.escalates() {
+    return;
+  }
+  jobs.spawn("escalator", Restart::Always, move || {
+    let rt = tokio::runtime::Runtime::new()
+      .unwrap_or_else(|e| panic!("Escalation runtime tragedy: {}", e));
+    let client = Client::new();
+    let alerts = alerts.clone();
+    rt.block_on(async move {
+      loop {
+        tokio::time::sleep(ESCALATION_CHECK_INTERVAL).await;
+        for (alert, channels) in alerts.escalate(Local::now()) {
+          let notice = Notice::from(&alert);
+          for (name, ch) in channels {
+            if let Err(e) = send(&client, &ch, &notice).await {
+              warn!("Couldn't notify {} of {}: {}", name, alert.id, e);
+            }
+          }
+        }
+      }
+    });
+  });
+}
+
+/// Tells some channels about something, on a thread of its own, so whoever
+/// asked needn't wait.
+pub(crate) fn spawn_send(
+  channels: Vec<(String, ChannelConfig)>, notice: Notice
+) {
+  if channels.is_empty() {
+    return;
+  }
+  thread::spawn(move || {
+    let rt = tokio::runtime::Runtime::new()
+      .unwrap_or_else(|e| panic!("Notification runtime tragedy: {}", e));
+    let client = Client::new();
+    rt.block_on(async move {
+      for (name, ch) in channels {
+        if let Err(e) = send(&client, &ch, &notice).await {
+          warn!("Couldn't notify {} of {}: {}", name, notice.headline, e);
+        }
+      }
+    });
+  });
+}
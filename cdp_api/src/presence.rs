@@ -0,0 +1,149 @@
+//! Who's home, as their phones say, and arming the system by it. Phones
+//! report through POST /presence, either plainly or as OwnTracks region
+//! transitions, and once everybody's away, or somebody's back, the system
+//! can go to whatever state the config says.
+//!
+//! Presence is only kept in memory: after a restart, nobody is anywhere
+//! until their phone says so.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::arming::{Arming, ArmingChange, ArmingState};
+
+/// Presence settings, as they lie in the config file.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct PresenceConfig {
+  /// Bearer token phones report with. The admin token works too. None
+  /// means only the admin token does.
+  pub(crate) token: Option<String>,
+  /// Who lives here. Empty means whoever reports.
+  #[serde(default)]
+  pub(crate) people: Vec<String>,
+  /// What to arm to once everybody's away. None means leave it be.
+  pub(crate) all_away: Option<ArmingState>,
+  /// What to go to once somebody's back after everybody was away. None
+  /// means leave it be.
+  pub(crate) someone_home: Option<ArmingState>
+}
+
+/// Where somebody is.
+#[derive(
+  Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash
+)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PresenceState {
+  /// Here.
+  Home,
+  /// Not here.
+  Away
+}
+
+/// Where somebody was last said to be.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct PersonPresence {
+  /// Where.
+  pub(crate) state: PresenceState,
+  /// Since when.
+  pub(crate) since: DateTime<Local>
+}
+
+/// Why a report didn't go through.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum PresenceError {
+  /// Nobody by that name lives here.
+  Stranger(String)
+}
+
+impl std::error::Error for PresenceError {}
+
+impl Display for PresenceError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      PresenceError::Stranger(p) => write!(f, "{} doesn't live here.", p),
+    };
+  }
+}
+
+/// Everybody's presence. Cheap to clone, all clones share the same people.
+#[derive(Clone, Debug)]
+pub(crate) struct Presence {
+  /// Presence settings.
+  cfg: Arc<PresenceConfig>,
+  /// The system to arm and disarm.
+  arming: Arming,
+  /// Where everybody is, by name.
+  people: Arc<Mutex<HashMap<String, PersonPresence>>>
+}
+
+impl Presence {
+  /// Sets it up, with nobody anywhere.
+  pub(crate) fn new(cfg: &PresenceConfig, arming: Arming) -> Self {
+    return Self {
+      cfg: Arc::new(cfg.clone()),
+      arming: arming,
+      people: Arc::default()
+    };
+  }
+
+  /// The token phones report with, if any.
+  pub(crate) fn token(&self) -> Option<&str> {
+    return self.cfg.token.as_deref();
+  }
+
+  /// Where everybody is, by name.
+  pub(crate) fn snapshot(&self) -> HashMap<String, PersonPresence> {
+    return self.people.lock().unwrap_or_else(|e| e.into_inner()).clone();
+  }
+
+  /// Whether everybody is known to be away.
+  fn all_away(&self, people: &HashMap<String, PersonPresence>) -> bool {
+    let away = |name: &String| people.get(name)
+      .map(|p| p.state == PresenceState::Away)
+      .unwrap_or(false);
+    if self.cfg.people.is_empty() {
+      return !people.is_empty() && people.keys().all(away);
+    }
+    return self.cfg.people.iter().all(away);
+  }
+
+  /// Notes where somebody is, and arms or disarms the system if that
+  /// makes everybody away, or somebody back. Returns the arming change, if
+  /// there was one.
+  pub(crate) fn report(&self, person: String, state: PresenceState)
+  -> Result<Option<ArmingChange>, PresenceError> {
+    if !self.cfg.people.is_empty() && !self.cfg.people.contains(&person) {
+      return Err(PresenceError::Stranger(person));
+    }
+    let mut people = self.people.lock().unwrap_or_else(|e| e.into_inner());
+    let was_all_away = self.all_away(&people);
+    let moved = people.get(&person).map(|p| p.state) != Some(state);
+    if moved {
+      people.insert(person.clone(), PersonPresence {
+        state: state,
+        since: Local::now()
+      });
+    }
+    let is_all_away = self.all_away(&people);
+    drop(people);
+    let target = match (was_all_away, is_all_away) {
+      (false, true) => self.cfg.all_away,
+      (true, false) => self.cfg.someone_home,
+      _ => None,
+    };
+    let target = match target {
+      Some(t) if t != self.arming.state() => t,
+      _ => return Ok(None),
+    };
+    let note = match state {
+      PresenceState::Home => format!("{} came home.", person),
+      PresenceState::Away => format!("{} left, and nobody's home.", person),
+    };
+    let by = format!("presence:{}", person);
+    return Ok(self.arming.set(target, by, Some(note)).ok());
+  }
+}
@@ -0,0 +1,231 @@
+//! Token-bucket rate limiting, so a misbehaving broker or some random client
+//! can't hog the ingestion path.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use libcdp::comm::broker_api::BrokerMessage;
+use libcdp::ids::BrokerId;
+use serde::{Deserialize, Serialize};
+
+/// Forget about idle buckets once we're tracking this many keys.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Rate limiting configuration, as it lies in the config file.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct RateLimitConfig {
+  /// Requests per minute allowed from a single IP. None means no limit.
+  pub(crate) per_ip_per_minute: Option<u32>,
+  /// Bundles per minute allowed from a single broker. None means no limit.
+  pub(crate) per_broker_bundles_per_minute: Option<u32>,
+  /// Messages per minute allowed from a single broker, however they're
+  /// bundled. None means no limit.
+  pub(crate) per_broker_messages_per_minute: Option<u32>,
+  /// How many requests can be made in a quick burst before the per-minute
+  /// rate kicks in. Defaults to a tenth of the rate, at least 1.
+  pub(crate) burst: Option<u32>
+}
+
+/// A single token bucket.
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+  /// Tokens currently available.
+  tokens: f64,
+  /// Last time we refilled.
+  last: Instant
+}
+
+/// A keyed token-bucket limiter. Cheap to clone, all clones share the same
+/// buckets.
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimiter<K: Eq + Hash> {
+  /// Tokens refilled per second.
+  rate: f64,
+  /// Bucket capacity.
+  burst: f64,
+  /// The buckets.
+  buckets: Arc<Mutex<HashMap<K, Bucket>>>,
+  /// How many requests we turned down.
+  rejected: Arc<AtomicU64>
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+  /// Creates a limiter allowing per_minute hits, with the given burst.
+  pub(crate) fn new(per_minute: u32, burst: Option<u32>) -> Self {
+    let burst = burst.unwrap_or(per_minute / 10).max(1);
+    return Self {
+      rate: per_minute as f64 / 60.0,
+      burst: burst as f64,
+      buckets: Arc::new(Mutex::new(HashMap::new())),
+      rejected: Arc::new(AtomicU64::new(0))
+    };
+  }
+
+  /// Takes a token for the key. False means the key is over its limit.
+  pub(crate) fn check(&self, key: K) -> bool {
+    return self.check_n(key, 1);
+  }
+
+  /// Takes n tokens for the key. As long as it has one left, it may go into
+  /// debt for the rest, and then waits for the bucket to refill past that,
+  /// so even a hit heavier than the burst goes through, once. False means
+  /// the key is over its limit.
+  pub(crate) fn check_n(&self, key: K, n: usize) -> bool {
+    let now = Instant::now();
+    let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+    if buckets.len() >= MAX_TRACKED_KEYS {
+      let (rate, burst) = (self.rate, self.burst);
+      buckets.retain(|_, b| {
+        b.tokens + now.duration_since(b.last).as_secs_f64() * rate < burst
+      });
+    }
+    let burst = self.burst;
+    let bucket = buckets.entry(key).or_insert(Bucket {
+      tokens: burst,
+      last: now
+    });
+    let elapsed = now.duration_since(bucket.last).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+    bucket.last = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= n as f64;
+      return true;
+    }
+    self.rejected.fetch_add(1, Ordering::Relaxed);
+    return false;
+  }
+
+  /// How many requests were turned down so far.
+  pub(crate) fn rejected(&self) -> u64 {
+    return self.rejected.load(Ordering::Relaxed);
+  }
+}
+
+/// All the limiters the API uses.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RateLimits {
+  /// Limits every request, by peer IP.
+  pub(crate) per_ip: Option<RateLimiter<IpAddr>>,
+  /// Limits bundles, by broker.
+  pub(crate) per_broker: Option<RateLimiter<BrokerId>>,
+  /// Limits messages, by broker.
+  pub(crate) per_broker_messages: Option<RateLimiter<BrokerId>>
+}
+
+impl From<&RateLimitConfig> for RateLimits {
+  fn from(cfg: &RateLimitConfig) -> Self {
+    return Self {
+      per_ip: cfg.per_ip_per_minute.map(|r| RateLimiter::new(r, cfg.burst)),
+      per_broker: cfg.per_broker_bundles_per_minute
+        .map(|r| RateLimiter::new(r, cfg.burst)),
+      per_broker_messages: cfg.per_broker_messages_per_minute
+        .map(|r| RateLimiter::new(r, cfg.burst))
+    };
+  }
+}
+
+impl RateLimits {
+  /// Whether a request from this IP may go through.
+  pub(crate) fn check_ip(&self, ip: Option<IpAddr>) -> bool {
+    return match (&self.per_ip, ip) {
+      (Some(rl), Some(ip)) => rl.check(ip),
+      _ => true,
+    };
+  }
+
+  /// Whether a bundle with this many messages from this broker may go
+  /// through.
+  pub(crate) fn check_broker(&self, broker_id: BrokerId, messages: usize)
+  -> bool {
+    let bundles = match &self.per_broker {
+      Some(rl) => rl.check(broker_id),
+      None => true,
+    };
+    return bundles && match &self.per_broker_messages {
+      Some(rl) => rl.check_n(broker_id, messages),
+      None => true,
+    };
+  }
+
+  /// Whether a batch may go through, checking every broker with messages
+  /// in it for as many messages as it has there. Brokers checked before one
+  /// over its limit stay charged.
+  pub(crate) fn check_batch(&self, batch: &[BrokerMessage]) -> bool {
+    let mut counts: BTreeMap<BrokerId, usize> = BTreeMap::new();
+    for msg in batch {
+      *counts.entry(msg.broker_id).or_insert(0) += 1;
+    }
+    return counts.into_iter().all(|(b, n)| self.check_broker(b, n));
+  }
+
+  /// Appends our metrics, in Prometheus text format, to out.
+  pub(crate) fn render_prometheus(&self, out: &mut String) {
+    let _ = writeln!(out, "# HELP cdp_rate_limited_requests_total Requests \
+      turned down for going over a rate limit.");
+    let _ = writeln!(out, "# TYPE cdp_rate_limited_requests_total counter");
+    if let Some(rl) = &self.per_ip {
+      let _ = writeln!(
+        out, "cdp_rate_limited_requests_total{{limit=\"ip\"}} {}",
+        rl.rejected()
+      );
+    }
+    if let Some(rl) = &self.per_broker {
+      let _ = writeln!(
+        out, "cdp_rate_limited_requests_total{{limit=\"broker\"}} {}",
+        rl.rejected()
+      );
+    }
+    if let Some(rl) = &self.per_broker_messages {
+      let _ = writeln!(
+        out,
+        "cdp_rate_limited_requests_total{{limit=\"broker_messages\"}} {}",
+        rl.rejected()
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn allows_a_burst_then_turns_away() {
+    // a token a minute never refills within a test
+    let rl = RateLimiter::new(1, Some(3));
+    assert!((0..3).all(|_| rl.check(1)));
+    assert!(!rl.check(1));
+    assert!(rl.check(2));
+    assert_eq!(rl.rejected(), 1);
+  }
+
+  #[test]
+  fn bursts_default_to_a_tenth_of_the_rate() {
+    let rl = RateLimiter::new(50, None);
+    assert_eq!((0..10).filter(|_| rl.check(1)).count(), 5);
+    let rl = RateLimiter::new(1, None);
+    assert_eq!((0..10).filter(|_| rl.check(1)).count(), 1);
+  }
+
+  #[test]
+  fn heavy_hits_go_into_debt() {
+    let rl = RateLimiter::new(1, Some(2));
+    assert!(rl.check_n(1, 5));
+    assert!(!rl.check(1));
+    assert!(!rl.check_n(1, 1));
+    assert_eq!(rl.rejected(), 2);
+  }
+
+  #[test]
+  fn refills_over_time() {
+    let rl = RateLimiter::new(60_000, Some(1));
+    assert!(rl.check(1));
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    assert!(rl.check(1));
+  }
+}
@@ -0,0 +1,411 @@
+//! Daily and weekly summaries: how each sensor did, how many alerts opened,
+//! and how much of the time each broker was up. Once a day, or week, is
+//! over, its report is made, stored in the database, and sent to whichever
+//! alert channels are listed under notify, on a thread of its own.
+//!
+//! Days start at local midnight, and weeks on Monday. A broker counts as up
+//! for a slot of the period if anything of its arrived in it, so brokers
+//! that only talk when there's news look worse than they are.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::time::Duration as StdDuration;
+
+use chrono::{Date, DateTime, Datelike, Duration, Local};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload, BrokerMessagePayloadType};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, SensorId};
+
+use crate::alerts::AlertBook;
+use crate::db::{ApiDatabase, MessageFilter};
+use crate::distribution::{Summary, SummaryView};
+use crate::notify::{self, ChannelConfig, Notice};
+use crate::supervisor::{Jobs, Restart};
+
+/// How often we check whether a report is due.
+const REPORT_CHECK_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
+/// Report settings, as they lie in the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ReportConfig {
+  /// Whether to make daily reports.
+  #[serde(default)]
+  pub(crate) daily: bool,
+  /// Whether to make weekly reports.
+  #[serde(default)]
+  pub(crate) weekly: bool,
+  /// Length of the slots broker uptime is counted in, in minutes.
+  #[serde(default = "ReportConfig::default_uptime_slot_minutes")]
+  pub(crate) uptime_slot_minutes: u32,
+  /// Names of the alert channels to send reports to.
+  #[serde(default)]
+  pub(crate) notify: Vec<String>
+}
+
+impl ReportConfig {
+  /// For serde.
+  fn default_uptime_slot_minutes() -> u32 {
+    return 10;
+  }
+
+  /// Checks that notify names known channels, and that slots have a
+  /// length.
+  pub(crate) fn check(&self, channels: &HashMap<String, ChannelConfig>)
+  -> Result<(), String> {
+    if self.uptime_slot_minutes == 0 {
+      return Err("Uptime slots can't be empty.".to_owned());
+    }
+    for name in &self.notify {
+      if !channels.contains_key(name) {
+        return Err(format!("Unknown alert channel \"{}\".", name));
+      }
+    }
+    return Ok(());
+  }
+
+  /// Periods to make reports for.
+  fn periods(&self) -> Vec<ReportPeriod> {
+    let mut periods = Vec::new();
+    if self.daily {
+      periods.push(ReportPeriod::Daily);
+    }
+    if self.weekly {
+      periods.push(ReportPeriod::Weekly);
+    }
+    return periods;
+  }
+}
+
+impl Default for ReportConfig {
+  fn default() -> Self {
+    return Self {
+      daily: false,
+      weekly: false,
+      uptime_slot_minutes: Self::default_uptime_slot_minutes(),
+      notify: Vec::new()
+    };
+  }
+}
+
+/// How long a report covers.
+#[derive(
+  Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash
+)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReportPeriod {
+  /// A day, from midnight.
+  Daily,
+  /// A week, from Monday.
+  Weekly
+}
+
+impl Display for ReportPeriod {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return write!(f, "{}", match self {
+      ReportPeriod::Daily => "daily",
+      ReportPeriod::Weekly => "weekly",
+    });
+  }
+}
+
+/// The first moment of a day. Midnight, unless a DST change skipped it.
+fn start_of(date: Date<Local>) -> DateTime<Local> {
+  return date.and_hms_opt(0, 0, 0)
+    .or_else(|| date.and_hms_opt(1, 0, 0))
+    .unwrap_or_else(|| date.and_hms(2, 0, 0));
+}
+
+impl ReportPeriod {
+  /// Start and end of the latest one that's over by a time.
+  pub(crate) fn last_over(self, now: DateTime<Local>)
+  -> (DateTime<Local>, DateTime<Local>) {
+    let today = now.date();
+    return match self {
+      ReportPeriod::Daily => {
+        (start_of(today - Duration::days(1)), start_of(today))
+      },
+      ReportPeriod::Weekly => {
+        let back = today.weekday().num_days_from_monday();
+        let monday = today - Duration::days(back.into());
+        (start_of(monday - Duration::weeks(1)), start_of(monday))
+      },
+    };
+  }
+}
+
+/// How a sensor did over a period.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SensorReport {
+  /// Type of the sensor.
+  pub(crate) sensor_type: SensorType,
+  /// ID of the sensor.
+  pub(crate) sensor_id: SensorId,
+  /// Its name, if it's registered with one.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) name: Option<String>,
+  /// How its readings went, in human units.
+  #[serde(flatten)]
+  pub(crate) readings: SummaryView
+}
+
+/// Alerts opened over a period.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct AlertCounts {
+  /// How many.
+  pub(crate) opened: usize,
+  /// How many of each kind.
+  pub(crate) by_kind: BTreeMap<String, usize>,
+  /// How many of each severity.
+  pub(crate) by_severity: BTreeMap<String, usize>
+}
+
+/// How much of a period a broker was up.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct BrokerUptime {
+  /// The broker's UID.
+  pub(crate) uid: BrokerId,
+  /// Messages of its that arrived.
+  pub(crate) messages: usize,
+  /// Share of the slots it was up in, from 0 to 1.
+  pub(crate) uptime: f64
+}
+
+/// A summary of a period.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Report {
+  /// Unique ID.
+  pub(crate) id: Uuid,
+  /// How long it covers.
+  pub(crate) period: ReportPeriod,
+  /// Start of what it covers, inclusive.
+  pub(crate) from: DateTime<Local>,
+  /// End of what it covers, exclusive.
+  pub(crate) to: DateTime<Local>,
+  /// When it was made.
+  pub(crate) generated_when: DateTime<Local>,
+  /// Every sensor heard from, by type and ID.
+  pub(crate) sensors: Vec<SensorReport>,
+  /// Alerts opened. Only counts those this instance still keeps.
+  pub(crate) alerts: AlertCounts,
+  /// Every broker heard from, by UID.
+  pub(crate) brokers: Vec<BrokerUptime>
+}
+
+impl From<&Report> for Notice {
+  fn from(report: &Report) -> Self {
+    let day = report.from.format("%Y-%m-%d");
+    let headline = match report.period {
+      ReportPeriod::Daily => format!("Daily report for {}", day),
+      ReportPeriod::Weekly => format!("Weekly report for the week of {}", day),
+    };
+    let mut body = format!("{}\n\nSensors:\n", headline);
+    for sr in &report.sensors {
+      let label = match &sr.name {
+        Some(n) => format!("{}:{} ({})", sr.sensor_type, sr.sensor_id, n),
+        None => format!("{}:{}", sr.sensor_type, sr.sensor_id),
+      };
+      let r = &sr.readings;
+      let num = |x: Option<f64>| x.map(|x| format!("{:.1}", x))
+        .unwrap_or_else(|| "-".to_owned());
+      body.push_str(&format!(
+        "  {}: min {}, max {}, mean {}, {} readings\n",
+        label, num(r.min), num(r.max), num(r.mean), r.count
+      ));
+    }
+    body.push_str(&format!("\nAlerts opened: {}\n", report.alerts.opened));
+    for (kind, n) in &report.alerts.by_kind {
+      body.push_str(&format!("  {}: {}\n", kind, n));
+    }
+    body.push_str("\nBrokers:\n");
+    for bu in &report.brokers {
+      body.push_str(&format!(
+        "  {}: {:.1}% up\n", bu.uid, bu.uptime * 100.0
+      ));
+    }
+    return Self {
+      headline: headline,
+      body: body,
+      payload: serde_json::to_value(report)
+        .unwrap_or(serde_json::Value::Null)
+    };
+  }
+}
+
+/// Which slots of a period each broker was heard from in.
+struct Uptimes {
+  /// Start of the period.
+  from: DateTime<Local>,
+  /// Length of a slot.
+  slot: Duration,
+  /// Messages and slots heard from in, by broker.
+  brokers: HashMap<BrokerId, (usize, HashSet<i64>)>
+}
+
+impl Uptimes {
+  /// Notes a broker's message.
+  fn observe(&mut self, msg: &BrokerMessage) {
+    let since = (msg.constructed_when - self.from).num_milliseconds();
+    let slot = since / self.slot.num_milliseconds();
+    let b = self.brokers.entry(msg.broker_id).or_default();
+    b.0 += 1;
+    b.1.insert(slot);
+  }
+
+  /// Uptime of each broker, by UID.
+  fn into_uptimes(self, to: DateTime<Local>) -> Vec<BrokerUptime> {
+    let slot = self.slot.num_milliseconds();
+    let slots = ((to - self.from).num_milliseconds() + slot - 1) / slot;
+    let mut uptimes: Vec<BrokerUptime> = self.brokers
+      .into_iter()
+      .map(|(uid, (n, seen))| BrokerUptime {
+        uid: uid,
+        messages: n,
+        uptime: seen.len() as f64 / slots.max(1) as f64
+      })
+      .collect();
+    uptimes.sort_by_key(|bu| bu.uid);
+    return uptimes;
+  }
+}
+
+/// Makes the report of a period.
+pub(crate) fn generate<D: ApiDatabase>(
+  db: &D, alerts: &AlertBook, cfg: &ReportConfig, period: ReportPeriod,
+  from: DateTime<Local>, to: DateTime<Local>
+) -> Result<Report, D::DbError> {
+  let mut uptimes = Uptimes {
+    from: from,
+    slot: Duration::minutes(cfg.uptime_slot_minutes.into()),
+    brokers: HashMap::new()
+  };
+  let in_period = |m: &BrokerMessage| {
+    return m.constructed_when >= from && m.constructed_when < to;
+  };
+  // one pass over each sensor type, and one over everything else
+  let mut sensors: BTreeMap<(SensorType, SensorId), Summary> = BTreeMap::new();
+  for stype in SensorType::all_types() {
+    let filter = MessageFilter::default().sensor(stype).between(from, to);
+    for msg in db.query(filter)? {
+      if !in_period(&msg) {
+        continue;
+      }
+      uptimes.observe(&msg);
+      if let BrokerMessagePayload::SensorData(sd) = &msg.payload {
+        sensors
+          .entry((sd.sensor_type(), sd.sensor_id()))
+          .or_insert_with(Summary::new)
+          .observe(sd.reading().human_value());
+      }
+    }
+  }
+  let others = [
+    BrokerMessagePayloadType::Heartbeat,
+    BrokerMessagePayloadType::DeviceHealth
+  ];
+  for mtype in others.iter() {
+    let filter = MessageFilter::of_type(*mtype).between(from, to);
+    for msg in db.query(filter)?.filter(in_period) {
+      uptimes.observe(&msg);
+    }
+  }
+  let names: HashMap<(SensorType, SensorId), String> = db.registered_sensors()?
+    .into_iter()
+    .filter_map(|rs| Some(((rs.sensor_type, rs.sensor_id), rs.info.name?)))
+    .collect();
+  let mut counts = AlertCounts::default();
+  for alert in alerts.list(None) {
+    match alert.opened_when() {
+      Some(w) if w >= from && w < to => {},
+      _ => continue,
+    }
+    counts.opened += 1;
+    *counts.by_kind.entry(alert.kind.name().to_owned()).or_insert(0) += 1;
+    let severity = alert.severity.to_string();
+    *counts.by_severity.entry(severity).or_insert(0) += 1;
+  }
+  return Ok(Report {
+    id: Uuid::new_v4(),
+    period: period,
+    from: from,
+    to: to,
+    generated_when: Local::now(),
+    sensors: sensors
+      .into_iter()
+      .map(|((stype, sensor_id), summary)| SensorReport {
+        sensor_type: stype,
+        sensor_id: sensor_id,
+        name: names.get(&(stype, sensor_id)).cloned(),
+        readings: summary.view()
+      })
+      .collect(),
+    alerts: counts,
+    brokers: uptimes.into_uptimes(to)
+  });
+}
+
+/// Makes reports forever, as a job, as periods end. Periods already
+/// reported on, going by the database, are skipped, so restarts, ours or
+/// the job's, don't repeat them.
+pub(crate) fn spawn_reporter<D: ApiDatabase + 'static>(
+  jobs: &Jobs, cfg: ReportConfig, channels: &HashMap<String, ChannelConfig>,
+  db: D, alerts: AlertBook
+) {
+  let periods = cfg.periods();
+  if periods.is_empty() {
+    return;
+  }
+  let channels: Vec<(String, ChannelConfig)> = cfg.notify.iter()
+    .filter_map(|n| Some((n.clone(), channels.get(n)?.clone())))
+    .collect();
+  jobs.spawn("reporter", Restart::Always, move || {
+    let rt = tokio::runtime::Runtime::new()
+      .unwrap_or_else(|e| panic!("Report runtime tragedy: {}", e));
+    let client = Client::new();
+    let (cfg, db, alerts) = (cfg.clone(), db.clone(), alerts.clone());
+    let (periods, channels) = (periods.clone(), channels.clone());
+    // start of the latest period reported on, by period
+    let mut done: HashMap<ReportPeriod, DateTime<Local>> = HashMap::new();
+    match db.reports() {
+      Ok(reports) => for r in reports {
+        let latest = done.entry(r.period).or_insert(r.from);
+        if r.from > *latest {
+          *latest = r.from;
+        }
+      },
+      Err(e) => warn!("Couldn't read past reports: {}", e),
+    }
+    rt.block_on(async move {
+      loop {
+        for period in periods.iter().copied() {
+          let (from, to) = period.last_over(Local::now());
+          if done.get(&period).map(|d| *d >= from).unwrap_or(false) {
+            continue;
+          }
+          let report = match generate(&db, &alerts, &cfg, period, from, to) {
+            Ok(r) => r,
+            Err(e) => {
+              warn!("Couldn't make the {} report: {}", period, e);
+              continue;
+            },
+          };
+          if let Err(e) = db.insert_report(report.clone()) {
+            warn!("Couldn't store the {} report: {}", period, e);
+            continue;
+          }
+          done.insert(period, from);
+          let notice = Notice::from(&report);
+          for (name, ch) in channels.iter() {
+            if let Err(e) = notify::send(&client, ch, &notice).await {
+              warn!("Couldn't send {} the {} report: {}", name, period, e);
+            }
+          }
+        }
+        tokio::time::sleep(REPORT_CHECK_INTERVAL).await;
+      }
+    });
+  });
+}
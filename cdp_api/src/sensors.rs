@@ -0,0 +1,48 @@
+//! The sensor registry: what people call each sensor, and which room it's
+//! in, so nobody has to remember that humidity:3 is the bathroom.
+
+use serde::{Deserialize, Serialize};
+
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::SensorId;
+
+/// What people call a sensor, and where it is.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SensorInfo {
+  /// Its name, like "Fridge".
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) name: Option<String>,
+  /// Its room, like "Kitchen".
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) room: Option<String>
+}
+
+impl SensorInfo {
+  /// Trims the labels, dropping blank ones.
+  pub(crate) fn tidy(self) -> Self {
+    let tidy = |s: Option<String>| s
+      .map(|s| s.trim().to_owned())
+      .filter(|s| !s.is_empty());
+    return Self {
+      name: tidy(self.name),
+      room: tidy(self.room)
+    };
+  }
+
+  /// Whether there's nothing to it.
+  pub(crate) fn is_empty(&self) -> bool {
+    return self.name.is_none() && self.room.is_none();
+  }
+}
+
+/// A sensor's info, along with the sensor it's about.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RegisteredSensor {
+  /// Type of the sensor.
+  pub(crate) sensor_type: SensorType,
+  /// ID of the sensor.
+  pub(crate) sensor_id: SensorId,
+  /// What it's called, and where.
+  #[serde(flatten)]
+  pub(crate) info: SensorInfo
+}
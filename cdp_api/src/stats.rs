@@ -0,0 +1,477 @@
+//! Ingestion statistics: message counts and rolling rates per broker, per
+//! sensor type and per sensor, so chatty or silent devices stand out, and of
+//! payloads brokers couldn't decode, so garbled ones do too. Also
+//! how long messages take to get here, hop by hop, so lag can be pinned on
+//! the broker or on the way from it.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, SensorId};
+
+/// Width of the rolling window, in seconds.
+pub(crate) const RATE_WINDOW_SECS: i64 = 60;
+/// How many of the latest latencies each figure is computed over.
+pub(crate) const LATENCY_SAMPLES: usize = 1000;
+
+/// Counts hits, in total and in one-second buckets over the rolling window.
+#[derive(Clone, Debug)]
+struct RateCounter {
+  /// Hits since startup.
+  total: u64,
+  /// (unix second, hits) pairs, oldest first.
+  buckets: VecDeque<(i64, u64)>,
+  /// Time of the latest hit.
+  last_seen: DateTime<Local>
+}
+
+impl RateCounter {
+  /// A counter with its first hit.
+  fn new(now: DateTime<Local>) -> Self {
+    let mut rc = Self {
+      total: 0,
+      buckets: VecDeque::new(),
+      last_seen: now
+    };
+    rc.hit(now);
+    return rc;
+  }
+
+  /// Registers a hit.
+  fn hit(&mut self, now: DateTime<Local>) {
+    let sec = now.timestamp();
+    self.total += 1;
+    self.last_seen = now;
+    match self.buckets.back_mut() {
+      Some((s, n)) if *s == sec => *n += 1,
+      _ => self.buckets.push_back((sec, 1)),
+    };
+    self.trim(sec);
+  }
+
+  /// Drops buckets that fell out of the window.
+  fn trim(&mut self, now_sec: i64) {
+    while let Some((s, _)) = self.buckets.front() {
+      if *s > now_sec - RATE_WINDOW_SECS { break; }
+      self.buckets.pop_front();
+    }
+  }
+
+  /// Hits within the window ending now.
+  fn in_window(&self, now_sec: i64) -> u64 {
+    return self.buckets
+      .iter()
+      .filter(|(s, _)| *s > now_sec - RATE_WINDOW_SECS)
+      .map(|(_, n)| n)
+      .sum();
+  }
+
+  /// Snapshot for serialization.
+  fn report(&self, now_sec: i64) -> RateReport {
+    return RateReport {
+      total: self.total,
+      per_minute: self.in_window(now_sec) as f64 * 60.0
+        / RATE_WINDOW_SECS as f64,
+      last_seen: self.last_seen
+    };
+  }
+}
+
+/// Counters and rate of a single thing, as reported to clients.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct RateReport {
+  /// Messages since startup.
+  pub(crate) total: u64,
+  /// Messages per minute, over the rolling window.
+  pub(crate) per_minute: f64,
+  /// Time of the latest message.
+  pub(crate) last_seen: DateTime<Local>
+}
+
+/// The latest latencies of a hop, in milliseconds, oldest first.
+#[derive(Clone, Debug, Default)]
+struct LatencySamples {
+  samples: VecDeque<i64>
+}
+
+impl LatencySamples {
+  /// Registers a latency, forgetting the oldest one if need be.
+  fn add(&mut self, ms: i64) {
+    if self.samples.len() >= LATENCY_SAMPLES {
+      self.samples.pop_front();
+    }
+    self.samples.push_back(ms);
+  }
+
+  /// Snapshot for serialization. None if nothing was ever registered.
+  fn report(&self) -> Option<LatencyReport> {
+    if self.samples.is_empty() {
+      return None;
+    }
+    let mut sorted: Vec<i64> = self.samples.iter().cloned().collect();
+    sorted.sort_unstable();
+    let pct = |p: usize| sorted[(sorted.len() - 1) * p / 100];
+    return Some(LatencyReport {
+      samples: sorted.len(),
+      mean_ms: sorted.iter().sum::<i64>() as f64 / sorted.len() as f64,
+      p50_ms: pct(50),
+      p95_ms: pct(95),
+      max_ms: sorted[sorted.len() - 1]
+    });
+  }
+}
+
+/// Latencies of a single hop, as reported to clients. Hops that cross from
+/// one machine to another also measure how far apart their clocks are, and
+/// may even come out negative.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct LatencyReport {
+  /// How many latencies these figures come from.
+  pub(crate) samples: usize,
+  pub(crate) mean_ms: f64,
+  pub(crate) p50_ms: i64,
+  pub(crate) p95_ms: i64,
+  pub(crate) max_ms: i64
+}
+
+/// Every hop of a single broker.
+#[derive(Clone, Debug, Default)]
+struct HopLatencies {
+  /// Decoded to sent: queueing and bundling within the broker.
+  in_broker: LatencySamples,
+  /// Sent to received: the trip from the broker to us.
+  broker_to_api: LatencySamples,
+  /// Decoded to received: the whole way.
+  end_to_end: LatencySamples
+}
+
+impl HopLatencies {
+  /// Registers whatever hops a message has the timestamps for.
+  fn record(&mut self, msg: &BrokerMessage) {
+    let ms = |from: Option<DateTime<Local>>, to: Option<DateTime<Local>>| {
+      return Some((to? - from?).num_milliseconds());
+    };
+    if let Some(l) = ms(msg.decoded_when, msg.sent_when) {
+      self.in_broker.add(l);
+    }
+    if let Some(l) = ms(msg.sent_when, msg.received_when) {
+      self.broker_to_api.add(l);
+    }
+    if let Some(l) = ms(msg.decoded_when, msg.received_when) {
+      self.end_to_end.add(l);
+    }
+  }
+}
+
+/// Latencies of a single broker's messages, hop by hop. Sensors have no
+/// clock, so the way starts when the broker decodes a reading. None for
+/// hops with nothing to go by, as with brokers too old to say.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct BrokerLatencyReport {
+  pub(crate) broker_id: BrokerId,
+  /// Decoded to sent: queueing and bundling within the broker.
+  pub(crate) in_broker: Option<LatencyReport>,
+  /// Sent to received: the trip from the broker to us.
+  pub(crate) broker_to_api: Option<LatencyReport>,
+  /// Decoded to received: the whole way.
+  pub(crate) end_to_end: Option<LatencyReport>
+}
+
+/// Everything we know about latencies.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct LatencyStatsReport {
+  /// How many of the latest latencies each figure is computed over, at
+  /// most.
+  pub(crate) max_samples: usize,
+  /// Per broker.
+  pub(crate) brokers: Vec<BrokerLatencyReport>
+}
+
+/// Rates of a single broker.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct BrokerRateReport {
+  pub(crate) broker_id: BrokerId,
+  #[serde(flatten)]
+  pub(crate) rate: RateReport
+}
+
+/// Rates of a single sensor type.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct SensorTypeRateReport {
+  pub(crate) sensor_type: SensorType,
+  #[serde(flatten)]
+  pub(crate) rate: RateReport
+}
+
+/// Rates of a single sensor.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct SensorRateReport {
+  pub(crate) sensor_type: SensorType,
+  pub(crate) sensor_id: SensorId,
+  #[serde(flatten)]
+  pub(crate) rate: RateReport
+}
+
+/// Rates of payloads a broker couldn't decode, from a single topic.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct DecodeFailureRateReport {
+  pub(crate) broker_id: BrokerId,
+  pub(crate) topic: String,
+  #[serde(flatten)]
+  pub(crate) rate: RateReport
+}
+
+/// Everything we know about ingestion rates.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct IngestReport {
+  /// Width of the rolling window, in seconds.
+  pub(crate) window_secs: i64,
+  /// Per broker, counting every kind of message.
+  pub(crate) brokers: Vec<BrokerRateReport>,
+  /// Per sensor type, counting sensor data only.
+  pub(crate) sensor_types: Vec<SensorTypeRateReport>,
+  /// Per sensor, counting sensor data only.
+  pub(crate) sensors: Vec<SensorRateReport>,
+  /// Per broker and topic, counting decode failures only.
+  pub(crate) decode_failures: Vec<DecodeFailureRateReport>
+}
+
+/// A broker and the type of sensor a message was from, if it was sensor
+/// data, which metrics are labelled by.
+type BrokerAndType = (BrokerId, Option<SensorType>);
+
+/// Latencies for each hop, named.
+type HopReports = [(&'static str, Option<LatencyReport>); 3];
+
+/// The actual counters.
+#[derive(Debug, Default)]
+struct IngestCounters {
+  by_broker: HashMap<BrokerId, RateCounter>,
+  by_broker_type: HashMap<BrokerAndType, RateCounter>,
+  by_type: HashMap<SensorType, RateCounter>,
+  by_sensor: HashMap<(SensorType, SensorId), RateCounter>,
+  decode_failures: HashMap<(BrokerId, String), RateCounter>,
+  latency: HashMap<BrokerId, HopLatencies>,
+  latency_by_type: HashMap<BrokerAndType, HopLatencies>
+}
+
+/// Registers a hit in a counter map.
+fn hit<K: Eq + Hash>(
+  map: &mut HashMap<K, RateCounter>, key: K, now: DateTime<Local>
+) {
+  match map.get_mut(&key) {
+    Some(rc) => rc.hit(now),
+    None => { map.insert(key, RateCounter::new(now)); },
+  };
+}
+
+/// Ingestion statistics. Cheap to clone, all clones share the same counters.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IngestStats {
+  inner: Arc<Mutex<IngestCounters>>
+}
+
+impl IngestStats {
+  /// Counts a freshly-ingested message.
+  pub(crate) fn record(&self, msg: &BrokerMessage) {
+    let now = Local::now();
+    let mut c = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+    hit(&mut c.by_broker, msg.broker_id, now);
+    c.latency.entry(msg.broker_id).or_default().record(msg);
+    let stype = match &msg.payload {
+      BrokerMessagePayload::SensorData(sd) => Some(sd.sensor_type()),
+      _ => None,
+    };
+    hit(&mut c.by_broker_type, (msg.broker_id, stype), now);
+    c.latency_by_type.entry((msg.broker_id, stype)).or_default().record(msg);
+    match &msg.payload {
+      BrokerMessagePayload::SensorData(sd) => {
+        hit(&mut c.by_type, sd.sensor_type(), now);
+        hit(&mut c.by_sensor, (sd.sensor_type(), sd.sensor_id()), now);
+      },
+      BrokerMessagePayload::DecodeFailure { topic, .. } => {
+        hit(&mut c.decode_failures, (msg.broker_id, topic.clone()), now);
+      },
+      _ => (),
+    };
+  }
+
+  /// Returns a snapshot of all rates, sorted by key.
+  pub(crate) fn report(&self) -> IngestReport {
+    let now_sec = Local::now().timestamp();
+    let c = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+    let mut brokers: Vec<BrokerRateReport> = c.by_broker
+      .iter()
+      .map(|(bid, rc)| BrokerRateReport {
+        broker_id: *bid,
+        rate: rc.report(now_sec)
+      })
+      .collect();
+    brokers.sort_by_key(|r| r.broker_id);
+    let mut sensor_types: Vec<SensorTypeRateReport> = c.by_type
+      .iter()
+      .map(|(st, rc)| SensorTypeRateReport {
+        sensor_type: *st,
+        rate: rc.report(now_sec)
+      })
+      .collect();
+    sensor_types.sort_by_key(|r| r.sensor_type);
+    let mut sensors: Vec<SensorRateReport> = c.by_sensor
+      .iter()
+      .map(|((st, id), rc)| SensorRateReport {
+        sensor_type: *st,
+        sensor_id: *id,
+        rate: rc.report(now_sec)
+      })
+      .collect();
+    sensors.sort_by_key(|r| (r.sensor_type, r.sensor_id));
+    let mut decode_failures: Vec<DecodeFailureRateReport> = c.decode_failures
+      .iter()
+      .map(|((bid, topic), rc)| DecodeFailureRateReport {
+        broker_id: *bid,
+        topic: topic.clone(),
+        rate: rc.report(now_sec)
+      })
+      .collect();
+    decode_failures.sort_by(|a, b| {
+      (a.broker_id, &a.topic).cmp(&(b.broker_id, &b.topic))
+    });
+    return IngestReport {
+      window_secs: RATE_WINDOW_SECS,
+      brokers: brokers,
+      sensor_types: sensor_types,
+      sensors: sensors,
+      decode_failures: decode_failures
+    };
+  }
+
+  /// Returns a snapshot of all latencies, sorted by broker.
+  pub(crate) fn latency_report(&self) -> LatencyStatsReport {
+    let c = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+    let mut brokers: Vec<BrokerLatencyReport> = c.latency
+      .iter()
+      .map(|(bid, hl)| BrokerLatencyReport {
+        broker_id: *bid,
+        in_broker: hl.in_broker.report(),
+        broker_to_api: hl.broker_to_api.report(),
+        end_to_end: hl.end_to_end.report()
+      })
+      .collect();
+    brokers.sort_by_key(|r| r.broker_id);
+    return LatencyStatsReport {
+      max_samples: LATENCY_SAMPLES,
+      brokers: brokers
+    };
+  }
+
+  /// Rates and latencies by broker and sensor type, sorted by them.
+  fn by_broker_type(&self)
+  -> Vec<(BrokerAndType, RateReport, HopReports)> {
+    let now_sec = Local::now().timestamp();
+    let c = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+    let mut all: Vec<_> = c.by_broker_type
+      .iter()
+      .map(|(key, rc)| {
+        let hl = c.latency_by_type.get(key).cloned().unwrap_or_default();
+        return (*key, rc.report(now_sec), [
+          ("in_broker", hl.in_broker.report()),
+          ("broker_to_api", hl.broker_to_api.report()),
+          ("end_to_end", hl.end_to_end.report())
+        ]);
+      })
+      .collect();
+    all.sort_by_key(|(key, _, _)| *key);
+    return all;
+  }
+
+  /// Appends our metrics, in Prometheus text format, to out.
+  pub(crate) fn render_prometheus(&self, out: &mut String) {
+    let rep = self.report();
+    let by_type = self.by_broker_type();
+    // sensor_type is empty for anything but sensor data
+    let label = |st: &Option<SensorType>| {
+      return st.map(|st| st.to_string()).unwrap_or_default();
+    };
+    let _ = writeln!(out, "# HELP cdp_ingested_messages_total Messages \
+      ingested, by broker and sensor type.");
+    let _ = writeln!(out, "# TYPE cdp_ingested_messages_total counter");
+    for ((bid, st), rate, _) in by_type.iter() {
+      let _ = writeln!(
+        out,
+        "cdp_ingested_messages_total{{broker=\"{}\",sensor_type=\"{}\"}} {}",
+        bid, label(st), rate.total
+      );
+    }
+    let _ = writeln!(out, "# HELP cdp_ingested_sensor_messages_total Sensor \
+      messages ingested, by sensor.");
+    let _ = writeln!(out, "# TYPE cdp_ingested_sensor_messages_total counter");
+    for s in rep.sensors.iter() {
+      let _ = writeln!(
+        out,
+        "cdp_ingested_sensor_messages_total{{sensor_type=\"{}\",\
+          sensor_id=\"{}\"}} {}",
+        s.sensor_type, s.sensor_id, s.rate.total
+      );
+    }
+    let _ = writeln!(out, "# HELP cdp_ingest_rate_per_minute Messages per \
+      minute over the last {} seconds, by broker and sensor type.",
+      RATE_WINDOW_SECS);
+    let _ = writeln!(out, "# TYPE cdp_ingest_rate_per_minute gauge");
+    for ((bid, st), rate, _) in by_type.iter() {
+      let _ = writeln!(
+        out,
+        "cdp_ingest_rate_per_minute{{broker=\"{}\",sensor_type=\"{}\"}} {}",
+        bid, label(st), rate.per_minute
+      );
+    }
+    let _ = writeln!(out, "# HELP cdp_message_latency_ms Latency of the \
+      latest {} messages, by broker, sensor type and hop.", LATENCY_SAMPLES);
+    let _ = writeln!(out, "# TYPE cdp_message_latency_ms gauge");
+    for ((bid, st), _, hops) in by_type.iter() {
+      for (hop, lr) in hops.iter() {
+        let lr = match lr {
+          Some(lr) => lr,
+          None => continue,
+        };
+        let quantiles = [
+          ("0.5", lr.p50_ms), ("0.95", lr.p95_ms), ("1", lr.max_ms)
+        ];
+        for (q, ms) in quantiles.iter() {
+          let _ = writeln!(
+            out,
+            "cdp_message_latency_ms{{broker=\"{}\",sensor_type=\"{}\",\
+              hop=\"{}\",quantile=\"{}\"}} {}",
+            bid, label(st), hop, q, ms
+          );
+        }
+      }
+    }
+    let _ = writeln!(out, "# HELP cdp_sensor_rate_per_minute Sensor messages \
+      per minute over the last {} seconds, by sensor.", RATE_WINDOW_SECS);
+    let _ = writeln!(out, "# TYPE cdp_sensor_rate_per_minute gauge");
+    for s in rep.sensors.iter() {
+      let _ = writeln!(
+        out,
+        "cdp_sensor_rate_per_minute{{sensor_type=\"{}\",sensor_id=\"{}\"}} {}",
+        s.sensor_type, s.sensor_id, s.rate.per_minute
+      );
+    }
+    let _ = writeln!(out, "# HELP cdp_decode_failures_total Payloads brokers \
+      couldn't decode, by broker and topic.");
+    let _ = writeln!(out, "# TYPE cdp_decode_failures_total counter");
+    for d in rep.decode_failures.iter() {
+      // topics come from brokers, so they're quoted and escaped
+      let _ = writeln!(
+        out, "cdp_decode_failures_total{{broker=\"{}\",topic={:?}}} {}",
+        d.broker_id, d.topic, d.rate.total
+      );
+    }
+  }
+}
@@ -0,0 +1,223 @@
+//! Keeping the API's background jobs going: reports, escalations, snapshots
+//! and the like, each on a thread of its own. A job that panics is logged,
+//! counted, and started over after a while, a second at first and twice
+//! that after every panic in a row, up to a minute, unless its policy says
+//! it's had enough. One that ran fine for a while is forgiven its earlier
+//! panics.
+//!
+//! How each job is doing shows up at /stats/jobs, in /metrics, and in
+//! /readyz, which fails once a job stays down for good.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+/// How long to wait before starting a job over after its first panic.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait before starting a job over.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long a job must run to be forgiven its earlier panics.
+const STABLE: Duration = Duration::from_secs(300);
+
+/// When a job that panicked is started over.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Restart {
+  /// Every time.
+  Always,
+  /// Up to so many panics in a row, after which it stays down.
+  UpTo(u32)
+}
+
+/// What a job is up to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobState {
+  /// Doing its thing.
+  Running,
+  /// Panicked, and waiting to be started over.
+  Restarting,
+  /// Panicked too many times, and won't be started over.
+  Down,
+  /// Ended on its own.
+  Done
+}
+
+/// A panic, as seen by the supervisor.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct PanicRecord {
+  /// When it happened.
+  pub(crate) when: DateTime<Local>,
+  /// What it said.
+  pub(crate) message: String
+}
+
+/// How a single job is doing.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct JobStatus {
+  /// What it's up to.
+  pub(crate) state: JobState,
+  /// How many times it panicked.
+  pub(crate) panics: u64,
+  /// How many times it was started over.
+  pub(crate) restarts: u64,
+  /// The latest panic, if any.
+  pub(crate) last_panic: Option<PanicRecord>
+}
+
+/// Every background job, by name, and how it's doing. Cheap to clone, and
+/// thread-safe.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Jobs {
+  /// The jobs.
+  jobs: Arc<Mutex<BTreeMap<String, JobStatus>>>
+}
+
+/// What a panic said, if it said it with a string.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+  if let Some(s) = payload.downcast_ref::<&str>() {
+    return s.to_string();
+  }
+  return match payload.downcast::<String>() {
+    Ok(s) => *s,
+    Err(_) => "no message".to_owned(),
+  };
+}
+
+/// How long to wait after so many panics in a row.
+fn backoff(panics: u32) -> Duration {
+  let factor = 2u32.saturating_pow(panics.saturating_sub(1));
+  return FIRST_BACKOFF.checked_mul(factor)
+    .unwrap_or(MAX_BACKOFF)
+    .min(MAX_BACKOFF);
+}
+
+impl Jobs {
+  /// The jobs, locked, poisoned or not.
+  fn lock(&self) -> MutexGuard<'_, BTreeMap<String, JobStatus>> {
+    return self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+  }
+
+  /// Changes how a job is doing.
+  fn update<F: FnOnce(&mut JobStatus)>(&self, name: &str, f: F) {
+    if let Some(job) = self.lock().get_mut(name) {
+      f(job);
+    }
+  }
+
+  /// Runs a job on a thread of its own, calling job again whenever it
+  /// panics, as the policy says.
+  pub(crate) fn spawn<F>(&self, name: &str, policy: Restart, mut job: F)
+  where F: FnMut() + Send + 'static {
+    self.lock().insert(name.to_owned(), JobStatus {
+      state: JobState::Running,
+      panics: 0,
+      restarts: 0,
+      last_panic: None
+    });
+    let jobs = self.clone();
+    let name = name.to_owned();
+    thread::Builder::new()
+      .name(name.clone())
+      .spawn(move || jobs.supervise(&name, policy, &mut job))
+      .expect("Couldn't start a thread!");
+  }
+
+  /// Runs a job until it ends on its own or stays down.
+  fn supervise(&self, name: &str, policy: Restart, job: &mut dyn FnMut()) {
+    let mut in_a_row = 0;
+    loop {
+      let started = Instant::now();
+      let payload = match panic::catch_unwind(AssertUnwindSafe(&mut *job)) {
+        Ok(()) => {
+          self.update(name, |j| j.state = JobState::Done);
+          return;
+        },
+        Err(p) => p,
+      };
+      if started.elapsed() >= STABLE {
+        in_a_row = 0;
+      }
+      in_a_row += 1;
+      let message = panic_message(payload);
+      let gives_up = match policy {
+        Restart::Always => false,
+        Restart::UpTo(n) => in_a_row > n,
+      };
+      self.update(name, |j| {
+        j.panics += 1;
+        j.last_panic = Some(PanicRecord {
+          when: Local::now(),
+          message: message.clone()
+        });
+        j.state = if gives_up { JobState::Down } else { JobState::Restarting };
+      });
+      if gives_up {
+        warn!(
+          "The {} job panicked: {}. That's {} in a row, so it stays down.",
+          name, message, in_a_row
+        );
+        return;
+      }
+      let wait = backoff(in_a_row);
+      warn!(
+        "The {} job panicked: {}. Starting it over in {:?}.",
+        name, message, wait
+      );
+      thread::sleep(wait);
+      self.update(name, |j| {
+        j.restarts += 1;
+        j.state = JobState::Running;
+      });
+    }
+  }
+
+  /// How every job is doing.
+  pub(crate) fn report(&self) -> BTreeMap<String, JobStatus> {
+    return self.lock().clone();
+  }
+
+  /// Names of the jobs that stay down.
+  pub(crate) fn down(&self) -> Vec<String> {
+    return self.lock().iter()
+      .filter(|(_, j)| j.state == JobState::Down)
+      .map(|(name, _)| name.clone())
+      .collect();
+  }
+
+  /// Renders the counters and states as Prometheus metrics.
+  pub(crate) fn render_prometheus(&self, out: &mut String) {
+    let jobs = self.report();
+    let _ = writeln!(out, "# HELP cdp_job_panics_total Times a background \
+      job panicked.");
+    let _ = writeln!(out, "# TYPE cdp_job_panics_total counter");
+    for (name, job) in jobs.iter() {
+      let _ = writeln!(
+        out, "cdp_job_panics_total{{job=\"{}\"}} {}", name, job.panics
+      );
+    }
+    let _ = writeln!(out, "# HELP cdp_job_restarts_total Times a background \
+      job was started over.");
+    let _ = writeln!(out, "# TYPE cdp_job_restarts_total counter");
+    for (name, job) in jobs.iter() {
+      let _ = writeln!(
+        out, "cdp_job_restarts_total{{job=\"{}\"}} {}", name, job.restarts
+      );
+    }
+    let _ = writeln!(out, "# HELP cdp_job_up Whether a background job is \
+      running.");
+    let _ = writeln!(out, "# TYPE cdp_job_up gauge");
+    for (name, job) in jobs.iter() {
+      let up = if job.state == JobState::Running { 1 } else { 0 };
+      let _ = writeln!(out, "cdp_job_up{{job=\"{}\"}} {}", name, up);
+    }
+  }
+}
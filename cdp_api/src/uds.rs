@@ -0,0 +1,41 @@
+//! Unix domain sockets to listen on, for sitting behind a local reverse
+//! proxy without opening TCP ports. A bind of "unix:/run/cdp_api.sock"
+//! listens at that path.
+
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+/// Prefix of binds that are socket paths.
+pub(crate) const PREFIX: &str = "unix:";
+
+/// Removes a socket left behind by an instance that didn't get to clean up.
+/// Sockets someone is still listening on are left alone, as is anything
+/// that isn't a socket.
+pub(crate) fn clear_stale(path: &Path) -> io::Result<()> {
+  let meta = match std::fs::symlink_metadata(path) {
+    Ok(m) => m,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+    Err(e) => return Err(e),
+  };
+  if !meta.file_type().is_socket() {
+    return Err(io::Error::new(
+      io::ErrorKind::AlreadyExists,
+      format!("{} exists, and isn't a socket.", path.display())
+    ));
+  }
+  if UnixStream::connect(path).is_ok() {
+    return Err(io::Error::new(
+      io::ErrorKind::AddrInUse,
+      format!("Something is already listening on {}.", path.display())
+    ));
+  }
+  info!("Removing stale socket {}...", path.display());
+  return std::fs::remove_file(path);
+}
+
+/// Sets a freshly bound socket's permissions, like 0o660.
+pub(crate) fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+  return std::fs::set_permissions(path, PermissionsExt::from_mode(mode));
+}
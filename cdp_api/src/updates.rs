@@ -0,0 +1,103 @@
+//! Broker self-updates, as scheduled by admins. Each broker has at most one
+//! update job; scheduling another replaces it. The order goes out with
+//! replies to the broker's heartbeats until it reports having heard of it,
+//! and whatever it reports after that is kept with the job.
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use url::Url;
+use uuid::Uuid;
+
+use libcdp::comm::update::{self, UpdateOrder, UpdateReport};
+use libcdp::ids::BrokerId;
+
+use crate::db::ApiDatabase;
+
+/// An update, as an admin asks for it.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct UpdateRequest {
+  /// Version being installed.
+  pub(crate) version: String,
+  /// Where brokers download it from. HTTP or HTTPS.
+  pub(crate) url: String,
+  /// SHA-256 of the artifact, in hex.
+  pub(crate) sha256: String
+}
+
+impl UpdateRequest {
+  /// Checks it makes sense, and makes an order out of it.
+  pub(crate) fn order(&self) -> Result<UpdateOrder, String> {
+    if self.version.trim().is_empty() {
+      return Err("version can't be empty.".to_owned());
+    }
+    let url = Url::parse(&self.url)
+      .map_err(|e| format!("Bad url: {}.", e))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+      return Err("url must be HTTP or HTTPS.".to_owned());
+    }
+    if !update::is_sha256(&self.sha256) {
+      return Err("sha256 must be 64 hex digits.".to_owned());
+    }
+    return Ok(UpdateOrder {
+      id: Uuid::new_v4(),
+      version: self.version.trim().to_owned(),
+      url: url.to_string(),
+      sha256: self.sha256.to_ascii_lowercase()
+    });
+  }
+}
+
+/// A broker's update, and how it went.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct UpdateJob {
+  /// What it was told to install.
+  pub(crate) order: UpdateOrder,
+  /// When it was scheduled.
+  pub(crate) scheduled: DateTime<Local>,
+  /// What the broker last said about it. None means it hasn't yet.
+  pub(crate) report: Option<UpdateReport>,
+  /// When it last said so.
+  pub(crate) reported: Option<DateTime<Local>>
+}
+
+impl UpdateJob {
+  /// Takes in what a heartbeat said about updates. Returns the order, if
+  /// the broker still has to hear of it.
+  pub(crate) fn heard(
+    &mut self, report: Option<&UpdateReport>, now: DateTime<Local>
+  ) -> Option<UpdateOrder> {
+    match report {
+      Some(rep) if rep.id == self.order.id => {
+        if self.report.as_ref() != Some(rep) {
+          self.report = Some(rep.clone());
+          self.reported = Some(now);
+        }
+        return None;
+      },
+      // a report about an older order is no news.
+      _ if self.report.is_none() => return Some(self.order.clone()),
+      _ => return None,
+    };
+  }
+}
+
+/// Schedules an update for a broker, replacing whatever was scheduled.
+/// Returns the job it replaced, if any, and the new one. None if we never
+/// heard from the broker.
+pub(crate) fn schedule<D: ApiDatabase>(
+  db: &D, uid: BrokerId, order: UpdateOrder
+) -> Result<Option<(Option<UpdateJob>, UpdateJob)>, D::DbError> {
+  let mut rec = match db.broker(uid)? {
+    Some(r) => r,
+    None => return Ok(None),
+  };
+  let job = UpdateJob {
+    order: order,
+    scheduled: Local::now(),
+    report: None,
+    reported: None
+  };
+  let before = rec.update.replace(job.clone());
+  db.update_broker(rec)?;
+  return Ok(Some((before, job)));
+}
@@ -0,0 +1,297 @@
+//! Write-ahead log of incoming bundles. Every bundle lands here before it
+//! touches the database, one JSON line each, so it can be replayed into a
+//! fresh database after data loss or a backend migration.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use libcdp::comm::broker_api::BrokerMessageBundle;
+
+/// Write-ahead log settings, as they lie in the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct WalConfig {
+  /// The current log file. Rotated files get ".1", ".2"... appended.
+  pub(crate) path: PathBuf,
+  /// Size at which the current file is rotated, in bytes.
+  #[serde(default = "WalConfig::default_max_file_bytes")]
+  pub(crate) max_file_bytes: u64,
+  /// How many rotated files to keep around.
+  #[serde(default = "WalConfig::default_keep_files")]
+  pub(crate) keep_files: usize,
+  /// Whether to fsync after every bundle. Slower, but nothing acknowledged
+  /// is ever lost.
+  #[serde(default = "WalConfig::default_fsync")]
+  pub(crate) fsync: bool
+}
+
+impl WalConfig {
+  /// For serde. 64 MiB.
+  fn default_max_file_bytes() -> u64 {
+    return 64 * 1024 * 1024;
+  }
+
+  /// For serde.
+  fn default_keep_files() -> usize {
+    return 5;
+  }
+
+  /// For serde.
+  fn default_fsync() -> bool {
+    return true;
+  }
+
+  /// Path of the n-th rotated file. 0 is the current one.
+  fn rotated_path(&self, n: usize) -> PathBuf {
+    if n == 0 {
+      return self.path.clone();
+    }
+    let mut name = self.path.clone().into_os_string();
+    name.push(format!(".{}", n));
+    return PathBuf::from(name);
+  }
+}
+
+/// A single line of the log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct WalRecord {
+  /// When the bundle arrived.
+  pub(crate) logged_when: DateTime<Local>,
+  /// The bundle, exactly as the broker sent it.
+  pub(crate) bundle: BrokerMessageBundle
+}
+
+/// What a replay went through.
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct ReplayReport {
+  /// Log files read, oldest first.
+  pub(crate) files: Vec<PathBuf>,
+  /// Bundles found.
+  pub(crate) bundles: usize,
+  /// Messages found.
+  pub(crate) messages: usize,
+  /// Bundles that couldn't be ingested.
+  pub(crate) failed_bundles: usize,
+  /// Lines that weren't valid records.
+  pub(crate) bad_lines: usize
+}
+
+/// The file being written to.
+#[derive(Debug)]
+struct WalWriter {
+  cfg: WalConfig,
+  file: File,
+  size: u64
+}
+
+impl WalWriter {
+  /// Opens the current file for appending.
+  fn open(cfg: WalConfig) -> io::Result<Self> {
+    let file = OpenOptions::new().create(true).append(true).open(&cfg.path)?;
+    let size = file.metadata()?.len();
+    return Ok(Self {
+      cfg: cfg,
+      file: file,
+      size: size
+    });
+  }
+
+  /// Shifts every file one step back, dropping the oldest, and starts a new
+  /// current file.
+  fn rotate(&mut self) -> io::Result<()> {
+    let keep = self.cfg.keep_files;
+    let oldest = self.cfg.rotated_path(keep);
+    if oldest.exists() {
+      fs::remove_file(&oldest)?;
+    }
+    for n in (0..keep).rev() {
+      let from = self.cfg.rotated_path(n);
+      if from.exists() {
+        fs::rename(&from, self.cfg.rotated_path(n + 1))?;
+      }
+    }
+    *self = Self::open(self.cfg.clone())?;
+    return Ok(());
+  }
+
+  /// Appends a record, rotating first if the file is full.
+  fn append(&mut self, rec: &WalRecord) -> io::Result<()> {
+    if self.size >= self.cfg.max_file_bytes {
+      self.rotate()?;
+    }
+    let mut line = serde_json::to_vec(rec)?;
+    line.push(b'\n');
+    self.file.write_all(&line)?;
+    if self.cfg.fsync {
+      self.file.sync_data()?;
+    }
+    self.size += line.len() as u64;
+    return Ok(());
+  }
+
+  /// Existing log files, oldest first.
+  fn files(&self) -> Vec<PathBuf> {
+    return (0..=self.cfg.keep_files)
+      .rev()
+      .map(|n| self.cfg.rotated_path(n))
+      .filter(|p| p.exists())
+      .collect();
+  }
+}
+
+/// The write-ahead log. Does nothing if not configured. Cheap to clone, all
+/// clones share the same file.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WriteAheadLog {
+  inner: Option<Arc<Mutex<WalWriter>>>
+}
+
+impl WriteAheadLog {
+  /// Opens the log, if configured.
+  pub(crate) fn open(cfg: Option<&WalConfig>) -> io::Result<Self> {
+    return Ok(Self {
+      inner: match cfg {
+        Some(c) => Some(Arc::new(Mutex::new(WalWriter::open(c.clone())?))),
+        None => None,
+      }
+    });
+  }
+
+  /// Whether there's a log at all.
+  pub(crate) fn is_enabled(&self) -> bool {
+    return self.inner.is_some();
+  }
+
+  /// Logs a freshly-arrived bundle. Returns the time it was logged at, which
+  /// is also when it arrived.
+  pub(crate) fn append(&self, bundle: &BrokerMessageBundle)
+  -> io::Result<DateTime<Local>> {
+    let now = Local::now();
+    if let Some(w) = &self.inner {
+      let rec = WalRecord {
+        logged_when: now,
+        bundle: bundle.clone()
+      };
+      w.lock().unwrap_or_else(|e| e.into_inner()).append(&rec)?;
+    }
+    return Ok(now);
+  }
+
+  /// Feeds every logged bundle, oldest first, to the closure, which says
+  /// whether it was ingested fine. Only holds the log while opening its
+  /// files, so bundles keep being logged meanwhile; those logged after that
+  /// are left out.
+  pub(crate) fn replay<F>(&self, mut ingest: F) -> io::Result<ReplayReport>
+  where F: FnMut(WalRecord) -> bool {
+    let mut report = ReplayReport::default();
+    let opened = match &self.inner {
+      Some(w) => {
+        let w = w.lock().unwrap_or_else(|e| e.into_inner());
+        report.files = w.files();
+        // open files stay readable even if rotated away, and reading each
+        // only as far as it went now keeps out what's logged later
+        report.files.iter().map(|p| {
+          let file = File::open(p)?;
+          let len = file.metadata()?.len();
+          return Ok(file.take(len));
+        }).collect::<io::Result<Vec<_>>>()?
+      },
+      None => return Ok(report),
+    };
+    for file in opened {
+      replay_file(file, &mut report, &mut ingest)?;
+    }
+    return Ok(report);
+  }
+}
+
+/// Replays a single log file.
+fn replay_file<R, F>(file: R, report: &mut ReplayReport, ingest: &mut F)
+-> io::Result<()>
+where R: Read, F: FnMut(WalRecord) -> bool {
+  for line in BufReader::new(file).lines() {
+    let rec: WalRecord = match serde_json::from_str(&line?) {
+      Ok(r) => r,
+      Err(_) => {
+        report.bad_lines += 1;
+        continue;
+      },
+    };
+    report.bundles += 1;
+    report.messages += rec.bundle.len();
+    if !ingest(rec) {
+      report.failed_bundles += 1;
+    }
+  }
+  return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+  use uuid::Uuid;
+
+  use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+  use libcdp::comm::sensor_broker::{AnySensorMessage, TemperatureMessage};
+  use libcdp::ids::BrokerId;
+
+  use super::*;
+
+  /// A bundle of n readings.
+  fn bundle(n: usize) -> BrokerMessageBundle {
+    let sd = AnySensorMessage::Temperature(TemperatureMessage {
+      sensor_id: 1,
+      kelvin: 294
+    });
+    return (0..n).map(|_| BrokerMessage::construct(
+      BrokerId::new_v4(), BrokerMessagePayload::SensorData(sd.clone())
+    )).collect();
+  }
+
+  #[test]
+  fn rotates_and_replays_what_it_kept() {
+    let dir = std::env::temp_dir()
+      .join(format!("cdp_wal_test_{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    let cfg = WalConfig {
+      path: dir.join("wal.ndjson"),
+      max_file_bytes: 1,
+      keep_files: 2,
+      fsync: false
+    };
+    let wal = WriteAheadLog::open(Some(&cfg)).unwrap();
+    // every append past the first rotates, so the first falls off the end
+    for n in 1..=4 {
+      wal.append(&bundle(n)).unwrap();
+    }
+    let mut current = OpenOptions::new().append(true).open(&cfg.path)
+      .unwrap();
+    current.write_all(b"not a record\n").unwrap();
+    let mut sizes = Vec::new();
+    let report = wal.replay(|rec| {
+      sizes.push(rec.bundle.len());
+      return rec.bundle.len() != 3;
+    }).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(sizes, vec![2, 3, 4]);
+    assert_eq!(report.files, vec![
+      cfg.rotated_path(2), cfg.rotated_path(1), cfg.rotated_path(0)
+    ]);
+    assert_eq!(report.bundles, 3);
+    assert_eq!(report.messages, 9);
+    assert_eq!(report.failed_bundles, 1);
+    assert_eq!(report.bad_lines, 1);
+  }
+
+  #[test]
+  fn does_nothing_unless_configured() {
+    let wal = WriteAheadLog::open(None).unwrap();
+    assert!(!wal.is_enabled());
+    wal.append(&bundle(1)).unwrap();
+    let report = wal.replay(|_| true).unwrap();
+    assert_eq!(report.bundles, 0);
+  }
+}
@@ -0,0 +1,216 @@
+//! Readings from devices that can only call webhooks, like cloud weather
+//! stations. Each source is configured by name under [webhooks], with
+//! where in the JSON it POSTs each reading lies, and which sensor that
+//! reading is stored as. Messages are attributed to a broker ID of the
+//! source's own, so they go through the same pipeline as any bundle.
+//!
+//! Paths are a small subset of JSONPath: "$", then any of ".field",
+//! "['field']" and "[index]", like "$.observations[0].metric.temp".
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+use libcdp::comm::sensor_broker::{AnySensorMessage, SensorType};
+use libcdp::ids::{BrokerId, SensorId};
+
+/// A device calling in, as it lies in the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct WebhookSource {
+  /// Bearer token the device calls with. The admin token works too. None
+  /// means only the admin token does.
+  pub(crate) token: Option<String>,
+  /// Broker ID its messages are stored under.
+  pub(crate) broker_id: BrokerId,
+  /// Where each reading is, and what it's stored as.
+  #[serde(default)]
+  pub(crate) readings: Vec<WebhookMapping>
+}
+
+/// Where a reading lies in a webhook's body.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct WebhookMapping {
+  /// Sensor type name, like "temperature".
+  pub(crate) sensor_type: String,
+  /// Sensor ID to store it as.
+  pub(crate) sensor_id: SensorId,
+  /// Path to the value, like "$.current.temp_c".
+  pub(crate) value: String,
+  /// What to multiply it by, to get °C or %RH.
+  #[serde(default = "WebhookMapping::default_scale")]
+  pub(crate) scale: f64,
+  /// What to add after scaling.
+  #[serde(default)]
+  pub(crate) offset: f64
+}
+
+impl WebhookMapping {
+  /// For serde.
+  fn default_scale() -> f64 {
+    return 1.0;
+  }
+}
+
+/// One step of a path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PathStep {
+  /// A field of an object.
+  Field(String),
+  /// An element of an array.
+  Index(usize)
+}
+
+/// A parsed path into a JSON value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct JsonPath(Vec<PathStep>);
+
+impl FromStr for JsonPath {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let bad = |why: &str| format!("Bad path \"{}\": {}.", s, why);
+    let mut rest = s.trim()
+      .strip_prefix('$')
+      .ok_or_else(|| bad("it must start with $"))?;
+    let mut steps = Vec::new();
+    while !rest.is_empty() {
+      if let Some(after) = rest.strip_prefix('.') {
+        let end = after.find(|c| c == '.' || c == '[').unwrap_or(after.len());
+        if end == 0 {
+          return Err(bad("empty field name"));
+        }
+        steps.push(PathStep::Field(after[..end].to_owned()));
+        rest = &after[end..];
+      } else if let Some(after) = rest.strip_prefix('[') {
+        let end = after.find(']').ok_or_else(|| bad("unclosed ["))?;
+        let inside = after[..end].trim();
+        let quoted = inside.strip_prefix('\'')
+          .and_then(|q| q.strip_suffix('\''))
+          .or_else(|| {
+            inside.strip_prefix('"').and_then(|q| q.strip_suffix('"'))
+          });
+        steps.push(match quoted {
+          Some(name) => PathStep::Field(name.to_owned()),
+          None => PathStep::Index(
+            inside.parse().map_err(|_| bad("indices are whole numbers"))?
+          ),
+        });
+        rest = &after[end + 1..];
+      } else {
+        return Err(bad("expected . or ["));
+      }
+    }
+    return Ok(Self(steps));
+  }
+}
+
+impl JsonPath {
+  /// What the path points at, if anything.
+  pub(crate) fn find<'a>(&self, root: &'a Value) -> Option<&'a Value> {
+    let mut here = root;
+    for step in &self.0 {
+      here = match step {
+        PathStep::Field(name) => here.get(name.as_str())?,
+        PathStep::Index(i) => here.get(*i)?,
+      };
+    }
+    return Some(here);
+  }
+}
+
+/// Why a webhook's body couldn't be taken in.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum WebhookError {
+  /// A value was there, but wasn't a number.
+  NotANumber(String),
+  /// A value doesn't fit its sensor's wire format.
+  OutOfRange(String, f64),
+  /// None of the paths led anywhere.
+  NothingMapped
+}
+
+impl WebhookError {
+  /// Machine-readable name, for error responses.
+  pub(crate) fn code(&self) -> &'static str {
+    return match self {
+      WebhookError::NotANumber(_) => "not_a_number",
+      WebhookError::OutOfRange(..) => "out_of_range",
+      WebhookError::NothingMapped => "nothing_mapped",
+    };
+  }
+}
+
+impl std::error::Error for WebhookError {}
+
+impl Display for WebhookError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      WebhookError::NotANumber(path) => {
+        write!(f, "The value at {} isn't a number.", path)
+      },
+      WebhookError::OutOfRange(path, v) => {
+        write!(f, "The value at {} ({}) is out of range.", path, v)
+      },
+      WebhookError::NothingMapped => {
+        write!(f, "None of the configured readings were in the body.")
+      },
+    };
+  }
+}
+
+impl WebhookSource {
+  /// Checks that sensor types and paths make sense.
+  pub(crate) fn check(&self, name: &str) -> Result<(), String> {
+    for m in &self.readings {
+      SensorType::from_str(&m.sensor_type).map_err(|_| format!(
+        "Bad sensor type \"{}\" in webhook \"{}\".", m.sensor_type, name
+      ))?;
+      JsonPath::from_str(&m.value)
+        .map_err(|e| format!("Webhook \"{}\": {}", name, e))?;
+      if !m.scale.is_finite() || !m.offset.is_finite() {
+        return Err(format!("Webhook \"{}\" has a bad scale or offset.", name));
+      }
+    }
+    return Ok(());
+  }
+
+  /// Picks readings out of a body, as messages from the source. Readings
+  /// that aren't in it are skipped, since devices often leave out what
+  /// they couldn't measure; numbers in strings are fine.
+  pub(crate) fn messages(&self, body: &Value)
+  -> Result<Vec<BrokerMessage>, WebhookError> {
+    let mut msgs = Vec::new();
+    for m in &self.readings {
+      // both were checked when the config was loaded
+      let (stype, path) = match (
+        SensorType::from_str(&m.sensor_type), JsonPath::from_str(&m.value)
+      ) {
+        (Ok(s), Ok(p)) => (s, p),
+        _ => continue,
+      };
+      let raw = match path.find(body) {
+        Some(Value::Null) | None => continue,
+        Some(v) => v,
+      };
+      let value = match raw {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse::<f64>().ok(),
+        _ => None,
+      }.filter(|v| v.is_finite())
+        .ok_or_else(|| WebhookError::NotANumber(m.value.clone()))?;
+      let value = value * m.scale + m.offset;
+      let msg = AnySensorMessage::from_human_value(stype, m.sensor_id, value)
+        .ok_or_else(|| WebhookError::OutOfRange(m.value.clone(), value))?;
+      msgs.push(BrokerMessage::construct(
+        self.broker_id, BrokerMessagePayload::SensorData(msg)
+      ));
+    }
+    if msgs.is_empty() {
+      return Err(WebhookError::NothingMapped);
+    }
+    return Ok(msgs);
+  }
+}
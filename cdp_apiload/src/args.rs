@@ -0,0 +1,125 @@
+//! What the command line says.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use cdp_client::BundleFormat;
+
+/// What the command line takes.
+pub(crate) const USAGE: &str = "\
+Usage: cdp_apiload [options]
+
+Pushes made-up bundles straight to the API's /bundle, as if from a fleet of
+brokers, and says how long the API took to take them.
+
+Options:
+  --api <url>           the API's base URL, else $CDP_API, else
+                        http://localhost:9869/
+  --rate <n>            bundles per second, all workers together; 0 means
+                        as fast as they go (10)
+  --concurrency <n>     workers pushing at once, each as a broker of its own
+                        (4)
+  --bundle-size <n>     messages per bundle (32)
+  --sensors <n>         sensors per broker, of each type (8)
+  --duration <secs>     how long to keep at it (30)
+  --format <format>     json, ndjson or protobuf (json)
+  -h, --help            print this and quit
+
+Mind the API's per-broker rate limit, if it has one: bundles it turns down
+count as failures.";
+
+/// Whatever the command line says.
+#[derive(Clone, Debug)]
+pub(crate) struct Args {
+  /// --api.
+  pub(crate) api: Option<String>,
+  /// --rate. None means as fast as they go.
+  pub(crate) rate: Option<f64>,
+  /// --concurrency.
+  pub(crate) concurrency: usize,
+  /// --bundle-size.
+  pub(crate) bundle_size: usize,
+  /// --sensors.
+  pub(crate) sensors: u8,
+  /// --duration.
+  pub(crate) duration: Duration,
+  /// --format.
+  pub(crate) format: BundleFormat,
+  /// Whether to print the usage and quit instead.
+  pub(crate) help: bool
+}
+
+/// Parses a value, saying which one was bad if it is.
+fn value<T: FromStr>(what: &str, s: Option<String>) -> Result<T, String> {
+  let s = s.ok_or_else(|| format!("Missing {}.", what))?;
+  return s.parse().map_err(|_| format!("Bad {} \"{}\".", what, s));
+}
+
+/// Parses a count, which can't be 0.
+fn count<T: FromStr + Default + PartialEq>(what: &str, s: Option<String>)
+-> Result<T, String> {
+  let n = value(what, s)?;
+  if n == T::default() {
+    return Err(format!("The {} can't be 0.", what));
+  }
+  return Ok(n);
+}
+
+/// Parses a bundle format.
+fn format(s: Option<String>) -> Result<BundleFormat, String> {
+  return match s.as_deref() {
+    Some("json") => Ok(BundleFormat::Json),
+    Some("ndjson") => Ok(BundleFormat::Ndjson),
+    Some("protobuf") => Ok(BundleFormat::Protobuf),
+    Some(other) => Err(format!("Unknown format \"{}\".", other)),
+    None => Err("Missing format.".to_owned()),
+  };
+}
+
+impl Args {
+  /// Parses the arguments, without the program name.
+  pub(crate) fn parse<I: Iterator<Item = String>>(args: I)
+  -> Result<Self, String> {
+    let mut args = args;
+    let mut parsed = Self {
+      api: None,
+      rate: Some(10.0),
+      concurrency: 4,
+      bundle_size: 32,
+      sensors: 8,
+      duration: Duration::from_secs(30),
+      format: BundleFormat::Json,
+      help: false
+    };
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "-h" | "--help" => parsed.help = true,
+        "--api" => parsed.api = Some(value("API URL", args.next())?),
+        "--rate" => {
+          let rate: f64 = value("rate", args.next())?;
+          if !rate.is_finite() || rate < 0.0 {
+            return Err("The rate can't be negative.".to_owned());
+          }
+          parsed.rate = if rate > 0.0 { Some(rate) } else { None };
+        },
+        "--concurrency" => {
+          parsed.concurrency = count("concurrency", args.next())?;
+        },
+        "--bundle-size" => {
+          parsed.bundle_size = count("bundle size", args.next())?;
+        },
+        "--sensors" => parsed.sensors = count("sensor count", args.next())?,
+        "--duration" => {
+          let secs: f64 = value("duration", args.next())?;
+          if !secs.is_finite() || secs <= 0.0 {
+            return Err("The duration must be positive.".to_owned());
+          }
+          parsed.duration = Duration::from_secs_f64(secs);
+        },
+        "--format" => parsed.format = format(args.next())?,
+        _ => return Err(format!("Unknown argument \"{}\".", arg)),
+      };
+    }
+    return Ok(parsed);
+  }
+}
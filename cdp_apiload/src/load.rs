@@ -0,0 +1,120 @@
+//! The workers: each one a made-up broker, pushing made-up bundles at its
+//! share of the rate until time's up.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use cdp_client::{ApiClient, BundleFormat, ClientError};
+use chrono::Local;
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+use libcdp::comm::sensor_broker::{AnySensorMessage, SensorType};
+use libcdp::ids::{BrokerId, SensorId};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::time::{self, Instant};
+
+/// What every worker does.
+#[derive(Clone, Debug)]
+pub(crate) struct Plan {
+  /// Messages per bundle.
+  pub(crate) bundle_size: usize,
+  /// Sensors per broker, of each type.
+  pub(crate) sensors: u8,
+  /// How bundles are encoded.
+  pub(crate) format: BundleFormat,
+  /// Time between a worker's bundles. None means no waiting.
+  pub(crate) period: Option<Duration>,
+  /// When to stop.
+  pub(crate) until: Instant
+}
+
+/// How many bundles went through, and how many didn't, so far. Every worker
+/// counts here, for progress reports.
+#[derive(Debug, Default)]
+pub(crate) struct Progress {
+  /// Bundles the API took.
+  pub(crate) ok: AtomicUsize,
+  /// Bundles that didn't make it.
+  pub(crate) failed: AtomicUsize
+}
+
+/// What a worker went through.
+#[derive(Debug, Default)]
+pub(crate) struct WorkerStats {
+  /// How long the API took to take each bundle it took.
+  pub(crate) latencies: Vec<Duration>,
+  /// How many bundles didn't make it, by why.
+  pub(crate) failures: BTreeMap<String, usize>
+}
+
+/// Why a bundle didn't make it, in few enough words that they add up.
+fn failure_kind(e: &ClientError) -> String {
+  return match e {
+    ClientError::Api { status, code, .. } => format!(
+      "HTTP {} ({})", status, code.as_deref().unwrap_or("no code")
+    ),
+    ClientError::Transport(e) if e.is_timeout() => "timed out".to_owned(),
+    ClientError::Transport(_) => "couldn't reach the API".to_owned(),
+    ClientError::BadReply(_) => "bad reply".to_owned(),
+    ClientError::BadUrl(_) => "bad URL".to_owned(),
+    ClientError::Seal(_) => "couldn't seal".to_owned(),
+  };
+}
+
+/// A bundle of plausible readings, from random sensors of a broker.
+fn make_bundle(broker: BrokerId, plan: &Plan, rng: &mut StdRng)
+-> Vec<BrokerMessage> {
+  return (0..plan.bundle_size)
+    .map(|_| {
+      let (stype, value) = if rng.gen_bool(0.5) {
+        (SensorType::Temperature, rng.gen_range(15.0..30.0))
+      } else {
+        (SensorType::Humidity, rng.gen_range(30.0..80.0))
+      };
+      let id = SensorId(rng.gen_range(0..plan.sensors));
+      let msg = AnySensorMessage::from_human_value(stype, id, value)
+        .expect("Made-up reading doesn't fit the wire!");
+      let mut bm = BrokerMessage::construct(
+        broker, BrokerMessagePayload::SensorData(msg)
+      );
+      bm.sent_when = Some(Local::now());
+      bm
+    })
+    .collect();
+}
+
+/// Pushes bundles as a broker of its own until time's up, starting after a
+/// delay so workers don't all push at once.
+pub(crate) async fn worker(
+  client: ApiClient, plan: Arc<Plan>, offset: Duration,
+  progress: Arc<Progress>
+) -> WorkerStats {
+  let broker = BrokerId::new_v4();
+  let mut rng = StdRng::from_entropy();
+  let mut stats = WorkerStats::default();
+  let mut ticks = plan.period
+    .map(|p| time::interval_at(Instant::now() + offset, p));
+  loop {
+    if let Some(t) = ticks.as_mut() {
+      t.tick().await;
+    }
+    if Instant::now() >= plan.until {
+      break;
+    }
+    let bundle = make_bundle(broker, &plan, &mut rng);
+    let start = Instant::now();
+    match client.push_bundle(&bundle, plan.format, None).await {
+      Ok(_) => {
+        stats.latencies.push(start.elapsed());
+        progress.ok.fetch_add(1, Ordering::Relaxed);
+      },
+      Err(e) => {
+        *stats.failures.entry(failure_kind(&e)).or_insert(0) += 1;
+        progress.failed.fetch_add(1, Ordering::Relaxed);
+      },
+    };
+  }
+  return stats;
+}
@@ -0,0 +1,106 @@
+//! cdp_apiload: loads the API with made-up bundles, straight to /bundle, to
+//! size it and its database without any broker or sensor in the way. The
+//! MQTT side is cdp_dummy's business. See USAGE in args.rs.
+
+mod args;
+mod load;
+mod report;
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use cdp_client::{ApiClient, ClientOptions};
+use tokio::time::Instant;
+use url::Url;
+
+use crate::args::{Args, USAGE};
+use crate::load::{Plan, Progress};
+
+/// Where the API is, unless told otherwise.
+const DEFAULT_API: &str = "http://localhost:9869/";
+
+/// A base URL, with the / that paths get joined after.
+fn base_url(s: &str) -> Result<Url, String> {
+  let s = if s.ends_with('/') { s.to_owned() } else { format!("{}/", s) };
+  return Url::parse(&s).map_err(|e| format!("Bad API URL \"{}\": {}", s, e));
+}
+
+#[tokio::main]
+async fn main() {
+  let args = Args::parse(std::env::args().skip(1))
+    .unwrap_or_else(|e| {
+      eprintln!("{}\n\n{}", e, USAGE);
+      std::process::exit(2);
+    });
+  if args.help {
+    println!("{}", USAGE);
+    return;
+  }
+  let api = args.api
+    .or_else(|| std::env::var("CDP_API").ok())
+    .unwrap_or_else(|| DEFAULT_API.to_owned());
+  let base = base_url(&api).unwrap_or_else(|e| {
+    eprintln!("{}", e);
+    std::process::exit(2);
+  });
+  // a retry would count as one slow bundle, so there are none
+  let client = ApiClient::new(base, ClientOptions {
+    retries: 0,
+    ..ClientOptions::default()
+  });
+
+  let workers = args.concurrency;
+  // each worker gets its share of the rate
+  let period = args.rate
+    .map(|r| Duration::from_secs_f64(workers as f64 / r));
+  let start = Instant::now();
+  let plan = Arc::new(Plan {
+    bundle_size: args.bundle_size,
+    sensors: args.sensors,
+    format: args.format,
+    period: period,
+    until: start + args.duration
+  });
+  let progress = Arc::new(Progress::default());
+  println!(
+    "Pushing to {} with {} worker(s), {} for {:.0}s...", api, workers,
+    match args.rate {
+      Some(r) => format!("{} bundles/s", r),
+      None => "as fast as they go".to_owned(),
+    },
+    args.duration.as_secs_f64()
+  );
+  let handles: Vec<_> = (0..workers)
+    .map(|i| {
+      // spread the workers over a period, so the rate is even
+      let offset = period.map(|p| p * i as u32 / workers as u32)
+        .unwrap_or_default();
+      tokio::spawn(load::worker(
+        client.clone(), plan.clone(), offset, progress.clone()
+      ))
+    })
+    .collect();
+
+  let reporter = {
+    let progress = progress.clone();
+    tokio::spawn(async move {
+      let mut every = tokio::time::interval(Duration::from_secs(5));
+      every.tick().await;
+      loop {
+        every.tick().await;
+        eprintln!(
+          "[{:>4.0}s] {} pushed, {} failed", start.elapsed().as_secs_f64(),
+          progress.ok.load(Ordering::Relaxed),
+          progress.failed.load(Ordering::Relaxed)
+        );
+      }
+    })
+  };
+  let mut stats = Vec::with_capacity(workers);
+  for h in handles {
+    stats.push(h.await.expect("A worker died!"));
+  }
+  reporter.abort();
+  report::print(stats, start.elapsed(), args.bundle_size);
+}
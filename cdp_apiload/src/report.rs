@@ -0,0 +1,60 @@
+//! Adding up what the workers went through.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::load::WorkerStats;
+
+/// Percentiles the report lists latencies at.
+const PERCENTILES: [(&str, f64); 4] = [
+  ("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("p99.9", 99.9)
+];
+
+/// The latency at a percentile, by nearest rank, of sorted latencies.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+  let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+  return sorted[rank.max(1) - 1];
+}
+
+/// A duration in milliseconds, for people.
+fn ms(d: Duration) -> String {
+  return format!("{:.1}", d.as_secs_f64() * 1000.0);
+}
+
+/// Prints how it went, over however long it took.
+pub(crate) fn print(
+  stats: Vec<WorkerStats>, elapsed: Duration, bundle_size: usize
+) {
+  let mut latencies = Vec::new();
+  let mut failures: BTreeMap<String, usize> = BTreeMap::new();
+  for ws in stats {
+    latencies.extend(ws.latencies);
+    for (why, n) in ws.failures {
+      *failures.entry(why).or_insert(0) += n;
+    }
+  }
+  latencies.sort();
+  let secs = elapsed.as_secs_f64();
+  let ok = latencies.len();
+  println!(
+    "Pushed {} bundles ({} messages) in {:.1}s: {:.1} bundles/s, \
+    {:.1} messages/s.",
+    ok, ok * bundle_size, secs, ok as f64 / secs,
+    (ok * bundle_size) as f64 / secs
+  );
+  let failed: usize = failures.values().sum();
+  if failed > 0 {
+    println!("Failed {}:", failed);
+    for (why, n) in failures {
+      println!("  {}: {}", why, n);
+    }
+  }
+  if let (Some(min), Some(max)) = (latencies.first(), latencies.last()) {
+    let mut line = format!("Latency (ms): min {}", ms(*min));
+    for (name, p) in PERCENTILES.iter() {
+      line.push_str(&format!("  {} {}", name, ms(percentile(&latencies, *p))));
+    }
+    line.push_str(&format!("  max {}", ms(*max)));
+    println!("{}", line);
+  }
+}
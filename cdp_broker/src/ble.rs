@@ -0,0 +1,164 @@
+//! Scans for BLE thermometers that shout their readings in advertisements,
+//! and enqueues those like MQTT data. Understands the Environmental Sensing
+//! (0x181A) service data sent by the popular custom firmwares for Xiaomi
+//! thermometers: the ATC1441 format and pvvx's own. Stock Xiaomi firmware
+//! encrypts its beacons, so those need flashing first.
+//!
+//! Only the sensors listed in the config are taken, each under the sensor ID
+//! given to its MAC. Beacons repeat every few seconds, so each sensor is
+//! forwarded at most once every ble_min_interval.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use btleplug::api::{Central, CentralEvent, Manager as _, ScanFilter};
+use btleplug::api::bleuuid::uuid_from_u16;
+use btleplug::platform::Manager;
+use futures::{FutureExt, StreamExt};
+use futures::future::BoxFuture;
+use libcdp::comm::sensor_broker::{AnySensorMessage, SensorType};
+use libcdp::ids::SensorId;
+
+use crate::broker::Broker;
+use crate::source::SensorSource;
+
+/// The Environmental Sensing service, whose data the beacons carry.
+const ENV_SENSING: u16 = 0x181A;
+/// Length of an ATC1441 beacon.
+const ATC1441_LEN: usize = 13;
+/// Length of a pvvx beacon.
+const PVVX_LEN: usize = 15;
+/// How long to wait before scanning again, if the adapter gives up.
+const RESCAN_DELAY: Duration = Duration::from_secs(10);
+
+/// What a beacon says.
+#[derive(Debug, PartialEq)]
+struct Beacon {
+  /// The sensor's MAC, most significant byte first.
+  mac: [u8; 6],
+  /// Temperature, in °C.
+  celsius: f64,
+  /// Relative humidity, in percent.
+  humidity: f64
+}
+
+impl Beacon {
+  /// Parses service data in either format. None if it's neither.
+  fn parse(data: &[u8]) -> Option<Self> {
+    return match data.len() {
+      ATC1441_LEN => Some(Self::parse_atc1441(data)),
+      PVVX_LEN => Some(Self::parse_pvvx(data)),
+      _ => None,
+    };
+  }
+
+  /// MAC in order, then big-endian temperature in 0.1 °C and humidity in
+  /// whole percent. Battery and frame counter follow, unused here.
+  fn parse_atc1441(data: &[u8]) -> Self {
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&data[0..6]);
+    return Self {
+      mac: mac,
+      celsius: i16::from_be_bytes([data[6], data[7]]) as f64 / 10.0,
+      humidity: data[8] as f64
+    };
+  }
+
+  /// MAC reversed, then little-endian temperature in 0.01 °C and humidity
+  /// in 0.01 %. Battery, counter and flags follow, unused here.
+  fn parse_pvvx(data: &[u8]) -> Self {
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&data[0..6]);
+    mac.reverse();
+    return Self {
+      mac: mac,
+      celsius: i16::from_le_bytes([data[6], data[7]]) as f64 / 100.0,
+      humidity: u16::from_le_bytes([data[8], data[9]]) as f64 / 100.0
+    };
+  }
+
+  /// Turns the readings into sensor messages, skipping any that don't fit
+  /// the wire format.
+  fn messages(&self, sensor_id: SensorId) -> Vec<AnySensorMessage> {
+    return vec![
+      (SensorType::Temperature, self.celsius),
+      (SensorType::Humidity, self.humidity),
+    ].into_iter()
+      .filter_map(|(st, value)| {
+        AnySensorMessage::from_human_value(st, sensor_id, value)
+      })
+      .collect();
+  }
+}
+
+/// Scans with the first adapter around until it stops giving us events.
+async fn scan(broker: &Broker) -> btleplug::Result<()> {
+  let manager = Manager::new().await?;
+  let central = match manager.adapters().await?.into_iter().next() {
+    Some(c) => c,
+    None => {
+      warn!("No Bluetooth adapter found.");
+      return Ok(());
+    },
+  };
+  let mut events = central.events().await?;
+  central.start_scan(ScanFilter::default()).await?;
+  info!("Scanning for BLE sensors...");
+  let service = uuid_from_u16(ENV_SENSING);
+  let mut last_sent: HashMap<[u8; 6], Instant> = HashMap::new();
+  while let Some(event) = events.next().await {
+    let beacon = match event {
+      CentralEvent::ServiceDataAdvertisement { service_data, .. } => {
+        match service_data.get(&service).and_then(|d| Beacon::parse(d)) {
+          Some(b) => b,
+          None => continue,
+        }
+      },
+      _ => continue,
+    };
+    let sensor = match broker.cfg.ble.iter().find(|s| s.mac == beacon.mac) {
+      Some(s) => s,
+      None => continue,
+    };
+    let now = Instant::now();
+    if let Some(prev) = last_sent.get(&beacon.mac) {
+      if now.duration_since(*prev) < broker.cfg.ble_min_interval {
+        continue;
+      }
+    }
+    last_sent.insert(beacon.mac, now);
+    for msg in beacon.messages(sensor.sensor_id) {
+      broker.ingest_message(msg).await;
+    }
+  }
+  return Ok(());
+}
+
+/// Scans for BLE sensors forever.
+async fn serve(broker: Arc<Broker>) {
+  loop {
+    if let Err(e) = scan(&broker).await {
+      warn!("BLE scan failed: {}", e);
+    }
+    tokio::time::sleep(RESCAN_DELAY).await;
+  }
+}
+
+/// The BLE scanner, as a sensor source. Listens for every sensor in the
+/// config.
+pub(crate) struct BleSource;
+
+impl SensorSource for BleSource {
+  fn name(&self) -> String {
+    return "BLE scanner".to_owned();
+  }
+
+  fn again(&self) -> Option<Box<dyn SensorSource>> {
+    return Some(Box::new(BleSource));
+  }
+
+  fn run(self: Box<Self>, broker: Arc<Broker>) -> BoxFuture<'static, ()> {
+    return serve(broker).boxed();
+  }
+}
@@ -1,237 +1,1043 @@
 //! Implements functions related to communicating with the API, and abstracts
 //! away the whole "Broker" inner state.
 
-use std::str::FromStr;
-use std::sync::Arc;
-use std::thread;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex as StdMutex, MutexGuard as StdMutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
 
+use cdp_client::BundleFormat;
 use chrono::{DateTime, Local};
-use libcdp::comm::broker_api::{BrokerMessage, BrokerMessageBundle, BrokerMessagePayload, HeartbeatMessage};
-use libcdp::comm::sensor_broker::{AnySensorMessage, SensorType};
+use futures::FutureExt;
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessageBundle, BrokerMessagePayload, BrokerStatus, BundleAck, HeartbeatMessage, RemoteConfig, SoftwareInfo};
+use libcdp::comm::broker_api::{self, features};
+use libcdp::comm::sensor_broker::{AnySensorMessage, DeviceHealthMessage, MessageParseError};
+use libcdp::comm::decoders;
+use libcdp::comm::signing::{self, Tag};
+use libcdp::comm::update::{UpdateOrder, UpdateReport, UpdateState};
+use libcdp::ids::SensorId;
+#[cfg(feature = "systemd")]
+use libcdp::systemd;
 
-use reqwest::{Client, Response};
-use tokio::sync::mpsc::error::SendError;
-use crate::config::BrokerConfig;
-use tokio::sync::{Mutex, MutexGuard};
+use tokio::sync::mpsc::error::TrySendError;
+use crate::budget::Budget;
+use crate::capture::Capture;
+use crate::config::{BackpressurePolicy, BrokerConfig};
+use crate::deadletter::DeadLetters;
+use crate::enroll;
+use crate::health::{self, Liveness};
+use crate::mqtt;
+use crate::remote::{self, LiveSettings};
+use crate::route::Route;
+use crate::source;
+use crate::spool::Spool;
+use crate::supervisor;
+use crate::transform;
+use crate::update;
+use crate::uplink::UplinkError;
+use tokio::sync::Mutex;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 
+/// What became of a raw payload handed to the broker.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RawOutcome {
+  /// Decoded and on its way home.
+  Queued,
+  /// A sensor type we weren't told to care about, or dropped by a
+  /// transform.
+  Ignored,
+  /// Not a topic we know.
+  BadTopic,
+  /// Didn't decode.
+  BadPayload,
+  /// From a sensor with a key, but unsigned or signed wrong.
+  BadSignature,
+  /// Decoded, but lost to backpressure.
+  Dropped
+}
+
 /// the entire state of the broker.
 #[derive(Debug)]
 pub(crate) struct Broker {
   /// Broker config.
   pub(crate) cfg: BrokerConfig,
-  /// Configuration for rumqqtd.
-  pub(crate) rumqttd_cfg: librumqttd::Config,
+  /// Configuration for rumqqtd. None if the embedded MQTT broker is off.
+  pub(crate) rumqttd_cfg: Option<librumqttd::Config>,
   /// Time of last successful exchange of data.
   pub(crate) last_seen: Mutex<Option<DateTime<Local>>>,
   /// Message queue for sending home when ready.
   message_comm: (Sender<BrokerMessage>, Arc<Mutex<Receiver<BrokerMessage>>>),
-  /// Message bundle within. Thread-safe.
-  message_bundle: Arc<Mutex<BrokerMessageBundle>>
+  /// Where messages are bundled and sent, first match wins. The last one is
+  /// the default route, which takes everything.
+  routes: Vec<Route>,
+  /// Capacity of the message queue.
+  queue_capacity: usize,
+  /// When the broker came up.
+  started_when: Instant,
+  /// Sensor messages decoded since the last heartbeat.
+  decoded: AtomicU64,
+  /// Sensor messages that failed to decode since the last heartbeat.
+  decode_errors: AtomicU64,
+  /// Messages lost to backpressure since the last heartbeat.
+  dropped: AtomicU64,
+  /// Messages the API said it stored since the last heartbeat.
+  acked: AtomicU64,
+  /// Messages the API said it didn't store since the last heartbeat.
+  refused: AtomicU64,
+  /// Bundles and heartbeats that timed out since the last heartbeat.
+  timeouts: AtomicU64,
+  /// Tasks that panicked and were started over since the last heartbeat.
+  restarts: AtomicU64,
+  /// What may be sent home, and what was. None if there's no limit.
+  budget: Option<Budget>,
+  /// Whether everything is still going, for health checks.
+  pub(crate) liveness: Liveness,
+  /// Where messages go when the channel is full, if so configured.
+  spool: Option<Spool>,
+  /// Where MQTT publishes are recorded, if we're capturing.
+  capture: Option<Capture>,
+  /// Payloads that didn't decode, waiting to be sent home. None if we keep
+  /// none.
+  dead_letters: Option<DeadLetters>,
+  /// Whether the API said it takes dead letters, as of the last heartbeat.
+  api_takes_dead_letters: AtomicBool,
+  /// Distinct MQTT topic filters subscribed to, on any MQTT broker.
+  mqtt_subscriptions: StdMutex<BTreeSet<String>>,
+  /// Key heartbeats are sent with. Starts as the one in the key file, or
+  /// the config's unless enrolling, and changes when the API rotates it.
+  home_key: StdMutex<Option<String>>,
+  /// Settings the API may change.
+  live: StdMutex<LiveSettings>,
+  /// Updates ordered by the API, for the updater to install.
+  update_orders: (Sender<UpdateOrder>, Arc<Mutex<Receiver<UpdateOrder>>>),
+  /// How the latest update went, for the next heartbeat.
+  update_report: StdMutex<Option<UpdateReport>>
 }
 
-impl From<(BrokerConfig, librumqttd::Config)> for Broker {
-  fn from((bc, rc): (BrokerConfig, librumqttd::Config)) -> Self {
-    let (s, r) = mpsc::channel(bc.bundle_size * bc.buffer_size_bundles);
+impl From<(BrokerConfig, Option<librumqttd::Config>)> for Broker {
+  fn from((bc, rc): (BrokerConfig, Option<librumqttd::Config>)) -> Self {
+    let capacity = bc.bundle_size * bc.buffer_size_bundles;
+    let (s, r) = mpsc::channel(capacity);
+    let spool = match &bc.backpressure {
+      BackpressurePolicy::Spool(path) => Some(
+        Spool::open(path.clone())
+          .unwrap_or_else(|e| panic!("Can't open the spool: {}", e))
+      ),
+      _ => None,
+    };
+    let capture = bc.capture_file.as_ref().map(|path| {
+      Capture::open(path)
+        .unwrap_or_else(|e| panic!("Can't open the capture file: {}", e))
+    });
+    let dead_letters = match bc.dead_letters {
+      0 => None,
+      n => Some(
+        DeadLetters::open(n, bc.dead_letter_path.clone())
+          .unwrap_or_else(|e| panic!("Can't open the dead letters: {}", e))
+      ),
+    };
+    let routes = bc.routes.iter()
+      .cloned()
+      .chain(std::iter::once(bc.default_route()))
+      .map(|r| {
+        let seal = bc.seal_key.clone().map(|k| (bc.uid, k));
+        return Route::new(
+          r, bc.wire_format, seal, bc.http_timeouts, bc.chaos.as_ref()
+        );
+      })
+      .collect();
+    let home_key = bc.key_file.as_ref()
+      .and_then(|path| std::fs::read_to_string(path).ok())
+      .map(|key| key.trim().to_owned())
+      .filter(|key| !key.is_empty())
+      .or_else(|| match bc.enrollment {
+        Some(_) => None,
+        None => bc.home_key.clone(),
+      });
+    let remote = bc.remote_config_file.as_deref()
+      .and_then(remote::load)
+      .unwrap_or_default();
+    let live = LiveSettings::new(&bc, remote)
+      .or_else(|e| {
+        warn!("Ignoring the API's settings: {}", e);
+        LiveSettings::new(&bc, RemoteConfig::default())
+      })
+      .expect("The config file's own settings are fine");
+    let budget = bc.bandwidth_budget.clone().map(Budget::new);
+    let (us, ur) = mpsc::channel(1);
     return Self {
       cfg: bc,
       rumqttd_cfg: rc,
       last_seen: Mutex::new(None),
       message_comm: (s, Arc::new(Mutex::new(r))),
-      message_bundle: Arc::new(Mutex::new(BrokerMessageBundle::new())),
+      routes: routes,
+      queue_capacity: capacity,
+      started_when: Instant::now(),
+      decoded: AtomicU64::new(0),
+      decode_errors: AtomicU64::new(0),
+      dropped: AtomicU64::new(0),
+      acked: AtomicU64::new(0),
+      refused: AtomicU64::new(0),
+      timeouts: AtomicU64::new(0),
+      restarts: AtomicU64::new(0),
+      budget: budget,
+      liveness: Liveness::default(),
+      spool: spool,
+      capture: capture,
+      dead_letters: dead_letters,
+      api_takes_dead_letters: AtomicBool::new(false),
+      mqtt_subscriptions: StdMutex::new(BTreeSet::new()),
+      home_key: StdMutex::new(home_key),
+      live: StdMutex::new(live),
+      update_orders: (us, Arc::new(Mutex::new(ur))),
+      update_report: StdMutex::new(None),
     };
   }
 }
 
 impl Broker {
+  /// Used to acquire a lock on the key we call home with. A task that
+  /// panicked holding it can't have left it half-written.
+  fn home_key(&self) -> StdMutexGuard<'_, Option<String>> {
+    return self.home_key.lock().unwrap_or_else(|e| e.into_inner());
+  }
+
+  /// Used to acquire a lock on the report of the last update.
+  fn update_report(&self) -> StdMutexGuard<'_, Option<UpdateReport>> {
+    return self.update_report.lock().unwrap_or_else(|e| e.into_inner());
+  }
+
   /// Private function, used by other ones to update the last_seen cell.
   async fn update_last_seen(&self) {
     let mut ls = self.last_seen.lock().await;
     ls.replace(Local::now());
   }
 
+  /// Records an MQTT publish, if we're capturing. Failing to is no reason
+  /// to lose the publish, so it's only complained about.
+  pub(crate) fn capture(&self, topic: &str, payload: &[u8]) {
+    if let Some(capture) = &self.capture {
+      if let Err(e) = capture.record(topic, payload) {
+        warn!("Failed to capture a {} publish: {}", topic, e);
+      }
+    }
+  }
+
   /// Expose a sender pipe so other threads can give us stuff to send.
   pub(crate) fn get_queue_sender(&self) -> Sender<BrokerMessage> {
     return self.message_comm.0.clone();
   }
 
-  /// Handles an HTTP response from the API. Returns only success responses.
-  async fn handle_response(&self, maybe_resp: Result<Response, reqwest::Error>)
-  -> Option<Response> {
-    if let Ok(resp) = maybe_resp {
-      if resp.status().is_success() {
+  /// Handles the outcome of a delivery. Returns whether it went through.
+  async fn delivered(&self, res: Result<(), UplinkError>) -> bool {
+    return match res {
+      Ok(()) => {
         self.update_last_seen().await;
-        return Some(resp);
+        self.liveness.uplink(None);
+        true
+      },
+      Err(e) => {
+        if let UplinkError::Timeout(_) = e {
+          self.timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+        self.liveness.uplink(Some(e.to_string()));
+        warn!("{}", e);
+        false
+      },
+    };
+  }
+
+  /// Counts a decode attempt, for the next heartbeat.
+  fn count_decode(&self, ok: bool) {
+    let ctr = if ok { &self.decoded } else { &self.decode_errors };
+    ctr.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Notes a topic filter some MQTT input subscribed to.
+  pub(crate) fn count_subscription(&self, filter: &str) {
+    let mut subs = self.mqtt_subscriptions.lock()
+      .unwrap_or_else(|e| e.into_inner());
+    subs.insert(filter.to_owned());
+  }
+
+  /// Whether any MQTT input is enabled.
+  fn speaks_mqtt(&self) -> bool {
+    return self.rumqttd_cfg.is_some() || self.cfg.external_mqtt.is_some();
+  }
+
+  /// Gathers our health figures, summed over every route. Resets the
+  /// since-last-heartbeat counters.
+  async fn status(&self) -> BrokerStatus {
+    let mut bundle_len = 0;
+    for route in &self.routes {
+      bundle_len += route.lock_bundle().await.len();
+    }
+    let pending = self.routes.iter().map(|r| r.lock_pending().len()).sum();
+    return BrokerStatus {
+      uptime_secs: self.started_when.elapsed().as_secs(),
+      queue_depth: self.queue_capacity
+        .saturating_sub(self.message_comm.0.capacity()),
+      bundle_len: bundle_len,
+      decoded_since_last: self.decoded.swap(0, Ordering::Relaxed),
+      decode_errors_since_last: self.decode_errors.swap(0, Ordering::Relaxed),
+      dropped_since_last: self.dropped.swap(0, Ordering::Relaxed),
+      spooled: self.spool.as_ref().map(|s| s.len()).unwrap_or(0),
+      bundles_pending: pending,
+      bundles_in_flight: self.routes.iter().map(Route::in_flight).sum(),
+      mqtt_connections: mqtt::embedded_connections(self).await,
+      mqtt_subscriptions: if self.speaks_mqtt() {
+        Some(self.mqtt_subscriptions.lock()
+          .unwrap_or_else(|e| e.into_inner())
+          .len())
       } else {
-        eprintln!("Got a non-2xx response:\n{:#?}", resp);
+        None
+      },
+      acked_since_last: self.acked.swap(0, Ordering::Relaxed),
+      refused_since_last: self.refused.swap(0, Ordering::Relaxed),
+      timeouts_since_last: self.timeouts.swap(0, Ordering::Relaxed),
+      bandwidth_used_bytes: self.budget.as_ref().map(Budget::used),
+      bandwidth_budget_bytes: self.budget.as_ref().map(Budget::limit),
+      task_restarts_since_last: self.restarts.swap(0, Ordering::Relaxed)
+    };
+  }
+
+  /// The route for whatever no configured one takes.
+  fn default_route(&self) -> &Route {
+    return self.routes.last().expect("No default route?");
+  }
+
+  /// Which route a message goes down, by index.
+  fn route_for(&self, payload: &BrokerMessagePayload) -> usize {
+    return self.routes.iter()
+      .position(|r| r.takes(payload))
+      .unwrap_or(self.routes.len() - 1);
+  }
+
+  /// Send a small request to the API to see if it's up, along with how
+  /// we're doing. Goes the default route's way.
+  pub(crate) async fn heartbeat(&self) -> bool {
+    let mut hb = HeartbeatMessage::from(&self.cfg);
+    hb.key = self.home_key().clone();
+    hb.status = Some(self.status().await);
+    hb.config_hash = Some(self.live().remote.hash());
+    hb.update = self.update_report().clone();
+    hb.software = Some(self.software());
+    if let Some(budget) = &self.budget {
+      budget.spend(serde_json::to_vec(&hb).map(|v| v.len()).unwrap_or(0));
+    }
+    let res = self.default_route().uplink.heartbeat(&hb).await
+      .map(|reply| {
+        let takes = reply.api.as_ref()
+          .map(|api| api.has(features::DECODE_FAILURES))
+          .unwrap_or(false);
+        self.api_takes_dead_letters.store(takes, Ordering::Relaxed);
+        if let Some(api) = reply.api.filter(|api| !api.compatible()) {
+          warn!(
+            "The API speaks protocol {} and wants at least {}; we speak {}.",
+            api.protocol, api.min_protocol, broker_api::PROTOCOL_VERSION
+          );
+        }
+        if let Some(key) = reply.new_key {
+          warn!("The API rotated our key.");
+          self.keep_key(key);
+        }
+        if let Some(remote) = reply.config {
+          self.apply_remote(remote);
+        }
+        if let Some(order) = reply.update {
+          self.order_update(order);
+        }
+      });
+    return self.delivered(res).await;
+  }
+
+  /// What we run, and can do. Updates only if there's a hook to take them.
+  fn software(&self) -> SoftwareInfo {
+    let mut can = vec![
+      features::REMOTE_CONFIG, features::KEY_ROTATION, features::ENROLLMENT,
+      features::SEALING, features::SIGNING, features::PROTOBUF,
+      features::NDJSON
+    ];
+    if self.cfg.update_hook.is_some() {
+      can.push(features::UPDATES);
+    }
+    if self.dead_letters.is_some() {
+      can.push(features::DECODE_FAILURES);
+    }
+    if cfg!(feature = "ble") {
+      can.push("ble");
+    }
+    if cfg!(feature = "systemd") {
+      can.push("systemd");
+    }
+    return SoftwareInfo::new(env!("CARGO_PKG_VERSION"), &can);
+  }
+
+  /// The settings the API may change, as they are now.
+  fn live(&self) -> std::sync::MutexGuard<'_, LiveSettings> {
+    return self.live.lock().unwrap_or_else(|e| e.into_inner());
+  }
+
+  /// Switches to settings the API sent, and keeps them so they outlive us.
+  /// Settings that make no sense are turned down, and keep coming until
+  /// someone fixes them on the API's end.
+  fn apply_remote(&self, remote: RemoteConfig) {
+    let live = match LiveSettings::new(&self.cfg, remote) {
+      Ok(l) => l,
+      Err(e) => {
+        warn!("Not applying the API's settings: {}", e);
+        return;
+      },
+    };
+    info!("Applying settings from the API: {:?}", live.remote);
+    match &self.cfg.remote_config_file {
+      Some(path) => {
+        if let Err(e) = remote::save(path, &live.remote) {
+          warn!("Couldn't save them to {}: {}", path.display(), e);
+        }
+      },
+      None => {
+        warn!("No remote_config_file set, they'll be lost on restart!");
+      },
+    };
+    *self.live() = live;
+  }
+
+  /// Hands an update the API ordered to the updater, and tells the API we
+  /// heard of it. If the updater's busy, the API will order it again.
+  fn order_update(&self, order: UpdateOrder) {
+    let id = order.id;
+    match self.update_orders.0.try_send(order) {
+      Ok(()) => self.report_update(UpdateReport {
+        id: id,
+        state: UpdateState::Downloading,
+        message: None
+      }),
+      Err(_) => warn!("Already updating, the next update has to wait."),
+    };
+  }
+
+  /// Keeps how an update went, for the next heartbeat.
+  pub(crate) fn report_update(&self, report: UpdateReport) {
+    self.update_report().replace(report);
+  }
+
+  /// How big a route's bundles get. The default route's size may be set by
+  /// the API.
+  fn bundle_size(&self, route: &Route) -> usize {
+    if std::ptr::eq(route, self.default_route()) {
+      return self.live().bundle_size;
+    }
+    return route.cfg.bundle_size;
+  }
+
+  /// Switches to a key the API handed us, and keeps it in the key file so
+  /// it outlives us. The file is replaced whole, so a crash halfway
+  /// through leaves the old key, which still works for a while.
+  fn keep_key(&self, key: String) {
+    self.home_key().replace(key.clone());
+    let path = match &self.cfg.key_file {
+      Some(path) => path,
+      None => {
+        warn!("No key_file set, the new key will be lost on restart!");
+        return;
+      },
+    };
+    let tmp = path.with_extension("tmp");
+    let res = std::fs::write(&tmp, key + "\n")
+      .and_then(|_| std::fs::rename(&tmp, path));
+    if let Err(e) = res {
+      warn!("Couldn't save the new key to {}: {}", path.display(), e);
+    }
+  }
+
+  /// Counts messages lost to backpressure, for the next heartbeat.
+  fn count_dropped(&self, n: u64) {
+    if n > 0 {
+      warn!("Dropped {} message(s) due to backpressure!", n);
+      self.dropped.fetch_add(n, Ordering::Relaxed);
+    }
+  }
+
+  /// Enqueue a message. If the channel is full, the backpressure policy
+  /// decides what happens. Returns whether the message was kept.
+  async fn enqueue(&self, msg: BrokerMessage) -> bool {
+    let tx = self.get_queue_sender();
+    let msg = match tx.try_send(msg) {
+      Ok(_) => return true,
+      Err(TrySendError::Full(msg)) => msg,
+      Err(TrySendError::Closed(_)) => {
+        self.count_dropped(1);
+        return false;
+      },
+    };
+    match &self.cfg.backpressure {
+      BackpressurePolicy::Block(wait) => {
+        let room = tokio::time::timeout(*wait, tx.reserve()).await;
+        if let Ok(Ok(permit)) = room {
+          permit.send(msg);
+          return true;
+        }
+      },
+      BackpressurePolicy::DropOldest => {
+        let route = &self.routes[self.route_for(&msg.payload)];
+        let mut bnd = route.lock_bundle().await;
+        if bnd.len() >= self.bundle_size(route) {
+          bnd.remove(0);
+          self.count_dropped(1);
+        }
+        bnd.push(msg);
+        return true;
+      },
+      BackpressurePolicy::Spool(_) => {
+        if let Some(spool) = &self.spool {
+          match spool.push(&msg) {
+            Ok(_) => return true,
+            Err(e) => warn!("Failed to spool a message: {}", e),
+          };
+        }
+      },
+    };
+    self.count_dropped(1);
+    return false;
+  }
+
+  /// Checks a topic before decoding anything published to it. Device health
+  /// is always taken, sensor data only for the configured types.
+  fn check_topic(&self, topic: &str) -> Result<(), RawOutcome> {
+    if topic == DeviceHealthMessage::TOPIC {
+      return Ok(());
+    }
+    return match decoders::find(topic) {
+      Some(d) if self.live().topics.contains(&d.sensor_type) => Ok(()),
+      Some(_) => Err(RawOutcome::Ignored),
+      None => {
+        warn!("Some sensor sent us a bad topic: \"{}\"", topic);
+        Err(RawOutcome::BadTopic)
+      },
+    };
+  }
+
+  /// Decodes a sensor payload, taking its signature off and checking it if
+  /// its sensor has a key. Sensors with a key must sign, and those without
+  /// one mustn't, nor get through at all if signatures are required.
+  /// Returns the message, and whether it was signed.
+  fn decode_signed(
+    &self,
+    topic: &str,
+    data: &[u8],
+    split: fn(&[u8]) -> Option<(&[u8], Tag)>,
+    decode: impl Fn(&[u8]) -> Result<AnySensorMessage, MessageParseError>
+  ) -> Result<(AnySensorMessage, bool), MessageParseError> {
+    let keys = &self.cfg.sensor_keys;
+    let key_of = |msg: &AnySensorMessage| {
+      return keys.get(&(msg.sensor_type(), msg.sensor_id()));
+    };
+    let stype = decoders::find(topic)
+      .map(|d| d.sensor_type)
+      .ok_or_else(|| MessageParseError::BadTopic(topic.to_owned()))?;
+    let unsigned = |msg| match self.cfg.require_signatures {
+      true => Err(MessageParseError::BadSignature),
+      false => Ok((msg, false)),
+    };
+    if !keys.keys().any(|(st, _)| *st == stype) {
+      return unsigned(decode(data)?);
+    }
+    // signed, if taking the tag off leaves a message from a keyed sensor
+    let signed = split(data)
+      .and_then(|(body, tag)| Some((decode(body).ok()?, body, tag)));
+    if let Some((msg, body, tag)) = signed {
+      if let Some(key) = key_of(&msg) {
+        if !signing::verify(key.as_bytes(), topic, body, &tag) {
+          return Err(MessageParseError::BadSignature);
+        }
+        return Ok((msg, true));
       }
     }
-    return None;
+    let msg = decode(data)?;
+    if key_of(&msg).is_some() {
+      return Err(MessageParseError::BadSignature);
+    }
+    return unsigned(msg);
   }
 
-  /// Send a small request to the API to see if it's up.
-  pub(crate) async fn heartbeat(&self) -> bool {
-    let tgt = self.cfg.endpoint.join("heartbeat").expect("Bad endpoint URL?");
-    let client = reqwest::Client::new();
-    let maybe_resp = client
-      .post(tgt)
-      .json(&HeartbeatMessage::from(&self.cfg))
-      .send()
-      .await;
-    return self.handle_response(maybe_resp).await.is_some();
-  }
-
-  /// Used to acquire a full-on lock on the message bundle.
-  async fn lock_bundle(&self) -> MutexGuard<'_, BrokerMessageBundle> {
-    return self.message_bundle.lock().await;
-  }
-
-  /// Enqueue a message.
-  async fn enqueue(&self, payload: BrokerMessagePayload)
-  -> Result<(), SendError<BrokerMessage>> {
-    let msg = BrokerMessage::construct(self.cfg.uid, payload);
-    return self.get_queue_sender().send(msg).await;
-  }
-
-  /// Sends a message bundle to API. Called on a timer, or when receiver size
-  /// reaches 10. Must be nice. We don't clear the bundle.
-  /// It's up to the caller.
-  async fn send_bundle(&self, require_size: bool) -> bool {
-    let real_bnd = self.lock_bundle().await;
-    let mut bnd = real_bnd.clone();
+  /// Keeps a payload that didn't decode as a dead letter, if we keep any.
+  fn bury(&self, topic: &str, raw: &[u8], e: &MessageParseError) {
+    let dead_letters = match &self.dead_letters {
+      Some(d) => d,
+      None => return,
+    };
+    let msg = BrokerMessage::construct(
+      self.cfg.uid,
+      BrokerMessagePayload::DecodeFailure {
+        topic: topic.to_owned(),
+        bytes: base64::encode(raw),
+        error: e.to_string()
+      }
+    );
+    match dead_letters.push(&msg) {
+      Ok(0) => (),
+      Ok(n) => warn!("No room for dead letters, {} old one(s) went.", n),
+      Err(e) => warn!("Failed to keep a dead letter: {}", e),
+    };
+  }
+
+  /// Sends dead letters home the default route's way, a bundle's worth at a
+  /// time, if the API takes them. Apart from sensor data, so an API that
+  /// chokes on them can't hold any up. Those that don't make it wait for
+  /// the next heartbeat.
+  async fn forward_dead_letters(&self) {
+    let dead_letters = match &self.dead_letters {
+      Some(d) if self.api_takes_dead_letters.load(Ordering::Relaxed) => d,
+      _ => return,
+    };
+    let route = self.default_route();
+    loop {
+      if self.budget.as_ref().map(Budget::spent).unwrap_or(false) {
+        info!("Dead letters wait for some bandwidth budget to free up.");
+        return;
+      }
+      let max = self.bundle_size(route);
+      let mut bnd = match dead_letters.take(max) {
+        Ok(b) if b.is_empty() => return,
+        Ok(b) => b,
+        Err(e) => {
+          warn!("Failed to read the dead letters: {}", e);
+          return;
+        },
+      };
+      if !self.send_bundle(route, &mut bnd).await {
+        for msg in bnd.iter() {
+          if let Err(e) = dead_letters.push(msg) {
+            warn!("Lost a dead letter: {}", e);
+          }
+        }
+        return;
+      }
+      info!("Sent {} dead letter(s) home.", bnd.len());
+    }
+  }
+
+  /// Counts and enqueues whatever came out of decoding a sensor payload,
+  /// along with the payload itself if so configured. The flag says whether
+  /// the payload was signed.
+  async fn accept(
+    &self,
+    topic: &str,
+    raw: Option<&[u8]>,
+    dec: Result<(BrokerMessagePayload, bool), MessageParseError>
+  ) -> RawOutcome {
+    let decoded_when = Local::now();
+    self.count_decode(dec.is_ok());
+    let (pl, verified) = match dec {
+      Ok(d) => d,
+      Err(MessageParseError::BadSignature) => {
+        warn!("Sensor sent {} data with a bad signature.", topic);
+        return RawOutcome::BadSignature;
+      },
+      Err(e) => {
+        warn!("Sensor sent bad {} data: {}.", topic, e);
+        self.bury(topic, raw.unwrap_or_default(), &e);
+        return RawOutcome::BadPayload;
+      },
+    };
+    let sensor_id = match &pl {
+      BrokerMessagePayload::SensorData(sd) => sd.sensor_id(),
+      BrokerMessagePayload::DeviceHealth(dh) => SensorId(dh.sensor_id),
+      BrokerMessagePayload::Heartbeat(_) => SensorId(0),
+      BrokerMessagePayload::DecodeFailure { .. } => SensorId(0),
+    };
+    info!("Got {} data from sensor #{}!", topic, sensor_id);
+    let mut msg = BrokerMessage::construct(self.cfg.uid, pl);
+    msg.decoded_when = Some(decoded_when);
+    msg.verified = verified;
+    if let (true, Some(raw)) = (self.cfg.retain_raw, raw) {
+      msg.set_raw_payload(raw);
+    }
+    let msg = match transform::apply(&self.cfg.transforms, msg) {
+      Some(m) => m,
+      None => return RawOutcome::Ignored,
+    };
+    if !self.enqueue(msg).await {
+      warn!("Failed to enqueue {} data.", topic);
+      return RawOutcome::Dropped;
+    }
+    return RawOutcome::Queued;
+  }
+
+  /// Decodes a raw payload from a sensor, as published to a topic, and
+  /// enqueues it. Whatever the sensor came in through.
+  pub(crate) async fn ingest_raw(&self, topic: &str, pbytes: Vec<u8>)
+  -> RawOutcome {
+    if let Err(outcome) = self.check_topic(topic) {
+      return outcome;
+    }
+    let dec = if topic == DeviceHealthMessage::TOPIC {
+      DeviceHealthMessage::try_from(&pbytes)
+        .map(|dh| (BrokerMessagePayload::DeviceHealth(dh), false))
+    } else {
+      self.decode_signed(topic, &pbytes, signing::split, |data| {
+        return AnySensorMessage::decode(topic, data.to_vec());
+      }).map(|(sd, v)| (BrokerMessagePayload::SensorData(sd), v))
+    };
+    return self.accept(topic, Some(&pbytes), dec).await;
+  }
+
+  /// Like ingest_raw, but the payload is the JSON form of the message, for
+  /// devices that would rather not pack bytes.
+  pub(crate) async fn ingest_json(&self, topic: &str, json: &[u8])
+  -> RawOutcome {
+    if let Err(outcome) = self.check_topic(topic) {
+      return outcome;
+    }
+    let dec = if topic == DeviceHealthMessage::TOPIC {
+      DeviceHealthMessage::decode_json(json)
+        .map(|dh| (BrokerMessagePayload::DeviceHealth(dh), false))
+    } else {
+      self.decode_signed(topic, json, signing::split_json, |data| {
+        return AnySensorMessage::decode_json(topic, data);
+      }).map(|(sd, v)| (BrokerMessagePayload::SensorData(sd), v))
+    };
+    return self.accept(topic, Some(json), dec).await;
+  }
+
+  /// Enqueues a sensor message some input decoded on its own, as if it had
+  /// been published to its topic. There are no raw bytes to keep.
+  pub(crate) async fn ingest_message(&self, msg: AnySensorMessage)
+  -> RawOutcome {
+    let topic = msg.sensor_type().to_string();
+    if let Err(outcome) = self.check_topic(&topic) {
+      return outcome;
+    }
+    let pl = BrokerMessagePayload::SensorData(msg);
+    return self.accept(&topic, None, Ok((pl, false))).await;
+  }
+
+  /// Moves spooled messages back into the channel, as far as there's room.
+  /// If someone beats us to the room, the rest stay at the front of the
+  /// spool, so they still go before anything spooled after them.
+  fn unspool(&self) {
+    let spool = match &self.spool {
+      Some(s) => s,
+      None => return,
+    };
+    let tx = self.get_queue_sender();
+    let res = spool.drain(tx.capacity(), |msg| match tx.try_send(msg) {
+      Ok(()) => None,
+      Err(TrySendError::Full(msg)) | Err(TrySendError::Closed(msg)) => {
+        Some(msg)
+      },
+    });
+    match res {
+      Ok((_, bad)) => self.count_dropped(bad as u64),
+      Err(e) => warn!("Failed to read the spool: {}", e),
+    }
+  }
+
+  /// Takes a route's current bundle out to be sent, giving it a sequence
+  /// number. Called on a timer, or when the bundle fills up. Returns whether
+  /// a bundle was sealed.
+  async fn seal_bundle(&self, route: &Route, require_size: bool) -> bool {
+    let mut real_bnd = route.lock_bundle().await;
+    if real_bnd.len() == 0 { return false; }
+    if require_size && real_bnd.len() < self.bundle_size(route) {
+      return false;
+    }
+    let bnd = std::mem::take(&mut *real_bnd);
     std::mem::drop(real_bnd);
-    if bnd.len() == 0 { return false; }
-    if require_size && bnd.len() < self.cfg.bundle_size { return false; };
-    println!("Sending bundle!");
+    let seq = route.next_seq.fetch_add(1, Ordering::Relaxed);
+    let mut pending = route.lock_pending();
+    pending.insert(seq, bnd);
+    // don't hoard bundles forever while the API is away.
+    while pending.len() > route.cfg.buffer_size_bundles {
+      let oldest = *pending.keys().next().unwrap();
+      let lost = pending.remove(&oldest).map(|b| b.len()).unwrap_or(0);
+      self.count_dropped(lost as u64);
+    }
+    return true;
+  }
+
+  /// Whether a bundle may be sent now, as far as the bandwidth budget goes.
+  fn within_budget(&self, bnd: &[BrokerMessage]) -> bool {
+    return self.budget.as_ref().map(|b| b.allows(bnd)).unwrap_or(true);
+  }
+
+  /// Sends as many of a route's sealed bundles as it has free slots, oldest
+  /// first, leaving those the bandwidth budget holds back for when it frees
+  /// up. Those that must go in order wait behind them. Each send runs on
+  /// its own task. Failed bundles go back to wait
+  /// for the next try; successful ones make room to drain the spool.
+  ///
+  /// A bundle counts as failed unless the API says it took it, even if it
+  /// may have: a reply lost on the way looks like a request lost on the
+  /// way. So bundles are sent until taken, with the same messages, IDs and
+  /// all, and the API leaves out messages it has stored before. Together,
+  /// that's at-least-once delivery without duplicates, short of bundles
+  /// dropped for the buffer being full.
+  fn dispatch(broker: &Arc<Self>, idx: usize) {
+    let route = &broker.routes[idx];
+    loop {
+      let permit = match route.send_slots.clone().try_acquire_owned() {
+        Ok(p) => p,
+        Err(_) => return,
+      };
+      let next = {
+        let mut pending = route.lock_pending();
+        let looked_at = if broker.cfg.preserve_order { 1 } else { usize::MAX };
+        let seq = pending.iter()
+          .take(looked_at)
+          .find(|(_, bnd)| broker.within_budget(bnd))
+          .map(|(seq, _)| *seq);
+        if seq.is_none() && !pending.is_empty() {
+          info!(
+            "Over the bandwidth budget, holding {} {} bundle(s) back.",
+            pending.len(), route.cfg.name
+          );
+        }
+        seq.and_then(|seq| pending.remove_entry(&seq))
+      };
+      let (seq, mut bnd) = match next {
+        Some(n) => n,
+        None => return,
+      };
+      let b = broker.clone();
+      tokio::spawn(async move {
+        if b.send_bundle(&b.routes[idx], &mut bnd).await {
+          std::mem::drop(permit);
+          b.unspool();
+          Broker::dispatch(&b, idx);
+        } else {
+          // put it back. the next timer tick will try again.
+          b.routes[idx].lock_pending().insert(seq, bnd);
+          std::mem::drop(permit);
+        }
+      });
+    }
+  }
+
+  /// Sends a message bundle up a route's uplink. Must be nice. Returns
+  /// whether it was taken.
+  async fn send_bundle(&self, route: &Route, bnd: &mut BrokerMessageBundle)
+  -> bool {
+    info!("Sending {} bundle!", route.cfg.name);
     bnd.iter_mut().for_each(|msg| msg.sent_when = Some(Local::now()));
-    let tgt = self.cfg.endpoint.join("bundle").expect("Bad endpoint URL?");
-    let cl = Client::new();
-    let maybe_resp = cl
-      .post(tgt)
-      .json(&bnd as &BrokerMessageBundle)
-      .send()
-      .await;
-    return self.handle_response(maybe_resp).await.is_some();
+    let sent = bnd.len();
+    if let Some(budget) = &self.budget {
+      budget.spend(BundleFormat::from(self.cfg.wire_format).encode(bnd).len());
+    }
+    let key = self.home_key().clone();
+    let res = route.uplink.send_bundle(bnd, key.as_deref()).await.map(|ack| {
+      if let Some(ack) = ack {
+        self.count_ack(route, sent, &ack);
+      }
+    });
+    return self.delivered(res).await;
+  }
+
+  /// Logs what the API made of a bundle, and counts it for the next
+  /// heartbeat. Complains if it doesn't add up.
+  fn count_ack(&self, route: &Route, sent: usize, ack: &BundleAck) {
+    let numbered = match ack.seq_range {
+      Some((lo, hi)) => format!(", numbered {} to {}", lo, hi),
+      None => String::new(),
+    };
+    info!(
+      "The API stored {} of {} {} message(s){}.",
+      ack.accepted, sent, route.cfg.name, numbered
+    );
+    let mut resent = 0;
+    for (why, n) in ack.reasons.iter() {
+      if why == BundleAck::DUPLICATE {
+        info!("The API already had {} message(s) of them.", n);
+        resent = *n;
+      } else {
+        warn!("The API turned {} message(s) down: {}", n, why);
+      }
+    }
+    if ack.accepted + ack.rejected != sent {
+      warn!(
+        "The API accounted for {} message(s) of the {} sent!",
+        ack.accepted + ack.rejected, sent
+      );
+    }
+    // already stored is as good as stored
+    self.acked.fetch_add((ack.accepted + resent) as u64, Ordering::Relaxed);
+    self.refused.fetch_add(
+      ack.rejected.saturating_sub(resent) as u64, Ordering::Relaxed
+    );
   }
 
   /// Starts the broker, main timers, and everything.
   pub(crate) async fn start(broker: Arc<Self>) {
-    // start up the broker
-    let (mut router, console, servers, builder)
-      = librumqttd::async_locallink::construct_broker(
-        broker.rumqttd_cfg.clone()
-      );
-    thread::spawn(move || {
-      router.start().unwrap();
-    });
     // init a tokio runtime to capture messages
     let mut rt = tokio::runtime::Builder::new_multi_thread();
     rt.enable_all();
     rt.build().unwrap().block_on(async {
-      let (mut tx, mut rx) = builder
-        .connect("localclient", 200)
-        .await
-        .unwrap();
-      // subscribe to known topics.
-      for st in SensorType::all_types() {
-        tx.subscribe(std::iter::once(st.to_string())).await.unwrap();
-      }
-      // no idea what this does, honestly
-      let console_task = tokio::spawn(console);
-      // clone some references to the broker...
-      let broker1 = broker.clone();
-      let broker2 = broker.clone();
-      let broker3 = broker.clone();
-      // message decode loop. must be fast. another thread will deal with
-      // the data, and sending it home.
-      let msg_decode_task = tokio::spawn(async move {
-        loop {
-          let msg = rx.recv().await;
-          if let Err(e) = msg {
-            eprintln!("LinkError when recv'ing message: {}", e.to_string());
-          } else {
-            let data = msg.unwrap();
-            let maybe_st = SensorType::from_str(data.topic.as_str());
-            if let Ok(st) = maybe_st {
-              if broker1.cfg.topics.contains(&st) {
-                // yeah we care about this. showtime!
-                let mut pbytes: Vec<u8> = Vec::new();
-                for b in data.payload {
-                  pbytes.extend(b);
-                }
-                let msg = AnySensorMessage::decode(&data.topic, &pbytes);
-                match msg {
-                  Ok(pl) => {
-                    println!(
-                      "Got {} data from sensor #{}!",
-                      data.topic,
-                      pl.sensor_id()
-                    );
-                    let sd = BrokerMessagePayload::SensorData(pl);
-                    if let Err(se) = broker1.enqueue(sd).await {
-                      eprintln!(
-                        "Failed to enqueue {} data: {}",
-                        data.topic,
-                        se
-                      );
-                    }
-                  },
-                  Err(dec) => {
-                    eprintln!("Sensor sent bad data: {}.", dec);
-                  },
-                };
-              }
-            } else {
-              // bad sensor topic
-              eprintln!("Some sensor sent us a bad topic: \"{}\"", &data.topic);
-            }
-          }
+      // first boot? wait to be let in before anything else.
+      if let Some(enr) = &broker.cfg.enrollment {
+        if broker.home_key().is_none() {
+          broker.keep_key(enroll::enroll(&broker.cfg, enr).await);
         }
+      }
+      for route in &broker.routes {
+        route.uplink.start();
+      }
+      if let Some(addr) = broker.cfg.health_bind {
+        tokio::spawn(health::serve(broker.clone(), addr));
+      }
+      let update_task = supervisor::spawn(&broker, "updater", {
+        let broker = broker.clone();
+        move || Some(broker.clone().update_forever().boxed())
       });
+      // start every way in. they'll decode and enqueue on their own.
+      let mut source_tasks = Vec::new();
+      for src in source::from_config(&broker) {
+        let name = src.name();
+        info!("Starting {} input...", name);
+        let broker2 = broker.clone();
+        let mut next = Some(src);
+        source_tasks.push(supervisor::spawn(&broker, &name.clone(), move || {
+          let src = next.take()?;
+          next = src.again();
+          let run = src.run(broker2.clone());
+          let (broker, name) = (broker2.clone(), name.clone());
+          return Some(async move {
+            let _running = broker.liveness.start(&name);
+            run.await;
+          }.boxed());
+        }));
+      }
+      if source_tasks.is_empty() {
+        warn!("No inputs enabled. Nothing will ever come in!");
+      }
       // message capture thread. reads messages from comm and puts them into
       // the bundle for sending home.
-      let msg_bundle_task = tokio::spawn(async move {
-        let mut receiver = (broker2.clone().message_comm.1).clone().lock_owned().await;
-        loop {
-          let msg = receiver.recv().await.expect("Inner channel closed!");
-          let mut bnd = broker2.lock_bundle().await;
-          bnd.push(msg);
-          while bnd.len() > broker2.cfg.bundle_size {
-            bnd.remove(0);
-          }
-          println!("Pushed to bundle, length is now {}!", bnd.len());
-          std::mem::drop(bnd);
-          if broker2.send_bundle(true).await {
-            let mut bnd2 = broker2.lock_bundle().await;
-            bnd2.clear();
-          }
-        }
+      let msg_bundle_task = supervisor::spawn(&broker, "bundler", {
+        let broker = broker.clone();
+        move || Some(broker.clone().bundle_forever().boxed())
       });
-      // message autosend thread. ensures we won't wait forever with a
-      // non-full bundle.
-      let msg_autosend_task = tokio::spawn(async move {
-        println!("Timer started!");
-        loop {
-          println!("Timer fired!");
-          tokio::time::sleep(broker3.cfg.bundle_timeout).await;
-          if broker3.send_bundle(false).await {
-            let mut bnd = broker3.lock_bundle().await;
-            bnd.clear();
-          }
-        }
+      // message autosend threads, one per route. ensure we won't wait
+      // forever with a non-full bundle.
+      let mut autosend_tasks = Vec::new();
+      for (idx, route) in broker.routes.iter().enumerate() {
+        let name = format!("timer for {}", route.cfg.name);
+        let broker2 = broker.clone();
+        autosend_tasks.push(supervisor::spawn(&broker, &name, move || {
+          Some(broker2.clone().autosend_forever(idx).boxed())
+        }));
+      }
+      // heartbeat thread. lets the API know we're alive, and how we're
+      // doing.
+      let heartbeat_task = supervisor::spawn(&broker, "heartbeat", {
+        let broker = broker.clone();
+        move || Some(broker.clone().heartbeat_forever().boxed())
       });
       // wait on all handles. that should be forever unless... yeah.
-      println!("Broker is up.");
+      info!("Broker is up.");
+      #[cfg(feature = "systemd")]
+      notify_systemd();
       if broker.heartbeat().await {
-        println!("API seems to be up.");
+        info!("API seems to be up.");
+        broker.forward_dead_letters().await;
       } else {
-        println!("API seems to be down? Better look into that.");
+        info!("API seems to be down? Better look into that.");
+      }
+      for task in source_tasks {
+        task.await.unwrap();
       }
-      servers.await;
-      msg_decode_task.await.unwrap();
       msg_bundle_task.await.unwrap();
-      msg_autosend_task.await.unwrap();
-      console_task.await.unwrap();
+      for task in autosend_tasks {
+        task.await.unwrap();
+      }
+      heartbeat_task.await.unwrap();
+      update_task.await.unwrap();
+    });
+  }
+
+  /// Takes messages off the queue and puts them in their route's bundle,
+  /// sending it once it's full, forever.
+  async fn bundle_forever(self: Arc<Self>) {
+    let _running = self.liveness.start("bundler");
+    let mut receiver = self.message_comm.1.clone().lock_owned().await;
+    loop {
+      let msg = receiver.recv().await.expect("Inner channel closed!");
+      let idx = self.route_for(&msg.payload);
+      let route = &self.routes[idx];
+      let mut bnd = route.lock_bundle().await;
+      bnd.push(msg);
+      while bnd.len() > self.bundle_size(route) {
+        bnd.remove(0);
+        self.count_dropped(1);
+      }
+      info!(
+        "Pushed to {} bundle, length is now {}!",
+        route.cfg.name, bnd.len()
+      );
+      std::mem::drop(bnd);
+      if self.seal_bundle(route, true).await {
+        Broker::dispatch(&self, idx);
+      }
+    }
+  }
+
+  /// Sends a route's bundle whenever it times out, full or not, forever.
+  async fn autosend_forever(self: Arc<Self>, idx: usize) {
+    let route = &self.routes[idx];
+    let name = format!("timer for {}", route.cfg.name);
+    let _running = self.liveness.start(&name);
+    info!("Timer for {} started!", route.cfg.name);
+    loop {
+      tokio::time::sleep(route.cfg.bundle_timeout).await;
+      info!("Timer for {} fired!", route.cfg.name);
+      self.seal_bundle(route, false).await;
+      Broker::dispatch(&self, idx);
+    }
+  }
+
+  /// Sends heartbeats, for as long as the API wants them.
+  async fn heartbeat_forever(self: Arc<Self>) {
+    let running = self.liveness.start("heartbeat");
+    loop {
+      // the API may change it with any heartbeat.
+      let interval = match self.live().heartbeat_interval {
+        Some(i) => i,
+        None => return running.finish(),
+      };
+      tokio::time::sleep(interval).await;
+      if self.heartbeat().await {
+        self.forward_dead_letters().await;
+      } else {
+        warn!("Heartbeat failed. Is the API down?");
+      }
+    }
+  }
+
+  /// Installs updates as the API orders them, forever.
+  async fn update_forever(self: Arc<Self>) {
+    let _running = self.liveness.start("updater");
+    let mut orders = self.update_orders.1.clone().lock_owned().await;
+    update::run(self.clone(), &mut orders).await;
+  }
+
+  /// Counts a task that panicked and is being started over.
+  pub(crate) fn count_restart(&self, name: &str) {
+    self.restarts.fetch_add(1, Ordering::Relaxed);
+    self.liveness.restarted(name);
+  }
+}
+
+/// Tells systemd we're up, and keeps its watchdog fed for as long as the
+/// runtime is.
+#[cfg(feature = "systemd")]
+fn notify_systemd() {
+  systemd::ready();
+  if let Some(every) = systemd::watchdog_interval() {
+    tokio::spawn(async move {
+      loop {
+        tokio::time::sleep(every).await;
+        systemd::watchdog_ping();
+      }
     });
   }
 }
@@ -0,0 +1,87 @@
+//! Keeping to a bandwidth budget, for gateways on metered links like LTE.
+//! Whatever goes home is tallied over the last hour. Once that's over the
+//! budget, only bundles with priority messages in them go; the rest wait,
+//! sealed, until older traffic ages out of the hour, and bulk telemetry
+//! catches up then. Heartbeats always go, and count. Sizes are as encoded,
+//! before sealing and HTTP's own overhead, so leave some room.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+
+use crate::config::BandwidthBudget;
+
+/// How far back sends count against the budget.
+const WINDOW: Duration = Duration::from_secs(3600);
+
+/// A budget, and what was sent against it. Thread-safe.
+#[derive(Debug)]
+pub(crate) struct Budget {
+  /// How much, and what goes anyway.
+  cfg: BandwidthBudget,
+  /// When something was sent, and how many bytes, oldest first. Only the
+  /// last hour is kept.
+  sent: Mutex<VecDeque<(Instant, u64)>>
+}
+
+impl Budget {
+  /// A budget nothing was sent against yet.
+  pub(crate) fn new(cfg: BandwidthBudget) -> Self {
+    return Self {
+      cfg: cfg,
+      sent: Mutex::new(VecDeque::new())
+    };
+  }
+
+  /// Locks the tally, forgetting whatever is older than an hour.
+  fn sent(&self) -> std::sync::MutexGuard<'_, VecDeque<(Instant, u64)>> {
+    let mut sent = self.sent.lock().unwrap_or_else(|e| e.into_inner());
+    while let Some((when, _)) = sent.front() {
+      if when.elapsed() < WINDOW {
+        break;
+      }
+      sent.pop_front();
+    }
+    return sent;
+  }
+
+  /// Most bytes sent in any hour.
+  pub(crate) fn limit(&self) -> u64 {
+    return self.cfg.bytes_per_hour;
+  }
+
+  /// Bytes sent in the last hour.
+  pub(crate) fn used(&self) -> u64 {
+    return self.sent().iter().map(|(_, n)| n).sum();
+  }
+
+  /// Counts bytes as sent, right now.
+  pub(crate) fn spend(&self, bytes: usize) {
+    self.sent().push_back((Instant::now(), bytes as u64));
+  }
+
+  /// Whether the last hour's sends used it all up.
+  pub(crate) fn spent(&self) -> bool {
+    return self.used() >= self.cfg.bytes_per_hour;
+  }
+
+  /// Whether a message is sent even over budget.
+  fn is_priority(&self, msg: &BrokerMessage) -> bool {
+    return match &msg.payload {
+      BrokerMessagePayload::SensorData(sd) => {
+        self.cfg.sensor_types.contains(&sd.sensor_type())
+      },
+      BrokerMessagePayload::DeviceHealth(_) => self.cfg.device_health,
+      BrokerMessagePayload::Heartbeat(_) => true,
+      BrokerMessagePayload::DecodeFailure { .. } => false,
+    };
+  }
+
+  /// Whether a bundle may go now: while there's budget left, or if there's
+  /// a priority message in it.
+  pub(crate) fn allows(&self, bnd: &[BrokerMessage]) -> bool {
+    return !self.spent() || bnd.iter().any(|msg| self.is_priority(msg));
+  }
+}
@@ -0,0 +1,136 @@
+//! Recording what comes in over MQTT, and playing it back. A capture holds
+//! one JSON record per line: when a publish came in, its topic, and its
+//! payload in base64, just as the sensor sent it. Replaying feeds those back
+//! through decoding as if they were coming in again, at the pace they first
+//! did or faster. Captured in the field, replayed at a desk, decode bugs
+//! have nowhere to hide.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::time::Instant;
+
+use crate::broker::{Broker, RawOutcome};
+use crate::config::ReplayConfig;
+use crate::mqtt;
+use crate::source::SensorSource;
+
+/// A publish, as it came in.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Captured {
+  /// When.
+  when: DateTime<Local>,
+  /// Where to.
+  topic: String,
+  /// The bytes, in base64.
+  payload: String
+}
+
+/// A capture being written. Thread-safe.
+#[derive(Debug)]
+pub(crate) struct Capture {
+  /// Appended to, a line at a time.
+  file: Mutex<File>
+}
+
+impl Capture {
+  /// Opens a capture file, adding to whatever is there.
+  pub(crate) fn open(path: &Path) -> io::Result<Self> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    return Ok(Self { file: Mutex::new(file) });
+  }
+
+  /// Records a publish. Written right away, so a crash loses nothing.
+  pub(crate) fn record(&self, topic: &str, payload: &[u8]) -> io::Result<()> {
+    let rec = Captured {
+      when: Local::now(),
+      topic: topic.to_owned(),
+      payload: base64::encode(payload)
+    };
+    let mut line = serde_json::to_string(&rec)?;
+    line.push('\n');
+    let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+    return file.write_all(line.as_bytes());
+  }
+}
+
+/// Plays a capture back, as a sensor source, instead of every other one.
+pub(crate) struct ReplaySource {
+  pub(crate) cfg: ReplayConfig
+}
+
+impl ReplaySource {
+  /// Feeds every record to the broker, in order, waiting between them as
+  /// long as they first came apart, over the speed. Records that don't
+  /// parse are skipped, and said so.
+  async fn replay(self, broker: Arc<Broker>) -> io::Result<()> {
+    let file = tokio::fs::File::open(&self.cfg.path).await?;
+    let mut lines = BufReader::new(file).lines();
+    let speed = self.cfg.speed;
+    let started = Instant::now();
+    let mut first: Option<DateTime<Local>> = None;
+    let (mut replayed, mut queued, mut bad) = (0, 0, 0);
+    let mut n = 0;
+    while let Some(line) = lines.next_line().await? {
+      n += 1;
+      let rec = match serde_json::from_str::<Captured>(&line) {
+        Ok(r) => r,
+        Err(e) => {
+          warn!("Skipping line {} of the capture: {}", n, e);
+          bad += 1;
+          continue;
+        },
+      };
+      let payload = match base64::decode(&rec.payload) {
+        Ok(p) => p,
+        Err(e) => {
+          warn!("Skipping line {} of the capture: {}", n, e);
+          bad += 1;
+          continue;
+        },
+      };
+      let t0 = *first.get_or_insert(rec.when);
+      if speed > 0.0 {
+        // records out of order go right away
+        let since = (rec.when - t0).to_std().unwrap_or_default();
+        let at = started + Duration::from_secs_f64(
+          since.as_secs_f64() / speed
+        );
+        tokio::time::sleep_until(at).await;
+      }
+      replayed += 1;
+      let outcome = mqtt::dispatch(&broker, &rec.topic, payload).await;
+      if outcome == RawOutcome::Queued {
+        queued += 1;
+      }
+    }
+    info!(
+      "Replay done: {} publishes replayed, {} of them queued, {} bad lines.",
+      replayed, queued, bad
+    );
+    return Ok(());
+  }
+}
+
+impl SensorSource for ReplaySource {
+  fn name(&self) -> String {
+    return format!("replay of {}", self.cfg.path.display());
+  }
+
+  fn run(self: Box<Self>, broker: Arc<Broker>) -> BoxFuture<'static, ()> {
+    return async move {
+      let path = self.cfg.path.clone();
+      if let Err(e) = self.replay(broker).await {
+        warn!("Couldn't replay {}: {}", path.display(), e);
+      }
+    }.boxed();
+  }
+}
@@ -0,0 +1,121 @@
+//! Make-believe trouble on the way home, for rehearsing how the spool,
+//! retries and alerts cope with an outage without pulling any cables.
+//! Wraps an uplink: requests are held up, lost or mangled at random, and
+//! the whole thing may go down now and then. Only debug builds take it.
+
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use libcdp::comm::broker_api::{BrokerMessageBundle, BundleAck, HeartbeatMessage, HeartbeatReply};
+use rand::Rng;
+
+use crate::config::ChaosConfig;
+use crate::uplink::{Uplink, UplinkError};
+
+/// What becomes of a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Fate {
+  /// Goes through, late as it may be.
+  Through,
+  /// Never makes it, since the uplink is down.
+  Down,
+  /// Never makes it, lost on the way.
+  Lost,
+  /// Makes it, but in no shape to be taken.
+  Mangled
+}
+
+/// An uplink with trouble, as configured.
+#[derive(Debug)]
+pub(crate) struct ChaosUplink {
+  /// The real one.
+  inner: Box<dyn Uplink>,
+  /// How much trouble.
+  cfg: ChaosConfig,
+  /// When we started, which outages are timed from.
+  started: Instant
+}
+
+impl ChaosUplink {
+  /// Wraps an uplink in trouble.
+  pub(crate) fn wrap(inner: Box<dyn Uplink>, cfg: ChaosConfig) -> Self {
+    info!("Chaos is on! The uplink will misbehave on purpose.");
+    return Self {
+      inner: inner,
+      cfg: cfg,
+      started: Instant::now()
+    };
+  }
+
+  /// Whether we're in one of the outages. They come at the end of every
+  /// period, so things start out fine.
+  fn in_outage(&self) -> bool {
+    return match self.cfg.outage {
+      Some((every, length)) => {
+        let into = self.started.elapsed().as_secs_f64() % every.as_secs_f64();
+        into >= (every - length).as_secs_f64()
+      },
+      None => false,
+    };
+  }
+
+  /// How long the next request is held up, and what becomes of it.
+  fn roll(&self) -> (Duration, Fate) {
+    let mut rng = rand::thread_rng();
+    let jitter = self.cfg.delay_jitter.as_secs_f64();
+    let delay = self.cfg.delay
+      + Duration::from_secs_f64(rng.gen_range(0.0..=jitter));
+    let luck: f64 = rng.gen();
+    let fate = if self.in_outage() {
+      Fate::Down
+    } else if luck < self.cfg.drop_rate {
+      Fate::Lost
+    } else if luck < self.cfg.drop_rate + self.cfg.corrupt_rate {
+      Fate::Mangled
+    } else {
+      Fate::Through
+    };
+    return (delay, fate);
+  }
+
+  /// Makes a request, or doesn't, as fate has it.
+  fn meddle<'a, T: Send + 'a>(
+    &self, what: &'static str, req: BoxFuture<'a, Result<T, UplinkError>>
+  ) -> BoxFuture<'a, Result<T, UplinkError>> {
+    let (delay, fate) = self.roll();
+    return async move {
+      tokio::time::sleep(delay).await;
+      return match fate {
+        Fate::Through => req.await,
+        Fate::Down => Err(UplinkError::Transport(
+          "chaos: the uplink is down".to_owned()
+        )),
+        Fate::Lost => Err(UplinkError::Transport(
+          format!("chaos: {} lost on the way", what)
+        )),
+        Fate::Mangled => Err(UplinkError::Rejected(
+          format!("chaos: {} mangled on the way", what)
+        )),
+      };
+    }.boxed();
+  }
+}
+
+impl Uplink for ChaosUplink {
+  fn start(&self) {
+    self.inner.start();
+  }
+
+  fn send_bundle<'a>(
+    &'a self, bnd: &'a BrokerMessageBundle, key: Option<&'a str>
+  ) -> BoxFuture<'a, Result<Option<BundleAck>, UplinkError>> {
+    return self.meddle("bundle", self.inner.send_bundle(bnd, key));
+  }
+
+  fn heartbeat<'a>(&'a self, hb: &'a HeartbeatMessage)
+  -> BoxFuture<'a, Result<HeartbeatReply, UplinkError>> {
+    return self.meddle("heartbeat", self.inner.heartbeat(hb));
+  }
+}
@@ -0,0 +1,274 @@
+//! A tiny CoAP (RFC 7252) listener, for sensors that would rather send a
+//! UDP datagram than keep an MQTT connection around. A POST or PUT to
+//! /{topic} carries the same bytes an MQTT publish to {topic} would.
+//!
+//! Only what that takes is implemented: no blockwise transfers, no
+//! observing, no DTLS, and confirmable requests are answered right away.
+//! Requests seen again within EXCHANGE_LIFETIME, by sender and message ID,
+//! are taken as retransmissions: confirmable ones get the same answer again,
+//! and non-confirmable ones are ignored, as per section 4.5.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use tokio::net::UdpSocket;
+
+use crate::broker::{Broker, RawOutcome};
+use crate::source::SensorSource;
+
+/// The only protocol version there is.
+const VERSION: u8 = 1;
+/// Confirmable message type.
+const TYPE_CON: u8 = 0;
+/// Non-confirmable message type.
+const TYPE_NON: u8 = 1;
+/// Acknowledgement message type.
+const TYPE_ACK: u8 = 2;
+/// Option number of Uri-Path.
+const OPT_URI_PATH: u16 = 11;
+/// Marks the end of options and start of payload.
+const PAYLOAD_MARKER: u8 = 0xFF;
+/// Largest datagram we bother reading. RFC 7252 suggests 1152.
+const MAX_DATAGRAM: usize = 1152;
+/// How long a message ID is remembered for. RFC 7252 section 4.8.2 says
+/// EXCHANGE_LIFETIME is around 247 seconds with the default parameters.
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+/// Most exchanges remembered at once. The oldest go first past that.
+const MAX_EXCHANGES: usize = 10_000;
+
+/// Method and response codes, as class << 5 | detail.
+const CODE_POST: u8 = 0x02;
+const CODE_PUT: u8 = 0x03;
+const CODE_CHANGED: u8 = 0x44;
+const CODE_BAD_REQUEST: u8 = 0x80;
+const CODE_UNAUTHORIZED: u8 = 0x81;
+const CODE_NOT_FOUND: u8 = 0x84;
+const CODE_METHOD_NOT_ALLOWED: u8 = 0x85;
+const CODE_SERVICE_UNAVAILABLE: u8 = 0xA3;
+
+/// The parts of a request we care about.
+#[derive(Debug)]
+struct CoapRequest {
+  mtype: u8,
+  code: u8,
+  message_id: u16,
+  token: Vec<u8>,
+  /// Uri-Path segments, in order.
+  path: Vec<String>,
+  payload: Vec<u8>
+}
+
+impl CoapRequest {
+  /// Parses a datagram. None if it's not a well-formed CoAP message.
+  fn parse(buf: &[u8]) -> Option<Self> {
+    if buf.len() < 4 || buf[0] >> 6 != VERSION {
+      return None;
+    }
+    let tkl = (buf[0] & 0x0F) as usize;
+    if tkl > 8 || buf.len() < 4 + tkl {
+      return None;
+    }
+    let mut req = Self {
+      mtype: (buf[0] >> 4) & 0x03,
+      code: buf[1],
+      message_id: u16::from_be_bytes([buf[2], buf[3]]),
+      token: buf[4..4 + tkl].to_vec(),
+      path: Vec::new(),
+      payload: Vec::new()
+    };
+    let mut pos = 4 + tkl;
+    let mut number: u16 = 0;
+    while pos < buf.len() {
+      if buf[pos] == PAYLOAD_MARKER {
+        req.payload = buf[pos + 1..].to_vec();
+        break;
+      }
+      let head = buf[pos];
+      pos += 1;
+      let delta = Self::extended(head >> 4, buf, &mut pos)?;
+      let len = Self::extended(head & 0x0F, buf, &mut pos)? as usize;
+      number = number.checked_add(delta)?;
+      let value = buf.get(pos..pos + len)?;
+      pos += len;
+      if number == OPT_URI_PATH {
+        req.path.push(String::from_utf8(value.to_vec()).ok()?);
+      }
+    }
+    return Some(req);
+  }
+
+  /// Reads an option delta or length, extended as per section 3.1.
+  fn extended(nibble: u8, buf: &[u8], pos: &mut usize) -> Option<u16> {
+    return match nibble {
+      13 => {
+        let b = *buf.get(*pos)?;
+        *pos += 1;
+        Some(b as u16 + 13)
+      },
+      14 => {
+        let b = buf.get(*pos..*pos + 2)?;
+        *pos += 2;
+        u16::from_be_bytes([b[0], b[1]]).checked_add(269)
+      },
+      15 => None,
+      n => Some(n as u16),
+    };
+  }
+
+  /// Builds the response, piggybacked on an ACK if the request was
+  /// confirmable.
+  fn respond(&self, code: u8, message_id: u16) -> Vec<u8> {
+    let (mtype, mid) = if self.mtype == TYPE_CON {
+      (TYPE_ACK, self.message_id)
+    } else {
+      (TYPE_NON, message_id)
+    };
+    let mut out = vec![
+      VERSION << 6 | mtype << 4 | self.token.len() as u8,
+      code
+    ];
+    out.extend_from_slice(&mid.to_be_bytes());
+    out.extend_from_slice(&self.token);
+    return out;
+  }
+}
+
+/// Sender and message ID of an exchange.
+type ExchangeKey = (SocketAddr, u16);
+
+/// Recent exchanges, to tell retransmissions from new requests.
+#[derive(Debug, Default)]
+struct Exchanges {
+  /// What we answered to each.
+  replies: HashMap<ExchangeKey, Vec<u8>>,
+  /// When each came in, oldest first.
+  order: VecDeque<(Instant, ExchangeKey)>
+}
+
+impl Exchanges {
+  /// Forgets exchanges past their lifetime, and the oldest ones if there
+  /// are too many.
+  fn forget_old(&mut self, now: Instant) {
+    while let Some((when, key)) = self.order.front() {
+      let stale = now.duration_since(*when) >= EXCHANGE_LIFETIME;
+      if !stale && self.order.len() < MAX_EXCHANGES {
+        break;
+      }
+      self.replies.remove(key);
+      self.order.pop_front();
+    }
+  }
+
+  /// What we answered to an exchange, if we've seen it.
+  fn reply(&self, key: &ExchangeKey) -> Option<&Vec<u8>> {
+    return self.replies.get(key);
+  }
+
+  /// Remembers what we answered to an exchange.
+  fn remember(&mut self, key: ExchangeKey, reply: Vec<u8>, now: Instant) {
+    self.replies.insert(key, reply);
+    self.order.push_back((now, key));
+  }
+}
+
+/// Decides what to answer, handing the payload over to the broker if it's
+/// something it should see.
+async fn handle(broker: &Broker, req: &CoapRequest) -> u8 {
+  if req.code != CODE_POST && req.code != CODE_PUT {
+    return CODE_METHOD_NOT_ALLOWED;
+  }
+  let topic = req.path.join("/");
+  return match broker.ingest_raw(&topic, req.payload.clone()).await {
+    RawOutcome::Queued | RawOutcome::Ignored => CODE_CHANGED,
+    RawOutcome::BadTopic => CODE_NOT_FOUND,
+    RawOutcome::BadPayload => CODE_BAD_REQUEST,
+    RawOutcome::BadSignature => CODE_UNAUTHORIZED,
+    RawOutcome::Dropped => CODE_SERVICE_UNAVAILABLE,
+  };
+}
+
+/// Listens for CoAP requests forever, on a socket of its own unless it's
+/// handed one.
+async fn serve(
+  broker: Arc<Broker>, addr: SocketAddr, socket: Option<std::net::UdpSocket>
+) {
+  let bound = match socket {
+    Some(s) => s.set_nonblocking(true).and_then(|_| UdpSocket::from_std(s)),
+    None => UdpSocket::bind(addr).await,
+  };
+  let socket = bound
+    .unwrap_or_else(|e| panic!("Can't bind CoAP to {}: {}", addr, e));
+  info!("Listening for CoAP on {}...", addr);
+  let mut buf = [0u8; MAX_DATAGRAM];
+  let mut next_mid: u16 = 0;
+  let mut seen = Exchanges::default();
+  loop {
+    let (len, peer) = match socket.recv_from(&mut buf).await {
+      Ok(r) => r,
+      Err(e) => {
+        warn!("CoAP receive failed: {}", e);
+        continue;
+      },
+    };
+    let req = match CoapRequest::parse(&buf[..len]) {
+      Some(r) => r,
+      None => {
+        warn!("Got a bad CoAP datagram from {}.", peer);
+        continue;
+      },
+    };
+    // ACKs and resets are for requests we never make.
+    if req.mtype != TYPE_CON && req.mtype != TYPE_NON {
+      continue;
+    }
+    let now = Instant::now();
+    seen.forget_old(now);
+    let key = (peer, req.message_id);
+    if let Some(reply) = seen.reply(&key) {
+      // our ACK got lost, so the same one again; repeated NONs just go away
+      if req.mtype == TYPE_CON {
+        if let Err(e) = socket.send_to(reply, peer).await {
+          warn!("CoAP reply to {} failed: {}", peer, e);
+        }
+      }
+      continue;
+    }
+    let code = handle(&broker, &req).await;
+    next_mid = next_mid.wrapping_add(1);
+    let reply = req.respond(code, next_mid);
+    if let Err(e) = socket.send_to(&reply, peer).await {
+      warn!("CoAP reply to {} failed: {}", peer, e);
+    }
+    seen.remember(key, reply, now);
+  }
+}
+
+/// CoAP, as a sensor source.
+pub(crate) struct CoapSource {
+  /// Where to listen.
+  pub(crate) addr: SocketAddr,
+  /// Already bound there, as systemd does with socket activation.
+  pub(crate) socket: Option<std::net::UdpSocket>
+}
+
+impl SensorSource for CoapSource {
+  fn name(&self) -> String {
+    return format!("CoAP on {}", self.addr);
+  }
+
+  fn again(&self) -> Option<Box<dyn SensorSource>> {
+    // systemd keeps its socket bound, so we can't bind it ourselves.
+    return Some(Box::new(CoapSource {
+      addr: self.addr,
+      socket: self.socket.as_ref().and_then(|s| s.try_clone().ok())
+    }));
+  }
+
+  fn run(self: Box<Self>, broker: Arc<Broker>) -> BoxFuture<'static, ()> {
+    return serve(broker, self.addr, self.socket).boxed();
+  }
+}
@@ -1,17 +1,28 @@
 //! Broker configuration. Loading, structures, etc.
 
+use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
 
+use cdp_client::{BundleFormat, ClientOptions};
 use libcdp::comm::broker_api::HeartbeatMessage;
-use libcdp::comm::sensor_broker::SensorType;
+use libcdp::comm::sealing::SealingKey;
+use libcdp::comm::sensor_broker::{DeviceHealthMessage, SensorType};
+use libcdp::ids::{BrokerId, SensorId};
+use libcdp::runtime;
 use reqwest::Url;
 use serde::{Serialize, Deserialize};
-use uuid::Uuid;
 use config::{Config, ConfigError};
 use librumqttd::Config as RumqqtdConfig;
 
+use crate::enroll;
+
+/// How many payloads that didn't decode are kept, unless told otherwise.
+const DEFAULT_DEAD_LETTERS: usize = 100;
+
 /// The broker config as it lies within the file.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct BrokerConfigFile {
@@ -19,8 +30,29 @@ struct BrokerConfigFile {
   topics: Vec<String>,
   /// Access key for the HTTP target. None means no authentication.
   home_key: Option<String>,
-  /// The server to contact when phoning home.
-  endpoint: String,
+  /// Where the key goes when the API rotates it, and is read from on
+  /// startup if it's there. None means rotated keys are lost on restart.
+  key_file: Option<String>,
+  /// Where settings the API sends are kept, and read back from on startup.
+  /// None means they're lost on restart.
+  remote_config_file: Option<String>,
+  /// The server to contact when phoning home, or a list of them, tried in
+  /// order. Only needed by the "http" uplink.
+  endpoint: Option<EndpointsFile>,
+  /// How often endpoints that are down are checked on, and host names
+  /// looked up again. None means a minute.
+  endpoint_check_secs: Option<usize>,
+  /// Where bundles go: "http", "tee", "mqtt" or "file". None means "http".
+  uplink: Option<String>,
+  /// APIs the "tee" uplink sends a copy of everything to, on top of the
+  /// endpoint.
+  mirrors: Option<Vec<MirrorConfigFile>>,
+  /// The upstream MQTT broker, for the "mqtt" uplink.
+  uplink_mqtt: Option<ExternalMqttConfigFile>,
+  /// Topic the "mqtt" uplink publishes under. None means "cdp/<uid>".
+  uplink_topic: Option<String>,
+  /// Directory the "file" uplink writes to.
+  uplink_dir: Option<String>,
   /// Bundle size for the endpoint. Accumulate messages and send no more than
   /// said amount.
   bundle_size: usize,
@@ -31,8 +63,107 @@ struct BrokerConfigFile {
   buffer_size_bundles: usize,
   /// Heartbeat interval for the endpoint. None means no auto heartbeat.
   heartbeat_interval_secs: Option<usize>,
-  /// This broker's unique identifier. Should be random and static.
-  uid: String,
+  /// How long connecting to the API may take. None means 5 seconds.
+  connect_timeout_msec: Option<usize>,
+  /// How long a call to the API may take, connecting included. None means
+  /// 30 seconds.
+  request_timeout_msec: Option<usize>,
+  /// What to do when the channel is full: "block", "drop_oldest" or
+  /// "spool". None means "block".
+  backpressure: Option<String>,
+  /// How long "block" waits for room before dropping a message.
+  backpressure_timeout_msec: Option<usize>,
+  /// Where "spool" keeps the messages that didn't fit.
+  spool_path: Option<String>,
+  /// Where to record every MQTT publish, for replaying later. None means
+  /// nowhere.
+  capture_file: Option<String>,
+  /// How many payloads that didn't decode to keep for the API. None means
+  /// 100, 0 means none.
+  dead_letters: Option<usize>,
+  /// Where to keep them, so they outlive us. None means in memory.
+  dead_letter_path: Option<String>,
+  /// How many bundles may be in flight at once. None means 1.
+  send_concurrency: Option<usize>,
+  /// Whether bundles must reach the API in order. Forces one bundle in
+  /// flight at a time. None means false.
+  preserve_order: Option<bool>,
+  /// Most bytes sent home in any hour, for metered uplinks. None means no
+  /// limit.
+  bandwidth_budget_bytes_per_hour: Option<usize>,
+  /// Sensor types, and maybe "device_health", sent even once the budget is
+  /// spent. None means none.
+  priority_topics: Option<Vec<String>>,
+  /// How bundles are encoded: "json", "protobuf" or "ndjson". None means
+  /// "json".
+  wire_format: Option<String>,
+  /// Key to seal bundles sent over HTTP with, in base64. None means they
+  /// go as they are.
+  seal_key: Option<String>,
+  /// Whether to send the bytes sensors sent along with what they decoded
+  /// to. None means false.
+  retain_raw: Option<bool>,
+  /// Whether to run the embedded MQTT broker sensors publish to. None means
+  /// true, unless external_mqtt is set.
+  embedded_mqtt: Option<bool>,
+  /// Whether to serve rumqttd's console, the embedded broker's config and
+  /// metrics over HTTP. None means true.
+  mqtt_console: Option<bool>,
+  /// Address:port for the console. None means the [console] listen in
+  /// cdp_rumqttd.toml.
+  mqtt_console_bind: Option<String>,
+  /// An MQTT broker to subscribe to as a client. None means none.
+  external_mqtt: Option<ExternalMqttConfigFile>,
+  /// Address:port to listen for CoAP on. None means no CoAP.
+  coap_bind: Option<String>,
+  /// Address:port to listen for HTTP ingestion on. None means no HTTP.
+  http_bind: Option<String>,
+  /// Address:port to answer health checks on. None means no health checks.
+  health_bind: Option<String>,
+  /// Serial ports to read sensor data from. None means none.
+  serial: Option<Vec<SerialPortConfigFile>>,
+  /// BLE sensors to listen for. None means no scanning.
+  ble: Option<Vec<BleSensorConfigFile>>,
+  /// Least time between readings forwarded from a single BLE sensor. None
+  /// means a minute.
+  ble_min_interval_secs: Option<usize>,
+  /// zigbee2mqtt devices to translate. None means none.
+  zigbee: Option<Vec<ZigbeeDeviceConfigFile>>,
+  /// Keys sensors sign their payloads with. None means none.
+  sensor_keys: Option<Vec<SensorKeyConfigFile>>,
+  /// Whether to turn away readings from sensors without a key. None means
+  /// false.
+  require_signatures: Option<bool>,
+  /// What to do to messages between decoding and bundling, in order. None
+  /// means nothing.
+  transforms: Option<Vec<TransformConfigFile>>,
+  /// Sensor types that get bundles and uplinks of their own. None means
+  /// everything goes the same way.
+  routes: Option<Vec<RouteConfigFile>>,
+  /// Make-believe uplink trouble, for rehearsing outages. Debug builds
+  /// only. None means none.
+  chaos: Option<ChaosConfigFile>,
+  /// Whether to run detached from the terminal. None means false, and
+  /// --detach or --foreground say otherwise.
+  detach: Option<bool>,
+  /// Where to write our PID. None means nowhere.
+  pid_file: Option<String>,
+  /// Where a detached broker's output goes. None means nowhere.
+  log_file: Option<String>,
+  /// Program that installs updates the API orders, given the downloaded
+  /// artifact and its version. None means updates are turned down.
+  update_hook: Option<String>,
+  /// Where updates are downloaded to. None means the temp directory.
+  update_dir: Option<String>,
+  /// One-time token to enroll with, on first boot. None means no
+  /// enrollment; uid and home_key must be given.
+  enroll_token: Option<String>,
+  /// Where an enrolling broker keeps its UID, keypair and key. Required
+  /// with enroll_token.
+  enroll_dir: Option<String>,
+  /// This broker's unique identifier. Should be random and static. None
+  /// means the one made up on enrollment.
+  uid: Option<String>,
 }
 
 /// Now, the broker config after some parsing and checks.
@@ -42,8 +173,13 @@ pub struct BrokerConfig {
   pub topics: Vec<SensorType>,
   /// Access key for the HTTP target. None means no authentication.
   pub home_key: Option<String>,
-  /// The server to contact when phoning home.
-  pub endpoint: Url,
+  /// Where rotated keys are kept. None means they're lost on restart.
+  pub key_file: Option<PathBuf>,
+  /// Where settings the API sends are kept. None means they're lost on
+  /// restart.
+  pub remote_config_file: Option<PathBuf>,
+  /// Where bundles and heartbeats go.
+  pub uplink: UplinkConfig,
   /// Bundle size for the endpoint. Accumulate messages and send no more than
   /// said amount.
   pub bundle_size: usize,
@@ -54,8 +190,691 @@ pub struct BrokerConfig {
   pub buffer_size_bundles: usize,
   /// Heartbeat interval for the endpoint. None means no auto heartbeat.
   pub heartbeat_interval: Option<Duration>,
+  /// How long calls to the API may take.
+  pub http_timeouts: HttpTimeouts,
+  /// What to do when the channel is full.
+  pub backpressure: BackpressurePolicy,
+  /// Where to record every MQTT publish. None means nowhere.
+  pub capture_file: Option<PathBuf>,
+  /// How many payloads that didn't decode to keep for the API. 0 means
+  /// none.
+  pub dead_letters: usize,
+  /// Where to keep them. None means in memory.
+  pub dead_letter_path: Option<PathBuf>,
+  /// A capture to play back instead of listening to sensors. None means
+  /// we listen.
+  pub replay: Option<ReplayConfig>,
+  /// How many bundles may be in flight at once. Always 1 if preserve_order.
+  pub send_concurrency: usize,
+  /// Whether bundles must reach the API in order.
+  pub preserve_order: bool,
+  /// How much may be sent home. None means there's no limit.
+  pub bandwidth_budget: Option<BandwidthBudget>,
+  /// How bundles are encoded.
+  pub wire_format: WireFormat,
+  /// Key to seal bundles sent over HTTP with. None means they go as they
+  /// are.
+  pub seal_key: Option<SealingKey>,
+  /// Whether to send the bytes sensors sent along with what they decoded
+  /// to.
+  pub retain_raw: bool,
+  /// Whether to run the embedded MQTT broker sensors publish to.
+  pub embedded_mqtt: bool,
+  /// Whether to serve rumqttd's console.
+  pub mqtt_console: bool,
+  /// Address for the console. None means cdp_rumqttd.toml decides.
+  pub mqtt_console_bind: Option<SocketAddr>,
+  /// An MQTT broker to subscribe to as a client. None means none.
+  pub external_mqtt: Option<ExternalMqttConfig>,
+  /// Address to listen for CoAP on. None means no CoAP.
+  pub coap_bind: Option<SocketAddr>,
+  /// Address to listen for HTTP ingestion on. None means no HTTP.
+  pub http_bind: Option<SocketAddr>,
+  /// Address to answer health checks on. None means no health checks.
+  pub health_bind: Option<SocketAddr>,
+  /// Serial ports to read sensor data from.
+  pub serial: Vec<SerialPortConfig>,
+  /// BLE sensors to listen for. Empty means no scanning.
+  pub ble: Vec<BleSensorConfig>,
+  /// Least time between readings forwarded from a single BLE sensor.
+  pub ble_min_interval: Duration,
+  /// zigbee2mqtt devices to translate.
+  pub zigbee: Vec<ZigbeeDeviceConfig>,
+  /// Keys sensors sign their payloads with, by sensor type and ID.
+  pub sensor_keys: HashMap<(SensorType, SensorId), String>,
+  /// Whether to turn away readings from sensors without a key.
+  pub require_signatures: bool,
+  /// What to do to messages between decoding and bundling, in order.
+  pub transforms: Vec<Transform>,
+  /// Sensor types that get bundles and uplinks of their own, first match
+  /// wins. Whatever none takes goes the default way.
+  pub routes: Vec<RouteConfig>,
+  /// Make-believe uplink trouble. None means none.
+  pub chaos: Option<ChaosConfig>,
+  /// Whether to run detached from the terminal.
+  pub detach: bool,
+  /// Where to write our PID. None means nowhere.
+  pub pid_file: Option<PathBuf>,
+  /// Where a detached broker's output goes. None means nowhere.
+  pub log_file: Option<PathBuf>,
+  /// Program that installs updates. None means updates are turned down.
+  pub update_hook: Option<PathBuf>,
+  /// Where updates are downloaded to.
+  pub update_dir: PathBuf,
+  /// How to enroll, on first boot. None means no enrollment.
+  pub enrollment: Option<EnrollConfig>,
   /// This broker's unique identifier. Should be random and static.
-  pub uid: Uuid,
+  pub uid: BrokerId,
+}
+
+/// How much may be sent home over a metered uplink, and what goes anyway.
+#[derive(Clone, Debug)]
+pub struct BandwidthBudget {
+  /// Most bytes sent in any hour.
+  pub bytes_per_hour: u64,
+  /// Sensor types sent even over budget.
+  pub sensor_types: Vec<SensorType>,
+  /// Whether device health is sent even over budget.
+  pub device_health: bool
+}
+
+/// How a broker enrolls itself with the API.
+#[derive(Clone, Debug)]
+pub struct EnrollConfig {
+  /// One-time token to enroll with.
+  pub token: String,
+  /// Where the UID, keypair and key are kept.
+  pub dir: PathBuf
+}
+
+/// A capture to play back, and how fast.
+#[derive(Clone, Debug)]
+pub struct ReplayConfig {
+  /// Where it is.
+  pub path: PathBuf,
+  /// How many times faster than it was captured. 0 means as fast as it
+  /// goes.
+  pub speed: f64
+}
+
+/// An MQTT broker someone else runs, as within the file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ExternalMqttConfigFile {
+  /// Its host name or address.
+  host: String,
+  /// Its port. None means 1883.
+  port: Option<u16>,
+  /// Client ID to connect with. None means one made from the broker UID.
+  client_id: Option<String>,
+  /// Username to log in with. None means no login.
+  username: Option<String>,
+  /// Password to log in with.
+  password: Option<String>,
+  /// Keep-alive interval. None means 30 seconds.
+  keep_alive_secs: Option<u16>,
+}
+
+/// An MQTT broker someone else runs, which we subscribe to.
+#[derive(Clone, Debug)]
+pub struct ExternalMqttConfig {
+  /// Its host name or address.
+  pub host: String,
+  /// Its port.
+  pub port: u16,
+  /// Client ID to connect with.
+  pub client_id: String,
+  /// Username and password to log in with. None means no login.
+  pub credentials: Option<(String, String)>,
+  /// Keep-alive interval, in seconds.
+  pub keep_alive_secs: u16,
+}
+
+impl ExternalMqttConfig {
+  /// Fills in the blanks, defaulting to the given client ID. That's best
+  /// made from the broker's UID, so it's stable and unique.
+  fn from_file(cfg: &ExternalMqttConfigFile, default_id: String) -> Self {
+    return Self {
+      host: cfg.host.clone(),
+      port: cfg.port.unwrap_or(1883),
+      client_id: cfg.client_id.clone().unwrap_or(default_id),
+      credentials: cfg.username.clone()
+        .map(|u| (u, cfg.password.clone().unwrap_or_default())),
+      keep_alive_secs: cfg.keep_alive_secs.unwrap_or(30),
+    };
+  }
+}
+
+/// A serial port with sensors on it, as within the file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SerialPortConfigFile {
+  /// Device path, like "/dev/ttyUSB0" or "COM3".
+  port: String,
+  /// Baud rate.
+  baud: u32,
+  /// How frames are delimited: "hex", "json" or "slip". None means "hex".
+  framing: Option<String>,
+}
+
+/// A serial port with sensors on it.
+#[derive(Clone, Debug)]
+pub struct SerialPortConfig {
+  /// Device path, like "/dev/ttyUSB0" or "COM3".
+  pub port: String,
+  /// Baud rate.
+  pub baud: u32,
+  /// How frames are delimited.
+  pub framing: SerialFraming,
+}
+
+/// How sensor frames are laid out on a serial port.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SerialFraming {
+  /// Lines of topic, space, payload bytes in hex.
+  Hex,
+  /// Lines of topic, space, payload as JSON.
+  Json,
+  /// SLIP frames of topic, a zero byte, payload bytes.
+  Slip
+}
+
+impl FromStr for SerialFraming {
+  type Err = BrokerConfigParseError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    return match s {
+      "hex" => Ok(SerialFraming::Hex),
+      "json" => Ok(SerialFraming::Json),
+      "slip" => Ok(SerialFraming::Slip),
+      _ => Err(BrokerConfigParseError::BadSerialFraming(s.to_owned())),
+    };
+  }
+}
+
+impl TryFrom<&SerialPortConfigFile> for SerialPortConfig {
+  type Error = BrokerConfigParseError;
+  fn try_from(cfg: &SerialPortConfigFile) -> Result<Self, Self::Error> {
+    return Ok(Self {
+      port: cfg.port.clone(),
+      baud: cfg.baud,
+      framing: SerialFraming::from_str(
+        cfg.framing.as_deref().unwrap_or("hex")
+      )?,
+    });
+  }
+}
+
+/// A BLE sensor to listen for, as within the file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BleSensorConfigFile {
+  /// Its MAC, like "A4:C1:38:12:34:56".
+  mac: String,
+  /// The sensor ID its readings are sent under.
+  sensor_id: SensorId,
+}
+
+/// A BLE sensor to listen for.
+#[derive(Clone, Debug)]
+pub struct BleSensorConfig {
+  /// Its MAC, most significant byte first.
+  pub mac: [u8; 6],
+  /// The sensor ID its readings are sent under.
+  pub sensor_id: SensorId,
+}
+
+impl TryFrom<&BleSensorConfigFile> for BleSensorConfig {
+  type Error = BrokerConfigParseError;
+  fn try_from(cfg: &BleSensorConfigFile) -> Result<Self, Self::Error> {
+    let bad = || BrokerConfigParseError::BadMacAddress(cfg.mac.clone());
+    let mut mac = [0u8; 6];
+    let mut parts = cfg.mac.split(|c| c == ':' || c == '-');
+    for byte in mac.iter_mut() {
+      let part = parts.next().filter(|p| p.len() == 2).ok_or_else(bad)?;
+      *byte = u8::from_str_radix(part, 16).map_err(|_| bad())?;
+    }
+    if parts.next().is_some() {
+      return Err(bad());
+    }
+    return Ok(Self { mac: mac, sensor_id: cfg.sensor_id });
+  }
+}
+
+/// A zigbee2mqtt device, as within the file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ZigbeeDeviceConfigFile {
+  /// MQTT topic filter its state is published on.
+  topic: String,
+  /// The sensor ID its readings are sent under.
+  sensor_id: SensorId,
+  /// JSON field holding each sensor type's reading. None means every sensor
+  /// type, in a field of the same name.
+  fields: Option<HashMap<String, String>>,
+}
+
+/// A zigbee2mqtt device.
+#[derive(Clone, Debug)]
+pub struct ZigbeeDeviceConfig {
+  /// MQTT topic filter its state is published on.
+  pub topic: String,
+  /// The sensor ID its readings are sent under.
+  pub sensor_id: SensorId,
+  /// JSON field holding each sensor type's reading, dotted if nested.
+  pub fields: Vec<(SensorType, String)>,
+}
+
+impl TryFrom<&ZigbeeDeviceConfigFile> for ZigbeeDeviceConfig {
+  type Error = BrokerConfigParseError;
+  fn try_from(cfg: &ZigbeeDeviceConfigFile) -> Result<Self, Self::Error> {
+    let fields = match &cfg.fields {
+      Some(map) => map.iter()
+        .map(|(name, path)| match SensorType::from_str(name) {
+          Ok(st) => Ok((st, path.clone())),
+          Err(_) => Err(BrokerConfigParseError::BadSensorType(name.clone())),
+        })
+        .collect::<Result<Vec<(SensorType, String)>, _>>()?,
+      None => SensorType::all_types()
+        .into_iter()
+        .map(|st| (st, st.to_string()))
+        .collect(),
+    };
+    return Ok(Self {
+      topic: cfg.topic.clone(),
+      sensor_id: cfg.sensor_id,
+      fields: fields,
+    });
+  }
+}
+
+/// A sensor's signing key, as within the file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SensorKeyConfigFile {
+  /// Its sensor type.
+  sensor_type: String,
+  /// Its sensor ID.
+  sensor_id: SensorId,
+  /// The key, as the sensor has it.
+  key: String,
+}
+
+/// One endpoint or several, as within the file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+enum EndpointsFile {
+  /// Just the one.
+  One(String),
+  /// Several, best first.
+  Many(Vec<String>),
+}
+
+impl EndpointsFile {
+  /// The URLs, best first.
+  fn urls(&self) -> Vec<&str> {
+    return match self {
+      EndpointsFile::One(url) => vec![url.as_str()],
+      EndpointsFile::Many(urls) => urls.iter().map(String::as_str).collect(),
+    };
+  }
+}
+
+/// An API that gets a copy of everything, as within the file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MirrorConfigFile {
+  /// Where it is.
+  endpoint: String,
+  /// Key to call in with. None means the one the endpoint gets.
+  home_key: Option<String>,
+  /// Where keys it rotates in are saved, and read back from on startup.
+  /// None means they're lost on restart.
+  key_file: Option<String>,
+  /// Key to seal bundles with, in base64. None means they go as they are.
+  seal_key: Option<String>,
+}
+
+/// An API that gets a copy of everything.
+#[derive(Clone, Debug)]
+pub struct MirrorConfig {
+  /// Where it is.
+  pub url: Url,
+  /// Key to call in with. None means the one the endpoint gets.
+  pub home_key: Option<String>,
+  /// Where keys it rotates in are saved, and read back from on startup.
+  /// None means they're lost on restart.
+  pub key_file: Option<PathBuf>,
+  /// Our UID and the key to seal bundles with. None means they go as they
+  /// are.
+  pub seal: Option<(BrokerId, SealingKey)>
+}
+
+/// APIs to POST to, and how they're kept an eye on.
+#[derive(Clone, Debug)]
+pub struct Endpoints {
+  /// Their URLs, best first. Never empty.
+  pub urls: Vec<Url>,
+  /// How often the ones that are down are checked on, and host names
+  /// looked up again.
+  pub check_every: Duration
+}
+
+impl UplinkConfig {
+  /// The APIs whose answers count, if it goes to any.
+  pub fn endpoints(&self) -> Option<&Endpoints> {
+    return match self {
+      UplinkConfig::Http(endpoints) => Some(endpoints),
+      UplinkConfig::Tee { main, .. } => Some(main),
+      _ => None,
+    };
+  }
+}
+
+impl MirrorConfig {
+  /// Parses a mirror, sealing as the given UID if it has a seal key.
+  fn from_file(cfg: &MirrorConfigFile, uid: &BrokerId)
+  -> Result<Self, BrokerConfigParseError> {
+    return Ok(Self {
+      url: Url::parse(&cfg.endpoint)
+        .map_err(|e| BrokerConfigParseError::BadEndpointUrl(e))?,
+      home_key: cfg.home_key.clone(),
+      key_file: cfg.key_file.as_ref().map(PathBuf::from),
+      seal: match &cfg.seal_key {
+        Some(k) => Some((*uid, SealingKey::from_base64(k)
+          .map_err(|_| BrokerConfigParseError::BadSealKey)?)),
+        None => None,
+      }
+    });
+  }
+}
+
+impl Endpoints {
+  /// How often endpoints are checked on by default, in seconds.
+  const DEFAULT_CHECK_SECS: usize = 60;
+
+  /// The best of them.
+  pub fn first(&self) -> &Url {
+    return &self.urls[0];
+  }
+}
+
+/// Where bundles and heartbeats go.
+#[derive(Clone, Debug)]
+pub enum UplinkConfig {
+  /// POSTed to the API at these URLs, the first that takes it.
+  Http(Endpoints),
+  /// POSTed like Http, and to every mirror too.
+  Tee {
+    /// The APIs whose answers count, as for Http.
+    main: Endpoints,
+    /// The APIs that get copies.
+    mirrors: Vec<MirrorConfig>,
+    /// Most bundles kept for a mirror that's down.
+    backlog: usize
+  },
+  /// Published to an upstream MQTT broker, under a topic.
+  Mqtt(ExternalMqttConfig, String),
+  /// Appended to NDJSON files in a directory, for offline setups.
+  File(PathBuf)
+}
+
+/// A transform, as within the file. Which fields matter depends on kind.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct TransformConfigFile {
+  /// "drop", "relabel", "clip" or "tag".
+  kind: String,
+  /// Only touch messages from these sensors. None means all of them.
+  sensor_ids: Option<Vec<SensorId>>,
+  /// Only touch messages of this sensor type. None means all of them.
+  sensor_type: Option<String>,
+  /// For "relabel": the sensor ID to give.
+  to: Option<SensorId>,
+  /// For "clip": the lowest value let through, °C or %RH.
+  min: Option<f64>,
+  /// For "clip": the highest value let through, °C or %RH.
+  max: Option<f64>,
+  /// For "tag": the tags to add.
+  tags: Option<BTreeMap<String, String>>,
+}
+
+/// Which messages a transform touches.
+#[derive(Clone, Debug, Default)]
+pub struct MessageMatcher {
+  /// Only these sensors. None means all of them.
+  pub sensor_ids: Option<Vec<SensorId>>,
+  /// Only this sensor type. None means all of them, device health included.
+  pub sensor_type: Option<SensorType>,
+}
+
+/// Something done to messages between decoding and bundling.
+#[derive(Clone, Debug)]
+pub enum Transform {
+  /// Throw them away.
+  Drop(MessageMatcher),
+  /// Give them another sensor ID.
+  Relabel(MessageMatcher, SensorId),
+  /// Pull readings into a range, in °C or %RH.
+  Clip(MessageMatcher, Option<f64>, Option<f64>),
+  /// Add tags, like where the sensor is.
+  Tag(MessageMatcher, BTreeMap<String, String>)
+}
+
+impl TryFrom<&TransformConfigFile> for Transform {
+  type Error = BrokerConfigParseError;
+  fn try_from(cfg: &TransformConfigFile) -> Result<Self, Self::Error> {
+    let bad = || BrokerConfigParseError::BadTransform(cfg.kind.clone());
+    let matcher = MessageMatcher {
+      sensor_ids: cfg.sensor_ids.clone(),
+      sensor_type: match &cfg.sensor_type {
+        Some(name) => Some(SensorType::from_str(name).map_err(|_| {
+          BrokerConfigParseError::BadSensorType(name.clone())
+        })?),
+        None => None,
+      },
+    };
+    return match cfg.kind.as_str() {
+      "drop" => Ok(Transform::Drop(matcher)),
+      "relabel" => Ok(Transform::Relabel(matcher, cfg.to.ok_or_else(bad)?)),
+      "clip" if cfg.min.is_some() || cfg.max.is_some() => {
+        Ok(Transform::Clip(matcher, cfg.min, cfg.max))
+      },
+      "tag" => Ok(Transform::Tag(matcher, cfg.tags.clone().ok_or_else(bad)?)),
+      _ => Err(bad()),
+    };
+  }
+}
+
+/// A route, as within the file. Whatever is left out is taken from the
+/// top-level settings.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct RouteConfigFile {
+  /// What to call it in logs. None means "route <n>", counting from 1.
+  name: Option<String>,
+  /// Sensor types taken, and maybe "device_health".
+  topics: Vec<String>,
+  /// Like the top-level endpoint.
+  endpoint: Option<EndpointsFile>,
+  /// Like the top-level uplink.
+  uplink: Option<String>,
+  /// Like the top-level uplink_mqtt.
+  uplink_mqtt: Option<ExternalMqttConfigFile>,
+  /// Like the top-level uplink_topic.
+  uplink_topic: Option<String>,
+  /// Like the top-level uplink_dir.
+  uplink_dir: Option<String>,
+  /// Like the top-level bundle_size.
+  bundle_size: Option<usize>,
+  /// Like the top-level bundle_timeout_msec.
+  bundle_timeout_msec: Option<usize>,
+  /// Like the top-level buffer_size_bundles.
+  buffer_size_bundles: Option<usize>,
+  /// Like the top-level send_concurrency.
+  send_concurrency: Option<usize>,
+}
+
+/// Where some messages go, and how they're bundled on the way.
+#[derive(Clone, Debug)]
+pub struct RouteConfig {
+  /// What to call it in logs.
+  pub name: String,
+  /// Sensor types taken.
+  pub sensor_types: Vec<SensorType>,
+  /// Whether device health is taken.
+  pub device_health: bool,
+  /// Where its bundles go.
+  pub uplink: UplinkConfig,
+  /// Most messages in one of its bundles.
+  pub bundle_size: usize,
+  /// Longest a message waits in its bundle.
+  pub bundle_timeout: Duration,
+  /// Most of its sealed bundles kept waiting to be sent.
+  pub buffer_size_bundles: usize,
+  /// How many of its bundles may be in flight at once.
+  pub send_concurrency: usize,
+}
+
+/// Make-believe uplink trouble, as within the file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ChaosConfigFile {
+  /// Chance, from 0 to 1, that a request is lost on the way. None means 0.
+  drop_rate: Option<f64>,
+  /// Chance, from 0 to 1, that a request is mangled on the way, and turned
+  /// away. None means 0.
+  corrupt_rate: Option<f64>,
+  /// Time every request is held up, at least. None means 0.
+  delay_msec: Option<usize>,
+  /// Most time a request is held up on top of delay_msec, picked at random.
+  /// None means 0.
+  delay_jitter_msec: Option<usize>,
+  /// How often the uplink goes down altogether. None means never.
+  outage_every_secs: Option<usize>,
+  /// How long it stays down. Needed with outage_every_secs.
+  outage_secs: Option<usize>,
+}
+
+/// Make-believe uplink trouble, for every uplink.
+#[derive(Clone, Debug)]
+pub struct ChaosConfig {
+  /// Chance that a request is lost on the way.
+  pub drop_rate: f64,
+  /// Chance that a request is mangled on the way.
+  pub corrupt_rate: f64,
+  /// Time every request is held up, at least.
+  pub delay: Duration,
+  /// Most time a request is held up on top of delay.
+  pub delay_jitter: Duration,
+  /// How often the uplink goes down, and for how long. None means never.
+  pub outage: Option<(Duration, Duration)>,
+}
+
+impl TryFrom<&ChaosConfigFile> for ChaosConfig {
+  type Error = BrokerConfigParseError;
+  #[cfg(debug_assertions)]
+  fn try_from(cfg: &ChaosConfigFile) -> Result<Self, Self::Error> {
+    let bad = |why: &str| BrokerConfigParseError::BadChaos(why.to_owned());
+    let drop_rate = cfg.drop_rate.unwrap_or(0.0);
+    let corrupt_rate = cfg.corrupt_rate.unwrap_or(0.0);
+    let rates = [drop_rate, corrupt_rate, drop_rate + corrupt_rate];
+    if rates.iter().any(|r| !(0.0..=1.0).contains(r)) {
+      return Err(bad("drop_rate, corrupt_rate and their sum go from 0 to 1"));
+    }
+    let secs = |s: usize| Duration::from_secs(s as u64);
+    let outage = match (cfg.outage_every_secs, cfg.outage_secs) {
+      (Some(every), Some(length)) if length < every => {
+        Some((secs(every), secs(length)))
+      },
+      (None, None) => None,
+      _ => return Err(bad("outage_secs must be under outage_every_secs")),
+    };
+    let msecs = |ms: Option<usize>| {
+      Duration::from_millis(ms.unwrap_or(0) as u64)
+    };
+    return Ok(Self {
+      drop_rate: drop_rate,
+      corrupt_rate: corrupt_rate,
+      delay: msecs(cfg.delay_msec),
+      delay_jitter: msecs(cfg.delay_jitter_msec),
+      outage: outage,
+    });
+  }
+
+  /// No chaos in release builds, however it's asked for.
+  #[cfg(not(debug_assertions))]
+  fn try_from(_: &ChaosConfigFile) -> Result<Self, Self::Error> {
+    return Err(BrokerConfigParseError::BadChaos(
+      "chaos only works in debug builds".to_owned()
+    ));
+  }
+}
+
+/// What to do with a fresh message when the channel is full.
+#[derive(Clone, Debug)]
+pub enum BackpressurePolicy {
+  /// Wait this long for room, then drop the message.
+  Block(Duration),
+  /// Put it straight into the bundle, evicting the oldest message there.
+  DropOldest,
+  /// Write it to the disk spool, to be queued again once there's room.
+  Spool(PathBuf)
+}
+
+impl BackpressurePolicy {
+  /// How long "block" waits by default, in milliseconds.
+  const DEFAULT_TIMEOUT_MSEC: usize = 1000;
+}
+
+/// How long calls to the API may take, be it bundles, heartbeats or
+/// enrollment, so a hung connection can't hold things up forever.
+#[derive(Copy, Clone, Debug)]
+pub struct HttpTimeouts {
+  /// To connect.
+  pub connect: Duration,
+  /// For the whole call, connecting included.
+  pub request: Duration
+}
+
+impl HttpTimeouts {
+  /// How long connecting may take by default, in milliseconds.
+  const DEFAULT_CONNECT_MSEC: usize = 5000;
+  /// How long a call may take by default, in milliseconds.
+  const DEFAULT_REQUEST_MSEC: usize = 30000;
+
+  /// Client options with these timeouts, and the defaults otherwise.
+  pub fn client_options(&self) -> ClientOptions {
+    return ClientOptions {
+      timeout: Some(self.request),
+      connect_timeout: Some(self.connect),
+      ..ClientOptions::default()
+    };
+  }
+}
+
+/// How bundles are encoded on their way to the API.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+  /// Plain JSON. What every API understands.
+  Json,
+  /// Protobuf, smaller. Needs an API that knows it.
+  Protobuf,
+  /// JSON, one message per line, which the API stores as it comes in. For
+  /// very large bundles.
+  Ndjson
+}
+
+impl FromStr for WireFormat {
+  type Err = BrokerConfigParseError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    return match s {
+      "json" => Ok(WireFormat::Json),
+      "protobuf" => Ok(WireFormat::Protobuf),
+      "ndjson" => Ok(WireFormat::Ndjson),
+      _ => Err(BrokerConfigParseError::BadWireFormat(s.to_owned())),
+    };
+  }
+}
+
+impl From<WireFormat> for BundleFormat {
+  fn from(wf: WireFormat) -> Self {
+    return match wf {
+      WireFormat::Json => BundleFormat::Json,
+      WireFormat::Protobuf => BundleFormat::Protobuf,
+      WireFormat::Ndjson => BundleFormat::Ndjson,
+    };
+  }
 }
 
 /// An error that can arise while parsing BrokerConfigFile into BrokerConfig.
@@ -63,10 +882,35 @@ pub struct BrokerConfig {
 pub enum BrokerConfigParseError {
   /// Meaning the endpoint URL has a syntax error of some sort.
   BadEndpointUrl(url::ParseError),
+  /// Unknown uplink, or one missing its settings.
+  BadUplink(String),
   /// Meaning the uuid for the broker was malformed.
   BadBrokerUuid(uuid::Error),
   /// Listed topic is not a valid sensor type.
   BadSensorType(String),
+  /// Unknown backpressure policy, or "spool" without a spool_path.
+  BadBackpressurePolicy(String),
+  /// Unknown wire format.
+  BadWireFormat(String),
+  /// A seal key that isn't 32 bytes of base64.
+  BadSealKey,
+  /// Unparseable address to listen on.
+  BadBindAddress(String),
+  /// Unknown serial framing.
+  BadSerialFraming(String),
+  /// Unparseable BLE sensor MAC.
+  BadMacAddress(String),
+  /// Unknown transform kind, or one missing its settings.
+  BadTransform(String),
+  /// A route that takes nothing.
+  BadRoute(String),
+  /// No uid, and no enrollment to make one up.
+  NoUid,
+  /// Enrollment without an enroll_dir or an HTTP uplink, or an enroll_dir
+  /// that can't be used.
+  BadEnrollment(String),
+  /// Chaos settings that don't add up, or chaos in a release build.
+  BadChaos(String),
   /// An error caught by the config crate.
   ConfigError(ConfigError)
 }
@@ -84,21 +928,232 @@ impl Default for BrokerConfigFile {
     return Self {
       topics: vec![],
       home_key: Some("<ACCESS KEY GOES HERE>".to_owned()),
-      endpoint: "<ENDPOINT URL GOES HERE>".to_owned(),
+      key_file: None,
+      remote_config_file: None,
+      endpoint: Some(EndpointsFile::One("<ENDPOINT URL GOES HERE>".to_owned())),
+      endpoint_check_secs: Some(Endpoints::DEFAULT_CHECK_SECS),
+      uplink: Some("http".to_owned()),
+      mirrors: None,
+      uplink_mqtt: None,
+      uplink_topic: None,
+      uplink_dir: None,
       bundle_size: 10,
       bundle_timeout_msec: 5000,
       buffer_size_bundles: 10,
       heartbeat_interval_secs: Some(30),
-      uid: Uuid::new_v4().to_string(),
+      connect_timeout_msec: Some(HttpTimeouts::DEFAULT_CONNECT_MSEC),
+      request_timeout_msec: Some(HttpTimeouts::DEFAULT_REQUEST_MSEC),
+      backpressure: Some("block".to_owned()),
+      backpressure_timeout_msec: Some(BackpressurePolicy::DEFAULT_TIMEOUT_MSEC),
+      spool_path: None,
+      capture_file: None,
+      dead_letters: Some(DEFAULT_DEAD_LETTERS),
+      dead_letter_path: None,
+      send_concurrency: Some(1),
+      preserve_order: Some(false),
+      bandwidth_budget_bytes_per_hour: None,
+      priority_topics: None,
+      wire_format: Some("json".to_owned()),
+      seal_key: None,
+      retain_raw: Some(false),
+      embedded_mqtt: Some(true),
+      mqtt_console: Some(true),
+      mqtt_console_bind: None,
+      external_mqtt: None,
+      coap_bind: None,
+      http_bind: None,
+      health_bind: None,
+      serial: None,
+      ble: None,
+      ble_min_interval_secs: Some(60),
+      zigbee: None,
+      sensor_keys: None,
+      require_signatures: Some(false),
+      transforms: None,
+      routes: None,
+      chaos: None,
+      detach: Some(false),
+      pid_file: None,
+      log_file: None,
+      update_hook: None,
+      update_dir: None,
+      enroll_token: None,
+      enroll_dir: None,
+      uid: Some(BrokerId::new_v4().to_string()),
     }
   }
 }
 
 impl BrokerConfigFile {
-  /// Returns the endpoint URl, properly parsed (if correct).
-  pub fn endpoint_url(&self) -> Result<Url, url::ParseError> {
-    return Url::parse(&self.endpoint)
+  /// Returns the endpoints of an uplink named so, properly parsed (if
+  /// correct). A route's own go over the top-level ones.
+  fn endpoints(&self, name: &str, r: Option<&RouteConfigFile>)
+  -> Result<Endpoints, BrokerConfigParseError> {
+    let missing = || BrokerConfigParseError::BadUplink(name.to_owned());
+    let urls = r.and_then(|r| r.endpoint.as_ref())
+      .or(self.endpoint.as_ref())
+      .ok_or_else(missing)?
+      .urls()
+      .into_iter()
+      .map(Url::parse)
+      .collect::<Result<Vec<Url>, _>>()
+      .map_err(|e| BrokerConfigParseError::BadEndpointUrl(e))?;
+    if urls.is_empty() {
+      return Err(missing());
+    }
+    let check_secs = self.endpoint_check_secs
+      .unwrap_or(Endpoints::DEFAULT_CHECK_SECS)
+      .max(1);
+    return Ok(Endpoints {
+      urls: urls,
+      check_every: Duration::from_secs(check_secs as u64)
+    });
+  }
+
+  /// Returns the uplink, properly parsed (if correct). For a route, its own
+  /// settings go over the top-level ones.
+  fn uplink_config(
+    &self, route: Option<(usize, &RouteConfigFile)>, uid: &BrokerId
+  ) -> Result<UplinkConfig, BrokerConfigParseError> {
+    let r = route.map(|(_, r)| r);
+    let name = r.and_then(|r| r.uplink.as_deref())
+      .or(self.uplink.as_deref())
+      .unwrap_or("http");
+    let missing = || BrokerConfigParseError::BadUplink(name.to_owned());
+    return match name {
+      "tee" => {
+        let main = self.endpoints(name, r)?;
+        let mirrors = self.mirrors.iter()
+          .flatten()
+          .map(|m| MirrorConfig::from_file(m, uid))
+          .collect::<Result<Vec<MirrorConfig>, _>>()?;
+        if mirrors.is_empty() {
+          return Err(missing());
+        }
+        Ok(UplinkConfig::Tee {
+          main: main,
+          mirrors: mirrors,
+          backlog: r.and_then(|r| r.buffer_size_bundles)
+            .unwrap_or(self.buffer_size_bundles)
+        })
+      },
+      "http" => Ok(UplinkConfig::Http(self.endpoints(name, r)?)),
+      "mqtt" => Ok(UplinkConfig::Mqtt(
+        ExternalMqttConfig::from_file(
+          r.and_then(|r| r.uplink_mqtt.as_ref())
+            .or(self.uplink_mqtt.as_ref())
+            .ok_or_else(missing)?,
+          match route {
+            // each route needs a client of its own.
+            Some((n, _)) => format!("cdp_broker-{}-uplink-{}", uid, n),
+            None => format!("cdp_broker-{}-uplink", uid),
+          }
+        ),
+        r.and_then(|r| r.uplink_topic.clone())
+          .or(self.uplink_topic.clone())
+          .unwrap_or_else(|| format!("cdp/{}", uid))
+      )),
+      "file" => Ok(UplinkConfig::File(PathBuf::from(
+        r.and_then(|r| r.uplink_dir.as_ref())
+          .or(self.uplink_dir.as_ref())
+          .ok_or_else(missing)?
+      ))),
+      _ => Err(missing()),
+    };
+  }
+
+  /// How many bundles may be in flight at once, given a route's setting.
+  fn send_concurrency(&self, own: Option<usize>) -> usize {
+    if self.preserve_order.unwrap_or(false) {
+      return 1;
+    }
+    return own.or(self.send_concurrency).unwrap_or(1).max(1);
   }
+
+  /// Returns a route, properly parsed (if correct). n counts from 1.
+  fn route_config(&self, n: usize, route: &RouteConfigFile, uid: &BrokerId)
+  -> Result<RouteConfig, BrokerConfigParseError> {
+    let name = route.name.clone().unwrap_or_else(|| format!("route {}", n));
+    let (sensor_types, device_health) = parse_topics(&route.topics)?;
+    if sensor_types.is_empty() && !device_health {
+      return Err(BrokerConfigParseError::BadRoute(name));
+    }
+    return Ok(RouteConfig {
+      name: name,
+      sensor_types: sensor_types,
+      device_health: device_health,
+      uplink: self.uplink_config(Some((n, route)), uid)?,
+      bundle_size: route.bundle_size.unwrap_or(self.bundle_size),
+      bundle_timeout: Duration::from_millis(
+        route.bundle_timeout_msec.unwrap_or(self.bundle_timeout_msec) as u64
+      ),
+      buffer_size_bundles: route.buffer_size_bundles
+        .unwrap_or(self.buffer_size_bundles),
+      send_concurrency: self.send_concurrency(route.send_concurrency),
+    });
+  }
+
+  /// Returns the bandwidth budget, properly parsed (if correct).
+  fn bandwidth_budget(&self)
+  -> Result<Option<BandwidthBudget>, BrokerConfigParseError> {
+    let bytes_per_hour = match self.bandwidth_budget_bytes_per_hour {
+      Some(b) => b as u64,
+      None => return Ok(None),
+    };
+    let priority = self.priority_topics.as_deref().unwrap_or_default();
+    let (sensor_types, device_health) = parse_topics(priority)?;
+    return Ok(Some(BandwidthBudget {
+      bytes_per_hour: bytes_per_hour,
+      sensor_types: sensor_types,
+      device_health: device_health
+    }));
+  }
+
+  /// Returns the backpressure policy, properly parsed (if correct).
+  pub fn backpressure_policy(&self)
+  -> Result<BackpressurePolicy, BrokerConfigParseError> {
+    let name = self.backpressure.as_deref().unwrap_or("block");
+    return match (name, &self.spool_path) {
+      ("block", _) => Ok(BackpressurePolicy::Block(Duration::from_millis(
+        self.backpressure_timeout_msec
+          .unwrap_or(BackpressurePolicy::DEFAULT_TIMEOUT_MSEC) as u64
+      ))),
+      ("drop_oldest", _) => Ok(BackpressurePolicy::DropOldest),
+      ("spool", Some(p)) => Ok(BackpressurePolicy::Spool(PathBuf::from(p))),
+      _ => Err(BrokerConfigParseError::BadBackpressurePolicy(
+        name.to_owned()
+      )),
+    };
+  }
+}
+
+/// Parses an optional address to listen on.
+fn parse_bind(addr: &Option<String>)
+-> Result<Option<SocketAddr>, BrokerConfigParseError> {
+  return match addr {
+    Some(a) => SocketAddr::from_str(a)
+      .map(Some)
+      .map_err(|_| BrokerConfigParseError::BadBindAddress(a.to_owned())),
+    None => Ok(None),
+  };
+}
+
+/// Parses a list of sensor types, and maybe "device_health", into the
+/// former and whether the latter was there.
+fn parse_topics(topics: &[String])
+-> Result<(Vec<SensorType>, bool), BrokerConfigParseError> {
+  let mut sensor_types = Vec::new();
+  let mut device_health = false;
+  for topic in topics {
+    if topic == DeviceHealthMessage::TOPIC {
+      device_health = true;
+    } else {
+      sensor_types.push(SensorType::from_str(topic).map_err(|_| {
+        BrokerConfigParseError::BadSensorType(topic.clone())
+      })?);
+    }
+  }
+  return Ok((sensor_types, device_health));
 }
 
 impl TryFrom<&BrokerConfigFile> for BrokerConfig {
@@ -114,18 +1169,122 @@ impl TryFrom<&BrokerConfigFile> for BrokerConfig {
         )
       };
     }
+    let enrollment = match &cfg.enroll_token {
+      Some(token) => Some(EnrollConfig {
+        token: token.clone(),
+        dir: PathBuf::from(cfg.enroll_dir.as_ref().ok_or_else(|| {
+          Self::Error::BadEnrollment("enroll_token needs enroll_dir".into())
+        })?)
+      }),
+      None => None,
+    };
+    let uid = match (&cfg.uid, &enrollment) {
+      (Some(uid), _) => uid.parse()
+        .map_err(|e| Self::Error::BadBrokerUuid(e))?,
+      (None, Some(e)) => enroll::load_uid(&e.dir)
+        .map_err(|e| Self::Error::BadEnrollment(e.to_string()))?,
+      (None, None) => return Err(Self::Error::NoUid),
+    };
+    let uplink = cfg.uplink_config(None, &uid)?;
+    if enrollment.is_some() && uplink.endpoints().is_none() {
+      return Err(Self::Error::BadEnrollment(
+        "enrollment needs the http or tee uplink".into()
+      ));
+    }
+    // an enrolled broker keeps the key it was given with the rest.
+    let key_file = cfg.key_file.as_ref()
+      .map(PathBuf::from)
+      .or_else(|| enrollment.as_ref().map(|e| e.dir.join(enroll::KEY_FILE)));
     return Ok(Self {
       topics: topics,
       home_key: cfg.home_key.clone(),
-      endpoint: cfg.endpoint_url()
-        .map_err(|e| Self::Error::BadEndpointUrl(e))?,
+      key_file: key_file,
+      remote_config_file: cfg.remote_config_file.as_ref().map(PathBuf::from),
+      uplink: uplink,
       bundle_size: cfg.bundle_size,
       bundle_timeout: Duration::from_millis(cfg.bundle_timeout_msec as u64),
       buffer_size_bundles: cfg.buffer_size_bundles,
       heartbeat_interval: cfg.heartbeat_interval_secs
         .map(|secs| Duration::from_secs(secs as u64)),
-      uid: Uuid::parse_str(&cfg.uid)
-        .map_err(|e| Self::Error::BadBrokerUuid(e))?,
+      http_timeouts: HttpTimeouts {
+        connect: Duration::from_millis(cfg.connect_timeout_msec
+          .unwrap_or(HttpTimeouts::DEFAULT_CONNECT_MSEC) as u64),
+        request: Duration::from_millis(cfg.request_timeout_msec
+          .unwrap_or(HttpTimeouts::DEFAULT_REQUEST_MSEC) as u64)
+      },
+      backpressure: cfg.backpressure_policy()?,
+      capture_file: cfg.capture_file.as_ref().map(PathBuf::from),
+      dead_letters: cfg.dead_letters.unwrap_or(DEFAULT_DEAD_LETTERS),
+      dead_letter_path: cfg.dead_letter_path.as_ref().map(PathBuf::from),
+      replay: None,
+      send_concurrency: cfg.send_concurrency(None),
+      preserve_order: cfg.preserve_order.unwrap_or(false),
+      bandwidth_budget: cfg.bandwidth_budget()?,
+      wire_format: WireFormat::from_str(
+        cfg.wire_format.as_deref().unwrap_or("json")
+      )?,
+      seal_key: match &cfg.seal_key {
+        Some(k) => Some(SealingKey::from_base64(k)
+          .map_err(|_| Self::Error::BadSealKey)?),
+        None => None,
+      },
+      retain_raw: cfg.retain_raw.unwrap_or(false),
+      embedded_mqtt: cfg.embedded_mqtt
+        .unwrap_or(cfg.external_mqtt.is_none()),
+      mqtt_console: cfg.mqtt_console.unwrap_or(true),
+      mqtt_console_bind: parse_bind(&cfg.mqtt_console_bind)?,
+      external_mqtt: cfg.external_mqtt.as_ref()
+        .map(|ext| {
+          ExternalMqttConfig::from_file(ext, format!("cdp_broker-{}", uid))
+        }),
+      coap_bind: parse_bind(&cfg.coap_bind)?,
+      http_bind: parse_bind(&cfg.http_bind)?,
+      health_bind: parse_bind(&cfg.health_bind)?,
+      serial: cfg.serial.iter()
+        .flatten()
+        .map(SerialPortConfig::try_from)
+        .collect::<Result<Vec<SerialPortConfig>, _>>()?,
+      ble: cfg.ble.iter()
+        .flatten()
+        .map(BleSensorConfig::try_from)
+        .collect::<Result<Vec<BleSensorConfig>, _>>()?,
+      ble_min_interval: Duration::from_secs(
+        cfg.ble_min_interval_secs.unwrap_or(60) as u64
+      ),
+      zigbee: cfg.zigbee.iter()
+        .flatten()
+        .map(ZigbeeDeviceConfig::try_from)
+        .collect::<Result<Vec<ZigbeeDeviceConfig>, _>>()?,
+      sensor_keys: cfg.sensor_keys.iter()
+        .flatten()
+        .map(|sk| match SensorType::from_str(&sk.sensor_type) {
+          Ok(st) => Ok(((st, sk.sensor_id), sk.key.clone())),
+          Err(_) => Err(Self::Error::BadSensorType(sk.sensor_type.clone())),
+        })
+        .collect::<Result<HashMap<(SensorType, SensorId), String>, _>>()?,
+      require_signatures: cfg.require_signatures.unwrap_or(false),
+      transforms: cfg.transforms.iter()
+        .flatten()
+        .map(Transform::try_from)
+        .collect::<Result<Vec<Transform>, _>>()?,
+      routes: cfg.routes.iter()
+        .flatten()
+        .enumerate()
+        .map(|(i, r)| cfg.route_config(i + 1, r, &uid))
+        .collect::<Result<Vec<RouteConfig>, _>>()?,
+      chaos: match &cfg.chaos {
+        Some(c) => Some(ChaosConfig::try_from(c)?),
+        None => None,
+      },
+      detach: cfg.detach.unwrap_or(false),
+      pid_file: cfg.pid_file.as_ref().map(PathBuf::from),
+      log_file: cfg.log_file.as_ref().map(PathBuf::from),
+      update_hook: cfg.update_hook.as_ref().map(PathBuf::from),
+      update_dir: cfg.update_dir.as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir),
+      enrollment: enrollment,
+      uid: uid,
     });
   }
 }
@@ -138,24 +1297,66 @@ impl TryFrom<BrokerConfigFile> for BrokerConfig {
   }
 }
 
+impl BrokerConfig {
+  /// The route for whatever no configured one takes, as per the top-level
+  /// settings.
+  pub fn default_route(&self) -> RouteConfig {
+    return RouteConfig {
+      name: "default".to_owned(),
+      sensor_types: self.topics.clone(),
+      device_health: true,
+      uplink: self.uplink.clone(),
+      bundle_size: self.bundle_size,
+      bundle_timeout: self.bundle_timeout,
+      buffer_size_bundles: self.buffer_size_bundles,
+      send_concurrency: self.send_concurrency,
+    };
+  }
+}
+
 impl From<&BrokerConfig> for HeartbeatMessage {
   /// Allow creation of a HeartbeatMessage directly from broker config.
   fn from(cfg: &BrokerConfig) -> Self {
     return Self {
+      version: HeartbeatMessage::VERSION,
       uid: cfg.uid,
-      key: cfg.home_key.clone()
+      key: cfg.home_key.clone(),
+      status: None,
+      config_hash: None,
+      update: None,
+      software: None
     }
   }
 }
 
-/// Load the default configuration files for the broker.
-pub fn load_defaults()
--> Result<(BrokerConfig, RumqqtdConfig), BrokerConfigParseError> {
+/// Load the default configuration files for the broker: cdp_broker.toml,
+/// unless another one is given, and cdp_rumqttd.toml next to it. The rumqttd
+/// one is only needed if the embedded MQTT broker is enabled.
+pub fn load_defaults(given: Option<&Path>)
+-> Result<(BrokerConfig, Option<RumqqtdConfig>), BrokerConfigParseError> {
+  let rumqttd = runtime::config_dir(given).join("cdp_rumqttd");
+  let rumqttd = config::File::with_name(&rumqttd.to_string_lossy());
   let mut cfg = Config::default();
   cfg
-    .merge(config::File::with_name("cdp_rumqttd"))?
-    .merge(config::File::with_name("cdp_broker"))?;
-  let bc: BrokerConfigFile = cfg.clone().try_into()?;
-  let rc: RumqqtdConfig = cfg.try_into()?;
-  return Ok((bc.try_into()?, rc));
+    .merge(rumqttd.required(false))?
+    .merge(runtime::config_file("cdp_broker", given))?;
+  return load_from(cfg);
+}
+
+/// Load the configuration for the broker, and the embedded MQTT broker's if
+/// it's enabled, from wherever they were merged from.
+pub fn load_from(cfg: Config)
+-> Result<(BrokerConfig, Option<RumqqtdConfig>), BrokerConfigParseError> {
+  let bc: BrokerConfig = cfg.clone().try_into::<BrokerConfigFile>()?
+    .try_into()?;
+  let rc: Option<RumqqtdConfig> = if bc.embedded_mqtt {
+    let mut rc: RumqqtdConfig = cfg.try_into()?;
+    if let Some(addr) = bc.mqtt_console_bind {
+      rc.console.listen = addr;
+    }
+    Some(rc)
+  } else {
+    None
+  };
+  return Ok((bc, rc));
 }
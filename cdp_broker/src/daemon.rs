@@ -0,0 +1,199 @@
+//! Running without a container or a service manager to babysit us. The
+//! broker stays in the foreground unless told to detach, by --detach or
+//! detach = true in the config. Detaching runs the broker again in the
+//! background, with its output going to the log file, and the one started
+//! from the terminal exits as soon as that's off.
+//!
+//! The PID file, if any, always holds the PID of the broker doing the work,
+//! and is there by the time the terminal gets its prompt back.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+
+use crate::config::{BrokerConfig, ReplayConfig};
+
+/// Set for the broker that runs in the background, so it knows it is.
+const DETACHED_ENV: &str = "CDP_BROKER_DETACHED";
+
+/// What the command line takes.
+pub(crate) const USAGE: &str = "\
+Usage: cdp_broker [options]
+
+  -c, --config <path> the config file (cdp_broker.toml in CDP_CONFIG_DIR,
+                      or here); cdp_rumqttd.toml is looked for next to it
+  -d, --detach        run in the background
+  -f, --foreground    stay in the foreground (the default)
+  --pid-file <path>   write our PID there
+  --log-file <path>   where a detached broker's output goes
+  --capture <path>    record every MQTT publish there
+  --replay <path>     play a capture back instead of listening to sensors
+  --replay-speed <x>  how many times faster to play it back; 0 means as
+                      fast as it goes (1)
+  -h, --help          print this and quit
+
+Options given here go over the ones in cdp_broker.toml.";
+
+/// Whatever the command line says, to go over the config.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Args {
+  /// --config.
+  pub(crate) config: Option<PathBuf>,
+  /// --detach or --foreground, whichever came last.
+  detach: Option<bool>,
+  /// --pid-file.
+  pid_file: Option<PathBuf>,
+  /// --log-file.
+  log_file: Option<PathBuf>,
+  /// --capture.
+  capture_file: Option<PathBuf>,
+  /// --replay.
+  replay: Option<PathBuf>,
+  /// --replay-speed.
+  replay_speed: Option<f64>,
+  /// --help.
+  pub(crate) help: bool,
+}
+
+impl Args {
+  /// Parses the arguments, without the program name.
+  pub(crate) fn parse<I: Iterator<Item = String>>(mut args: I)
+  -> Result<Self, String> {
+    let mut parsed = Self::default();
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "-c" | "--config" => parsed.config = Some(
+          args.next().ok_or("--config needs a path.")?.into()
+        ),
+        "-d" | "--detach" => parsed.detach = Some(true),
+        "-f" | "--foreground" => parsed.detach = Some(false),
+        "--pid-file" => parsed.pid_file = Some(
+          args.next().ok_or("--pid-file needs a path.")?.into()
+        ),
+        "--log-file" => parsed.log_file = Some(
+          args.next().ok_or("--log-file needs a path.")?.into()
+        ),
+        "--capture" => parsed.capture_file = Some(
+          args.next().ok_or("--capture needs a path.")?.into()
+        ),
+        "--replay" => parsed.replay = Some(
+          args.next().ok_or("--replay needs a path.")?.into()
+        ),
+        "--replay-speed" => {
+          let speed = args.next().ok_or("--replay-speed needs a speed.")?;
+          parsed.replay_speed = match speed.parse::<f64>() {
+            Ok(s) if s.is_finite() && s >= 0.0 => Some(s),
+            _ => return Err(format!("Bad replay speed \"{}\".", speed)),
+          };
+        },
+        "-h" | "--help" => parsed.help = true,
+        _ => return Err(format!("Unknown argument \"{}\".", arg)),
+      }
+    }
+    if parsed.replay_speed.is_some() && parsed.replay.is_none() {
+      return Err("--replay-speed needs --replay.".to_owned());
+    }
+    return Ok(parsed);
+  }
+
+  /// Puts them over the config.
+  pub(crate) fn apply(self, cfg: &mut BrokerConfig) {
+    if let Some(detach) = self.detach {
+      cfg.detach = detach;
+    }
+    if self.pid_file.is_some() {
+      cfg.pid_file = self.pid_file;
+    }
+    if self.log_file.is_some() {
+      cfg.log_file = self.log_file;
+    }
+    if self.capture_file.is_some() {
+      cfg.capture_file = self.capture_file;
+    }
+    if let Some(path) = self.replay {
+      cfg.replay = Some(ReplayConfig {
+        path: path,
+        speed: self.replay_speed.unwrap_or(1.0)
+      });
+    }
+  }
+}
+
+/// Whether a process is still around, as far as /proc can tell. Without a
+/// /proc, nobody is, and stale PID files get overwritten.
+fn running(pid: u32) -> bool {
+  return Path::new("/proc").join(pid.to_string()).exists();
+}
+
+/// Our PID, written down for init scripts. Removed when dropped.
+#[derive(Debug)]
+pub(crate) struct PidFile(PathBuf);
+
+impl PidFile {
+  /// Fails if the file names a broker that's still running, other than us.
+  /// Files left behind by dead ones are fair game.
+  fn check(path: &Path) -> io::Result<()> {
+    let old = match fs::read_to_string(path) {
+      Ok(s) => s,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(e),
+    };
+    return match old.trim().parse::<u32>() {
+      Ok(pid) if pid != process::id() && running(pid) => Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} says we're already running as {}.", path.display(), pid)
+      )),
+      _ => Ok(()),
+    };
+  }
+
+  /// Writes our PID down, unless another broker's is there.
+  pub(crate) fn create(path: &Path) -> io::Result<Self> {
+    Self::check(path)?;
+    fs::write(path, format!("{}\n", process::id()))?;
+    return Ok(Self(path.to_owned()));
+  }
+}
+
+impl Drop for PidFile {
+  fn drop(&mut self) {
+    if let Err(e) = fs::remove_file(&self.0) {
+      warn!("Couldn't remove {}: {}", self.0.display(), e);
+    }
+  }
+}
+
+/// Runs the broker again in the background and exits, unless we're to stay
+/// in the foreground or we're the one in the background already.
+pub(crate) fn detach(cfg: &BrokerConfig) -> io::Result<()> {
+  if !cfg.detach || env::var_os(DETACHED_ENV).is_some() {
+    return Ok(());
+  }
+  if let Some(path) = &cfg.pid_file {
+    PidFile::check(path)?;
+  }
+  let (stdout, stderr) = match &cfg.log_file {
+    Some(path) => {
+      let log = OpenOptions::new().create(true).append(true).open(path)?;
+      (Stdio::from(log.try_clone()?), Stdio::from(log))
+    },
+    None => (Stdio::null(), Stdio::null()),
+  };
+  let mut cmd = Command::new(env::current_exe()?);
+  cmd.args(env::args_os().skip(1))
+    .env(DETACHED_ENV, "1")
+    .stdin(Stdio::null())
+    .stdout(stdout)
+    .stderr(stderr);
+  // out of the terminal's process group, so closing it leaves us be.
+  #[cfg(unix)]
+  std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+  let child = cmd.spawn()?;
+  if let Some(path) = &cfg.pid_file {
+    fs::write(path, format!("{}\n", child.id()))?;
+  }
+  info!("Detached as PID {}.", child.id());
+  process::exit(0);
+}
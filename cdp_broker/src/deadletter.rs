@@ -0,0 +1,82 @@
+//! Dead letters: payloads that didn't decode, kept instead of only being
+//! complained about. They wait here, in memory or in a spool of their own,
+//! until an API that takes them hears of them, so whoever runs it can tell
+//! which devices are acting up. There's only room for so many; when full,
+//! the oldest go.
+
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use libcdp::comm::broker_api::BrokerMessage;
+
+use crate::spool::Spool;
+
+/// Where dead letters are kept.
+#[derive(Debug)]
+enum Store {
+  /// In memory, lost on restart.
+  Memory(Mutex<VecDeque<BrokerMessage>>),
+  /// On disk, to outlive us.
+  Disk(Spool)
+}
+
+/// The dead letters. Thread-safe.
+#[derive(Debug)]
+pub(crate) struct DeadLetters {
+  /// How many there's room for.
+  capacity: usize,
+  /// Where they are.
+  store: Store
+}
+
+impl DeadLetters {
+  /// Sets up room for some dead letters, on disk if given a path, picking
+  /// up whatever a previous run left there.
+  pub(crate) fn open(capacity: usize, path: Option<PathBuf>)
+  -> io::Result<Self> {
+    let store = match path {
+      Some(p) => Store::Disk(Spool::open(p)?),
+      None => Store::Memory(Mutex::new(VecDeque::new())),
+    };
+    return Ok(Self {
+      capacity: capacity,
+      store: store
+    });
+  }
+
+  /// Keeps a dead letter, making room if need be. Returns how many older
+  /// ones went to make room.
+  pub(crate) fn push(&self, msg: &BrokerMessage) -> io::Result<usize> {
+    return match &self.store {
+      Store::Memory(q) => {
+        let mut q = q.lock().unwrap_or_else(|e| e.into_inner());
+        let mut lost = 0;
+        while q.len() >= self.capacity && q.pop_front().is_some() {
+          lost += 1;
+        }
+        q.push_back(msg.clone());
+        Ok(lost)
+      },
+      Store::Disk(spool) => {
+        let over = (spool.len() + 1).saturating_sub(self.capacity);
+        let (taken, bad) = spool.take(over)?;
+        spool.push(msg)?;
+        Ok(taken.len() + bad)
+      },
+    };
+  }
+
+  /// Takes up to max dead letters out, oldest first.
+  pub(crate) fn take(&self, max: usize) -> io::Result<Vec<BrokerMessage>> {
+    return match &self.store {
+      Store::Memory(q) => {
+        let mut q = q.lock().unwrap_or_else(|e| e.into_inner());
+        let n = max.min(q.len());
+        Ok(q.drain(..n).collect())
+      },
+      Store::Disk(spool) => spool.take(max).map(|(msgs, _)| msgs),
+    };
+  }
+}
@@ -0,0 +1,116 @@
+//! First-boot enrollment. A broker with an enroll_token and no key makes up
+//! its UID and keypair, keeps them in its enroll_dir, and asks the API to
+//! enroll it, over and over, until an admin approves and the API answers
+//! with a key. The key is kept in the enroll_dir too, so later boots go
+//! straight to work.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use cdp_client::ApiClient;
+use libcdp::comm::enrollment::{EnrollState, Identity};
+use libcdp::ids::BrokerId;
+
+use crate::config::{BrokerConfig, EnrollConfig};
+
+/// Where the UID is kept, within the enroll_dir.
+const UID_FILE: &str = "uid";
+
+/// Where the keypair is kept, within the enroll_dir, in PKCS#8.
+const IDENTITY_FILE: &str = "identity.pk8";
+
+/// Where the key is kept, within the enroll_dir, unless key_file says
+/// otherwise.
+pub(crate) const KEY_FILE: &str = "home_key";
+
+/// How long to wait between asks when there's no heartbeat interval.
+const DEFAULT_POLL: Duration = Duration::from_secs(30);
+
+/// Writes a file only we can read, all at once, so a crash halfway through
+/// leaves nothing behind.
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+  let tmp = path.with_extension("tmp");
+  let mut opts = OpenOptions::new();
+  opts.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+  let mut file = opts.open(&tmp)?;
+  file.write_all(data)?;
+  file.sync_all()?;
+  return fs::rename(&tmp, path);
+}
+
+/// The UID kept in an enroll_dir, made up and kept there if there's none.
+pub(crate) fn load_uid(dir: &Path) -> io::Result<BrokerId> {
+  let path = dir.join(UID_FILE);
+  match fs::read_to_string(&path) {
+    Ok(s) => {
+      return s.trim().parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    },
+    Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+    Err(e) => return Err(e),
+  };
+  fs::create_dir_all(dir)?;
+  let uid = BrokerId::new_v4();
+  write_private(&path, format!("{}\n", uid).as_bytes())?;
+  info!("Made up a UID for enrollment: {}", uid);
+  return Ok(uid);
+}
+
+/// The keypair kept in an enroll_dir, made up and kept there if there's
+/// none.
+fn load_identity(dir: &Path) -> Result<Identity, String> {
+  let path = dir.join(IDENTITY_FILE);
+  let pkcs8 = match fs::read(&path) {
+    Ok(bytes) => bytes,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+      let bytes = Identity::generate().map_err(|e| e.to_string())?;
+      write_private(&path, &bytes).map_err(|e| e.to_string())?;
+      bytes
+    },
+    Err(e) => return Err(e.to_string()),
+  };
+  return Identity::from_pkcs8(&pkcs8).map_err(|e| e.to_string());
+}
+
+/// Asks the API once. Some(key) once approved.
+async fn ask(
+  client: &ApiClient, cfg: &BrokerConfig, enr: &EnrollConfig, id: &Identity
+) -> Result<Option<String>, String> {
+  let reply = client.enroll(&id.request(cfg.uid, &enr.token))
+    .await
+    .map_err(|e| e.to_string())?;
+  return Ok(match reply.state {
+    EnrollState::Approved => reply.key,
+    EnrollState::Pending => None,
+  });
+}
+
+/// Enrolls, waiting for as long as it takes. Returns the key to call in
+/// with. Only the first endpoint is asked, the one called home.
+pub(crate) async fn enroll(cfg: &BrokerConfig, enr: &EnrollConfig)
+-> String {
+  let id = load_identity(&enr.dir)
+    .unwrap_or_else(|e| panic!("Can't load our enrollment keypair: {}", e));
+  info!("Enrolling as {}, public key {}...", cfg.uid, id.public_key());
+  let endpoint = match cfg.uplink.endpoints() {
+    Some(endpoints) => endpoints.first().clone(),
+    None => panic!("Enrollment needs the http or tee uplink."),
+  };
+  let client = ApiClient::new(endpoint, cfg.http_timeouts.client_options());
+  let every = cfg.heartbeat_interval.unwrap_or(DEFAULT_POLL);
+  loop {
+    match ask(&client, cfg, enr, &id).await {
+      Ok(Some(key)) => {
+        info!("Enrolled!");
+        return key;
+      },
+      Ok(None) => info!("Enrollment is waiting for approval."),
+      Err(e) => warn!("Couldn't enroll: {}", e),
+    }
+    tokio::time::sleep(every).await;
+  }
+}
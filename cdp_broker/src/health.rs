@@ -0,0 +1,159 @@
+//! A tiny HTTP endpoint for orchestrators, like Kubernetes with a liveness
+//! probe, to tell a wedged broker from a working one and restart it.
+//!
+//! GET /healthz answers 200 while the embedded MQTT router's thread and the
+//! broker's tasks are all running, and 503 once any of them stopped, or
+//! while it's waiting to be started over. Either way, the body says which
+//! is which, how many times each was started over, and how the last attempt
+//! at sending something home went, as JSON. The uplink alone never fails
+//! it, since restarting won't bring the API back. It's served by the
+//! broker's own runtime, so if that hangs, so does the probe, which counts
+//! as a failure.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::broker::Broker;
+
+/// An attempt at sending something home.
+#[derive(Clone, Debug, Serialize)]
+struct UplinkAttempt {
+  /// When it ended.
+  when: DateTime<Local>,
+  /// Whether it went through.
+  ok: bool,
+  /// Why it didn't, if it didn't.
+  error: Option<String>
+}
+
+/// Whether the broker's insides are still going. Thread-safe.
+#[derive(Debug, Default)]
+pub(crate) struct Liveness {
+  /// Threads and tasks that should run for as long as we do, by name, and
+  /// whether each still is.
+  running: Mutex<BTreeMap<String, bool>>,
+  /// How many times each task was started over after panicking.
+  restarts: Mutex<BTreeMap<String, u64>>,
+  /// The last attempt at sending something home. None until there's one.
+  uplink: Mutex<Option<UplinkAttempt>>
+}
+
+/// Marks a thread or task as running, until it's dropped along with it,
+/// even if it panicked.
+pub(crate) struct Running<'a> {
+  /// Whose it is.
+  liveness: &'a Liveness,
+  /// What's running.
+  name: String
+}
+
+impl Running<'_> {
+  /// For things that stop on purpose: forgets it, instead of taking it
+  /// for dead.
+  pub(crate) fn finish(self) {
+    self.liveness.running().remove(&self.name);
+  }
+}
+
+impl Drop for Running<'_> {
+  fn drop(&mut self) {
+    if let Some(up) = self.liveness.running().get_mut(&self.name) {
+      *up = false;
+    }
+  }
+}
+
+impl Liveness {
+  /// The running map, locked, poisoned or not.
+  fn running(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, bool>> {
+    return self.running.lock().unwrap_or_else(|e| e.into_inner());
+  }
+
+  /// Marks something as running, for as long as the guard lives.
+  pub(crate) fn start(&self, name: &str) -> Running<'_> {
+    self.running().insert(name.to_owned(), true);
+    return Running { liveness: self, name: name.to_owned() };
+  }
+
+  /// Counts a task that panicked and is being started over.
+  pub(crate) fn restarted(&self, name: &str) {
+    let mut restarts = self.restarts.lock()
+      .unwrap_or_else(|e| e.into_inner());
+    *restarts.entry(name.to_owned()).or_insert(0) += 1;
+  }
+
+  /// Notes how an attempt at sending something home went.
+  pub(crate) fn uplink(&self, error: Option<String>) {
+    let attempt = UplinkAttempt {
+      when: Local::now(),
+      ok: error.is_none(),
+      error: error
+    };
+    *self.uplink.lock().unwrap_or_else(|e| e.into_inner()) = Some(attempt);
+  }
+
+  /// Whether everything that should be running is, and the details.
+  fn report(&self) -> (bool, serde_json::Value) {
+    let running = self.running().clone();
+    let healthy = running.values().all(|up| *up);
+    let restarts = self.restarts.lock().unwrap_or_else(|e| e.into_inner())
+      .clone();
+    let uplink = self.uplink.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    return (healthy, json!({
+      "healthy": healthy,
+      "running": running,
+      "restarts": restarts,
+      "last_uplink": uplink
+    }));
+  }
+}
+
+/// Answers a single request.
+async fn handle(broker: Arc<Broker>, req: Request<Body>)
+-> Result<Response<Body>, Infallible> {
+  if req.uri().path() != "/healthz" {
+    let mut resp = Response::new(Body::from("Nothing here."));
+    *resp.status_mut() = StatusCode::NOT_FOUND;
+    return Ok(resp);
+  }
+  if req.method() != Method::GET && req.method() != Method::HEAD {
+    let mut resp = Response::new(Body::from("GET only."));
+    *resp.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+    return Ok(resp);
+  }
+  let (healthy, report) = broker.liveness.report();
+  let mut resp = Response::new(Body::from(report.to_string()));
+  resp.headers_mut().insert(
+    CONTENT_TYPE, "application/json".parse().unwrap()
+  );
+  if !healthy {
+    *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+  }
+  return Ok(resp);
+}
+
+/// Answers health checks forever.
+pub(crate) async fn serve(broker: Arc<Broker>, addr: SocketAddr) {
+  let make_svc = make_service_fn(move |_| {
+    let broker = broker.clone();
+    return async move {
+      Ok::<_, Infallible>(service_fn(move |req| handle(broker.clone(), req)))
+    };
+  });
+  let server = Server::try_bind(&addr)
+    .unwrap_or_else(|e| panic!("Can't bind health checks to {}: {}", addr, e))
+    .serve(make_svc);
+  info!("Answering health checks at http://{}/healthz.", addr);
+  if let Err(e) = server.await {
+    warn!("Health checks died: {}", e);
+  }
+}
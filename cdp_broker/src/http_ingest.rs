@@ -0,0 +1,126 @@
+//! A small HTTP listener, for devices on the local network that can't speak
+//! MQTT. POST /ingest/{topic} takes either the same bytes an MQTT publish to
+//! {topic} would carry, or the message's JSON form if the content type says
+//! application/json.
+
+use std::convert::Infallible;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+
+use crate::broker::{Broker, RawOutcome};
+use crate::source::SensorSource;
+
+/// Largest body we'll read. Sensor payloads are a handful of bytes.
+const MAX_BODY_BYTES: usize = 4096;
+
+/// Builds a plain-text response.
+fn reply(status: StatusCode, text: &'static str) -> Response<Body> {
+  let mut resp = Response::new(Body::from(text));
+  *resp.status_mut() = status;
+  return resp;
+}
+
+/// Reads the whole body, unless it's too big.
+async fn read_body(body: &mut Body) -> Option<Vec<u8>> {
+  let mut out: Vec<u8> = Vec::new();
+  while let Some(chunk) = body.data().await {
+    out.extend_from_slice(&chunk.ok()?);
+    if out.len() > MAX_BODY_BYTES {
+      return None;
+    }
+  }
+  return Some(out);
+}
+
+/// Handles a single request.
+async fn handle(broker: Arc<Broker>, mut req: Request<Body>)
+-> Result<Response<Body>, Infallible> {
+  let topic = match req.uri().path().strip_prefix("/ingest/") {
+    Some(t) if !t.is_empty() => t.to_owned(),
+    _ => return Ok(reply(StatusCode::NOT_FOUND, "Nothing here.")),
+  };
+  if req.method() != Method::POST {
+    return Ok(reply(StatusCode::METHOD_NOT_ALLOWED, "POST only."));
+  }
+  let is_json = req.headers()
+    .get(CONTENT_TYPE)
+    .and_then(|v| v.to_str().ok())
+    .map(|v| v.starts_with("application/json"))
+    .unwrap_or(false);
+  let body = match read_body(req.body_mut()).await {
+    Some(b) => b,
+    None => return Ok(reply(StatusCode::PAYLOAD_TOO_LARGE, "Too big.")),
+  };
+  let outcome = if is_json {
+    broker.ingest_json(&topic, &body).await
+  } else {
+    broker.ingest_raw(&topic, body).await
+  };
+  return Ok(match outcome {
+    RawOutcome::Queued => reply(StatusCode::ACCEPTED, "OK"),
+    RawOutcome::Ignored => reply(StatusCode::ACCEPTED, "Ignored."),
+    RawOutcome::BadTopic => reply(StatusCode::NOT_FOUND, "No such topic."),
+    RawOutcome::BadPayload => reply(StatusCode::BAD_REQUEST, "Bad payload."),
+    RawOutcome::BadSignature => {
+      reply(StatusCode::UNAUTHORIZED, "Bad signature.")
+    },
+    RawOutcome::Dropped => reply(StatusCode::SERVICE_UNAVAILABLE, "Full."),
+  });
+}
+
+/// Listens for HTTP requests forever, on a socket of its own unless it's
+/// handed one.
+async fn serve(
+  broker: Arc<Broker>, addr: SocketAddr, socket: Option<TcpListener>
+) {
+  let make_svc = make_service_fn(move |_| {
+    let broker = broker.clone();
+    return async move {
+      Ok::<_, Infallible>(service_fn(move |req| handle(broker.clone(), req)))
+    };
+  });
+  let builder = match socket {
+    Some(s) => Server::from_tcp(s),
+    None => Server::try_bind(&addr),
+  };
+  let server = builder
+    .unwrap_or_else(|e| panic!("Can't bind HTTP to {}: {}", addr, e))
+    .serve(make_svc);
+  info!("Listening for HTTP on {}...", addr);
+  if let Err(e) = server.await {
+    warn!("HTTP listener died: {}", e);
+  }
+}
+
+/// HTTP ingestion, as a sensor source.
+pub(crate) struct HttpSource {
+  /// Where to listen.
+  pub(crate) addr: SocketAddr,
+  /// Already listening there, as systemd does with socket activation.
+  pub(crate) socket: Option<TcpListener>
+}
+
+impl SensorSource for HttpSource {
+  fn name(&self) -> String {
+    return format!("HTTP on {}", self.addr);
+  }
+
+  fn again(&self) -> Option<Box<dyn SensorSource>> {
+    // systemd keeps its socket bound, so we can't bind it ourselves.
+    return Some(Box::new(HttpSource {
+      addr: self.addr,
+      socket: self.socket.as_ref().and_then(|s| s.try_clone().ok())
+    }));
+  }
+
+  fn run(self: Box<Self>, broker: Arc<Broker>) -> BoxFuture<'static, ()> {
+    return serve(broker, self.addr, self.socket).boxed();
+  }
+}
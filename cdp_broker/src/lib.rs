@@ -0,0 +1,72 @@
+//! Main broker module. Entry points and such.
+
+#[macro_use]
+extern crate libcdp;
+
+use std::sync::Arc;
+
+use crate::broker::Broker;
+
+#[cfg(feature = "ble")]
+mod ble;
+mod broker;
+mod budget;
+mod capture;
+mod chaos;
+mod coap;
+mod config;
+mod daemon;
+mod deadletter;
+mod enroll;
+mod health;
+mod http_ingest;
+mod mqtt;
+mod remote;
+mod route;
+mod serial;
+mod source;
+mod spool;
+mod supervisor;
+mod tee;
+mod transform;
+mod update;
+mod uplink;
+mod zigbee;
+
+/// Runs a broker with the configuration in source, laid out like
+/// cdp_broker.toml and cdp_rumqttd.toml merged together, until it stops.
+/// For running it in the same process as other things, like cdp_all does.
+pub fn run(source: ::config::Config) {
+  let (broker_config, rumqttd_config) = config::load_from(source)
+    .unwrap_or_else(|e| panic!("Configuration tragedy: {:#?}", e));
+  let broker = Broker::from((broker_config, rumqttd_config));
+  futures::executor::block_on(Broker::start(Arc::new(broker)));
+}
+
+/// Broker entry point. Read arguments and config, and start.
+pub fn main() {
+  libcdp::runtime::init("cdp_broker");
+  let args = daemon::Args::parse(std::env::args().skip(1))
+    .unwrap_or_else(|e| {
+      eprintln!("{}\n\n{}", e, daemon::USAGE);
+      std::process::exit(2);
+    });
+  if args.help {
+    println!("{}", daemon::USAGE);
+    return;
+  }
+  info!("Hi! Loading configuration...");
+  let (mut broker_config, rumqttd_config) =
+    config::load_defaults(args.config.as_deref())
+    .unwrap_or_else(|e| panic!("Configuration tragedy: {:#?}", e));
+  args.apply(&mut broker_config);
+  daemon::detach(&broker_config)
+    .unwrap_or_else(|e| panic!("Couldn't detach: {}", e));
+  let _pid_file = broker_config.pid_file.as_ref().map(|p| {
+    daemon::PidFile::create(p)
+      .unwrap_or_else(|e| panic!("PID file tragedy: {}", e))
+  });
+  info!("Configuration loaded! Phew. Initializing broker...");
+  let broker = Broker::from((broker_config, rumqttd_config));
+  futures::executor::block_on(Broker::start(Arc::new(broker)));
+}
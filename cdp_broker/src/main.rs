@@ -1,17 +1,5 @@
-//! Main broker module. Entry point and such.
-
-use std::sync::Arc;
-
-use crate::broker::Broker;
-
-mod broker;
-mod config;
+//! Entry point for the broker.
 
 fn main() {
-  println!("Hi! Loading configuration...");
-  let (broker_config, rumqttd_config) = config::load_defaults()
-    .unwrap_or_else(|e| panic!("Configuration tragedy: {:#?}", e));
-  println!("Configuration loaded! Phew. Initializing broker...");
-  let broker = Broker::from((broker_config, rumqttd_config));
-  futures::executor::block_on(Broker::start(Arc::new(broker)));
+  cdp_broker::main();
 }
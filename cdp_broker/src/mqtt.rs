@@ -0,0 +1,248 @@
+//! MQTT, the way most sensors talk to us. Either an embedded rumqttd broker
+//! they publish to, with a local link subscribed to every topic we
+//! understand, or an external broker (say, a Mosquitto already running) we
+//! subscribe to as a client. Or both.
+//!
+//! The embedded broker comes with a console: a small HTTP server, listening
+//! where mqtt_console_bind (or else cdp_rumqttd.toml) says, with no
+//! authentication whatsoever. As of rumqttd 0.7, it answers GETs on:
+//!
+//! - /config: the whole rumqttd config, as JSON. Login credentials included,
+//!   so mind who can reach it.
+//! - /router: router metrics, as JSON. Total connections, topics and
+//!   subscriptions.
+//! - /connection/<client id>: metrics for one connected client.
+//!
+//! We ask /router for the connection count that goes in our heartbeats. The
+//! topic filters we subscribed to are counted on our side.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use libcdp::comm::decoders;
+use libcdp::comm::sensor_broker::DeviceHealthMessage;
+use librumqttd::async_locallink::{self, LinkRx, LinkTx};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+
+use crate::broker::{Broker, RawOutcome};
+use crate::config::ExternalMqttConfig;
+use crate::source::SensorSource;
+use crate::zigbee;
+
+/// How many messages the local link may have pending.
+const LINK_CAPACITY: usize = 200;
+/// How many requests an MQTT client may have pending.
+pub(crate) const CLIENT_CAPACITY: usize = 64;
+/// How long to wait before reconnecting to an external broker.
+pub(crate) const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long to wait on the console for metrics.
+const CONSOLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Hands a publish to the broker, through the zigbee2mqtt adapter if it
+/// comes from a device we translate.
+pub(crate) async fn dispatch(broker: &Broker, topic: &str, payload: Vec<u8>)
+-> RawOutcome {
+  return match zigbee::device_for(broker, topic) {
+    Some(dev) => zigbee::ingest(broker, dev, topic, &payload).await,
+    None => broker.ingest_raw(topic, payload).await,
+  };
+}
+
+/// Takes a publish in, recording it first if we're capturing.
+async fn on_publish(broker: &Broker, topic: &str, payload: Vec<u8>) {
+  broker.capture(topic, &payload);
+  dispatch(broker, topic, payload).await;
+}
+
+/// Client options for connecting to an external broker.
+pub(crate) fn options(cfg: &ExternalMqttConfig) -> MqttOptions {
+  let mut opts = MqttOptions::new(&cfg.client_id, &cfg.host, cfg.port);
+  opts.set_keep_alive(cfg.keep_alive_secs);
+  if let Some((username, password)) = &cfg.credentials {
+    opts.set_credentials(username, password);
+  }
+  return opts;
+}
+
+/// Every topic filter worth subscribing to.
+fn topics(broker: &Broker) -> Vec<String> {
+  let mut topics: Vec<String> = decoders::topics()
+    .into_iter()
+    .map(str::to_owned)
+    .collect();
+  topics.push(DeviceHealthMessage::TOPIC.to_owned());
+  topics.extend(broker.cfg.zigbee.iter().map(|dev| dev.topic.clone()));
+  return topics;
+}
+
+/// How many clients are connected to the embedded broker, as per its
+/// console. None if there's no console, or it didn't answer.
+pub(crate) async fn embedded_connections(broker: &Broker) -> Option<usize> {
+  let rc = broker.rumqttd_cfg.as_ref()?;
+  if !broker.cfg.mqtt_console {
+    return None;
+  }
+  let mut addr: SocketAddr = rc.console.listen;
+  if addr.ip().is_unspecified() {
+    addr.set_ip(Ipv4Addr::LOCALHOST.into());
+  }
+  let metrics: serde_json::Value = reqwest::Client::new()
+    .get(format!("http://{}/router", addr))
+    .timeout(CONSOLE_TIMEOUT)
+    .send()
+    .await
+    .ok()?
+    .json()
+    .await
+    .ok()?;
+  let total = metrics.get("total_connections")?.as_u64()? as usize;
+  // our local link counts as one.
+  return Some(total.saturating_sub(1));
+}
+
+/// The embedded rumqttd broker, as a sensor source.
+pub(crate) struct EmbeddedMqtt {
+  cfg: librumqttd::Config,
+  /// Whether to serve the console.
+  console: bool
+}
+
+impl EmbeddedMqtt {
+  /// Sets it up with a rumqttd config. Nothing starts until it's run.
+  pub(crate) fn new(cfg: librumqttd::Config, console: bool) -> Self {
+    return Self { cfg: cfg, console: console };
+  }
+
+  /// Reads everything the local link gets, forever. Holds on to the link's
+  /// sending half, since the link goes away with it.
+  async fn read_link(broker: Arc<Broker>, _tx: LinkTx, mut rx: LinkRx) {
+    loop {
+      let data = match rx.recv().await {
+        Ok(d) => d,
+        Err(e) => {
+          warn!("LinkError when recv'ing message: {}", e.to_string());
+          continue;
+        },
+      };
+      let mut pbytes: Vec<u8> = Vec::new();
+      for b in data.payload {
+        pbytes.extend(b);
+      }
+      on_publish(&broker, &data.topic, pbytes).await;
+    }
+  }
+}
+
+impl SensorSource for EmbeddedMqtt {
+  fn name(&self) -> String {
+    return "embedded MQTT".to_owned();
+  }
+
+  fn run(self: Box<Self>, broker: Arc<Broker>) -> BoxFuture<'static, ()> {
+    let serve_console = self.console;
+    let console_addr = self.cfg.console.listen;
+    let (mut router, console, servers, builder)
+      = async_locallink::construct_broker(self.cfg);
+    let broker2 = broker.clone();
+    thread::spawn(move || {
+      let _running = broker2.liveness.start("MQTT router");
+      router.start().unwrap();
+    });
+    // the servers get a runtime of their own, so they needn't be Send.
+    let broker3 = broker.clone();
+    thread::spawn(move || {
+      let _running = broker3.liveness.start("MQTT servers");
+      tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(servers);
+    });
+    return async move {
+      let (mut tx, rx) = builder
+        .connect("localclient", LINK_CAPACITY)
+        .await
+        .unwrap();
+      for topic in topics(&broker) {
+        tx.subscribe(std::iter::once(topic.clone())).await.unwrap();
+        broker.count_subscription(&topic);
+      }
+      if serve_console {
+        info!("MQTT console at http://{}/.", console_addr);
+        tokio::spawn(console);
+      }
+      Self::read_link(broker, tx, rx).await;
+    }.boxed();
+  }
+}
+
+/// An external MQTT broker, as a sensor source.
+pub(crate) struct ExternalMqtt {
+  cfg: ExternalMqttConfig
+}
+
+impl ExternalMqtt {
+  /// Sets it up. Nothing connects until it's run.
+  pub(crate) fn new(cfg: ExternalMqttConfig) -> Self {
+    return Self { cfg: cfg };
+  }
+
+  /// Subscribes to every topic worth it. Done on every connection, in case
+  /// the other broker forgot about us.
+  fn subscribe(broker: &Arc<Broker>, client: &AsyncClient) {
+    let client = client.clone();
+    let broker = broker.clone();
+    tokio::spawn(async move {
+      for topic in topics(&broker) {
+        match client.subscribe(topic.clone(), QoS::AtLeastOnce).await {
+          Ok(_) => broker.count_subscription(&topic),
+          Err(e) => {
+            warn!("Couldn't subscribe on the external broker: {}", e);
+          },
+        }
+      }
+    });
+  }
+
+  /// Connects, and reads everything that comes in, forever. The client
+  /// reconnects on its own after failures.
+  async fn read_client(self, broker: Arc<Broker>) {
+    let cfg = self.cfg;
+    let opts = options(&cfg);
+    let (client, mut eventloop) = AsyncClient::new(opts, CLIENT_CAPACITY);
+    loop {
+      match eventloop.poll().await {
+        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+          info!("Connected to MQTT at {}:{}.", cfg.host, cfg.port);
+          Self::subscribe(&broker, &client);
+        },
+        Ok(Event::Incoming(Packet::Publish(p))) => {
+          on_publish(&broker, &p.topic, p.payload.to_vec()).await;
+        },
+        Ok(_) => (),
+        Err(e) => {
+          warn!("MQTT at {}:{} failed: {}", cfg.host, cfg.port, e);
+          tokio::time::sleep(RECONNECT_DELAY).await;
+        },
+      }
+    }
+  }
+}
+
+impl SensorSource for ExternalMqtt {
+  fn name(&self) -> String {
+    return format!("MQTT client of {}:{}", self.cfg.host, self.cfg.port);
+  }
+
+  fn again(&self) -> Option<Box<dyn SensorSource>> {
+    return Some(Box::new(ExternalMqtt::new(self.cfg.clone())));
+  }
+
+  fn run(self: Box<Self>, broker: Arc<Broker>) -> BoxFuture<'static, ()> {
+    return self.read_client(broker).boxed();
+  }
+}
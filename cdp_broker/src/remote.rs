@@ -0,0 +1,82 @@
+//! Settings the API manages for us. It sends them along with heartbeat
+//! replies whenever the hash we report isn't that of what it wants, and we
+//! switch to them right away, keeping them in remote_config_file so they
+//! outlive a restart. Whatever they leave out goes as per the config file.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use libcdp::comm::broker_api::RemoteConfig;
+use libcdp::comm::sensor_broker::SensorType;
+
+use crate::config::BrokerConfig;
+
+/// The settings the API may change, as they are now.
+#[derive(Clone, Debug)]
+pub(crate) struct LiveSettings {
+  /// What the API sent last. Empty if it never did.
+  pub(crate) remote: RemoteConfig,
+  /// Sensor types taken in.
+  pub(crate) topics: Vec<SensorType>,
+  /// Bundle size for the default route.
+  pub(crate) bundle_size: usize,
+  /// Heartbeat interval. None means no auto heartbeat.
+  pub(crate) heartbeat_interval: Option<Duration>
+}
+
+impl LiveSettings {
+  /// The config file's settings, with the API's on top. Fails if the API
+  /// asks for something that makes no sense.
+  pub(crate) fn new(cfg: &BrokerConfig, remote: RemoteConfig)
+  -> Result<Self, String> {
+    let topics = match &remote.topics {
+      Some(names) => names.iter()
+        .map(|n| {
+          SensorType::from_str(n).map_err(|_| format!("Bad topic \"{}\".", n))
+        })
+        .collect::<Result<Vec<SensorType>, String>>()?,
+      None => cfg.topics.clone(),
+    };
+    if remote.bundle_size == Some(0) {
+      return Err("Bundles can't be empty.".to_owned());
+    }
+    if remote.heartbeat_interval_secs == Some(0) {
+      return Err("Heartbeats can't be that often.".to_owned());
+    }
+    return Ok(Self {
+      topics: topics,
+      bundle_size: remote.bundle_size.unwrap_or(cfg.bundle_size),
+      heartbeat_interval: remote.heartbeat_interval_secs
+        .map(Duration::from_secs)
+        .or(cfg.heartbeat_interval),
+      remote: remote
+    });
+  }
+}
+
+/// The settings kept in a file, if any. Bad ones are ignored, since the API
+/// will send them again.
+pub(crate) fn load(path: &Path) -> Option<RemoteConfig> {
+  let data = match fs::read(path) {
+    Ok(d) => d,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+    Err(e) => {
+      warn!("Couldn't read {}: {}", path.display(), e);
+      return None;
+    },
+  };
+  return serde_json::from_slice(&data)
+    .map_err(|e| warn!("Ignoring {}: {}", path.display(), e))
+    .ok();
+}
+
+/// Keeps settings in a file. The file is replaced whole, so a crash halfway
+/// through leaves the old settings.
+pub(crate) fn save(path: &Path, remote: &RemoteConfig) -> io::Result<()> {
+  let tmp = path.with_extension("tmp");
+  fs::write(&tmp, serde_json::to_vec_pretty(remote)?)?;
+  return fs::rename(&tmp, path);
+}
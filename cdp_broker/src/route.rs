@@ -0,0 +1,94 @@
+//! Routes, so different sensor types can go home different ways. Each has a
+//! bundle, sealed bundles and an uplink of its own: motion can go out one by
+//! one to a low-latency endpoint while temperature piles up into big bundles
+//! for a bulk one. Whatever no configured route takes goes the default way,
+//! as per the top-level settings.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::sync::atomic::AtomicU64;
+
+use libcdp::comm::broker_api::{BrokerMessageBundle, BrokerMessagePayload};
+use libcdp::comm::sealing::SealingKey;
+use libcdp::ids::BrokerId;
+use tokio::sync::{Mutex, MutexGuard, Semaphore};
+
+use crate::chaos::ChaosUplink;
+use crate::config::{ChaosConfig, HttpTimeouts, RouteConfig, WireFormat};
+use crate::uplink::{self, Uplink};
+
+/// A route, and the state of its bundler.
+#[derive(Debug)]
+pub(crate) struct Route {
+  /// Route config.
+  pub(crate) cfg: RouteConfig,
+  /// Where its bundles go.
+  pub(crate) uplink: Box<dyn Uplink>,
+  /// Message bundle within.
+  message_bundle: Mutex<BrokerMessageBundle>,
+  /// Sealed bundles waiting to be sent, by sequence number. Failed sends
+  /// come back here, so the oldest always goes first.
+  pending_bundles: StdMutex<BTreeMap<u64, BrokerMessageBundle>>,
+  /// Sequence number for the next sealed bundle.
+  pub(crate) next_seq: AtomicU64,
+  /// One permit per bundle allowed in flight.
+  pub(crate) send_slots: Arc<Semaphore>,
+}
+
+impl Route {
+  /// Sets up a route with nothing in it yet. Bundles going over HTTP are
+  /// sealed, if given the broker's UID and key. Its uplink misbehaves, if
+  /// given chaos.
+  pub(crate) fn new(
+    cfg: RouteConfig,
+    wire_format: WireFormat,
+    seal: Option<(BrokerId, SealingKey)>,
+    timeouts: HttpTimeouts,
+    chaos: Option<&ChaosConfig>
+  ) -> Self {
+    let slots = cfg.send_concurrency;
+    let mut uplink
+      = uplink::from_config(&cfg.uplink, wire_format, seal, timeouts);
+    if let Some(chaos) = chaos {
+      uplink = Box::new(ChaosUplink::wrap(uplink, chaos.clone()));
+    }
+    return Self {
+      cfg: cfg,
+      uplink: uplink,
+      message_bundle: Mutex::new(BrokerMessageBundle::new()),
+      pending_bundles: StdMutex::new(BTreeMap::new()),
+      next_seq: AtomicU64::new(0),
+      send_slots: Arc::new(Semaphore::new(slots)),
+    };
+  }
+
+  /// Whether a message goes this way.
+  pub(crate) fn takes(&self, payload: &BrokerMessagePayload) -> bool {
+    return match payload {
+      BrokerMessagePayload::SensorData(sd) => {
+        self.cfg.sensor_types.contains(&sd.sensor_type())
+      },
+      BrokerMessagePayload::DeviceHealth(_) => self.cfg.device_health,
+      BrokerMessagePayload::Heartbeat(_) => false,
+      BrokerMessagePayload::DecodeFailure { .. } => false,
+    };
+  }
+
+  /// Used to acquire a full-on lock on the message bundle.
+  pub(crate) async fn lock_bundle(&self)
+  -> MutexGuard<'_, BrokerMessageBundle> {
+    return self.message_bundle.lock().await;
+  }
+
+  /// Used to acquire a lock on the sealed bundles.
+  pub(crate) fn lock_pending(&self)
+  -> std::sync::MutexGuard<'_, BTreeMap<u64, BrokerMessageBundle>> {
+    return self.pending_bundles.lock().unwrap_or_else(|e| e.into_inner());
+  }
+
+  /// How many of its bundles are out right now.
+  pub(crate) fn in_flight(&self) -> usize {
+    return self.cfg.send_concurrency
+      .saturating_sub(self.send_slots.available_permits());
+  }
+}
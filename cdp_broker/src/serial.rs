@@ -0,0 +1,195 @@
+//! Sensors wired straight to the gateway, over a serial port. Each frame
+//! names a topic and carries a payload, which goes down the same decode
+//! path as MQTT data. Ports that go away are reopened every few seconds.
+//!
+//! Framings, as configured per port:
+//!  - "hex": text lines like "temperature 03000000b0010000", the payload
+//!    being the MQTT bytes in hex;
+//!  - "json": text lines like "temperature {"sensor_id": 3, ...}", the
+//!    payload being the message's JSON form;
+//!  - "slip": SLIP (RFC 1055) frames holding the topic, a zero byte, then
+//!    the MQTT bytes.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio_serial::SerialPortBuilderExt;
+
+use crate::broker::Broker;
+use crate::config::{SerialFraming, SerialPortConfig};
+use crate::source::SensorSource;
+
+/// How long to wait before trying to reopen a port.
+const REOPEN_DELAY: Duration = Duration::from_secs(5);
+/// Longest frame we'll take. Anything longer is line noise.
+const MAX_FRAME: usize = 1024;
+
+/// SLIP frame delimiter.
+const SLIP_END: u8 = 0xC0;
+/// SLIP escape.
+const SLIP_ESC: u8 = 0xDB;
+/// Escaped SLIP_END.
+const SLIP_ESC_END: u8 = 0xDC;
+/// Escaped SLIP_ESC.
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+/// Reads the next frame, up to the delimiter, into buf. Overlong frames are
+/// skipped whole. False once the port has nothing more to say.
+async fn next_frame<R: AsyncBufRead + Unpin>(
+  reader: &mut R, delim: u8, buf: &mut Vec<u8>
+) -> io::Result<bool> {
+  let mut overlong = false;
+  loop {
+    buf.clear();
+    let n = (&mut *reader)
+      .take(MAX_FRAME as u64)
+      .read_until(delim, buf)
+      .await?;
+    if n == 0 {
+      return Ok(false);
+    }
+    if buf.last() != Some(&delim) {
+      if n < MAX_FRAME {
+        // cut off by the end of the stream
+        return Ok(false);
+      }
+      overlong = true;
+      continue;
+    }
+    buf.pop();
+    if overlong {
+      warn!("Skipped an overlong serial frame.");
+      overlong = false;
+      continue;
+    }
+    return Ok(true);
+  }
+}
+
+/// Decodes a hex string, ignoring whitespace.
+fn unhex(s: &str) -> Option<Vec<u8>> {
+  let digits: Vec<u8> = s.bytes()
+    .filter(|b| !b.is_ascii_whitespace())
+    .collect();
+  if digits.len() % 2 != 0 {
+    return None;
+  }
+  return digits.chunks(2)
+    .map(|p| u8::from_str_radix(std::str::from_utf8(p).ok()?, 16).ok())
+    .collect();
+}
+
+/// Undoes SLIP escaping. None if there's a bad escape.
+fn unslip(frame: &[u8]) -> Option<Vec<u8>> {
+  let mut out: Vec<u8> = Vec::with_capacity(frame.len());
+  let mut bytes = frame.iter();
+  while let Some(&b) = bytes.next() {
+    out.push(match b {
+      SLIP_ESC => match bytes.next() {
+        Some(&SLIP_ESC_END) => SLIP_END,
+        Some(&SLIP_ESC_ESC) => SLIP_ESC,
+        _ => return None,
+      },
+      b => b,
+    });
+  }
+  return Some(out);
+}
+
+/// Splits a text line into topic and the rest.
+fn split_line(frame: &[u8]) -> Option<(&str, &str)> {
+  let line = std::str::from_utf8(frame).ok()?.trim();
+  let mut parts = line.splitn(2, ' ');
+  return Some((parts.next()?, parts.next().unwrap_or("").trim()));
+}
+
+/// Hands a frame to the broker, as per the framing.
+async fn handle(broker: &Broker, framing: SerialFraming, frame: &[u8]) {
+  match framing {
+    SerialFraming::Hex => match split_line(frame) {
+      Some((topic, hex)) => match unhex(hex) {
+        Some(pbytes) => { broker.ingest_raw(topic, pbytes).await; },
+        None => warn!("Bad hex in serial {} frame.", topic),
+      },
+      None => warn!("Bad serial line."),
+    },
+    SerialFraming::Json => match split_line(frame) {
+      Some((topic, json)) => {
+        broker.ingest_json(topic, json.as_bytes()).await;
+      },
+      None => warn!("Bad serial line."),
+    },
+    SerialFraming::Slip => {
+      let data = match unslip(frame) {
+        Some(d) => d,
+        None => return warn!("Bad escape in SLIP frame."),
+      };
+      let sep = match data.iter().position(|&b| b == 0) {
+        Some(i) => i,
+        None => return warn!("SLIP frame without a topic."),
+      };
+      match std::str::from_utf8(&data[..sep]) {
+        Ok(topic) => {
+          broker.ingest_raw(topic, data[sep + 1..].to_vec()).await;
+        },
+        Err(_) => warn!("SLIP frame with a bad topic."),
+      }
+    },
+  }
+}
+
+/// Reads frames off an open port until it goes away.
+async fn read_port(broker: &Broker, cfg: &SerialPortConfig)
+-> io::Result<()> {
+  let port = tokio_serial::new(&cfg.port, cfg.baud).open_native_async()?;
+  info!("Reading sensors on {}...", cfg.port);
+  let mut reader = BufReader::new(port);
+  let delim = match cfg.framing {
+    SerialFraming::Slip => SLIP_END,
+    _ => b'\n',
+  };
+  let mut buf: Vec<u8> = Vec::new();
+  while next_frame(&mut reader, delim, &mut buf).await? {
+    // SLIP senders may open frames with an END too, and lines may be blank
+    if buf.iter().all(|b| b.is_ascii_whitespace()) {
+      continue;
+    }
+    handle(broker, cfg.framing, &buf).await;
+  }
+  return Ok(());
+}
+
+/// Reads sensor data from a serial port forever.
+async fn serve(broker: Arc<Broker>, cfg: SerialPortConfig) {
+  loop {
+    match read_port(&broker, &cfg).await {
+      Ok(()) => warn!("Serial port {} closed.", cfg.port),
+      Err(e) => warn!("Serial port {} failed: {}", cfg.port, e),
+    }
+    tokio::time::sleep(REOPEN_DELAY).await;
+  }
+}
+
+/// A serial port, as a sensor source.
+pub(crate) struct SerialSource {
+  /// Which port, and how to read it.
+  pub(crate) cfg: SerialPortConfig
+}
+
+impl SensorSource for SerialSource {
+  fn name(&self) -> String {
+    return format!("serial port {}", self.cfg.port);
+  }
+
+  fn again(&self) -> Option<Box<dyn SensorSource>> {
+    return Some(Box::new(SerialSource { cfg: self.cfg.clone() }));
+  }
+
+  fn run(self: Box<Self>, broker: Arc<Broker>) -> BoxFuture<'static, ()> {
+    return serve(broker, self.cfg).boxed();
+  }
+}
@@ -0,0 +1,100 @@
+//! Where sensor data comes from. Every way in is a SensorSource: each is
+//! enabled in the config on its own, started next to the others, and hands
+//! whatever it reads to the same pipeline, the broker's ingest_* methods,
+//! which decode it into sensor messages and queue them for sending home.
+
+#[cfg(not(feature = "systemd"))]
+use std::net::{TcpListener, UdpSocket};
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+#[cfg(feature = "systemd")]
+use libcdp::systemd::{self, ListenFd};
+
+#[cfg(feature = "ble")]
+use crate::ble::BleSource;
+use crate::broker::Broker;
+use crate::capture::ReplaySource;
+use crate::coap::CoapSource;
+use crate::http_ingest::HttpSource;
+use crate::mqtt::{EmbeddedMqtt, ExternalMqtt};
+use crate::serial::SerialSource;
+
+/// A way for sensor data to come in.
+pub(crate) trait SensorSource: Send {
+  /// What to call it in logs.
+  fn name(&self) -> String;
+
+  /// Feeds the broker until there's nothing more to read, which for most
+  /// sources is never.
+  fn run(self: Box<Self>, broker: Arc<Broker>) -> BoxFuture<'static, ()>;
+
+  /// Another one just like it, to start over with if this one panics. None
+  /// means it can't be started over.
+  fn again(&self) -> Option<Box<dyn SensorSource>> {
+    return None;
+  }
+}
+
+/// Every source the config enables. Or just the replay, if we're playing a
+/// capture back: nothing else gets in its way.
+pub(crate) fn from_config(broker: &Broker) -> Vec<Box<dyn SensorSource>> {
+  let cfg = &broker.cfg;
+  if let Some(replay) = &cfg.replay {
+    return vec![Box::new(ReplaySource { cfg: replay.clone() })];
+  }
+  let mut sources: Vec<Box<dyn SensorSource>> = Vec::new();
+  if let Some(rc) = &broker.rumqttd_cfg {
+    sources.push(Box::new(EmbeddedMqtt::new(rc.clone(), cfg.mqtt_console)));
+  }
+  if let Some(ext) = &cfg.external_mqtt {
+    sources.push(Box::new(ExternalMqtt::new(ext.clone())));
+  }
+  // sockets systemd opened for us, named coap or http, win over binds.
+  #[cfg(feature = "systemd")]
+  let (coap_socket, http_socket) = {
+    let mut fds = systemd::listen_fds();
+    let coap = take_activated(&mut fds, "coap").map(ListenFd::into_udp);
+    let http = take_activated(&mut fds, "http").map(ListenFd::into_tcp);
+    for fd in fds {
+      warn!("Got a socket from systemd we've no use for: {:?}", fd.name);
+    }
+    (coap, http)
+  };
+  #[cfg(not(feature = "systemd"))]
+  let (coap_socket, http_socket): (Option<UdpSocket>, Option<TcpListener>) =
+    (None, None);
+  let coap_addr = match &coap_socket {
+    Some(s) => Some(s.local_addr()
+      .unwrap_or_else(|e| panic!("Bad CoAP socket from systemd: {}", e))),
+    None => cfg.coap_bind,
+  };
+  if let Some(addr) = coap_addr {
+    sources.push(Box::new(CoapSource { addr: addr, socket: coap_socket }));
+  }
+  let http_addr = match &http_socket {
+    Some(s) => Some(s.local_addr()
+      .unwrap_or_else(|e| panic!("Bad HTTP socket from systemd: {}", e))),
+    None => cfg.http_bind,
+  };
+  if let Some(addr) = http_addr {
+    sources.push(Box::new(HttpSource { addr: addr, socket: http_socket }));
+  }
+  for port in &cfg.serial {
+    sources.push(Box::new(SerialSource { cfg: port.clone() }));
+  }
+  if !cfg.ble.is_empty() {
+    #[cfg(feature = "ble")]
+    sources.push(Box::new(BleSource));
+    #[cfg(not(feature = "ble"))]
+    warn!("BLE sensors configured, but built without BLE support.");
+  }
+  return sources;
+}
+
+/// Takes the socket systemd opened for us under a name, if it did.
+#[cfg(feature = "systemd")]
+fn take_activated(fds: &mut Vec<ListenFd>, name: &str) -> Option<ListenFd> {
+  let idx = fds.iter().position(|fd| fd.name.as_deref() == Some(name))?;
+  return Some(fds.remove(idx));
+}
@@ -0,0 +1,181 @@
+//! A dead-simple disk spool, for messages that don't fit the queue. One JSON
+//! message per line, oldest first.
+//!
+//! Messages are taken from a read offset, kept in a file named after the
+//! spool plus ".offset", rather than by rewriting the whole thing every
+//! time. The file is emptied once everything's been read, and compacted once
+//! more than half of it has, so that's only copied every so often.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use libcdp::comm::broker_api::BrokerMessage;
+
+/// Don't bother compacting until this many bytes have been read.
+const COMPACT_AFTER: u64 = 1024 * 1024;
+
+/// A file next to the spool, named after it plus a suffix.
+fn beside(path: &Path, suffix: &str) -> PathBuf {
+  let mut name = path.as_os_str().to_owned();
+  name.push(suffix);
+  return PathBuf::from(name);
+}
+
+/// Where reading is at, behind the lock.
+#[derive(Debug)]
+struct Cursor {
+  /// How many messages are left to read.
+  len: usize,
+  /// Where the first of them starts, in bytes.
+  offset: u64
+}
+
+/// The spool. Thread-safe.
+#[derive(Debug)]
+pub(crate) struct Spool {
+  /// Where it lives.
+  path: PathBuf,
+  /// Where the read offset is kept.
+  offset_path: PathBuf,
+  /// Where reading is at. Doubles as the file lock.
+  cursor: Mutex<Cursor>
+}
+
+impl Spool {
+  /// Opens a spool, counting whatever a previous run left behind.
+  pub(crate) fn open(path: PathBuf) -> io::Result<Self> {
+    let offset_path = beside(&path, ".offset");
+    let mut offset = fs::read_to_string(&offset_path).ok()
+      .and_then(|s| s.trim().parse::<u64>().ok())
+      .unwrap_or(0);
+    let len = match File::open(&path) {
+      Ok(mut f) => {
+        // a stale offset means the spool went missing or was replaced
+        if offset > f.metadata()?.len() {
+          offset = 0;
+        }
+        f.seek(SeekFrom::Start(offset))?;
+        BufReader::new(f).lines().count()
+      },
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        offset = 0;
+        0
+      },
+      Err(e) => return Err(e),
+    };
+    return Ok(Self {
+      path: path,
+      offset_path: offset_path,
+      cursor: Mutex::new(Cursor {
+        len: len,
+        offset: offset
+      })
+    });
+  }
+
+  /// Used to acquire a lock on the cursor.
+  fn lock(&self) -> std::sync::MutexGuard<'_, Cursor> {
+    return self.cursor.lock().unwrap_or_else(|e| e.into_inner());
+  }
+
+  /// How many messages are waiting in the spool.
+  pub(crate) fn len(&self) -> usize {
+    return self.lock().len;
+  }
+
+  /// Appends a message to the spool.
+  pub(crate) fn push(&self, msg: &BrokerMessage) -> io::Result<()> {
+    let mut cursor = self.lock();
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)?;
+    let mut line = serde_json::to_string(msg)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    cursor.len += 1;
+    return Ok(());
+  }
+
+  /// Hands up to max messages out of the spool to send, oldest first, until
+  /// it gives one back for not having room. That one and all after it stay
+  /// in the spool, at the front. Lines that don't parse are dropped. Returns
+  /// how many were sent, and how many dropped.
+  pub(crate) fn drain<F>(&self, max: usize, mut send: F)
+  -> io::Result<(usize, usize)>
+  where F: FnMut(BrokerMessage) -> Option<BrokerMessage> {
+    let mut cursor = self.lock();
+    if cursor.len == 0 || max == 0 {
+      return Ok((0, 0));
+    }
+    let mut file = File::open(&self.path)?;
+    file.seek(SeekFrom::Start(cursor.offset))?;
+    let mut reader = BufReader::new(file);
+    let (mut sent, mut bad) = (0, 0);
+    let mut line = String::new();
+    while sent + bad < max {
+      line.clear();
+      let n = reader.read_line(&mut line)?;
+      if n == 0 {
+        break;
+      }
+      match serde_json::from_str(line.trim_end()) {
+        Ok(msg) => match send(msg) {
+          Some(_) => break,
+          None => sent += 1,
+        },
+        Err(_) => bad += 1,
+      };
+      cursor.offset += n as u64;
+      cursor.len = cursor.len.saturating_sub(1);
+    }
+    if sent + bad > 0 {
+      self.settle(&mut cursor, reader.into_inner())?;
+    }
+    return Ok((sent, bad));
+  }
+
+  /// Takes up to max messages out of the spool, oldest first. Lines that
+  /// don't parse are dropped, and counted in the second return value.
+  pub(crate) fn take(&self, max: usize)
+  -> io::Result<(Vec<BrokerMessage>, usize)> {
+    let mut taken = Vec::new();
+    let (_, bad) = self.drain(max, |msg| {
+      taken.push(msg);
+      return None;
+    })?;
+    return Ok((taken, bad));
+  }
+
+  /// Saves the read offset after reading, emptying or compacting the spool
+  /// if it's time. The offset is saved first, so a crash in between means
+  /// messages are sent twice, rather than lost.
+  fn settle(&self, cursor: &mut Cursor, mut file: File) -> io::Result<()> {
+    let size = file.metadata()?.len();
+    if cursor.len == 0 || cursor.offset >= size {
+      self.save_offset(0)?;
+      File::create(&self.path)?;
+      cursor.len = 0;
+      cursor.offset = 0;
+    } else if cursor.offset >= COMPACT_AFTER && cursor.offset * 2 >= size {
+      let tmp = beside(&self.path, ".tmp");
+      file.seek(SeekFrom::Start(cursor.offset))?;
+      let mut out = File::create(&tmp)?;
+      io::copy(&mut file, &mut out)?;
+      out.sync_all()?;
+      self.save_offset(0)?;
+      fs::rename(&tmp, &self.path)?;
+      cursor.offset = 0;
+    } else {
+      self.save_offset(cursor.offset)?;
+    }
+    return Ok(());
+  }
+
+  /// Writes down the read offset.
+  fn save_offset(&self, offset: u64) -> io::Result<()> {
+    return fs::write(&self.offset_path, offset.to_string());
+  }
+}
@@ -0,0 +1,81 @@
+//! Keeping the broker's tasks going. A task that panics, like one that hit
+//! an unwrap it shouldn't have, is logged, counted for the next heartbeat
+//! and /healthz, and started over after a while: a second at first, twice
+//! that after every panic in a row, up to a minute. A task that ran fine
+//! for a while before panicking starts over from a second again.
+//!
+//! Tasks that end on their own are left be, and so are those that can't be
+//! started over, like the embedded MQTT broker, whose threads hold on to
+//! its ports.
+
+use std::any::Any;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use tokio::task::JoinHandle;
+
+use crate::broker::Broker;
+
+/// How long to wait before starting a task over after its first panic.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait before starting a task over.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long a task must run to be forgiven its earlier panics.
+const STABLE: Duration = Duration::from_secs(300);
+
+/// What a panic said, if it said it with a string.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+  if let Some(s) = payload.downcast_ref::<&str>() {
+    return s.to_string();
+  }
+  return match payload.downcast::<String>() {
+    Ok(s) => *s,
+    Err(_) => "no message".to_owned(),
+  };
+}
+
+/// How long to wait after so many panics in a row.
+fn backoff(panics: u32) -> Duration {
+  let factor = 2u32.saturating_pow(panics.saturating_sub(1));
+  return FIRST_BACKOFF.checked_mul(factor)
+    .unwrap_or(MAX_BACKOFF)
+    .min(MAX_BACKOFF);
+}
+
+/// Runs a task, made by make, starting it over whenever it panics, for as
+/// long as make makes another. Returns once it ends on its own.
+async fn supervise<F>(broker: Arc<Broker>, name: String, mut make: F)
+where F: FnMut() -> Option<BoxFuture<'static, ()>> + Send + 'static {
+  let mut panics = 0;
+  while let Some(task) = make() {
+    let started = Instant::now();
+    let err = match tokio::spawn(task).await {
+      Ok(()) => return,
+      Err(e) if e.is_panic() => e,
+      Err(_) => return,
+    };
+    if started.elapsed() >= STABLE {
+      panics = 0;
+    }
+    panics += 1;
+    broker.count_restart(&name);
+    let wait = backoff(panics);
+    warn!(
+      "The {} task panicked: {}. Starting it over in {:?}.",
+      name, panic_message(err.into_panic()), wait
+    );
+    tokio::time::sleep(wait).await;
+  }
+  warn!("The {} task can't be started over, and stays down.", name);
+}
+
+/// Spawns a task that's started over whenever it panics, for as long as
+/// make makes another.
+pub(crate) fn spawn<F>(broker: &Arc<Broker>, name: &str, make: F)
+-> JoinHandle<()>
+where F: FnMut() -> Option<BoxFuture<'static, ()>> + Send + 'static {
+  return tokio::spawn(supervise(broker.clone(), name.to_owned(), make));
+}
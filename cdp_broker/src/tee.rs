@@ -0,0 +1,249 @@
+//! Sending everything to several APIs at once, like a local one and a remote
+//! one while moving from one to the other. The main endpoint is called as
+//! ever: its answers are the ones that count, and bundles it doesn't take
+//! are sent again by the route. Every mirror gets a copy of each bundle on
+//! the side, with a key of its own, and keeps the ones it couldn't take to
+//! try again later, so one being down holds nothing up. Sending a bundle
+//! twice does no harm, since APIs leave out messages they already have.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use cdp_client::{ApiClient, BundleFormat, ClientOptions};
+use futures::future::BoxFuture;
+use libcdp::comm::broker_api::{BrokerMessageBundle, BundleAck, HeartbeatMessage, HeartbeatReply};
+
+use crate::config::{HttpTimeouts, MirrorConfig, WireFormat};
+use crate::uplink::{HttpUplink, Uplink, UplinkError};
+
+/// An API that gets copies.
+#[derive(Debug)]
+struct Mirror {
+  /// Knows the way, and whether to seal.
+  client: ApiClient,
+  /// How bundles are encoded.
+  format: BundleFormat,
+  /// Key to call in with. Starts as configured, and changes when the
+  /// mirror rotates it. None means the one the main endpoint gets.
+  key: Mutex<Option<String>>,
+  /// Where keys it rotates in are saved.
+  key_file: Option<PathBuf>,
+  /// The key the main endpoint got with the latest bundle.
+  main_key: Mutex<Option<String>>,
+  /// Bundles it has yet to take, oldest first, numbered.
+  backlog: Mutex<VecDeque<(u64, BrokerMessageBundle)>>,
+  /// Number for the next bundle kept.
+  next: AtomicU64,
+  /// Most bundles in the backlog.
+  capacity: usize,
+  /// Held while the backlog is being sent, so no bundle goes twice at once.
+  sending: tokio::sync::Mutex<()>
+}
+
+impl Mirror {
+  /// Locks the backlog.
+  fn backlog(&self)
+  -> std::sync::MutexGuard<'_, VecDeque<(u64, BrokerMessageBundle)>> {
+    return self.backlog.lock().unwrap_or_else(|e| e.into_inner());
+  }
+
+  /// Our own key for it, if it has one.
+  fn key(&self) -> Option<String> {
+    return self.key.lock().unwrap_or_else(|e| e.into_inner()).clone();
+  }
+
+  /// Switches to a key the mirror handed us, and saves it to its key file
+  /// the way the broker saves its own, so it lasts past a restart.
+  fn keep_key(&self, key: String) {
+    *self.key.lock().unwrap_or_else(|e| e.into_inner()) = Some(key.clone());
+    let path = match &self.key_file {
+      Some(path) => path,
+      None => {
+        warn!(
+          "No key_file set for the mirror at {}, the new key will be lost on \
+            restart!", self.client.base()
+        );
+        return;
+      },
+    };
+    let tmp = path.with_extension("tmp");
+    let res = std::fs::write(&tmp, key + "\n")
+      .and_then(|_| std::fs::rename(&tmp, path));
+    if let Err(e) = res {
+      warn!("Couldn't save the new key to {}: {}", path.display(), e);
+    }
+  }
+
+  /// Adds a bundle to the backlog, dropping the oldest to make room.
+  fn keep(&self, bnd: BrokerMessageBundle) {
+    let n = self.next.fetch_add(1, Ordering::Relaxed);
+    let mut backlog = self.backlog();
+    while backlog.len() >= self.capacity.max(1) {
+      if let Some((_, lost)) = backlog.pop_front() {
+        warn!(
+          "Dropped {} message(s) for the mirror at {}, for lack of room.",
+          lost.len(), self.client.base()
+        );
+      }
+    }
+    backlog.push_back((n, bnd));
+  }
+
+  /// Sends the backlog, oldest first, until it's empty or a bundle doesn't
+  /// go through. Bundles the mirror turns down for good are dropped. Does
+  /// nothing if the backlog is being sent already.
+  async fn flush(&self) {
+    let _sending = match self.sending.try_lock() {
+      Ok(s) => s,
+      Err(_) => return,
+    };
+    loop {
+      let (n, bnd) = match self.backlog().front().cloned() {
+        Some(next) => next,
+        None => return,
+      };
+      let key = self.key().or_else(|| {
+        return self.main_key.lock().unwrap_or_else(|e| e.into_inner()).clone();
+      });
+      let res = self.client.push_bundle(&bnd, self.format, key.as_deref())
+        .await;
+      if let Err(e) = &res {
+        if e.is_transient() {
+          warn!("The mirror at {} is down: {}", self.client.base(), e);
+          return;
+        }
+        warn!(
+          "The mirror at {} turned {} message(s) down for good: {}",
+          self.client.base(), bnd.len(), e
+        );
+      }
+      if let Ok(Some(ack)) = res {
+        info!(
+          "The mirror at {} stored {} of {} message(s).",
+          self.client.base(), ack.accepted, bnd.len()
+        );
+      }
+      // unless it was dropped to make room while we were at it
+      let mut backlog = self.backlog();
+      if backlog.front().map(|(m, _)| *m) == Some(n) {
+        backlog.pop_front();
+      }
+    }
+  }
+
+  /// Sends a heartbeat, with our key for the mirror if it has one of its
+  /// own. Keys it rotates in are kept in its key file.
+  async fn heartbeat(&self, mut hb: HeartbeatMessage) {
+    let key = self.key();
+    if key.is_some() {
+      hb.key = key;
+    }
+    match self.client.heartbeat(&hb).await {
+      Ok(reply) => {
+        if let Some(key) = reply.new_key {
+          warn!("The mirror at {} rotated our key.", self.client.base());
+          self.keep_key(key);
+        }
+      },
+      Err(e) => {
+        warn!(
+          "The mirror at {} missed a heartbeat: {}", self.client.base(), e
+        );
+      },
+    };
+  }
+}
+
+/// The main endpoint, and copies of everything to the mirrors.
+#[derive(Debug)]
+pub(crate) struct TeeUplink {
+  /// Whose answers count.
+  main: HttpUplink,
+  /// Who gets copies.
+  mirrors: Vec<Arc<Mirror>>,
+  /// How often mirrors with a backlog are tried again.
+  retry_every: Duration
+}
+
+impl TeeUplink {
+  /// Sets up the mirrors, keeping up to backlog bundles for each while
+  /// it's down, and trying again every so often.
+  pub(crate) fn new(
+    main: HttpUplink,
+    mirrors: &[MirrorConfig],
+    backlog: usize,
+    retry_every: Duration,
+    wire_format: WireFormat,
+    timeouts: HttpTimeouts
+  ) -> Self {
+    let mirrors = mirrors.iter()
+      .map(|m| Arc::new(Mirror {
+        client: ApiClient::new(m.url.clone(), ClientOptions {
+          seal: m.seal.clone(),
+          ..timeouts.client_options()
+        }),
+        format: wire_format.into(),
+        key: Mutex::new(m.key_file.as_ref()
+          .and_then(|path| std::fs::read_to_string(path).ok())
+          .map(|key| key.trim().to_owned())
+          .filter(|key| !key.is_empty())
+          .or_else(|| m.home_key.clone())),
+        key_file: m.key_file.clone(),
+        main_key: Mutex::new(None),
+        backlog: Mutex::new(VecDeque::new()),
+        next: AtomicU64::new(0),
+        capacity: backlog,
+        sending: tokio::sync::Mutex::new(())
+      }))
+      .collect();
+    return Self {
+      main: main,
+      mirrors: mirrors,
+      retry_every: retry_every
+    };
+  }
+}
+
+impl Uplink for TeeUplink {
+  /// Starts the main endpoint's checks, and tries the mirrors' backlogs
+  /// again every so often.
+  fn start(&self) {
+    self.main.start();
+    let mirrors = self.mirrors.clone();
+    let every = self.retry_every;
+    tokio::spawn(async move {
+      let mut ticks = tokio::time::interval(every);
+      loop {
+        ticks.tick().await;
+        for mirror in mirrors.iter() {
+          mirror.flush().await;
+        }
+      }
+    });
+  }
+
+  fn send_bundle<'a>(
+    &'a self, bnd: &'a BrokerMessageBundle, key: Option<&'a str>
+  ) -> BoxFuture<'a, Result<Option<BundleAck>, UplinkError>> {
+    for mirror in self.mirrors.iter() {
+      *mirror.main_key.lock().unwrap_or_else(|e| e.into_inner())
+        = key.map(str::to_owned);
+      mirror.keep(bnd.clone());
+      let mirror = mirror.clone();
+      tokio::spawn(async move { mirror.flush().await });
+    }
+    return self.main.send_bundle(bnd, key);
+  }
+
+  fn heartbeat<'a>(&'a self, hb: &'a HeartbeatMessage)
+  -> BoxFuture<'a, Result<HeartbeatReply, UplinkError>> {
+    for mirror in self.mirrors.iter() {
+      let (mirror, hb) = (mirror.clone(), hb.clone());
+      tokio::spawn(async move { mirror.heartbeat(hb).await });
+    }
+    return self.main.heartbeat(hb);
+  }
+}
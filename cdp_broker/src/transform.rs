@@ -0,0 +1,95 @@
+//! The transforms stage, between decoding and bundling. Edge-side policy,
+//! like dropping a noisy sensor or noting which room another is in, goes in
+//! the config as an ordered list of transforms, each applied to whatever the
+//! ones before it let through.
+
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+use libcdp::comm::sensor_broker::AnySensorMessage;
+use libcdp::ids::SensorId;
+
+use crate::config::{MessageMatcher, Transform};
+
+impl MessageMatcher {
+  /// Whether a message is one to touch. Heartbeats and decode failures
+  /// never are.
+  fn matches(&self, payload: &BrokerMessagePayload) -> bool {
+    let (sensor_id, stype) = match payload {
+      BrokerMessagePayload::SensorData(sd) => {
+        (sd.sensor_id(), Some(sd.sensor_type()))
+      },
+      BrokerMessagePayload::DeviceHealth(dh) => (SensorId(dh.sensor_id), None),
+      BrokerMessagePayload::Heartbeat(_) => return false,
+      BrokerMessagePayload::DecodeFailure { .. } => return false,
+    };
+    if let Some(ids) = &self.sensor_ids {
+      if !ids.contains(&sensor_id) {
+        return false;
+      }
+    }
+    return match self.sensor_type {
+      Some(wanted) => stype == Some(wanted),
+      None => true,
+    };
+  }
+}
+
+/// Gives a message another sensor ID.
+fn relabel(payload: &mut BrokerMessagePayload, to: SensorId) {
+  let to = u8::from(to);
+  match payload {
+    BrokerMessagePayload::SensorData(AnySensorMessage::Temperature(tm)) => {
+      tm.sensor_id = to;
+    },
+    BrokerMessagePayload::SensorData(AnySensorMessage::Humidity(hm)) => {
+      hm.sensor_id = to;
+    },
+    BrokerMessagePayload::DeviceHealth(dh) => dh.sensor_id = to,
+    BrokerMessagePayload::Heartbeat(_) => (),
+    BrokerMessagePayload::DecodeFailure { .. } => (),
+  }
+}
+
+/// Pulls a reading into a range.
+fn clip(
+  payload: &mut BrokerMessagePayload, min: Option<f64>, max: Option<f64>
+) {
+  let sd = match payload {
+    BrokerMessagePayload::SensorData(sd) => sd,
+    _ => return,
+  };
+  let value = sd.reading().human_value();
+  let clipped = value
+    .max(min.unwrap_or(f64::NEG_INFINITY))
+    .min(max.unwrap_or(f64::INFINITY));
+  if clipped == value {
+    return;
+  }
+  let rebuilt = AnySensorMessage::from_human_value(
+    sd.sensor_type(), sd.sensor_id(), clipped
+  );
+  if let Some(msg) = rebuilt {
+    *sd = msg;
+  }
+}
+
+/// Runs a message through every transform. None if one dropped it.
+pub(crate) fn apply(transforms: &[Transform], mut msg: BrokerMessage)
+-> Option<BrokerMessage> {
+  for t in transforms {
+    match t {
+      Transform::Drop(m) => if m.matches(&msg.payload) {
+        return None;
+      },
+      Transform::Relabel(m, to) => if m.matches(&msg.payload) {
+        relabel(&mut msg.payload, *to);
+      },
+      Transform::Clip(m, min, max) => if m.matches(&msg.payload) {
+        clip(&mut msg.payload, *min, *max);
+      },
+      Transform::Tag(m, tags) => if m.matches(&msg.payload) {
+        msg.tags.extend(tags.iter().map(|(k, v)| (k.clone(), v.clone())));
+      },
+    }
+  }
+  return Some(msg);
+}
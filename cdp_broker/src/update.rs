@@ -0,0 +1,117 @@
+//! Self-updates, as ordered by the API with heartbeat replies. Artifacts are
+//! downloaded to update_dir, checked against the order's SHA-256, and handed
+//! to update_hook, along with their version, to swap in. The hook should
+//! leave restarting to something else, like `systemctl restart --no-block`,
+//! so it can tell us how it went first.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use libcdp::comm::update::{self, UpdateOrder, UpdateReport, UpdateState};
+use tokio::process::Command;
+use tokio::sync::mpsc::Receiver;
+
+use crate::broker::Broker;
+use crate::config::BrokerConfig;
+
+/// Most of what the hook says that's kept for the report, in bytes.
+const MAX_MESSAGE_BYTES: usize = 200;
+
+/// Where an artifact goes, named after its version, with anything odd in
+/// the version replaced so it can't point elsewhere.
+fn artifact_path(dir: &Path, version: &str) -> PathBuf {
+  let safe: String = version.chars()
+    .map(|c| match c {
+      'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+      _ => '_',
+    })
+    .collect();
+  return dir.join(format!("cdp_broker-{}", safe));
+}
+
+/// The last few lines of something a program said, within reason.
+fn tail(out: &[u8]) -> Option<String> {
+  let text = String::from_utf8_lossy(out);
+  let text = text.trim();
+  if text.is_empty() {
+    return None;
+  }
+  let mut start = text.len().saturating_sub(MAX_MESSAGE_BYTES);
+  while !text.is_char_boundary(start) {
+    start += 1;
+  }
+  return Some(text[start..].to_owned());
+}
+
+/// Downloads, checks and installs an update. Returns what the hook said.
+async fn install(cfg: &BrokerConfig, order: &UpdateOrder)
+-> Result<Option<String>, String> {
+  let hook = cfg.update_hook.as_ref()
+    .ok_or_else(|| "No update_hook set.".to_owned())?;
+  // no overall timeout, as big downloads on slow links take a while
+  let resp = reqwest::Client::builder()
+    .connect_timeout(cfg.http_timeouts.connect)
+    .build()
+    .map_err(|e| format!("Couldn't set up a download: {}", e))?
+    .get(order.url.as_str())
+    .send()
+    .await
+    .map_err(|e| format!("Couldn't download: {}", e))?;
+  if !resp.status().is_success() {
+    return Err(format!("Couldn't download: {}", resp.status()));
+  }
+  let data = resp.bytes()
+    .await
+    .map_err(|e| format!("Couldn't download: {}", e))?;
+  if !update::checksum_matches(&data, &order.sha256) {
+    return Err("Checksum mismatch.".to_owned());
+  }
+  let path = artifact_path(&cfg.update_dir, &order.version);
+  let save_err = |e: std::io::Error| {
+    format!("Couldn't save {}: {}", path.display(), e)
+  };
+  tokio::fs::create_dir_all(&cfg.update_dir).await.map_err(save_err)?;
+  tokio::fs::write(&path, &data).await.map_err(save_err)?;
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    let perms = std::fs::Permissions::from_mode(0o755);
+    tokio::fs::set_permissions(&path, perms)
+      .await
+      .map_err(|e| format!("Couldn't make it executable: {}", e))?;
+  }
+  let out = Command::new(hook)
+    .arg(&path)
+    .arg(&order.version)
+    .output()
+    .await
+    .map_err(|e| format!("Couldn't run {}: {}", hook.display(), e))?;
+  if !out.status.success() {
+    return Err(tail(&out.stderr).unwrap_or_else(|| out.status.to_string()));
+  }
+  return Ok(tail(&out.stdout));
+}
+
+/// Installs updates as they're ordered, one at a time, telling the API how
+/// each went as soon as it's over.
+pub(crate) async fn run(
+  broker: Arc<Broker>, orders: &mut Receiver<UpdateOrder>
+) {
+  while let Some(order) = orders.recv().await {
+    info!("Updating to {} from {}...", order.version, order.url);
+    let (state, message) = match install(&broker.cfg, &order).await {
+      Ok(said) => (UpdateState::Installed, said),
+      Err(e) => (UpdateState::Failed, Some(e)),
+    };
+    match &message {
+      Some(m) => info!("Update to {}: {:?}, {}", order.version, state, m),
+      None => info!("Update to {}: {:?}", order.version, state),
+    };
+    broker.report_update(UpdateReport {
+      id: order.id,
+      state: state,
+      message: message
+    });
+    broker.heartbeat().await;
+  }
+}
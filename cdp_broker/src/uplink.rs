@@ -0,0 +1,367 @@
+//! Where bundles and heartbeats go. Home, usually: the API, over HTTP, or
+//! the first of several that's up, say a home one and a cloud one. But
+//! they may also be published to an upstream MQTT broker, or appended to
+//! local NDJSON files when there's no home to phone. Picked in the config.
+
+use std::error::Error;
+use std::fmt::{Debug, Display};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use cdp_client::{ApiClient, BundleFormat, ClientError, ClientOptions};
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use libcdp::comm::broker_api::{BrokerMessageBundle, BundleAck, HeartbeatMessage, HeartbeatReply};
+use libcdp::comm::sealing::SealingKey;
+use libcdp::ids::BrokerId;
+use rumqttc::{AsyncClient, EventLoop, QoS};
+use tokio::io::AsyncWriteExt;
+
+use crate::config::{Endpoints, HttpTimeouts, UplinkConfig, WireFormat};
+use crate::tee::TeeUplink;
+use crate::mqtt;
+
+/// Why something didn't make it.
+#[derive(Debug)]
+pub(crate) enum UplinkError {
+  /// Couldn't get it there.
+  Transport(String),
+  /// Got there, but was turned away.
+  Rejected(String),
+  /// Took too long, to connect or to be answered. It may have gotten there.
+  Timeout(String)
+}
+
+impl Error for UplinkError {}
+
+impl Display for UplinkError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      UplinkError::Transport(e) => write!(f, "Couldn't deliver: {}", e),
+      UplinkError::Rejected(e) => write!(f, "Delivery refused: {}", e),
+      UplinkError::Timeout(e) => write!(f, "Delivery timed out: {}", e),
+    };
+  }
+}
+
+/// A way for bundles and heartbeats to leave the broker.
+pub(crate) trait Uplink: Send + Sync + Debug {
+  /// Starts whatever it needs running in the background. Called once, from
+  /// within the runtime, before anything is sent.
+  fn start(&self) {}
+
+  /// Delivers a bundle, with our key, if we have one, for those that check
+  /// it. Ok once it's out of our hands, with what the other end made of it
+  /// if it says. Uplinks that get no answer say nothing.
+  fn send_bundle<'a>(
+    &'a self, bnd: &'a BrokerMessageBundle, key: Option<&'a str>
+  ) -> BoxFuture<'a, Result<Option<BundleAck>, UplinkError>>;
+
+  /// Delivers a heartbeat. Uplinks that get no answer reply with nothing.
+  fn heartbeat<'a>(&'a self, hb: &'a HeartbeatMessage)
+  -> BoxFuture<'a, Result<HeartbeatReply, UplinkError>>;
+}
+
+/// The uplink a config picks. Only HTTP seals bundles, and minds the
+/// timeouts.
+pub(crate) fn from_config(
+  cfg: &UplinkConfig,
+  wire_format: WireFormat,
+  seal: Option<(BrokerId, SealingKey)>,
+  timeouts: HttpTimeouts
+) -> Box<dyn Uplink> {
+  return match cfg {
+    UplinkConfig::Http(endpoints) => Box::new(
+      HttpUplink::new(endpoints, wire_format, seal, timeouts)
+    ),
+    UplinkConfig::Tee { main, mirrors, backlog } => Box::new(TeeUplink::new(
+      HttpUplink::new(main, wire_format, seal, timeouts),
+      mirrors, *backlog, main.check_every, wire_format, timeouts
+    )),
+    UplinkConfig::Mqtt(ext, topic) => {
+      let (client, eventloop)
+        = AsyncClient::new(mqtt::options(ext), mqtt::CLIENT_CAPACITY);
+      Box::new(MqttUplink {
+        client: client,
+        eventloop: Mutex::new(Some(eventloop)),
+        topic: topic.clone(),
+        wire_format: wire_format
+      })
+    },
+    UplinkConfig::File(dir) => Box::new(FileUplink { dir: dir.clone() }),
+  };
+}
+
+/// An API we may POST to.
+#[derive(Debug)]
+struct Endpoint {
+  /// Knows the way, and whether to seal. Swapped for a fresh one every so
+  /// often, so the host name is looked up again.
+  client: RwLock<ApiClient>,
+  /// Whether the last call to it went through, or the last check said it's
+  /// up. Endpoints that are down are tried last.
+  up: AtomicBool
+}
+
+impl Endpoint {
+  /// The client as of now.
+  fn client(&self) -> ApiClient {
+    return self.client.read().unwrap_or_else(|e| e.into_inner()).clone();
+  }
+
+  /// Whether it's up, as far as we know.
+  fn is_up(&self) -> bool {
+    return self.up.load(Ordering::Relaxed);
+  }
+
+  /// Notes whether it's up, and says so when that changes.
+  fn mark(&self, up: bool, why: Option<&ClientError>) {
+    if self.up.swap(up, Ordering::Relaxed) == up {
+      return;
+    }
+    let url = self.client().base().clone();
+    match (up, why) {
+      (true, _) => info!("The API at {} is back.", url),
+      (false, Some(e)) => warn!("The API at {} is down: {}", url, e),
+      (false, None) => warn!("The API at {} is down.", url),
+    };
+  }
+
+  /// Starts over with fresh connections, then, if it's down, checks
+  /// whether it's back.
+  async fn check(&self) {
+    let fresh = self.client().reconnect();
+    *self.client.write().unwrap_or_else(|e| e.into_inner()) = fresh.clone();
+    if !self.is_up() && fresh.ready().await.is_ok() {
+      self.mark(true, None);
+    }
+  }
+}
+
+/// POSTs to the API. The way it's always been done. With several of them,
+/// each call goes to the first that's up and takes it, trying the ones
+/// that are down last, so a home API gets everything while it's there and
+/// a fallback gets the rest.
+#[derive(Debug)]
+pub(crate) struct HttpUplink {
+  /// Where to, best first. Never empty.
+  endpoints: Arc<Vec<Endpoint>>,
+  /// How often endpoints are checked on.
+  check_every: Duration,
+  /// How bundles are encoded.
+  wire_format: WireFormat
+}
+
+impl HttpUplink {
+  /// Sets up calls to some endpoints, sealed if given a UID and key.
+  pub(crate) fn new(
+    endpoints: &Endpoints,
+    wire_format: WireFormat,
+    seal: Option<(BrokerId, SealingKey)>,
+    timeouts: HttpTimeouts
+  ) -> Self {
+    return Self {
+      endpoints: Arc::new(endpoints.urls
+        .iter()
+        .map(|url| Endpoint {
+          client: RwLock::new(ApiClient::new(url.clone(), ClientOptions {
+            seal: seal.clone(),
+            ..timeouts.client_options()
+          })),
+          up: AtomicBool::new(true)
+        })
+        .collect()
+      ),
+      check_every: endpoints.check_every,
+      wire_format: wire_format
+    };
+  }
+
+  /// Makes a call to the first endpoint that takes it. An endpoint that
+  /// can't be reached, or can't serve it just then, is down, and the next
+  /// one is tried; any other answer is final.
+  async fn call<T, F, R>(&self, call: F) -> Result<T, UplinkError>
+  where F: Fn(ApiClient) -> R, R: Future<Output=Result<T, ClientError>> {
+    let (up, down): (Vec<&Endpoint>, Vec<&Endpoint>)
+      = self.endpoints.iter().partition(|ep| ep.is_up());
+    let mut last = None;
+    for ep in up.into_iter().chain(down) {
+      match call(ep.client()).await {
+        Ok(v) => {
+          ep.mark(true, None);
+          return Ok(v);
+        },
+        Err(e) if e.is_transient() => {
+          ep.mark(false, Some(&e));
+          last = Some(e);
+        },
+        Err(e) => return Err(e.into()),
+      };
+    }
+    return Err(last.expect("No endpoints?").into());
+  }
+}
+
+impl From<ClientError> for UplinkError {
+  fn from(e: ClientError) -> Self {
+    return match e {
+      ClientError::Api { .. } => UplinkError::Rejected(e.to_string()),
+      _ if e.is_timeout() => UplinkError::Timeout(e.to_string()),
+      _ => UplinkError::Transport(e.to_string()),
+    };
+  }
+}
+
+impl Uplink for HttpUplink {
+  /// Checks on the endpoints every so often.
+  fn start(&self) {
+    let endpoints = self.endpoints.clone();
+    let every = self.check_every;
+    tokio::spawn(async move {
+      let mut ticks = tokio::time::interval(every);
+      ticks.tick().await;
+      loop {
+        ticks.tick().await;
+        for ep in endpoints.iter() {
+          ep.check().await;
+        }
+      }
+    });
+  }
+
+  fn send_bundle<'a>(
+    &'a self, bnd: &'a BrokerMessageBundle, key: Option<&'a str>
+  ) -> BoxFuture<'a, Result<Option<BundleAck>, UplinkError>> {
+    let format = self.wire_format.into();
+    return self.call(move |c| async move {
+      return c.push_bundle(bnd, format, key).await;
+    }).boxed();
+  }
+
+  fn heartbeat<'a>(&'a self, hb: &'a HeartbeatMessage)
+  -> BoxFuture<'a, Result<HeartbeatReply, UplinkError>> {
+    return self.call(move |c| async move {
+      return c.heartbeat(hb).await;
+    }).boxed();
+  }
+}
+
+/// Publishes to an upstream MQTT broker, on {topic}/bundle and
+/// {topic}/heartbeat, at QoS 1. Delivered means handed to the MQTT client,
+/// which retries for as long as it stays connected.
+pub(crate) struct MqttUplink {
+  client: AsyncClient,
+  /// Polled on a task of its own once started.
+  eventloop: Mutex<Option<EventLoop>>,
+  /// Prefix for the topics published to.
+  topic: String,
+  /// How bundles are encoded.
+  wire_format: WireFormat
+}
+
+impl Debug for MqttUplink {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return write!(f, "MqttUplink({})", self.topic);
+  }
+}
+
+impl MqttUplink {
+  /// Publishes under our topic.
+  async fn publish(&self, sub: &str, payload: Vec<u8>)
+  -> Result<(), UplinkError> {
+    let topic = format!("{}/{}", self.topic, sub);
+    return self.client.publish(topic, QoS::AtLeastOnce, false, payload)
+      .await
+      .map_err(|e| UplinkError::Transport(e.to_string()));
+  }
+}
+
+impl Uplink for MqttUplink {
+  fn start(&self) {
+    let taken = self.eventloop.lock().unwrap_or_else(|e| e.into_inner()).take();
+    let mut eventloop = match taken {
+      Some(e) => e,
+      None => return,
+    };
+    tokio::spawn(async move {
+      loop {
+        if let Err(e) = eventloop.poll().await {
+          warn!("MQTT uplink failed: {}", e);
+          tokio::time::sleep(mqtt::RECONNECT_DELAY).await;
+        }
+      }
+    });
+  }
+
+  fn send_bundle<'a>(
+    &'a self, bnd: &'a BrokerMessageBundle, _: Option<&'a str>
+  ) -> BoxFuture<'a, Result<Option<BundleAck>, UplinkError>> {
+    let payload = BundleFormat::from(self.wire_format).encode(bnd);
+    return self.publish("bundle", payload)
+      .map(|r| r.map(|_| None))
+      .boxed();
+  }
+
+  fn heartbeat<'a>(&'a self, hb: &'a HeartbeatMessage)
+  -> BoxFuture<'a, Result<HeartbeatReply, UplinkError>> {
+    let payload = serde_json::to_vec(hb).unwrap_or_default();
+    return self.publish("heartbeat", payload)
+      .map(|r| r.map(|_| HeartbeatReply::default()))
+      .boxed();
+  }
+}
+
+/// Appends to NDJSON files in a directory: one message per line in
+/// messages.ndjson, one heartbeat per line in heartbeats.ndjson.
+#[derive(Debug)]
+pub(crate) struct FileUplink {
+  dir: PathBuf
+}
+
+impl FileUplink {
+  /// Appends lines to a file in the directory, creating both as needed.
+  async fn append(&self, name: &str, lines: String)
+  -> Result<(), UplinkError> {
+    let io_err = |e: std::io::Error| UplinkError::Transport(e.to_string());
+    tokio::fs::create_dir_all(&self.dir).await.map_err(io_err)?;
+    let mut file = tokio::fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(self.dir.join(name))
+      .await
+      .map_err(io_err)?;
+    file.write_all(lines.as_bytes()).await.map_err(io_err)?;
+    return file.flush().await.map_err(io_err);
+  }
+}
+
+impl Uplink for FileUplink {
+  fn send_bundle<'a>(
+    &'a self, bnd: &'a BrokerMessageBundle, _: Option<&'a str>
+  ) -> BoxFuture<'a, Result<Option<BundleAck>, UplinkError>> {
+    let mut lines = String::new();
+    for msg in bnd.iter() {
+      match serde_json::to_string(msg) {
+        Ok(line) => {
+          lines.push_str(&line);
+          lines.push('\n');
+        },
+        Err(e) => warn!("Couldn't serialize a message: {}", e),
+      }
+    }
+    return self.append("messages.ndjson", lines)
+      .map(|r| r.map(|_| None))
+      .boxed();
+  }
+
+  fn heartbeat<'a>(&'a self, hb: &'a HeartbeatMessage)
+  -> BoxFuture<'a, Result<HeartbeatReply, UplinkError>> {
+    let line = serde_json::to_string(hb).unwrap_or_default() + "\n";
+    return self.append("heartbeats.ndjson", line)
+      .map(|r| r.map(|_| HeartbeatReply::default()))
+      .boxed();
+  }
+}
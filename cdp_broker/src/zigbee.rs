@@ -0,0 +1,75 @@
+//! Adapter for zigbee2mqtt, which publishes each device's state as JSON on
+//! zigbee2mqtt/<device>. Point it at this broker, list the devices in the
+//! config, and their readings come out as regular sensor messages.
+//!
+//! Each configured device has an MQTT topic filter (+ and # work), the
+//! sensor ID its readings go under, and which JSON field holds each reading.
+//! Fields may be nested, as in "state.temperature". Temperatures are taken
+//! in °C and humidities in %RH, which is what zigbee2mqtt reports.
+
+use serde_json::Value;
+
+use libcdp::comm::sensor_broker::{AnySensorMessage, MessageParseError};
+
+use crate::broker::{Broker, RawOutcome};
+use crate::config::ZigbeeDeviceConfig;
+
+/// Whether an MQTT topic matches a topic filter.
+pub(crate) fn topic_matches(filter: &str, topic: &str) -> bool {
+  let mut levels = topic.split('/');
+  for f in filter.split('/') {
+    match (f, levels.next()) {
+      ("#", _) => return true,
+      ("+", Some(_)) => continue,
+      (f, Some(l)) if f == l => continue,
+      _ => return false,
+    }
+  }
+  return levels.next().is_none();
+}
+
+/// Looks a dotted path up in a JSON object.
+fn lookup<'a>(json: &'a Value, path: &str) -> Option<&'a Value> {
+  return path.split('.').try_fold(json, |v, key| v.get(key));
+}
+
+/// Translates a device's JSON state into sensor messages. Readings that are
+/// missing, not numbers or out of range are skipped.
+pub(crate) fn translate(dev: &ZigbeeDeviceConfig, payload: &[u8])
+-> Result<Vec<AnySensorMessage>, MessageParseError> {
+  let json: Value = serde_json::from_slice(payload)?;
+  return Ok(dev.fields.iter()
+    .filter_map(|(stype, path)| {
+      let value = lookup(&json, path)?.as_f64()?;
+      return AnySensorMessage::from_human_value(*stype, dev.sensor_id, value);
+    })
+    .collect());
+}
+
+/// Finds the device a topic belongs to, if any.
+pub(crate) fn device_for<'a>(broker: &'a Broker, topic: &str)
+-> Option<&'a ZigbeeDeviceConfig> {
+  return broker.cfg.zigbee.iter().find(|d| topic_matches(&d.topic, topic));
+}
+
+/// Translates and enqueues a device's state. Queued if anything was.
+pub(crate) async fn ingest(
+  broker: &Broker, dev: &ZigbeeDeviceConfig, topic: &str, payload: &[u8]
+) -> RawOutcome {
+  let msgs = match translate(dev, payload) {
+    Ok(m) => m,
+    Err(e) => {
+      warn!("zigbee2mqtt sent bad {} data: {}", topic, e);
+      return RawOutcome::BadPayload;
+    },
+  };
+  let mut outcome = RawOutcome::Ignored;
+  for msg in msgs {
+    match broker.ingest_message(msg).await {
+      RawOutcome::Queued => outcome = RawOutcome::Queued,
+      RawOutcome::Dropped => return RawOutcome::Dropped,
+      _ => (),
+    }
+  }
+  return outcome;
+}
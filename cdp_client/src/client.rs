@@ -0,0 +1,318 @@
+//! The client itself.
+
+use std::time::Duration;
+
+use libcdp::comm::broker_api::{self, BrokerMessage, BrokerMessageBundle, BundleAck, HeartbeatMessage, HeartbeatReply};
+use libcdp::comm::enrollment::{EnrollReply, EnrollRequest};
+use libcdp::comm::sealing::{self, SealingKey};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, SensorId};
+#[cfg(feature = "protobuf")]
+use libcdp::proto;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client, RequestBuilder, Response, Url};
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+use crate::error::{ApiErrorBody, ClientError};
+use crate::views::{AckRequest, Alert, AlertState, Broker, Changes, RangeQuery, RegisteredSensor, SensorInfo};
+
+/// How bundles are encoded on their way up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BundleFormat {
+  /// Plain JSON. What every API understands.
+  Json,
+  /// JSON, one message per line, which the API stores as it comes in.
+  Ndjson,
+  /// Protobuf, smaller. Needs an API that knows it.
+  #[cfg(feature = "protobuf")]
+  Protobuf
+}
+
+impl BundleFormat {
+  /// Encodes a bundle.
+  pub fn encode(&self, bnd: &BrokerMessageBundle) -> Vec<u8> {
+    return match self {
+      BundleFormat::Json => serde_json::to_vec(bnd).unwrap_or_default(),
+      BundleFormat::Ndjson => broker_api::bundle_to_ndjson(bnd),
+      #[cfg(feature = "protobuf")]
+      BundleFormat::Protobuf => proto::encode_bundle(bnd),
+    };
+  }
+
+  /// Content type of bundles so encoded.
+  pub fn content_type(&self) -> &'static str {
+    return match self {
+      BundleFormat::Json => "application/json",
+      BundleFormat::Ndjson => broker_api::NDJSON_CONTENT_TYPE,
+      #[cfg(feature = "protobuf")]
+      BundleFormat::Protobuf => proto::CONTENT_TYPE,
+    };
+  }
+}
+
+/// How a client goes about its calls.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+  /// Bearer token for the admin endpoints. None means we're no admin.
+  pub admin_token: Option<String>,
+  /// The broker's UID and key, if bundles are sealed.
+  pub seal: Option<(BrokerId, SealingKey)>,
+  /// How many times to try again when a call fails for reasons that may
+  /// pass.
+  pub retries: u32,
+  /// How long to wait before trying again the first time. It doubles with
+  /// every try.
+  pub retry_delay: Duration,
+  /// How long a call may take, each try, connecting and all. None means as
+  /// long as it takes.
+  pub timeout: Option<Duration>,
+  /// How long connecting may take, each try. None means as long as the
+  /// system lets it.
+  pub connect_timeout: Option<Duration>
+}
+
+impl Default for ClientOptions {
+  fn default() -> Self {
+    return Self {
+      admin_token: None,
+      seal: None,
+      retries: 2,
+      retry_delay: Duration::from_millis(500),
+      timeout: Some(Duration::from_secs(30)),
+      connect_timeout: Some(Duration::from_secs(10))
+    };
+  }
+}
+
+/// Talks to the API at some base URL.
+#[derive(Clone, Debug)]
+pub struct ApiClient {
+  /// The API's base URL. Paths are joined to it, so it should end in /.
+  base: Url,
+  /// How calls are made.
+  opts: ClientOptions,
+  /// Shared between requests, for the connection pool.
+  http: Client
+}
+
+impl ApiClient {
+  /// A client for the API at a base URL. Panics if there's no setting up
+  /// TLS, like reqwest's own Client::new.
+  pub fn new(base: Url, opts: ClientOptions) -> Self {
+    let mut http = Client::builder();
+    if let Some(timeout) = opts.connect_timeout {
+      http = http.connect_timeout(timeout);
+    }
+    return Self {
+      base: base,
+      opts: opts,
+      http: http.build().expect("Couldn't set up an HTTP client")
+    };
+  }
+
+  /// The API's base URL.
+  pub fn base(&self) -> &Url {
+    return &self.base;
+  }
+
+  /// The same client, with connections of its own, so the host name is
+  /// looked up again. Connections already made are kept for as long as
+  /// they're of use, which is forever when there's a steady stream of
+  /// calls, even if the name now means somewhere else.
+  pub fn reconnect(&self) -> Self {
+    return Self::new(self.base.clone(), self.opts.clone());
+  }
+
+  /// Where something is.
+  fn target(&self, path: &str) -> Result<Url, ClientError> {
+    return self.base.join(path)
+      .map_err(|e| ClientError::BadUrl(e.to_string()));
+  }
+
+  /// Makes a request, as many times as it takes, within reason. The request
+  /// is made anew every try. Succeeds on 2xx.
+  async fn send<F>(&self, make: F) -> Result<Response, ClientError>
+  where F: Fn(&Client) -> RequestBuilder {
+    let mut delay = self.opts.retry_delay;
+    let mut tries = 0;
+    loop {
+      let res = self.send_once(make(&self.http)).await;
+      match res {
+        Err(e) if e.is_transient() && tries < self.opts.retries => {
+          tries += 1;
+          tokio::time::sleep(delay).await;
+          delay *= 2;
+        },
+        other => return other,
+      };
+    }
+  }
+
+  /// Makes a request once, with whatever we always send.
+  async fn send_once(&self, mut req: RequestBuilder)
+  -> Result<Response, ClientError> {
+    if let Some(token) = &self.opts.admin_token {
+      req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    if let Some(timeout) = self.opts.timeout {
+      req = req.timeout(timeout);
+    }
+    let resp = req.send().await?;
+    let status = resp.status();
+    if status.is_success() {
+      return Ok(resp);
+    }
+    let body: Option<ApiErrorBody> = resp.json().await.ok();
+    return Err(ClientError::Api {
+      status: status.as_u16(),
+      code: body.as_ref().and_then(|b| b.code.clone()),
+      message: body.and_then(|b| b.message)
+    });
+  }
+
+  /// Reads an answer.
+  async fn read<T: DeserializeOwned>(resp: Response)
+  -> Result<T, ClientError> {
+    return resp.json()
+      .await
+      .map_err(|e| ClientError::BadReply(e.to_string()));
+  }
+
+  /// GETs something, and reads it.
+  async fn get<T, Q>(&self, path: &str, query: &Q) -> Result<T, ClientError>
+  where T: DeserializeOwned, Q: serde::Serialize + ?Sized {
+    let url = self.target(path)?;
+    let resp = self.send(|c| c.get(url.clone()).query(query)).await?;
+    return Self::read(resp).await;
+  }
+
+  /// Whether the API is ready to take calls, as per /readyz. Asked once,
+  /// with no retries.
+  pub async fn ready(&self) -> Result<(), ClientError> {
+    let url = self.target("readyz")?;
+    return self.send_once(self.http.get(url)).await.map(|_| ());
+  }
+
+  /// Sends a heartbeat, and reads what the API replied. Older APIs reply
+  /// with nothing much, which is the same as an empty reply.
+  pub async fn heartbeat(&self, hb: &HeartbeatMessage)
+  -> Result<HeartbeatReply, ClientError> {
+    let url = self.target("heartbeat")?;
+    let resp = self.send(|c| c.post(url.clone()).json(hb)).await?;
+    return Ok(resp.json().await.unwrap_or_default());
+  }
+
+  /// Asks to be enrolled, or whether we were.
+  pub async fn enroll(&self, req: &EnrollRequest)
+  -> Result<EnrollReply, ClientError> {
+    let url = self.target("enroll")?;
+    let resp = self.send(|c| c.post(url.clone()).json(req)).await?;
+    return Self::read(resp).await;
+  }
+
+  /// Pushes a bundle of messages, sealed if there's a key to seal with,
+  /// and with the broker's key, if it has one. Returns what the API made of
+  /// it. Older APIs don't say, which is None.
+  pub async fn push_bundle(
+    &self, bnd: &BrokerMessageBundle, format: BundleFormat, key: Option<&str>
+  ) -> Result<Option<BundleAck>, ClientError> {
+    let url = self.target("bundle")?;
+    let ctype = format.content_type();
+    let body = format.encode(bnd);
+    let (ctype, body, sealed_by) = match &self.opts.seal {
+      Some((uid, key)) => {
+        let sealed = key.seal(uid, ctype, &body)
+          .map_err(|e| ClientError::Seal(e.to_string()))?;
+        (sealing::CONTENT_TYPE, sealed, Some(uid.to_string()))
+      },
+      None => (ctype, body, None),
+    };
+    let resp = self.send(|c| {
+      let mut req = c.post(url.clone())
+        .header(CONTENT_TYPE, ctype)
+        .body(body.clone());
+      if let Some(key) = key {
+        req = req.header(broker_api::KEY_HEADER, key);
+      }
+      return match &sealed_by {
+        Some(uid) => req.header(sealing::BROKER_HEADER, uid.as_str()),
+        None => req,
+      };
+    }).await?;
+    return Ok(resp.json().await.ok());
+  }
+
+  /// The latest message of every sensor.
+  pub async fn current(&self) -> Result<Vec<BrokerMessage>, ClientError> {
+    return self.get("current", &()).await;
+  }
+
+  /// The latest message of every sensor whose reading changed since a
+  /// cursor. 0 means every sensor.
+  pub async fn changes(&self, since: u64) -> Result<Changes, ClientError> {
+    return self.get("changes", &[("since", since)]).await;
+  }
+
+  /// Messages of a sensor type, within a range.
+  pub async fn sensor_messages(&self, stype: SensorType, query: &RangeQuery)
+  -> Result<Vec<BrokerMessage>, ClientError> {
+    let path = format!("messages/sensor/{}", stype);
+    return self.get(&path, query).await;
+  }
+
+  /// Alerts kept, newest first, maybe only those in some state.
+  pub async fn alerts(&self, state: Option<AlertState>)
+  -> Result<Vec<Alert>, ClientError> {
+    return self.get("alerts", &[("state", state)]).await;
+  }
+
+  /// An alert.
+  pub async fn alert(&self, id: Uuid) -> Result<Alert, ClientError> {
+    return self.get(&format!("alerts/{}", id), &()).await;
+  }
+
+  /// Says somebody is on an alert. Returns the alert, as it is now.
+  pub async fn ack_alert(&self, id: Uuid, by: &str, note: Option<&str>)
+  -> Result<Alert, ClientError> {
+    let url = self.target(&format!("alerts/{}/ack", id))?;
+    let ack = AckRequest { by: by, note: note };
+    let resp = self.send(|c| c.post(url.clone()).json(&ack)).await?;
+    return Self::read(resp).await;
+  }
+
+  /// Brokers the API heard from.
+  pub async fn brokers(&self) -> Result<Vec<Broker>, ClientError> {
+    return self.get("brokers", &()).await;
+  }
+
+  /// Sensors somebody named, or put in a room.
+  pub async fn sensors(&self) -> Result<Vec<RegisteredSensor>, ClientError> {
+    return self.get("sensors", &()).await;
+  }
+
+  /// Names a sensor, or says which room it's in.
+  pub async fn set_sensor_info(
+    &self, stype: SensorType, sensor_id: SensorId, info: &SensorInfo
+  ) -> Result<(), ClientError> {
+    let url = self.target(&format!("sensors/{}/{}", stype, sensor_id))?;
+    self.send(|c| c.put(url.clone()).json(info)).await?;
+    return Ok(());
+  }
+
+  /// Forgets a sensor's name and room.
+  pub async fn remove_sensor_info(
+    &self, stype: SensorType, sensor_id: SensorId
+  ) -> Result<(), ClientError> {
+    let url = self.target(&format!("sensors/{}/{}", stype, sensor_id))?;
+    self.send(|c| c.delete(url.clone())).await?;
+    return Ok(());
+  }
+
+  /// A gzipped snapshot of the whole database. Admin only.
+  pub async fn backup(&self) -> Result<Vec<u8>, ClientError> {
+    let url = self.target("admin/backup")?;
+    let resp = self.send(|c| c.get(url.clone())).await?;
+    return Ok(resp.bytes().await?.to_vec());
+  }
+}
@@ -0,0 +1,89 @@
+//! What can go wrong when calling the API.
+
+use std::error::Error;
+use std::fmt::Display;
+
+use serde::Deserialize;
+
+/// Why a call didn't work out.
+#[derive(Debug)]
+pub enum ClientError {
+  /// Couldn't get the request there, or the answer back.
+  Transport(reqwest::Error),
+  /// The API answered with an error.
+  Api {
+    /// HTTP status.
+    status: u16,
+    /// What went wrong, in snake_case, if the API said.
+    code: Option<String>,
+    /// What went wrong, in words, if the API said.
+    message: Option<String>
+  },
+  /// The API answered with something we can't make sense of.
+  BadReply(String),
+  /// Couldn't make a URL out of the base and a path.
+  BadUrl(String),
+  /// Couldn't seal a bundle.
+  Seal(String)
+}
+
+impl ClientError {
+  /// Whether trying again might work: the request never made it, or the
+  /// API couldn't serve it just then.
+  pub fn is_transient(&self) -> bool {
+    return match self {
+      ClientError::Transport(_) => true,
+      ClientError::Api { status, .. } => *status >= 500 || *status == 429,
+      _ => false,
+    };
+  }
+
+  /// Whether the API took too long, to connect to or to answer.
+  pub fn is_timeout(&self) -> bool {
+    return match self {
+      ClientError::Transport(e) => e.is_timeout(),
+      _ => false,
+    };
+  }
+
+  /// The error code the API answered with, if any.
+  pub fn code(&self) -> Option<&str> {
+    return match self {
+      ClientError::Api { code, .. } => code.as_deref(),
+      _ => None,
+    };
+  }
+}
+
+impl Error for ClientError {}
+
+impl Display for ClientError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      ClientError::Transport(e) => write!(f, "Couldn't reach the API: {}", e),
+      ClientError::Api { status, code, message } => write!(
+        f, "API error {} ({}): {}", status,
+        code.as_deref().unwrap_or("no code"),
+        message.as_deref().unwrap_or("no message")
+      ),
+      ClientError::BadReply(e) => write!(f, "Bad reply from the API: {}", e),
+      ClientError::BadUrl(e) => write!(f, "Bad URL: {}", e),
+      ClientError::Seal(e) => write!(f, "Couldn't seal: {}", e),
+    };
+  }
+}
+
+impl From<reqwest::Error> for ClientError {
+  fn from(e: reqwest::Error) -> Self {
+    return ClientError::Transport(e);
+  }
+}
+
+/// An error, as the API words it.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ApiErrorBody {
+  /// What went wrong, in snake_case.
+  pub(crate) code: Option<String>,
+  /// What went wrong, in words.
+  pub(crate) message: Option<String>
+}
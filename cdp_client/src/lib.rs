@@ -0,0 +1,20 @@
+//! A typed client for cdp_api, for brokers and for anything else that
+//! talks to it: heartbeats, enrollment and bundles on the broker side, and
+//! sensor data and alerts on the other. Requests that fail along the way,
+//! or that the API couldn't serve just then, are retried a few times.
+//!
+//! ```ignore
+//! let api = ApiClient::new(
+//!   "http://casa:8080/".parse()?, ClientOptions::default()
+//! );
+//! for msg in api.current().await? {
+//!   println!("{:?}", msg.payload);
+//! }
+//! ```
+
+mod client;
+mod error;
+pub mod views;
+
+pub use client::{ApiClient, BundleFormat, ClientOptions};
+pub use error::ClientError;
@@ -0,0 +1,168 @@
+//! What the API answers with, and what some calls take, beyond what libcdp
+//! already has types for. Fields the API adds later are ignored.
+
+use chrono::{DateTime, Local};
+use libcdp::comm::broker_api::{BrokerMessage, BrokerStatus, SoftwareInfo};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, SensorId};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Sensors whose reading changed since a cursor.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Changes {
+  /// Where to ask from next time.
+  pub cursor: u64,
+  /// Latest message of every sensor that changed.
+  pub changed: Vec<BrokerMessage>
+}
+
+/// A broker the API heard from.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Broker {
+  /// Its UID.
+  pub uid: BrokerId,
+  /// Heartbeat format version it spoke last time. 0 if it only enrolled.
+  pub version: u32,
+  /// When the API first heard from it.
+  pub first_seen: DateTime<Local>,
+  /// When the API last heard from it.
+  pub last_heartbeat: DateTime<Local>,
+  /// How it was doing, if it said.
+  pub status: Option<BrokerStatus>,
+  /// What it runs, if it said.
+  #[serde(default)]
+  pub software: Option<SoftwareInfo>,
+  /// Whether it speaks a protocol the API doesn't get along with.
+  #[serde(default)]
+  pub incompatible: bool
+}
+
+/// What a sensor is called, and where it is.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SensorInfo {
+  /// Its name, like "Fridge".
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+  /// Its room, like "Kitchen".
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub room: Option<String>
+}
+
+/// A sensor somebody named, or put in a room.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RegisteredSensor {
+  /// Type of the sensor.
+  pub sensor_type: SensorType,
+  /// ID of the sensor.
+  pub sensor_id: SensorId,
+  /// What it's called, and where.
+  #[serde(flatten)]
+  pub info: SensorInfo
+}
+
+/// Which way messages go, and which time a range goes by.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageOrder {
+  /// By when brokers made them.
+  Constructed,
+  /// By when the API got them.
+  Received,
+  /// By order of arrival, for paging.
+  Seq
+}
+
+/// Which messages of a sensor type to fetch. Everything None means all of
+/// them.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RangeQuery {
+  /// Start of the range. None means the dawn of time.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub from: Option<DateTime<Local>>,
+  /// End of the range. None means now, or no end when going by seq.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub to: Option<DateTime<Local>>,
+  /// Only verified messages, or only unverified ones.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub verified: Option<bool>,
+  /// Which way they go.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub order: Option<MessageOrder>,
+  /// Only messages with a sequence number past this one.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub after: Option<u64>,
+  /// Most messages to answer with.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub limit: Option<usize>
+}
+
+/// Where an alert is at.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+  /// Nobody has said they're on it.
+  Open,
+  /// Somebody is on it.
+  Acknowledged,
+  /// The sensor recovered.
+  Resolved
+}
+
+/// Something that happened to an alert.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AlertTransition {
+  /// Where it went.
+  pub state: AlertState,
+  /// When.
+  pub when: DateTime<Local>,
+  /// Who moved it along, if anybody.
+  #[serde(default)]
+  pub by: Option<String>,
+  /// What they had to say.
+  #[serde(default)]
+  pub note: Option<String>
+}
+
+/// A sensor, or device, needing a human.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Alert {
+  /// Unique ID, for acknowledging.
+  pub id: Uuid,
+  /// What it's about, like anomaly or low_battery.
+  pub kind: String,
+  /// Type of the sensor it's about. None for device and rule alerts.
+  pub sensor_type: Option<SensorType>,
+  /// ID of the sensor, or device, it's about. 0 for rule alerts.
+  pub sensor_id: SensorId,
+  /// Name of the rule that holds, for rule alerts.
+  #[serde(default)]
+  pub rule: Option<String>,
+  /// UID of the broker at the sensor's site.
+  #[serde(default)]
+  pub broker_id: Option<BrokerId>,
+  /// How much it matters: info, warning or critical.
+  pub severity: String,
+  /// What set it off, in words.
+  pub summary: String,
+  /// Where it's at.
+  pub state: AlertState,
+  /// Everything that happened to it, oldest first.
+  pub history: Vec<AlertTransition>
+}
+
+impl Alert {
+  /// When it opened.
+  pub fn opened_when(&self) -> Option<DateTime<Local>> {
+    return self.history.first().map(|t| t.when);
+  }
+}
+
+/// Somebody saying they're on an alert.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct AckRequest<'a> {
+  /// Who.
+  pub(crate) by: &'a str,
+  /// Anything they have to say.
+  pub(crate) note: Option<&'a str>
+}
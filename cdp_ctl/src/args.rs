@@ -0,0 +1,249 @@
+//! What the command line says to do.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use cdp_client::views::{AlertState, SensorInfo};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::{BrokerId, SensorId};
+use uuid::Uuid;
+
+/// What the command line takes.
+pub(crate) const USAGE: &str = "\
+Usage: cdpctl [options] <command> [arguments]
+
+Commands:
+  current                       latest reading of every sensor
+  tail [--every <secs>]         readings as they change, until interrupted
+  topics                        topics sensors may publish to
+  sensors                       sensors with a name or a room
+  sensors set <type> <id> [--name <name>] [--room <room>]
+                                name a sensor, or say which room it's in
+                                (admin)
+  sensors rm <type> <id>        forget a sensor's name and room (admin)
+  brokers                       brokers the API heard from
+  alerts [open|acknowledged|resolved]
+                                alerts, newest first
+  ack <alert> --by <who> [--note <note>]
+                                say somebody is on an alert (admin)
+  backup <file>                 save a snapshot of the database (admin)
+  push <type> <id> <value> [--broker <uid>] [--key <key>] [--count <n>]
+                                push a test bundle, value in °C or %RH, as
+                                a broker, with its key if it has one
+
+Options:
+  --api <url>       the API's base URL, else $CDP_API, else
+                    http://localhost:9869/
+  --token <token>   admin token, else $CDP_ADMIN_TOKEN
+  -h, --help        print this and quit";
+
+/// Something to do.
+#[derive(Clone, Debug)]
+pub(crate) enum Command {
+  /// Print the latest reading of every sensor.
+  Current,
+  /// Print readings as they change, checking this often.
+  Tail(Duration),
+  /// Print the topics this build knows of.
+  Topics,
+  /// Print the registered sensors.
+  Sensors,
+  /// Set a sensor's name and room.
+  SetSensor(SensorType, SensorId, SensorInfo),
+  /// Forget a sensor's name and room.
+  RemoveSensor(SensorType, SensorId),
+  /// Print the brokers.
+  Brokers,
+  /// Print the alerts, maybe only those in some state.
+  Alerts(Option<AlertState>),
+  /// Acknowledge an alert, as somebody, maybe with a note.
+  Ack(Uuid, String, Option<String>),
+  /// Save a backup to a file.
+  Backup(PathBuf),
+  /// Push a bundle of test readings.
+  Push(TestBundle),
+  /// Print the usage.
+  Help
+}
+
+/// Test readings to push.
+#[derive(Clone, Debug)]
+pub(crate) struct TestBundle {
+  /// Type of the sensor they're from.
+  pub(crate) sensor_type: SensorType,
+  /// ID of the sensor they're from.
+  pub(crate) sensor_id: SensorId,
+  /// Their value, in human units.
+  pub(crate) value: f64,
+  /// Broker they're from. None means a made-up one.
+  pub(crate) broker: Option<BrokerId>,
+  /// That broker's key, if the API gave it one.
+  pub(crate) key: Option<String>,
+  /// How many of them.
+  pub(crate) count: usize
+}
+
+/// Whatever the command line says.
+#[derive(Clone, Debug)]
+pub(crate) struct Args {
+  /// --api.
+  pub(crate) api: Option<String>,
+  /// --token.
+  pub(crate) token: Option<String>,
+  /// What to do.
+  pub(crate) command: Command
+}
+
+/// Parses a value, saying which one was bad if it is.
+fn value<T: FromStr>(what: &str, s: Option<String>) -> Result<T, String> {
+  let s = s.ok_or_else(|| format!("Missing {}.", what))?;
+  return s.parse().map_err(|_| format!("Bad {} \"{}\".", what, s));
+}
+
+/// Parses a sensor type.
+fn sensor_type(s: Option<String>) -> Result<SensorType, String> {
+  let s = s.ok_or("Missing sensor type.")?;
+  return SensorType::from_str(&s).map_err(|_| format!(
+    "Unknown sensor type \"{}\". Try one of: {}.", s,
+    SensorType::all_types().iter()
+      .map(|t| t.name())
+      .collect::<Vec<_>>()
+      .join(", ")
+  ));
+}
+
+/// Parses an alert state.
+fn alert_state(s: &str) -> Result<AlertState, String> {
+  return match s {
+    "open" => Ok(AlertState::Open),
+    "acknowledged" => Ok(AlertState::Acknowledged),
+    "resolved" => Ok(AlertState::Resolved),
+    _ => Err(format!("Unknown alert state \"{}\".", s)),
+  };
+}
+
+/// Takes the options that follow a command's arguments, like --name and
+/// --room, each with a value. Unknown ones are an error.
+fn options<I: Iterator<Item = String>>(args: I, known: &[&str])
+-> Result<Vec<(String, String)>, String> {
+  let mut args = args;
+  let mut found = Vec::new();
+  while let Some(arg) = args.next() {
+    if !known.contains(&arg.as_str()) {
+      return Err(format!("Unknown argument \"{}\".", arg));
+    }
+    let val = args.next().ok_or_else(|| format!("{} needs a value.", arg))?;
+    found.push((arg, val));
+  }
+  return Ok(found);
+}
+
+/// The value of an option, if given; the last one wins.
+fn option(opts: &[(String, String)], name: &str) -> Option<String> {
+  return opts.iter().rev().find(|(n, _)| n == name).map(|(_, v)| v.clone());
+}
+
+impl Args {
+  /// Parses the arguments, without the program name.
+  pub(crate) fn parse<I: Iterator<Item = String>>(args: I)
+  -> Result<Self, String> {
+    let mut args = args.peekable();
+    let mut api = None;
+    let mut token = None;
+    while let Some(arg) = args.peek().cloned() {
+      match arg.as_str() {
+        "--api" => {
+          args.next();
+          api = Some(args.next().ok_or("--api needs a URL.")?);
+        },
+        "--token" => {
+          args.next();
+          token = Some(args.next().ok_or("--token needs a token.")?);
+        },
+        _ => break,
+      };
+    }
+    let cmd = args.next().unwrap_or_else(|| "help".to_owned());
+    let command = match cmd.as_str() {
+      "-h" | "--help" | "help" => Command::Help,
+      "current" => Command::Current,
+      "tail" => {
+        let opts = options(args.by_ref(), &["--every"])?;
+        let secs: f64 = match option(&opts, "--every") {
+          Some(s) => value("interval", Some(s))?,
+          None => 2.0,
+        };
+        if !secs.is_finite() || secs <= 0.0 {
+          return Err("The interval must be positive.".to_owned());
+        }
+        Command::Tail(Duration::from_secs_f64(secs))
+      },
+      "topics" => Command::Topics,
+      "sensors" => match args.next().as_deref() {
+        None => Command::Sensors,
+        Some("set") => {
+          let stype = sensor_type(args.next())?;
+          let id = value("sensor ID", args.next())?;
+          let opts = options(args.by_ref(), &["--name", "--room"])?;
+          let info = SensorInfo {
+            name: option(&opts, "--name"),
+            room: option(&opts, "--room")
+          };
+          if info.name.is_none() && info.room.is_none() {
+            return Err("Give it a --name, a --room, or both.".to_owned());
+          }
+          Command::SetSensor(stype, id, info)
+        },
+        Some("rm") => {
+          let stype = sensor_type(args.next())?;
+          Command::RemoveSensor(stype, value("sensor ID", args.next())?)
+        },
+        Some(other) => {
+          return Err(format!("Unknown sensors command \"{}\".", other));
+        },
+      },
+      "brokers" => Command::Brokers,
+      "alerts" => match args.next() {
+        Some(s) => Command::Alerts(Some(alert_state(&s)?)),
+        None => Command::Alerts(None),
+      },
+      "ack" => {
+        let id = value("alert ID", args.next())?;
+        let opts = options(args.by_ref(), &["--by", "--note"])?;
+        let by = option(&opts, "--by").ok_or("Say --by whom.")?;
+        Command::Ack(id, by, option(&opts, "--note"))
+      },
+      "backup" => Command::Backup(value("file", args.next())?),
+      "push" => {
+        let stype = sensor_type(args.next())?;
+        let id = value("sensor ID", args.next())?;
+        let val = value("value", args.next())?;
+        let opts = options(args.by_ref(), &["--broker", "--key", "--count"])?;
+        Command::Push(TestBundle {
+          sensor_type: stype,
+          sensor_id: id,
+          value: val,
+          broker: match option(&opts, "--broker") {
+            Some(b) => Some(value("broker UID", Some(b))?),
+            None => None,
+          },
+          key: option(&opts, "--key"),
+          count: match option(&opts, "--count") {
+            Some(c) => value("count", Some(c))?,
+            None => 1,
+          }
+        })
+      },
+      _ => return Err(format!("Unknown command \"{}\".", cmd)),
+    };
+    if let Some(extra) = args.next() {
+      return Err(format!("Unexpected argument \"{}\".", extra));
+    }
+    return Ok(Self {
+      api: api,
+      token: token,
+      command: command
+    });
+  }
+}
@@ -0,0 +1,146 @@
+//! Doing what the command line says.
+
+use std::error::Error;
+
+use cdp_client::ApiClient;
+use cdp_client::views::Alert;
+use chrono::Local;
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+use libcdp::comm::decoders;
+use libcdp::comm::sensor_broker::{AnySensorMessage, SensorType};
+use libcdp::ids::BrokerId;
+
+use crate::args::{Command, TestBundle};
+
+/// Whatever went wrong.
+type CmdResult = Result<(), Box<dyn Error>>;
+
+/// Unit of a sensor type's readings, in human terms.
+fn unit(stype: SensorType) -> &'static str {
+  return match stype {
+    SensorType::Temperature => "°C",
+    SensorType::Humidity => "%RH",
+  };
+}
+
+/// A message, on one line: when, which sensor, what it read.
+fn describe(msg: &BrokerMessage) -> Option<String> {
+  let sd = match &msg.payload {
+    BrokerMessagePayload::SensorData(sd) => sd,
+    _ => return None,
+  };
+  let stype = sd.sensor_type();
+  return Some(format!(
+    "{}  {:<12} {:>3}  {:>8.2} {}",
+    msg.constructed_when.format("%Y-%m-%d %H:%M:%S"), stype.name(),
+    sd.sensor_id(), sd.reading().human_value(), unit(stype)
+  ));
+}
+
+/// Prints messages, a line each.
+fn print_messages(msgs: &[BrokerMessage]) {
+  for line in msgs.iter().filter_map(describe) {
+    println!("{}", line);
+  }
+}
+
+/// Prints an alert, on one line.
+fn print_alert(a: &Alert) {
+  let opened = a.opened_when()
+    .map(|w| w.format("%Y-%m-%d %H:%M").to_string())
+    .unwrap_or_default();
+  println!(
+    "{}  {:<16} {:<12} {:<8} {:?}  {}",
+    a.id, opened, a.kind, a.severity, a.state, a.summary
+  );
+}
+
+/// Pushes test readings, as if a broker sent them.
+async fn push(client: &ApiClient, tb: &TestBundle) -> CmdResult {
+  let stype = tb.sensor_type;
+  let msg = AnySensorMessage::from_human_value(stype, tb.sensor_id, tb.value)
+    .ok_or_else(|| format!(
+      "{} {} doesn't fit a {} message.", tb.value, unit(stype), stype
+    ))?;
+  let broker = tb.broker.unwrap_or_else(BrokerId::new_v4);
+  let bundle: Vec<BrokerMessage> = (0..tb.count)
+    .map(|_| {
+      let mut bm = BrokerMessage::construct(
+        broker, BrokerMessagePayload::SensorData(msg.clone())
+      );
+      bm.sent_when = Some(Local::now());
+      bm
+    })
+    .collect();
+  let format = cdp_client::BundleFormat::Json;
+  let ack = client.push_bundle(&bundle, format, tb.key.as_deref()).await?;
+  println!("Pushed {} message(s) as broker {}.", bundle.len(), broker);
+  if let Some((lo, hi)) = ack.and_then(|a| a.seq_range) {
+    println!("They're numbered {} to {}.", lo, hi);
+  }
+  return Ok(());
+}
+
+/// Does it.
+pub(crate) async fn run(client: &ApiClient, cmd: Command) -> CmdResult {
+  match cmd {
+    Command::Help => {},
+    Command::Current => print_messages(&client.current().await?),
+    Command::Tail(every) => {
+      let mut cursor = 0;
+      loop {
+        let ch = client.changes(cursor).await?;
+        print_messages(&ch.changed);
+        cursor = ch.cursor;
+        tokio::time::sleep(every).await;
+      }
+    },
+    Command::Topics => {
+      for topic in decoders::topics() {
+        println!("{}", topic);
+      }
+    },
+    Command::Sensors => {
+      for rs in client.sensors().await? {
+        println!(
+          "{:<12} {:>3}  {:<20} {}",
+          rs.sensor_type.name(), rs.sensor_id,
+          rs.info.name.unwrap_or_default(), rs.info.room.unwrap_or_default()
+        );
+      }
+    },
+    Command::SetSensor(stype, id, info) => {
+      client.set_sensor_info(stype, id, &info).await?;
+    },
+    Command::RemoveSensor(stype, id) => {
+      client.remove_sensor_info(stype, id).await?;
+    },
+    Command::Brokers => {
+      for b in client.brokers().await? {
+        let version = b.software.as_ref()
+          .map(|s| s.version.clone())
+          .unwrap_or_else(|| "?".to_owned());
+        println!(
+          "{}  {:<8} last heard {}{}",
+          b.uid, version, b.last_heartbeat.format("%Y-%m-%d %H:%M:%S"),
+          if b.incompatible { "  (incompatible)" } else { "" }
+        );
+      }
+    },
+    Command::Alerts(state) => {
+      for a in client.alerts(state).await? {
+        print_alert(&a);
+      }
+    },
+    Command::Ack(id, by, note) => {
+      print_alert(&client.ack_alert(id, &by, note.as_deref()).await?);
+    },
+    Command::Backup(path) => {
+      let gz = client.backup().await?;
+      std::fs::write(&path, &gz)?;
+      println!("Saved {} bytes to {}.", gz.len(), path.display());
+    },
+    Command::Push(tb) => push(client, &tb).await?,
+  };
+  return Ok(());
+}
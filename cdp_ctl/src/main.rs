@@ -0,0 +1,47 @@
+//! cdpctl: runs the house from a terminal, through the API. See USAGE in
+//! args.rs for what it can do.
+
+mod args;
+mod commands;
+
+use cdp_client::{ApiClient, ClientOptions};
+use url::Url;
+
+use crate::args::{Args, Command, USAGE};
+
+/// Where the API is, unless told otherwise.
+const DEFAULT_API: &str = "http://localhost:9869/";
+
+/// A base URL, with the / that paths get joined after.
+fn base_url(s: &str) -> Result<Url, String> {
+  let s = if s.ends_with('/') { s.to_owned() } else { format!("{}/", s) };
+  return Url::parse(&s).map_err(|e| format!("Bad API URL \"{}\": {}", s, e));
+}
+
+#[tokio::main]
+async fn main() {
+  let args = Args::parse(std::env::args().skip(1))
+    .unwrap_or_else(|e| {
+      eprintln!("{}\n\n{}", e, USAGE);
+      std::process::exit(2);
+    });
+  if let Command::Help = args.command {
+    println!("{}", USAGE);
+    return;
+  }
+  let api = args.api
+    .or_else(|| std::env::var("CDP_API").ok())
+    .unwrap_or_else(|| DEFAULT_API.to_owned());
+  let base = base_url(&api).unwrap_or_else(|e| {
+    eprintln!("{}", e);
+    std::process::exit(2);
+  });
+  let client = ApiClient::new(base, ClientOptions {
+    admin_token: args.token.or_else(|| std::env::var("CDP_ADMIN_TOKEN").ok()),
+    ..ClientOptions::default()
+  });
+  if let Err(e) = commands::run(&client, args.command).await {
+    eprintln!("{}", e);
+    std::process::exit(1);
+  }
+}
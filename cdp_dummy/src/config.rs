@@ -3,13 +3,19 @@
 use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::fmt::Display;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
 
 use config::{Config, ConfigError};
-use libcdp::comm::sensor_broker::SensorType;
+use libcdp::comm::sensor_broker::{AnySensorMessage, SensorType};
+use libcdp::comm::signing;
+use libcdp::ids::SensorId;
+use libcdp::runtime;
+use libcdp::units;
 use rand::Rng;
-use rand::prelude::{SliceRandom, ThreadRng};
+use rand::prelude::ThreadRng;
+use rumqttc::{Key, MqttOptions};
 use serde::{Serialize, Deserialize};
 
 /// Dummy sensor mode of operation.
@@ -20,7 +26,11 @@ pub(crate) enum DummyMode {
   /// Constantly output the maximum value in the set range.
   ConstantMax,
   /// Output random values from the set range.
-  Random
+  Random,
+  /// Output values from the set range along a daily curve.
+  Diurnal,
+  /// Output values from the set range that track another dummy's.
+  Follow
 }
 
 impl Display for DummyMode {
@@ -29,6 +39,8 @@ impl Display for DummyMode {
       DummyMode::ConstantMin => "constant_min",
       DummyMode::ConstantMax => "constant_max",
       DummyMode::Random => "random",
+      DummyMode::Diurnal => "diurnal",
+      DummyMode::Follow => "follow",
     });
   }
 }
@@ -51,11 +63,192 @@ impl DummyMode {
     return vec![
       DummyMode::ConstantMin,
       DummyMode::ConstantMax,
-      DummyMode::Random
+      DummyMode::Random,
+      DummyMode::Diurnal,
+      DummyMode::Follow
     ];
   }
 }
 
+/// A range of values, as people read them, to make payloads from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ValueRangeFile {
+  /// Lowest value.
+  pub(crate) min: f64,
+  /// Highest value.
+  pub(crate) max: f64,
+  /// Distance between values. None means 1.
+  pub(crate) step: Option<f64>,
+  /// What the values are in: "celsius", "kelvin" or "fahrenheit" for
+  /// temperature, "percent" for humidity. None means "celsius" or
+  /// "percent".
+  pub(crate) unit: Option<String>
+}
+
+impl ValueRangeFile {
+  /// Most payloads a range may make.
+  const MAX_STEPS: usize = 10000;
+
+  /// Turns a value in some unit into the one from_human_value takes, if
+  /// the unit is the sensor type's.
+  fn to_human(stype: SensorType, unit: &str, v: f64) -> Option<f64> {
+    return match (stype, unit) {
+      (SensorType::Temperature, "celsius") => Some(v),
+      (SensorType::Temperature, "kelvin") => Some(units::kelvin_to_celsius(v)),
+      (SensorType::Temperature, "fahrenheit") => {
+        Some(units::fahrenheit_to_celsius(v))
+      },
+      (SensorType::Humidity, "percent") => Some(v),
+      _ => None,
+    };
+  }
+
+  /// What the values are in, for some sensor type.
+  fn unit(&self, stype: SensorType) -> &str {
+    return match (&self.unit, stype) {
+      (Some(u), _) => u.as_str(),
+      (None, SensorType::Humidity) => "percent",
+      (None, _) => "celsius",
+    };
+  }
+
+  /// Distance between values.
+  fn step(&self) -> f64 {
+    return self.step.unwrap_or(1.0);
+  }
+
+  /// Encodes a value in the range's unit as a sensor of some type would.
+  fn payload(&self, stype: SensorType, v: f64)
+  -> Result<Vec<u8>, DummyConfigError> {
+    let unit = self.unit(stype);
+    let human = Self::to_human(stype, unit, v)
+      .ok_or_else(|| DummyConfigError::BadUnit(unit.to_owned()))?;
+    let msg = AnySensorMessage::from_human_value(stype, SensorId(0), human)
+      .ok_or_else(|| DummyConfigError::BadValues(format!(
+        "{} {} doesn't fit a {} message", v, unit, stype
+      )))?;
+    return Ok(msg.encode());
+  }
+
+  /// Where a value sits within the range, from -1 at the bottom to 1 at
+  /// the top.
+  fn level_of(&self, v: f64) -> f64 {
+    if self.max <= self.min {
+      return 0.0;
+    }
+    return 2.0 * (v - self.min) / (self.max - self.min) - 1.0;
+  }
+
+  /// The value in the range nearest to some other, on a step.
+  fn nearest(&self, v: f64) -> f64 {
+    let last = ((self.max - self.min) / self.step()).floor();
+    let k = ((v - self.min) / self.step()).round().max(0.0).min(last);
+    return self.min + k * self.step();
+  }
+
+  /// Encodes every value in the range, lowest first, as a sensor of some
+  /// type would. Values that round to the same payload are sent as one.
+  fn payloads(&self, stype: SensorType)
+  -> Result<Vec<Vec<u8>>, DummyConfigError> {
+    let step = self.step();
+    // careful with NaN, which compares false to everything.
+    let sane = step > 0.0 && self.min <= self.max;
+    if !sane {
+      return Err(DummyConfigError::BadValues(format!(
+        "range from {} to {} by {}", self.min, self.max, step
+      )));
+    }
+    let steps = ((self.max - self.min) / step).floor() as usize + 1;
+    if steps > Self::MAX_STEPS {
+      return Err(DummyConfigError::BadValues(format!(
+        "range makes {} values, more than {}", steps, Self::MAX_STEPS
+      )));
+    }
+    let mut payloads: Vec<Vec<u8>> = Vec::new();
+    for i in 0..steps {
+      payloads.push(self.payload(stype, self.min + step * i as f64)?);
+    }
+    payloads.dedup();
+    return Ok(payloads);
+  }
+}
+
+/// A daily curve for values to follow, as within the file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct DiurnalFile {
+  /// How far the curve goes from the middle of the range, either way, in
+  /// the range's unit. None means all the way to its ends.
+  pub(crate) amplitude: Option<f64>,
+  /// Hour of the simulated day the curve peaks at. None means 15.
+  pub(crate) peak_hour: Option<f64>,
+  /// How long a simulated day lasts, in seconds. None means a real day.
+  pub(crate) day_secs: Option<usize>
+}
+
+/// A daily curve for values to follow. Simulated days start at midnight,
+/// when the dummy does.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Diurnal {
+  /// How far the curve goes from the middle of the range, either way.
+  pub(crate) amplitude: f64,
+  /// Hour of the simulated day the curve peaks at.
+  pub(crate) peak_hour: f64,
+  /// How long a simulated day lasts.
+  pub(crate) day: Duration
+}
+
+impl Diurnal {
+  /// Hour of the simulated day the curve peaks at, unless told otherwise.
+  const DEFAULT_PEAK_HOUR: f64 = 15.0;
+
+  /// Fills in the blanks, for values in some range.
+  fn from_file(cfg: &DiurnalFile, range: &ValueRangeFile)
+  -> Result<Self, DummyConfigError> {
+    let day_secs = cfg.day_secs.unwrap_or(24 * 60 * 60);
+    if day_secs == 0 {
+      return Err(DummyConfigError::BadValues(
+        "a simulated day can't be over in no time".to_owned()
+      ));
+    }
+    return Ok(Self {
+      amplitude: cfg.amplitude.unwrap_or((range.max - range.min) / 2.0),
+      peak_hour: cfg.peak_hour.unwrap_or(Self::DEFAULT_PEAK_HOUR),
+      day: Duration::from_secs(day_secs as u64)
+    });
+  }
+
+  /// Where on the curve a value is, some time into the run, from -1 at the
+  /// bottom to 1 at the peak.
+  fn level(&self, elapsed: Duration) -> f64 {
+    let day = self.day.as_secs_f64();
+    let hour = 24.0 * (elapsed.as_secs_f64() % day) / day;
+    return ((hour - self.peak_hour) / 24.0 * std::f64::consts::TAU).cos();
+  }
+}
+
+/// Another dummy to track, as within the file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct FollowFile {
+  /// Its name, as in [dummies.<name>].
+  pub(crate) dummy: String,
+  /// How it's tracked: 1 goes up as it does, -1 goes down as it goes up,
+  /// and anything between less so. None means 1.
+  pub(crate) correlation: Option<f64>,
+  /// Most noise added either way, in the range's unit. None means none.
+  pub(crate) noise: Option<f64>
+}
+
+/// Another dummy to track.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Follow {
+  /// Its name.
+  pub(crate) dummy: String,
+  /// How it's tracked, from -1 to 1.
+  pub(crate) correlation: f64,
+  /// Most noise added either way.
+  pub(crate) noise: f64
+}
+
 /// Configuration for a single dummy sensor. Read from config file too.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct DummyConfigFile {
@@ -65,14 +258,51 @@ pub(crate) struct DummyConfigFile {
   pub(crate) broker_port: u16,
   /// Value selection mode.
   pub(crate) mode: String,
-  /// List of values that the dummy can output as (number, bytelen).
-  pub(crate) values: Vec<(usize, u8)>,
+  /// List of values that the dummy can output as (number, bytelen). Needed
+  /// unless there's a range.
+  pub(crate) values: Option<Vec<(usize, u8)>>,
+  /// Range of values that the dummy can output, as people read them. Goes
+  /// instead of values.
+  pub(crate) range: Option<ValueRangeFile>,
+  /// The curve the "diurnal" mode follows, within the range. None means
+  /// the default one.
+  pub(crate) diurnal: Option<DiurnalFile>,
+  /// The dummy the "follow" mode tracks. Needed by it.
+  pub(crate) follows: Option<FollowFile>,
   /// The topic/sensor type to output.
   pub(crate) topic: String,
   /// The time interval between sends.
   pub(crate) interval_msecs: usize,
   /// A jitter for the interval.
-  pub(crate) interval_jitter_msecs: usize
+  pub(crate) interval_jitter_msecs: usize,
+  /// Key to sign payloads with, as the broker has it. None means unsigned.
+  pub(crate) key: Option<String>,
+  /// MQTT keep-alive interval, in seconds. None means 5.
+  pub(crate) keep_alive_secs: Option<u16>,
+  /// How long to wait before reconnecting the first time after a failure.
+  /// Doubles with every failure in a row. None means 500.
+  pub(crate) reconnect_msecs: Option<usize>,
+  /// Longest wait before reconnecting. None means 30000.
+  pub(crate) reconnect_max_msecs: Option<usize>,
+  /// Whether to start a clean MQTT session on every connection. None means
+  /// true.
+  pub(crate) clean_session: Option<bool>,
+  /// Most QoS 1 and 2 publishes awaiting acknowledgement. None means 100.
+  pub(crate) max_inflight: Option<u16>,
+  /// Username to log in with. None means no login.
+  pub(crate) username: Option<String>,
+  /// Password to log in with.
+  pub(crate) password: Option<String>,
+  /// PEM file with the CA the broker's certificate is checked against.
+  /// None means no TLS.
+  pub(crate) ca_cert: Option<String>,
+  /// PEM file with a certificate to show the broker. Needs ca_cert and
+  /// client_key. None means none.
+  pub(crate) client_cert: Option<String>,
+  /// PEM file with the key for client_cert.
+  pub(crate) client_key: Option<String>,
+  /// What kind of key that is: "rsa" or "ecc". None means "rsa".
+  pub(crate) client_key_type: Option<String>
 }
 
 impl Default for DummyConfigFile {
@@ -81,34 +311,181 @@ impl Default for DummyConfigFile {
       broker_address: "localhost".to_owned(),
       broker_port: 9869,
       mode: "random".to_owned(),
-      values: Vec::new(),
+      values: None,
+      range: Some(ValueRangeFile {
+        min: 20.0,
+        max: 30.0,
+        step: Some(0.5),
+        unit: Some("celsius".to_owned())
+      }),
+      diurnal: None,
+      follows: None,
       topic: "<INSERT TOPIC HERE>".to_owned(),
       interval_msecs: 1000,
-      interval_jitter_msecs: 500
+      interval_jitter_msecs: 500,
+      key: None,
+      keep_alive_secs: Some(DummyConfig::DEFAULT_KEEP_ALIVE_SECS),
+      reconnect_msecs: Some(DummyConfig::DEFAULT_RECONNECT_MSECS),
+      reconnect_max_msecs: Some(DummyConfig::DEFAULT_RECONNECT_MAX_MSECS),
+      clean_session: Some(true),
+      max_inflight: Some(DummyConfig::DEFAULT_MAX_INFLIGHT),
+      username: None,
+      password: None,
+      ca_cert: None,
+      client_cert: None,
+      client_key: None,
+      client_key_type: None
     }
   }
 }
 
+/// What kind of key a client certificate has.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum ClientKeyType {
+  /// An RSA key.
+  Rsa,
+  /// An elliptic curve key.
+  Ecc
+}
+
+/// TLS settings, with the files they name read in.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct DummyTls {
+  /// The CA the broker's certificate is checked against, in PEM.
+  pub(crate) ca: Vec<u8>,
+  /// A certificate to show the broker, its key, both in PEM, and what kind
+  /// of key. None means none.
+  pub(crate) client_auth: Option<(Vec<u8>, Vec<u8>, ClientKeyType)>
+}
+
+impl DummyTls {
+  /// Reads the files a dummy's settings name in. None if there's no TLS.
+  fn from_file(cfgf: &DummyConfigFile)
+  -> Result<Option<Self>, DummyConfigError> {
+    let read = |path: &String| {
+      return std::fs::read(path).map_err(|e| {
+        DummyConfigError::BadTls(format!("can't read {}: {}", path, e))
+      });
+    };
+    let ca = match &cfgf.ca_cert {
+      Some(path) => read(path)?,
+      None if cfgf.client_cert.is_some() => {
+        return Err(DummyConfigError::BadTls(
+          "client_cert needs ca_cert".to_owned()
+        ));
+      },
+      None => return Ok(None),
+    };
+    let key_type = match cfgf.client_key_type.as_deref() {
+      None | Some("rsa") => ClientKeyType::Rsa,
+      Some("ecc") => ClientKeyType::Ecc,
+      Some(other) => return Err(DummyConfigError::BadTls(
+        format!("unknown key type \"{}\"", other)
+      )),
+    };
+    let client_auth = match (&cfgf.client_cert, &cfgf.client_key) {
+      (Some(cert), Some(key)) => Some((read(cert)?, read(key)?, key_type)),
+      (None, None) => None,
+      _ => return Err(DummyConfigError::BadTls(
+        "client_cert and client_key go together".to_owned()
+      )),
+    };
+    return Ok(Some(Self {
+      ca: ca,
+      client_auth: client_auth
+    }));
+  }
+}
+
 /// Configuration for a single dummy sensor. Read from config file too.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct DummyConfig {
+  /// What it's called in the file, as in [dummies.<name>].
+  pub(crate) name: String,
   /// The target broker's address.
   pub(crate) broker_address: String,
   /// The target broker's port.
   pub(crate) broker_port: u16,
   /// The value selection mode, parsed.
   pub(crate) mode: DummyMode,
-  /// List of payloads to send.
+  /// List of payloads to send, lowest value first.
   pub(crate) payloads: Vec<Vec<u8>>,
+  /// Range the payloads were made from. None if they were listed.
+  pub(crate) range: Option<ValueRangeFile>,
+  /// The curve to follow, in the "diurnal" mode.
+  pub(crate) diurnal: Option<Diurnal>,
+  /// The dummy to track, in the "follow" mode.
+  pub(crate) follows: Option<Follow>,
   /// The topic/sensor type to output.
   pub(crate) topic: SensorType,
   /// The time interval between sends.
   pub(crate) interval: Duration,
   /// A jitter for the interval.
-  pub(crate) interval_jitter: Duration 
+  pub(crate) interval_jitter: Duration,
+  /// Key to sign payloads with. None means unsigned.
+  pub(crate) key: Option<String>,
+  /// MQTT keep-alive interval, in seconds.
+  pub(crate) keep_alive_secs: u16,
+  /// Wait before reconnecting the first time after a failure.
+  pub(crate) reconnect: Duration,
+  /// Longest wait before reconnecting.
+  pub(crate) reconnect_max: Duration,
+  /// Whether to start a clean MQTT session on every connection.
+  pub(crate) clean_session: bool,
+  /// Most QoS 1 and 2 publishes awaiting acknowledgement.
+  pub(crate) max_inflight: u16,
+  /// Username and password to log in with. None means no login.
+  pub(crate) credentials: Option<(String, String)>,
+  /// TLS settings. None means plain TCP.
+  pub(crate) tls: Option<DummyTls>
 }
 
 impl DummyConfig {
+  /// Keep-alive interval, unless told otherwise.
+  pub(crate) const DEFAULT_KEEP_ALIVE_SECS: u16 = 5;
+  /// First wait before reconnecting, unless told otherwise.
+  pub(crate) const DEFAULT_RECONNECT_MSECS: usize = 500;
+  /// Longest wait before reconnecting, unless told otherwise.
+  pub(crate) const DEFAULT_RECONNECT_MAX_MSECS: usize = 30000;
+  /// Most publishes awaiting acknowledgement, unless told otherwise.
+  pub(crate) const DEFAULT_MAX_INFLIGHT: u16 = 100;
+
+  /// Builds the MQTT options to connect with, under some client ID.
+  pub(crate) fn mqtt_options(&self, client_id: &str) -> MqttOptions {
+    let mut opts = MqttOptions::new(
+      client_id.to_owned(),
+      &self.broker_address,
+      self.broker_port
+    );
+    opts
+      .set_keep_alive(self.keep_alive_secs)
+      .set_clean_session(self.clean_session)
+      .set_inflight(self.max_inflight);
+    if let Some((username, password)) = &self.credentials {
+      opts.set_credentials(username, password);
+    }
+    if let Some(tls) = &self.tls {
+      opts.set_ca(tls.ca.clone());
+      if let Some((cert, key, key_type)) = &tls.client_auth {
+        let key = match key_type {
+          ClientKeyType::Rsa => Key::RSA(key.clone()),
+          ClientKeyType::Ecc => Key::ECC(key.clone()),
+        };
+        opts.set_client_auth(cert.clone(), key);
+      }
+    }
+    return opts;
+  }
+
+  /// How long to wait before reconnecting, after a number of failures in a
+  /// row, counting from 1.
+  pub(crate) fn reconnect_delay(&self, failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    return self.reconnect.checked_mul(factor)
+      .unwrap_or(self.reconnect_max)
+      .min(self.reconnect_max);
+  }
+
   /// Generate an interval based on jitter and such.
   pub(crate) fn gen_interval(&self, rng: &mut ThreadRng) -> Duration {
     let range = 0u128 .. self.interval_jitter.as_millis(); 
@@ -121,18 +498,82 @@ impl DummyConfig {
     );
   }
 
-  /// Generate a random payload. Optionally override first byte (ID).
+  /// One of the payloads, and where it sits among them, from -1 for the
+  /// first to 1 for the last.
+  fn indexed(&self, i: usize) -> Option<(Vec<u8>, f64)> {
+    let last = self.payloads.len().saturating_sub(1);
+    let level = match last {
+      0 => 0.0,
+      _ => 2.0 * i as f64 / last as f64 - 1.0,
+    };
+    return self.payloads.get(i).map(|p| (p.clone(), level));
+  }
+
+  /// The payload for the value in the range nearest to some other, and
+  /// where it sits within the range.
+  fn valued(&self, v: f64) -> Option<(Vec<u8>, f64)> {
+    let range = self.range.as_ref()?;
+    let v = range.nearest(v);
+    return range.payload(self.topic, v).ok().map(|p| (p, range.level_of(v)));
+  }
+
+  /// The value for some time into the run, along the curve.
+  fn diurnal_value(&self, elapsed: Duration) -> Option<f64> {
+    let (range, curve) = (self.range.as_ref()?, self.diurnal.as_ref()?);
+    let mid = (range.min + range.max) / 2.0;
+    return Some(mid + curve.amplitude * curve.level(elapsed));
+  }
+
+  /// The value tracking another dummy's level, with some noise. The middle
+  /// of the range, give or take the noise, until it has one.
+  fn following_value(&self, leader: Option<f64>, rng: &mut ThreadRng)
+  -> Option<f64> {
+    let (range, follow) = (self.range.as_ref()?, self.follows.as_ref()?);
+    let mid = (range.min + range.max) / 2.0;
+    let half = (range.max - range.min) / 2.0;
+    let noise = if follow.noise > 0.0 {
+      rng.gen_range(-follow.noise..=follow.noise)
+    } else {
+      0.0
+    };
+    let level = follow.correlation * leader.unwrap_or(0.0);
+    return Some(mid + half * level + noise);
+  }
+
+  /// Generate a payload, as per the mode, some time into the run, given
+  /// where the dummy it follows is, if it does. Optionally override first
+  /// byte (ID). Signed if there's a key. Also returns where the payload
+  /// sits within the range, from -1 to 1, for others to follow.
   pub(crate) fn gen_payload(
-    &self, id_override: Option<u8>, rng: &mut ThreadRng
-  ) -> Vec<u8> {
-    let mut payload = self.payloads.choose(rng).unwrap().clone();
+    &self,
+    id_override: Option<u8>,
+    elapsed: Duration,
+    leader: Option<f64>,
+    rng: &mut ThreadRng
+  ) -> (Vec<u8>, f64) {
+    let n = self.payloads.len();
+    let (mut payload, level) = match self.mode {
+      DummyMode::ConstantMin => self.indexed(0),
+      DummyMode::ConstantMax => self.indexed(n - 1),
+      DummyMode::Random => self.indexed(rng.gen_range(0..n)),
+      DummyMode::Diurnal => {
+        self.diurnal_value(elapsed).and_then(|v| self.valued(v))
+      },
+      DummyMode::Follow => {
+        self.following_value(leader, rng).and_then(|v| self.valued(v))
+      },
+    }.unwrap();
     if let Some(b) = id_override {
       if payload.len() > 0 {
         payload.remove(0);
         payload.insert(0, b);
       }
     }
-    return payload;
+    if let Some(key) = &self.key {
+      let topic = self.topic.to_string();
+      payload = signing::sign(key.as_bytes(), &topic, &payload);
+    }
+    return (payload, level);
   }
 }
 
@@ -144,7 +585,15 @@ pub(crate) enum DummyConfigError {
   /// Sensor type string not recognized.
   BadSensorType(String),
   /// Bad value mode.
-  BadModeName(String)
+  BadModeName(String),
+  /// A dummy following one that isn't there, or itself.
+  BadFollow(String),
+  /// Neither values nor a range, or a range that's no good.
+  BadValues(String),
+  /// Unit not recognized, or not one of the sensor type's.
+  BadUnit(String),
+  /// TLS files that can't be read, or settings that don't go together.
+  BadTls(String)
 }
 
 impl std::error::Error for DummyConfigError {}
@@ -161,6 +610,18 @@ impl Display for DummyConfigError {
       DummyConfigError::BadModeName(s) => {
         return write!(f, "Bad sensor mode \"{}\"!", s);
       },
+      DummyConfigError::BadFollow(s) => {
+        return write!(f, "Can't follow dummy \"{}\"!", s);
+      },
+      DummyConfigError::BadValues(s) => {
+        return write!(f, "Bad values: {}!", s);
+      },
+      DummyConfigError::BadUnit(s) => {
+        return write!(f, "Bad unit \"{}\"!", s);
+      },
+      DummyConfigError::BadTls(s) => {
+        return write!(f, "Bad TLS settings: {}!", s);
+      },
     }
   }
 }
@@ -171,33 +632,93 @@ impl From<ConfigError> for DummyConfigError {
   }
 }
 
-impl TryFrom<DummyConfigFile> for DummyConfig {
+impl TryFrom<(String, DummyConfigFile)> for DummyConfig {
   type Error = DummyConfigError;
-  fn try_from(cfgf: DummyConfigFile) -> Result<Self, Self::Error> {
+  fn try_from((name, cfgf): (String, DummyConfigFile))
+  -> Result<Self, Self::Error> {
+    let topic = SensorType::from_str(&cfgf.topic)
+      .map_err(|_| DummyConfigError::BadSensorType(cfgf.topic.clone()))?;
+    let payloads = match (&cfgf.values, &cfgf.range) {
+      (Some(values), None) => {
+        let mut values = values.clone();
+        values.sort_by_key(|(v, _)| *v);
+        values.into_iter()
+          .map(|(v, bl): (usize, u8)| {
+            // weirdo routine to convert usize to zero-padded Vec<u8>
+            let mut vec = v.to_le_bytes().to_vec();
+            vec.truncate(bl.into());
+            while vec.len() < bl.into() {
+              vec.insert(0, 0);
+            }
+            vec.reverse();
+            return vec;
+          })
+          .collect()
+      },
+      (None, Some(range)) => range.payloads(topic)?,
+      _ => return Err(DummyConfigError::BadValues(
+        "give either values or a range".to_owned()
+      )),
+    };
+    if payloads.is_empty() {
+      return Err(DummyConfigError::BadValues("nothing to send".to_owned()));
+    }
+    let mode = DummyMode::from_str(&cfgf.mode)
+      .map_err(|_| DummyConfigError::BadModeName(cfgf.mode.clone()))?;
+    let diurnal = match (mode, &cfgf.range) {
+      (DummyMode::Diurnal, Some(range)) => Some(Diurnal::from_file(
+        cfgf.diurnal.as_ref().unwrap_or(&DiurnalFile {
+          amplitude: None,
+          peak_hour: None,
+          day_secs: None
+        }),
+        range
+      )?),
+      (DummyMode::Diurnal, None) => return Err(DummyConfigError::BadValues(
+        "the diurnal mode needs a range".to_owned()
+      )),
+      _ => None,
+    };
+    let follows = match (mode, &cfgf.follows, &cfgf.range) {
+      (DummyMode::Follow, Some(f), Some(_)) => Some(Follow {
+        dummy: f.dummy.clone(),
+        correlation: f.correlation.unwrap_or(1.0).clamp(-1.0, 1.0),
+        noise: f.noise.unwrap_or(0.0).abs()
+      }),
+      (DummyMode::Follow, _, _) => return Err(DummyConfigError::BadValues(
+        "the follow mode needs a range and a dummy to follow".to_owned()
+      )),
+      _ => None,
+    };
+    let tls = DummyTls::from_file(&cfgf)?;
+    let credentials = cfgf.username.clone()
+      .map(|u| (u, cfgf.password.clone().unwrap_or_default()));
     return Ok(Self {
+      name: name,
       broker_address: cfgf.broker_address.clone(),
       broker_port: cfgf.broker_port,
-      mode: DummyMode::from_str(&cfgf.mode)
-        .map_err(|_| DummyConfigError::BadModeName(cfgf.mode.clone()))?,
-      payloads: cfgf.values.clone()
-        .into_iter()
-        .map(|(v, bl): (usize, u8)| {
-          // weirdo routine to convert usize to zero-padded Vec<u8>
-          let mut vec = v.to_le_bytes().to_vec();
-          vec.truncate(bl.into());
-          while vec.len() < bl.into() {
-            vec.insert(0, 0);
-          }
-          vec.reverse();
-          return vec;
-        })
-        .collect(),
-      topic: SensorType::from_str(&cfgf.topic)
-        .map_err(|_| DummyConfigError::BadSensorType(cfgf.topic.clone()))?,
+      mode: mode,
+      payloads: payloads,
+      range: cfgf.range.clone(),
+      diurnal: diurnal,
+      follows: follows,
+      topic: topic,
       interval: Duration::from_millis(cfgf.interval_msecs as u64),
       interval_jitter: Duration::from_millis(
         cfgf.interval_jitter_msecs as u64
       ),
+      key: cfgf.key,
+      keep_alive_secs: cfgf.keep_alive_secs
+        .unwrap_or(DummyConfig::DEFAULT_KEEP_ALIVE_SECS),
+      reconnect: Duration::from_millis(cfgf.reconnect_msecs
+        .unwrap_or(DummyConfig::DEFAULT_RECONNECT_MSECS) as u64),
+      reconnect_max: Duration::from_millis(cfgf.reconnect_max_msecs
+        .unwrap_or(DummyConfig::DEFAULT_RECONNECT_MAX_MSECS) as u64),
+      clean_session: cfgf.clean_session.unwrap_or(true),
+      max_inflight: cfgf.max_inflight
+        .unwrap_or(DummyConfig::DEFAULT_MAX_INFLIGHT),
+      credentials: credentials,
+      tls: tls,
     });
   }
 }
@@ -205,25 +726,70 @@ impl TryFrom<DummyConfigFile> for DummyConfig {
 /// Config file for multiple dummies.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct MultiDummyConfigFile {
-  dummies: HashMap<String, DummyConfigFile>
+  dummies: HashMap<String, DummyConfigFile>,
+  /// How long to publish for, in seconds. None means until they fail.
+  run_secs: Option<usize>,
+  /// Where to write a JSON summary of the run at exit, or "-" for stdout.
+  /// None means nowhere.
+  summary_file: Option<String>
+}
+
+/// Where the run summary goes.
+#[derive(Clone, Debug)]
+pub(crate) enum SummaryTarget {
+  /// Printed, after everything else.
+  Stdout,
+  /// Written to a file, replacing whatever was there.
+  File(PathBuf)
+}
+
+/// Configuration for multiple dummies, and the run as a whole.
+#[derive(Clone, Debug)]
+pub(crate) struct MultiDummyConfig {
+  /// The dummies.
+  pub(crate) dummies: Vec<DummyConfig>,
+  /// How long to publish for. None means until they fail.
+  pub(crate) run_for: Option<Duration>,
+  /// Where the run summary goes. None means nowhere.
+  pub(crate) summary: Option<SummaryTarget>
 }
 
-impl TryFrom<MultiDummyConfigFile> for Vec<DummyConfig> {
+impl TryFrom<MultiDummyConfigFile> for MultiDummyConfig {
   type Error = DummyConfigError;
 
   fn try_from(m: MultiDummyConfigFile) -> Result<Self, Self::Error> {
-    let mut vec = Self::new();
-    for (_, dcf) in m.dummies {
-      let dc = DummyConfig::try_from(dcf)?;
+    let mut vec = Vec::new();
+    for (name, dcf) in m.dummies.clone() {
+      if let Some(f) = &dcf.follows {
+        if f.dummy == name || !m.dummies.contains_key(&f.dummy) {
+          return Err(DummyConfigError::BadFollow(f.dummy.clone()));
+        }
+      }
+      let dc = DummyConfig::try_from((name, dcf))?;
       vec.push(dc);
     }
-    return Ok(vec);
+    return Ok(Self {
+      dummies: vec,
+      run_for: m.run_secs.map(|secs| Duration::from_secs(secs as u64)),
+      summary: m.summary_file.map(|f| match f.as_str() {
+        "-" => SummaryTarget::Stdout,
+        _ => SummaryTarget::File(PathBuf::from(f)),
+      })
+    });
   }
 }
 
-pub(crate) fn load_multi() -> Result<Vec<DummyConfig>, DummyConfigError> {
+/// Reads every dummy from cdp_dummy.toml, unless another file is given.
+pub(crate) fn load_multi(given: Option<&Path>)
+-> Result<MultiDummyConfig, DummyConfigError> {
   let mut cfg = Config::default();
-  cfg.merge(config::File::with_name("cdp_dummy"))?;
+  cfg.merge(runtime::config_file("cdp_dummy", given))?;
+  return load_multi_from(cfg);
+}
+
+/// Reads every dummy from wherever the configuration was merged from.
+pub(crate) fn load_multi_from(cfg: Config)
+-> Result<MultiDummyConfig, DummyConfigError> {
   let multi: MultiDummyConfigFile = cfg.try_into()?;
   return Ok(multi.try_into()?);
 }
@@ -1,55 +1,147 @@
 //! Implements a single dummy sensor.
 
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread::{self, JoinHandle};
-use rumqttc::{MqttOptions, Client, QoS};
+use std::time::{Duration, Instant};
+use rumqttc::{Client, Event, Packet, QoS};
+use serde::Serialize;
 
 use crate::config::DummyConfig;
 
+/// How many publishes may wait to go out before publishing blocks.
+const CHANNEL_CAPACITY: usize = 10;
+
+/// Where a dummy's latest payload sat within its range, from -1 at the
+/// bottom to 1 at the top, for others to follow. Thread-safe.
+#[derive(Debug)]
+pub(crate) struct Level(AtomicU64);
+
+impl Level {
+  /// A level that isn't known yet.
+  pub(crate) fn new() -> Self {
+    return Self(AtomicU64::new(f64::NAN.to_bits()));
+  }
+
+  /// The latest level, if there's been a payload.
+  fn get(&self) -> Option<f64> {
+    let level = f64::from_bits(self.0.load(Ordering::Relaxed));
+    return if level.is_nan() { None } else { Some(level) };
+  }
+
+  /// Notes a payload's level.
+  fn set(&self, level: f64) {
+    self.0.store(level.to_bits(), Ordering::Relaxed);
+  }
+}
+
+/// How a dummy's run went.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct DummySummary {
+  /// What it went by in the logs.
+  pub(crate) name: String,
+  /// The topic it published to.
+  pub(crate) topic: String,
+  /// Publishes handed to the client.
+  pub(crate) sent: usize,
+  /// Publishes the client turned down.
+  pub(crate) failed: usize,
+  /// Times the connection failed.
+  pub(crate) connection_errors: usize,
+  /// How long it published for.
+  pub(crate) duration_secs: f64,
+  /// Publishes sent per second, on average.
+  pub(crate) rate_per_sec: f64
+}
+
 /// A dummy and its whole state.
 pub(crate) struct Dummy {
   /// A copy of the dummy config.
   pub(crate) cfg: DummyConfig,
   /// A byte to override the first byte of payloads (sensor ID).
   pub(crate) id_override: Option<u8>,
-  /// A handle for the inner thread. Counts ok and fails.
-  thread: Option<JoinHandle<(usize, usize)>>
+  /// How long to publish for. None means until it fails.
+  run_for: Option<Duration>,
+  /// Times the connection failed.
+  connection_errors: usize,
+  /// Where its payloads sit, for others to follow.
+  level: Arc<Level>,
+  /// Where the payloads of the dummy it follows sit. None if it follows
+  /// none.
+  leader: Option<Arc<Level>>,
+  /// A handle for the inner thread. Counts ok and fails, and how long it
+  /// ran.
+  thread: Option<JoinHandle<(usize, usize, Duration)>>
 }
 
 impl Dummy {
-  /// Construct a dummy.
-  pub(crate) fn construct(cfg: DummyConfig, id_override: Option<u8>) -> Self {
+  /// Construct a dummy, to publish for so long, if given.
+  pub(crate) fn construct(
+    cfg: DummyConfig, id_override: Option<u8>, run_for: Option<Duration>
+  ) -> Self {
     return Self {
       cfg: cfg,
       id_override: id_override,
+      run_for: run_for,
+      connection_errors: 0,
+      level: Arc::new(Level::new()),
+      leader: None,
       thread: None
     }
   }
 
+  /// Where its payloads sit, for others to follow.
+  pub(crate) fn level(&self) -> Arc<Level> {
+    return self.level.clone();
+  }
+
+  /// Has it follow another dummy, given where that one's payloads sit.
+  pub(crate) fn follow(&mut self, leader: Arc<Level>) {
+    self.leader = Some(leader);
+  }
+
+  /// What it goes by in the logs.
+  fn name(&self) -> String {
+    let idname = self.id_override
+      .map(|s| s.to_string())
+      .unwrap_or("?".to_owned());
+    return format!("dummy-{}", idname);
+  }
+
   /// Returns true if the join handle is started.
   pub(crate) fn is_running(&self) -> bool {
     return self.thread.is_some();
   }
   
-  /// Starts this dummy's thread and sets up the join handle.
+  /// Starts this dummy's thread and sets up the join handle, then keeps the
+  /// connection going, reconnecting after failures, waiting longer each
+  /// time. Returns once the thread stops publishing, for having run long
+  /// enough or for failing.
   pub(crate) fn start(&mut self) {
     if self.is_running() { return; }
     let cfg = self.cfg.clone();
     let cid = self.id_override.clone();
-    let idname = cid.map(|s| s.to_string()).unwrap_or("?".to_owned());
-    let name = format!("dummy-{}", idname);
+    let run_for = self.run_for;
+    let (level, leader) = (self.level.clone(), self.leader.clone());
+    let name = self.name();
     let outername = name.clone();
-    let mut opts = MqttOptions::new(
-      name.to_owned(),
-      &cfg.broker_address,
-      cfg.broker_port
-    );
-    opts.set_keep_alive(5);
-    let (mut client, mut cxn) = Client::new(opts, 10);
+    let opts = cfg.mqtt_options(&name);
+    let (mut client, mut cxn) = Client::new(opts, CHANNEL_CAPACITY);
     self.thread = Some(thread::spawn(move || {
       let (mut oks, mut fails): (usize, usize) = (0, 0);  
       let mut rng = rand::thread_rng();
+      let started = Instant::now();
       loop {
-        let pld = cfg.gen_payload(cid, &mut rng);
+        if run_for.map(|d| started.elapsed() >= d).unwrap_or(false) {
+          break;
+        }
+        let (pld, at) = cfg.gen_payload(
+          cid,
+          started.elapsed(),
+          leader.as_ref().and_then(|l| l.get()),
+          &mut rng
+        );
+        level.set(at);
         let res = client.publish(
           cfg.topic.to_string(),
           QoS::AtMostOnce,
@@ -58,7 +150,7 @@ impl Dummy {
         );
         match res {
           Ok(_) => {
-            println!(
+            info!(
               "[{}] Sent {} data to the broker successfully!",
               &name,
               &cfg.topic
@@ -66,7 +158,7 @@ impl Dummy {
             oks += 1;
           },
           Err(ce) => {
-            eprintln!(
+            warn!(
               "[{}] Failed to send data (ClientError): {}",
               name,
               &ce
@@ -77,30 +169,60 @@ impl Dummy {
         };
         thread::sleep(cfg.gen_interval(&mut rng));
       }
-      return (oks, fails);
+      return (oks, fails, started.elapsed());
     }));
-    println!("[{}] Started!", &outername);
+    info!("[{}] Started!", &outername);
     let mut cxn_errs = 0;
-    for (_, nxn) in cxn.iter().enumerate() {
-      if nxn.is_err() {
-        cxn_errs += 1;
-        if cxn_errs > 10 {
-          break;
-        }
-      }
+    for nxn in cxn.iter() {
+      match nxn {
+        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+          info!("[{}] Connected!", &outername);
+          cxn_errs = 0;
+        },
+        Ok(_) => (),
+        Err(ce) => {
+          self.connection_errors += 1;
+          let finished = self.thread.as_ref()
+            .map(|t| t.is_finished())
+            .unwrap_or(true);
+          if finished {
+            // no one's left to publish, so no need to reconnect.
+            break;
+          }
+          cxn_errs += 1;
+          let delay = self.cfg.reconnect_delay(cxn_errs);
+          warn!(
+            "[{}] Connection failed: {}. Reconnecting in {:?}...",
+            &outername,
+            &ce,
+            delay
+          );
+          thread::sleep(delay);
+        },
+      };
     }
-    panic!("[{}] stopped due to max errors!", &outername);
+    info!("[{}] Stopped.", &outername);
   }
 
-  /// Wait on the dummy.
-  pub(crate) fn join(&mut self) -> (usize, usize) {
-    if self.thread.is_some() {
+  /// Wait on the dummy, and sum its run up.
+  pub(crate) fn join(&mut self) -> DummySummary {
+    let (sent, failed, ran) = if self.thread.is_some() {
       let jh = self.thread.take().unwrap();
-      return jh
+      jh
         .join()
-        .expect("Could not acquire JoinHandle result! Did the thread die?");
+        .expect("Could not acquire JoinHandle result! Did the thread die?")
     } else {
-      return (0, 0);
-    }
+      (0, 0, Duration::default())
+    };
+    let secs = ran.as_secs_f64();
+    return DummySummary {
+      name: self.name(),
+      topic: self.cfg.topic.to_string(),
+      sent: sent,
+      failed: failed,
+      connection_errors: self.connection_errors,
+      duration_secs: secs,
+      rate_per_sec: if secs > 0.0 { sent as f64 / secs } else { 0.0 }
+    };
   }
 }
@@ -0,0 +1,90 @@
+//! Dummy sensors, publishing made-up readings to a broker.
+
+#[macro_use]
+extern crate libcdp;
+
+use std::collections::HashMap;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use crate::config::MultiDummyConfig;
+use crate::dummy::Dummy;
+use crate::summary::RunSummary;
+
+mod config;
+mod dummy;
+mod summary;
+
+/// What the command line takes.
+const USAGE: &str = "\
+Usage: cdp_dummy [options]
+
+  -c, --config <path> the config file (cdp_dummy.toml in CDP_CONFIG_DIR, or
+                      here)";
+
+/// Runs the dummies with the configuration in source, laid out like
+/// cdp_dummy.toml, until they're done. For running them in the same process
+/// as other things, like cdp_all does.
+pub fn run(source: ::config::Config) {
+  let multi = config::load_multi_from(source)
+    .unwrap_or_else(|err| panic!("Configuration tragedy: {}", err));
+  run_multi(multi);
+}
+
+/// Dummy entry point. Read config, and start.
+pub fn main() {
+  libcdp::runtime::init("cdp_dummy");
+  let mut args: Vec<String> = std::env::args().skip(1).collect();
+  let given = libcdp::runtime::config_arg(&mut args)
+    .and_then(|given| match args.first() {
+      Some(arg) => Err(format!("Unknown argument \"{}\".", arg)),
+      None => Ok(given),
+    })
+    .unwrap_or_else(|e| {
+      eprintln!("{}\n\n{}", e, USAGE);
+      std::process::exit(2);
+    });
+  info!("Hey! Loading config...");
+  let multi = config::load_multi(given.as_deref())
+    .unwrap_or_else(|err| panic!("Configuration tragedy: {}", err));
+  run_multi(multi);
+}
+
+/// Starts every dummy, waits for them all, and sums their runs up.
+fn run_multi(multi: MultiDummyConfig) {
+  let mut dummies: Vec<JoinHandle<Dummy>> = Vec::new();
+  info!(
+    "Configuration loaded! Starting {} dummies...", multi.dummies.len()
+  );
+  let mut built: Vec<Dummy> = multi.dummies.iter()
+    .enumerate()
+    .map(|(i, cfg)| Dummy::construct(cfg.clone(), Some(i as u8), multi.run_for))
+    .collect();
+  // link followers up to whoever they follow, by name.
+  let levels: HashMap<String, _> = built.iter()
+    .map(|d| (d.cfg.name.clone(), d.level()))
+    .collect();
+  for dummy in built.iter_mut() {
+    let leader = dummy.cfg.follows.as_ref().map(|f| levels[&f.dummy].clone());
+    if let Some(leader) = leader {
+      dummy.follow(leader);
+    }
+  }
+  let started = Instant::now();
+  for mut dummy in built {
+    let jh = thread::spawn(move || { (&mut dummy).start(); dummy });
+    dummies.push(jh);
+  }
+  let mut summaries = Vec::new();
+  for dummy in dummies {
+    summaries.push(dummy.join().unwrap().join());
+  }
+  let summary = RunSummary::new(summaries, started.elapsed());
+  let (oks, fails) = summary.totals();
+  info!("All dummies finidhed! Sent {} and failed {}.", oks, fails);
+  if let Some(target) = &multi.summary {
+    if let Err(e) = summary.write(target) {
+      warn!("Couldn't write the run summary: {}", e);
+    }
+  }
+}
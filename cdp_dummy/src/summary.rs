@@ -0,0 +1,57 @@
+//! A machine-readable summary of a run, for tests and benchmarks to check.
+
+use std::io::{self, Write};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::config::SummaryTarget;
+use crate::dummy::DummySummary;
+
+/// How a whole run went.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct RunSummary {
+  /// How long the run took, start to finish.
+  duration_secs: f64,
+  /// Publishes handed to the clients, by every dummy.
+  sent: usize,
+  /// Publishes the clients turned down, for every dummy.
+  failed: usize,
+  /// Times connections failed, for every dummy.
+  connection_errors: usize,
+  /// Publishes sent per second, on average, by every dummy together.
+  rate_per_sec: f64,
+  /// How each dummy's run went.
+  dummies: Vec<DummySummary>
+}
+
+impl RunSummary {
+  /// Adds up the dummies' runs, over how long the whole thing took.
+  pub(crate) fn new(dummies: Vec<DummySummary>, took: Duration) -> Self {
+    let secs = took.as_secs_f64();
+    let sent = dummies.iter().map(|d| d.sent).sum();
+    return Self {
+      duration_secs: secs,
+      sent: sent,
+      failed: dummies.iter().map(|d| d.failed).sum(),
+      connection_errors: dummies.iter().map(|d| d.connection_errors).sum(),
+      rate_per_sec: if secs > 0.0 { sent as f64 / secs } else { 0.0 },
+      dummies: dummies
+    };
+  }
+
+  /// Publishes sent, and failed, by every dummy.
+  pub(crate) fn totals(&self) -> (usize, usize) {
+    return (self.sent, self.failed);
+  }
+
+  /// Writes it where it goes, as JSON.
+  pub(crate) fn write(&self, target: &SummaryTarget) -> io::Result<()> {
+    let mut json = serde_json::to_string_pretty(self)?;
+    json.push('\n');
+    return match target {
+      SummaryTarget::Stdout => io::stdout().write_all(json.as_bytes()),
+      SummaryTarget::File(path) => std::fs::write(path, json),
+    };
+  }
+}
@@ -0,0 +1,13 @@
+//! Generates the client for the API's gRPC service, whose definition lives
+//! with the API.
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  tonic_build::configure()
+    .build_server(false)
+    .extern_path(".cdp", "::libcdp::proto::pb")
+    .compile(
+      &["../cdp_api/proto/cdp_api.proto"],
+      &["../cdp_api/proto", "../libcdp/proto"]
+    )?;
+  return Ok(());
+}
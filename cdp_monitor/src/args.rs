@@ -0,0 +1,78 @@
+//! What the command line says.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What the command line takes.
+pub(crate) const USAGE: &str = "\
+Usage: cdpmon [options]
+
+Shows every sensor's latest reading, how it went lately, the brokers and the
+latest alerts, live, until q is pressed.
+
+Options:
+  --api <url>         the API's base URL, else $CDP_API, else
+                      http://localhost:9869/
+  --grpc <url>        the API's gRPC address, for live readings, else
+                      $CDP_GRPC, else http://localhost:9870/
+  --refresh <secs>    how often to ask about brokers and alerts (15)
+  --history <n>       readings per sensor to draw a sparkline of (60)
+  -h, --help          print this and quit";
+
+/// Whatever the command line says.
+#[derive(Clone, Debug)]
+pub(crate) struct Args {
+  /// --api.
+  pub(crate) api: Option<String>,
+  /// --grpc.
+  pub(crate) grpc: Option<String>,
+  /// --refresh.
+  pub(crate) refresh: Duration,
+  /// --history.
+  pub(crate) history: usize,
+  /// Whether to print the usage and quit instead.
+  pub(crate) help: bool
+}
+
+/// Parses a value, saying which one was bad if it is.
+fn value<T: FromStr>(what: &str, s: Option<String>) -> Result<T, String> {
+  let s = s.ok_or_else(|| format!("Missing {}.", what))?;
+  return s.parse().map_err(|_| format!("Bad {} \"{}\".", what, s));
+}
+
+impl Args {
+  /// Parses the arguments, without the program name.
+  pub(crate) fn parse<I: Iterator<Item = String>>(args: I)
+  -> Result<Self, String> {
+    let mut args = args;
+    let mut parsed = Self {
+      api: None,
+      grpc: None,
+      refresh: Duration::from_secs(15),
+      history: 60,
+      help: false
+    };
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "-h" | "--help" => parsed.help = true,
+        "--api" => parsed.api = Some(value("API URL", args.next())?),
+        "--grpc" => parsed.grpc = Some(value("gRPC URL", args.next())?),
+        "--refresh" => {
+          let secs: f64 = value("refresh interval", args.next())?;
+          if !secs.is_finite() || secs <= 0.0 {
+            return Err("The refresh interval must be positive.".to_owned());
+          }
+          parsed.refresh = Duration::from_secs_f64(secs);
+        },
+        "--history" => {
+          parsed.history = value("history length", args.next())?;
+          if parsed.history == 0 {
+            return Err("The history can't be empty.".to_owned());
+          }
+        },
+        _ => return Err(format!("Unknown argument \"{}\".", arg)),
+      };
+    }
+    return Ok(parsed);
+  }
+}
@@ -0,0 +1,152 @@
+//! Where what's on screen comes from: live readings over gRPC, the rest
+//! asked for over HTTP every so often, and keys pressed. All of it ends up
+//! as events on one channel.
+
+use std::convert::TryFrom;
+use std::thread;
+use std::time::Duration;
+
+use cdp_client::ApiClient;
+use cdp_client::views::{Alert, Broker, RegisteredSensor};
+use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind, KeyModifiers};
+use libcdp::comm::broker_api::BrokerMessage;
+use tokio::sync::mpsc::Sender;
+
+/// Generated from cdp_api's proto/cdp_api.proto.
+mod proto {
+  tonic::include_proto!("cdp_api");
+}
+
+use proto::ingest_client::IngestClient;
+
+/// How long to wait before reconnecting the first time. It doubles with
+/// every failure.
+const MIN_RETRY: Duration = Duration::from_secs(1);
+
+/// Most time to wait before reconnecting.
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+/// How the live stream is doing.
+#[derive(Clone, Debug)]
+pub(crate) enum StreamState {
+  /// Trying to get through.
+  Connecting,
+  /// Readings are coming in as they're stored.
+  Live,
+  /// Lost it, for this reason. Will try again.
+  Down(String)
+}
+
+/// Something that changes what's on screen.
+#[derive(Debug)]
+pub(crate) enum Event {
+  /// A message, as it was stored.
+  Message(Box<BrokerMessage>),
+  /// The latest message of every sensor.
+  Current(Vec<BrokerMessage>),
+  /// The live stream came, or went.
+  Stream(StreamState),
+  /// The brokers, as of now.
+  Brokers(Vec<Broker>),
+  /// The alerts, newest first.
+  Alerts(Vec<Alert>),
+  /// The sensors somebody named, or put in a room.
+  Sensors(Vec<RegisteredSensor>),
+  /// A call to the API didn't work out.
+  Problem(String),
+  /// The terminal changed size.
+  Resize,
+  /// Somebody wants out.
+  Quit
+}
+
+/// Follows the stream until it ends, sending what comes in.
+async fn follow(addr: &str, tx: &Sender<Event>, delay: &mut Duration)
+-> Result<(), String> {
+  let mut client = IngestClient::connect(addr.to_owned()).await
+    .map_err(|e| e.to_string())?;
+  let req = proto::SubscribeRequest { sensor_types: Vec::new() };
+  let mut stream = client.subscribe(req).await
+    .map_err(|s| s.message().to_owned())?
+    .into_inner();
+  let _ = tx.send(Event::Stream(StreamState::Live)).await;
+  *delay = MIN_RETRY;
+  loop {
+    let pb = match stream.message().await {
+      Ok(Some(pb)) => pb,
+      Ok(None) => return Err("The API hung up.".to_owned()),
+      Err(s) => return Err(s.message().to_owned()),
+    };
+    // a message we can't read is no reason to drop the stream
+    if let Ok(msg) = BrokerMessage::try_from(pb) {
+      if tx.send(Event::Message(Box::new(msg))).await.is_err() {
+        return Ok(());
+      }
+    }
+  }
+}
+
+/// Keeps the live stream going, reconnecting whenever it drops, for as long
+/// as somebody listens.
+pub(crate) async fn stream(addr: String, tx: Sender<Event>) {
+  let mut delay = MIN_RETRY;
+  while !tx.is_closed() {
+    let _ = tx.send(Event::Stream(StreamState::Connecting)).await;
+    if let Err(e) = follow(&addr, &tx, &mut delay).await {
+      let _ = tx.send(Event::Stream(StreamState::Down(e))).await;
+    }
+    tokio::time::sleep(delay).await;
+    delay = std::cmp::min(delay * 2, MAX_RETRY);
+  }
+}
+
+/// Asks for everything the stream doesn't carry, every so often, for as
+/// long as somebody listens. The latest readings too, so none are missed
+/// while the stream is down.
+pub(crate) async fn poll(
+  client: ApiClient, every: Duration, tx: Sender<Event>
+) {
+  while !tx.is_closed() {
+    let (current, brokers, alerts, sensors) = tokio::join!(
+      client.current(), client.brokers(), client.alerts(None),
+      client.sensors()
+    );
+    let events = vec![
+      current.map(Event::Current),
+      brokers.map(Event::Brokers),
+      alerts.map(Event::Alerts),
+      sensors.map(Event::Sensors)
+    ];
+    for ev in events {
+      let ev = ev.unwrap_or_else(|e| Event::Problem(e.to_string()));
+      let _ = tx.send(ev).await;
+    }
+    tokio::time::sleep(every).await;
+  }
+}
+
+/// Reads keys on a thread of its own, since crossterm blocks. q, Esc and
+/// Ctrl-C quit.
+pub(crate) fn keys(tx: Sender<Event>) {
+  thread::spawn(move || {
+    loop {
+      let ev = match event::read() {
+        Ok(TermEvent::Key(k)) if k.kind == KeyEventKind::Press => {
+          let ctrl_c = k.code == KeyCode::Char('c')
+            && k.modifiers.contains(KeyModifiers::CONTROL);
+          match k.code {
+            KeyCode::Char('q') | KeyCode::Esc => Event::Quit,
+            _ if ctrl_c => Event::Quit,
+            _ => continue,
+          }
+        },
+        Ok(TermEvent::Resize(_, _)) => Event::Resize,
+        Ok(_) => continue,
+        Err(_) => Event::Quit,
+      };
+      if tx.blocking_send(ev).is_err() {
+        return;
+      }
+    }
+  });
+}
@@ -0,0 +1,89 @@
+//! cdpmon: a live dashboard for a terminal, like the one on the small screen
+//! in the hallway. Readings come in over the API's gRPC stream; brokers and
+//! alerts are asked for over HTTP every so often. See USAGE in args.rs.
+
+mod args;
+mod feed;
+mod state;
+mod ui;
+
+use std::time::Duration;
+
+use cdp_client::{ApiClient, ClientOptions};
+use tokio::sync::mpsc;
+use url::Url;
+
+use crate::args::{Args, USAGE};
+use crate::feed::Event;
+use crate::state::Dashboard;
+
+/// Where the API is, unless told otherwise.
+const DEFAULT_API: &str = "http://localhost:9869/";
+
+/// Where the API's gRPC service is, unless told otherwise.
+const DEFAULT_GRPC: &str = "http://localhost:9870/";
+
+/// How many events may wait to be drawn.
+const EVENT_QUEUE: usize = 1024;
+
+/// A base URL, with the / that paths get joined after.
+fn base_url(s: &str) -> Result<Url, String> {
+  let s = if s.ends_with('/') { s.to_owned() } else { format!("{}/", s) };
+  return Url::parse(&s).map_err(|e| format!("Bad API URL \"{}\": {}", s, e));
+}
+
+#[tokio::main]
+async fn main() {
+  let args = Args::parse(std::env::args().skip(1))
+    .unwrap_or_else(|e| {
+      eprintln!("{}\n\n{}", e, USAGE);
+      std::process::exit(2);
+    });
+  if args.help {
+    println!("{}", USAGE);
+    return;
+  }
+  let api = args.api
+    .or_else(|| std::env::var("CDP_API").ok())
+    .unwrap_or_else(|| DEFAULT_API.to_owned());
+  let base = base_url(&api).unwrap_or_else(|e| {
+    eprintln!("{}", e);
+    std::process::exit(2);
+  });
+  let grpc = args.grpc
+    .or_else(|| std::env::var("CDP_GRPC").ok())
+    .unwrap_or_else(|| DEFAULT_GRPC.to_owned());
+  // no point retrying, it asks again soon enough
+  let client = ApiClient::new(base, ClientOptions {
+    retries: 0,
+    timeout: Some(Duration::from_secs(10)),
+    ..ClientOptions::default()
+  });
+
+  let (tx, mut rx) = mpsc::channel(EVENT_QUEUE);
+  tokio::spawn(feed::poll(client, args.refresh, tx.clone()));
+  tokio::spawn(feed::stream(grpc, tx.clone()));
+  feed::keys(tx);
+
+  let mut dash = Dashboard::new(args.history);
+  let mut terminal = ratatui::init();
+  // redraw every second anyway, so ages and the clock move along
+  let mut tick = tokio::time::interval(Duration::from_secs(1));
+  let res = loop {
+    if let Err(e) = terminal.draw(|f| ui::draw(f, &dash)) {
+      break Err(e);
+    }
+    tokio::select! {
+      ev = rx.recv() => match ev {
+        None | Some(Event::Quit) => break Ok(()),
+        Some(ev) => dash.apply(ev),
+      },
+      _ = tick.tick() => {},
+    };
+  };
+  ratatui::restore();
+  if let Err(e) = res {
+    eprintln!("Couldn't draw: {}", e);
+    std::process::exit(1);
+  }
+}
@@ -0,0 +1,104 @@
+//! What's known, as of the latest event.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use cdp_client::views::{Alert, Broker, SensorInfo};
+use chrono::{DateTime, Local};
+use libcdp::comm::broker_api::{BrokerMessage, BrokerMessagePayload};
+use libcdp::comm::sensor_broker::SensorType;
+use libcdp::ids::SensorId;
+
+use crate::feed::{Event, StreamState};
+
+/// Which sensor.
+pub(crate) type SensorKey = (SensorType, SensorId);
+
+/// A sensor, as far as the screen goes.
+#[derive(Clone, Debug)]
+pub(crate) struct SensorRow {
+  /// Its latest reading, in human units.
+  pub(crate) value: f64,
+  /// When its latest message was made.
+  pub(crate) when: DateTime<Local>,
+  /// Its latest readings, oldest first.
+  pub(crate) history: VecDeque<f64>
+}
+
+/// Everything on screen.
+#[derive(Debug)]
+pub(crate) struct Dashboard {
+  /// Sensors that sent anything, by type and ID.
+  pub(crate) sensors: BTreeMap<SensorKey, SensorRow>,
+  /// Names and rooms of the sensors that have them.
+  pub(crate) names: HashMap<SensorKey, SensorInfo>,
+  /// The brokers.
+  pub(crate) brokers: Vec<Broker>,
+  /// The alerts, newest first.
+  pub(crate) alerts: Vec<Alert>,
+  /// How the live stream is doing.
+  pub(crate) stream: StreamState,
+  /// The latest thing that went wrong, and when.
+  pub(crate) problem: Option<(DateTime<Local>, String)>,
+  /// Readings to keep per sensor.
+  history_len: usize
+}
+
+impl Dashboard {
+  /// Nothing known yet, keeping this many readings per sensor.
+  pub(crate) fn new(history_len: usize) -> Self {
+    return Self {
+      sensors: BTreeMap::new(),
+      names: HashMap::new(),
+      brokers: Vec::new(),
+      alerts: Vec::new(),
+      stream: StreamState::Connecting,
+      problem: None,
+      history_len: history_len
+    };
+  }
+
+  /// Takes a message in. Ones older than what's known are ignored, so the
+  /// latest readings asked for over HTTP don't count twice.
+  fn take(&mut self, msg: &BrokerMessage) {
+    let sd = match &msg.payload {
+      BrokerMessagePayload::SensorData(sd) => sd,
+      _ => return,
+    };
+    let value = sd.reading().human_value();
+    let when = msg.constructed_when;
+    let history_len = self.history_len;
+    let row = self.sensors.entry((sd.sensor_type(), sd.sensor_id()))
+      .or_insert_with(|| SensorRow {
+        value: value,
+        when: when,
+        history: VecDeque::with_capacity(history_len)
+      });
+    if !row.history.is_empty() && when <= row.when {
+      return;
+    }
+    row.value = value;
+    row.when = when;
+    if row.history.len() == history_len {
+      row.history.pop_front();
+    }
+    row.history.push_back(value);
+  }
+
+  /// Updates whatever the event is about.
+  pub(crate) fn apply(&mut self, ev: Event) {
+    match ev {
+      Event::Message(msg) => self.take(&msg),
+      Event::Current(msgs) => msgs.iter().for_each(|m| self.take(m)),
+      Event::Stream(state) => self.stream = state,
+      Event::Brokers(brokers) => self.brokers = brokers,
+      Event::Alerts(alerts) => self.alerts = alerts,
+      Event::Sensors(sensors) => {
+        self.names = sensors.into_iter()
+          .map(|rs| ((rs.sensor_type, rs.sensor_id), rs.info))
+          .collect();
+      },
+      Event::Problem(e) => self.problem = Some((Local::now(), e)),
+      Event::Resize | Event::Quit => {},
+    };
+  }
+}
@@ -0,0 +1,232 @@
+//! Drawing the dashboard: the sensors on top, the brokers and the alerts
+//! under them.
+
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Local};
+use libcdp::comm::sensor_broker::SensorType;
+use ratatui::Frame;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::symbols::bar;
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Table};
+
+use cdp_client::views::AlertState;
+
+use crate::feed::StreamState;
+use crate::state::{Dashboard, SensorKey};
+
+/// Readings older than this many seconds are drawn dim.
+const STALE_SECS: i64 = 15 * 60;
+
+/// Brokers not heard from in this many seconds are drawn in yellow.
+const QUIET_SECS: i64 = 5 * 60;
+
+/// Problems older than this many seconds aren't shown anymore.
+const PROBLEM_SECS: i64 = 60;
+
+/// Bars a sparkline is drawn with, lowest first.
+const LEVELS: [&str; 8] = [
+  bar::ONE_EIGHTH, bar::ONE_QUARTER, bar::THREE_EIGHTHS, bar::HALF,
+  bar::FIVE_EIGHTHS, bar::THREE_QUARTERS, bar::SEVEN_EIGHTHS, bar::FULL
+];
+
+/// Unit of a sensor type's readings, in human terms.
+fn unit(stype: SensorType) -> &'static str {
+  return match stype {
+    SensorType::Temperature => "°C",
+    SensorType::Humidity => "%RH",
+  };
+}
+
+/// How long ago something was, briefly, like 5s or 3h.
+fn ago(when: DateTime<Local>) -> String {
+  let secs = (Local::now() - when).num_seconds().max(0);
+  return match secs {
+    0..=59 => format!("{}s", secs),
+    60..=3599 => format!("{}m", secs / 60),
+    3600..=86399 => format!("{}h", secs / 3600),
+    _ => format!("{}d", secs / 86400),
+  };
+}
+
+/// The latest values, as bars at most this wide, scaled so the lowest is
+/// the shortest and the highest the tallest.
+fn sparkline(values: &VecDeque<f64>, width: usize) -> String {
+  let skip = values.len().saturating_sub(width);
+  let shown: Vec<f64> = values.iter().skip(skip).copied().collect();
+  let lo = shown.iter().copied().fold(f64::INFINITY, f64::min);
+  let hi = shown.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+  let top = (LEVELS.len() - 1) as f64;
+  return shown.iter()
+    .map(|v| {
+      let level = if hi <= lo {
+        LEVELS.len() / 2
+      } else {
+        ((v - lo) / (hi - lo) * top).round() as usize
+      };
+      LEVELS[level]
+    })
+    .collect();
+}
+
+/// What to call a sensor: its name, else its type and ID.
+fn sensor_label(dash: &Dashboard, key: &SensorKey) -> String {
+  return dash.names.get(key)
+    .and_then(|i| i.name.clone())
+    .unwrap_or_else(|| format!("{} #{}", key.0.name(), key.1));
+}
+
+/// The top line: what this is, and how the stream is doing.
+fn draw_header(f: &mut Frame, area: Rect, dash: &Dashboard) {
+  let stream = match &dash.stream {
+    StreamState::Live => {
+      Span::styled("● live", Style::default().fg(Color::Green))
+    },
+    StreamState::Connecting => {
+      Span::styled("● connecting", Style::default().fg(Color::Yellow))
+    },
+    StreamState::Down(why) => Span::styled(
+      format!("● down: {}", why), Style::default().fg(Color::Red)
+    ),
+  };
+  let line = Line::from(vec![
+    Span::styled(
+      " casa do pânico ", Style::default().add_modifier(Modifier::BOLD)
+    ),
+    Span::raw(Local::now().format("%H:%M:%S  ").to_string()),
+    stream
+  ]);
+  f.render_widget(Paragraph::new(line), area);
+}
+
+/// Every sensor: what it reads, how that went lately, and how fresh it is.
+fn draw_sensors(f: &mut Frame, area: Rect, dash: &Dashboard) {
+  // what's left after the other columns, the borders, and a space between
+  // every two columns
+  let fixed = 20 + 14 + 11 + 5;
+  let trend_width = (area.width as usize).saturating_sub(fixed + 2 + 4);
+  let rows = dash.sensors.iter().map(|(key, row)| {
+    let room = dash.names.get(key)
+      .and_then(|i| i.room.clone())
+      .unwrap_or_default();
+    let stale = (Local::now() - row.when).num_seconds() > STALE_SECS;
+    let style = if stale {
+      Style::default().fg(Color::DarkGray)
+    } else {
+      Style::default()
+    };
+    return Row::new(vec![
+      Cell::from(sensor_label(dash, key)),
+      Cell::from(room),
+      Cell::from(format!("{:>7.1} {}", row.value, unit(key.0))),
+      Cell::from(sparkline(&row.history, trend_width))
+        .style(Style::default().fg(Color::Cyan)),
+      Cell::from(ago(row.when))
+    ]).style(style);
+  });
+  let header = Row::new(vec!["Sensor", "Room", "Now", "Lately", "Age"])
+    .style(Style::default().add_modifier(Modifier::BOLD));
+  let table = Table::new(rows, [
+    Constraint::Length(20), Constraint::Length(14), Constraint::Length(11),
+    Constraint::Fill(1), Constraint::Length(5)
+  ])
+    .header(header)
+    .block(Block::default().borders(Borders::ALL).title(" Sensors "));
+  f.render_widget(table, area);
+}
+
+/// The brokers, and how they were doing last time they said.
+fn draw_brokers(f: &mut Frame, area: Rect, dash: &Dashboard) {
+  let rows = dash.brokers.iter().map(|b| {
+    let uid = b.uid.to_string();
+    let version = b.software.as_ref()
+      .map(|s| s.version.clone())
+      .unwrap_or_else(|| "?".to_owned());
+    let (queued, spooled) = b.status.as_ref()
+      .map(|s| (s.queue_depth.to_string(), s.spooled.to_string()))
+      .unwrap_or_default();
+    let quiet = (Local::now() - b.last_heartbeat).num_seconds() > QUIET_SECS;
+    let style = if b.incompatible {
+      Style::default().fg(Color::Red)
+    } else if quiet {
+      Style::default().fg(Color::Yellow)
+    } else {
+      Style::default()
+    };
+    return Row::new(vec![
+      uid[..8].to_owned(), version, ago(b.last_heartbeat), queued, spooled
+    ]).style(style);
+  });
+  let header = Row::new(vec!["Broker", "Version", "Heard", "Queue", "Spool"])
+    .style(Style::default().add_modifier(Modifier::BOLD));
+  let table = Table::new(rows, [
+    Constraint::Length(8), Constraint::Length(8), Constraint::Length(5),
+    Constraint::Length(5), Constraint::Length(5)
+  ])
+    .header(header)
+    .block(Block::default().borders(Borders::ALL).title(" Brokers "));
+  f.render_widget(table, area);
+}
+
+/// The latest alerts, as many as fit.
+fn draw_alerts(f: &mut Frame, area: Rect, dash: &Dashboard) {
+  let rows = dash.alerts.iter().map(|a| {
+    let opened = a.opened_when()
+      .map(|w| w.format("%d/%m %H:%M").to_string())
+      .unwrap_or_default();
+    let severity = match a.severity.as_str() {
+      "critical" => Style::default().fg(Color::Red),
+      "warning" => Style::default().fg(Color::Yellow),
+      _ => Style::default().fg(Color::Blue),
+    };
+    let (state, style) = match a.state {
+      AlertState::Open => ("open", Style::default()),
+      AlertState::Acknowledged => ("acked", Style::default()),
+      AlertState::Resolved => ("done", Style::default().fg(Color::DarkGray)),
+    };
+    return Row::new(vec![
+      Cell::from(opened),
+      Cell::from(a.severity.clone()).style(severity),
+      Cell::from(state),
+      Cell::from(a.summary.clone())
+    ]).style(style);
+  });
+  let table = Table::new(rows, [
+    Constraint::Length(11), Constraint::Length(8), Constraint::Length(5),
+    Constraint::Fill(1)
+  ])
+    .block(Block::default().borders(Borders::ALL).title(" Alerts "));
+  f.render_widget(table, area);
+}
+
+/// The bottom line: the latest problem, if it's recent, else how to quit.
+fn draw_footer(f: &mut Frame, area: Rect, dash: &Dashboard) {
+  let problem = dash.problem.as_ref()
+    .filter(|(when, _)| (Local::now() - *when).num_seconds() < PROBLEM_SECS);
+  let line = match problem {
+    Some((when, e)) => Line::styled(
+      format!(" {} {}", when.format("%H:%M:%S"), e),
+      Style::default().fg(Color::Red)
+    ),
+    None => Line::styled(" q quits", Style::default().fg(Color::DarkGray)),
+  };
+  f.render_widget(Paragraph::new(line), area);
+}
+
+/// Draws the whole thing.
+pub(crate) fn draw(f: &mut Frame, dash: &Dashboard) {
+  let [header, sensors, bottom, footer] = Layout::vertical([
+    Constraint::Length(1), Constraint::Min(5), Constraint::Length(10),
+    Constraint::Length(1)
+  ]).areas(f.area());
+  let [brokers, alerts] = Layout::horizontal([
+    Constraint::Length(37), Constraint::Fill(1)
+  ]).areas(bottom);
+  draw_header(f, header, dash);
+  draw_sensors(f, sensors, dash);
+  draw_brokers(f, brokers, dash);
+  draw_alerts(f, alerts, dash);
+  draw_footer(f, footer, dash);
+}
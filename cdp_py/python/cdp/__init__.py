@@ -0,0 +1,54 @@
+"""Sensor and broker messages of casa_do_panico, as plain dicts.
+
+Messages look just like the API serves them: a sensor message is something
+like {"Temperature": {"sensor_id": 3, "kelvin": 295}}, and a bundle is a
+list of broker messages. The codecs are libcdp's own, so they always agree
+with brokers and the API. See cdp.client for talking to the API.
+"""
+
+import json
+
+from . import _cdp
+from .client import Client, ApiError
+
+__all__ = [
+  "topics", "decode_sensor", "decode_sensor_json", "encode_sensor",
+  "decode_bundle", "encode_bundle", "Client", "ApiError",
+]
+
+
+def topics():
+  """Every topic sensors may publish to."""
+  return _cdp.topics()
+
+
+def decode_sensor(topic, data):
+  """Decodes the bytes a sensor published to a topic."""
+  return json.loads(_cdp.decode_sensor(topic, bytes(data)))
+
+
+def decode_sensor_json(topic, data):
+  """Decodes the JSON form of what a sensor published to a topic, like
+  {"sensor_id": 3, "kelvin": 295}. Takes a str, bytes or dict."""
+  if isinstance(data, dict):
+    data = json.dumps(data)
+  if isinstance(data, str):
+    data = data.encode()
+  return json.loads(_cdp.decode_sensor_json(topic, data))
+
+
+def encode_sensor(msg):
+  """Encodes a sensor message into what a sensor would publish. Returns the
+  topic, and the bytes."""
+  return _cdp.encode_sensor(json.dumps(msg))
+
+
+def decode_bundle(data, format="json"):
+  """Decodes a bundle of broker messages, as json, ndjson or protobuf.
+  Protobuf needs the module built with the protobuf feature."""
+  return json.loads(_cdp.decode_bundle(bytes(data), format))
+
+
+def encode_bundle(bundle, format="json"):
+  """Encodes a list of broker messages as json, ndjson or protobuf."""
+  return _cdp.encode_bundle(json.dumps(bundle), format)
@@ -0,0 +1,126 @@
+"""A small client for the API, for scripts. Answers come back as the API
+serves them, parsed from JSON; errors raise ApiError.
+
+  >>> api = cdp.Client("http://casa:8080")
+  >>> api.current()
+  >>> api.sensor_messages("temperature", start=datetime(2021, 7, 1))
+"""
+
+import json
+import urllib.error
+import urllib.parse
+import urllib.request
+from datetime import datetime
+
+
+class ApiError(Exception):
+  """What the API said went wrong: the HTTP status, and the code and
+  message in its answer, if it gave one."""
+
+  def __init__(self, status, code=None, message=None):
+    super().__init__("{} {}: {}".format(status, code, message))
+    self.status = status
+    self.code = code
+    self.message = message
+
+
+def _param(value):
+  """Turns a query parameter into what the API expects."""
+  if isinstance(value, datetime):
+    # RFC 3339 needs an offset, so naive times are taken as local ones.
+    if value.tzinfo is None:
+      value = value.astimezone()
+    return value.isoformat()
+  if isinstance(value, bool):
+    return "true" if value else "false"
+  return str(value)
+
+
+class Client:
+  """Talks to the API at some base URL. The admin token is only needed for
+  admin endpoints."""
+
+  def __init__(self, base_url, admin_token=None, timeout=10):
+    self.base_url = base_url.rstrip("/")
+    self.admin_token = admin_token
+    self.timeout = timeout
+
+  def request(self, method, path, params=None, body=None):
+    """Makes a request, and returns the answer, parsed. None-valued params
+    are left out."""
+    url = self.base_url + path
+    params = {k: _param(v) for k, v in (params or {}).items() if v is not None}
+    if params:
+      url += "?" + urllib.parse.urlencode(params)
+    headers = {"Accept": "application/json"}
+    data = None
+    if body is not None:
+      data = json.dumps(body).encode()
+      headers["Content-Type"] = "application/json"
+    if self.admin_token is not None:
+      headers["Authorization"] = "Bearer " + self.admin_token
+    req = urllib.request.Request(url, data, headers, method=method)
+    try:
+      with urllib.request.urlopen(req, timeout=self.timeout) as resp:
+        text = resp.read()
+    except urllib.error.HTTPError as e:
+      try:
+        err = json.loads(e.read())
+      except ValueError:
+        err = {}
+      raise ApiError(e.code, err.get("code"), err.get("message")) from None
+    return json.loads(text) if text else None
+
+  def get(self, path, **params):
+    """GETs an endpoint, with query parameters."""
+    return self.request("GET", path, params)
+
+  def current(self):
+    """The latest reading of every sensor."""
+    return self.get("/current")
+
+  def changes(self, since=0):
+    """Sensors whose reading changed since a cursor, along with the cursor
+    to ask with next time. 0 means every sensor."""
+    return self.get("/changes", since=since)
+
+  def sensor_messages(self, sensor_type, start=None, end=None, order=None,
+                      after=None, limit=None, verified=None):
+    """Messages of a sensor type within a time range. order is one of
+    constructed, received or seq; after and limit page through them by
+    sequence number."""
+    return self.get(
+      "/messages/sensor/" + urllib.parse.quote(sensor_type), **{
+        "from": start, "to": end, "order": order, "after": after,
+        "limit": limit, "verified": verified,
+      }
+    )
+
+  def all_sensor_messages(self):
+    """Every sensor message kept."""
+    return self.get("/messages/sensor")
+
+  def raw_payload(self, message_id):
+    """The bytes a sensor sent for a message, if its broker kept them."""
+    return self.get("/messages/{}/raw".format(message_id))
+
+  def anomalies(self):
+    """Messages flagged as outliers."""
+    return self.get("/anomalies")
+
+  def alerts(self, state=None):
+    """Alerts kept, newest first. state may be open, acknowledged or
+    resolved."""
+    return self.get("/alerts", state=state)
+
+  def sensors(self):
+    """The name and room of every registered sensor."""
+    return self.get("/sensors")
+
+  def rooms(self):
+    """Every room, with its sensors."""
+    return self.get("/rooms")
+
+  def brokers(self):
+    """Brokers heard from."""
+    return self.get("/brokers")
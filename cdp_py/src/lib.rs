@@ -0,0 +1,110 @@
+//! Python bindings for libcdp's messages, so scripts can make sense of what
+//! sensors and brokers send without redoing the formats. Messages go in and
+//! out as JSON, as the API serves them; the cdp package, in python/, turns
+//! that into dicts, and has a small client for the API besides.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use libcdp::comm::broker_api::{self, BrokerMessage, BrokerMessageBundle};
+use libcdp::comm::decoders;
+use libcdp::comm::sensor_broker::AnySensorMessage;
+#[cfg(feature = "protobuf")]
+use libcdp::proto;
+
+/// Turns any error into a ValueError.
+fn value_error<E: std::fmt::Display>(e: E) -> PyErr {
+  return PyValueError::new_err(e.to_string());
+}
+
+/// Every topic sensors may publish to.
+#[pyfunction]
+fn topics() -> Vec<&'static str> {
+  return decoders::topics();
+}
+
+/// Decodes what a sensor published to a topic. Returns the message as JSON.
+#[pyfunction]
+fn decode_sensor(topic: &str, data: &[u8]) -> PyResult<String> {
+  let msg = AnySensorMessage::decode(topic, data.to_vec())
+    .map_err(value_error)?;
+  return serde_json::to_string(&msg).map_err(value_error);
+}
+
+/// Decodes the JSON form of what a sensor published to a topic, like
+/// {"sensor_id": 3, "kelvin": 295}. Returns the message as JSON.
+#[pyfunction]
+fn decode_sensor_json(topic: &str, data: &[u8]) -> PyResult<String> {
+  let msg = AnySensorMessage::decode_json(topic, data)
+    .map_err(value_error)?;
+  return serde_json::to_string(&msg).map_err(value_error);
+}
+
+/// Encodes a message, given as JSON, into what a sensor would publish.
+/// Returns the topic, and the bytes.
+#[pyfunction]
+fn encode_sensor<'py>(py: Python<'py>, msg: &str)
+-> PyResult<(&'static str, Bound<'py, PyBytes>)> {
+  let msg: AnySensorMessage = serde_json::from_str(msg)
+    .map_err(value_error)?;
+  let topic = msg.sensor_type().name();
+  return Ok((topic, PyBytes::new_bound(py, &msg.encode())));
+}
+
+/// Decodes a bundle of broker messages, in some format: json, ndjson or
+/// protobuf. Returns the messages as a JSON list.
+#[pyfunction]
+#[pyo3(signature = (data, format = "json"))]
+fn decode_bundle(data: &[u8], format: &str) -> PyResult<String> {
+  let bnd: BrokerMessageBundle = match format {
+    "json" => serde_json::from_slice(data).map_err(value_error)?,
+    "ndjson" => data.split(|b| *b == b'\n')
+      .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+      .map(serde_json::from_slice::<BrokerMessage>)
+      .collect::<Result<_, _>>()
+      .map_err(value_error)?,
+    #[cfg(feature = "protobuf")]
+    "protobuf" => proto::decode_bundle(data).map_err(value_error)?,
+    other => return Err(no_such_format(other)),
+  };
+  return serde_json::to_string(&bnd).map_err(value_error);
+}
+
+/// Encodes a bundle of broker messages, given as a JSON list, in some
+/// format: json, ndjson or protobuf.
+#[pyfunction]
+#[pyo3(signature = (bundle, format = "json"))]
+fn encode_bundle<'py>(py: Python<'py>, bundle: &str, format: &str)
+-> PyResult<Bound<'py, PyBytes>> {
+  let bnd: BrokerMessageBundle = serde_json::from_str(bundle)
+    .map_err(value_error)?;
+  let data = match format {
+    "json" => serde_json::to_vec(&bnd).map_err(value_error)?,
+    "ndjson" => broker_api::bundle_to_ndjson(&bnd),
+    #[cfg(feature = "protobuf")]
+    "protobuf" => proto::encode_bundle(&bnd),
+    other => return Err(no_such_format(other)),
+  };
+  return Ok(PyBytes::new_bound(py, &data));
+}
+
+/// The error for a format we don't know, or weren't built with.
+fn no_such_format(format: &str) -> PyErr {
+  return PyValueError::new_err(format!(
+    "Unknown bundle format \"{}\". Protobuf needs the protobuf feature.",
+    format
+  ));
+}
+
+/// The native half of the cdp package.
+#[pymodule]
+fn _cdp(m: &Bound<'_, PyModule>) -> PyResult<()> {
+  m.add_function(wrap_pyfunction!(topics, m)?)?;
+  m.add_function(wrap_pyfunction!(decode_sensor, m)?)?;
+  m.add_function(wrap_pyfunction!(decode_sensor_json, m)?)?;
+  m.add_function(wrap_pyfunction!(encode_sensor, m)?)?;
+  m.add_function(wrap_pyfunction!(decode_bundle, m)?)?;
+  m.add_function(wrap_pyfunction!(encode_bundle, m)?)?;
+  return Ok(());
+}
@@ -0,0 +1,7 @@
+//! Generates the protobuf messages, if wanted.
+
+fn main() {
+  #[cfg(feature = "protobuf")]
+  prost_build::compile_protos(&["proto/cdp.proto"], &["proto"])
+    .expect("Could not compile the protobuf definitions!");
+}
@@ -0,0 +1,71 @@
+#ifndef CDP_WIRE_H
+#define CDP_WIRE_H
+
+/* Generated by cbindgen from libcdp/src/ffi.rs, don't edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Bytes a temperature message takes.
+#define CDP_TEMPERATURE_LENGTH 3
+
+// Bytes a humidity message takes.
+#define CDP_HUMIDITY_LENGTH 2
+
+// Bytes a device health message takes.
+#define CDP_DEVICE_HEALTH_LENGTH 7
+
+// Types of messages a sensor may publish.
+typedef enum CdpMessageType {
+  // TemperatureMessage.
+  CDP_MESSAGE_TYPE_TEMPERATURE,
+  // HumidityMessage.
+  CDP_MESSAGE_TYPE_HUMIDITY,
+  // DeviceHealthMessage.
+  CDP_MESSAGE_TYPE_DEVICE_HEALTH,
+} CdpMessageType;
+
+// Bytes a message of some type takes.
+size_t cdp_message_length(enum CdpMessageType mtype);
+
+// Writes the topic messages of some type go to into a buffer, followed by
+// a NUL. Returns the topic's length, without the NUL; 0 if it didn't fit.
+//
+// # Safety
+//
+// out must be null, or point to at least out_len writable bytes.
+size_t cdp_topic(enum CdpMessageType mtype, uint8_t *out, size_t out_len);
+
+// Encodes a temperature message into a buffer. Returns how many bytes were
+// written; 0 if it didn't fit.
+//
+// # Safety
+//
+// out must be null, or point to at least out_len writable bytes.
+size_t cdp_encode_temperature(uint8_t sensor_id, uint16_t kelvin, uint8_t *out, size_t out_len);
+
+// Encodes a humidity message into a buffer. Returns how many bytes were
+// written; 0 if it didn't fit.
+//
+// # Safety
+//
+// out must be null, or point to at least out_len writable bytes.
+size_t cdp_encode_humidity(uint8_t sensor_id, uint8_t humidity, uint8_t *out, size_t out_len);
+
+// Encodes a device health message into a buffer. Returns how many bytes
+// were written; 0 if it didn't fit.
+//
+// # Safety
+//
+// out must be null, or point to at least out_len writable bytes.
+size_t cdp_encode_device_health(uint8_t sensor_id,
+                                uint8_t battery,
+                                int8_t rssi,
+                                uint32_t uptime_secs,
+                                uint8_t *out,
+                                size_t out_len);
+
+#endif  /* CDP_WIRE_H */
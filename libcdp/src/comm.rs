@@ -2,3 +2,8 @@
 
 pub mod sensor_broker;
 pub mod broker_api;
+pub mod decoders;
+pub mod enrollment;
+pub mod sealing;
+pub mod signing;
+pub mod update;
@@ -1,23 +1,250 @@
 //! Messages between brokers and APIs.
 
+use std::collections::BTreeMap;
 use std::error::Error as StdError;
 use std::fmt::Display;
 
 use chrono::{DateTime, Local};
 use reqwest::Response;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+use crate::comm::sensor_broker::{AnySensorMessage, DeviceHealthMessage};
+use crate::comm::update::{UpdateOrder, UpdateReport};
+use crate::ids::{BrokerId, MessageId};
 
-use crate::comm::sensor_broker::AnySensorMessage;
 
-
-/// A heartbeat message. Carries key and uuid.
+/// A heartbeat message. Carries key and uuid, and optionally some news about
+/// the broker's health.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HeartbeatMessage {
+  /// Version of the heartbeat format. Missing means 1 (uid and key only).
+  #[serde(default = "HeartbeatMessage::default_version")]
+  pub version: u32,
   /// The unique id of the broker.
-  pub uid: Uuid,
+  pub uid: BrokerId,
   /// The API access secret key.
-  pub key: Option<String>
+  pub key: Option<String>,
+  /// How the broker is doing. Only in version 2 and up.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub status: Option<BrokerStatus>,
+  /// Hash of the remote config the broker runs with. Only in version 3 and
+  /// up.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub config_hash: Option<String>,
+  /// How the latest update it was ordered went, if it was ever ordered one.
+  /// Only in version 3 and up.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub update: Option<UpdateReport>,
+  /// What the broker is, and can do. Only in version 3 and up.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub software: Option<SoftwareInfo>
+}
+
+/// Version of the protocol between brokers and APIs this crate speaks.
+/// Bumped when a change leaves older peers unable to follow.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this crate still gets along with.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Names of things brokers and APIs may or may not be able to do.
+pub mod features {
+  /// Settings sent with heartbeat replies.
+  pub const REMOTE_CONFIG: &str = "remote_config";
+  /// Self-updates ordered with heartbeat replies.
+  pub const UPDATES: &str = "updates";
+  /// Keys rotated with heartbeat replies.
+  pub const KEY_ROTATION: &str = "key_rotation";
+  /// First-boot enrollment.
+  pub const ENROLLMENT: &str = "enrollment";
+  /// Sealed bundles.
+  pub const SEALING: &str = "sealing";
+  /// Signed sensor payloads.
+  pub const SIGNING: &str = "signing";
+  /// Protobuf bundles.
+  pub const PROTOBUF: &str = "protobuf";
+  /// NDJSON bundles.
+  pub const NDJSON: &str = "ndjson";
+  /// Payloads that failed to decode, sent home as DecodeFailure.
+  pub const DECODE_FAILURES: &str = "decode_failures";
+}
+
+/// What a broker or an API is, and can do.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SoftwareInfo {
+  /// Its crate's version, like "0.1.0".
+  pub version: String,
+  /// Protocol version it speaks.
+  pub protocol: u32,
+  /// Oldest protocol version it gets along with.
+  pub min_protocol: u32,
+  /// What it can do, as in features.
+  #[serde(default)]
+  pub features: Vec<String>
+}
+
+impl SoftwareInfo {
+  /// Info for some crate, speaking this crate's protocol.
+  pub fn new(version: &str, features: &[&str]) -> Self {
+    return Self {
+      version: version.to_owned(),
+      protocol: PROTOCOL_VERSION,
+      min_protocol: MIN_PROTOCOL_VERSION,
+      features: features.iter().map(|f| f.to_string()).collect()
+    };
+  }
+
+  /// Whether we get along with it: each of us speaks a protocol the other
+  /// still follows.
+  pub fn compatible(&self) -> bool {
+    return self.min_protocol <= PROTOCOL_VERSION
+      && MIN_PROTOCOL_VERSION <= self.protocol;
+  }
+
+  /// Whether it can do something.
+  pub fn has(&self, feature: &str) -> bool {
+    return self.features.iter().any(|f| f == feature);
+  }
+}
+
+impl HeartbeatMessage {
+  /// Version of the heartbeat format this crate speaks.
+  pub const VERSION: u32 = 3;
+
+  /// For serde. Heartbeats without a version predate versioning.
+  fn default_version() -> u32 {
+    return 1;
+  }
+}
+
+/// What the API answers a heartbeat with. Older APIs answer with nothing.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HeartbeatReply {
+  /// A key to use from now on, when the API is rotating it.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub new_key: Option<String>,
+  /// Settings to run with, when they aren't what the broker reported.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub config: Option<RemoteConfig>,
+  /// An update to install, when one's scheduled and the broker didn't say
+  /// it heard of it.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub update: Option<UpdateOrder>,
+  /// What the API is, and can do.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub api: Option<SoftwareInfo>
+}
+
+/// What the API answers a bundle it took with, so whoever sent it can tell
+/// exactly what became of it. Older APIs answer with a bare "OK".
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleAck {
+  /// Messages stored.
+  pub accepted: usize,
+  /// Messages taken, but not stored.
+  pub rejected: usize,
+  /// Why those weren't stored, and how many for each reason.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub reasons: BTreeMap<String, usize>,
+  /// Lowest and highest sequence number given to the stored messages.
+  /// Messages from elsewhere may have gotten some in between. None if none
+  /// were stored.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub seq_range: Option<(u64, u64)>,
+  /// The API's clock, when it was done with the bundle.
+  pub server_time: DateTime<Local>
+}
+
+impl BundleAck {
+  /// The reason for messages not stored for having been stored before,
+  /// which a resent bundle may well have.
+  pub const DUPLICATE: &'static str = "duplicate";
+}
+
+/// Settings the API wants a broker to run with. Whatever's left out goes
+/// as per the broker's own config file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteConfig {
+  /// Sensor type names to take in, like "temperature".
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub topics: Option<Vec<String>>,
+  /// Bundle size for the default route.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub bundle_size: Option<usize>,
+  /// Seconds between heartbeats.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub heartbeat_interval_secs: Option<u64>
+}
+
+impl RemoteConfig {
+  /// A short hash, so brokers can say what they run with in a few bytes.
+  /// The same settings always hash the same, topics in any order.
+  pub fn hash(&self) -> String {
+    let mut normal = self.clone();
+    if let Some(topics) = normal.topics.as_mut() {
+      topics.sort();
+      topics.dedup();
+    }
+    let json = serde_json::to_vec(&normal).unwrap_or_default();
+    let digest = ring::digest::digest(&ring::digest::SHA256, &json);
+    return digest.as_ref()[..8].iter()
+      .map(|b| format!("{:02x}", b))
+      .collect();
+  }
+}
+
+/// Health figures a broker reports along with its heartbeats.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BrokerStatus {
+  /// Seconds since the broker started.
+  pub uptime_secs: u64,
+  /// Messages waiting in the internal channel.
+  pub queue_depth: usize,
+  /// Messages sitting in the bundle, waiting to be sent.
+  pub bundle_len: usize,
+  /// Sensor messages decoded since the last heartbeat.
+  pub decoded_since_last: u64,
+  /// Sensor messages that failed to decode since the last heartbeat.
+  pub decode_errors_since_last: u64,
+  /// Messages lost to backpressure since the last heartbeat.
+  #[serde(default)]
+  pub dropped_since_last: u64,
+  /// Messages waiting in the disk spool.
+  #[serde(default)]
+  pub spooled: usize,
+  /// Sealed bundles waiting to be sent, or sent again.
+  #[serde(default)]
+  pub bundles_pending: usize,
+  /// Bundles being sent right now.
+  #[serde(default)]
+  pub bundles_in_flight: usize,
+  /// Clients connected to the embedded MQTT server, if known.
+  pub mqtt_connections: Option<usize>,
+  /// Distinct MQTT topic filters the broker subscribed to, if it speaks
+  /// MQTT at all.
+  #[serde(default)]
+  pub mqtt_subscriptions: Option<usize>,
+  /// Messages the API said it stored since the last heartbeat, as per its
+  /// acknowledgements.
+  #[serde(default)]
+  pub acked_since_last: u64,
+  /// Messages the API said it took but didn't store since the last
+  /// heartbeat, as per its acknowledgements.
+  #[serde(default)]
+  pub refused_since_last: u64,
+  /// Bundle sends and heartbeats that timed out since the last heartbeat,
+  /// as opposed to failing outright.
+  #[serde(default)]
+  pub timeouts_since_last: u64,
+  /// Bytes sent home in the last hour, if the broker keeps to a bandwidth
+  /// budget.
+  #[serde(default)]
+  pub bandwidth_used_bytes: Option<u64>,
+  /// The bandwidth budget, in bytes per hour, if there is one.
+  #[serde(default)]
+  pub bandwidth_budget_bytes: Option<u64>,
+  /// Tasks that panicked and were started over since the last heartbeat.
+  #[serde(default)]
+  pub task_restarts_since_last: u64
 }
 
 /// Payload that can be sent upstream.
@@ -26,21 +253,47 @@ pub enum BrokerMessagePayload {
   /// Message is sensor data.
   SensorData(AnySensorMessage),
   /// Message is a mere heartbeat. Will send key and uuid for checking.
-  Heartbeat(HeartbeatMessage)
+  Heartbeat(HeartbeatMessage),
+  /// Message is telemetry about a sensor device (battery, signal...).
+  DeviceHealth(DeviceHealthMessage),
+  /// Message is a payload some sensor sent that didn't decode, for finding
+  /// out which devices are acting up. Only sent to APIs that take them.
+  DecodeFailure {
+    /// Topic it was published to.
+    topic: String,
+    /// The bytes, in base64.
+    bytes: String,
+    /// Why they didn't decode.
+    error: String
+  }
 }
 
 /// Type of payload that can be sent upstream.
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum BrokerMessagePayloadType {
   SensorData,
-  Heartbeat
+  Heartbeat,
+  DeviceHealth,
+  DecodeFailure
+}
+
+impl BrokerMessagePayloadType {
+  /// Every payload type there is.
+  pub fn all_types() -> Vec<Self> {
+    return vec![
+      Self::SensorData, Self::Heartbeat, Self::DeviceHealth,
+      Self::DecodeFailure
+    ];
+  }
 }
 
 impl Display for BrokerMessagePayloadType {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     return write!(f, "{}", match self {
       BrokerMessagePayloadType::SensorData => "sensor_data",
-      BrokerMessagePayloadType::Heartbeat => "heartbeat"
+      BrokerMessagePayloadType::Heartbeat => "heartbeat",
+      BrokerMessagePayloadType::DeviceHealth => "device_health",
+      BrokerMessagePayloadType::DecodeFailure => "decode_failure"
     })
   }
 }
@@ -50,6 +303,8 @@ impl From<&BrokerMessagePayload> for BrokerMessagePayloadType {
     return match pl {
       BrokerMessagePayload::SensorData(_) => Self::SensorData,
       BrokerMessagePayload::Heartbeat(_) => Self::Heartbeat,
+      BrokerMessagePayload::DeviceHealth(_) => Self::DeviceHealth,
+      BrokerMessagePayload::DecodeFailure { .. } => Self::DecodeFailure,
     }
   }
 }
@@ -57,29 +312,82 @@ impl From<&BrokerMessagePayload> for BrokerMessagePayloadType {
 /// Message to be sent upstream.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BrokerMessage {
+  /// Unique message ID. Set by the broker, once, and kept through every
+  /// resend, so the API can tell a resend from a new message. None for
+  /// messages from brokers that predate IDs.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub id: Option<MessageId>,
   /// When this message was constructed. Set by the broker.
   pub constructed_when: DateTime<Local>,
-  /// When this message was sent.
+  /// When the broker decoded what the sensor sent. Sensors have no clock,
+  /// so this is as close to the reading as it gets. Set by the broker.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub decoded_when: Option<DateTime<Local>>,
+  /// When this message was sent. Set by the broker, right before each try.
   pub sent_when: Option<DateTime<Local>>,
   /// When this message was received. Set by the API.
   pub received_when: Option<DateTime<Local>>,
   /// A copy of the broker unique ID.
-  pub broker_id: Uuid,
+  pub broker_id: BrokerId,
   /// The payload.
-  pub payload: BrokerMessagePayload
+  pub payload: BrokerMessagePayload,
+  /// The sensor data exactly as received, if the API had to correct it
+  /// (e.g. calibration). Set by the API.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub raw_sensor_data: Option<AnySensorMessage>,
+  /// How many standard deviations off the sensor's recent behaviour this
+  /// reading was, if the API flagged it as an outlier. Set by the API.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub anomaly_score: Option<f64>,
+  /// Free-form labels, like where the sensor is. Set by the broker's
+  /// transforms, if any.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub tags: BTreeMap<String, String>,
+  /// The bytes the sensor sent, in base64, if the broker was told to keep
+  /// them. For debugging bad sensors.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub raw_payload: Option<String>,
+  /// Whether the sensor signed the payload and the broker checked the
+  /// signature. Set by the broker.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub verified: bool,
+  /// Order of arrival at the API, counting up from 1 and never reused, for
+  /// paging through messages as they come in. Set by the API. None for
+  /// messages stored before it kept count.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub seq: Option<u64>
 }
 
 impl BrokerMessage {
-  /// Construct a BrokerMessage from the viewpoint of the broker.
-  pub fn construct(broker_id: Uuid, payload: BrokerMessagePayload) -> Self {
+  /// Construct a BrokerMessage from the viewpoint of the broker, with an
+  /// ID of its own.
+  pub fn construct(broker_id: BrokerId, payload: BrokerMessagePayload)
+  -> Self {
     return Self {
+      id: Some(MessageId::new_v4()),
       constructed_when: Local::now(),
+      decoded_when: None,
       sent_when: None,
       received_when: None,
       broker_id: broker_id,
       payload: payload,
+      raw_sensor_data: None,
+      anomaly_score: None,
+      tags: BTreeMap::new(),
+      raw_payload: None,
+      verified: false,
+      seq: None
     }
   }
+  /// Keeps the bytes the sensor sent along with the message.
+  pub fn set_raw_payload(&mut self, raw: &[u8]) {
+    self.raw_payload = Some(base64::encode(raw));
+  }
+  /// The bytes the sensor sent, if kept. None if they weren't, or if they
+  /// aren't valid base64.
+  pub fn raw_payload_bytes(&self) -> Option<Vec<u8>> {
+    return base64::decode(self.raw_payload.as_ref()?).ok();
+  }
   /// Returns the payload type.
   pub fn payload_type(&self) -> BrokerMessagePayloadType {
     return (&self.payload).into();
@@ -89,6 +397,26 @@ impl BrokerMessage {
 /// A bundle of messages to be sent upstream.
 pub type BrokerMessageBundle = Vec<BrokerMessage>;
 
+/// Content type of bundles sent as NDJSON, one message per line.
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Header, or gRPC metadata key, carrying the key of the broker a bundle is
+/// from, the same one its heartbeats carry. APIs that gave the broker a key
+/// turn away its bundles without it.
+pub const KEY_HEADER: &str = "x-cdp-broker-key";
+
+/// Encodes a bundle as NDJSON, so the API can store it as it comes in
+/// rather than once it's all there.
+pub fn bundle_to_ndjson(bnd: &BrokerMessageBundle) -> Vec<u8> {
+  let mut out = Vec::new();
+  for msg in bnd {
+    if serde_json::to_writer(&mut out, msg).is_ok() {
+      out.push(b'\n');
+    }
+  }
+  return out;
+}
+
 /// Any error that can occur when phoning home.
 #[derive(Debug)]
 pub enum UpstreamCommError {
@@ -0,0 +1,118 @@
+//! Which topics carry which sensor messages, and how those go over the
+//! wire. Every sensor type registers its topic, along with how to decode
+//! its bytes and its JSON form, and how to encode it back into bytes.
+//!
+//! The built-in types are always there, as listed in sensor_types! over in
+//! comm::sensor_broker. With the custom_decoders feature,
+//! downstream crates may register more topics at startup, decoding into any
+//! of the types -- say, some vendor's own format for temperatures -- without
+//! touching the built-in ones.
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::RwLock;
+
+use crate::comm::sensor_broker::{AnySensorMessage, MessageParseError, SensorMessage, SensorType, BUILTIN_DECODERS};
+
+/// Decodes what was published to a topic.
+pub type DecodeFn = fn(&[u8]) -> Result<AnySensorMessage, MessageParseError>;
+
+/// Encodes a message into the bytes a sensor would publish. None if it's
+/// not a message this topic carries.
+pub type EncodeFn = fn(&AnySensorMessage) -> Option<Vec<u8>>;
+
+/// A topic, and how to make sense of what's published to it.
+#[derive(Clone, Copy, Debug)]
+pub struct SensorDecoder {
+  /// The topic name.
+  pub topic: &'static str,
+  /// Type of the messages it carries.
+  pub sensor_type: SensorType,
+  /// Decodes raw payloads.
+  pub decode: DecodeFn,
+  /// Decodes JSON payloads.
+  pub decode_json: DecodeFn,
+  /// Encodes messages into raw payloads.
+  pub encode: EncodeFn
+}
+
+/// Topics registered by downstream crates.
+static CUSTOM: RwLock<Vec<SensorDecoder>> = RwLock::new(Vec::new());
+
+/// Why a decoder couldn't be registered.
+#[derive(Debug)]
+pub enum RegisterError {
+  /// Some other decoder has the topic.
+  Taken(&'static str)
+}
+
+impl Error for RegisterError {}
+
+impl Display for RegisterError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      RegisterError::Taken(t) => write!(f, "Topic \"{}\" is taken.", t),
+    };
+  }
+}
+
+/// Decodes a raw payload into a message of some type.
+pub fn decode_raw<M>(data: &[u8])
+-> Result<AnySensorMessage, MessageParseError>
+where M: SensorMessage + Into<AnySensorMessage> {
+  return M::try_from(data.to_vec()).map(Into::into);
+}
+
+/// Decodes a JSON payload into a message of some type.
+pub fn decode_json<M>(data: &[u8])
+-> Result<AnySensorMessage, MessageParseError>
+where M: SensorMessage + Into<AnySensorMessage> {
+  return Ok(serde_json::from_slice::<M>(data)?.into());
+}
+
+/// Encodes a message of some type into a raw payload.
+pub fn encode<M>(msg: &AnySensorMessage) -> Option<Vec<u8>>
+where M: SensorMessage + TryFrom<AnySensorMessage> {
+  return M::try_from(msg.clone()).ok().map(|m| m.to_bytes());
+}
+
+/// The decoder of a topic, built-in or not.
+pub fn find(topic: &str) -> Option<SensorDecoder> {
+  if let Some(dec) = BUILTIN_DECODERS.iter().find(|d| d.topic == topic) {
+    return Some(*dec);
+  }
+  let custom = CUSTOM.read().unwrap_or_else(|e| e.into_inner());
+  return custom.iter().find(|d| d.topic == topic).copied();
+}
+
+/// The built-in decoder of a sensor type.
+pub fn builtin(stype: SensorType) -> SensorDecoder {
+  return *BUILTIN_DECODERS.iter()
+    .find(|d| d.sensor_type == stype)
+    .expect("every sensor type has a built-in decoder");
+}
+
+/// Every topic there's a decoder for, built-in ones first.
+pub fn topics() -> Vec<&'static str> {
+  let custom = CUSTOM.read().unwrap_or_else(|e| e.into_inner());
+  return BUILTIN_DECODERS.iter()
+    .chain(custom.iter())
+    .map(|d| d.topic)
+    .collect();
+}
+
+/// Registers a decoder for a new topic. Topics can't be registered twice,
+/// and built-in ones can't be replaced.
+#[cfg(feature = "custom_decoders")]
+pub fn register(dec: SensorDecoder) -> Result<(), RegisterError> {
+  let mut custom = CUSTOM.write().unwrap_or_else(|e| e.into_inner());
+  let taken = BUILTIN_DECODERS.iter()
+    .chain(custom.iter())
+    .any(|d| d.topic == dec.topic);
+  if taken {
+    return Err(RegisterError::Taken(dec.topic));
+  }
+  custom.push(dec);
+  return Ok(());
+}
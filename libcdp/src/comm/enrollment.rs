@@ -0,0 +1,152 @@
+//! Enrollment, so brokers needn't have UIDs and keys typed into them. On
+//! first boot, a broker makes up its UID and an Ed25519 keypair, and asks
+//! the API to enroll it with a one-time token. Once the API approves, it
+//! hands the broker a key, which the broker then calls in with as usual.
+//!
+//! Requests are signed with the broker's private key, over its UID, the
+//! token and when they were made, so only the broker that enrolled can
+//! fetch its key, and only for a while after signing.
+
+use std::error::Error;
+use std::fmt::{Debug, Display};
+
+use chrono::Local;
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+
+use crate::ids::BrokerId;
+
+/// How old or new a request may be, in seconds, to allow for clocks that
+/// disagree a little.
+pub const MAX_SKEW_SECS: i64 = 300;
+
+/// Why an enrollment request couldn't be made or checked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EnrollError {
+  /// The private key didn't parse, or the public key isn't base64.
+  BadKey,
+  /// No randomness to be had for a keypair.
+  NoRandomness,
+  /// Not signed by the key it came with.
+  BadSignature,
+  /// Signed too long ago, or too far ahead.
+  Stale
+}
+
+impl Error for EnrollError {}
+
+impl Display for EnrollError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return write!(f, "{}", match self {
+      EnrollError::BadKey => "Bad Ed25519 key.",
+      EnrollError::NoRandomness => "Couldn't get randomness for a keypair.",
+      EnrollError::BadSignature => "Bad signature.",
+      EnrollError::Stale => "Signed too long ago, or clocks disagree.",
+    });
+  }
+}
+
+/// A broker asking to be enrolled, or whether it's been.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EnrollRequest {
+  /// The UID the broker made up.
+  pub uid: BrokerId,
+  /// Its public key, in base64.
+  pub public_key: String,
+  /// The one-time token it was given.
+  pub token: String,
+  /// When it signed this, in seconds since the epoch.
+  pub when: i64,
+  /// Its signature, in base64.
+  pub signature: String
+}
+
+/// Where an enrollment stands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnrollState {
+  /// Waiting for an admin.
+  Pending,
+  /// Good to go.
+  Approved
+}
+
+/// What the API answers an enrollment request with.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EnrollReply {
+  /// Where the enrollment stands.
+  pub state: EnrollState,
+  /// The key to call in with, once approved.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub key: Option<String>
+}
+
+/// What gets signed.
+fn signed_bytes(uid: &BrokerId, token: &str, when: i64) -> Vec<u8> {
+  return format!("cdp-enroll\n{}\n{}\n{}", uid, token, when).into_bytes();
+}
+
+impl EnrollRequest {
+  /// Checks the request was signed by the key it came with, recently.
+  pub fn verify(&self) -> Result<(), EnrollError> {
+    let skew = (Local::now().timestamp() - self.when).abs();
+    if skew > MAX_SKEW_SECS {
+      return Err(EnrollError::Stale);
+    }
+    let key = base64::decode(&self.public_key)
+      .map_err(|_| EnrollError::BadKey)?;
+    let sig = base64::decode(&self.signature)
+      .map_err(|_| EnrollError::BadSignature)?;
+    return UnparsedPublicKey::new(&signature::ED25519, key)
+      .verify(&signed_bytes(&self.uid, &self.token, self.when), &sig)
+      .map_err(|_| EnrollError::BadSignature);
+  }
+}
+
+/// A broker's keypair.
+pub struct Identity {
+  /// The keypair itself.
+  pair: Ed25519KeyPair
+}
+
+impl Debug for Identity {
+  /// Keeps the private key out of logs.
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return write!(f, "Identity({})", self.public_key());
+  }
+}
+
+impl Identity {
+  /// Makes up a new keypair. Returns it in PKCS#8, for keeping.
+  pub fn generate() -> Result<Vec<u8>, EnrollError> {
+    let doc = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+      .map_err(|_| EnrollError::NoRandomness)?;
+    return Ok(doc.as_ref().to_vec());
+  }
+
+  /// Reads a keypair kept in PKCS#8.
+  pub fn from_pkcs8(bytes: &[u8]) -> Result<Self, EnrollError> {
+    let pair = Ed25519KeyPair::from_pkcs8(bytes)
+      .map_err(|_| EnrollError::BadKey)?;
+    return Ok(Self { pair: pair });
+  }
+
+  /// The public key, in base64.
+  pub fn public_key(&self) -> String {
+    return base64::encode(self.pair.public_key().as_ref());
+  }
+
+  /// A request to enroll, or to know whether we were, signed now.
+  pub fn request(&self, uid: BrokerId, token: &str) -> EnrollRequest {
+    let when = Local::now().timestamp();
+    let sig = self.pair.sign(&signed_bytes(&uid, token, when));
+    return EnrollRequest {
+      uid: uid,
+      public_key: self.public_key(),
+      token: token.to_owned(),
+      when: when,
+      signature: base64::encode(sig.as_ref())
+    };
+  }
+}
@@ -0,0 +1,229 @@
+//! Sealed bundles, encrypted and authenticated end to end between a broker
+//! and the API, for uploads over networks nobody trusts when TLS can't be
+//! terminated where it should. Each broker has a 256-bit key the API knows
+//! too, given in base64, like `head -c 32 /dev/urandom | base64` makes.
+//!
+//! A sealed body is a random 96-bit nonce followed by the ChaCha20-Poly1305
+//! ciphertext of the original content type, a newline, and the original
+//! body. The broker's UID, sent along in a header, is the associated data,
+//! so a bundle can't be passed off as another broker's.
+
+use std::error::Error;
+use std::fmt::{Debug, Display};
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::ids::BrokerId;
+
+/// Content type of sealed bodies.
+pub const CONTENT_TYPE: &str = "application/x-cdp-sealed";
+
+/// Header carrying the UID of the broker that sealed a body.
+pub const BROKER_HEADER: &str = "x-cdp-broker";
+
+/// Length of a key, in bytes.
+pub const KEY_BYTES: usize = 32;
+
+/// Why something couldn't be sealed or opened.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SealError {
+  /// The key isn't KEY_BYTES of base64.
+  BadKey,
+  /// No randomness to be had for a nonce.
+  NoRandomness,
+  /// Too short to be sealed.
+  TooShort,
+  /// Tampered with, sealed with another key, or by another broker.
+  Forged,
+  /// Opened fine, but there was no content type inside.
+  NoContentType
+}
+
+impl Error for SealError {}
+
+impl Display for SealError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return write!(f, "{}", match self {
+      SealError::BadKey => "Keys must be 32 bytes, in base64.",
+      SealError::NoRandomness => "Couldn't get randomness for a nonce.",
+      SealError::TooShort => "Too short to be sealed.",
+      SealError::Forged => "Doesn't open with the key.",
+      SealError::NoContentType => "No content type inside.",
+    });
+  }
+}
+
+/// A broker's key.
+#[derive(Clone)]
+pub struct SealingKey {
+  /// The key itself.
+  bytes: [u8; KEY_BYTES]
+}
+
+impl Debug for SealingKey {
+  /// Keeps the key out of logs.
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return write!(f, "SealingKey(..)");
+  }
+}
+
+impl SealingKey {
+  /// Reads a key from base64.
+  pub fn from_base64(s: &str) -> Result<Self, SealError> {
+    let raw = base64::decode(s.trim()).map_err(|_| SealError::BadKey)?;
+    if raw.len() != KEY_BYTES {
+      return Err(SealError::BadKey);
+    }
+    let mut bytes = [0u8; KEY_BYTES];
+    bytes.copy_from_slice(&raw);
+    return Ok(Self { bytes: bytes });
+  }
+
+  /// The key, as ring wants it.
+  fn key(&self) -> LessSafeKey {
+    let unbound = UnboundKey::new(&aead::CHACHA20_POLY1305, &self.bytes)
+      .expect("ChaCha20-Poly1305 keys are 32 bytes");
+    return LessSafeKey::new(unbound);
+  }
+
+  /// Seals a body of some content type, as sent by a broker.
+  pub fn seal(&self, broker_id: &BrokerId, content_type: &str, body: &[u8])
+  -> Result<Vec<u8>, SealError> {
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    SystemRandom::new()
+      .fill(&mut nonce)
+      .map_err(|_| SealError::NoRandomness)?;
+    let mut data = Vec::with_capacity(content_type.len() + 1 + body.len());
+    data.extend_from_slice(content_type.as_bytes());
+    data.push(b'\n');
+    data.extend_from_slice(body);
+    self.key()
+      .seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(broker_id.as_bytes()),
+        &mut data
+      )
+      .map_err(|_| SealError::Forged)?;
+    let mut out = nonce.to_vec();
+    out.append(&mut data);
+    return Ok(out);
+  }
+
+  /// Opens a body sealed by a broker. Returns the content type and body
+  /// within.
+  pub fn open(&self, broker_id: &BrokerId, sealed: &[u8])
+  -> Result<(String, Vec<u8>), SealError> {
+    if sealed.len() < aead::NONCE_LEN + aead::MAX_TAG_LEN {
+      return Err(SealError::TooShort);
+    }
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    nonce.copy_from_slice(&sealed[..aead::NONCE_LEN]);
+    let mut data = sealed[aead::NONCE_LEN..].to_vec();
+    let plain = self.key()
+      .open_in_place(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(broker_id.as_bytes()),
+        &mut data
+      )
+      .map_err(|_| SealError::Forged)?;
+    let nl = plain.iter()
+      .position(|b| *b == b'\n')
+      .ok_or(SealError::NoContentType)?;
+    let ctype = std::str::from_utf8(&plain[..nl])
+      .map_err(|_| SealError::NoContentType)?
+      .to_owned();
+    return Ok((ctype, plain[nl + 1..].to_vec()));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A key of all the same byte.
+  fn key(b: u8) -> SealingKey {
+    return SealingKey::from_base64(&base64::encode([b; KEY_BYTES])).unwrap();
+  }
+
+  #[test]
+  fn sealed_bodies_open() {
+    let uid = BrokerId::new_v4();
+    let sealed = key(1).seal(&uid, "application/json", b"[]").unwrap();
+    let (ctype, body) = key(1).open(&uid, &sealed).unwrap();
+    assert_eq!(ctype, "application/json");
+    assert_eq!(body, b"[]");
+  }
+
+  #[test]
+  fn the_same_body_seals_differently_every_time() {
+    let uid = BrokerId::new_v4();
+    let once = key(1).seal(&uid, "application/json", b"[]").unwrap();
+    let twice = key(1).seal(&uid, "application/json", b"[]").unwrap();
+    assert_ne!(once, twice);
+  }
+
+  #[test]
+  fn tampered_bodies_dont_open() {
+    let uid = BrokerId::new_v4();
+    let sealed = key(1).seal(&uid, "application/json", b"[]").unwrap();
+    for i in 0..sealed.len() {
+      let mut tampered = sealed.clone();
+      tampered[i] ^= 1;
+      assert_eq!(key(1).open(&uid, &tampered), Err(SealError::Forged));
+    }
+    let mut longer = sealed.clone();
+    longer.push(0);
+    assert_eq!(key(1).open(&uid, &longer), Err(SealError::Forged));
+    let shorter = &sealed[..sealed.len() - 1];
+    assert_eq!(key(1).open(&uid, shorter), Err(SealError::Forged));
+  }
+
+  #[test]
+  fn bodies_dont_open_with_another_key() {
+    let uid = BrokerId::new_v4();
+    let sealed = key(1).seal(&uid, "application/json", b"[]").unwrap();
+    assert_eq!(key(2).open(&uid, &sealed), Err(SealError::Forged));
+  }
+
+  #[test]
+  fn bodies_replayed_as_another_broker_dont_open() {
+    let (uid, other) = (BrokerId::new_v4(), BrokerId::new_v4());
+    let sealed = key(1).seal(&uid, "application/json", b"[]").unwrap();
+    assert_eq!(key(1).open(&other, &sealed), Err(SealError::Forged));
+  }
+
+  #[test]
+  fn short_bodies_dont_open() {
+    let uid = BrokerId::new_v4();
+    let short = [0u8; aead::NONCE_LEN + aead::MAX_TAG_LEN - 1];
+    assert_eq!(key(1).open(&uid, &short), Err(SealError::TooShort));
+  }
+
+  #[test]
+  fn bodies_without_a_content_type_dont_open() {
+    let uid = BrokerId::new_v4();
+    let nonce = [0u8; aead::NONCE_LEN];
+    // seal() always puts a newline in, so seal without one by hand
+    let mut bare = b"no newline".to_vec();
+    key(1).key()
+      .seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(uid.as_bytes()),
+        &mut bare
+      )
+      .unwrap();
+    let mut sealed = nonce.to_vec();
+    sealed.append(&mut bare);
+    assert_eq!(key(1).open(&uid, &sealed), Err(SealError::NoContentType));
+  }
+
+  #[test]
+  fn keys_must_be_32_bytes_of_base64() {
+    let short = base64::encode([1u8; KEY_BYTES - 1]);
+    assert!(SealingKey::from_base64("not base64!").is_err());
+    assert!(SealingKey::from_base64(&short).is_err());
+    let padded = format!(" {}\n", base64::encode([1u8; KEY_BYTES]));
+    assert!(SealingKey::from_base64(&padded).is_ok());
+  }
+}
@@ -7,11 +7,16 @@ use std::str::FromStr;
 use serde::de::DeserializeOwned;
 use serde::{Serialize, Deserialize};
 
-/// Any measurement message.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum AnySensorMessage {
-  Temperature(TemperatureMessage),
-  Humidity(HumidityMessage)
+use crate::comm::decoders;
+use crate::ids::SensorId;
+use crate::units::{AnyReading, HumidityReading, TemperatureReading};
+use crate::wire::{topics, WireError};
+
+pub use crate::wire::{DeviceHealthMessage, HumidityMessage, TemperatureMessage};
+
+sensor_types! {
+  Temperature(TemperatureMessage) => topics::TEMPERATURE,
+  Humidity(HumidityMessage) => topics::HUMIDITY
 }
 
 impl AnySensorMessage {
@@ -20,52 +25,50 @@ impl AnySensorMessage {
     return self.into();
   }
 
-  /// Decodes the sensor message from a topic name and a byte sequence.
+  /// Decodes the sensor message from a topic name and a byte sequence, as
+  /// per the topic's decoder.
   pub fn decode<T: AsRef<Vec<u8>>>(topic: &str, data: T)
   -> Result<AnySensorMessage, MessageParseError> {
-    return match topic {
-      "temperature" => Ok(AnySensorMessage::Temperature(
-        TemperatureMessage::try_from(data.as_ref())?
-      )),
-      "humidity" => Ok(AnySensorMessage::Humidity(
-        HumidityMessage::try_from(data.as_ref())?
-      )),
-      _ => Err(MessageParseError::BadTopic(topic.to_owned()))
-    }
+    let dec = decoders::find(topic)
+      .ok_or_else(|| MessageParseError::BadTopic(topic.to_owned()))?;
+    return (dec.decode)(data.as_ref());
   }
 
-  /// Returns the sensor ID within.
-  pub fn sensor_id(&self) -> usize {
-    return match self {
-      AnySensorMessage::Temperature(tm) => tm.get_sensor_id(),
-      AnySensorMessage::Humidity(hm) => hm.get_sensor_id(),
-    }
+  /// Decodes the sensor message from a topic name and the message's JSON
+  /// form, like {"sensor_id": 3, "kelvin": 295}.
+  pub fn decode_json(topic: &str, data: &[u8])
+  -> Result<AnySensorMessage, MessageParseError> {
+    let dec = decoders::find(topic)
+      .ok_or_else(|| MessageParseError::BadTopic(topic.to_owned()))?;
+    return (dec.decode_json)(data);
   }
-}
 
-/// Types of measurement messages.
-#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
-pub enum SensorType {
-  Temperature,
-  Humidity
-}
-
-impl SensorType {
-  /// Returns a vector with all types.
-  pub fn all_types() -> Vec<Self> {
-    return vec![
-      Self::Temperature,
-      Self::Humidity
-    ]
+  /// Builds a message from a value in the unit people usually read it in:
+  /// °C for temperature, %RH for humidity. None if it doesn't fit the wire
+  /// format.
+  pub fn from_human_value(stype: SensorType, sensor_id: SensorId, value: f64)
+  -> Option<AnySensorMessage> {
+    return match stype {
+      SensorType::Temperature => TemperatureReading::from_celsius(value)
+        .map(|t| AnySensorMessage::Temperature(TemperatureMessage {
+          sensor_id: sensor_id.0,
+          kelvin: t.raw()
+        })),
+      SensorType::Humidity => HumidityReading::from_fraction(value / 100.0)
+        .map(|h| AnySensorMessage::Humidity(HumidityMessage {
+          sensor_id: sensor_id.0,
+          humidity: h.raw()
+        })),
+    };
   }
-}
 
-impl From<&AnySensorMessage> for SensorType {
-  /// Extract the type of sensor from the message.
-  fn from(msg: &AnySensorMessage) -> Self {
-    return match msg {
-      AnySensorMessage::Temperature(_) => Self::Temperature,
-      AnySensorMessage::Humidity(_) => Self::Humidity,
+  /// Returns the typed reading within.
+  pub fn reading(&self) -> AnyReading {
+    return match self {
+      AnySensorMessage::Temperature(tm) => {
+        AnyReading::Temperature(tm.reading())
+      },
+      AnySensorMessage::Humidity(hm) => AnyReading::Humidity(hm.reading()),
     }
   }
 }
@@ -84,10 +87,7 @@ impl FromStr for SensorType {
 
 impl Display for SensorType {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    return write!(f, "{}", match self {
-      SensorType::Temperature => "temperature",
-      SensorType::Humidity => "humidity",
-    });
+    return write!(f, "{}", self.name());
   }
 }
 
@@ -98,7 +98,27 @@ pub enum MessageParseError {
   /// Bad length: expected first, got last.
   BadLength(usize, usize),
   /// Bad topic name.
-  BadTopic(String)
+  BadTopic(String),
+  /// Bad JSON form of a message.
+  BadJson(String),
+  /// Unsigned, or signed wrong, by a sensor that has a key.
+  BadSignature
+}
+
+impl From<serde_json::Error> for MessageParseError {
+  fn from(e: serde_json::Error) -> Self {
+    return MessageParseError::BadJson(e.to_string());
+  }
+}
+
+impl From<WireError> for MessageParseError {
+  fn from(e: WireError) -> Self {
+    return match e {
+      WireError::BadLength { expected, got } => {
+        MessageParseError::BadLength(expected, got)
+      },
+    };
+  }
 }
 
 impl Error for MessageParseError {}
@@ -112,6 +132,12 @@ impl Display for MessageParseError {
       MessageParseError::BadTopic(tn) => {
         write!(f, "Bad topic name \"{}\".", tn)
       },
+      MessageParseError::BadJson(e) => {
+        write!(f, "Bad JSON: {}", e)
+      },
+      MessageParseError::BadSignature => {
+        write!(f, "Missing or bad signature.")
+      },
     };
   }
 }
@@ -119,85 +145,30 @@ impl Display for MessageParseError {
 /// The kind of stuff all sensor messages can do.
 pub trait SensorMessage: Copy + Clone + std::fmt::Debug
 + TryFrom<Vec<u8>, Error=MessageParseError> + Serialize + DeserializeOwned {
-  /// Return the sensor ID as an usize.
-  fn get_sensor_id(&self) -> usize;
-}
-
-/// Message sent by a temperature sensor.
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
-pub struct TemperatureMessage {
-  /// Numeric ID of the sensor.
-  pub sensor_id: u8,
-  /// Temperature value in K.
-  pub kelvin: u16
-}
-
-impl TryFrom<&Vec<u8>> for TemperatureMessage {
-  /// No good though.
-  type Error = MessageParseError;
-  /// Convert a three-byte sequence into a temperature message.
-  fn try_from(data: &Vec<u8>) -> Result<Self, Self::Error> {
-    if data.len() != 3 {
-      return Err(Self::Error::BadLength(3, data.len()));
-    } else {
-      let (e1, e2, e3): (u8, u16, u16)
-        = (data[0], data[1] as u16, data[2] as u16);
-      return Ok(Self {
-        sensor_id: e1,
-        kelvin: ((e2 as u16) << 8) + e3
-      });
-    }
-  }
+  /// Return the sensor ID.
+  fn get_sensor_id(&self) -> SensorId;
+  /// Encode the message into the bytes a sensor would publish.
+  fn to_bytes(&self) -> Vec<u8>;
 }
 
-impl TryFrom<Vec<u8>> for TemperatureMessage {
-  type Error = MessageParseError;
-  fn try_from(vec: Vec<u8>) -> Result<Self, Self::Error> {
-    return Self::try_from(&vec);
-  }
-}
-
-impl SensorMessage for TemperatureMessage {
-  fn get_sensor_id(&self) -> usize {
-    return self.sensor_id as usize;
-  }
-}
-
-/// Message sent by a humidity sensor.
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
-pub struct HumidityMessage {
-  /// Numeric ID of the sensor.
-  pub sensor_id: u8,
-  /// Humidity value in relative humidity percentage.
-  pub humidity: u8
-}
-
-impl TryFrom<&Vec<u8>> for HumidityMessage {
-  /// No good though.
-  type Error = MessageParseError;
-  /// Convert a two-byte sequence into a temperature message.
-  fn try_from(data: &Vec<u8>) -> Result<Self, Self::Error> {
-    if data.len() != 2 {
-      return Err(Self::Error::BadLength(3, data.len()));
-    } else {
-      let (e1, e2) = (data[0], data[1]);
-      return Ok(Self {
-        sensor_id: e1,
-        humidity: e2
-      });
-    }
+impl TemperatureMessage {
+  /// Returns the temperature as a typed reading.
+  pub fn reading(&self) -> TemperatureReading {
+    return TemperatureReading::from_kelvin(self.kelvin);
   }
 }
 
-impl TryFrom<Vec<u8>> for HumidityMessage {
-  type Error = MessageParseError;
-  fn try_from(vec: Vec<u8>) -> Result<Self, Self::Error> {
-    return Self::try_from(&vec);
+impl HumidityMessage {
+  /// Returns the humidity as a typed reading.
+  pub fn reading(&self) -> HumidityReading {
+    return HumidityReading::from_percent(self.humidity);
   }
 }
 
-impl SensorMessage for HumidityMessage {
-  fn get_sensor_id(&self) -> usize {
-    return self.sensor_id as usize;
+impl DeviceHealthMessage {
+  /// Decodes the message's JSON form, like {"sensor_id": 3, "battery": 80,
+  /// "rssi": -60, "uptime_secs": 3600}.
+  pub fn decode_json(data: &[u8]) -> Result<Self, MessageParseError> {
+    return Ok(serde_json::from_slice(data)?);
   }
 }
@@ -0,0 +1,134 @@
+//! Signed sensor payloads. A sensor with a key appends an HMAC-SHA256 of
+//! the topic and payload to what it publishes, truncated to TAG_BYTES so
+//! small radios don't mind, and the broker checks it before taking the
+//! reading in.
+//!
+//! Raw payloads have the tag appended as bytes. JSON payloads have it
+//! appended as lowercase hex, so they stay text.
+
+use ring::constant_time;
+use ring::hmac;
+
+/// Length of a tag, in bytes.
+pub const TAG_BYTES: usize = 8;
+
+/// A truncated HMAC tag.
+pub type Tag = [u8; TAG_BYTES];
+
+/// The tag for a payload published to a topic. The topic is covered too,
+/// so a humidity reading can't be replayed as a temperature one.
+pub fn tag(key: &[u8], topic: &str, payload: &[u8]) -> Tag {
+  let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+  let mut ctx = hmac::Context::with_key(&key);
+  ctx.update(topic.as_bytes());
+  ctx.update(&[0]);
+  ctx.update(payload);
+  let mut out = [0u8; TAG_BYTES];
+  out.copy_from_slice(&ctx.sign().as_ref()[..TAG_BYTES]);
+  return out;
+}
+
+/// Whether a tag is right for a payload, taking the same time either way.
+pub fn verify(key: &[u8], topic: &str, payload: &[u8], given: &Tag) -> bool {
+  let want = tag(key, topic, payload);
+  return constant_time::verify_slices_are_equal(&want, given).is_ok();
+}
+
+/// A raw payload with its tag appended, as a sensor would publish it.
+pub fn sign(key: &[u8], topic: &str, payload: &[u8]) -> Vec<u8> {
+  let mut out = payload.to_vec();
+  out.extend_from_slice(&tag(key, topic, payload));
+  return out;
+}
+
+/// A JSON payload with its tag appended in hex, as a sensor would publish
+/// it.
+pub fn sign_json(key: &[u8], topic: &str, json: &[u8]) -> Vec<u8> {
+  let mut out = json.to_vec();
+  for b in tag(key, topic, json).iter() {
+    out.extend_from_slice(format!("{:02x}", b).as_bytes());
+  }
+  return out;
+}
+
+/// Splits a raw payload into what was signed and the tag. None if it's
+/// too short to have one.
+pub fn split(data: &[u8]) -> Option<(&[u8], Tag)> {
+  let at = data.len().checked_sub(TAG_BYTES)?;
+  let mut tag = [0u8; TAG_BYTES];
+  tag.copy_from_slice(&data[at..]);
+  return Some((&data[..at], tag));
+}
+
+/// Splits a JSON payload into what was signed and the tag. None if it
+/// doesn't end in one.
+pub fn split_json(data: &[u8]) -> Option<(&[u8], Tag)> {
+  let at = data.len().checked_sub(TAG_BYTES * 2)?;
+  let hex = std::str::from_utf8(&data[at..]).ok()?;
+  let mut tag = [0u8; TAG_BYTES];
+  for (i, b) in tag.iter_mut().enumerate() {
+    *b = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+  }
+  return Some((&data[..at], tag));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const KEY: &[u8] = b"a sensor's key";
+  const TOPIC: &str = "cdp/temperature/1";
+
+  #[test]
+  fn signed_raw_payloads_verify() {
+    let signed = sign(KEY, TOPIC, b"\x01\x02\x03");
+    let (payload, tag) = split(&signed).unwrap();
+    assert_eq!(payload, b"\x01\x02\x03");
+    assert!(verify(KEY, TOPIC, payload, &tag));
+  }
+
+  #[test]
+  fn signed_json_payloads_verify_and_stay_text() {
+    let json = br#"{"sensor_id":1,"kelvin":29500}"#;
+    let signed = sign_json(KEY, TOPIC, json);
+    assert!(std::str::from_utf8(&signed).is_ok());
+    let (payload, tag) = split_json(&signed).unwrap();
+    assert_eq!(payload, &json[..]);
+    assert!(verify(KEY, TOPIC, payload, &tag));
+  }
+
+  #[test]
+  fn tampered_payloads_dont_verify() {
+    let mut signed = sign(KEY, TOPIC, b"\x01\x02\x03");
+    signed[1] ^= 1;
+    let (payload, tag) = split(&signed).unwrap();
+    assert!(!verify(KEY, TOPIC, payload, &tag));
+    let mut signed = sign(KEY, TOPIC, b"\x01\x02\x03");
+    let last = signed.len() - 1;
+    signed[last] ^= 1;
+    let (payload, tag) = split(&signed).unwrap();
+    assert!(!verify(KEY, TOPIC, payload, &tag));
+  }
+
+  #[test]
+  fn payloads_dont_verify_with_another_key() {
+    let signed = sign(KEY, TOPIC, b"\x01\x02\x03");
+    let (payload, tag) = split(&signed).unwrap();
+    assert!(!verify(b"another key", TOPIC, payload, &tag));
+  }
+
+  #[test]
+  fn payloads_replayed_to_another_topic_dont_verify() {
+    let signed = sign(KEY, TOPIC, b"\x01\x02\x03");
+    let (payload, tag) = split(&signed).unwrap();
+    assert!(!verify(KEY, "cdp/humidity/1", payload, &tag));
+    assert!(!verify(KEY, "cdp/temperature/2", payload, &tag));
+  }
+
+  #[test]
+  fn unsigned_payloads_dont_split() {
+    assert_eq!(split(&[0u8; TAG_BYTES - 1]), None);
+    assert_eq!(split_json(b"{}"), None);
+    assert_eq!(split_json(br#"{"kelvin":29500}"#), None);
+  }
+}
@@ -0,0 +1,66 @@
+//! Broker self-updates. An admin schedules one on the API, which hands the
+//! order to the broker with its next heartbeat reply. The broker downloads
+//! the artifact, checks it against the SHA-256 in the order, and has its
+//! update hook swap it in, reporting how it went with its heartbeats.
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// An update for a broker to install.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdateOrder {
+  /// Tells orders apart, so reports can say which one they're about.
+  pub id: Uuid,
+  /// Version being installed, like "0.2.0".
+  pub version: String,
+  /// Where to download the artifact from.
+  pub url: String,
+  /// SHA-256 of the artifact, in hex.
+  pub sha256: String
+}
+
+/// How an update went, or is going.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateState {
+  /// Being downloaded and checked.
+  Downloading,
+  /// Handed to the update hook, which took it.
+  Installed,
+  /// Didn't happen. The report says why.
+  Failed
+}
+
+impl UpdateState {
+  /// Whether there's nothing more to come.
+  pub fn is_final(&self) -> bool {
+    return *self != UpdateState::Downloading;
+  }
+}
+
+/// A broker's word on an update.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdateReport {
+  /// The order it's about.
+  pub id: Uuid,
+  /// How it's going.
+  pub state: UpdateState,
+  /// Why it failed, or what the hook said.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub message: Option<String>
+}
+
+/// Whether some bytes are what a SHA-256, in hex, says they are. Case
+/// doesn't matter.
+pub fn checksum_matches(data: &[u8], sha256: &str) -> bool {
+  let digest = ring::digest::digest(&ring::digest::SHA256, data);
+  let hex: String = digest.as_ref().iter()
+    .map(|b| format!("{:02x}", b))
+    .collect();
+  return hex.eq_ignore_ascii_case(sha256.trim());
+}
+
+/// Whether a string looks like a SHA-256 in hex.
+pub fn is_sha256(s: &str) -> bool {
+  return s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit());
+}
@@ -0,0 +1,143 @@
+//! The wire module, for firmware written in C. Each message type gets a
+//! function that encodes it into a buffer, so the payloads always match what
+//! the broker decodes. The header, include/cdp_wire.h, is generated from
+//! this module by cbindgen, see cbindgen.toml; regenerate it after changing
+//! anything here.
+//!
+//! Where there's std, as on ESP-IDF, build libcdp as a static library to
+//! link against, like:
+//!
+//! ```text
+//! cargo rustc -p libcdp --release --features ffi --crate-type staticlib
+//! ```
+//!
+//! Bare targets have no std, and need a #[panic_handler] libcdp doesn't
+//! have, so there it takes a small staticlib crate of one's own, depending
+//! on libcdp with default-features = false and features = ["ffi"].
+
+use crate::wire::{self, DeviceHealthMessage, HumidityMessage, TemperatureMessage};
+
+/// Bytes a temperature message takes.
+pub const CDP_TEMPERATURE_LENGTH: usize = 3;
+/// Bytes a humidity message takes.
+pub const CDP_HUMIDITY_LENGTH: usize = 2;
+/// Bytes a device health message takes.
+pub const CDP_DEVICE_HEALTH_LENGTH: usize = 7;
+
+// the header can only have literals, so check they're right.
+const _: () = assert!(CDP_TEMPERATURE_LENGTH == TemperatureMessage::LENGTH);
+const _: () = assert!(CDP_HUMIDITY_LENGTH == HumidityMessage::LENGTH);
+const _: () = assert!(
+  CDP_DEVICE_HEALTH_LENGTH == DeviceHealthMessage::LENGTH
+);
+
+/// Types of messages a sensor may publish.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CdpMessageType {
+  /// TemperatureMessage.
+  Temperature,
+  /// HumidityMessage.
+  Humidity,
+  /// DeviceHealthMessage.
+  DeviceHealth
+}
+
+/// Turns what C handed us into a buffer. None if it's null.
+unsafe fn buffer<'a>(out: *mut u8, out_len: usize) -> Option<&'a mut [u8]> {
+  if out.is_null() {
+    return None;
+  }
+  return Some(core::slice::from_raw_parts_mut(out, out_len));
+}
+
+/// Encodes a message into a buffer. 0 if it didn't fit.
+unsafe fn encode_with<F>(out: *mut u8, out_len: usize, encode: F) -> usize
+where F: FnOnce(&mut [u8]) -> Result<usize, wire::WireError> {
+  return match buffer(out, out_len) {
+    Some(buf) => encode(buf).unwrap_or(0),
+    None => 0,
+  };
+}
+
+/// Bytes a message of some type takes.
+#[no_mangle]
+pub extern "C" fn cdp_message_length(mtype: CdpMessageType) -> usize {
+  return match mtype {
+    CdpMessageType::Temperature => TemperatureMessage::LENGTH,
+    CdpMessageType::Humidity => HumidityMessage::LENGTH,
+    CdpMessageType::DeviceHealth => DeviceHealthMessage::LENGTH,
+  };
+}
+
+/// Writes the topic messages of some type go to into a buffer, followed by
+/// a NUL. Returns the topic's length, without the NUL; 0 if it didn't fit.
+///
+/// # Safety
+///
+/// out must be null, or point to at least out_len writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cdp_topic(
+  mtype: CdpMessageType, out: *mut u8, out_len: usize
+) -> usize {
+  let topic = match mtype {
+    CdpMessageType::Temperature => wire::topics::TEMPERATURE,
+    CdpMessageType::Humidity => wire::topics::HUMIDITY,
+    CdpMessageType::DeviceHealth => wire::topics::DEVICE_HEALTH,
+  };
+  let buf = match buffer(out, out_len) {
+    Some(b) if b.len() > topic.len() => b,
+    _ => return 0,
+  };
+  buf[..topic.len()].copy_from_slice(topic.as_bytes());
+  buf[topic.len()] = 0;
+  return topic.len();
+}
+
+/// Encodes a temperature message into a buffer. Returns how many bytes were
+/// written; 0 if it didn't fit.
+///
+/// # Safety
+///
+/// out must be null, or point to at least out_len writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cdp_encode_temperature(
+  sensor_id: u8, kelvin: u16, out: *mut u8, out_len: usize
+) -> usize {
+  let msg = TemperatureMessage { sensor_id: sensor_id, kelvin: kelvin };
+  return encode_with(out, out_len, |buf| msg.encode_into(buf));
+}
+
+/// Encodes a humidity message into a buffer. Returns how many bytes were
+/// written; 0 if it didn't fit.
+///
+/// # Safety
+///
+/// out must be null, or point to at least out_len writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cdp_encode_humidity(
+  sensor_id: u8, humidity: u8, out: *mut u8, out_len: usize
+) -> usize {
+  let msg = HumidityMessage { sensor_id: sensor_id, humidity: humidity };
+  return encode_with(out, out_len, |buf| msg.encode_into(buf));
+}
+
+/// Encodes a device health message into a buffer. Returns how many bytes
+/// were written; 0 if it didn't fit.
+///
+/// # Safety
+///
+/// out must be null, or point to at least out_len writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cdp_encode_device_health(
+  sensor_id: u8, battery: u8, rssi: i8, uptime_secs: u32, out: *mut u8,
+  out_len: usize
+) -> usize {
+  let msg = DeviceHealthMessage {
+    sensor_id: sensor_id,
+    battery: battery,
+    rssi: rssi,
+    uptime_secs: uptime_secs
+  };
+  return encode_with(out, out_len, |buf| msg.encode_into(buf));
+}
@@ -0,0 +1,111 @@
+//! IDs of the things flying around, each a type of its own, so a broker's
+//! ID can't be passed where a message's goes, nor a sensor's where some
+//! other number does. They go through serde as what's inside, so nothing
+//! changes on the wire or in storage.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A sensor's ID, unique among sensors of its type. It's a single byte on
+/// the wire, so there are 256 of each.
+#[derive(
+  Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord,
+  Serialize, Deserialize
+)]
+#[serde(transparent)]
+pub struct SensorId(pub u8);
+
+impl Display for SensorId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    return write!(f, "{}", self.0);
+  }
+}
+
+impl FromStr for SensorId {
+  type Err = std::num::ParseIntError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    return Ok(SensorId(s.parse()?));
+  }
+}
+
+impl From<u8> for SensorId {
+  fn from(id: u8) -> Self {
+    return SensorId(id);
+  }
+}
+
+impl From<SensorId> for u8 {
+  fn from(id: SensorId) -> Self {
+    return id.0;
+  }
+}
+
+impl From<SensorId> for usize {
+  fn from(id: SensorId) -> Self {
+    return id.0 as usize;
+  }
+}
+
+/// Defines an ID that's a UUID underneath.
+macro_rules! uuid_id {
+  ($(#[$meta:meta])* $name:ident) => {
+    $(#[$meta])*
+    #[derive(
+      Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord,
+      Serialize, Deserialize
+    )]
+    #[serde(transparent)]
+    pub struct $name(pub Uuid);
+
+    impl $name {
+      /// A brand new, random one.
+      pub fn new_v4() -> Self {
+        return $name(Uuid::new_v4());
+      }
+
+      /// The UUID's bytes, for keys.
+      pub fn as_bytes(&self) -> &[u8; 16] {
+        return self.0.as_bytes();
+      }
+    }
+
+    impl Display for $name {
+      fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}", self.0);
+      }
+    }
+
+    impl FromStr for $name {
+      type Err = uuid::Error;
+      fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return Ok($name(Uuid::parse_str(s)?));
+      }
+    }
+
+    impl From<Uuid> for $name {
+      fn from(id: Uuid) -> Self {
+        return $name(id);
+      }
+    }
+
+    impl From<$name> for Uuid {
+      fn from(id: $name) -> Self {
+        return id.0;
+      }
+    }
+  };
+}
+
+uuid_id! {
+  /// A broker's ID, which it picks once and keeps for good.
+  BrokerId
+}
+
+uuid_id! {
+  /// A message's ID, picked by whoever made it, and how copies of it are
+  /// told apart from others.
+  MessageId
+}
@@ -1,3 +1,22 @@
-//! Export the inner modules.
+//! Export the inner modules. Without the std feature, only wire is left,
+//! for firmware to share the message layouts with.
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[macro_use]
+mod macros;
+#[cfg(feature = "std")]
 pub mod comm;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod ids;
+#[cfg(feature = "protobuf")]
+pub mod proto;
+#[cfg(feature = "std")]
+pub mod runtime;
+#[cfg(all(unix, feature = "systemd"))]
+pub mod systemd;
+#[cfg(feature = "std")]
+pub mod units;
+pub mod wire;
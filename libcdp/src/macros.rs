@@ -0,0 +1,269 @@
+//! Macros for defining sensor messages and types, so adding one takes a
+//! struct with its fields and a line in the list of types, instead of
+//! edits all over comm::sensor_broker. The struct goes in wire, so firmware
+//! gets it too.
+
+/// Defines a sensor message struct, along with its wire codec. The first
+/// field must be `sensor_id: u8`, followed by at least one more; each of
+/// those must be a primitive integer, and goes on the wire big-endian,
+/// taking as many bytes as its type does, in order. The struct gets LENGTH,
+/// decode and encode_into, which need nothing but core, so firmware can
+/// use them too. With libcdp's std feature, it also gets serde, TryFrom for
+/// byte vectors and SensorMessage; serde must then be a dependency wherever
+/// it's used.
+///
+/// ```ignore
+/// sensor_message! {
+///   /// Message sent by a pressure sensor.
+///   pub struct PressureMessage {
+///     /// Numeric ID of the sensor.
+///     sensor_id: u8,
+///     /// Pressure, in Pa.
+///     pascals: u32
+///   }
+/// }
+/// ```
+#[macro_export]
+macro_rules! sensor_message {
+  (
+    $(#[$meta:meta])*
+    pub struct $name:ident {
+      $(#[$id_meta:meta])*
+      sensor_id: u8,
+      $(
+        $(#[$field_meta:meta])*
+        $field:ident: $ty:ty
+      ),* $(,)?
+    }
+  ) => {
+    $crate::__sensor_message_struct! {
+      [$(#[$meta])*]
+      $name {
+        $(#[$id_meta])*
+        pub sensor_id: u8,
+        $(
+          $(#[$field_meta])*
+          pub $field: $ty,
+        )*
+      }
+    }
+
+    impl $name {
+      /// Length of the message on the wire.
+      pub const LENGTH: usize = 1 $(+ core::mem::size_of::<$ty>())*;
+
+      /// Decodes the sensor ID, then every other field, big-endian.
+      #[allow(unused_assignments, unused_mut)]
+      pub fn decode(data: &[u8])
+      -> core::result::Result<Self, $crate::wire::WireError> {
+        if data.len() != Self::LENGTH {
+          return Err($crate::wire::WireError::BadLength {
+            expected: Self::LENGTH,
+            got: data.len()
+          });
+        }
+        let mut at = 1;
+        $(
+          let $field = {
+            let len = core::mem::size_of::<$ty>();
+            let mut buf = [0u8; core::mem::size_of::<$ty>()];
+            buf.copy_from_slice(&data[at..at + len]);
+            at += len;
+            <$ty>::from_be_bytes(buf)
+          };
+        )*
+        return Ok(Self {
+          sensor_id: data[0],
+          $($field: $field,)*
+        });
+      }
+
+      /// Encodes the message into the start of a buffer, which must fit
+      /// LENGTH bytes. Returns how many bytes were written.
+      #[allow(unused_assignments, unused_mut)]
+      pub fn encode_into(&self, out: &mut [u8])
+      -> core::result::Result<usize, $crate::wire::WireError> {
+        if out.len() < Self::LENGTH {
+          return Err($crate::wire::WireError::BadLength {
+            expected: Self::LENGTH,
+            got: out.len()
+          });
+        }
+        out[0] = self.sensor_id;
+        let mut at = 1;
+        $(
+          let bytes = self.$field.to_be_bytes();
+          out[at..at + bytes.len()].copy_from_slice(&bytes);
+          at += bytes.len();
+        )*
+        return Ok(Self::LENGTH);
+      }
+    }
+
+    $crate::__sensor_message_std!($name);
+  };
+}
+
+/// Emits a sensor message struct, with serde if there's std.
+#[cfg(feature = "std")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __sensor_message_struct {
+  ([$(#[$meta:meta])*] $name:ident { $($body:tt)* }) => {
+    $(#[$meta])*
+    #[derive(
+      Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize
+    )]
+    pub struct $name { $($body)* }
+  };
+}
+
+/// Emits a sensor message struct, with serde if there's std.
+#[cfg(not(feature = "std"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __sensor_message_struct {
+  ([$(#[$meta:meta])*] $name:ident { $($body:tt)* }) => {
+    $(#[$meta])*
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct $name { $($body)* }
+  };
+}
+
+/// Glues a sensor message to the std side of libcdp.
+#[cfg(feature = "std")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __sensor_message_std {
+  ($name:ident) => {
+    impl std::convert::TryFrom<&Vec<u8>> for $name {
+      type Error = $crate::comm::sensor_broker::MessageParseError;
+      fn try_from(data: &Vec<u8>) -> Result<Self, Self::Error> {
+        return Ok(Self::decode(data)?);
+      }
+    }
+
+    impl std::convert::TryFrom<Vec<u8>> for $name {
+      type Error = $crate::comm::sensor_broker::MessageParseError;
+      fn try_from(vec: Vec<u8>) -> Result<Self, Self::Error> {
+        return Self::try_from(&vec);
+      }
+    }
+
+    impl $crate::comm::sensor_broker::SensorMessage for $name {
+      fn get_sensor_id(&self) -> $crate::ids::SensorId {
+        return $crate::ids::SensorId(self.sensor_id);
+      }
+
+      fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![0; Self::LENGTH];
+        // can't fail, it's just the right size.
+        let _ = self.encode_into(&mut out);
+        return out;
+      }
+    }
+  };
+}
+
+/// Glues a sensor message to the std side of libcdp.
+#[cfg(not(feature = "std"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __sensor_message_std {
+  ($name:ident) => {};
+}
+
+/// Defines AnySensorMessage and SensorType, with a variant of each per
+/// sensor type, everything that goes from one to the other, and the
+/// built-in decoders, each on a topic of its own. Only for use in
+/// comm::sensor_broker.
+#[cfg(feature = "std")]
+macro_rules! sensor_types {
+  ($($variant:ident($msg:ident) => $topic:expr),* $(,)?) => {
+    /// Any measurement message.
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub enum AnySensorMessage {
+      $($variant($msg)),*
+    }
+
+    /// Types of measurement messages.
+    #[derive(
+      Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash,
+      PartialOrd, Ord
+    )]
+    pub enum SensorType {
+      $($variant),*
+    }
+
+    impl SensorType {
+      /// Returns a vector with all types.
+      pub fn all_types() -> Vec<Self> {
+        return vec![$(Self::$variant),*];
+      }
+
+      /// Name of the type, which is also the topic its sensors publish to.
+      pub fn name(&self) -> &'static str {
+        return match self {
+          $(Self::$variant => $topic),*
+        };
+      }
+    }
+
+    impl From<&AnySensorMessage> for SensorType {
+      /// Extract the type of sensor from the message.
+      fn from(msg: &AnySensorMessage) -> Self {
+        return match msg {
+          $(AnySensorMessage::$variant(_) => Self::$variant),*
+        };
+      }
+    }
+
+    impl AnySensorMessage {
+      /// Returns the sensor ID within.
+      pub fn sensor_id(&self) -> $crate::ids::SensorId {
+        return match self {
+          $(AnySensorMessage::$variant(m) => m.get_sensor_id()),*
+        };
+      }
+
+      /// Encodes the message into the bytes a sensor would publish to its
+      /// type's topic.
+      pub fn encode(&self) -> Vec<u8> {
+        return match self {
+          $(AnySensorMessage::$variant(m) => m.to_bytes()),*
+        };
+      }
+    }
+
+    $(
+      impl From<$msg> for AnySensorMessage {
+        fn from(msg: $msg) -> Self {
+          return AnySensorMessage::$variant(msg);
+        }
+      }
+
+      impl TryFrom<AnySensorMessage> for $msg {
+        /// The message, if it's of some other type.
+        type Error = AnySensorMessage;
+        fn try_from(msg: AnySensorMessage) -> Result<Self, Self::Error> {
+          return match msg {
+            AnySensorMessage::$variant(m) => Ok(m),
+            #[allow(unreachable_patterns)]
+            other => Err(other),
+          };
+        }
+      }
+    )*
+
+    /// The built-in decoders, one per sensor type.
+    pub(crate) const BUILTIN_DECODERS: &[decoders::SensorDecoder] = &[
+      $(decoders::SensorDecoder {
+        topic: $topic,
+        sensor_type: SensorType::$variant,
+        decode: decoders::decode_raw::<$msg>,
+        decode_json: decoders::decode_json::<$msg>,
+        encode: decoders::encode::<$msg>
+      }),*
+    ];
+  };
+}
@@ -0,0 +1,391 @@
+//! Protobuf encoding of the broker-API messages, a leaner alternative to
+//! JSON on the wire. See proto/cdp.proto. Only with the "protobuf" feature.
+
+use std::convert::TryFrom;
+use std::error::Error as StdError;
+use std::fmt::Display;
+
+use chrono::{DateTime, Local, TimeZone};
+use prost::Message;
+use uuid::Uuid;
+
+use crate::comm::broker_api::{BrokerMessage, BrokerMessageBundle, BrokerMessagePayload, BrokerStatus, HeartbeatMessage, RemoteConfig, SoftwareInfo};
+use crate::comm::sensor_broker::{AnySensorMessage, DeviceHealthMessage, HumidityMessage, TemperatureMessage};
+use crate::comm::update::{UpdateOrder, UpdateReport, UpdateState};
+
+/// The message types, generated from proto/cdp.proto.
+pub mod pb {
+  include!(concat!(env!("OUT_DIR"), "/cdp.rs"));
+}
+
+/// Content type of protobuf-encoded bundles.
+pub const CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// What can go wrong when decoding.
+#[derive(Debug)]
+pub enum ProtoError {
+  /// Not valid protobuf, or not a message we know.
+  Decode(String),
+  /// Valid protobuf, but a field holds nonsense. String is the field.
+  BadField(String),
+  /// Like BadField, within a bundle. The message's position, and the field.
+  BadMessage(usize, String)
+}
+
+impl StdError for ProtoError {}
+
+impl Display for ProtoError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    return match self {
+      ProtoError::Decode(e) => write!(f, "Bad protobuf: {}", e),
+      ProtoError::BadField(field) => write!(f, "Bad value for {}.", field),
+      ProtoError::BadMessage(i, field) => {
+        write!(f, "Bad value for {} in message #{}.", field, i)
+      },
+    };
+  }
+}
+
+/// Encodes a bundle.
+pub fn encode_bundle(bnd: &BrokerMessageBundle) -> Vec<u8> {
+  let msg = pb::Bundle {
+    messages: bnd.iter().map(pb::BrokerMessage::from).collect()
+  };
+  return msg.encode_to_vec();
+}
+
+/// Decodes a bundle.
+pub fn decode_bundle(data: &[u8]) -> Result<BrokerMessageBundle, ProtoError> {
+  let msg = pb::Bundle::decode(data)
+    .map_err(|e| ProtoError::Decode(e.to_string()))?;
+  return msg.messages.into_iter()
+    .enumerate()
+    .map(|(i, m)| BrokerMessage::try_from(m).map_err(|e| match e {
+      ProtoError::BadField(field) => ProtoError::BadMessage(i, field),
+      e => e,
+    }))
+    .collect();
+}
+
+/// Milliseconds since the epoch to a local time.
+fn from_ms(ms: i64) -> DateTime<Local> {
+  return Local.timestamp_millis(ms);
+}
+
+/// Like from_ms, but 0 means unknown.
+fn from_ms_opt(ms: i64) -> Option<DateTime<Local>> {
+  return if ms == 0 { None } else { Some(from_ms(ms)) };
+}
+
+/// Narrows a protobuf integer, complaining about the field if it won't fit.
+fn narrow<T: TryFrom<U>, U>(value: U, field: &str) -> Result<T, ProtoError> {
+  return T::try_from(value)
+    .map_err(|_| ProtoError::BadField(field.to_owned()));
+}
+
+/// Parses a uuid, complaining about the field if it's bad.
+fn uuid(s: &str, field: &str) -> Result<Uuid, ProtoError> {
+  return Uuid::parse_str(s)
+    .map_err(|_| ProtoError::BadField(field.to_owned()));
+}
+
+impl From<&BrokerStatus> for pb::BrokerStatus {
+  fn from(st: &BrokerStatus) -> Self {
+    return Self {
+      uptime_secs: st.uptime_secs,
+      queue_depth: st.queue_depth as u64,
+      bundle_len: st.bundle_len as u64,
+      decoded_since_last: st.decoded_since_last,
+      decode_errors_since_last: st.decode_errors_since_last,
+      dropped_since_last: st.dropped_since_last,
+      spooled: st.spooled as u64,
+      bundles_pending: st.bundles_pending as u64,
+      bundles_in_flight: st.bundles_in_flight as u64,
+      mqtt_connections: st.mqtt_connections.map(|c| c as i64).unwrap_or(-1),
+      mqtt_subscriptions: st.mqtt_subscriptions
+        .map(|c| c as i64)
+        .unwrap_or(-1),
+      acked_since_last: st.acked_since_last,
+      refused_since_last: st.refused_since_last,
+      timeouts_since_last: st.timeouts_since_last,
+      bandwidth_used_bytes: st.bandwidth_used_bytes
+        .map(|b| b as i64)
+        .unwrap_or(-1),
+      bandwidth_budget_bytes: st.bandwidth_budget_bytes
+        .map(|b| b as i64)
+        .unwrap_or(-1),
+      task_restarts_since_last: st.task_restarts_since_last
+    };
+  }
+}
+
+impl TryFrom<pb::BrokerStatus> for BrokerStatus {
+  type Error = ProtoError;
+  fn try_from(st: pb::BrokerStatus) -> Result<Self, Self::Error> {
+    return Ok(Self {
+      uptime_secs: st.uptime_secs,
+      queue_depth: narrow(st.queue_depth, "queue_depth")?,
+      bundle_len: narrow(st.bundle_len, "bundle_len")?,
+      decoded_since_last: st.decoded_since_last,
+      decode_errors_since_last: st.decode_errors_since_last,
+      dropped_since_last: st.dropped_since_last,
+      spooled: narrow(st.spooled, "spooled")?,
+      bundles_pending: narrow(st.bundles_pending, "bundles_pending")?,
+      bundles_in_flight: narrow(st.bundles_in_flight, "bundles_in_flight")?,
+      mqtt_connections: usize::try_from(st.mqtt_connections).ok(),
+      mqtt_subscriptions: usize::try_from(st.mqtt_subscriptions).ok(),
+      acked_since_last: st.acked_since_last,
+      refused_since_last: st.refused_since_last,
+      timeouts_since_last: st.timeouts_since_last,
+      bandwidth_used_bytes: u64::try_from(st.bandwidth_used_bytes).ok(),
+      bandwidth_budget_bytes: u64::try_from(st.bandwidth_budget_bytes).ok(),
+      task_restarts_since_last: st.task_restarts_since_last
+    });
+  }
+}
+
+impl From<&HeartbeatMessage> for pb::HeartbeatMessage {
+  fn from(hb: &HeartbeatMessage) -> Self {
+    return Self {
+      version: hb.version,
+      uid: hb.uid.to_string(),
+      key: hb.key.clone().unwrap_or_default(),
+      status: hb.status.as_ref().map(pb::BrokerStatus::from),
+      config_hash: hb.config_hash.clone().unwrap_or_default(),
+      update: hb.update.as_ref().map(pb::UpdateReport::from),
+      software: hb.software.as_ref().map(pb::SoftwareInfo::from)
+    };
+  }
+}
+
+impl TryFrom<pb::HeartbeatMessage> for HeartbeatMessage {
+  type Error = ProtoError;
+  fn try_from(hb: pb::HeartbeatMessage) -> Result<Self, Self::Error> {
+    return Ok(Self {
+      version: hb.version,
+      uid: uuid(&hb.uid, "uid")?.into(),
+      key: if hb.key.is_empty() { None } else { Some(hb.key) },
+      status: match hb.status {
+        Some(st) => Some(BrokerStatus::try_from(st)?),
+        None => None,
+      },
+      config_hash: if hb.config_hash.is_empty() {
+        None
+      } else {
+        Some(hb.config_hash)
+      },
+      update: match hb.update {
+        Some(up) => Some(UpdateReport::try_from(up)?),
+        None => None,
+      },
+      software: hb.software.map(SoftwareInfo::from)
+    });
+  }
+}
+
+impl From<&SoftwareInfo> for pb::SoftwareInfo {
+  fn from(sw: &SoftwareInfo) -> Self {
+    return Self {
+      version: sw.version.clone(),
+      protocol: sw.protocol,
+      min_protocol: sw.min_protocol,
+      features: sw.features.clone()
+    };
+  }
+}
+
+impl From<pb::SoftwareInfo> for SoftwareInfo {
+  fn from(sw: pb::SoftwareInfo) -> Self {
+    return Self {
+      version: sw.version,
+      protocol: sw.protocol,
+      min_protocol: sw.min_protocol,
+      features: sw.features
+    };
+  }
+}
+
+impl From<&UpdateOrder> for pb::UpdateOrder {
+  fn from(order: &UpdateOrder) -> Self {
+    return Self {
+      id: order.id.to_string(),
+      version: order.version.clone(),
+      url: order.url.clone(),
+      sha256: order.sha256.clone()
+    };
+  }
+}
+
+impl From<&UpdateReport> for pb::UpdateReport {
+  fn from(rep: &UpdateReport) -> Self {
+    return Self {
+      id: rep.id.to_string(),
+      state: match rep.state {
+        UpdateState::Downloading => "downloading",
+        UpdateState::Installed => "installed",
+        UpdateState::Failed => "failed",
+      }.to_owned(),
+      message: rep.message.clone().unwrap_or_default()
+    };
+  }
+}
+
+impl TryFrom<pb::UpdateReport> for UpdateReport {
+  type Error = ProtoError;
+  fn try_from(rep: pb::UpdateReport) -> Result<Self, Self::Error> {
+    return Ok(Self {
+      id: uuid(&rep.id, "update.id")?,
+      state: match rep.state.as_str() {
+        "downloading" => UpdateState::Downloading,
+        "installed" => UpdateState::Installed,
+        "failed" => UpdateState::Failed,
+        _ => return Err(ProtoError::BadField("update.state".to_owned())),
+      },
+      message: if rep.message.is_empty() { None } else { Some(rep.message) }
+    });
+  }
+}
+
+impl From<&RemoteConfig> for pb::RemoteConfig {
+  fn from(rc: &RemoteConfig) -> Self {
+    return Self {
+      has_topics: rc.topics.is_some(),
+      topics: rc.topics.clone().unwrap_or_default(),
+      bundle_size: rc.bundle_size.unwrap_or(0) as u64,
+      heartbeat_interval_secs: rc.heartbeat_interval_secs.unwrap_or(0)
+    };
+  }
+}
+
+impl From<&BrokerMessagePayload> for pb::broker_message::Payload {
+  fn from(pl: &BrokerMessagePayload) -> Self {
+    use pb::broker_message::Payload;
+    return match pl {
+      BrokerMessagePayload::SensorData(AnySensorMessage::Temperature(tm)) => {
+        Payload::Temperature(pb::TemperatureMessage {
+          sensor_id: tm.sensor_id as u32,
+          kelvin: tm.kelvin as u32
+        })
+      },
+      BrokerMessagePayload::SensorData(AnySensorMessage::Humidity(hm)) => {
+        Payload::Humidity(pb::HumidityMessage {
+          sensor_id: hm.sensor_id as u32,
+          humidity: hm.humidity as u32
+        })
+      },
+      BrokerMessagePayload::Heartbeat(hb) => Payload::Heartbeat(hb.into()),
+      BrokerMessagePayload::DeviceHealth(dh) => {
+        Payload::DeviceHealth(pb::DeviceHealthMessage {
+          sensor_id: dh.sensor_id as u32,
+          battery: dh.battery as u32,
+          rssi: dh.rssi as i32,
+          uptime_secs: dh.uptime_secs
+        })
+      },
+      BrokerMessagePayload::DecodeFailure { topic, bytes, error } => {
+        Payload::DecodeFailure(pb::DecodeFailure {
+          topic: topic.clone(),
+          bytes: base64::decode(bytes).unwrap_or_default(),
+          error: error.clone()
+        })
+      },
+    };
+  }
+}
+
+impl TryFrom<pb::broker_message::Payload> for BrokerMessagePayload {
+  type Error = ProtoError;
+  fn try_from(pl: pb::broker_message::Payload)
+  -> Result<Self, Self::Error> {
+    use pb::broker_message::Payload;
+    return Ok(match pl {
+      Payload::Temperature(tm) => BrokerMessagePayload::SensorData(
+        AnySensorMessage::Temperature(TemperatureMessage {
+          sensor_id: narrow(tm.sensor_id, "sensor_id")?,
+          kelvin: narrow(tm.kelvin, "kelvin")?
+        })
+      ),
+      Payload::Humidity(hm) => BrokerMessagePayload::SensorData(
+        AnySensorMessage::Humidity(HumidityMessage {
+          sensor_id: narrow(hm.sensor_id, "sensor_id")?,
+          humidity: narrow(hm.humidity, "humidity")?
+        })
+      ),
+      Payload::Heartbeat(hb) => {
+        BrokerMessagePayload::Heartbeat(HeartbeatMessage::try_from(hb)?)
+      },
+      Payload::DeviceHealth(dh) => {
+        BrokerMessagePayload::DeviceHealth(DeviceHealthMessage {
+          sensor_id: narrow(dh.sensor_id, "sensor_id")?,
+          battery: narrow(dh.battery, "battery")?,
+          rssi: narrow(dh.rssi, "rssi")?,
+          uptime_secs: dh.uptime_secs
+        })
+      },
+      Payload::DecodeFailure(df) => BrokerMessagePayload::DecodeFailure {
+        topic: df.topic,
+        bytes: base64::encode(&df.bytes),
+        error: df.error
+      },
+    });
+  }
+}
+
+impl From<&BrokerMessage> for pb::BrokerMessage {
+  fn from(msg: &BrokerMessage) -> Self {
+    return Self {
+      constructed_when_ms: msg.constructed_when.timestamp_millis(),
+      sent_when_ms: msg.sent_when.map(|t| t.timestamp_millis()).unwrap_or(0),
+      received_when_ms: msg.received_when
+        .map(|t| t.timestamp_millis())
+        .unwrap_or(0),
+      broker_id: msg.broker_id.to_string(),
+      payload: Some((&msg.payload).into()),
+      anomaly_score: msg.anomaly_score.unwrap_or(0.0),
+      tags: msg.tags.iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect(),
+      id: msg.id.map(|id| id.to_string()).unwrap_or_default(),
+      raw_payload: msg.raw_payload_bytes().unwrap_or_default(),
+      decoded_when_ms: msg.decoded_when
+        .map(|t| t.timestamp_millis())
+        .unwrap_or(0),
+      verified: msg.verified,
+      seq: msg.seq.unwrap_or(0)
+    };
+  }
+}
+
+/// What the API is told is taken as is, except for what the API itself
+/// sets, which is ignored.
+impl TryFrom<pb::BrokerMessage> for BrokerMessage {
+  type Error = ProtoError;
+  fn try_from(msg: pb::BrokerMessage) -> Result<Self, Self::Error> {
+    let payload = msg.payload
+      .ok_or_else(|| ProtoError::BadField("payload".to_owned()))?;
+    let id = if msg.id.is_empty() {
+      None
+    } else {
+      Some(uuid(&msg.id, "id")?.into())
+    };
+    let mut out = Self {
+      id: id,
+      constructed_when: from_ms(msg.constructed_when_ms),
+      decoded_when: from_ms_opt(msg.decoded_when_ms),
+      sent_when: from_ms_opt(msg.sent_when_ms),
+      received_when: None,
+      broker_id: uuid(&msg.broker_id, "broker_id")?.into(),
+      payload: BrokerMessagePayload::try_from(payload)?,
+      raw_sensor_data: None,
+      anomaly_score: None,
+      tags: msg.tags.into_iter().collect(),
+      raw_payload: None,
+      verified: msg.verified,
+      seq: None
+    };
+    if !msg.raw_payload.is_empty() {
+      out.set_raw_payload(&msg.raw_payload);
+    }
+    return Ok(out);
+  }
+}
@@ -0,0 +1,169 @@
+//! Where config files are found and where logs go, the same way for every
+//! component, so each runs in a container as it is.
+//!
+//! Config files are looked for in the working directory, or the one in
+//! CDP_CONFIG_DIR if it's set, or taken from wherever --config says; files
+//! that go with them, like cdp_rumqttd.toml, are looked for next to them.
+//!
+//! Logs only ever go to stdout (info!) and stderr (warn!), as plain lines,
+//! or with CDP_LOG_FORMAT=json, as one JSON object per line, with the time,
+//! level, component and message, for log collectors to pick up. Panics too,
+//! once init was called.
+
+use std::env;
+use std::fmt;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use config::{File, FileSourceFile};
+use serde_json::json;
+
+/// Sets the directory config files are looked for in.
+pub const CONFIG_DIR_ENV: &str = "CDP_CONFIG_DIR";
+
+/// Sets how logs are written: "text" (the default) or "json".
+pub const LOG_FORMAT_ENV: &str = "CDP_LOG_FORMAT";
+
+/// Takes a --config (or -c) and its path out of the arguments, if it's
+/// there, leaving the rest to whoever parses them.
+pub fn config_arg(args: &mut Vec<String>)
+-> Result<Option<PathBuf>, String> {
+  let mut path = None;
+  let mut i = 0;
+  while i < args.len() {
+    if let Some(p) = args[i].strip_prefix("--config=") {
+      path = Some(PathBuf::from(p));
+      args.remove(i);
+    } else if args[i] == "--config" || args[i] == "-c" {
+      if i + 1 >= args.len() {
+        return Err(format!("{} needs a path.", args[i]));
+      }
+      path = Some(PathBuf::from(args.remove(i + 1)));
+      args.remove(i);
+    } else {
+      i += 1;
+    }
+  }
+  return Ok(path);
+}
+
+/// Where config files are: next to the one given, if any, else in
+/// CDP_CONFIG_DIR, else in the working directory.
+pub fn config_dir(given: Option<&Path>) -> PathBuf {
+  if let Some(dir) = given.and_then(Path::parent) {
+    return dir.to_path_buf();
+  }
+  return env::var_os(CONFIG_DIR_ENV)
+    .map(PathBuf::from)
+    .unwrap_or_default();
+}
+
+/// A config file: the one given, if any, else the one called name (with
+/// whatever extension the config crate understands) in config_dir.
+pub fn config_file(name: &str, given: Option<&Path>)
+-> File<FileSourceFile> {
+  return match given {
+    Some(path) => File::from(path),
+    None => {
+      File::with_name(&config_dir(None).join(name).to_string_lossy())
+    },
+  };
+}
+
+/// How bad a log line is, which says where it goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+  /// Business as usual. Goes to stdout.
+  Info,
+  /// Something went wrong. Goes to stderr.
+  Warn
+}
+
+impl Level {
+  /// Its name, for JSON logs.
+  fn as_str(self) -> &'static str {
+    return match self {
+      Level::Info => "info",
+      Level::Warn => "warn",
+    };
+  }
+}
+
+/// Whether logs are JSON: 0 until the environment was looked at, then 1
+/// for plain lines and 2 for JSON.
+static JSON: AtomicU8 = AtomicU8::new(0);
+
+/// Whether logs are to be JSON, as the environment says.
+pub fn json_logs() -> bool {
+  let known = JSON.load(Ordering::Relaxed);
+  if known != 0 {
+    return known == 2;
+  }
+  let json = env::var(LOG_FORMAT_ENV).map(|f| f == "json").unwrap_or(false);
+  JSON.store(if json { 2 } else { 1 }, Ordering::Relaxed);
+  return json;
+}
+
+/// Writes a log line. Use info! and warn! instead, which fill the component
+/// in with the crate's name.
+pub fn log(level: Level, component: &str, msg: fmt::Arguments) {
+  let line = if json_logs() {
+    json!({
+      "ts": chrono::Utc::now().to_rfc3339(),
+      "level": level.as_str(),
+      "component": component,
+      "msg": msg.to_string()
+    }).to_string()
+  } else {
+    msg.to_string()
+  };
+  match level {
+    Level::Info => println!("{}", line),
+    Level::Warn => eprintln!("{}", line),
+  };
+}
+
+/// Sets logging up for a component: with JSON logs, panics are logged as
+/// JSON too, with where they happened, instead of as plain lines.
+pub fn init(component: &'static str) {
+  if !json_logs() {
+    return;
+  }
+  panic::set_hook(Box::new(move |info| {
+    let thread = std::thread::current();
+    let at = info.location()
+      .map(|l| format!(" at {}:{}", l.file(), l.line()))
+      .unwrap_or_default();
+    let payload = info.payload();
+    let msg = payload.downcast_ref::<&str>().map(|s| s.to_string())
+      .or_else(|| payload.downcast_ref::<String>().cloned())
+      .unwrap_or_default();
+    log(Level::Warn, component, format_args!(
+      "Thread '{}' panicked{}: {}",
+      thread.name().unwrap_or("<unnamed>"), at, msg
+    ));
+  }));
+}
+
+/// Logs a line to stdout, as the calling crate.
+#[macro_export]
+macro_rules! info {
+  ($($arg:tt)*) => {
+    $crate::runtime::log(
+      $crate::runtime::Level::Info, env!("CARGO_PKG_NAME"),
+      format_args!($($arg)*)
+    )
+  };
+}
+
+/// Logs a line to stderr, as the calling crate.
+#[macro_export]
+macro_rules! warn {
+  ($($arg:tt)*) => {
+    $crate::runtime::log(
+      $crate::runtime::Level::Warn, env!("CARGO_PKG_NAME"),
+      format_args!($($arg)*)
+    )
+  };
+}
@@ -0,0 +1,155 @@
+//! Talking to systemd, for when it's the one running us. Three things:
+//!
+//! - socket activation, where systemd opens the sockets and hands them over
+//!   as file descriptors 3 and up, as described by LISTEN_FDS, LISTEN_PID
+//!   and LISTEN_FDNAMES;
+//! - readiness, where Type=notify units wait on a READY=1 sent to the
+//!   datagram socket at NOTIFY_SOCKET;
+//! - the watchdog, where units with WatchdogSec= get restarted unless they
+//!   send WATCHDOG=1 every so often. WATCHDOG_USEC says how often.
+//!
+//! None of it needs libsystemd, and all of it is a no-op when we weren't
+//! started by systemd.
+
+use std::env;
+use std::io;
+use std::net::{TcpListener, UdpSocket};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::time::Duration;
+
+/// First file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Whether an environment variable meant for a PID is meant for us. Unset
+/// counts as yes.
+fn for_us(var: &str) -> bool {
+  return match env::var(var) {
+    Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+    Err(_) => true,
+  };
+}
+
+/// Sends a state update, like "READY=1", to systemd. Ok(false) if systemd
+/// isn't listening.
+pub fn notify(state: &str) -> io::Result<bool> {
+  let path = match env::var_os("NOTIFY_SOCKET") {
+    Some(p) => p,
+    None => return Ok(false),
+  };
+  let sock = UnixDatagram::unbound()?;
+  let path = path.to_string_lossy();
+  match path.strip_prefix('@') {
+    #[cfg(target_os = "linux")]
+    Some(name) => {
+      use std::os::linux::net::SocketAddrExt;
+      let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+      sock.send_to_addr(state.as_bytes(), &addr)?;
+    },
+    _ => {
+      sock.send_to(state.as_bytes(), path.as_ref())?;
+    },
+  }
+  return Ok(true);
+}
+
+/// Tells systemd we're up. Failing to is no reason to stop, so it's only
+/// logged.
+pub fn ready() {
+  if let Err(e) = notify("READY=1") {
+    crate::warn!("Couldn't tell systemd we're ready: {}", e);
+  }
+}
+
+/// Tells systemd we're on our way out.
+pub fn stopping() {
+  if let Err(e) = notify("STOPPING=1") {
+    crate::warn!("Couldn't tell systemd we're stopping: {}", e);
+  }
+}
+
+/// Tells systemd we're not hung.
+pub fn watchdog_ping() {
+  if let Err(e) = notify("WATCHDOG=1") {
+    crate::warn!("Couldn't ping the systemd watchdog: {}", e);
+  }
+}
+
+/// How often to ping the watchdog, if it's on: half its timeout, as systemd
+/// suggests. None if it's off.
+pub fn watchdog_interval() -> Option<Duration> {
+  if !for_us("WATCHDOG_PID") {
+    return None;
+  }
+  let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+  if usec == 0 {
+    return None;
+  }
+  return Some(Duration::from_micros(usec / 2));
+}
+
+/// A socket systemd opened for us.
+#[derive(Debug)]
+pub struct ListenFd {
+  /// The file descriptor itself.
+  fd: RawFd,
+  /// Its FileDescriptorName=, if any. systemd defaults it to the name of
+  /// the .socket unit.
+  pub name: Option<String>
+}
+
+/// A listening stream socket systemd opened for us.
+#[derive(Debug)]
+pub enum ActivatedListener {
+  /// From a ListenStream= with an address.
+  Tcp(TcpListener),
+  /// From a ListenStream= with a path.
+  Unix(UnixListener),
+}
+
+impl ListenFd {
+  /// Takes it as a stream socket, TCP or Unix.
+  pub fn into_listener(self) -> ActivatedListener {
+    // systemd handed it over for us, and only us, to own.
+    let unix = unsafe { UnixListener::from_raw_fd(self.fd) };
+    // only AF_UNIX sockets have Unix addresses
+    if unix.local_addr().is_ok() {
+      return ActivatedListener::Unix(unix);
+    }
+    let fd = unix.into_raw_fd();
+    return ActivatedListener::Tcp(unsafe { TcpListener::from_raw_fd(fd) });
+  }
+
+  /// Takes it as a TCP socket.
+  pub fn into_tcp(self) -> TcpListener {
+    return unsafe { TcpListener::from_raw_fd(self.fd) };
+  }
+
+  /// Takes it as a UDP socket, from a ListenDatagram=.
+  pub fn into_udp(self) -> UdpSocket {
+    return unsafe { UdpSocket::from_raw_fd(self.fd) };
+  }
+}
+
+/// Takes every socket systemd opened for us. Empty if we weren't socket
+/// activated. Only works once: the environment is cleared, so nothing we
+/// spawn mistakes them for its own.
+pub fn listen_fds() -> Vec<ListenFd> {
+  let activated = env::var_os("LISTEN_PID").is_some() && for_us("LISTEN_PID");
+  let n: RawFd = match env::var("LISTEN_FDS") {
+    Ok(n) if activated => n.parse().unwrap_or(0),
+    _ => 0,
+  };
+  let names: Vec<String> = env::var("LISTEN_FDNAMES")
+    .map(|n| n.split(':').map(String::from).collect())
+    .unwrap_or_default();
+  env::remove_var("LISTEN_PID");
+  env::remove_var("LISTEN_FDS");
+  env::remove_var("LISTEN_FDNAMES");
+  return (0..n.max(0))
+    .map(|i| ListenFd {
+      fd: LISTEN_FDS_START + i,
+      name: names.get(i as usize).cloned().filter(|n| !n.is_empty()),
+    })
+    .collect();
+}
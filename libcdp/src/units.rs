@@ -0,0 +1,205 @@
+//! Typed measurement values and unit conversions.
+//!
+//! Sensors send compact integers over the wire (whole kelvins, whole
+//! percentage points). The types in here wrap those raw values and know how
+//! to turn them into the units people actually read.
+
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeStruct;
+
+/// Offset between the Kelvin and Celsius scales.
+pub const KELVIN_CELSIUS_OFFSET: f64 = 273.15;
+
+/// Convert a temperature in K to °C.
+pub fn kelvin_to_celsius(k: f64) -> f64 {
+  return k - KELVIN_CELSIUS_OFFSET;
+}
+
+/// Convert a temperature in °C to K.
+pub fn celsius_to_kelvin(c: f64) -> f64 {
+  return c + KELVIN_CELSIUS_OFFSET;
+}
+
+/// Convert a temperature in °C to °F.
+pub fn celsius_to_fahrenheit(c: f64) -> f64 {
+  return c * 9.0 / 5.0 + 32.0;
+}
+
+/// Convert a temperature in °F to °C.
+pub fn fahrenheit_to_celsius(f: f64) -> f64 {
+  return (f - 32.0) * 5.0 / 9.0;
+}
+
+/// Convert a temperature in K to °F.
+pub fn kelvin_to_fahrenheit(k: f64) -> f64 {
+  return celsius_to_fahrenheit(kelvin_to_celsius(k));
+}
+
+/// Convert a temperature in °F to K.
+pub fn fahrenheit_to_kelvin(f: f64) -> f64 {
+  return celsius_to_kelvin(fahrenheit_to_celsius(f));
+}
+
+/// A temperature reading, kept in whole kelvins just like on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TemperatureReading {
+  /// The raw value, in K.
+  kelvin: u16
+}
+
+impl TemperatureReading {
+  /// Lowest temperature we believe a home sensor can report (-100.15 °C).
+  pub const MIN_VALID_KELVIN: u16 = 173;
+  /// Highest temperature we believe a home sensor can report (149.85 °C).
+  pub const MAX_VALID_KELVIN: u16 = 423;
+
+  /// Wrap a raw value in K.
+  pub fn from_kelvin(kelvin: u16) -> Self {
+    return Self { kelvin: kelvin };
+  }
+
+  /// Build a reading from a value in °C, rounding to the nearest kelvin.
+  /// None if it doesn't fit the wire format.
+  pub fn from_celsius(c: f64) -> Option<Self> {
+    let k = celsius_to_kelvin(c).round();
+    if k.is_nan() || k < 0.0 || k > u16::MAX as f64 {
+      return None;
+    }
+    return Some(Self::from_kelvin(k as u16));
+  }
+
+  /// Build a reading from a value in °F, rounding to the nearest kelvin.
+  /// None if it doesn't fit the wire format.
+  pub fn from_fahrenheit(f: f64) -> Option<Self> {
+    return Self::from_celsius(fahrenheit_to_celsius(f));
+  }
+
+  /// The raw value, exactly as the sensor sent it.
+  pub fn raw(&self) -> u16 {
+    return self.kelvin;
+  }
+
+  /// The value in K.
+  pub fn kelvin(&self) -> f64 {
+    return self.kelvin as f64;
+  }
+
+  /// The value in °C.
+  pub fn celsius(&self) -> f64 {
+    return kelvin_to_celsius(self.kelvin());
+  }
+
+  /// The value in °F.
+  pub fn fahrenheit(&self) -> f64 {
+    return kelvin_to_fahrenheit(self.kelvin());
+  }
+
+  /// Whether the value falls within the range a real sensor could report.
+  pub fn is_valid(&self) -> bool {
+    return (Self::MIN_VALID_KELVIN..=Self::MAX_VALID_KELVIN)
+      .contains(&self.kelvin);
+  }
+}
+
+impl Serialize for TemperatureReading {
+  /// Serializes the raw value along with all conversions.
+  fn serialize<S: Serializer>(&self, serializer: S)
+  -> Result<S::Ok, S::Error> {
+    let mut st = serializer.serialize_struct("TemperatureReading", 5)?;
+    st.serialize_field("raw", &self.raw())?;
+    st.serialize_field("kelvin", &self.kelvin())?;
+    st.serialize_field("celsius", &self.celsius())?;
+    st.serialize_field("fahrenheit", &self.fahrenheit())?;
+    st.serialize_field("valid", &self.is_valid())?;
+    return st.end();
+  }
+}
+
+/// A relative humidity reading, kept in whole percentage points just like on
+/// the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HumidityReading {
+  /// The raw value, in %RH.
+  percent: u8
+}
+
+impl HumidityReading {
+  /// Highest humidity that makes any physical sense.
+  pub const MAX_VALID_PERCENT: u8 = 100;
+
+  /// Wrap a raw value in %RH.
+  pub fn from_percent(percent: u8) -> Self {
+    return Self { percent: percent };
+  }
+
+  /// Build a reading from a 0.0-1.0 fraction, rounding to the nearest
+  /// percentage point. None if it doesn't fit the wire format.
+  pub fn from_fraction(frac: f64) -> Option<Self> {
+    let p = (frac * 100.0).round();
+    if p.is_nan() || p < 0.0 || p > u8::MAX as f64 {
+      return None;
+    }
+    return Some(Self::from_percent(p as u8));
+  }
+
+  /// The raw value, exactly as the sensor sent it.
+  pub fn raw(&self) -> u8 {
+    return self.percent;
+  }
+
+  /// The value in %RH.
+  pub fn percent(&self) -> f64 {
+    return self.percent as f64;
+  }
+
+  /// The value scaled to 0.0-1.0.
+  pub fn fraction(&self) -> f64 {
+    return self.percent() / 100.0;
+  }
+
+  /// Whether the value falls within 0-100 %RH.
+  pub fn is_valid(&self) -> bool {
+    return self.percent <= Self::MAX_VALID_PERCENT;
+  }
+}
+
+impl Serialize for HumidityReading {
+  /// Serializes the raw value along with all conversions.
+  fn serialize<S: Serializer>(&self, serializer: S)
+  -> Result<S::Ok, S::Error> {
+    let mut st = serializer.serialize_struct("HumidityReading", 4)?;
+    st.serialize_field("raw", &self.raw())?;
+    st.serialize_field("percent", &self.percent())?;
+    st.serialize_field("fraction", &self.fraction())?;
+    st.serialize_field("valid", &self.is_valid())?;
+    return st.end();
+  }
+}
+
+/// Any typed reading. Serializes as the inner reading only, since whoever
+/// holds it usually knows the sensor type already.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum AnyReading {
+  Temperature(TemperatureReading),
+  Humidity(HumidityReading)
+}
+
+impl AnyReading {
+  /// The value in the unit people usually read it in: °C for temperature,
+  /// %RH for humidity.
+  pub fn human_value(&self) -> f64 {
+    return match self {
+      AnyReading::Temperature(t) => t.celsius(),
+      AnyReading::Humidity(h) => h.percent(),
+    }
+  }
+
+  /// Whether the inner reading is within its valid range.
+  pub fn is_valid(&self) -> bool {
+    return match self {
+      AnyReading::Temperature(t) => t.is_valid(),
+      AnyReading::Humidity(h) => h.is_valid(),
+    }
+  }
+}
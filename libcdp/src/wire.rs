@@ -0,0 +1,88 @@
+//! What sensors publish, byte by byte. Needs nothing but core, so firmware
+//! can depend on libcdp with default-features = false and share these
+//! layouts with the broker, instead of keeping its own copy in sync.
+//!
+//! Everything else -- JSON forms, readings, decoders by topic -- lives in
+//! comm::sensor_broker, behind the std feature.
+
+use core::fmt::Display;
+
+/// Topics sensors publish to, one per message type.
+pub mod topics {
+  /// Where TemperatureMessage goes.
+  pub const TEMPERATURE: &str = "temperature";
+  /// Where HumidityMessage goes.
+  pub const HUMIDITY: &str = "humidity";
+  /// Where DeviceHealthMessage goes.
+  pub const DEVICE_HEALTH: &str = "device_health";
+}
+
+/// Why a message couldn't be decoded or encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireError {
+  /// The data, or the buffer it goes into, has the wrong length.
+  BadLength {
+    /// Bytes the message takes.
+    expected: usize,
+    /// Bytes there were.
+    got: usize
+  }
+}
+
+impl Display for WireError {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+    return match self {
+      WireError::BadLength { expected, got } => {
+        write!(f, "Bad length! Expected {}, got {}.", expected, got)
+      },
+    };
+  }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for WireError {}
+
+sensor_message! {
+  /// Message sent by a temperature sensor.
+  pub struct TemperatureMessage {
+    /// Numeric ID of the sensor.
+    sensor_id: u8,
+    /// Temperature value in K.
+    kelvin: u16
+  }
+}
+
+sensor_message! {
+  /// Message sent by a humidity sensor.
+  pub struct HumidityMessage {
+    /// Numeric ID of the sensor.
+    sensor_id: u8,
+    /// Humidity value in relative humidity percentage.
+    humidity: u8
+  }
+}
+
+sensor_message! {
+  /// Telemetry about the sensor device itself, rather than what it
+  /// measures. Sent on its own topic, see DeviceHealthMessage::TOPIC.
+  pub struct DeviceHealthMessage {
+    /// Numeric ID of the sensor.
+    sensor_id: u8,
+    /// Battery charge, in percent. 0xFF means mains-powered/unknown.
+    battery: u8,
+    /// Received signal strength, in dBm.
+    rssi: i8,
+    /// Seconds since the device booted.
+    uptime_secs: u32
+  }
+}
+
+impl DeviceHealthMessage {
+  /// The MQTT topic devices publish health telemetry to.
+  pub const TOPIC: &'static str = topics::DEVICE_HEALTH;
+
+  /// Returns the battery percentage, if the device runs on one.
+  pub fn battery_percent(&self) -> Option<u8> {
+    return if self.battery > 100 { None } else { Some(self.battery) };
+  }
+}